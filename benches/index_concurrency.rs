@@ -0,0 +1,76 @@
+//! Measures how many index operations many users get through at once while one of them keeps
+//! scanning a large mailbox, with every mailbox behind one lock and spread over several.
+//!
+//! Run with `cargo bench --bench index_concurrency`.
+
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+use async_std::task;
+
+use imaprust::index::inmemory::InMemoryIndex;
+use imaprust::index::message::MessageRecord;
+use imaprust::index::{Index, Owner};
+
+const USERS: u32 = 64;
+const OPERATIONS: u32 = 500;
+const LARGE_MAILBOX: u32 = 50_000;
+
+async fn run(shards: usize) -> f64 {
+    let index = Arc::new(InMemoryIndex::new().with_shards(shards));
+    let hoarder = Owner::new("hoarder");
+    for uid in 1..=LARGE_MAILBOX {
+        index
+            .add_message(
+                &hoarder,
+                "INBOX",
+                MessageRecord::new(uid, 1024, SystemTime::now()),
+            )
+            .await
+            .unwrap();
+    }
+
+    let start = Instant::now();
+    let scanner = {
+        let index = index.clone();
+        task::spawn(async move {
+            for _ in 0..OPERATIONS / 10 {
+                index.list_messages(&hoarder, "INBOX").await.unwrap();
+            }
+        })
+    };
+    let users: Vec<_> = (0..USERS)
+        .map(|user| {
+            let index = index.clone();
+            task::spawn(async move {
+                let owner = Owner::new(&format!("user{}", user));
+                for uid in 1..=OPERATIONS {
+                    let record = MessageRecord::new(uid, 1024, SystemTime::now());
+                    index.add_message(&owner, "INBOX", record).await.unwrap();
+                    index
+                        .set_flags(&owner, "INBOX", uid, vec!["\\Seen".to_string()])
+                        .await
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for user in users {
+        user.await;
+    }
+    let elapsed = start.elapsed();
+    scanner.await;
+    (USERS * OPERATIONS * 2) as f64 / elapsed.as_secs_f64()
+}
+
+fn main() {
+    task::block_on(async {
+        for shards in [1, 4, 16, 64] {
+            println!(
+                "{:>2} shards: {:>10.0} operations/s",
+                shards,
+                run(shards).await
+            );
+        }
+    });
+}
@@ -0,0 +1,225 @@
+//! Measures the parts of the server every command goes through: parsing what the client sends,
+//! writing responses, parsing the MIME structure of messages, and a whole SELECT and FETCH
+//! exchange with a server over an in-memory pipe.
+//!
+//! Run with `cargo bench --bench protocol`. Criterion compares each run with the one before,
+//! so run it on the base of a change first to see what the change costs.
+
+use std::io::Write;
+
+use async_std::task;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use imaprust::handlers::fetch::items;
+use imaprust::handlers::search::criteria;
+use imaprust::mime::Part;
+use imaprust::server::{Command, Response, ResponseStatus};
+
+const COMMANDS: [&str; 5] = [
+    "a1 LOGIN me@example.com password",
+    "a2 SELECT INBOX",
+    "a3 UID FETCH 1:* (UID FLAGS RFC822.SIZE BODY.PEEK[HEADER.FIELDS (DATE FROM SUBJECT)])",
+    "a4 SEARCH UNSEEN SINCE 1-Feb-1994 NOT FROM \"Smith\"",
+    "a5 UID STORE 1:100 +FLAGS.SILENT (\\Seen \\Flagged)",
+];
+
+const PLAIN: &[u8] = b"From: someone@example.com\r\n\
+To: someone_else@example.com\r\n\
+Subject: An RFC 822 formatted message\r\n\
+Date: Mon, 7 Feb 1994 21:52:25 -0800\r\n\
+\r\n\
+This is a test email body.\r\n";
+
+/// A message with an alternative text and HTML body and a base64 attachment.
+fn multipart() -> Vec<u8> {
+    let mut message = b"From: someone@example.com\r\n\
+Subject: Quarterly report\r\n\
+MIME-Version: 1.0\r\n\
+Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+\r\n\
+--outer\r\n\
+Content-Type: multipart/alternative; boundary=\"inner\"\r\n\
+\r\n\
+--inner\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+The report is attached.=0D=0A\r\n\
+--inner\r\n\
+Content-Type: text/html; charset=utf-8\r\n\
+\r\n\
+<p>The report is attached.</p>\r\n\
+--inner--\r\n\
+--outer\r\n\
+Content-Type: application/pdf; name=\"report.pdf\"\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n"
+        .to_vec();
+    for _ in 0..1000 {
+        message.extend_from_slice(
+            b"JVBERi0xLjQKJcfsj6IKNSAwIG9iago8PC9MZW5ndGggNiAwIFIvRmlsdGVyIC9GbGF0ZURl\r\n",
+        );
+    }
+    message.extend_from_slice(b"--outer--\r\n");
+    message
+}
+
+fn parsing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parsing");
+    group.throughput(Throughput::Elements(COMMANDS.len() as u64));
+    group.bench_function("commands", |b| {
+        b.iter(|| {
+            for command in COMMANDS {
+                black_box(Command::parse(black_box(command)).unwrap());
+            }
+        })
+    });
+    group.finish();
+    c.bench_function("parsing/fetch items", |b| {
+        b.iter(|| {
+            items::parse(black_box(
+                "(UID FLAGS RFC822.SIZE BODY.PEEK[HEADER.FIELDS (DATE FROM SUBJECT)] BODY[1.2]<0.2048>)",
+            ))
+            .unwrap()
+        })
+    });
+    c.bench_function("parsing/search criteria", |b| {
+        b.iter(|| {
+            criteria::parse(black_box(
+                "UNSEEN SINCE 1-Feb-1994 OR FROM \"Smith\" SUBJECT \"report\" NOT LARGER 10000",
+            ))
+            .unwrap()
+        })
+    });
+}
+
+fn serialization(c: &mut Criterion) {
+    let responses: Vec<Response> = (1..=1000)
+        .map(|n| {
+            Response::untagged(&format!(
+                "{} FETCH (UID {} FLAGS (\\Seen) RFC822.SIZE 4096)",
+                n,
+                n + 100
+            ))
+        })
+        .chain([Response::new("a3", ResponseStatus::OK, "FETCH completed")])
+        .collect();
+    let mut group = c.benchmark_group("serialization");
+    group.throughput(Throughput::Elements(responses.len() as u64));
+    group.bench_function("fetch responses", |b| {
+        let mut buffer = Vec::with_capacity(64 * 1024);
+        b.iter(|| {
+            buffer.clear();
+            for response in &responses {
+                write!(buffer, "{}\r\n", response).unwrap();
+            }
+            black_box(buffer.len())
+        })
+    });
+    group.finish();
+}
+
+fn mime(c: &mut Criterion) {
+    let multipart = multipart();
+    let mut group = c.benchmark_group("mime");
+    for (name, message) in [("plain", PLAIN), ("multipart", &multipart[..])] {
+        group.throughput(Throughput::Bytes(message.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| black_box(Part::parse(black_box(message))))
+        });
+    }
+    group.finish();
+}
+
+#[cfg(unix)]
+fn session(c: &mut Criterion) {
+    use async_std::io::BufReader;
+    use async_std::os::unix::net::UnixStream;
+    use futures::io::Lines;
+    use futures::{AsyncBufReadExt, AsyncWriteExt, StreamExt};
+
+    use imaprust::auth::inmemory::InMemoryUserStore;
+    use imaprust::index::{inmemory::InMemoryIndex, reindex::reindex, Owner};
+    use imaprust::server::{Configuration, ServerBuilder};
+    use imaprust::store::{inmemory::InMemoryStore, DataStore, Message};
+
+    const MESSAGES: usize = 50;
+
+    /// Sends `command` and reads the responses up to its tagged one.
+    async fn exchange(
+        client: &mut UnixStream,
+        lines: &mut Lines<BufReader<UnixStream>>,
+        tag: &str,
+        command: &str,
+    ) {
+        client
+            .write_all(format!("{} {}\r\n", tag, command).as_bytes())
+            .await
+            .unwrap();
+        let tagged = format!("{} ", tag);
+        while let Some(line) = lines.next().await {
+            if line.unwrap().starts_with(&tagged) {
+                return;
+            }
+        }
+        panic!("the server closed the connection");
+    }
+
+    let (mut client, mut lines) = task::block_on(async {
+        let data_store = InMemoryStore::new();
+        for _ in 0..MESSAGES {
+            data_store
+                .append("me@example.com", "INBOX", Message::new(PLAIN))
+                .await
+                .unwrap();
+        }
+        let index = InMemoryIndex::new();
+        reindex(&data_store, &index, &Owner::new("me@example.com"), |_| {})
+            .await
+            .unwrap();
+        let server = ServerBuilder::new()
+            .with_configuration(Configuration::default().with_listeners(vec![]))
+            .with_user_store(InMemoryUserStore::new().with_user("me@example.com", "password"))
+            .with_data_store(data_store)
+            .with_index(index)
+            .bind()
+            .await
+            .unwrap();
+        let (mut client, theirs) = UnixStream::pair().unwrap();
+        task::spawn(async move { server.serve_stream(theirs).await });
+        let mut lines = BufReader::new(client.clone()).lines();
+        lines.next().await.unwrap().unwrap();
+        exchange(
+            &mut client,
+            &mut lines,
+            "a0",
+            "LOGIN me@example.com password",
+        )
+        .await;
+        (client, lines)
+    });
+
+    let mut group = c.benchmark_group("session");
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+    group.bench_function("select and fetch", |b| {
+        b.iter(|| {
+            task::block_on(async {
+                exchange(&mut client, &mut lines, "a1", "SELECT INBOX").await;
+                exchange(
+                    &mut client,
+                    &mut lines,
+                    "a2",
+                    "FETCH 1:* (UID FLAGS RFC822.SIZE BODY[])",
+                )
+                .await;
+            })
+        })
+    });
+    group.finish();
+}
+
+#[cfg(not(unix))]
+fn session(_: &mut Criterion) {}
+
+criterion_group!(benches, parsing, serialization, mime, session);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+#![no_main]
+
+use imaprust::handlers::fetch::items;
+use imaprust::handlers::search::criteria;
+use imaprust::server::Command;
+use libfuzzer_sys::fuzz_target;
+
+// A command line as the connection hands it to the handlers, arguments included.
+fuzz_target!(|line: &str| {
+    if let Ok(command) = Command::parse(line) {
+        let arguments = line.splitn(3, ' ').nth(2).unwrap_or_default();
+        let _ = items::parse(arguments);
+        let _ = criteria::parse(arguments);
+        for position in 0..command.num_args() {
+            let _ = items::parse(&command.arg(position));
+        }
+    }
+});
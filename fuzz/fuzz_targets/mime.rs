@@ -0,0 +1,25 @@
+#![no_main]
+
+use imaprust::mime::address::Address;
+use imaprust::mime::encoding::decode_words;
+use imaprust::mime::Part;
+use libfuzzer_sys::fuzz_target;
+
+fn exercise(part: &Part) {
+    for field in part.headers().fields() {
+        let value = field.value();
+        decode_words(&value);
+        Address::parse_list(&value);
+    }
+    part.disposition();
+    part.text();
+    part.lines();
+    part.children.iter().for_each(exercise);
+    if let Some(message) = &part.message {
+        exercise(message);
+    }
+}
+
+fuzz_target!(|raw: &[u8]| {
+    exercise(&Part::parse(raw));
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use imaprust::handlers::sequence::SequenceSet;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|value: &str| {
+    if let Ok(set) = SequenceSet::parse(value) {
+        assert_eq!(SequenceSet::parse(&set.to_string()).unwrap(), set);
+        set.resolve(1000);
+    }
+});
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+/// A CIDR block, such as `192.168.0.0/16` or `2001:db8::/32`. A bare address is a block of
+/// one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidNetwork(String);
+
+impl Error for InvalidNetwork {}
+impl Display for InvalidNetwork {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is not an address or CIDR block", self.0)
+    }
+}
+
+impl Network {
+    pub fn parse(network: &str) -> std::result::Result<Self, InvalidNetwork> {
+        let invalid = || InvalidNetwork(network.to_string());
+        let (address, prefix) = match network.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (network, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+        Ok(Self { address, prefix })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // An IPv4 client of a dual-stack socket is seen as an IPv4-mapped IPv6 address.
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+            v4 => v4,
+        };
+        let masked = |bits: u128, width: u8| match self.prefix {
+            0 => 0,
+            prefix => bits >> (width - prefix),
+        };
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                masked(u32::from(network).into(), 32) == masked(u32::from(address).into(), 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                masked(network.into(), 128) == masked(address.into(), 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Networks a client is allowed or denied from. A client is let in when its address is in
+/// no denied network and, if any networks are allowed, in one of those.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rules {
+    allow: Vec<Network>,
+    deny: Vec<Network>,
+}
+
+impl Rules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn allow(mut self, network: &str) -> std::result::Result<Self, InvalidNetwork> {
+        self.allow.push(Network::parse(network)?);
+        Ok(self)
+    }
+    pub fn deny(mut self, network: &str) -> std::result::Result<Self, InvalidNetwork> {
+        self.deny.push(Network::parse(network)?);
+        Ok(self)
+    }
+
+    /// Whether a client at `address` is let in. Clients of Unix sockets have no address and
+    /// are always let in.
+    pub fn permits(&self, address: Option<IpAddr>) -> bool {
+        let address = match address {
+            Some(address) => address,
+            None => return true,
+        };
+        let denied = self.deny.iter().any(|network| network.contains(address));
+        let allowed =
+            self.allow.is_empty() || self.allow.iter().any(|network| network.contains(address));
+        allowed && !denied
+    }
+}
+
+/// The rules applied when a client connects, and those applied when a particular user logs
+/// in on top of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessPolicy {
+    connect: Rules,
+    users: HashMap<String, Rules>,
+}
+
+impl AccessPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Turns away clients which `rules` do not permit with `* BYE` as soon as they connect.
+    pub fn with_connect_rules(mut self, rules: Rules) -> Self {
+        self.connect = rules;
+        self
+    }
+    /// Refuses logins of `user` from addresses `rules` do not permit, such as an admin
+    /// account only allowed from the LAN.
+    pub fn with_user_rules(mut self, user: &str, rules: Rules) -> Self {
+        self.users.insert(user.to_string(), rules);
+        self
+    }
+}
+
+/// The access policy of a server, which may be replaced while it runs. Clones share the
+/// policy, so a reload applies to every listener and handler.
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    policy: Arc<RwLock<AccessPolicy>>,
+}
+
+impl AccessControl {
+    pub fn new(policy: AccessPolicy) -> Self {
+        Self {
+            policy: Arc::new(RwLock::new(policy)),
+        }
+    }
+
+    /// Applies `policy` to connections and logins from now on. Open connections are not
+    /// re-checked.
+    pub fn reload(&self, policy: AccessPolicy) {
+        match self.policy.write() {
+            Ok(mut current) => *current = policy,
+            Err(poisoned) => *poisoned.into_inner() = policy,
+        }
+    }
+
+    /// Whether a client at `peer` may connect.
+    pub fn admits(&self, peer: Option<IpAddr>) -> bool {
+        match self.policy.read() {
+            Ok(policy) => policy.connect.permits(peer),
+            Err(poisoned) => poisoned.into_inner().connect.permits(peer),
+        }
+    }
+
+    /// Whether `user` may log in from `peer`. Users without rules of their own may log in
+    /// from wherever they could connect.
+    pub fn authorizes(&self, user: &str, peer: Option<IpAddr>) -> bool {
+        let permits = |policy: &AccessPolicy| {
+            policy
+                .users
+                .get(user)
+                .is_none_or(|rules| rules.permits(peer))
+        };
+        match self.policy.read() {
+            Ok(policy) => permits(&policy),
+            Err(poisoned) => permits(&poisoned.into_inner()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{AccessControl, AccessPolicy, Network, Rules};
+
+    fn address(address: &str) -> Option<IpAddr> {
+        Some(address.parse().unwrap())
+    }
+
+    #[test]
+    fn test_network() {
+        let lan = Network::parse("192.168.0.0/16").unwrap();
+        assert!(lan.contains("192.168.4.20".parse().unwrap()));
+        assert!(lan.contains("::ffff:192.168.4.20".parse().unwrap()));
+        assert!(!lan.contains("192.169.0.1".parse().unwrap()));
+        assert!(!lan.contains("2001:db8::1".parse().unwrap()));
+        assert!(Network::parse("0.0.0.0/0")
+            .unwrap()
+            .contains("203.0.113.9".parse().unwrap()));
+        assert!(Network::parse("2001:db8::/32")
+            .unwrap()
+            .contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(Network::parse("10.0.0.0/33").is_err());
+        assert!(Network::parse("example.com").is_err());
+    }
+
+    #[test]
+    fn test_access_control() {
+        let lan_only = Rules::new().allow("10.0.0.0/8").unwrap();
+        let access = AccessControl::new(
+            AccessPolicy::new()
+                .with_connect_rules(Rules::new().deny("203.0.113.0/24").unwrap())
+                .with_user_rules("admin", lan_only),
+        );
+        assert!(access.admits(address("198.51.100.7")));
+        assert!(!access.admits(address("203.0.113.9")));
+        assert!(access.admits(None));
+        assert!(access.authorizes("admin", address("10.1.2.3")));
+        assert!(!access.authorizes("admin", address("198.51.100.7")));
+        assert!(access.authorizes("user", address("198.51.100.7")));
+
+        access.reload(AccessPolicy::new());
+        assert!(access.admits(address("203.0.113.9")));
+        assert!(access.authorizes("admin", address("198.51.100.7")));
+    }
+}
@@ -0,0 +1,474 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_lock::Mutex;
+use async_std::fs::{File, OpenOptions};
+use async_std::path::Path;
+use async_std::prelude::*;
+use serde::Serialize;
+
+use crate::connection::Context;
+use crate::server::{Command, ResponseStatus};
+use crate::util::{rfc3339, Result};
+
+/// What an `AuditEvent` records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    LoginSucceeded,
+    LoginFailed,
+    Logout,
+    MailboxCreated,
+    MailboxDeleted,
+    MailboxRenamed,
+    Expunge,
+}
+
+/// An authentication or a change to a mailbox, as recorded in an `AuditLog`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditEvent {
+    /// When the command completed, in RFC 3339 form in UTC.
+    pub timestamp: String,
+    pub action: Action,
+    /// The user acting, or the one a failed login was for, when known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// The master user acting as `user`, for impersonated sessions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<String>,
+    /// The address of the client. Clients of Unix sockets have none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<IpAddr>,
+    /// The UUID of the connection, as in its tracing span.
+    pub session: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mailbox: Option<String>,
+    /// The new name of a renamed mailbox.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_mailbox: Option<String>,
+}
+
+impl AuditEvent {
+    /// The event for `command`, which completed with `status` in the session `session`,
+    /// moving it from the state `before` to `after`. Commands which are not audited, and
+    /// mailbox changes which did not succeed, have none.
+    pub fn of(
+        command: &Command,
+        status: Option<ResponseStatus>,
+        before: &Context,
+        after: &Context,
+        session: &str,
+    ) -> Option<Self> {
+        let ok = status == Some(ResponseStatus::OK);
+        let name = command.command();
+        let action = match (name.as_str(), status) {
+            ("LOGIN" | "AUTHENTICATE", Some(ResponseStatus::OK)) => Action::LoginSucceeded,
+            ("LOGIN" | "AUTHENTICATE", Some(ResponseStatus::NO)) => Action::LoginFailed,
+            ("LOGOUT", _) => Action::Logout,
+            ("CREATE", _) if ok => Action::MailboxCreated,
+            ("DELETE", _) if ok => Action::MailboxDeleted,
+            ("RENAME", _) if ok => Action::MailboxRenamed,
+            ("EXPUNGE", _) if ok => Action::Expunge,
+            ("UID", _) if ok && command.arg(0).eq_ignore_ascii_case("EXPUNGE") => Action::Expunge,
+            _ => return None,
+        };
+        // The user is the one logged in by the command, or the one it was acting as.
+        let acting = match action {
+            Action::LoginSucceeded => after.user(),
+            _ => before.user(),
+        };
+        let user = match (action, acting) {
+            (_, Some(user)) => Some(user.name()),
+            (Action::LoginFailed, None) if name == "LOGIN" => Some(command.arg(0)),
+            _ => None,
+        };
+        let (mailbox, new_mailbox) = match action {
+            Action::MailboxCreated | Action::MailboxDeleted => (Some(command.arg(0)), None),
+            Action::MailboxRenamed => (Some(command.arg(0)), Some(command.arg(1))),
+            Action::Expunge => (before.folder(), None),
+            _ => (None, None),
+        };
+        Some(AuditEvent {
+            timestamp: rfc3339(SystemTime::now()),
+            action,
+            user,
+            impersonator: acting
+                .and_then(|user| user.impersonator())
+                .map(str::to_string),
+            address: before.peer(),
+            session: session.to_string(),
+            mailbox,
+            new_mailbox,
+        })
+    }
+}
+
+/// Somewhere to keep a record of who logged in and what they changed, for compliance.
+#[async_trait::async_trait]
+pub trait AuditLog: Send + Sync {
+    async fn record(&self, event: &AuditEvent) -> Result<()>;
+}
+
+/// Records each event in several audit logs, such as a JSON log of everything and an
+/// `AuthFailureLog` for fail2ban. Every log is tried even when one fails.
+#[derive(Clone, Default)]
+pub struct AuditLogs {
+    logs: Vec<Arc<dyn AuditLog>>,
+}
+
+impl AuditLogs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_log(mut self, log: Arc<dyn AuditLog>) -> Self {
+        self.logs.push(log);
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditLog for AuditLogs {
+    async fn record(&self, event: &AuditEvent) -> Result<()> {
+        let mut failure = Ok(());
+        for log in &self.logs {
+            if let Err(e) = log.record(event).await {
+                failure = Err(e);
+            }
+        }
+        failure
+    }
+}
+
+/// Appends each event to a file as a line of JSON.
+pub struct JsonLinesAuditLog {
+    file: Mutex<File>,
+}
+
+impl JsonLinesAuditLog {
+    /// Opens `path` for appending, creating it if need be.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditLog for JsonLinesAuditLog {
+    async fn record(&self, event: &AuditEvent) -> Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+/// The syslog facility for security and authorization messages.
+const AUTHPRIV: u8 = 10;
+const WARNING: u8 = 4;
+const INFO: u8 = 6;
+
+enum SyslogSocket {
+    Udp(async_std::net::UdpSocket),
+    #[cfg(unix)]
+    Unix(async_std::os::unix::net::UnixDatagram),
+}
+
+/// Sends each event to syslog as an RFC 5424 message in the authpriv facility, with the
+/// event as JSON for its text. Failed logins are warnings, other events informational.
+pub struct SyslogAuditLog {
+    socket: SyslogSocket,
+    hostname: String,
+}
+
+impl SyslogAuditLog {
+    /// Sends to the local syslog daemon over a Unix datagram socket, usually `/dev/log`.
+    #[cfg(unix)]
+    pub async fn unix<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let socket = async_std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect(path.as_ref()).await?;
+        Ok(Self::on(SyslogSocket::Unix(socket)))
+    }
+    /// Sends to a syslog server over UDP, such as `127.0.0.1:514`.
+    pub async fn udp(address: &str) -> Result<Self> {
+        let socket = async_std::net::UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(address).await?;
+        Ok(Self::on(SyslogSocket::Udp(socket)))
+    }
+    fn on(socket: SyslogSocket) -> Self {
+        let hostname = std::env::var("HOSTNAME").unwrap_or_else(|_| "-".to_string());
+        Self { socket, hostname }
+    }
+    fn format(&self, event: &AuditEvent) -> Result<String> {
+        let severity = match event.action {
+            Action::LoginFailed => WARNING,
+            _ => INFO,
+        };
+        let text = serde_json::to_string(event)?;
+        Ok(self.message(severity, &event.timestamp, "audit", &text))
+    }
+    fn message(&self, severity: u8, timestamp: &str, id: &str, text: &str) -> String {
+        format!(
+            "<{}>1 {} {} treasurmap {} {} - {}",
+            AUTHPRIV * 8 + severity,
+            timestamp,
+            self.hostname,
+            std::process::id(),
+            id,
+            text
+        )
+    }
+    async fn send(&self, message: &str) -> Result<()> {
+        match &self.socket {
+            SyslogSocket::Udp(socket) => socket.send(message.as_bytes()).await?,
+            #[cfg(unix)]
+            SyslogSocket::Unix(socket) => socket.send(message.as_bytes()).await?,
+        };
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditLog for SyslogAuditLog {
+    async fn record(&self, event: &AuditEvent) -> Result<()> {
+        let message = self.format(event)?;
+        self.send(&message).await
+    }
+}
+
+enum FailureSink {
+    File(Mutex<File>),
+    Syslog(SyslogAuditLog),
+}
+
+/// Records failed logins, and nothing else, as single lines for intrusion prevention tools
+/// such as fail2ban and CrowdSec to ban the addresses of brute-forcers. The format is stable:
+///
+/// ```text
+/// 2024-05-01T09:30:00Z treasurmap[4242]: authentication failure; user=alice rhost=192.0.2.1 session=<uuid>
+/// ```
+///
+/// `user` is the name given to LOGIN, or `-` when there is none, with anything but printable
+/// ASCII replaced by `?`; `rhost` is `-` for clients of Unix sockets. Sent to syslog, the
+/// time and process are in the header, in the authpriv facility with the message ID
+/// `authfail`, and the text starts at `authentication failure`. A fail2ban filter can use
+///
+/// ```text
+/// failregex = authentication failure; user=\S+ rhost=<HOST> session=
+/// ```
+pub struct AuthFailureLog {
+    sink: FailureSink,
+}
+
+impl AuthFailureLog {
+    /// Appends to the file at `path`, creating it if need be.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self {
+            sink: FailureSink::File(Mutex::new(file)),
+        })
+    }
+    /// Sends to syslog, such as a `SyslogAuditLog` at `/dev/log` reserved for failures.
+    pub fn syslog(syslog: SyslogAuditLog) -> Self {
+        Self {
+            sink: FailureSink::Syslog(syslog),
+        }
+    }
+
+    /// The text of the line for a failed login, without the time and process.
+    fn text(event: &AuditEvent) -> String {
+        let user = match &event.user {
+            Some(user) if !user.is_empty() => user
+                .chars()
+                .map(|c| if c.is_ascii_graphic() { c } else { '?' })
+                .collect(),
+            _ => "-".to_string(),
+        };
+        let address = event
+            .address
+            .map_or("-".to_string(), |address| address.to_string());
+        format!(
+            "authentication failure; user={} rhost={} session={}",
+            user, address, event.session
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditLog for AuthFailureLog {
+    async fn record(&self, event: &AuditEvent) -> Result<()> {
+        if event.action != Action::LoginFailed {
+            return Ok(());
+        }
+        let text = Self::text(event);
+        match &self.sink {
+            FailureSink::File(file) => {
+                let line = format!(
+                    "{} treasurmap[{}]: {}\n",
+                    event.timestamp,
+                    std::process::id(),
+                    text
+                );
+                let mut file = file.lock().await;
+                file.write_all(line.as_bytes()).await?;
+                file.flush().await?;
+            }
+            FailureSink::Syslog(syslog) => {
+                let message = syslog.message(WARNING, &event.timestamp, "authfail", &text);
+                syslog.send(&message).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+
+    use async_std::net::UdpSocket;
+
+    use super::{
+        Action, AuditEvent, AuditLog, AuditLogs, AuthFailureLog, JsonLinesAuditLog, SyslogAuditLog,
+    };
+    use crate::auth::User;
+    use crate::connection::Context;
+    use crate::server::{Command, ResponseStatus};
+
+    const ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn event(
+        command: Command,
+        status: ResponseStatus,
+        before: &Context,
+        after: &Context,
+    ) -> Option<AuditEvent> {
+        AuditEvent::of(&command, Some(status), before, after, "session")
+    }
+
+    #[test]
+    fn test_events() {
+        let anonymous = Context::default().with_peer(ADDRESS);
+        let user = User::new("username", "password");
+        let logged_in = Context::of(Some(user), Some("INBOX".into())).with_peer(ADDRESS);
+
+        let login = Command::new("a1", "LOGIN", vec!["username", "password"]);
+        let success = event(login.clone(), ResponseStatus::OK, &anonymous, &logged_in).unwrap();
+        assert_eq!(success.action, Action::LoginSucceeded);
+        assert_eq!(success.user.as_deref(), Some("username"));
+        assert_eq!(success.address, Some(ADDRESS));
+        let failure = event(login, ResponseStatus::NO, &anonymous, &anonymous).unwrap();
+        assert_eq!(failure.action, Action::LoginFailed);
+        assert_eq!(failure.user.as_deref(), Some("username"));
+
+        let rename = Command::new("a2", "RENAME", vec!["Old", "New"]);
+        let renamed = event(rename.clone(), ResponseStatus::OK, &logged_in, &logged_in).unwrap();
+        assert_eq!(renamed.mailbox.as_deref(), Some("Old"));
+        assert_eq!(renamed.new_mailbox.as_deref(), Some("New"));
+        assert!(event(rename, ResponseStatus::NO, &logged_in, &logged_in).is_none());
+
+        let expunge = Command::new("a3", "UID", vec!["expunge", "1:3"]);
+        let expunged = event(expunge, ResponseStatus::OK, &logged_in, &logged_in).unwrap();
+        assert_eq!(expunged.action, Action::Expunge);
+        assert_eq!(expunged.mailbox.as_deref(), Some("INBOX"));
+
+        let fetch = Command::new("a4", "FETCH", vec!["1", "FLAGS"]);
+        assert!(event(fetch, ResponseStatus::OK, &logged_in, &logged_in).is_none());
+    }
+
+    #[async_std::test]
+    async fn test_json_lines() {
+        let path = std::env::temp_dir().join(format!(
+            "treasurmap-audit-json-lines-{}.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let log = JsonLinesAuditLog::open(&path).await.unwrap();
+        let logout = Command::new("a1", "LOGOUT", vec![]);
+        let context = Context::of(Some(User::new("username", "password")), None);
+        let event = AuditEvent::of(&logout, None, &context, &context, "session").unwrap();
+        log.record(&event).await.unwrap();
+        log.record(&event).await.unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let json: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(json["action"], "logout");
+        assert_eq!(json["user"], "username");
+        assert_eq!(json["session"], "session");
+        assert!(json.get("address").is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_auth_failures() {
+        let path = std::env::temp_dir().join(format!(
+            "treasurmap-audit-auth-failures-{}.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let failures = Arc::new(AuthFailureLog::open(&path).await.unwrap());
+        let log = AuditLogs::new().with_log(failures);
+        let context = Context::default().with_peer(ADDRESS);
+        let login = Command::new("a1", "LOGIN", vec!["mallory smith", "wrong"]);
+        let failure = event(login.clone(), ResponseStatus::NO, &context, &context).unwrap();
+        log.record(&failure).await.unwrap();
+        let user = User::new("mallory", "password");
+        let logged_in = Context::of(Some(user), None).with_peer(ADDRESS);
+        let success = event(login, ResponseStatus::OK, &context, &logged_in).unwrap();
+        log.record(&success).await.unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let expected = format!(
+            "{} treasurmap[{}]: authentication failure; user=mallory?smith rhost=192.0.2.1 session=session",
+            failure.timestamp,
+            std::process::id()
+        );
+        assert_eq!(lines[0], expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_syslog() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let log = SyslogAuditLog::udp(&address).await.unwrap();
+        let login = Command::new("a1", "LOGIN", vec!["username", "wrong"]);
+        let context = Context::default();
+        let failure = AuditEvent::of(
+            &login,
+            Some(ResponseStatus::NO),
+            &context,
+            &context,
+            "session",
+        )
+        .unwrap();
+        log.record(&failure).await.unwrap();
+
+        let mut buffer = [0u8; 1024];
+        let (read, _) = server.recv_from(&mut buffer).await.unwrap();
+        let message = String::from_utf8_lossy(&buffer[..read]);
+        // authpriv.warning
+        assert!(message.starts_with("<84>1 "), "{}", message);
+        assert!(message.contains(" treasurmap "));
+        assert!(
+            message.ends_with(r#""action":"login_failed","user":"username","session":"session"}"#)
+        );
+    }
+}
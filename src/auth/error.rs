@@ -4,6 +4,7 @@ use std::{fmt::{Display, Formatter, self}, error::Error};
 pub enum UserStoreError {
     Exists(String),
     DoesNotExist(String),
+    Backend(String),
 }
 impl Error for UserStoreError{}
 impl Display for UserStoreError {
@@ -15,10 +16,58 @@ impl Display for UserStoreError {
             UserStoreError::DoesNotExist(name) => {
                 write!(f, "user {} does not exist", name)
             },
+            UserStoreError::Backend(reason) => {
+                write!(f, "user store failed: {}", reason)
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PasswordError {
+    /// The stored hash is not in a format any supported scheme understands.
+    Malformed(String),
+    Hashing(String),
+}
+impl Error for PasswordError {}
+impl Display for PasswordError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordError::Malformed(_) => write!(f, "unrecognised password hash"),
+            PasswordError::Hashing(reason) => write!(f, "password hashing failed: {}", reason),
         }
     }
 }
 
+#[derive(Debug, Clone)]
+pub enum LdapError {
+    Configuration(String),
+    Connection(String),
+    Protocol(String),
+    InvalidFilter(String),
+    /// The server answered with a result code other than success or invalid credentials.
+    Server { code: i64, message: String },
+}
+impl Error for LdapError {}
+impl Display for LdapError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LdapError::Configuration(reason) => write!(f, "invalid LDAP configuration: {}", reason),
+            LdapError::Connection(reason) => write!(f, "LDAP connection failed: {}", reason),
+            LdapError::Protocol(reason) => write!(f, "LDAP protocol error: {}", reason),
+            LdapError::InvalidFilter(filter) => write!(f, "invalid LDAP filter {}", filter),
+            LdapError::Server { code, message } => {
+                write!(f, "LDAP server returned result code {}: {}", code, message)
+            }
+        }
+    }
+}
+impl From<std::io::Error> for LdapError {
+    fn from(e: std::io::Error) -> Self {
+        LdapError::Connection(e.to_string())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum AuthenticationError {
     BadCredentials,
@@ -1,54 +1,174 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
+use async_lock::RwLock;
 use async_std::task::block_on;
 
-use super::error::{UserAlreadyExists, UserStoreError};
-use super::{Authenticate, AuthenticationPrincipal, Password, User, UserStore};
+use super::error::{UserAlreadyExists, UserDoesNotExist};
+use super::password::{Argon2id, PasswordHasher};
+use super::username::Normalization;
+use super::{
+    rehash, reject_unknown, Authenticate, AuthenticationPrincipal, Password, User, UserStore,
+};
 
 use crate::util::Result;
 
 pub struct InMemoryUserStore {
-    users: HashMap<String, User>,
+    users: RwLock<HashMap<String, User>>,
+    aliases: RwLock<HashMap<String, String>>,
+    normalization: Normalization,
+    hasher: Arc<dyn PasswordHasher>,
+    dummy: OnceLock<Option<Password>>,
 }
 
 #[async_trait::async_trait]
 impl UserStore for InMemoryUserStore {
     async fn authenticate(&self, principal: Box<dyn AuthenticationPrincipal>) -> Result<User> {
-        match self.users.get(&principal.principal()) {
-            Some(user) => Ok(user.clone()),
-            None => Err(Box::new(UserStoreError::DoesNotExist(
-                principal.principal(),
-            ))),
+        let mut user = match self.get(&principal.principal()).await? {
+            Some(user) => user,
+            None => {
+                return Err(reject_unknown(&self.hasher, &self.dummy, principal.as_ref()).await)
+            }
+        };
+        principal.authenticate(&user).await?;
+        if let Some(password) = rehash(&self.hasher, principal.as_ref(), &user).await {
+            user.password_hash = password;
+            self.users
+                .write()
+                .await
+                .insert(user.name.clone(), user.clone());
         }
+        Ok(user)
     }
 
-    async fn get(&self, username: &str) -> Result<Option<&User>> {
-        Ok(self.users.get(username))
+    async fn get(&self, username: &str) -> Result<Option<User>> {
+        let username = self.resolve(username).await;
+        Ok(self.users.read().await.get(&username).cloned())
     }
 
-    async fn add(&mut self, user: User) -> Result<()> {
-        let username = user.name.clone();
-        if self.users.contains_key(&username) {
-            return Err(UserAlreadyExists::new(&username));
+    async fn canonical(&self, username: &str) -> Result<String> {
+        Ok(self.resolve(username).await)
+    }
+
+    async fn add(&self, mut user: User) -> Result<()> {
+        user.name = self.normalization.canonical(&user.name);
+        let mut users = self.users.write().await;
+        if users.contains_key(&user.name) || self.aliases.read().await.contains_key(&user.name) {
+            return Err(UserAlreadyExists::new(&user.name));
         }
-        match self.users.insert(username.clone(), user) {
-            Some(..) => Err(UserAlreadyExists::new(&username)),
-            None => Ok(()),
+        users.insert(user.name.clone(), user);
+        Ok(())
+    }
+
+    async fn update(&self, mut user: User) -> Result<()> {
+        user.name = self.normalization.canonical(&user.name);
+        match self.users.write().await.get_mut(&user.name) {
+            Some(existing) => {
+                *existing = user;
+                Ok(())
+            }
+            None => Err(UserDoesNotExist::new(&user.name)),
+        }
+    }
+
+    async fn remove(&self, username: &str) -> Result<()> {
+        let username = self.normalization.canonical(username);
+        let mut users = self.users.write().await;
+        if users.remove(&username).is_none() {
+            return Err(UserDoesNotExist::new(&username));
+        }
+        self.aliases
+            .write()
+            .await
+            .retain(|_, target| *target != username);
+        Ok(())
+    }
+
+    async fn set_password(&self, username: &str, password: &str) -> Result<()> {
+        let username = self.resolve(username).await;
+        let password = Password::hash(self.hasher.clone(), password).await?;
+        match self.users.write().await.get_mut(&username) {
+            Some(user) => {
+                user.password_hash = password;
+                Ok(())
+            }
+            None => Err(UserDoesNotExist::new(&username)),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self.users.read().await.keys().cloned().collect();
+        names.sort();
+        Ok(names)
+    }
+
+    async fn add_alias(&self, alias: &str, username: &str) -> Result<()> {
+        let alias = self.normalization.canonical(alias);
+        let username = self.resolve(username).await;
+        let users = self.users.read().await;
+        if !users.contains_key(&username) {
+            return Err(UserDoesNotExist::new(&username));
+        }
+        let mut aliases = self.aliases.write().await;
+        if users.contains_key(&alias) || aliases.contains_key(&alias) {
+            return Err(UserAlreadyExists::new(&alias));
+        }
+        aliases.insert(alias, username);
+        Ok(())
+    }
+
+    async fn remove_alias(&self, alias: &str) -> Result<()> {
+        let alias = self.normalization.canonical(alias);
+        match self.aliases.write().await.remove(&alias) {
+            Some(_) => Ok(()),
+            None => Err(UserDoesNotExist::new(&alias)),
         }
     }
 }
 
+impl Default for InMemoryUserStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl InMemoryUserStore {
     pub fn new() -> Self {
         InMemoryUserStore {
-            users: HashMap::new(),
+            users: RwLock::new(HashMap::new()),
+            aliases: RwLock::new(HashMap::new()),
+            normalization: Normalization::default(),
+            hasher: Arc::new(Argon2id::default()),
+            dummy: OnceLock::new(),
+        }
+    }
+    /// Sets the hasher for new passwords. Stored hashes from another scheme, or with other
+    /// parameters, are re-hashed on the next successful login.
+    pub fn with_hasher<H: PasswordHasher + 'static>(mut self, hasher: H) -> Self {
+        self.hasher = Arc::new(hasher);
+        self.dummy = OnceLock::new();
+        self
+    }
+    /// Sets how usernames are canonicalized. Users added before keep the names they had.
+    pub fn with_normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+    /// The canonical name of `username`, following an alias to the account it names.
+    async fn resolve(&self, username: &str) -> String {
+        let username = self.normalization.canonical(username);
+        match self.aliases.read().await.get(&username) {
+            Some(target) => target.clone(),
+            None => username,
         }
     }
-    pub fn with_user(mut self, username: &str, password: &str) -> Self {
+    pub fn with_user(self, username: &str, password: &str) -> Self {
+        let password_hash = Password::with_hasher(self.hasher.as_ref(), password).unwrap();
         block_on(self.add(User {
             name: username.to_string(),
-            password_hash: Password::new(password).unwrap(),
+            password_hash,
+            impersonator: None,
+            anonymous: false,
         }))
         .unwrap();
         self
@@ -68,13 +188,21 @@ impl Authenticate for InMemoryAuthenticator {
     async fn authenticate(&self, user: Box<dyn AuthenticationPrincipal>) -> Result<User> {
         self.user_store.authenticate(user).await
     }
+    async fn canonical(&self, username: &str) -> String {
+        match self.user_store.canonical(username).await {
+            Ok(canonical) => canonical,
+            Err(..) => username.to_string(),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
 
-    use crate::auth::{Authenticate, BasicAuth};
+    use crate::auth::password::{Argon2id, Bcrypt};
+    use crate::auth::username::Normalization;
+    use crate::auth::{Authenticate, BasicAuth, User, UserStore};
 
     use super::{InMemoryAuthenticator, InMemoryUserStore};
 
@@ -88,4 +216,139 @@ mod tests {
             assert_eq!(result.name, "test@email.com");
         }
     }
+
+    #[async_std::test]
+    async fn test_rehash_on_login() {
+        let store = InMemoryUserStore::new()
+            .with_hasher(Bcrypt { cost: 4 })
+            .with_user("me", "password")
+            .with_hasher(Argon2id {
+                memory_kib: 1024,
+                iterations: 1,
+                parallelism: 1,
+            });
+        let stored = || async { store.get("me").await.unwrap().unwrap().password_hash.hash };
+        assert!(stored().await.starts_with("$2b$04$"));
+
+        assert!(store
+            .authenticate(Box::new(BasicAuth::from("me", "wrong")))
+            .await
+            .is_err());
+        assert!(stored().await.starts_with("$2b$04$"));
+
+        store
+            .authenticate(Box::new(BasicAuth::from("me", "password")))
+            .await
+            .unwrap();
+        assert!(stored().await.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+        assert!(store
+            .authenticate(Box::new(BasicAuth::from("me", "password")))
+            .await
+            .is_ok());
+    }
+
+    #[async_std::test]
+    async fn test_unknown_user_looks_like_bad_password() {
+        let store = InMemoryUserStore::new()
+            .with_hasher(Bcrypt { cost: 4 })
+            .with_user("me", "password");
+        let wrong_password = store
+            .authenticate(Box::new(BasicAuth::from("me", "wrong")))
+            .await
+            .unwrap_err();
+        assert!(store.dummy.get().is_none());
+        let unknown_user = store
+            .authenticate(Box::new(BasicAuth::from("you", "wrong")))
+            .await
+            .unwrap_err();
+        assert_eq!(wrong_password.to_string(), unknown_user.to_string());
+        // The unknown user was checked against a hash from the same hasher.
+        let dummy = store.dummy.get().unwrap().as_ref().unwrap();
+        assert!(dummy.hash.starts_with("$2b$04$"));
+    }
+
+    #[async_std::test]
+    async fn test_user_management() {
+        let store = InMemoryUserStore::new().with_hasher(Bcrypt { cost: 4 });
+        store.add(User::new("me", "password")).await.unwrap();
+        assert!(store.add(User::new("me", "other")).await.is_err());
+        store.add(User::new("you", "password")).await.unwrap();
+        assert_eq!(store.list().await.unwrap(), vec!["me", "you"]);
+
+        store.set_password("me", "changed").await.unwrap();
+        let login = |password: &str| store.authenticate(Box::new(BasicAuth::from("me", password)));
+        assert!(login("password").await.is_err());
+        assert!(login("changed").await.is_ok());
+        store.update(User::new("me", "updated")).await.unwrap();
+        assert!(login("updated").await.is_ok());
+
+        store.remove("me").await.unwrap();
+        assert!(store.remove("me").await.is_err());
+        assert!(store.set_password("me", "x").await.is_err());
+        assert!(store.update(User::new("me", "x")).await.is_err());
+        assert_eq!(store.list().await.unwrap(), vec!["you"]);
+    }
+
+    #[async_std::test]
+    async fn test_normalization_and_aliases() {
+        let store = InMemoryUserStore::new()
+            .with_hasher(Bcrypt { cost: 4 })
+            .with_normalization(Normalization::new().with_lowercase().with_subaddress('+'));
+        store
+            .add(User::new("Me@Example.COM", "password"))
+            .await
+            .unwrap();
+        assert_eq!(store.list().await.unwrap(), vec!["me@example.com"]);
+        assert!(store
+            .add(User::new("me+other@example.com", "x"))
+            .await
+            .is_err());
+        for name in ["Me@Example.COM", "me@example.com", "me+tag@example.com"] {
+            let user = store
+                .authenticate(Box::new(BasicAuth::from(name, "password")))
+                .await
+                .unwrap();
+            assert_eq!(user.name(), "me@example.com");
+        }
+
+        store
+            .add_alias("Boss@Example.com", "me+x@example.com")
+            .await
+            .unwrap();
+        let user = store.get("boss@example.com").await.unwrap().unwrap();
+        assert_eq!(user.name(), "me@example.com");
+        store
+            .set_password("boss@example.com", "changed")
+            .await
+            .unwrap();
+        assert!(store
+            .authenticate(Box::new(BasicAuth::from("boss@example.com", "changed")))
+            .await
+            .is_ok());
+        assert!(store
+            .add_alias("boss@example.com", "me@example.com")
+            .await
+            .is_err());
+        assert!(store
+            .add_alias("me@example.com", "me@example.com")
+            .await
+            .is_err());
+        assert!(store
+            .add_alias("other@example.com", "nobody@example.com")
+            .await
+            .is_err());
+        assert!(store.add(User::new("boss@example.com", "x")).await.is_err());
+
+        store.remove_alias("boss@example.com").await.unwrap();
+        assert!(store.get("boss@example.com").await.unwrap().is_none());
+        assert!(store.remove_alias("boss@example.com").await.is_err());
+
+        // Removing an account removes its aliases.
+        store
+            .add_alias("boss@example.com", "me@example.com")
+            .await
+            .unwrap();
+        store.remove("ME@example.com").await.unwrap();
+        assert!(store.get("boss@example.com").await.unwrap().is_none());
+    }
 }
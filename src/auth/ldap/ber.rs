@@ -0,0 +1,189 @@
+//! The subset of the Basic Encoding Rules (X.690) needed for LDAP messages.
+
+use std::io::Read;
+
+use crate::auth::error::LdapError;
+
+pub const BOOLEAN: u8 = 0x01;
+pub const INTEGER: u8 = 0x02;
+pub const OCTET_STRING: u8 = 0x04;
+pub const ENUMERATED: u8 = 0x0A;
+pub const SEQUENCE: u8 = 0x30;
+pub const SET: u8 = 0x31;
+
+/// Messages larger than this are rejected rather than buffered, so a hostile server cannot make
+/// us allocate without bound.
+const MAX_LENGTH: usize = 1024 * 1024;
+
+/// Encodes one element with a definite length.
+pub fn encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    let length = contents.len();
+    if length < 0x80 {
+        encoded.push(length as u8);
+    } else {
+        let bytes: Vec<u8> = length
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        encoded.push(0x80 | bytes.len() as u8);
+        encoded.extend(bytes);
+    }
+    encoded.extend_from_slice(contents);
+    encoded
+}
+
+pub fn integer(tag: u8, value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    // Drop leading bytes which only repeat the sign of the next byte.
+    let mut start = 0;
+    while start < 7
+        && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xFF && bytes[start + 1] & 0x80 != 0))
+    {
+        start += 1;
+    }
+    encode(tag, &bytes[start..])
+}
+
+pub fn string(value: &str) -> Vec<u8> {
+    encode(OCTET_STRING, value.as_bytes())
+}
+
+/// Encodes a constructed element from already encoded children.
+pub fn constructed(tag: u8, children: &[Vec<u8>]) -> Vec<u8> {
+    encode(tag, &children.concat())
+}
+
+/// A decoded element, borrowing its contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tlv<'a> {
+    pub tag: u8,
+    pub contents: &'a [u8],
+}
+
+impl<'a> Tlv<'a> {
+    /// Decodes the element at the start of `bytes`, returning it with the bytes after it.
+    pub fn decode(bytes: &'a [u8]) -> Result<(Self, &'a [u8]), LdapError> {
+        let (header, length) = header(bytes)?.ok_or_else(truncated)?;
+        let end = header.checked_add(length).ok_or_else(truncated)?;
+        if bytes.len() < end {
+            return Err(truncated());
+        }
+        let tlv = Tlv {
+            tag: bytes[0],
+            contents: &bytes[header..end],
+        };
+        Ok((tlv, &bytes[end..]))
+    }
+
+    /// Decodes the children of a constructed element.
+    pub fn children(&self) -> Result<Vec<Tlv<'a>>, LdapError> {
+        let mut children = vec![];
+        let mut rest = self.contents;
+        while !rest.is_empty() {
+            let (child, after) = Tlv::decode(rest)?;
+            children.push(child);
+            rest = after;
+        }
+        Ok(children)
+    }
+
+    pub fn integer(&self) -> Result<i64, LdapError> {
+        if self.contents.is_empty() || self.contents.len() > 8 {
+            return Err(LdapError::Protocol("invalid integer".to_string()));
+        }
+        let sign = if self.contents[0] & 0x80 != 0 { -1 } else { 0 };
+        Ok(self
+            .contents
+            .iter()
+            .fold(sign, |value, byte| (value << 8) | *byte as i64))
+    }
+
+    pub fn string(&self) -> String {
+        String::from_utf8_lossy(self.contents).into_owned()
+    }
+}
+
+fn truncated() -> LdapError {
+    LdapError::Protocol("truncated message".to_string())
+}
+
+/// Returns the header length and content length of the element at the start of `bytes`, or
+/// `None` if more bytes are needed to tell.
+fn header(bytes: &[u8]) -> Result<Option<(usize, usize)>, LdapError> {
+    let first = match bytes.get(1) {
+        Some(first) => *first,
+        None => return Ok(None),
+    };
+    if first & 0x80 == 0 {
+        return Ok(Some((2, first as usize)));
+    }
+    let count = (first & 0x7F) as usize;
+    if count == 0 || count > 4 {
+        return Err(LdapError::Protocol("unsupported length".to_string()));
+    }
+    let bytes = match bytes.get(2..2 + count) {
+        Some(bytes) => bytes,
+        None => return Ok(None),
+    };
+    let length = bytes
+        .iter()
+        .fold(0usize, |length, byte| (length << 8) | *byte as usize);
+    Ok(Some((2 + count, length)))
+}
+
+/// Reads exactly one element from `reader`.
+pub fn read<R: Read>(reader: &mut R) -> Result<Vec<u8>, LdapError> {
+    let mut message = vec![0; 2];
+    reader.read_exact(&mut message)?;
+    let (header, length) = loop {
+        match header(&message)? {
+            Some(lengths) => break lengths,
+            None => {
+                let mut byte = [0];
+                reader.read_exact(&mut byte)?;
+                message.push(byte[0]);
+            }
+        }
+    };
+    if length > MAX_LENGTH {
+        return Err(LdapError::Protocol(format!(
+            "message of {} bytes is too large",
+            length
+        )));
+    }
+    message.resize(header + length, 0);
+    reader.read_exact(&mut message[header..])?;
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{constructed, encode, integer, read, string, Tlv, INTEGER, SEQUENCE};
+
+    #[test]
+    fn test_round_trip() {
+        for value in [0, 1, 127, 128, 255, 256, -1, -128, -129, i64::MAX, i64::MIN] {
+            let encoded = integer(INTEGER, value);
+            let (tlv, rest) = Tlv::decode(&encoded).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(tlv.integer().unwrap(), value, "{:?}", encoded);
+        }
+        assert_eq!(integer(INTEGER, 128), vec![0x02, 0x02, 0x00, 0x80]);
+
+        let long = "x".repeat(300);
+        let message = constructed(SEQUENCE, &[integer(INTEGER, 3), string(&long)]);
+        assert_eq!(&message[..4], &[0x30, 0x82, 0x01, 0x33]);
+        let read_back = read(&mut &message[..]).unwrap();
+        assert_eq!(read_back, message);
+        let (sequence, _) = Tlv::decode(&read_back).unwrap();
+        let children = sequence.children().unwrap();
+        assert_eq!(children[0].integer().unwrap(), 3);
+        assert_eq!(children[1].string(), long);
+
+        assert!(Tlv::decode(&encode(SEQUENCE, b"abc")[..4]).is_err());
+        assert!(read(&mut &[0x30, 0x84, 0x7F, 0xFF, 0xFF, 0xFF][..]).is_err());
+    }
+}
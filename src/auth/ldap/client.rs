@@ -0,0 +1,435 @@
+//! A minimal synchronous LDAPv3 client (RFC 4511): simple bind, search and unbind.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use super::ber::{self, Tlv, BOOLEAN, ENUMERATED, INTEGER, SEQUENCE, SET};
+use crate::auth::error::LdapError;
+
+const BIND_REQUEST: u8 = 0x60;
+const BIND_RESPONSE: u8 = 0x61;
+const UNBIND_REQUEST: u8 = 0x42;
+const SEARCH_REQUEST: u8 = 0x63;
+const SEARCH_RESULT_ENTRY: u8 = 0x64;
+const SEARCH_RESULT_DONE: u8 = 0x65;
+const SEARCH_RESULT_REFERENCE: u8 = 0x73;
+const SIMPLE_AUTHENTICATION: u8 = 0x80;
+
+const SUCCESS: i64 = 0;
+const INVALID_CREDENTIALS: i64 = 49;
+
+/// Where an LDAP server listens, parsed from an `ldap://` or `ldaps://` URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LdapUrl {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+}
+
+impl LdapUrl {
+    pub fn parse(url: &str) -> Result<Self, LdapError> {
+        let invalid = || LdapError::Configuration(format!("invalid LDAP URL {}", url));
+        let (tls, rest) = match url.split_once("://") {
+            Some(("ldap", rest)) => (false, rest),
+            Some(("ldaps", rest)) => (true, rest),
+            _ => return Err(invalid()),
+        };
+        let authority = rest.split('/').next().unwrap_or_default();
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, if tls { 636 } else { 389 }),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            tls,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Base = 0,
+    Subtree = 2,
+}
+
+/// A search filter (RFC 4515). Substring and ordering matches are not supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Equal(String, Vec<u8>),
+    Present(String),
+}
+
+impl Filter {
+    pub fn parse(filter: &str) -> Result<Self, LdapError> {
+        let (parsed, rest) = Self::parse_one(filter.trim())?;
+        match rest.is_empty() {
+            true => Ok(parsed),
+            false => Err(LdapError::InvalidFilter(filter.to_string())),
+        }
+    }
+
+    fn parse_one(filter: &str) -> Result<(Self, &str), LdapError> {
+        let invalid = || LdapError::InvalidFilter(filter.to_string());
+        let inner = filter.strip_prefix('(').ok_or_else(invalid)?;
+        let (parsed, rest) = match inner.chars().next() {
+            Some(operator @ ('&' | '|')) => {
+                let mut filters = vec![];
+                let mut rest = &inner[1..];
+                while rest.starts_with('(') {
+                    let (filter, after) = Self::parse_one(rest)?;
+                    filters.push(filter);
+                    rest = after;
+                }
+                match operator {
+                    '&' => (Filter::And(filters), rest),
+                    _ => (Filter::Or(filters), rest),
+                }
+            }
+            Some('!') => {
+                let (filter, rest) = Self::parse_one(&inner[1..])?;
+                (Filter::Not(Box::new(filter)), rest)
+            }
+            _ => {
+                let end = inner.find(')').ok_or_else(invalid)?;
+                let (attribute, value) = inner[..end].split_once('=').ok_or_else(invalid)?;
+                if attribute.is_empty() || attribute.ends_with(['~', '<', '>', ':']) {
+                    return Err(invalid());
+                }
+                let filter = match value {
+                    "*" => Filter::Present(attribute.to_string()),
+                    _ if value.contains('*') => return Err(invalid()),
+                    _ => Filter::Equal(attribute.to_string(), unescape(value).ok_or_else(invalid)?),
+                };
+                (filter, &inner[end..])
+            }
+        };
+        let rest = rest.strip_prefix(')').ok_or_else(invalid)?;
+        Ok((parsed, rest))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Filter::And(filters) => ber::constructed(0xA0, &encode_all(filters)),
+            Filter::Or(filters) => ber::constructed(0xA1, &encode_all(filters)),
+            Filter::Not(filter) => ber::constructed(0xA2, &[filter.encode()]),
+            Filter::Equal(attribute, value) => ber::constructed(
+                0xA3,
+                &[
+                    ber::string(attribute),
+                    ber::encode(ber::OCTET_STRING, value),
+                ],
+            ),
+            Filter::Present(attribute) => ber::encode(0x87, attribute.as_bytes()),
+        }
+    }
+}
+
+fn encode_all(filters: &[Filter]) -> Vec<Vec<u8>> {
+    filters.iter().map(Filter::encode).collect()
+}
+
+/// Decodes the `\XX` escapes of a filter value.
+fn unescape(value: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut rest = value.as_bytes();
+    while let Some((byte, after)) = rest.split_first() {
+        if *byte == b'\\' {
+            let hex = std::str::from_utf8(after.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &after[2..];
+        } else {
+            bytes.push(*byte);
+            rest = after;
+        }
+    }
+    Some(bytes)
+}
+
+/// Escapes a value for use inside a filter (RFC 4515 section 3).
+pub fn escape_filter(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '*' | '(' | ')' | '\\' | '\u{0}' => escaped.push_str(&format!("\\{:02x}", c as u32)),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escapes a value for use as an attribute value in a DN (RFC 4514 section 2.4).
+pub fn escape_dn(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (position, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' | ' ' if position == 0 => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            ' ' if position == last => escaped.push_str("\\ "),
+            '\u{0}' => escaped.push_str("\\00"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// An entry returned by a search, with attribute names lower-cased.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Entry {
+    pub dn: String,
+    pub attributes: HashMap<String, Vec<String>>,
+}
+
+impl Entry {
+    pub fn first(&self, attribute: &str) -> Option<&str> {
+        self.attributes
+            .get(&attribute.to_ascii_lowercase())
+            .and_then(|values| values.first())
+            .map(String::as_str)
+    }
+}
+
+trait Stream: Read + Write + Send {}
+impl<S: Read + Write + Send> Stream for S {}
+
+pub struct Connection {
+    stream: Box<dyn Stream>,
+    next_id: i64,
+}
+
+impl Connection {
+    /// Connects to the server, applying `timeout` to the connection and to every read and write.
+    pub fn open(url: &LdapUrl, timeout: Duration) -> Result<Self, LdapError> {
+        let address = std::net::ToSocketAddrs::to_socket_addrs(&(url.host.as_str(), url.port))?
+            .next()
+            .ok_or_else(|| LdapError::Connection(format!("cannot resolve {}", url.host)))?;
+        let stream = TcpStream::connect_timeout(&address, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(Self {
+            stream: Self::secure(url, stream)?,
+            next_id: 1,
+        })
+    }
+
+    #[cfg(feature = "ldaps")]
+    fn secure(url: &LdapUrl, stream: TcpStream) -> Result<Box<dyn Stream>, LdapError> {
+        if !url.tls {
+            return Ok(Box::new(stream));
+        }
+        let connector =
+            native_tls::TlsConnector::new().map_err(|e| LdapError::Connection(e.to_string()))?;
+        let stream = connector
+            .connect(&url.host, stream)
+            .map_err(|e| LdapError::Connection(e.to_string()))?;
+        Ok(Box::new(stream))
+    }
+
+    #[cfg(not(feature = "ldaps"))]
+    fn secure(url: &LdapUrl, stream: TcpStream) -> Result<Box<dyn Stream>, LdapError> {
+        match url.tls {
+            true => Err(LdapError::Configuration(
+                "ldaps:// requires the ldaps feature".to_string(),
+            )),
+            false => Ok(Box::new(stream)),
+        }
+    }
+
+    fn send(&mut self, operation: Vec<u8>) -> Result<i64, LdapError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let message = ber::constructed(SEQUENCE, &[ber::integer(INTEGER, id), operation]);
+        self.stream.write_all(&message)?;
+        self.stream.flush()?;
+        Ok(id)
+    }
+
+    /// Reads the next message for `id`, returning its protocol operation.
+    fn receive(&mut self, id: i64) -> Result<(u8, Vec<u8>), LdapError> {
+        loop {
+            let message = ber::read(&mut self.stream)?;
+            let (envelope, _) = Tlv::decode(&message)?;
+            let children = envelope.children()?;
+            let (message_id, operation) = match children.as_slice() {
+                [message_id, operation, ..] if envelope.tag == SEQUENCE => (message_id, operation),
+                _ => return Err(LdapError::Protocol("malformed message".to_string())),
+            };
+            // Unsolicited notifications use message ID 0; anything else is not ours.
+            if message_id.integer()? != id {
+                continue;
+            }
+            return Ok((operation.tag, operation.contents.to_vec()));
+        }
+    }
+
+    /// Performs a simple bind, returning `false` if the server rejects the credentials.
+    pub fn bind(&mut self, dn: &str, password: &str) -> Result<bool, LdapError> {
+        let id = self.send(ber::constructed(
+            BIND_REQUEST,
+            &[
+                ber::integer(INTEGER, 3),
+                ber::string(dn),
+                ber::encode(SIMPLE_AUTHENTICATION, password.as_bytes()),
+            ],
+        ))?;
+        let (tag, contents) = self.receive(id)?;
+        if tag != BIND_RESPONSE {
+            return Err(LdapError::Protocol("expected a bind response".to_string()));
+        }
+        match result(&contents)? {
+            (SUCCESS, _) => Ok(true),
+            (INVALID_CREDENTIALS, _) => Ok(false),
+            (code, message) => Err(LdapError::Server { code, message }),
+        }
+    }
+
+    pub fn search(
+        &mut self,
+        base: &str,
+        scope: Scope,
+        filter: &Filter,
+        attributes: &[&str],
+    ) -> Result<Vec<Entry>, LdapError> {
+        let attributes: Vec<Vec<u8>> = attributes.iter().map(|a| ber::string(a)).collect();
+        let id = self.send(ber::constructed(
+            SEARCH_REQUEST,
+            &[
+                ber::string(base),
+                ber::integer(ENUMERATED, scope as i64),
+                ber::integer(ENUMERATED, 0),
+                ber::integer(INTEGER, 2),
+                ber::integer(INTEGER, 0),
+                ber::encode(BOOLEAN, &[0]),
+                filter.encode(),
+                ber::constructed(SEQUENCE, &attributes),
+            ],
+        ))?;
+        let mut entries = vec![];
+        loop {
+            let (tag, contents) = self.receive(id)?;
+            match tag {
+                SEARCH_RESULT_ENTRY => entries.push(entry(&contents)?),
+                SEARCH_RESULT_REFERENCE => {}
+                SEARCH_RESULT_DONE => {
+                    return match result(&contents)? {
+                        (SUCCESS, _) => Ok(entries),
+                        (code, message) => Err(LdapError::Server { code, message }),
+                    }
+                }
+                _ => {
+                    return Err(LdapError::Protocol(
+                        "unexpected search response".to_string(),
+                    ))
+                }
+            }
+        }
+    }
+
+    pub fn unbind(mut self) {
+        // The server closes the connection without replying, so errors are irrelevant.
+        let _ = self.send(ber::encode(UNBIND_REQUEST, &[]));
+    }
+}
+
+/// Decodes an `LDAPResult` into its result code and diagnostic message.
+fn result(contents: &[u8]) -> Result<(i64, String), LdapError> {
+    let result = Tlv {
+        tag: SEQUENCE,
+        contents,
+    };
+    match result.children()?.as_slice() {
+        [code, _matched, message, ..] => Ok((code.integer()?, message.string())),
+        _ => Err(LdapError::Protocol("malformed result".to_string())),
+    }
+}
+
+fn entry(contents: &[u8]) -> Result<Entry, LdapError> {
+    let malformed = || LdapError::Protocol("malformed search entry".to_string());
+    let entry = Tlv {
+        tag: SEQUENCE,
+        contents,
+    };
+    let children = entry.children()?;
+    let (dn, attributes) = match children.as_slice() {
+        [dn, attributes] => (dn.string(), attributes.children()?),
+        _ => return Err(malformed()),
+    };
+    let mut entry = Entry {
+        dn,
+        attributes: HashMap::new(),
+    };
+    for attribute in attributes {
+        let (name, values) = match attribute.children()?.as_slice() {
+            [name, values] if values.tag == SET => (name.string(), values.children()?),
+            _ => return Err(malformed()),
+        };
+        entry
+            .attributes
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .extend(values.iter().map(Tlv::string));
+    }
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_dn, escape_filter, Filter, LdapUrl};
+
+    #[test]
+    fn test_filters() {
+        assert_eq!(
+            Filter::parse("(&(objectClass=person)(|(uid=jo\\2ae)(!(mail=*))))").unwrap(),
+            Filter::And(vec![
+                Filter::Equal("objectClass".to_string(), b"person".to_vec()),
+                Filter::Or(vec![
+                    Filter::Equal("uid".to_string(), b"jo*e".to_vec()),
+                    Filter::Not(Box::new(Filter::Present("mail".to_string()))),
+                ]),
+            ])
+        );
+        for invalid in [
+            "uid=a",
+            "(uid=a",
+            "(uid=a*)",
+            "(uid>=a)",
+            "(=a)",
+            "(uid=a))",
+            "(uid=\\zz)",
+        ] {
+            assert!(Filter::parse(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(escape_filter("a*(b)\\"), "a\\2a\\28b\\29\\5c");
+        assert_eq!(escape_filter("José"), "José");
+        assert_eq!(escape_dn(" a,b=c "), "\\ a\\,b\\=c\\ ");
+    }
+
+    #[test]
+    fn test_urls() {
+        assert_eq!(
+            LdapUrl::parse("ldaps://ldap.example.com").unwrap(),
+            LdapUrl {
+                host: "ldap.example.com".to_string(),
+                port: 636,
+                tls: true
+            }
+        );
+        assert_eq!(LdapUrl::parse("ldap://127.0.0.1:3389/").unwrap().port, 3389);
+        assert!(LdapUrl::parse("http://example.com").is_err());
+    }
+}
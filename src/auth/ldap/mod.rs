@@ -0,0 +1,349 @@
+pub mod ber;
+pub mod client;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+
+use self::client::{escape_dn, escape_filter, Connection, Filter, LdapUrl, Scope};
+use super::error::{AuthenticationFailed, LdapError};
+use super::password::Argon2id;
+use super::{Authenticate, AuthenticationPrincipal, Password, User};
+use crate::runtime::spawn_blocking;
+use crate::util::Result;
+
+/// How the DN to bind as is found for a username.
+#[derive(Debug, Clone)]
+pub enum BindStrategy {
+    /// Binds directly as the DN formed by substituting the escaped username for `{username}`,
+    /// e.g. `uid={username},ou=people,dc=example,dc=com`.
+    Template(String),
+    /// Binds as a service account, searches the subtree under `base` for the single entry
+    /// matching `filter` (with `{username}` substituted, e.g. `(sAMAccountName={username})`),
+    /// then binds as that entry. This is the usual setup for Active Directory.
+    Search {
+        bind_dn: String,
+        bind_password: String,
+        base: String,
+        filter: String,
+    },
+}
+
+/// An `Authenticate` implementation which checks passwords by binding to an LDAP server.
+///
+/// Successful binds are cached for `cache_ttl` as a hash of the password, so repeated
+/// logins within the TTL do not reach the directory and the plaintext is never kept.
+pub struct LdapAuthenticator {
+    url: LdapUrl,
+    strategy: BindStrategy,
+    name_attribute: Option<String>,
+    timeout: Duration,
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<String, (User, Instant)>>,
+}
+
+impl LdapAuthenticator {
+    /// Creates an authenticator for the server at an `ldap://host[:port]` URL. `ldaps://` URLs
+    /// need the `ldaps` feature.
+    pub fn new(url: &str, strategy: BindStrategy) -> std::result::Result<Self, LdapError> {
+        Ok(Self {
+            url: LdapUrl::parse(url)?,
+            strategy,
+            name_attribute: None,
+            timeout: Duration::from_secs(10),
+            cache_ttl: Duration::from_secs(300),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+    /// Names the user after the first value of `attribute` (e.g. `mail`) instead of the login
+    /// name.
+    pub fn with_name_attribute(mut self, attribute: &str) -> Self {
+        self.name_attribute = Some(attribute.to_string());
+        self
+    }
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// A zero TTL disables the cache.
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    fn cached(&self, username: &str) -> Option<User> {
+        let mut cache = self.cache.lock().ok()?;
+        match cache.get(username) {
+            Some((user, expiry)) if *expiry > Instant::now() => Some(user.clone()),
+            Some(_) => {
+                cache.remove(username);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+/// Binds as `username`, returning the name to give the user, or `None` if the directory
+/// rejects the credentials or does not know the user.
+fn bind(
+    url: &LdapUrl,
+    timeout: Duration,
+    strategy: &BindStrategy,
+    name_attribute: Option<&str>,
+    username: &str,
+    password: &str,
+) -> std::result::Result<Option<String>, LdapError> {
+    let mut connection = Connection::open(url, timeout)?;
+    let attributes: Vec<&str> = name_attribute.into_iter().collect();
+    let (dn, entry) = match strategy {
+        BindStrategy::Template(template) => {
+            (template.replace("{username}", &escape_dn(username)), None)
+        }
+        BindStrategy::Search {
+            bind_dn,
+            bind_password,
+            base,
+            filter,
+        } => {
+            if !connection.bind(bind_dn, bind_password)? {
+                return Err(LdapError::Configuration(
+                    "the service account credentials were rejected".to_string(),
+                ));
+            }
+            let filter = Filter::parse(&filter.replace("{username}", &escape_filter(username)))?;
+            let mut entries = connection.search(base, Scope::Subtree, &filter, &attributes)?;
+            // An ambiguous match must not let someone log in as whichever entry came first.
+            if entries.len() != 1 {
+                connection.unbind();
+                return Ok(None);
+            }
+            let entry = entries.remove(0);
+            (entry.dn.clone(), Some(entry))
+        }
+    };
+    if !connection.bind(&dn, password)? {
+        connection.unbind();
+        return Ok(None);
+    }
+    let name = match (name_attribute, entry) {
+        (None, _) => None,
+        (Some(attribute), Some(entry)) => entry.first(attribute).map(str::to_string),
+        (Some(attribute), None) => {
+            let everything = Filter::Present("objectClass".to_string());
+            connection
+                .search(&dn, Scope::Base, &everything, &[attribute])?
+                .first()
+                .and_then(|entry| entry.first(attribute))
+                .map(str::to_string)
+        }
+    };
+    connection.unbind();
+    Ok(Some(name.unwrap_or_else(|| username.to_string())))
+}
+
+#[async_trait::async_trait]
+impl Authenticate for LdapAuthenticator {
+    async fn authenticate(&self, principal: Box<dyn AuthenticationPrincipal>) -> Result<User> {
+        let username = principal.principal();
+        // An empty password would be an unauthenticated bind, which servers report as success
+        // (RFC 4513 section 5.1.2).
+        let password = match principal.password() {
+            Some(password) if !password.is_empty() && !username.is_empty() => password,
+            _ => return Err(Box::new(AuthenticationFailed {})),
+        };
+        if let Some(user) = self.cached(&username) {
+            if principal.authenticate(&user).await.is_ok() {
+                return Ok(user);
+            }
+        }
+
+        let (url, timeout, strategy) = (self.url.clone(), self.timeout, self.strategy.clone());
+        let name_attribute = self.name_attribute.clone();
+        let (login, secret) = (username.clone(), password.clone());
+        let name = spawn_blocking(move || {
+            bind(
+                &url,
+                timeout,
+                &strategy,
+                name_attribute.as_deref(),
+                &login,
+                &secret,
+            )
+        })
+        .await?;
+        let name = match name {
+            Some(name) => name,
+            None => return Err(Box::new(AuthenticationFailed {})),
+        };
+        let user = User {
+            name,
+            password_hash: Password::hash(Arc::new(Argon2id::default()), &password).await?,
+            impersonator: None,
+            anonymous: false,
+        };
+        if !self.cache_ttl.is_zero() {
+            if let Ok(mut cache) = self.cache.lock() {
+                cache.insert(username, (user.clone(), Instant::now() + self.cache_ttl));
+            }
+        }
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::ber::{self, Tlv, ENUMERATED, INTEGER, SEQUENCE, SET};
+    use super::{BindStrategy, LdapAuthenticator};
+    use crate::auth::{Authenticate, BasicAuth};
+
+    const USER_DN: &str = "uid=jane,ou=people,dc=example,dc=com";
+
+    fn respond(id: i64, tag: u8, children: &[Vec<u8>]) -> Vec<u8> {
+        ber::constructed(
+            SEQUENCE,
+            &[ber::integer(INTEGER, id), ber::constructed(tag, children)],
+        )
+    }
+
+    fn result(code: i64) -> Vec<Vec<u8>> {
+        vec![
+            ber::integer(ENUMERATED, code),
+            ber::string(""),
+            ber::string(""),
+        ]
+    }
+
+    /// Serves a directory with a service account and one user, counting the binds it sees.
+    fn directory() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("ldap://{}", listener.local_addr().unwrap());
+        let binds = Arc::new(AtomicUsize::new(0));
+        let counter = binds.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                while let Ok(message) = ber::read(&mut stream) {
+                    let (envelope, _) = Tlv::decode(&message).unwrap();
+                    let children = envelope.children().unwrap();
+                    let id = children[0].integer().unwrap();
+                    let fields = children[1].children().unwrap();
+                    let response = match children[1].tag {
+                        0x60 => {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                            let credentials = (fields[1].string(), fields[2].string());
+                            let code = match (credentials.0.as_str(), credentials.1.as_str()) {
+                                ("cn=service", "service") | (USER_DN, "secret") => 0,
+                                _ => 49,
+                            };
+                            respond(id, 0x61, &result(code))
+                        }
+                        0x63 => {
+                            let entry = ber::constructed(
+                                0x64,
+                                &[
+                                    ber::string(USER_DN),
+                                    ber::constructed(
+                                        SEQUENCE,
+                                        &[ber::constructed(
+                                            SEQUENCE,
+                                            &[
+                                                ber::string("mail"),
+                                                ber::constructed(
+                                                    SET,
+                                                    &[ber::string("jane@example.com")],
+                                                ),
+                                            ],
+                                        )],
+                                    ),
+                                ],
+                            );
+                            // Only `uid=jane` exists for subtree searches, whose filter is
+                            // `(&(objectClass=person)(uid=...))`.
+                            let base = fields[1].integer().unwrap() == 0;
+                            let uid = fields[6].children().unwrap()[1].children().unwrap()[1];
+                            let mut response = vec![];
+                            if base || uid.contents == b"jane" {
+                                response =
+                                    ber::constructed(SEQUENCE, &[ber::integer(INTEGER, id), entry]);
+                            }
+                            response.extend(respond(id, 0x65, &result(0)));
+                            response
+                        }
+                        _ => break,
+                    };
+                    stream.write_all(&response).unwrap();
+                }
+            }
+        });
+        (address, binds)
+    }
+
+    #[async_std::test]
+    async fn test_search_and_bind() {
+        let (address, binds) = directory();
+        let authenticator = LdapAuthenticator::new(
+            &address,
+            BindStrategy::Search {
+                bind_dn: "cn=service".to_string(),
+                bind_password: "service".to_string(),
+                base: "dc=example,dc=com".to_string(),
+                filter: "(&(objectClass=person)(uid={username}))".to_string(),
+            },
+        )
+        .unwrap()
+        .with_name_attribute("mail")
+        .with_timeout(Duration::from_secs(5));
+
+        let user = authenticator
+            .authenticate(Box::new(BasicAuth::from("jane", "secret")))
+            .await
+            .unwrap();
+        assert_eq!(user.name(), "jane@example.com");
+        assert_eq!(binds.load(Ordering::SeqCst), 2);
+
+        // The second login is served from the cache.
+        assert!(authenticator
+            .authenticate(Box::new(BasicAuth::from("jane", "secret")))
+            .await
+            .is_ok());
+        assert_eq!(binds.load(Ordering::SeqCst), 2);
+
+        for (username, password) in [("jane", "wrong"), ("john", "secret"), ("jane", "")] {
+            assert!(authenticator
+                .authenticate(Box::new(BasicAuth::from(username, password)))
+                .await
+                .is_err());
+        }
+    }
+
+    #[async_std::test]
+    async fn test_template_bind() {
+        let (address, binds) = directory();
+        let authenticator = LdapAuthenticator::new(
+            &address,
+            BindStrategy::Template("uid={username},ou=people,dc=example,dc=com".to_string()),
+        )
+        .unwrap()
+        .with_cache_ttl(Duration::ZERO);
+        for _ in 0..2 {
+            let user = authenticator
+                .authenticate(Box::new(BasicAuth::from("jane", "secret")))
+                .await
+                .unwrap();
+            assert_eq!(user.name(), "jane");
+        }
+        assert_eq!(binds.load(Ordering::SeqCst), 2);
+        assert!(authenticator
+            .authenticate(Box::new(BasicAuth::from("jane,ou=admins", "secret")))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use log::{info, warn};
+
+use super::error::AuthenticationFailed;
+use super::{Authenticate, AuthenticationPrincipal, User, UserStore};
+use crate::util::Result;
+
+/// The users allowed to log in as anyone else, and how they do so.
+#[derive(Debug, Clone)]
+pub struct MasterUsers {
+    names: HashSet<String>,
+    separator: char,
+}
+
+impl MasterUsers {
+    pub fn new(names: &[&str]) -> Self {
+        Self {
+            names: names.iter().map(|name| name.to_string()).collect(),
+            separator: '*',
+        }
+    }
+    /// The character between the master user and the user to log in as, `*` by default.
+    pub fn with_separator(mut self, separator: char) -> Self {
+        self.separator = separator;
+        self
+    }
+}
+
+/// Lets a master user log in as `master*user` with their own password and act as `user`, for
+/// migrations and debugging. Every other login is passed through to the wrapped authenticator.
+///
+/// The session's user is the account `user` names in the user store, with
+/// `User::impersonator` naming the master user, and logins as someone the store does not know
+/// fail. Each attempt is logged to the `audit` log target.
+pub struct MasterUserAuthenticator {
+    authenticator: Box<dyn Authenticate>,
+    user_store: Arc<Box<dyn UserStore>>,
+    masters: MasterUsers,
+}
+
+impl MasterUserAuthenticator {
+    pub fn new(
+        authenticator: Box<dyn Authenticate>,
+        user_store: Arc<Box<dyn UserStore>>,
+        masters: MasterUsers,
+    ) -> Self {
+        Self {
+            authenticator,
+            user_store,
+            masters,
+        }
+    }
+
+    /// Whether `name`, as the user store names accounts, is one of the master users.
+    async fn is_master(&self, name: &str) -> bool {
+        for master in &self.masters.names {
+            let canonical = self.user_store.canonical(master).await;
+            if canonical.is_ok_and(|canonical| canonical == name) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// The master user's half of a `master*user` login, checked with the same credentials.
+struct Master {
+    name: String,
+    principal: Box<dyn AuthenticationPrincipal>,
+}
+
+#[async_trait::async_trait]
+impl AuthenticationPrincipal for Master {
+    fn principal(&self) -> String {
+        self.name.clone()
+    }
+    async fn authenticate(&self, user: &User) -> Result<()> {
+        self.principal.authenticate(user).await
+    }
+    fn password(&self) -> Option<String> {
+        self.principal.password()
+    }
+}
+
+#[async_trait::async_trait]
+impl Authenticate for MasterUserAuthenticator {
+    async fn authenticate(&self, principal: Box<dyn AuthenticationPrincipal>) -> Result<User> {
+        let login = principal.principal();
+        let (master, target) = match login.split_once(self.masters.separator) {
+            Some((master, target)) if !master.is_empty() && !target.is_empty() => {
+                (master.to_string(), target.to_string())
+            }
+            _ => return self.authenticator.authenticate(principal).await,
+        };
+        let master = self
+            .authenticator
+            .authenticate(Box::new(Master {
+                name: master,
+                principal,
+            }))
+            .await?;
+        if !self.is_master(&master.name).await {
+            warn!(target: "audit", "{} tried to log in as {} but is not a master user", master.name, target);
+            return Err(Box::new(AuthenticationFailed {}));
+        }
+        let target = match self.user_store.get(&target).await {
+            Ok(Some(user)) => user.name,
+            Ok(None) | Err(..) => {
+                warn!(
+                    target: "audit",
+                    "master user {} tried to log in as unknown user {}",
+                    master.name, target
+                );
+                return Err(Box::new(AuthenticationFailed {}));
+            }
+        };
+        info!(target: "audit", "master user {} logged in as {}", master.name, target);
+        Ok(User {
+            name: target,
+            password_hash: master.password_hash,
+            impersonator: Some(master.name),
+            anonymous: false,
+        })
+    }
+    /// The canonical name of the master user for a `master*user` login, as it is their
+    /// password which is checked.
+    async fn canonical(&self, username: &str) -> String {
+        match username.split_once(self.masters.separator) {
+            Some((master, target)) if !master.is_empty() && !target.is_empty() => {
+                self.authenticator.canonical(master).await
+            }
+            _ => self.authenticator.canonical(username).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{MasterUserAuthenticator, MasterUsers};
+    use crate::auth::inmemory::{InMemoryAuthenticator, InMemoryUserStore};
+    use crate::auth::password::Bcrypt;
+    use crate::auth::username::Normalization;
+    use crate::auth::{Authenticate, BasicAuth, UserStore};
+
+    fn authenticator(masters: MasterUsers) -> MasterUserAuthenticator {
+        let store = InMemoryUserStore::new()
+            .with_hasher(Bcrypt { cost: 4 })
+            .with_normalization(Normalization::new().with_lowercase())
+            .with_user("admin", "secret")
+            .with_user("jane", "password");
+        let store: Arc<Box<dyn UserStore>> = Arc::new(Box::new(store));
+        let inner = InMemoryAuthenticator::new(store.clone());
+        MasterUserAuthenticator::new(Box::new(inner), store, masters)
+    }
+
+    #[async_std::test]
+    async fn test_impersonation() {
+        let authenticator = authenticator(MasterUsers::new(&["admin"]));
+        let login = |name: &str, password: &str| {
+            authenticator.authenticate(Box::new(BasicAuth::from(name, password)))
+        };
+
+        let user = login("admin*jane", "secret").await.unwrap();
+        assert_eq!(user.name(), "jane");
+        assert_eq!(user.impersonator(), Some("admin"));
+
+        let user = login("jane", "password").await.unwrap();
+        assert_eq!(user.impersonator(), None);
+        let user = login("admin", "secret").await.unwrap();
+        assert_eq!(user.name(), "admin");
+
+        // The master user's own password is needed, and only master users may impersonate.
+        assert!(login("admin*jane", "password").await.is_err());
+        assert!(login("jane*admin", "password").await.is_err());
+        assert!(login("*jane", "secret").await.is_err());
+        // Only users the store knows may be impersonated.
+        assert!(login("admin*nobody", "secret").await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_canonical_names() {
+        let authenticator = authenticator(MasterUsers::new(&["Admin"]));
+        let user = authenticator
+            .authenticate(Box::new(BasicAuth::from("ADMIN*Jane", "secret")))
+            .await
+            .unwrap();
+        assert_eq!(user.name(), "jane");
+        assert_eq!(user.impersonator(), Some("admin"));
+    }
+
+    #[async_std::test]
+    async fn test_separator() {
+        let authenticator = authenticator(MasterUsers::new(&["admin"]).with_separator('%'));
+        let user = authenticator
+            .authenticate(Box::new(BasicAuth::from("admin%jane", "secret")))
+            .await
+            .unwrap();
+        assert_eq!(user.name(), "jane");
+        assert!(authenticator
+            .authenticate(Box::new(BasicAuth::from("admin*jane", "secret")))
+            .await
+            .is_err());
+    }
+}
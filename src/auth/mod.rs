@@ -1,62 +1,227 @@
 pub mod inmemory;
 pub mod error;
+pub mod ldap;
+pub mod master;
+pub mod password;
+pub mod provision;
+pub mod sqlite;
+pub mod throttle;
+pub mod username;
+
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 use futures::channel::oneshot::Sender;
 
-use bcrypt::{DEFAULT_COST, hash_with_result, BcryptError, verify, Version};
 use log::error;
 
+use crate::runtime::spawn_blocking;
 use crate::util::Result;
 
-use self::error::AuthenticationFailed;
+use self::error::{AuthenticationFailed, PasswordError};
+use self::password::{verify, Argon2id, PasswordHasher};
 
 #[async_trait::async_trait]
 pub trait Authenticate: Send + Sync {
     async fn authenticate(&self, user: Box<dyn AuthenticationPrincipal>) -> Result<User>;
+    /// The name of the account a login as `username` is checked against, once case,
+    /// sub-addresses and aliases are accounted for. Logins are throttled by it.
+    async fn canonical(&self, username: &str) -> String {
+        username.to_string()
+    }
+    /// Authenticates a login from `peer`, returning how long to wait before answering it if
+    /// it failed. Only `throttle::ThrottledAuthenticator` makes anyone wait.
+    async fn login(
+        &self,
+        principal: Box<dyn AuthenticationPrincipal>,
+        _peer: Option<IpAddr>,
+    ) -> std::result::Result<User, Duration> {
+        self.authenticate(principal)
+            .await
+            .map_err(|_| Duration::ZERO)
+    }
 }
+/// Accounts and their password hashes. Stores canonicalize every username they are given with
+/// their `username::Normalization`, and `authenticate`, `get` and `set_password` also follow
+/// aliases to the account they name.
 #[async_trait::async_trait]
 pub trait UserStore: Sync + Send {
     async fn authenticate(&self, principal: Box<dyn AuthenticationPrincipal>) -> Result<User>;
-    async fn get(&self, username: &str) -> Result<Option<&User>>;
-    async fn add(&mut self, user: User) -> Result<()>;
+    async fn get(&self, username: &str) -> Result<Option<User>>;
+    /// The name of the account `username` names, whether or not it exists.
+    async fn canonical(&self, username: &str) -> Result<String> {
+        Ok(username.to_string())
+    }
+    /// Adds a new user, failing if one with the same name exists.
+    async fn add(&self, user: User) -> Result<()>;
+    /// Replaces the stored record of an existing user, failing if they do not exist.
+    async fn update(&self, user: User) -> Result<()>;
+    /// Deletes a user, failing if they do not exist.
+    async fn remove(&self, username: &str) -> Result<()>;
+    /// Hashes `password` with the store's hasher and makes it the password of an existing user.
+    async fn set_password(&self, username: &str, password: &str) -> Result<()>;
+    /// The names of every user, in order.
+    async fn list(&self) -> Result<Vec<String>>;
+    /// Makes `alias` another name for the existing user `username`, for logins and lookups.
+    async fn add_alias(&self, alias: &str, username: &str) -> Result<()>;
+    async fn remove_alias(&self, alias: &str) -> Result<()>;
 }
 
 #[derive(Debug, Clone)]
 pub struct User {
     name: String,
     password_hash: Password,
+    /// The master user who logged in as this user, if any. See `master::MasterUserAuthenticator`.
+    impersonator: Option<String>,
+    /// Whether this is a guest session from the ANONYMOUS mechanism, named after the
+    /// public namespace it may read.
+    anonymous: bool,
 }
 
 impl User {
     pub fn new(username: &str, password: &str) -> Self {
-        User { name: username.to_string(), password_hash: Password::new(password).unwrap() }
+        User {
+            name: username.to_string(),
+            password_hash: Password::new(password).unwrap(),
+            impersonator: None,
+            anonymous: false,
+        }
+    }
+    /// A guest with read-only access to the mailboxes of `namespace`. It has no password, so
+    /// it can never log in with one.
+    pub fn anonymous(namespace: &str) -> Self {
+        User {
+            name: namespace.to_string(),
+            password_hash: Password { hash: String::new() },
+            impersonator: None,
+            anonymous: true,
+        }
+    }
+    /// A user the server vouches for without a password, such as the one connections to a
+    /// PREAUTH listener are logged in as. It has no password, so it can never log in with one.
+    pub fn trusted(username: &str) -> Self {
+        User {
+            name: username.to_string(),
+            password_hash: Password { hash: String::new() },
+            impersonator: None,
+            anonymous: false,
+        }
     }
     pub fn name(&self) -> String {
         self.name.clone()
     }
+    pub fn impersonator(&self) -> Option<&str> {
+        self.impersonator.as_deref()
+    }
+    pub fn is_anonymous(&self) -> bool {
+        self.anonymous
+    }
 }
 
+/// A self-describing password hash: argon2id in PHC string format or bcrypt in its modular
+/// crypt format. See `password::verify`.
 #[derive(Debug, Clone)]
 pub struct Password {
     hash: String,
-    _salt: String,
-    _cost: u32
 }
 
 impl Password {
-    pub fn new(password: &str) -> std::result::Result<Self, BcryptError> {
-        hash_with_result(password, DEFAULT_COST).map(|hash| Password{
-            hash: hash.format_for_version(Version::TwoB),
-            _salt: hash.get_salt(),
-            _cost: hash.get_cost(),
-        })
+    /// Hashes `password` with the default hasher, argon2id.
+    pub fn new(password: &str) -> std::result::Result<Self, PasswordError> {
+        Self::with_hasher(&Argon2id::default(), password)
+    }
+
+    pub fn with_hasher(
+        hasher: &dyn PasswordHasher,
+        password: &str,
+    ) -> std::result::Result<Self, PasswordError> {
+        Ok(Password { hash: hasher.hash(password)? })
+    }
+
+    /// Wraps an existing hash, e.g. one read back from a database.
+    fn from_hash(hash: &str) -> std::result::Result<Self, PasswordError> {
+        if !hash.starts_with('$') {
+            return Err(PasswordError::Malformed(hash.to_string()));
+        }
+        Ok(Password { hash: hash.to_string() })
+    }
+
+    pub fn verify(&self, password: &str) -> std::result::Result<bool, PasswordError> {
+        verify(password, &self.hash)
+    }
+
+    /// Hashes `password` with `hasher` on the blocking thread pool. Hashing takes tens of
+    /// milliseconds by design, which would hold up every other task on an executor thread.
+    pub async fn hash(
+        hasher: Arc<dyn PasswordHasher>,
+        password: &str,
+    ) -> std::result::Result<Self, PasswordError> {
+        let password = password.to_string();
+        spawn_blocking(move || Self::with_hasher(hasher.as_ref(), &password)).await
+    }
+
+    /// Checks `password` against the hash on the blocking thread pool, see `hash`.
+    pub async fn check(&self, password: &str) -> std::result::Result<bool, PasswordError> {
+        let (password, hash) = (password.to_string(), self.hash.clone());
+        spawn_blocking(move || verify(&password, &hash)).await
+    }
+}
+
+/// After a successful login, hashes the password again when the stored hash was made with
+/// another scheme or other parameters than `hasher`, so stores can upgrade it transparently.
+async fn rehash(
+    hasher: &Arc<dyn PasswordHasher>,
+    principal: &dyn AuthenticationPrincipal,
+    user: &User,
+) -> Option<Password> {
+    if !hasher.needs_rehash(&user.password_hash.hash) {
+        return None;
+    }
+    let password = principal.password()?;
+    match Password::hash(hasher.clone(), &password).await {
+        Ok(password) => Some(password),
+        Err(e) => {
+            error!("could not re-hash the password of {}: {}", user.name, e);
+            None
+        }
     }
 }
 
+/// Rejects a login for a username the store does not know. The password is still verified,
+/// against a throwaway hash made with `hasher` on first use, so an unknown username takes as
+/// long to reject as a wrong password, and it fails with the same error.
+async fn reject_unknown(
+    hasher: &Arc<dyn PasswordHasher>,
+    dummy: &OnceLock<Option<Password>>,
+    principal: &dyn AuthenticationPrincipal,
+) -> Box<dyn Error + Send + Sync> {
+    if dummy.get().is_none() {
+        // Concurrent first rejections may each make one; only the first is kept.
+        let _ = dummy.set(Password::hash(hasher.clone(), "not a password").await.ok());
+    }
+    let dummy = dummy.get().and_then(Option::as_ref);
+    if let Some(password_hash) = dummy {
+        let user = User {
+            name: principal.principal(),
+            password_hash: password_hash.clone(),
+            impersonator: None,
+            anonymous: false,
+        };
+        let _ = principal.authenticate(&user).await;
+    }
+    Box::new(AuthenticationFailed {})
+}
+
 #[async_trait::async_trait]
 pub trait AuthenticationPrincipal: Send + Sync {
     fn principal(&self) -> String;
     async fn authenticate(&self, user: &User) -> Result<()>;
+    /// The plaintext password, for authenticators which check it against an external service.
+    fn password(&self) -> Option<String> {
+        None
+    }
 }
 
 #[derive(Debug)]
@@ -69,8 +234,11 @@ impl AuthenticationPrincipal for BasicAuth{
     fn principal(&self) -> String {
         self.username.clone()
     }
+    fn password(&self) -> Option<String> {
+        Some(self.password.clone())
+    }
     async fn authenticate(&self,user: &User) -> Result<()> {
-        match verify(&self.password, &user.password_hash.hash) {
+        match user.password_hash.check(&self.password).await {
             Ok(success) => {
                 if success {
                     return Ok(())
@@ -98,6 +266,9 @@ pub struct AuthRequest<T> {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
+    use super::password::Bcrypt;
     use super::{User, Password, BasicAuth, AuthenticationPrincipal};
 
     #[async_std::test]
@@ -105,6 +276,8 @@ mod tests {
         let user = User{
             name: "me".to_string(),
             password_hash: Password::new("password").unwrap(),
+            impersonator: None,
+            anonymous: false,
         };
         let auth = BasicAuth::from("me", "password");
         assert!(auth.authenticate(&user).await.is_ok());
@@ -114,8 +287,17 @@ mod tests {
         let user = User{
             name: "me".to_string(),
             password_hash: Password::new("password").unwrap(),
+            impersonator: None,
+            anonymous: false,
         };
         let auth = BasicAuth::from("me", "password2");
         assert!(auth.authenticate(&user).await.is_err());
     }
-}
\ No newline at end of file
+    #[async_std::test]
+    async fn test_hash_and_check_off_the_executor() {
+        let password = Password::hash(Arc::new(Bcrypt { cost: 4 }), "password").await.unwrap();
+        assert!(password.hash.starts_with("$2b$04$"));
+        assert!(password.check("password").await.unwrap());
+        assert!(!password.check("password2").await.unwrap());
+    }
+}
@@ -0,0 +1,151 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, SaltString};
+use argon2::{Algorithm, Argon2, Params, PasswordVerifier, Version};
+
+use super::error::PasswordError;
+
+/// A password hashing scheme. Hashes are self-describing strings, so any stored hash can be
+/// verified with `verify` whichever hasher is configured now, and `needs_rehash` tells when a
+/// stored hash should be upgraded to the current scheme and parameters.
+pub trait PasswordHasher: Send + Sync {
+    fn hash(&self, password: &str) -> Result<String, PasswordError>;
+    /// Whether `hash` was produced by another scheme or with other parameters than this hasher
+    /// would use.
+    fn needs_rehash(&self, hash: &str) -> bool;
+}
+
+/// bcrypt, stored in its modular crypt format, e.g. `$2b$12$<salt><digest>`.
+#[derive(Debug, Clone, Copy)]
+pub struct Bcrypt {
+    pub cost: u32,
+}
+
+impl Default for Bcrypt {
+    fn default() -> Self {
+        Self {
+            cost: bcrypt::DEFAULT_COST,
+        }
+    }
+}
+
+impl PasswordHasher for Bcrypt {
+    fn hash(&self, password: &str) -> Result<String, PasswordError> {
+        bcrypt::hash_with_result(password, self.cost)
+            .map(|hash| hash.format_for_version(bcrypt::Version::TwoB))
+            .map_err(|e| PasswordError::Hashing(e.to_string()))
+    }
+    fn needs_rehash(&self, hash: &str) -> bool {
+        match hash.split('$').collect::<Vec<_>>().as_slice() {
+            ["", "2b", cost, _] => cost.parse::<u32>().ok() != Some(self.cost),
+            _ => true,
+        }
+    }
+}
+
+/// Argon2id (RFC 9106), stored in PHC string format, e.g.
+/// `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<digest>`. The defaults follow the OWASP
+/// recommendation of 19 MiB of memory, two iterations and one lane.
+#[derive(Debug, Clone, Copy)]
+pub struct Argon2id {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for Argon2id {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl Argon2id {
+    fn argon2(&self) -> Result<Argon2<'static>, PasswordError> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|e| PasswordError::Hashing(e.to_string()))?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+}
+
+impl PasswordHasher for Argon2id {
+    fn hash(&self, password: &str) -> Result<String, PasswordError> {
+        let salt = SaltString::generate(&mut OsRng);
+        argon2::PasswordHasher::hash_password(&self.argon2()?, password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| PasswordError::Hashing(e.to_string()))
+    }
+    fn needs_rehash(&self, hash: &str) -> bool {
+        let hash = match PasswordHash::new(hash) {
+            Ok(hash) => hash,
+            Err(_) => return true,
+        };
+        let params = match Params::try_from(&hash) {
+            Ok(params) => params,
+            Err(_) => return true,
+        };
+        hash.algorithm != Algorithm::Argon2id.ident()
+            || hash.version != Some(Version::V0x13.into())
+            || params.m_cost() != self.memory_kib
+            || params.t_cost() != self.iterations
+            || params.p_cost() != self.parallelism
+    }
+}
+
+/// Checks `password` against a hash from any supported scheme.
+pub fn verify(password: &str, hash: &str) -> Result<bool, PasswordError> {
+    if hash.starts_with("$argon2") {
+        let parsed =
+            PasswordHash::new(hash).map_err(|_| PasswordError::Malformed(hash.to_string()))?;
+        // The algorithm and parameters are read from the hash itself.
+        return Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok());
+    }
+    if ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+    {
+        return bcrypt::verify(password, hash)
+            .map_err(|_| PasswordError::Malformed(hash.to_string()));
+    }
+    Err(PasswordError::Malformed(hash.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify, Argon2id, Bcrypt, PasswordHasher};
+
+    #[test]
+    fn test_hashers() {
+        let argon2 = Argon2id {
+            memory_kib: 1024,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let bcrypt = Bcrypt { cost: 4 };
+        for hasher in [&argon2 as &dyn PasswordHasher, &bcrypt] {
+            let hash = hasher.hash("password").unwrap();
+            assert!(verify("password", &hash).unwrap());
+            assert!(!verify("wrong", &hash).unwrap());
+            assert!(!hasher.needs_rehash(&hash));
+        }
+
+        let weak = bcrypt.hash("password").unwrap();
+        assert!(weak.starts_with("$2b$04$"));
+        assert!(argon2.needs_rehash(&weak));
+        assert!(Bcrypt { cost: 5 }.needs_rehash(&weak));
+
+        let hash = argon2.hash("password").unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+        assert!(bcrypt.needs_rehash(&hash));
+        let stronger = Argon2id {
+            iterations: 2,
+            ..argon2
+        };
+        assert!(stronger.needs_rehash(&hash));
+        assert!(verify("password", "plaintext").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use async_lock::RwLock;
+use log::{info, warn};
+
+use super::{Authenticate, AuthenticationPrincipal, User};
+use crate::index::{Index, Mailbox, MailboxError, Owner, Permission};
+use crate::util::Result;
+
+/// The folders of RFC 6154 which clients look for, by their usual names.
+pub const SPECIAL_USE_FOLDERS: [&str; 5] = ["Archive", "Drafts", "Junk", "Sent", "Trash"];
+
+/// The mailboxes every account starts with. INBOX is always one of them.
+#[derive(Debug, Clone)]
+pub struct Provisioning {
+    folders: Vec<String>,
+}
+
+impl Default for Provisioning {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Provisioning {
+    pub fn new() -> Self {
+        Self {
+            folders: vec!["INBOX".to_string()],
+        }
+    }
+    pub fn with_folder(mut self, name: &str) -> Self {
+        if !self.folders.iter().any(|folder| folder == name) {
+            self.folders.push(name.to_string());
+        }
+        self
+    }
+    /// Adds Archive, Drafts, Junk, Sent and Trash.
+    pub fn with_special_use_folders(self) -> Self {
+        SPECIAL_USE_FOLDERS
+            .iter()
+            .fold(self, |provisioning, name| provisioning.with_folder(name))
+    }
+}
+
+/// Creates the mailboxes of `Provisioning` for a user the first time they log in, so that
+/// accounts which only exist in an external authenticator such as LDAP can be used at once.
+///
+/// Mailboxes which already exist are left alone, and each user is only provisioned once while
+/// the server runs. Guests from AUTHENTICATE ANONYMOUS are never provisioned. A login still
+/// succeeds when provisioning fails; it is retried on the next one.
+pub struct ProvisioningAuthenticator {
+    authenticator: Box<dyn Authenticate>,
+    index: Arc<Box<dyn Index>>,
+    provisioning: Provisioning,
+    provisioned: RwLock<HashSet<Owner>>,
+}
+
+impl ProvisioningAuthenticator {
+    pub fn new(
+        authenticator: Box<dyn Authenticate>,
+        index: Arc<Box<dyn Index>>,
+        provisioning: Provisioning,
+    ) -> Self {
+        Self {
+            authenticator,
+            index,
+            provisioning,
+            provisioned: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// Creates whichever of the mailboxes are missing, returning how many were.
+    async fn provision(&self, owner: &Owner) -> std::result::Result<usize, MailboxError> {
+        let mut created = 0;
+        for name in &self.provisioning.folders {
+            let existing = self
+                .index
+                .get_mailbox(owner, name, Permission::ReadOnly)
+                .await;
+            match existing {
+                Ok(..) => {}
+                Err(MailboxError::DoesNotExist(..)) => {
+                    let mailbox = Mailbox::new(name, 0, vec![], Permission::ReadWrite);
+                    match self.index.add_mailbox(owner, mailbox).await {
+                        Ok(()) => created += 1,
+                        // Another session of the same user got there first.
+                        Err(MailboxError::Exists(..)) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(created)
+    }
+}
+
+#[async_trait::async_trait]
+impl Authenticate for ProvisioningAuthenticator {
+    async fn authenticate(&self, principal: Box<dyn AuthenticationPrincipal>) -> Result<User> {
+        let user = self.authenticator.authenticate(principal).await?;
+        let owner = Owner::from(&user);
+        if user.is_anonymous() || self.provisioned.read().await.contains(&owner) {
+            return Ok(user);
+        }
+        match self.provision(&owner).await {
+            Ok(created) => {
+                if created > 0 {
+                    info!("Provisioned {} mailboxes for {}", created, owner);
+                }
+                self.provisioned.write().await.insert(owner);
+            }
+            Err(e) => warn!("Could not provision the mailboxes of {}: {}", owner, e),
+        }
+        Ok(user)
+    }
+    async fn canonical(&self, username: &str) -> String {
+        self.authenticator.canonical(username).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Provisioning, ProvisioningAuthenticator};
+    use crate::auth::inmemory::{InMemoryAuthenticator, InMemoryUserStore};
+    use crate::auth::password::Bcrypt;
+    use crate::auth::{Authenticate, AuthenticationPrincipal, BasicAuth, User};
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::message::MessageRecord;
+    use crate::index::{Index, Mailbox, Owner, Permission};
+    use crate::util::Result;
+
+    /// Lets anyone in as a guest.
+    struct Guests;
+
+    #[async_trait::async_trait]
+    impl Authenticate for Guests {
+        async fn authenticate(&self, _: Box<dyn AuthenticationPrincipal>) -> Result<User> {
+            Ok(User::anonymous("public"))
+        }
+    }
+
+    #[async_std::test]
+    async fn test_provisioning() {
+        let store = InMemoryUserStore::new()
+            .with_hasher(Bcrypt { cost: 4 })
+            .with_user("jane", "password");
+        let inner = InMemoryAuthenticator::new(Arc::new(Box::new(store)));
+        let index: Arc<Box<dyn Index>> = Arc::new(Box::new(InMemoryIndex::new()));
+        let jane = Owner::new("jane");
+        let sent = Mailbox::new("Sent", 0, vec![], Permission::ReadWrite);
+        index.add_mailbox(&jane, sent).await.unwrap();
+        let record = MessageRecord::new(1, 10, std::time::SystemTime::now());
+        index.add_message(&jane, "Sent", record).await.unwrap();
+
+        let provisioning = Provisioning::new()
+            .with_special_use_folders()
+            .with_folder("Lists");
+        let authenticator =
+            ProvisioningAuthenticator::new(Box::new(inner), index.clone(), provisioning);
+        let login = |name: &str, password: &str| {
+            authenticator.authenticate(Box::new(BasicAuth::from(name, password)))
+        };
+
+        assert!(login("jane", "wrong").await.is_err());
+        assert!(index
+            .get_mailbox(&jane, "Drafts", Permission::ReadOnly)
+            .await
+            .is_err());
+
+        login("jane", "password").await.unwrap();
+        for name in ["INBOX", "Archive", "Drafts", "Junk", "Trash", "Lists"] {
+            index
+                .get_mailbox(&jane, name, Permission::ReadOnly)
+                .await
+                .unwrap();
+        }
+        // The existing mailbox keeps its messages.
+        assert_eq!(index.list_messages(&jane, "Sent").await.unwrap().len(), 1);
+        login("jane", "password").await.unwrap();
+
+        let guests = ProvisioningAuthenticator::new(
+            Box::new(Guests),
+            index.clone(),
+            Provisioning::new().with_folder("Drafts"),
+        );
+        guests
+            .authenticate(Box::new(BasicAuth::from("anyone", "")))
+            .await
+            .unwrap();
+        assert!(index
+            .get_mailbox(&Owner::new("public"), "Drafts", Permission::ReadOnly)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use log::error;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::error::{UserAlreadyExists, UserDoesNotExist, UserStoreError};
+use super::password::{Argon2id, PasswordHasher};
+use super::username::Normalization;
+use super::{rehash, reject_unknown, AuthenticationPrincipal, Password, User, UserStore};
+
+use crate::runtime::spawn_blocking;
+use crate::util::Result;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    name TEXT PRIMARY KEY,
+    password_hash TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS aliases (
+    alias TEXT PRIMARY KEY,
+    name TEXT NOT NULL
+);
+";
+
+/// A `UserStore` keeping accounts and their password hashes in a SQLite database, so a
+/// single-binary deployment needs no external database.
+///
+/// The database can be shared with the `useradd`, `userdel` and `passwd` commands of the
+/// binary while the server runs, since every lookup reads from the database.
+pub struct SqliteUserStore {
+    connection: Arc<Mutex<Connection>>,
+    normalization: Normalization,
+    hasher: Arc<dyn PasswordHasher>,
+    dummy: OnceLock<Option<Password>>,
+}
+
+impl SqliteUserStore {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            normalization: Normalization::default(),
+            hasher: Arc::new(Argon2id::default()),
+            dummy: OnceLock::new(),
+        })
+    }
+    /// Sets the hasher for new passwords. Stored hashes from another scheme, or with other
+    /// parameters, are re-hashed on the next successful login.
+    pub fn with_hasher<H: PasswordHasher + 'static>(mut self, hasher: H) -> Self {
+        self.hasher = Arc::new(hasher);
+        self.dummy = OnceLock::new();
+        self
+    }
+
+    /// Sets how usernames are canonicalized. Users added before keep the names they had.
+    pub fn with_normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    async fn store_hash(&self, username: &str, password: Password) -> Result<()> {
+        let username = username.to_string();
+        self.run(move |connection| {
+            match connection.execute(
+                "UPDATE users SET password_hash = ?2 WHERE name = ?1",
+                params![username, password.hash],
+            )? {
+                0 => Err(UserDoesNotExist::new(&username)),
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+
+    async fn run<T, F>(&self, operation: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        spawn_blocking(move || {
+            let connection = connection
+                .lock()
+                .map_err(|e| UserStoreError::Backend(e.to_string()))?;
+            operation(&connection)
+        })
+        .await
+    }
+}
+
+/// Follows `username` to the account it is an alias of, if it is one.
+fn resolve(connection: &Connection, username: String) -> rusqlite::Result<String> {
+    let target = connection
+        .query_row(
+            "SELECT name FROM aliases WHERE alias = ?1",
+            params![username],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(target.unwrap_or(username))
+}
+
+#[async_trait::async_trait]
+impl UserStore for SqliteUserStore {
+    async fn authenticate(&self, principal: Box<dyn AuthenticationPrincipal>) -> Result<User> {
+        let mut user = match self.get(&principal.principal()).await? {
+            Some(user) => user,
+            None => {
+                return Err(reject_unknown(&self.hasher, &self.dummy, principal.as_ref()).await)
+            }
+        };
+        principal.authenticate(&user).await?;
+        if let Some(password) = rehash(&self.hasher, principal.as_ref(), &user).await {
+            // A failed upgrade leaves the old hash, which still works, so the login succeeds.
+            match self.store_hash(&user.name, password.clone()).await {
+                Ok(()) => user.password_hash = password,
+                Err(e) => error!(
+                    "could not store the re-hashed password of {}: {}",
+                    user.name, e
+                ),
+            }
+        }
+        Ok(user)
+    }
+
+    async fn get(&self, username: &str) -> Result<Option<User>> {
+        let username = self.normalization.canonical(username);
+        self.run(move |connection| {
+            let username = resolve(connection, username)?;
+            let hash: Option<String> = connection
+                .query_row(
+                    "SELECT password_hash FROM users WHERE name = ?1",
+                    params![username],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            match hash {
+                Some(hash) => Ok(Some(User {
+                    name: username,
+                    password_hash: Password::from_hash(&hash)?,
+                    impersonator: None,
+                    anonymous: false,
+                })),
+                None => Ok(None),
+            }
+        })
+        .await
+    }
+
+    async fn canonical(&self, username: &str) -> Result<String> {
+        let username = self.normalization.canonical(username);
+        self.run(move |connection| Ok(resolve(connection, username)?))
+            .await
+    }
+
+    async fn add(&self, mut user: User) -> Result<()> {
+        user.name = self.normalization.canonical(&user.name);
+        self.run(move |connection| {
+            let inserted = connection.execute(
+                "INSERT OR IGNORE INTO users (name, password_hash) SELECT ?1, ?2
+                 WHERE NOT EXISTS (SELECT 1 FROM aliases WHERE alias = ?1)",
+                params![user.name, user.password_hash.hash],
+            )?;
+            match inserted {
+                0 => Err(UserAlreadyExists::new(&user.name)),
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+
+    async fn update(&self, user: User) -> Result<()> {
+        let username = self.normalization.canonical(&user.name);
+        self.store_hash(&username, user.password_hash).await
+    }
+
+    async fn remove(&self, username: &str) -> Result<()> {
+        let username = self.normalization.canonical(username);
+        self.run(move |connection| {
+            match connection.execute("DELETE FROM users WHERE name = ?1", params![username])? {
+                0 => Err(UserDoesNotExist::new(&username)),
+                _ => {
+                    connection.execute("DELETE FROM aliases WHERE name = ?1", params![username])?;
+                    Ok(())
+                }
+            }
+        })
+        .await
+    }
+
+    async fn set_password(&self, username: &str, password: &str) -> Result<()> {
+        let username = self.normalization.canonical(username);
+        let username = self
+            .run(move |connection| Ok(resolve(connection, username)?))
+            .await?;
+        let password = Password::hash(self.hasher.clone(), password).await?;
+        self.store_hash(&username, password).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        self.run(|connection| {
+            let mut statement = connection.prepare("SELECT name FROM users ORDER BY name")?;
+            let names = statement
+                .query_map([], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            Ok(names)
+        })
+        .await
+    }
+
+    async fn add_alias(&self, alias: &str, username: &str) -> Result<()> {
+        let alias = self.normalization.canonical(alias);
+        let username = self.normalization.canonical(username);
+        self.run(move |connection| {
+            let username = resolve(connection, username)?;
+            let exists = |name: &str| {
+                connection
+                    .query_row("SELECT 1 FROM users WHERE name = ?1", params![name], |_| {
+                        Ok(())
+                    })
+                    .optional()
+                    .map(|row| row.is_some())
+            };
+            if !exists(&username)? {
+                return Err(UserDoesNotExist::new(&username));
+            }
+            if exists(&alias)? {
+                return Err(UserAlreadyExists::new(&alias));
+            }
+            match connection.execute(
+                "INSERT OR IGNORE INTO aliases (alias, name) VALUES (?1, ?2)",
+                params![alias, username],
+            )? {
+                0 => Err(UserAlreadyExists::new(&alias)),
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+
+    async fn remove_alias(&self, alias: &str) -> Result<()> {
+        let alias = self.normalization.canonical(alias);
+        self.run(move |connection| {
+            match connection.execute("DELETE FROM aliases WHERE alias = ?1", params![alias])? {
+                0 => Err(UserDoesNotExist::new(&alias)),
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::password::{Argon2id, Bcrypt};
+    use crate::auth::username::Normalization;
+    use crate::auth::{BasicAuth, User, UserStore};
+
+    use super::SqliteUserStore;
+
+    fn database(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "treasurmap-users-{}-{}.sqlite",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[async_std::test]
+    async fn test_user_management() {
+        let path = database("management");
+        let store = SqliteUserStore::open(&path).unwrap();
+        store
+            .add(User::new("me@example.com", "password"))
+            .await
+            .unwrap();
+        assert!(store
+            .add(User::new("me@example.com", "other"))
+            .await
+            .is_err());
+        store
+            .add(User::new("you@example.com", "password"))
+            .await
+            .unwrap();
+        assert_eq!(
+            store.list().await.unwrap(),
+            vec!["me@example.com", "you@example.com"]
+        );
+
+        // A second handle sees changes made through the first, as the CLI and server would.
+        let reopened = SqliteUserStore::open(&path).unwrap().with_hasher(Argon2id {
+            memory_kib: 1024,
+            iterations: 1,
+            parallelism: 1,
+        });
+        let login = |password: &str| Box::new(BasicAuth::from("me@example.com", password));
+        assert_eq!(
+            reopened
+                .authenticate(login("password"))
+                .await
+                .unwrap()
+                .name(),
+            "me@example.com"
+        );
+        assert!(reopened.authenticate(login("wrong")).await.is_err());
+
+        // A bcrypt hash is upgraded to the configured argon2id parameters on the next login.
+        let store = store.with_hasher(Bcrypt { cost: 4 });
+        store
+            .set_password("me@example.com", "changed")
+            .await
+            .unwrap();
+        let stored = |store: &SqliteUserStore| {
+            let user = async_std::task::block_on(store.get("me@example.com"));
+            user.unwrap().unwrap().password_hash.hash
+        };
+        assert!(stored(&store).starts_with("$2b$04$"));
+        assert!(reopened.authenticate(login("password")).await.is_err());
+        assert!(stored(&store).starts_with("$2b$04$"));
+        assert!(reopened.authenticate(login("changed")).await.is_ok());
+        assert!(stored(&store).starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+
+        store.remove("me@example.com").await.unwrap();
+        assert!(store.remove("me@example.com").await.is_err());
+        assert!(store.set_password("me@example.com", "x").await.is_err());
+        assert!(reopened.get("me@example.com").await.unwrap().is_none());
+        assert!(reopened.authenticate(login("changed")).await.is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[async_std::test]
+    async fn test_normalization_and_aliases() {
+        let path = database("aliases");
+        let store = SqliteUserStore::open(&path)
+            .unwrap()
+            .with_hasher(Bcrypt { cost: 4 })
+            .with_normalization(Normalization::new().with_lowercase().with_subaddress('+'));
+        store
+            .add(User::new("Me@Example.COM", "password"))
+            .await
+            .unwrap();
+        assert_eq!(store.list().await.unwrap(), vec!["me@example.com"]);
+        assert!(store
+            .add(User::new("me+other@example.com", "x"))
+            .await
+            .is_err());
+        for name in ["Me@Example.COM", "me@example.com", "me+tag@example.com"] {
+            let user = store
+                .authenticate(Box::new(BasicAuth::from(name, "password")))
+                .await
+                .unwrap();
+            assert_eq!(user.name(), "me@example.com");
+        }
+
+        store
+            .add_alias("Boss@Example.com", "me+x@example.com")
+            .await
+            .unwrap();
+        let user = store.get("boss@example.com").await.unwrap().unwrap();
+        assert_eq!(user.name(), "me@example.com");
+        store
+            .set_password("boss@example.com", "changed")
+            .await
+            .unwrap();
+        assert!(store
+            .authenticate(Box::new(BasicAuth::from("boss@example.com", "changed")))
+            .await
+            .is_ok());
+        assert!(store
+            .add_alias("boss@example.com", "me@example.com")
+            .await
+            .is_err());
+        assert!(store
+            .add_alias("me@example.com", "me@example.com")
+            .await
+            .is_err());
+        assert!(store
+            .add_alias("other@example.com", "nobody@example.com")
+            .await
+            .is_err());
+        assert!(store.add(User::new("boss@example.com", "x")).await.is_err());
+
+        store.remove_alias("boss@example.com").await.unwrap();
+        assert!(store.get("boss@example.com").await.unwrap().is_none());
+        assert!(store.remove_alias("boss@example.com").await.is_err());
+
+        // Removing an account removes its aliases.
+        store
+            .add_alias("boss@example.com", "me@example.com")
+            .await
+            .unwrap();
+        store.remove("ME@example.com").await.unwrap();
+        assert!(store.get("boss@example.com").await.unwrap().is_none());
+        let _ = std::fs::remove_file(&path);
+    }
+}
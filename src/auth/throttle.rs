@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_std::task::sleep;
+use log::warn;
+
+use super::error::AuthenticationFailed;
+use super::{Authenticate, AuthenticationPrincipal, User};
+use crate::access::AccessControl;
+use crate::util::Result;
+
+/// Once this many counters are tracked, stale ones are dropped on the next failure so a
+/// spray of usernames or addresses cannot grow the table without bound.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    User(String),
+    Address(IpAddr),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Brute-force protection for logins: failed attempts are counted per username and per client
+/// address, each failure is answered after an exponentially growing delay, and a username or
+/// address which reaches its threshold is locked out for a while.
+///
+/// Counters are forgotten once `window` passes without a failure. A successful login resets
+/// the counter of the username but not of the address, so an attacker cannot clear their
+/// address by logging into an account of their own.
+pub struct Throttle {
+    user_threshold: u32,
+    address_threshold: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    lockout: Duration,
+    window: Duration,
+    failures: Mutex<HashMap<Key, Failures>>,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self {
+            user_threshold: 5,
+            address_threshold: 20,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(16),
+            lockout: Duration::from_secs(15 * 60),
+            window: Duration::from_secs(15 * 60),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Throttle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The number of failures after which a username, or a client address, is locked out.
+    pub fn with_thresholds(mut self, user: u32, address: u32) -> Self {
+        self.user_threshold = user;
+        self.address_threshold = address;
+        self
+    }
+    /// The delay before answering the first failure, doubling with each further failure up to
+    /// `max`.
+    pub fn with_delay(mut self, base: Duration, max: Duration) -> Self {
+        self.base_delay = base;
+        self.max_delay = max;
+        self
+    }
+    pub fn with_lockout(mut self, lockout: Duration) -> Self {
+        self.lockout = lockout;
+        self
+    }
+    /// How long a counter is kept after its last failure.
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    fn keys(username: &str, address: Option<IpAddr>) -> Vec<Key> {
+        let mut keys = vec![Key::User(username.to_string())];
+        keys.extend(address.map(Key::Address));
+        keys
+    }
+
+    /// Whether logins for `username`, or from `address`, are currently locked out. Locked out
+    /// attempts should be rejected without checking the password, so they reveal nothing.
+    pub fn is_locked(&self, username: &str, address: Option<IpAddr>) -> bool {
+        let failures = match self.failures.lock() {
+            Ok(failures) => failures,
+            Err(_) => return false,
+        };
+        let now = Instant::now();
+        Self::keys(username, address).iter().any(|key| {
+            matches!(failures.get(key), Some(Failures { locked_until: Some(until), .. }) if *until > now)
+        })
+    }
+
+    /// Records a failed login, returning how long to wait before answering it.
+    pub fn failed(&self, username: &str, address: Option<IpAddr>) -> Duration {
+        let mut failures = match self.failures.lock() {
+            Ok(failures) => failures,
+            Err(_) => return self.max_delay,
+        };
+        let now = Instant::now();
+        if failures.len() >= PRUNE_THRESHOLD {
+            failures.retain(|_, entry| !self.expired(entry, now));
+        }
+        let mut count = 0;
+        for key in Self::keys(username, address) {
+            let threshold = match key {
+                Key::User(_) => self.user_threshold,
+                Key::Address(_) => self.address_threshold,
+            };
+            let entry = failures.entry(key).or_insert(Failures {
+                count: 0,
+                last: now,
+                locked_until: None,
+            });
+            if self.expired(entry, now) {
+                entry.count = 0;
+                entry.locked_until = None;
+            }
+            entry.count = entry.count.saturating_add(1);
+            entry.last = now;
+            // Failures during a lockout do not extend it, or anyone could keep an account
+            // locked indefinitely.
+            let locked = matches!(entry.locked_until, Some(until) if until > now);
+            if !locked && entry.count >= threshold {
+                entry.locked_until = Some(now + self.lockout);
+            }
+            count = count.max(entry.count);
+        }
+        self.delay(count)
+    }
+
+    /// Records a successful login, resetting the counter of `username`.
+    pub fn succeeded(&self, username: &str) {
+        if let Ok(mut failures) = self.failures.lock() {
+            failures.remove(&Key::User(username.to_string()));
+        }
+    }
+
+    fn expired(&self, entry: &Failures, now: Instant) -> bool {
+        let locked = matches!(entry.locked_until, Some(until) if until > now);
+        !locked && now.duration_since(entry.last) >= self.window
+    }
+
+    fn delay(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31);
+        self.base_delay
+            .checked_mul(1 << doublings)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+/// Throttles every login through the wrapped authenticator with a `Throttle`, so that IMAP,
+/// POP3 and JMAP share the same counters for each account and client address. Usernames are
+/// counted by their `Authenticate::canonical` name, so that `Me`, `me+tag` and an alias of
+/// `me` cannot each fail a fresh set of attempts.
+///
+/// `login` returns the delay for the caller to wait before answering a failure, while
+/// `authenticate`, which does not know the client's address, waits it out itself.
+///
+/// Logins the `AccessControl` does not authorize from the client's address are refused
+/// before the password is checked, and count as failures, so that they cannot be told apart
+/// from a wrong password.
+pub struct ThrottledAuthenticator {
+    authenticator: Box<dyn Authenticate>,
+    throttle: Throttle,
+    access: AccessControl,
+}
+
+impl ThrottledAuthenticator {
+    pub fn new(authenticator: Box<dyn Authenticate>, throttle: Throttle) -> Self {
+        Self {
+            authenticator,
+            throttle,
+            access: AccessControl::default(),
+        }
+    }
+    /// Refuses logins of users who `access` does not let log in from the client's address.
+    pub fn with_access_control(mut self, access: AccessControl) -> Self {
+        self.access = access;
+        self
+    }
+
+    fn authorizes(&self, username: &str, peer: Option<IpAddr>) -> bool {
+        let authorized = self.access.authorizes(username, peer);
+        if !authorized {
+            warn!(
+                "Refusing a login of {} from {}: not allowed by the access policy",
+                username,
+                peer.map_or("an unknown address".to_string(), |peer| peer.to_string())
+            );
+        }
+        authorized
+    }
+}
+
+#[async_trait::async_trait]
+impl Authenticate for ThrottledAuthenticator {
+    async fn authenticate(&self, principal: Box<dyn AuthenticationPrincipal>) -> Result<User> {
+        match self.login(principal, None).await {
+            Ok(user) => Ok(user),
+            Err(delay) => {
+                sleep(delay).await;
+                Err(Box::new(AuthenticationFailed {}))
+            }
+        }
+    }
+    async fn canonical(&self, username: &str) -> String {
+        self.authenticator.canonical(username).await
+    }
+    async fn login(
+        &self,
+        principal: Box<dyn AuthenticationPrincipal>,
+        peer: Option<IpAddr>,
+    ) -> std::result::Result<User, Duration> {
+        let username = self.authenticator.canonical(&principal.principal()).await;
+        if self.throttle.is_locked(&username, peer) || !self.authorizes(&username, peer) {
+            return Err(self.throttle.failed(&username, peer));
+        }
+        match self.authenticator.authenticate(principal).await {
+            // The account logged in to is not the one named when a master user impersonates
+            // someone, and must be allowed from the client's address too.
+            Ok(user) if user.name() == username || self.authorizes(&user.name(), peer) => {
+                self.throttle.succeeded(&username);
+                Ok(user)
+            }
+            _ => Err(self.throttle.failed(&username, peer)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{Throttle, ThrottledAuthenticator};
+    use crate::auth::inmemory::{InMemoryAuthenticator, InMemoryUserStore};
+    use crate::auth::password::Bcrypt;
+    use crate::auth::username::Normalization;
+    use crate::auth::{Authenticate, BasicAuth};
+
+    const ADDRESS: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+    const OTHER: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)));
+
+    #[test]
+    fn test_backoff_and_lockout() {
+        let throttle = Throttle::new()
+            .with_thresholds(3, 4)
+            .with_delay(Duration::from_millis(100), Duration::from_millis(250));
+        assert!(!throttle.is_locked("me", ADDRESS));
+        assert_eq!(throttle.failed("me", ADDRESS), Duration::from_millis(100));
+        assert_eq!(throttle.failed("me", ADDRESS), Duration::from_millis(200));
+        assert!(!throttle.is_locked("me", OTHER));
+        assert_eq!(throttle.failed("me", OTHER), Duration::from_millis(250));
+        // The username is now locked out from any address.
+        assert!(throttle.is_locked("me", None));
+        assert!(throttle.is_locked("me", OTHER));
+        assert!(!throttle.is_locked("you", ADDRESS));
+
+        assert_eq!(throttle.failed("you", ADDRESS), Duration::from_millis(250));
+        throttle.failed("them", ADDRESS);
+        assert!(throttle.is_locked("someone", ADDRESS));
+        assert!(!throttle.is_locked("someone", OTHER));
+
+        // Success clears the username but not the address.
+        throttle.succeeded("me");
+        assert!(!throttle.is_locked("me", None));
+        assert!(throttle.is_locked("me", ADDRESS));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let throttle = Throttle::new()
+            .with_thresholds(2, 10)
+            .with_delay(Duration::ZERO, Duration::ZERO)
+            .with_lockout(Duration::from_millis(20))
+            .with_window(Duration::from_millis(20));
+        throttle.failed("me", ADDRESS);
+        throttle.failed("me", ADDRESS);
+        assert!(throttle.is_locked("me", ADDRESS));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!throttle.is_locked("me", ADDRESS));
+        // The counter starts again after the window.
+        throttle.failed("me", ADDRESS);
+        assert!(!throttle.is_locked("me", ADDRESS));
+    }
+
+    #[async_std::test]
+    async fn test_variants_share_a_lockout() {
+        let store = InMemoryUserStore::new()
+            .with_hasher(Bcrypt { cost: 4 })
+            .with_normalization(Normalization::new().with_lowercase().with_subaddress('+'))
+            .with_user("me@example.com", "password");
+        let authenticator = ThrottledAuthenticator::new(
+            Box::new(InMemoryAuthenticator::new(Arc::new(Box::new(store)))),
+            Throttle::new()
+                .with_thresholds(3, 10)
+                .with_delay(Duration::ZERO, Duration::ZERO),
+        );
+        let login = |name: &str, password: &str| {
+            authenticator.login(Box::new(BasicAuth::from(name, password)), ADDRESS)
+        };
+        for name in ["Me@Example.com", "me+a@example.com", "ME+b@EXAMPLE.COM"] {
+            assert!(login(name, "wrong").await.is_err());
+        }
+        // Each variant counted against the one account, which is now locked out.
+        assert!(login("me@example.com", "password").await.is_err());
+        assert!(login("me+c@example.com", "password").await.is_err());
+    }
+}
@@ -0,0 +1,106 @@
+/// What to do with the `@domain` part of usernames.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Domain {
+    #[default]
+    Keep,
+    /// Removes the domain, for single-domain deployments where people log in with either form.
+    Strip,
+    /// Appends `@domain` to usernames without one.
+    Append(String),
+}
+
+/// How a `UserStore` turns the name someone logs in with into the name of their account.
+/// Nothing is changed by default.
+#[derive(Debug, Clone, Default)]
+pub struct Normalization {
+    lowercase: bool,
+    domain: Domain,
+    subaddress: Option<char>,
+}
+
+impl Normalization {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_lowercase(mut self) -> Self {
+        self.lowercase = true;
+        self
+    }
+    pub fn with_domain(mut self, domain: Domain) -> Self {
+        self.domain = domain;
+        self
+    }
+    /// Drops the sub-address from `separator` up to the domain, so with `+`,
+    /// `me+tag@example.com` is `me@example.com`.
+    pub fn with_subaddress(mut self, separator: char) -> Self {
+        self.subaddress = Some(separator);
+        self
+    }
+
+    pub fn canonical(&self, username: &str) -> String {
+        let (local, domain) = match username.rsplit_once('@') {
+            Some((local, domain)) => (local, Some(domain)),
+            None => (username, None),
+        };
+        let local = match self
+            .subaddress
+            .and_then(|separator| local.split_once(separator))
+        {
+            // A name which starts with the separator has nothing left to keep.
+            Some((base, _)) if !base.is_empty() => base,
+            _ => local,
+        };
+        let domain = match (&self.domain, domain) {
+            (Domain::Strip, _) => None,
+            (Domain::Append(default), None) => Some(default.as_str()),
+            (_, domain) => domain,
+        };
+        let name = match domain {
+            Some(domain) => format!("{}@{}", local, domain),
+            None => local.to_string(),
+        };
+        if self.lowercase {
+            name.to_lowercase()
+        } else {
+            name
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Domain, Normalization};
+
+    #[test]
+    fn test_canonical() {
+        assert_eq!(
+            Normalization::new().canonical("Me+tag@Example.COM"),
+            "Me+tag@Example.COM"
+        );
+
+        let normalization = Normalization::new().with_lowercase().with_subaddress('+');
+        for name in [
+            "Me@Example.COM",
+            "me@example.com",
+            "me+tag@example.com",
+            "ME+a+b@example.com",
+        ] {
+            assert_eq!(normalization.canonical(name), "me@example.com");
+        }
+        assert_eq!(
+            normalization.canonical("+me@example.com"),
+            "+me@example.com"
+        );
+        assert_eq!(normalization.canonical("me+tag"), "me");
+
+        let append = normalization
+            .clone()
+            .with_domain(Domain::Append("example.com".to_string()));
+        assert_eq!(append.canonical("Me+tag"), "me@example.com");
+        assert_eq!(append.canonical("me@example.org"), "me@example.org");
+
+        let strip = normalization.with_domain(Domain::Strip);
+        assert_eq!(strip.canonical("me+tag@example.com"), "me");
+        assert_eq!(strip.canonical("me"), "me");
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::server::Response;
+use crate::util::Sender;
+
+#[derive(Default)]
+struct Sessions {
+    next: AtomicU64,
+    responders: Mutex<HashMap<u64, Sender<Vec<Response>>>>,
+}
+
+/// A handle for sending untagged responses to every open connection of a `Server`, see
+/// `Server::broadcast`. Clones reach the same connections.
+#[derive(Clone, Default)]
+pub struct Broadcast {
+    sessions: Arc<Sessions>,
+}
+
+impl Broadcast {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends `* OK [ALERT] <message>`, which clients must show to the user, such as a
+    /// warning of planned maintenance. Returns the number of connections it was sent to.
+    pub fn alert(&self, message: &str) -> usize {
+        self.send(vec![Response::alert(message)])
+    }
+
+    /// Sends `responses` to every connection, returning how many it was sent to. They are
+    /// written after whatever the connection was already writing.
+    pub fn send(&self, responses: Vec<Response>) -> usize {
+        let mut responders = match self.sessions.responders.lock() {
+            Ok(responders) => responders,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        responders.retain(|_, responder| responder.unbounded_send(responses.clone()).is_ok());
+        responders.len()
+    }
+
+    /// The number of connections responses are sent to.
+    pub fn connections(&self) -> usize {
+        match self.sessions.responders.lock() {
+            Ok(responders) => responders.len(),
+            Err(poisoned) => poisoned.into_inner().len(),
+        }
+    }
+
+    /// Sends broadcasts to `responder` until the subscription is dropped. A connection must
+    /// drop it before waiting for its writer, which only stops once every sender is gone.
+    pub(crate) fn subscribe(&self, responder: Sender<Vec<Response>>) -> Subscription {
+        let id = self.sessions.next.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut responders) = self.sessions.responders.lock() {
+            responders.insert(id, responder);
+        }
+        Subscription {
+            broadcast: self.clone(),
+            id,
+        }
+    }
+}
+
+pub(crate) struct Subscription {
+    broadcast: Broadcast,
+    id: u64,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Ok(mut responders) = self.broadcast.sessions.responders.lock() {
+            responders.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc::unbounded;
+    use futures::StreamExt;
+
+    use super::Broadcast;
+    use crate::server::Response;
+
+    #[async_std::test]
+    async fn test_alert() {
+        let broadcast = Broadcast::new();
+        let (first, mut first_received) = unbounded();
+        let (second, mut second_received) = unbounded();
+        let _first = broadcast.subscribe(first);
+        let second = broadcast.subscribe(second);
+
+        assert_eq!(broadcast.alert("Maintenance at 22:00 UTC"), 2);
+        let alert = Response::from("* OK [ALERT] Maintenance at 22:00 UTC").unwrap();
+        assert_eq!(first_received.next().await, Some(vec![alert.clone()]));
+        assert_eq!(second_received.next().await, Some(vec![alert]));
+
+        drop(second);
+        assert_eq!(broadcast.connections(), 1);
+        // A connection whose writer has stopped is no longer sent to.
+        drop(first_received);
+        assert_eq!(broadcast.alert("Again"), 0);
+    }
+}
@@ -0,0 +1,151 @@
+//! Reuses the buffers connections read commands into and write responses from.
+//!
+//! Every connection needs a buffer large enough for the longest command line it accepts, so a
+//! server whose clients connect and disconnect often, such as monitoring probes checking it
+//! every few seconds, would otherwise allocate and free large buffers all the time. A
+//! connection takes its buffers from the pool of its listener when it is accepted, and they
+//! go back to it when the connection closes.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// How many buffers a pool keeps unless told otherwise.
+pub const DEFAULT_POOLED: usize = 64;
+
+struct Pool {
+    size: usize,
+    limit: usize,
+    free: Mutex<Vec<Vec<u8>>>,
+}
+
+/// Buffers with room for `size` bytes. Clones share the buffers.
+#[derive(Clone)]
+pub struct BufferPool {
+    pool: Arc<Pool>,
+}
+
+impl BufferPool {
+    pub fn new(size: usize) -> Self {
+        Self {
+            pool: Arc::new(Pool {
+                size,
+                limit: DEFAULT_POOLED,
+                free: Mutex::new(vec![]),
+            }),
+        }
+    }
+
+    /// Keeps at most `limit` buffers which are not in use rather than `DEFAULT_POOLED`.
+    /// Buffers returned beyond that are freed.
+    pub fn with_limit(self, limit: usize) -> Self {
+        Self {
+            pool: Arc::new(Pool {
+                size: self.pool.size,
+                limit,
+                free: Mutex::new(vec![]),
+            }),
+        }
+    }
+
+    /// An empty buffer with room for at least the size of the pool, which is returned to the
+    /// pool when dropped.
+    pub fn take(&self) -> Buffer {
+        let bytes = match self.pool.free.lock() {
+            Ok(mut free) => free.pop(),
+            Err(poisoned) => poisoned.into_inner().pop(),
+        };
+        Buffer {
+            bytes: bytes.unwrap_or_else(|| Vec::with_capacity(self.pool.size)),
+            pool: Some(self.clone()),
+        }
+    }
+
+    /// The number of buffers waiting to be taken.
+    pub fn available(&self) -> usize {
+        match self.pool.free.lock() {
+            Ok(free) => free.len(),
+            Err(poisoned) => poisoned.into_inner().len(),
+        }
+    }
+
+    fn give(&self, mut bytes: Vec<u8>) {
+        // A buffer which was replaced by a smaller one is of no use, and one which grew far
+        // past the size of the pool, such as for a large response, would hold on to memory.
+        let capacity = bytes.capacity();
+        if capacity < self.pool.size || capacity > 2 * self.pool.size {
+            return;
+        }
+        bytes.clear();
+        let mut free = match self.pool.free.lock() {
+            Ok(free) => free,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if free.len() < self.pool.limit {
+            free.push(bytes);
+        }
+    }
+}
+
+/// A byte buffer, returned to the pool it was taken from when dropped. One made with `new`
+/// belongs to no pool.
+#[derive(Default)]
+pub struct Buffer {
+    bytes: Vec<u8>,
+    pool: Option<BufferPool>,
+}
+
+impl Buffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Deref for Buffer {
+    type Target = Vec<u8>;
+    fn deref(&self) -> &Vec<u8> {
+        &self.bytes
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.bytes
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.give(std::mem::take(&mut self.bytes));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferPool;
+
+    #[test]
+    fn test_buffers_reused() {
+        let pool = BufferPool::new(1024).with_limit(1);
+        let mut first = pool.take();
+        first.extend_from_slice(b"a1 NOOP\r\n");
+        let address = first.as_ptr();
+        let second = pool.take();
+        drop(first);
+        drop(second);
+        // Only one buffer is kept.
+        assert_eq!(pool.available(), 1);
+
+        let reused = pool.take();
+        assert!(reused.is_empty());
+        assert!(reused.capacity() >= 1024);
+        assert_eq!(reused.as_ptr(), address);
+        drop(reused);
+
+        let mut grown = pool.take();
+        grown.resize(64 * 1024, 0);
+        drop(grown);
+        assert_eq!(pool.available(), 0);
+    }
+}
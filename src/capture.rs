@@ -0,0 +1,161 @@
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use tracing::debug;
+
+/// Where the protocol exchange of connections is captured, to diagnose problems with clients
+/// without a packet capture. Passwords and SASL responses are redacted.
+#[derive(Debug, Clone)]
+pub enum Capture {
+    /// Logs each line at debug level with the `wire` target.
+    Log,
+    /// Writes the exchange of each connection to its own file in this directory, named after
+    /// the connection's UUID.
+    Directory(PathBuf),
+}
+
+pub(crate) const REDACTED: &str = "[redacted]";
+
+/// Hides the credentials in a line sent by the client, keeping the tag, the command and, for
+/// AUTHENTICATE, the mechanism.
+pub fn redact(line: &str) -> Cow<'_, str> {
+    let command = line.split(' ').nth(1).unwrap_or_default();
+    let kept = match command.to_uppercase().as_str() {
+        "LOGIN" => 2,
+        "AUTHENTICATE" => 3,
+        _ => return Cow::Borrowed(line),
+    };
+    let words: Vec<&str> = line.splitn(kept + 1, ' ').collect();
+    let (shown, hidden) = words.split_at(words.len().min(kept));
+    let mut redacted = shown.join(" ");
+    if !hidden.is_empty() {
+        redacted.push(' ');
+        redacted.push_str(REDACTED);
+    }
+    Cow::Owned(redacted)
+}
+
+enum Sink {
+    Log,
+    File(Mutex<File>),
+}
+
+/// The capture of one connection.
+pub(crate) struct Transcript {
+    session: String,
+    sink: Sink,
+    /// Whether an AUTHENTICATE is waiting for its completion, during which lines from the
+    /// client are SASL responses.
+    authenticating: AtomicBool,
+}
+
+impl Transcript {
+    pub(crate) fn open(capture: &Capture, session: &str) -> std::io::Result<Self> {
+        let sink = match capture {
+            Capture::Log => Sink::Log,
+            Capture::Directory(directory) => {
+                fs::create_dir_all(directory)?;
+                let path = directory.join(format!("{}.log", session));
+                let file = File::options().create(true).append(true).open(path)?;
+                Sink::File(Mutex::new(file))
+            }
+        };
+        Ok(Self {
+            session: session.to_string(),
+            sink,
+            authenticating: AtomicBool::new(false),
+        })
+    }
+
+    /// Records a line read from the client, without its CRLF.
+    pub(crate) fn client(&self, line: &str) {
+        let redacted = match self.authenticating.load(Ordering::SeqCst) {
+            true => Cow::Borrowed(REDACTED),
+            false => redact(line),
+        };
+        let command = line.split(' ').nth(1).unwrap_or_default();
+        if command.eq_ignore_ascii_case("AUTHENTICATE") {
+            self.authenticating.store(true, Ordering::SeqCst);
+        }
+        self.record("C", &redacted);
+    }
+
+    /// Records a line written to the client, without its CRLF.
+    pub(crate) fn server(&self, line: &str) {
+        let tagged = !line.starts_with("* ") && !line.starts_with('+');
+        if tagged {
+            self.authenticating.store(false, Ordering::SeqCst);
+        }
+        self.record("S", line);
+    }
+
+    fn record(&self, from: &str, line: &str) {
+        match &self.sink {
+            Sink::Log => debug!(target: "wire", "{} {}: {}", self.session, from, line),
+            Sink::File(file) => {
+                let mut file = match file.lock() {
+                    Ok(file) => file,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                // Losing a line of a debugging aid is better than closing the connection.
+                let _ = writeln!(file, "{}: {}", from, line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{redact, Capture, Transcript};
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("a1 LOGIN me@example.com secret"),
+            "a1 LOGIN [redacted]"
+        );
+        assert_eq!(
+            redact("a1 login \"me\" \"pass word\""),
+            "a1 login [redacted]"
+        );
+        assert_eq!(
+            redact("a2 AUTHENTICATE PLAIN AG1lAHNlY3JldA=="),
+            "a2 AUTHENTICATE PLAIN [redacted]"
+        );
+        assert_eq!(redact("a2 AUTHENTICATE PLAIN"), "a2 AUTHENTICATE PLAIN");
+        assert_eq!(redact("a3 SELECT INBOX"), "a3 SELECT INBOX");
+    }
+
+    #[test]
+    fn test_transcript() {
+        let directory = std::env::temp_dir().join(format!(
+            "treasurmap-capture-transcript-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&directory);
+        let transcript =
+            Transcript::open(&Capture::Directory(directory.clone()), "session").unwrap();
+        transcript.server("* OK IMAP4rev2 server ready");
+        transcript.client("a1 AUTHENTICATE PLAIN");
+        transcript.server("+ ");
+        transcript.client("AG1lAHNlY3JldA==");
+        transcript.server("a1 OK AUTHENTICATE completed.");
+        transcript.client("a2 SELECT INBOX");
+
+        let written = std::fs::read_to_string(directory.join("session.log")).unwrap();
+        assert_eq!(
+            written,
+            "S: * OK IMAP4rev2 server ready\n\
+             C: a1 AUTHENTICATE PLAIN\n\
+             S: + \n\
+             C: [redacted]\n\
+             S: a1 OK AUTHENTICATE completed.\n\
+             C: a2 SELECT INBOX\n"
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
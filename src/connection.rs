@@ -1,45 +1,155 @@
-use std::collections::HashMap;
 use std::sync::Arc;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
 
 use async_lock::RwLock;
 use async_std::path::PathBuf;
 use async_std::{
-    io::BufReader,
-    net::TcpStream,
+    future::timeout,
     prelude::*,
-    task::spawn,
-    task::JoinHandle,
 };
 
-use futures::channel::mpsc::UnboundedSender;
-use futures::channel::oneshot::{self, channel};
-use futures::{SinkExt, channel::mpsc::unbounded};
-use log::{info, trace};
+use futures::channel::oneshot;
+use futures::io::{ReadHalf, WriteHalf};
+use futures::future::{self, Either};
+use futures::stream::select;
+use futures::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, SinkExt, channel::mpsc::unbounded};
+use tracing::{debug, field, info, info_span, trace, warn, Instrument, Span};
 
+use crate::audit::{AuditEvent, AuditLog};
 use crate::auth::User;
-use crate::server::{Command, Response, ResponseStatus};
-use crate::util::{Result, Receiver, Sender};
+use crate::broadcast::{Broadcast, Subscription};
+use crate::buffers::{Buffer, BufferPool};
+use crate::capture::{Capture, Transcript};
+use crate::framing::Framed;
+use crate::greeting::Greeting;
+use crate::handlers::capability::{code, Advertised};
+use crate::handlers::unknown_command;
+use crate::index::{Owner, Permission};
+use crate::limits::{Excess, RateLimit, TokenBucket};
+use crate::listener::Io;
+use crate::metrics::Metrics;
+use crate::middleware::Pipeline;
+use crate::runtime::{spawn, JoinHandle};
+use crate::server::{Command, Handlers, Literal, Response, ResponseStatus, EXTENSIONS};
+use crate::sessions::{Registration, Sessions};
+use crate::shutdown::Shutdown;
+use crate::slowlog::{self, Measured, SlowLog, Timings, WriteClock};
+use crate::state::State;
+use crate::util::{uuid, Result, Receiver, Sender};
+
+type Input = Framed<ReadHalf<Box<dyn Io>>>;
+type Output = WriteHalf<Box<dyn Io>>;
+
+/// What the writer task is asked to do.
+enum Write {
+    Responses(Vec<Response>),
+    /// Writes the responses, then hands the write half of the stream back so it can be
+    /// wrapped in TLS, and carries on with the half it is given next.
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    Swap(Vec<Response>, oneshot::Sender<Output>, oneshot::Receiver<Output>),
+}
+
+/// RFC 9051 requires the autologout timer of an authenticated connection to be at least
+/// 30 minutes.
+pub const MIN_AUTOLOGOUT: Duration = Duration::from_secs(30 * 60);
+
+/// How long a connection may go without a command before it is logged out.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    authenticated: Duration,
+    unauthenticated: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            authenticated: MIN_AUTOLOGOUT,
+            unauthenticated: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The autologout timer once logged in. Values below `MIN_AUTOLOGOUT` are raised to it.
+    pub fn with_autologout(mut self, autologout: Duration) -> Self {
+        self.authenticated = autologout.max(MIN_AUTOLOGOUT);
+        self
+    }
+    /// The shorter timer for connections which have not logged in yet.
+    pub fn with_login_timeout(mut self, login_timeout: Duration) -> Self {
+        self.unauthenticated = login_timeout;
+        self
+    }
+}
 
 pub struct Connection {
-    shutdown: oneshot::Receiver<()>,
-    state_manager: Option<JoinHandle<()>>,
-    state_updater: Sender<Event>,
+    id: String,
+    /// The span of the whole connection, which the span of each command is a child of.
+    span: Span,
+    /// Signalled once there is nothing more to say to the client: after LOGOUT, or when it
+    /// can no longer be written to.
+    closed: Receiver<()>,
+    closing: Sender<()>,
+    /// Signalled as each command dispatched to a handler completes.
+    completed: Receiver<()>,
+    completing: Sender<()>,
+    in_flight: usize,
+    max_in_flight: usize,
     state: Arc<RwLock<Context>>,
     writer: Option<JoinHandle<()>>,
-    stream: Arc<TcpStream>,
+    unstarted: Option<Unstarted>,
+    input: Option<Input>,
+    swaps: Sender<Write>,
     responder: Sender<Vec<Response>>,
+    /// Requests from handlers for lines from the client, see `Continuation`.
+    prompts: Sender<Prompt>,
+    prompted: Receiver<Prompt>,
+    timeouts: Timeouts,
+    max_line_length: usize,
+    rate_limit: Option<TokenBucket>,
+    metrics: Arc<Metrics>,
+    audit: Option<Arc<dyn AuditLog>>,
+    capture: Option<Capture>,
+    middleware: Pipeline,
+    /// The server's shutdown, on which the connection says BYE and closes.
+    stop: Shutdown,
+    broadcasts: Option<Subscription>,
+    /// The entry of the connection in the server's sessions, and the signal on which an
+    /// administrator terminates it.
+    session: Option<Registration>,
+    terminated: Shutdown,
+    slow_log: Option<SlowLog>,
+    write_clock: Arc<WriteClock>,
+    /// Where the buffers for reading commands and writing responses come from.
+    buffers: Option<BufferPool>,
+    /// The capabilities listed in the greeting.
+    capabilities: Option<Advertised>,
+    greeting: Greeting,
+    #[cfg(feature = "tls")]
+    starttls: Option<crate::listener::TlsAcceptor>,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct Context{
     current_folder: Option<PathBuf>,
+    /// What the selected mailbox was opened for: read-only by EXAMINE, or where the user may
+    /// not write to it.
+    permission: Option<Permission>,
     user: Option<User>,
+    peer: Option<IpAddr>,
+    secure: bool,
+    starttls: bool,
+    logged_out: bool,
 }
 
 #[derive(Debug, Clone)]
 pub enum Event {
     AUTH(User),
-    SELECT(PathBuf),
+    SELECT(PathBuf, Permission),
     UNAUTH(),
 }
 
@@ -50,9 +160,339 @@ impl Context {
     pub fn is_selected(&self) -> bool {
         self.current_folder.is_some()
     }
+    /// The name of the selected mailbox, if any.
+    pub fn folder(&self) -> Option<String> {
+        self.current_folder
+            .as_ref()
+            .map(|folder| folder.to_string_lossy().to_string())
+    }
+    pub fn user(&self) -> Option<&User> {
+        self.user.as_ref()
+    }
+    /// Whether the session may only read, as for guests from AUTHENTICATE ANONYMOUS.
+    pub fn is_read_only(&self) -> bool {
+        self.user.as_ref().is_some_and(User::is_anonymous)
+    }
+    /// Whether the selected mailbox was opened read-only, so its messages and flags may not
+    /// be changed.
+    pub fn is_selected_read_only(&self) -> bool {
+        matches!(self.permission, Some(Permission::ReadOnly))
+    }
+    pub fn with_permission(mut self, permission: Permission) -> Self {
+        self.permission = Some(permission);
+        self
+    }
+    /// The master user acting as `user()`, when the session is an impersonation.
+    pub fn impersonator(&self) -> Option<&str> {
+        self.user.as_ref().and_then(User::impersonator)
+    }
+    /// The owner whose mailboxes this connection operates on, once authenticated.
+    pub fn owner(&self) -> Option<Owner> {
+        self.user.as_ref().map(Owner::from)
+    }
+    /// The address of the client, used to throttle failed logins.
+    pub fn peer(&self) -> Option<IpAddr> {
+        self.peer
+    }
     pub fn of(user: Option<User>, folder: Option<PathBuf>) -> Self {
-        Self { current_folder: folder, user }
+        Self { current_folder: folder, user, ..Default::default() }
+    }
+    pub fn with_peer(mut self, peer: IpAddr) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+    /// Whether the connection is encrypted, by implicit TLS or after STARTTLS.
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+    pub fn with_secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+    /// Whether the listener offers STARTTLS, so it is advertised until the connection is
+    /// encrypted.
+    pub fn offers_starttls(&self) -> bool {
+        self.starttls
+    }
+    pub fn with_starttls(mut self) -> Self {
+        self.starttls = true;
+        self
+    }
+    /// Whether LOGOUT has been accepted, so the connection is closing.
+    pub fn is_logged_out(&self) -> bool {
+        self.logged_out
+    }
+    pub fn with_logged_out(mut self) -> Self {
+        self.logged_out = true;
+        self
+    }
+    pub(crate) fn describe_peer(&self) -> String {
+        self.peer
+            .map_or("a local socket".to_string(), |peer| peer.to_string())
+    }
+}
+
+/// The tag of a command line which could not be parsed, or `*` when it has none.
+fn tag(line: &str) -> &str {
+    match line.split_once(' ') {
+        Some((tag, _)) if !tag.is_empty() => tag,
+        _ => "*",
+    }
+}
+
+/// RFC 7162 asks servers to accept command lines of at least 8192 bytes.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// What `read_line` read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Line {
+    Complete,
+    /// The line was longer than allowed. Only its start was kept, the rest was discarded.
+    TooLong,
+    End,
+}
+
+/// Reads a line into `line`, keeping no more than `max` bytes of it in memory. A line the
+/// client did not finish before closing the connection is still returned.
+pub(crate) async fn read_line<R: AsyncBufRead + Unpin>(input: &mut R, line: &mut Vec<u8>, max: usize) -> std::io::Result<Line> {
+    let mut too_long = false;
+    loop {
+        let available = input.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(match too_long || line.is_empty() {
+                true => Line::End,
+                false => Line::Complete,
+            });
+        }
+        let (used, done) = match available.iter().position(|byte| *byte == b'\n') {
+            Some(end) => (end + 1, true),
+            None => (available.len(), false),
+        };
+        let keep = used.min(max.saturating_sub(line.len()));
+        line.extend_from_slice(&available[..keep]);
+        too_long |= keep < used;
+        input.consume_unpin(used);
+        if done {
+            return Ok(match too_long {
+                true => Line::TooLong,
+                false => Line::Complete,
+            });
+        }
+    }
+}
+
+/// The number of commands a connection runs at once by default.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 8;
+
+/// A command dispatched to a handler, measured for the metrics and the audit log.
+struct Dispatched {
+    command: Command,
+    /// The session as the command found it.
+    before: Context,
+    session: String,
+    started: Instant,
+    metrics: Arc<Metrics>,
+    audit: Option<Arc<dyn AuditLog>>,
+    /// The status of the tagged response completing the command.
+    status: Option<ResponseStatus>,
+    /// What is measured for the slow log, when there is one.
+    measured: Option<Measured>,
+}
+
+impl Dispatched {
+    fn observe(&mut self, responses: &[Response]) {
+        if let Some(completion) = responses.iter().rfind(|response| response.tag() != "*") {
+            self.status = completion.status();
+        }
+        if let Some(measured) = self.measured.as_mut() {
+            measured.observe(responses);
+        }
+    }
+
+    /// Records the command once its handler is done with it, leaving the session as `after`.
+    async fn finish(self, after: &Context) {
+        let verb = self.command.command();
+        self.metrics.command_completed(&verb, self.started.elapsed());
+        if let Some(measured) = self.measured {
+            spawn(measured.finish());
+        }
+        let authenticating = matches!(verb.as_str(), "LOGIN" | "AUTHENTICATE");
+        if authenticating && self.status == Some(ResponseStatus::NO) {
+            self.metrics.auth_failure();
+        }
+        let audit = match &self.audit {
+            Some(audit) => audit,
+            None => return,
+        };
+        let event = AuditEvent::of(&self.command, self.status, &self.before, after, &self.session);
+        if let Some(event) = event {
+            if let Err(e) = audit.record(&event).await {
+                warn!("Could not record {:?} in the audit log: {}", event.action, e);
+            }
+        }
+    }
+}
+
+/// Passes a command's responses on to the writer and applies its events to the session,
+/// then signals `completed` once the handler is done with the command. Its events have all
+/// been applied by then, so the next command sees the state they leave behind.
+async fn forward(
+    responses: Receiver<Vec<Response>>,
+    events: Receiver<Event>,
+    mut writer: Sender<Vec<Response>>,
+    context: Arc<RwLock<Context>>,
+    closing: Sender<()>,
+    completed: Sender<()>,
+    mut dispatched: Dispatched,
+) {
+    let mut updates = select(responses.map(Either::Left), events.map(Either::Right));
+    while let Some(update) = updates.next().await {
+        match update {
+            Either::Left(responses) => {
+                dispatched.observe(&responses);
+                let _ = writer.send(responses).await;
+            }
+            Either::Right(event) => {
+                let mut context = context.write().await;
+                match event {
+                    Event::AUTH(user) => {
+                        context.user.replace(user);
+                    }
+                    Event::SELECT(folder, permission) => {
+                        context.current_folder.replace(folder);
+                        context.permission.replace(permission);
+                    }
+                    Event::UNAUTH() => {
+                        context.current_folder.take();
+                        context.permission.take();
+                        context.user.take();
+                        context.logged_out = true;
+                        let _ = closing.unbounded_send(());
+                    }
+                }
+            }
+        }
+    }
+    let after = context.read().await.clone();
+    dispatched.finish(&after).await;
+    let _ = completed.unbounded_send(());
+}
+
+/// The most a connection keeps allocated for writing between responses, so that one large
+/// FETCH does not pin its memory for the rest of the session.
+const MAX_RETAINED_BUFFER: usize = 1024 * 1024;
+
+/// Writes `responses` to the client in a single write and flushes them, so that a large
+/// answer goes out in full segments rather than two tiny writes per line. `buffer` is
+/// reused from one call to the next.
+async fn send<W: futures::AsyncWrite + Unpin>(
+    output: &mut W,
+    responses: Vec<Response>,
+    transcript: Option<&Transcript>,
+    buffer: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    buffer.clear();
+    for reply in responses {
+        trace!("Sending {}", reply);
+        let start = buffer.len();
+        std::io::Write::write_fmt(buffer, format_args!("{}", reply))?;
+        if let Some(transcript) = transcript {
+            transcript.server(&String::from_utf8_lossy(&buffer[start..]));
+        }
+        buffer.extend_from_slice(b"\r\n");
+        if let Some((literal, rest)) = reply.literal() {
+            // What is buffered goes first, then the body without being buffered at all.
+            output.write_all(buffer).await?;
+            buffer.clear();
+            stream_literal(output, literal).await?;
+            if let Some(transcript) = transcript {
+                transcript.server(&format!("<{} bytes>{}", literal.length(), rest));
+            }
+            buffer.extend_from_slice(rest.as_bytes());
+            buffer.extend_from_slice(b"\r\n");
+        }
+    }
+    output.write_all(buffer).await?;
+    if buffer.capacity() > MAX_RETAINED_BUFFER {
+        *buffer = vec![];
     }
+    output.flush().await
+}
+
+/// Copies the body of `literal` to the client. Fewer bytes than the literal announced cannot
+/// be made up for, so the connection must be closed when that happens.
+async fn stream_literal<W: futures::AsyncWrite + Unpin>(
+    output: &mut W,
+    literal: &Literal,
+) -> std::io::Result<()> {
+    let body = literal
+        .take()
+        .ok_or_else(|| std::io::Error::other("The literal was already sent"))?;
+    let copied = futures::io::copy(body.take(literal.length()), output).await?;
+    if copied < literal.length() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!(
+                "The message ended after {} of {} bytes",
+                copied,
+                literal.length()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Writes what it is asked to the client until every sender is gone.
+async fn write(
+    output: Output,
+    mut writes: impl Stream<Item = Write> + Unpin,
+    closing: Sender<()>,
+    transcript: Option<Arc<Transcript>>,
+    mut buffer: Buffer,
+    clock: Option<Arc<WriteClock>>,
+) {
+    // Once writing fails, responses are still taken and dropped so that handlers
+    // answering this connection are not stopped by a closed channel.
+    let mut output = Some(output);
+    while let Some(write) = writes.next().await {
+        let (response, swap) = match write {
+            Write::Responses(response) => (response, None),
+            Write::Swap(response, give, take) => (response, Some((give, take))),
+        };
+        let stream = match output.as_mut() {
+            Some(stream) => stream,
+            None => continue,
+        };
+        let tags = clock.as_ref().map(|_| slowlog::tags(&response));
+        let started = Instant::now();
+        let sent = send(stream, response, transcript.as_deref(), &mut buffer).await;
+        if let (Some(clock), Some(tags)) = (&clock, tags) {
+            clock.wrote(&tags, started.elapsed());
+        }
+        if let Err(e) = sent {
+            warn!("Could not write to the client: {}", e);
+            output = None;
+            let _ = closing.unbounded_send(());
+            continue;
+        }
+        if let Some((give, take)) = swap {
+            output = match output.take().map(|stream| give.send(stream)) {
+                Some(Ok(())) => take.await.ok(),
+                _ => None,
+            };
+            if output.is_none() {
+                let _ = closing.unbounded_send(());
+            }
+        }
+    }
+}
+
+/// The write half of the stream and what is to be written to it, until `handle` starts
+/// writing.
+struct Unstarted {
+    output: Output,
+    responses: Receiver<Vec<Response>>,
+    swaps: Receiver<Write>,
 }
 
 #[derive(Debug, Clone)]
@@ -61,124 +501,1219 @@ pub struct Request {
     pub responder: Sender<Vec<Response>>,
     pub events: Sender<Event>,
     pub context: Context,
+    /// The span of the command, for handlers to run their work in so that index and store
+    /// calls are traced as part of it.
+    pub span: Span,
+    /// Asks the client for more lines of the command.
+    pub continuation: Continuation,
+    /// Where the time the command spends in the index and store is added up for the slow
+    /// log. Handlers run their work in `timings.scope` for it to be.
+    pub timings: Timings,
+}
+
+/// A handler's request for more of its command from the client, see `Continuation`.
+#[derive(Debug)]
+pub(crate) struct Prompt {
+    /// The text of the continuation request, or `None` to read on without sending one, as
+    /// for a non-synchronizing literal.
+    text: Option<String>,
+    /// The length of a literal to read before the line.
+    literal: Option<usize>,
+    /// Whether the literal is read past rather than kept, leaving it empty in the reply.
+    discard: bool,
+    reply: oneshot::Sender<(Vec<u8>, String)>,
+}
+
+/// Lets a handler ask the client for more lines before answering its command, as
+/// AUTHENTICATE does for each round of a SASL exchange, or for the literal at the end of
+/// its command line, as APPEND does for the message. Lines are only read while the
+/// connection waits for commands to finish, so only handlers of commands which run alone
+/// (see `State::runs_concurrently`) should ask for them.
+#[derive(Debug, Clone, Default)]
+pub struct Continuation {
+    prompts: Option<Sender<Prompt>>,
+}
+
+impl Continuation {
+    /// Sends `+ <text>` to the client and returns the line it answers with, without its
+    /// CRLF. Fails when the command did not come from a `Connection`, or the client sent no
+    /// line in time.
+    pub async fn request(&self, text: &str) -> Result<String> {
+        let (_, line) = self.prompt(Some(text), None, false).await?;
+        Ok(line)
+    }
+
+    /// Reads the literal of `length` bytes the command line announced, and the rest of the
+    /// command after it, without its CRLF. The client is asked for a synchronizing literal
+    /// (`{N}`) with `+ Ready for literal data`, and sends a non-synchronizing one (`{N+}`)
+    /// without being asked.
+    pub async fn literal(&self, length: usize, synchronizing: bool) -> Result<(Vec<u8>, String)> {
+        let text = synchronizing.then_some("Ready for literal data");
+        self.prompt(text, Some(length), false).await
+    }
+
+    /// Reads past the non-synchronizing literal of `length` bytes the command line announced
+    /// without keeping it, as when it is too large to accept, and returns the rest of the
+    /// command after it. RFC 7888 section 4 has the server read such a literal even though
+    /// it refuses the command, or the client's data would be taken for commands.
+    pub async fn discard(&self, length: usize) -> Result<String> {
+        let (_, line) = self.prompt(None, Some(length), true).await?;
+        Ok(line)
+    }
+
+    async fn prompt(
+        &self,
+        text: Option<&str>,
+        literal: Option<usize>,
+        discard: bool,
+    ) -> Result<(Vec<u8>, String)> {
+        let prompts = self
+            .prompts
+            .as_ref()
+            .ok_or("the command cannot be continued")?;
+        let (reply, replied) = oneshot::channel();
+        let prompt = Prompt {
+            text: text.map(str::to_string),
+            literal,
+            discard,
+            reply,
+        };
+        prompts
+            .unbounded_send(prompt)
+            .map_err(|_| "the connection has closed")?;
+        replied
+            .await
+            .map_err(|_| "the client did not continue the command".into())
+    }
 }
 
 impl Connection {
-    pub async fn new(stream: TcpStream) -> Result<Self> {
-        let stream = Arc::new(stream);
-        let output = Arc::clone(&stream);
-        let (mut response_sender, mut response_receiver): (
+    pub async fn new(stream: Box<dyn Io>, context: Context) -> Result<Self> {
+        let (input, output) = stream.split();
+        let (response_sender, response_receiver): (
             Sender<Vec<Response>>,
             Receiver<Vec<Response>>,
         ) = unbounded();
-        let context = Arc::new(RwLock::new(Context::default()));
-        let ctx = context.clone();
-        let (event_sender, mut event_receiver): (Sender<Event>, Receiver<Event>) = unbounded();
-        let (shutdown_signal, shutdown): (oneshot::Sender<()>, oneshot::Receiver<()>) = channel();
-        trace!(
-            "Spawning writer thread for connection from {}",
-            &stream.peer_addr()?
-        );
-        let state_manager = spawn(async move {
-            while let Some(event) = event_receiver.next().await {
-                match event {
-                    Event::AUTH(user) => {
-                        let mut lock = ctx.write().await;
-                        lock.user.replace(user);
-                        drop(lock);
-                    },
-                    Event::SELECT(folder) => {
-                        let mut lock = ctx.write().await;
-                        lock.current_folder.replace(folder);
-                        drop(lock);
-                    }
-                    Event::UNAUTH() => {
-                        let mut lock = ctx.write().await;
-                        lock.current_folder.take();
-                        lock.user.take();
-                        drop(lock);
-                        break;
-                    }
-                }
-            }
-            shutdown_signal.send(()).unwrap();
-        });
-        let writer = spawn(async move {
-            let mut output = &*output;
-            while let Some(response) = response_receiver.next().await {
-                for reply in response {
-                    trace!(
-                        "Sending {} to client at {}",
-                        &reply.to_string(),
-                        &output.peer_addr().unwrap()
-                    );
-                    output.write(reply.to_string().as_bytes()).await.unwrap();
-                    output.write("\r\n".as_bytes()).await.unwrap();
-                }
-            }
-        });
-        info!("Sending greeting to client at {}", &stream.peer_addr()?);
-        response_sender
-            .send(vec![Response::new(
-                "*",
-                ResponseStatus::OK,
-                "IMAP4rev2 server ready",
-            )])
-            .await?;
-        trace!(
-            "Reading input from connection at {}",
-            &stream.peer_addr().unwrap()
-        );
+        let (swaps, swap_receiver): (Sender<Write>, Receiver<Write>) = unbounded();
+        let peer = context.describe_peer();
+        let context = Arc::new(RwLock::new(context));
+        let (closing, closed): (Sender<()>, Receiver<()>) = unbounded();
+        let (completing, completed): (Sender<()>, Receiver<()>) = unbounded();
+        let (prompts, prompted): (Sender<Prompt>, Receiver<Prompt>) = unbounded();
+        let id = uuid();
+        let span = info_span!("connection", id = %id, peer = %peer);
         Ok(Connection {
-            state_manager: Some(state_manager),
-            state_updater: event_sender,
+            id,
+            span,
+            closing,
+            completed,
+            completing,
+            in_flight: 0,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
             state: context,
-            writer: Some(writer),
-            stream,
+            writer: None,
+            unstarted: Some(Unstarted {
+                output,
+                responses: response_receiver,
+                swaps: swap_receiver,
+            }),
+            input: Some(Framed::new(input)),
+            swaps,
             responder: response_sender,
-            shutdown,
+            prompts,
+            prompted,
+            closed,
+            timeouts: Timeouts::default(),
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            rate_limit: None,
+            metrics: Arc::new(Metrics::new()),
+            audit: None,
+            capture: None,
+            middleware: Pipeline::default(),
+            stop: Shutdown::new(),
+            broadcasts: None,
+            session: None,
+            terminated: Shutdown::new(),
+            slow_log: None,
+            write_clock: Arc::new(WriteClock::default()),
+            buffers: None,
+            capabilities: None,
+            greeting: Greeting::default(),
+            #[cfg(feature = "tls")]
+            starttls: None,
         })
     }
 
-    pub async fn handle(mut self, handler: Arc<HashMap<String, UnboundedSender<Request>>>) -> Result<()> {
-        let input = BufReader::new(&*self.stream);
-        let mut lines = input.lines();
-        while let Some(line) = lines.next().await {
-            let shutdown = match self.shutdown.try_recv() {
-                Ok(signal) => {
-                    match signal {
-                        Some(..) => true,
-                        None => false,
+    /// A connection over any byte stream, such as one end of an in-memory pipe or a TLS
+    /// stream accepted by the embedder.
+    pub async fn from_duplex<S>(stream: S, context: Context) -> Result<Self>
+    where
+        S: futures::AsyncRead + futures::AsyncWrite + Send + Unpin + 'static,
+    {
+        Self::new(Box::new(stream), context).await
+    }
+
+    /// A UUID identifying this connection in the logs.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// The longest command line accepted, in bytes including the CRLF. Longer lines are
+    /// discarded and answered with `BAD`.
+    pub fn with_max_line_length(mut self, max_line_length: usize) -> Self {
+        self.max_line_length = max_line_length;
+        self
+    }
+
+    /// The number of commands which may run at once. Commands which change or depend on
+    /// the state of the session always run alone.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    /// Limits how fast the client may send commands.
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit.bucket());
+        self
+    }
+
+    /// Logs the commands of this connection which go over the thresholds of `slow_log`.
+    pub fn with_slow_log(mut self, slow_log: SlowLog) -> Self {
+        self.slow_log = Some(slow_log);
+        self
+    }
+
+    /// Records the commands of this connection in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Records logins, logouts and changes to mailboxes made over this connection in `audit`.
+    pub fn with_audit_log(mut self, audit: Arc<dyn AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Captures the protocol exchange, with credentials redacted.
+    pub fn with_capture(mut self, capture: Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Runs every command through `middleware` on its way to its handler.
+    pub fn with_middleware(mut self, middleware: Pipeline) -> Self {
+        self.middleware = middleware;
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.stop = shutdown;
+        self
+    }
+
+    /// Writes the responses sent through `broadcast`, such as alerts, to the client.
+    pub fn with_broadcast(mut self, broadcast: &Broadcast) -> Self {
+        self.broadcasts = Some(broadcast.subscribe(self.responder.clone()));
+        self
+    }
+
+    /// Lists the connection in `sessions` while it is open, from where it can be terminated.
+    pub fn with_sessions(mut self, sessions: &Sessions) -> Self {
+        let context = self
+            .state
+            .try_read()
+            .map(|context| context.clone())
+            .unwrap_or_default();
+        let session = sessions.register(&self.id, context.peer());
+        session.update(&context);
+        self.terminated = session.terminated();
+        self.session = Some(session);
+        self
+    }
+
+    /// Reads commands and writes responses with buffers taken from `buffers`, which get them
+    /// back when the connection closes.
+    pub fn with_buffer_pool(mut self, buffers: BufferPool) -> Self {
+        self.input = self
+            .input
+            .take()
+            .map(|input| Framed::with_buffer(input.into_parts().0, buffers.take()));
+        self.buffers = Some(buffers);
+        self
+    }
+
+    /// Lists the capabilities of `capabilities` in the greeting, so that clients need not ask
+    /// for them with CAPABILITY.
+    pub fn with_capabilities(mut self, capabilities: Advertised) -> Self {
+        self.capabilities = Some(capabilities);
+        self
+    }
+
+    /// Greets the client as `greeting` says rather than with the default greeting.
+    pub fn with_greeting(mut self, greeting: Greeting) -> Self {
+        self.greeting = greeting;
+        self
+    }
+
+    /// Offers STARTTLS with `acceptor`. The context should say so with
+    /// `Context::with_starttls`, for CAPABILITY to advertise it.
+    #[cfg(feature = "tls")]
+    pub fn with_starttls(mut self, acceptor: crate::listener::TlsAcceptor) -> Self {
+        self.starttls = Some(acceptor);
+        self
+    }
+
+    /// Answers STARTTLS and negotiates TLS, returning the input to read from afterwards.
+    #[cfg(feature = "tls")]
+    async fn starttls(&mut self, input: Input, command: &Command) -> Result<Input> {
+        let acceptor = match &self.starttls {
+            Some(acceptor) if !self.state.read().await.is_secure() => acceptor.clone(),
+            _ => {
+                self.responder
+                    .send(vec![Response::new(
+                        &command.tag(),
+                        ResponseStatus::BAD,
+                        "STARTTLS is not available.",
+                    )])
+                    .await?;
+                return Ok(input);
+            }
+        };
+        let (give, given) = oneshot::channel();
+        let (take, taken) = oneshot::channel();
+        let ready = Response::new(&command.tag(), ResponseStatus::OK, "Begin TLS negotiation now.");
+        self.swaps.send(Write::Swap(vec![ready], give, taken)).await?;
+        // Anything the client sent after STARTTLS, before the handshake, is dropped rather
+        // than being treated as if it had come over TLS.
+        let (input, buffer) = input.into_parts();
+        let output = given.await?;
+        let stream = input.reunite(output)?;
+        let stream: Box<dyn Io> = Box::new(acceptor.accept(stream).await?);
+        let (input, output) = stream.split();
+        if take.send(output).is_err() {
+            return Err(Box::new(std::io::Error::from(std::io::ErrorKind::BrokenPipe)));
+        }
+        let mut context = self.state.write().await;
+        *context = context.clone().with_secure();
+        drop(context);
+        Ok(Framed::with_buffer(input, buffer))
+    }
+
+    pub async fn handle(self, handler: Arc<Handlers>) -> Result<()> {
+        let span = self.span.clone();
+        self.serve(handler).instrument(span).await
+    }
+
+    async fn serve(mut self, handler: Arc<Handlers>) -> Result<()> {
+        let (mut input, unstarted) = match (self.input.take(), self.unstarted.take()) {
+            (Some(input), Some(unstarted)) => (input, unstarted),
+            _ => return Ok(()),
+        };
+        let open = |capture: &Capture| match Transcript::open(capture, &self.id) {
+            Ok(transcript) => Some(transcript),
+            Err(e) => {
+                warn!("Could not capture the protocol exchange: {}", e);
+                None
+            }
+        };
+        let transcript = self.capture.as_ref().and_then(open).map(Arc::new);
+        let context = self.state.read().await.clone();
+        let preauthenticated = context.is_authenticated();
+        let capabilities = match (&self.capabilities, preauthenticated) {
+            (Some(capabilities), true) => Some(code(&capabilities.authenticated().await)),
+            (Some(capabilities), false) => Some(code(&capabilities.list(&context).await)),
+            (None, _) => None,
+        };
+        let greeting = self.greeting.response(preauthenticated, capabilities.as_deref());
+        info!("Sending greeting");
+        self.responder.send(vec![greeting]).await?;
+        trace!("Spawning writer thread");
+        let writes = select(unstarted.responses.map(Write::Responses), unstarted.swaps);
+        let buffer = self
+            .buffers
+            .as_ref()
+            .map_or_else(Buffer::new, BufferPool::take);
+        let writer = write(
+            unstarted.output,
+            writes,
+            self.closing.clone(),
+            transcript.clone(),
+            buffer,
+            self.slow_log.map(|_| self.write_clock.clone()),
+        );
+        self.writer = Some(spawn(writer.instrument(self.span.clone())));
+        trace!("Reading input");
+        loop {
+            let context = self.state.read().await;
+            let idle = match context.is_authenticated() {
+                true => self.timeouts.authenticated,
+                false => self.timeouts.unauthenticated,
+            };
+            if let Some(session) = &self.session {
+                session.update(&context);
+            }
+            drop(context);
+            let read = input.read_line(self.max_line_length);
+            let read = Box::pin(timeout(idle, read));
+            let signals =
+                future::select(Box::pin(self.stop.wait()), Box::pin(self.terminated.wait()));
+            let stop = future::select(signals, self.closed.next());
+            let read = match future::select(read, stop).await {
+                Either::Left((read, _)) => read,
+                // LOGOUT has been answered or the client has gone, so there is nothing more
+                // to say.
+                Either::Right((Either::Right(..), _)) => break,
+                Either::Right((Either::Left((Either::Left(..), _)), _)) => {
+                    debug!("Closing the connection for shutdown");
+                    self.responder
+                        .send(vec![Response::untagged("BYE Server shutting down")])
+                        .await?;
+                    break;
+                }
+                Either::Right((Either::Left((Either::Right(..), _)), _)) => {
+                    info!("Closing the connection at the request of an administrator");
+                    let bye = Response::untagged("BYE Session terminated by the administrator");
+                    self.responder.send(vec![bye]).await?;
+                    break;
+                }
+            };
+            match read {
+                Ok(read) => match read? {
+                    Line::End => break,
+                    Line::Complete => {}
+                    Line::TooLong => {
+                        debug!("Discarded an overlong line");
+                        let line = String::from_utf8_lossy(input.line());
+                        let response =
+                            Response::new(tag(&line), ResponseStatus::BAD, "Command line too long");
+                        self.responder.send(vec![response]).await?;
+                        continue;
                     }
                 },
                 Err(..) => {
-                    true
+                    debug!("Logging out idle client");
+                    self.responder
+                        .send(vec![Response::untagged("BYE Autologout")])
+                        .await?;
+                    break;
+                }
+            }
+            // A line the client got wrong is answered with BAD, and the session carries on.
+            let line = match std::str::from_utf8(input.line()) {
+                Ok(line) => line.trim_end_matches(['\r', '\n']),
+                Err(..) => {
+                    debug!("Read a line which is not UTF-8");
+                    let line = String::from_utf8_lossy(input.line());
+                    let tag = match tag(&line) {
+                        tag if tag.contains(char::REPLACEMENT_CHARACTER) => "*",
+                        tag => tag,
+                    };
+                    let response =
+                        Response::new(tag, ResponseStatus::BAD, "Command line is not UTF-8");
+                    self.responder.send(vec![response]).await?;
+                    continue;
                 }
             };
-            if shutdown {
-                break;
-            }
-            let line = line?;
-            trace!(
-                "Read {} from client at {}",
-                &line,
-                &self.stream.peer_addr().unwrap()
-            );
-            let command = Command::parse(&line)?;
-            if let Some(mut channel) = handler.get(&command.command()) {
-                let ctx = self.state.read().await;
-                channel.send(Request{command, responder: self.responder.clone(), context: ctx.clone(), events: self.state_updater.clone()}).await?;
-                drop(ctx);
+            trace!("Read {}", line);
+            if let Some(transcript) = &transcript {
+                transcript.client(line);
+            }
+            let parsing = Instant::now();
+            let command = match Command::parse(line) {
+                Ok(command) => command,
+                Err(..) => {
+                    let tag = if line.is_empty() { "*" } else { line };
+                    let response = Response::new(tag, ResponseStatus::BAD, "Missing command");
+                    self.responder.send(vec![response]).await?;
+                    continue;
+                }
             };
+            let parse = parsing.elapsed();
+            let span = info_span!(
+                "command",
+                tag = %command.tag(),
+                command = %command.command(),
+                user = field::Empty,
+            );
+            self.metrics.command(&command.command());
+            if let Some(session) = &self.session {
+                session.command();
+            }
+            if let Some(bucket) = self.rate_limit.as_mut() {
+                // LOGOUT is always let through, so that a client can leave.
+                if let (Err(wait), false) = (bucket.take(), command.command() == "LOGOUT") {
+                    match bucket.limit().excess() {
+                        Excess::Delay => {
+                            trace!(parent: &span, "Delaying by {:?}", wait);
+                            async_std::task::sleep(wait).await;
+                            let _ = bucket.take();
+                        }
+                        Excess::Reject => {
+                            let tag = command.tag();
+                            let message = "[LIMIT] Too many commands, please slow down.";
+                            let response = Response::new(&tag, ResponseStatus::NO, message);
+                            self.responder.send(vec![response]).await?;
+                            continue;
+                        }
+                    }
+                }
+            }
+            // From RFC 9051 section 5.5, commands which change the state of the session, or
+            // depend on it, wait for the commands before them and run alone.
+            let exclusive = !State::runs_concurrently(&command);
+            let limit = if exclusive { 1 } else { self.max_in_flight };
+            self.wait_for_commands(limit, &mut input, transcript.as_deref()).await?;
+            let rejection = State::check(&command, &*self.state.read().await);
+            if let Some(rejection) = rejection {
+                self.responder.send(vec![rejection]).await?;
+                continue;
+            }
+            #[cfg(feature = "tls")]
+            if command.command() == "STARTTLS" {
+                input = self.starttls(input, &command).await?;
+                continue;
+            }
+            let channel = handler
+                .get(&command.command())
+                .or_else(|| handler.get(EXTENSIONS));
+            if let Some(mut channel) = channel {
+                let context = self.state.read().await.clone();
+                let measured = self.slow_log.map(|log| {
+                    Measured::new(log, &command, &context, parse, &self.write_clock)
+                });
+                let timings = measured
+                    .as_ref()
+                    .map_or_else(Timings::new, |measured| measured.timings.clone());
+                let dispatched = Dispatched {
+                    command: command.clone(),
+                    before: context.clone(),
+                    session: self.id.clone(),
+                    started: Instant::now(),
+                    metrics: self.metrics.clone(),
+                    audit: self.audit.clone(),
+                    status: None,
+                    measured,
+                };
+                let (responder, responses) = unbounded();
+                let (events, updates) = unbounded();
+                if let Some(user) = context.user() {
+                    span.record("user", user.name().as_str());
+                }
+                debug!(parent: &span, "Dispatching command");
+                let continuation = Continuation {
+                    prompts: Some(self.prompts.clone()),
+                };
+                let request = Request {
+                    command,
+                    responder,
+                    context,
+                    events,
+                    span: span.clone(),
+                    continuation,
+                    timings,
+                };
+                if self.middleware.is_empty() {
+                    channel.send(request).await?;
+                } else {
+                    let middleware = self.middleware.clone();
+                    let channel = channel.clone();
+                    let answer = async move { middleware.answer(channel, request).await };
+                    spawn(answer.instrument(span.clone()));
+                }
+                self.in_flight += 1;
+                spawn(forward(
+                    responses,
+                    updates,
+                    self.responder.clone(),
+                    self.state.clone(),
+                    self.closing.clone(),
+                    self.completing.clone(),
+                    dispatched,
+                ).instrument(span));
+                if exclusive {
+                    self.wait_for_commands(1, &mut input, transcript.as_deref()).await?;
+                }
+            } else {
+                debug!(parent: &span, "No handler for command");
+                self.responder.send(vec![unknown_command(&command)]).await?;
+            }
         }
-        drop(&self.responder);
+        drop(self.broadcasts);
+        drop(self.session);
+        drop(self.responder);
+        drop(self.swaps);
         if let Some(writer) = self.writer.take() {
             writer.await
         }
-        drop(&self.state_updater);
-        if let Some(updater) = self.state_manager.take() {
-            updater.await
+        Ok(())
+    }
+
+    /// Waits until fewer than `limit` commands are running, reading the lines handlers ask
+    /// the client for meanwhile.
+    async fn wait_for_commands(
+        &mut self,
+        limit: usize,
+        input: &mut Input,
+        transcript: Option<&Transcript>,
+    ) -> Result<()> {
+        while self.in_flight >= limit {
+            let next = match future::select(self.completed.next(), self.prompted.next()).await {
+                Either::Left((completed, _)) => Either::Left(completed),
+                Either::Right((prompt, _)) => Either::Right(prompt),
+            };
+            match next {
+                Either::Left(None) => break,
+                Either::Left(Some(())) => self.in_flight -= 1,
+                Either::Right(Some(prompt)) => {
+                    self.continue_command(prompt, input, transcript).await?
+                }
+                Either::Right(None) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the continuation request of `prompt`, if it has one, and answers it with the
+    /// literal it asks for and the next line from the client. A line which is too long, or
+    /// anything not sent in time, is not given to the handler.
+    async fn continue_command(
+        &mut self,
+        prompt: Prompt,
+        input: &mut Input,
+        transcript: Option<&Transcript>,
+    ) -> Result<()> {
+        if let Some(text) = &prompt.text {
+            self.responder.send(vec![Response::continuation(text)]).await?;
+        }
+        let idle = match self.state.read().await.is_authenticated() {
+            true => self.timeouts.authenticated,
+            false => self.timeouts.unauthenticated,
+        };
+        let mut literal = vec![];
+        if let Some(length) = prompt.literal {
+            let read = match prompt.discard {
+                true => timeout(idle, input.skip(length)).await,
+                false => timeout(idle, input.read_literal(length)).await,
+            };
+            match read {
+                Ok(Ok(Line::Complete)) => {
+                    literal = input.line().to_vec();
+                    if let Some(transcript) = transcript {
+                        transcript.client(&format!("<{} bytes>", length));
+                    }
+                }
+                Ok(Ok(..)) | Err(..) => {
+                    debug!("The client did not send the literal");
+                    return Ok(());
+                }
+                Ok(Err(e)) => return Err(Box::new(e)),
+            }
+        }
+        let read = input.read_line(self.max_line_length);
+        match timeout(idle, read).await {
+            Ok(Ok(Line::Complete)) => {
+                let line = String::from_utf8_lossy(input.line());
+                let line = line.trim_end_matches(['\r', '\n']);
+                if let Some(transcript) = transcript {
+                    transcript.client(line);
+                }
+                let _ = prompt.reply.send((literal, line.to_string()));
+            }
+            Ok(Ok(..)) | Err(..) => debug!("The client did not continue the command"),
+            Ok(Err(e)) => return Err(Box::new(e)),
         }
         Ok(())
     }
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_std::io::BufReader;
+    use async_std::os::unix::net::UnixStream;
+    use async_std::path::PathBuf;
+    use async_std::prelude::*;
+    use async_std::task::spawn;
+
+    use futures::channel::mpsc::unbounded;
+    use futures::SinkExt;
+
+    use super::{Connection, Context, Request, Timeouts, MIN_AUTOLOGOUT};
+    use crate::audit::{Action, AuditEvent, AuditLog};
+    use crate::auth::User;
+    use crate::capture::Capture;
+    use crate::handlers::append::AppendHandler;
+    use crate::handlers::authenticate::AuthenticateHandler;
+    use crate::handlers::fetch::FetchHandler;
+    use crate::handlers::logout::LogoutHandler;
+    use crate::handlers::{Handle, Workers};
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::reindex::reindex;
+    use crate::index::{Index, Owner};
+    use crate::limits::{Excess, RateLimit};
+    use crate::server::{Literal, Response, ResponseStatus};
+    use crate::sessions::Sessions;
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message};
+
+    async fn idle(context: Context, timeouts: Timeouts) -> Vec<String> {
+        let (client, server) = UnixStream::pair().unwrap();
+        let connection = Connection::new(Box::new(server), context).await.unwrap();
+        spawn(connection.with_timeouts(timeouts).handle(Arc::new(HashMap::new())));
+        let mut lines = BufReader::new(client).lines();
+        let mut received = vec![];
+        while let Some(line) = lines.next().await {
+            received.push(line.unwrap());
+        }
+        received
+    }
+
+    #[async_std::test]
+    async fn test_autologout() {
+        let short = Duration::from_millis(50);
+        let expected = vec!["* OK IMAP4rev2 server ready", "* BYE Autologout"];
+
+        let timeouts = Timeouts::new().with_login_timeout(short);
+        assert_eq!(idle(Context::default(), timeouts).await, expected);
+
+        // The login timeout does not apply once authenticated.
+        let timeouts = Timeouts {
+            authenticated: Duration::from_millis(100),
+            unauthenticated: Duration::from_secs(60),
+        };
+        let context = Context::of(Some(User::anonymous("public")), None);
+        assert_eq!(idle(context, timeouts).await, expected);
+    }
+
+    #[test]
+    fn test_minimum_autologout() {
+        let timeouts = Timeouts::new().with_autologout(Duration::from_secs(60));
+        assert_eq!(timeouts.authenticated, MIN_AUTOLOGOUT);
+        let timeouts = Timeouts::new().with_autologout(2 * MIN_AUTOLOGOUT);
+        assert_eq!(timeouts.authenticated, 2 * MIN_AUTOLOGOUT);
+    }
+
+    #[async_std::test]
+    async fn test_connection_ids() {
+        let (_client, server) = UnixStream::pair().unwrap();
+        let first = Connection::new(Box::new(server), Context::default()).await.unwrap();
+        let (_client, server) = UnixStream::pair().unwrap();
+        let second = Connection::new(Box::new(server), Context::default()).await.unwrap();
+        assert_ne!(first.id(), second.id());
+        let groups: Vec<usize> = first.id().split('-').map(str::len).collect();
+        assert_eq!(groups, vec![8, 4, 4, 4, 12]);
+        assert_eq!(&first.id()[14..15], "4");
+    }
+
+    #[async_std::test]
+    async fn test_terminated_session() {
+        let sessions = Sessions::new();
+        let (client, server) = UnixStream::pair().unwrap();
+        let context = Context::of(Some(User::new("me", "secret")), None);
+        let connection = Connection::new(Box::new(server), context)
+            .await
+            .unwrap()
+            .with_sessions(&sessions);
+        let id = connection.id().to_string();
+        let connection = spawn(connection.handle(Arc::new(HashMap::new())));
+        let mut lines = BufReader::new(client).lines();
+        assert_eq!(lines.next().await.unwrap().unwrap(), "* OK IMAP4rev2 server ready");
+
+        assert_eq!(sessions.list()[0].id, id);
+        assert_eq!(sessions.terminate_user("me"), 1);
+        assert_eq!(
+            lines.next().await.unwrap().unwrap(),
+            "* BYE Session terminated by the administrator"
+        );
+        assert!(lines.next().await.is_none());
+        connection.await.unwrap();
+        assert!(sessions.list().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_logout_closes_connection() {
+        let (sender, requests) = unbounded();
+        spawn(async move { LogoutHandler {}.start(requests).await });
+        let handlers = Arc::new(HashMap::from([("LOGOUT".to_string(), sender)]));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let connection = Connection::new(Box::new(server), Context::default())
+            .await
+            .unwrap();
+        let connection = spawn(connection.handle(handlers));
+        client.write_all(b"a1 LOGOUT\r\n").await.unwrap();
+
+        // The client does not close its end, but still reaches the end of the stream.
+        let mut lines = BufReader::new(client.clone()).lines();
+        let mut received = vec![];
+        while let Some(line) = lines.next().await {
+            received.push(line.unwrap());
+        }
+        assert_eq!(
+            received,
+            vec![
+                "* OK IMAP4rev2 server ready",
+                "* BYE IMAP4rev2 server logging out",
+                "a1 OK LOGOUT completed. Goodbye!",
+            ]
+        );
+        connection.await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_continuation() {
+        let (sender, requests) = unbounded();
+        let mut authenticate = AuthenticateHandler::new().with_anonymous("public");
+        spawn(async move { authenticate.start(requests).await });
+        let handlers = Arc::new(HashMap::from([("AUTHENTICATE".to_string(), sender)]));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let connection = Connection::new(Box::new(server), Context::default())
+            .await
+            .unwrap();
+        let connection = spawn(connection.handle(handlers));
+        let mut lines = BufReader::new(client.clone()).lines();
+        lines.next().await.unwrap().unwrap();
+
+        client
+            .write_all(b"a1 AUTHENTICATE ANONYMOUS\r\n")
+            .await
+            .unwrap();
+        assert_eq!(lines.next().await.unwrap().unwrap(), "+ ");
+        // The response is not mistaken for a command.
+        client.write_all(b"c2lyaEBleGFtcGxlLmNvbQ==\r\n").await.unwrap();
+        assert_eq!(
+            lines.next().await.unwrap().unwrap(),
+            "a1 OK AUTHENTICATE completed."
+        );
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        connection.await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_oversized_literal_discarded() {
+        let (sender, requests) = unbounded();
+        let mut append = AppendHandler::new(
+            Arc::new(Box::new(InMemoryIndex::new())),
+            Arc::new(Box::new(InMemoryStore::new())),
+        )
+        .with_max_size(16);
+        spawn(async move { append.start(requests).await });
+        let handlers = Arc::new(HashMap::from([("APPEND".to_string(), sender)]));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let context = Context::of(Some(User::new("me", "secret")), None);
+        let connection = Connection::new(Box::new(server), context).await.unwrap();
+        let connection = spawn(connection.handle(handlers));
+        let body = "a9 FROB\r\n".repeat(8);
+        let command = format!("a1 APPEND INBOX {{{}+}}\r\n{}\r\n", body.len(), body);
+        client.write_all(command.as_bytes()).await.unwrap();
+        client.write_all(b"a2 FROB\r\n").await.unwrap();
+
+        // The literal is read past rather than taken for commands.
+        let mut lines = BufReader::new(client.clone()).lines();
+        lines.next().await.unwrap().unwrap();
+        assert_eq!(
+            lines.next().await.unwrap().unwrap(),
+            "a1 BAD [TOOBIG] Messages are limited to 16 bytes."
+        );
+        assert_eq!(
+            lines.next().await.unwrap().unwrap(),
+            "a2 BAD Command 'FROB' unknown"
+        );
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        connection.await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_stalled_append_holds_no_worker() {
+        let index: Arc<Box<dyn Index>> = Arc::new(Box::new(InMemoryIndex::new()));
+        let store: Arc<Box<dyn DataStore>> = Arc::new(Box::new(InMemoryStore::new()));
+        store
+            .append("me", "INBOX", Message::new(b"Hello"))
+            .await
+            .unwrap();
+        reindex(store.as_ref().as_ref(), index.as_ref().as_ref(), &Owner::new("me"), |_| {})
+            .await
+            .unwrap();
+        let workers = Workers::new(1);
+        let (appends, requests) = unbounded();
+        let mut append =
+            AppendHandler::new(index.clone(), store.clone()).with_workers(workers.clone());
+        spawn(async move { append.start(requests).await });
+        let (fetches, requests) = unbounded();
+        let mut fetch = FetchHandler::new(index, store).with_workers(workers);
+        spawn(async move { fetch.start(requests).await });
+        let handlers = Arc::new(HashMap::from([
+            ("APPEND".to_string(), appends),
+            ("FETCH".to_string(), fetches),
+        ]));
+
+        // The first client is asked for its literal and never sends it.
+        let (mut stalled, server) = UnixStream::pair().unwrap();
+        let context = Context::of(Some(User::new("me", "secret")), None);
+        let connection = Connection::new(Box::new(server), context).await.unwrap();
+        spawn(connection.handle(handlers.clone()));
+        stalled.write_all(b"a1 APPEND INBOX {10}\r\n").await.unwrap();
+        let mut lines = BufReader::new(stalled.clone()).lines();
+        lines.next().await.unwrap().unwrap();
+        assert!(lines.next().await.unwrap().unwrap().starts_with('+'));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let context = Context::of(Some(User::new("me", "secret")), Some(PathBuf::from("INBOX")));
+        let connection = Connection::new(Box::new(server), context).await.unwrap();
+        spawn(connection.handle(handlers));
+        client.write_all(b"a1 FETCH 1 (FLAGS)\r\n").await.unwrap();
+        let mut lines = BufReader::new(client.clone()).lines();
+        lines.next().await.unwrap().unwrap();
+        let fetched = async_std::future::timeout(Duration::from_secs(5), async {
+            (lines.next().await, lines.next().await)
+        });
+        let (fetched, completed) = fetched.await.unwrap();
+        assert!(fetched.unwrap().unwrap().starts_with("* 1 FETCH"));
+        assert_eq!(completed.unwrap().unwrap(), "a1 OK FETCH completed.");
+    }
+
+    #[async_std::test]
+    async fn test_unknown_command() {
+        let (sender, requests) = unbounded();
+        spawn(async move { LogoutHandler {}.start(requests).await });
+        let handlers = Arc::new(HashMap::from([("LOGOUT".to_string(), sender)]));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let connection = Connection::new(Box::new(server), Context::default())
+            .await
+            .unwrap();
+        let connection = spawn(connection.handle(handlers));
+        client.write_all(b"a1 FROB\r\na2 LOGOUT\r\n").await.unwrap();
+
+        let mut lines = BufReader::new(client.clone()).lines();
+        let mut received = vec![];
+        while let Some(line) = lines.next().await {
+            received.push(line.unwrap());
+        }
+        assert_eq!(received[1], "a1 BAD Command 'FROB' unknown");
+        assert_eq!(received.last().unwrap(), "a2 OK LOGOUT completed. Goodbye!");
+        connection.await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_malformed_lines() {
+        let (sender, requests) = unbounded();
+        spawn(async move { LogoutHandler {}.start(requests).await });
+        let handlers = Arc::new(HashMap::from([("LOGOUT".to_string(), sender)]));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let connection = Connection::new(Box::new(server), Context::default())
+            .await
+            .unwrap();
+        let connection = spawn(connection.handle(handlers));
+        client.write_all(b"a1\r\n").await.unwrap();
+        client.write_all(b"\xff\xfe NOOP\r\n").await.unwrap();
+        client.write_all(b"a2 NOOP\r\na3 LOGOUT\r\n").await.unwrap();
+
+        let mut lines = BufReader::new(client.clone()).lines();
+        let mut received = vec![];
+        while let Some(line) = lines.next().await {
+            received.push(line.unwrap());
+        }
+        assert_eq!(received[1], "a1 BAD Missing command");
+        assert_eq!(received[2], "* BAD Command line is not UTF-8");
+        assert_eq!(received[3], "a2 BAD Command 'NOOP' unknown");
+        assert_eq!(received.last().unwrap(), "a3 OK LOGOUT completed. Goodbye!");
+        connection.await.unwrap();
+    }
+
+    /// Keeps the events recorded in memory.
+    #[derive(Default)]
+    struct Recorded(std::sync::Mutex<Vec<AuditEvent>>);
+
+    #[async_trait::async_trait]
+    impl AuditLog for Recorded {
+        async fn record(&self, event: &AuditEvent) -> crate::util::Result<()> {
+            self.0.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+    }
+
+    #[async_std::test]
+    async fn test_audit_log() {
+        let (sender, requests) = unbounded();
+        spawn(async move { LogoutHandler {}.start(requests).await });
+        let handlers = Arc::new(HashMap::from([("LOGOUT".to_string(), sender)]));
+        let audit = Arc::new(Recorded::default());
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let context = Context::of(Some(User::new("username", "password")), None);
+        let connection = Connection::new(Box::new(server), context).await.unwrap();
+        let id = connection.id().to_string();
+        let connection = connection.with_audit_log(audit.clone());
+        let connection = spawn(connection.handle(handlers));
+        client.write_all(b"a1 NOOP\r\na2 LOGOUT\r\n").await.unwrap();
+        connection.await.unwrap();
+
+        let events = audit.0.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].action, Action::Logout);
+        assert_eq!(events[0].user.as_deref(), Some("username"));
+        assert_eq!(events[0].session, id);
+    }
+
+    #[async_std::test]
+    async fn test_capture() {
+        let (sender, requests) = unbounded();
+        spawn(async move { LogoutHandler {}.start(requests).await });
+        let handlers = Arc::new(HashMap::from([("LOGOUT".to_string(), sender)]));
+        let directory = std::env::temp_dir().join(format!(
+            "treasurmap-connection-capture-{}",
+            std::process::id()
+        ));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let connection = Connection::new(Box::new(server), Context::default())
+            .await
+            .unwrap();
+        let path = directory.join(format!("{}.log", connection.id()));
+        let connection = connection.with_capture(Capture::Directory(directory.clone()));
+        let connection = spawn(connection.handle(handlers));
+        let mut lines = BufReader::new(client.clone()).lines();
+        lines.next().await.unwrap().unwrap();
+        client
+            .write_all(b"a1 LOGIN me secret\r\na2 LOGOUT\r\n")
+            .await
+            .unwrap();
+        connection.await.unwrap();
+
+        let captured = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            captured,
+            "S: * OK IMAP4rev2 server ready\n\
+             C: a1 LOGIN [redacted]\n\
+             C: a2 LOGOUT\n\
+             S: * BYE IMAP4rev2 server logging out\n\
+             S: a2 OK LOGOUT completed. Goodbye!\n"
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_commands_are_checked_against_state() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let context = Context::of(Some(User::anonymous("public")), None);
+        let connection = Connection::new(Box::new(server), context).await.unwrap();
+        // No handlers are registered, so only the state machine can answer.
+        spawn(connection.handle(Arc::new(HashMap::new())));
+        client
+            .write_all(b"a1 LOGIN me password\r\na2 FETCH 1 FLAGS\r\n")
+            .await
+            .unwrap();
+        let mut lines = BufReader::new(client).lines();
+        let mut received = vec![];
+        for _ in 0..3 {
+            received.push(lines.next().await.unwrap().unwrap());
+        }
+        assert_eq!(
+            received,
+            vec![
+                "* OK IMAP4rev2 server ready",
+                "a1 BAD [CLIENTBUG] cannot LOGIN once authenticated.",
+                "a2 NO cannot FETCH before SELECT. Please SELECT a folder.",
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_write_failure_closes_connection() {
+        let (client, server) = UnixStream::pair().unwrap();
+        // Writes to a socket whose peer will not read fail with a broken pipe.
+        client.shutdown(std::net::Shutdown::Read).unwrap();
+        let connection = Connection::new(Box::new(server), Context::default())
+            .await
+            .unwrap();
+        let handled = async_std::future::timeout(
+            Duration::from_secs(5),
+            connection.handle(Arc::new(HashMap::new())),
+        )
+        .await;
+        assert!(matches!(handled, Ok(Ok(()))));
+        drop(client);
+    }
+
+    #[async_std::test]
+    async fn test_rate_limit() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let rate_limit = RateLimit::new(2, 0.01).with_excess(Excess::Reject);
+        let connection = Connection::new(Box::new(server), Context::default())
+            .await
+            .unwrap()
+            .with_rate_limit(rate_limit);
+        spawn(connection.handle(Arc::new(HashMap::new())));
+        // With no handlers, only rejected commands are answered.
+        client
+            .write_all(b"a1 NOOP\r\na2 NOOP\r\na3 NOOP\r\n")
+            .await
+            .unwrap();
+        let mut lines = BufReader::new(client).lines();
+        lines.next().await.unwrap().unwrap();
+        assert_eq!(
+            lines.next().await.unwrap().unwrap(),
+            "a3 NO [LIMIT] Too many commands, please slow down."
+        );
+    }
+
+    #[async_std::test]
+    async fn test_max_line_length() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let connection = Connection::new(Box::new(server), Context::default())
+            .await
+            .unwrap()
+            .with_max_line_length(32);
+        spawn(connection.handle(Arc::new(HashMap::new())));
+        let long = format!("a1 LOGIN {} password\r\n", "x".repeat(1000));
+        client.write_all(long.as_bytes()).await.unwrap();
+        // The connection carries on with the next line.
+        client.write_all(b"a2 FETCH 1 FLAGS\r\n").await.unwrap();
+        let mut lines = BufReader::new(client).lines();
+        lines.next().await.unwrap().unwrap();
+        assert_eq!(
+            lines.next().await.unwrap().unwrap(),
+            "a1 BAD Command line too long"
+        );
+        assert!(lines
+            .next()
+            .await
+            .unwrap()
+            .unwrap()
+            .starts_with("a2 NO cannot FETCH"));
+    }
+
+    /// Answers `FETCH <milliseconds>` after that long, or `NOOP` at once, each on its own task.
+    async fn sleepy(max_in_flight: usize, commands: &[u8]) -> Vec<String> {
+        let (sender, mut requests) = unbounded::<Request>();
+        spawn(async move {
+            while let Some(mut request) = requests.next().await {
+                spawn(async move {
+                    let delay = request.command.arg(0).parse().unwrap_or(0);
+                    async_std::task::sleep(Duration::from_millis(delay)).await;
+                    let tag = request.command.tag();
+                    let response = Response::new(&tag, ResponseStatus::OK, "done");
+                    request.responder.send(vec![response]).await.unwrap();
+                });
+            }
+        });
+        let handlers = HashMap::from([
+            ("FETCH".to_string(), sender.clone()),
+            ("SELECT".to_string(), sender),
+        ]);
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let context = Context::of(Some(User::anonymous("public")), Some("INBOX".into()));
+        let connection = Connection::new(Box::new(server), context)
+            .await
+            .unwrap()
+            .with_max_in_flight(max_in_flight);
+        spawn(connection.handle(Arc::new(handlers)));
+        client.write_all(commands).await.unwrap();
+        let count = commands.iter().filter(|byte| **byte == b'\n').count();
+        let mut lines = BufReader::new(client).lines();
+        lines.next().await.unwrap().unwrap();
+        let mut tags = vec![];
+        for _ in 0..count {
+            let line = lines.next().await.unwrap().unwrap();
+            tags.push(line.split(' ').next().unwrap().to_string());
+        }
+        tags
+    }
+
+    #[async_std::test]
+    async fn test_concurrent_commands() {
+        let commands = b"a1 FETCH 200\r\na2 FETCH 0\r\n";
+        assert_eq!(sleepy(2, commands).await, vec!["a2", "a1"]);
+        assert_eq!(sleepy(1, commands).await, vec!["a1", "a2"]);
+        // SELECT waits for the FETCH before it, and the FETCH after it waits for SELECT.
+        let commands = b"a1 FETCH 200\r\na2 SELECT 100\r\na3 FETCH 0\r\n";
+        assert_eq!(sleepy(8, commands).await, vec!["a1", "a2", "a3"]);
+    }
+
+    /// Counts the writes made to it.
+    #[derive(Default)]
+    struct Recording {
+        writes: usize,
+        written: Vec<u8>,
+    }
+
+    impl futures::AsyncWrite for Recording {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.writes += 1;
+            self.written.extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[async_std::test]
+    async fn test_responses_written_at_once() {
+        let mut responses: Vec<Response> = (1..=1000)
+            .map(|number| Response::untagged(&format!("{} FETCH (FLAGS (\\Seen))", number)))
+            .collect();
+        responses.push(Response::new("a1", ResponseStatus::OK, "FETCH completed."));
+        let mut output = Recording::default();
+        let mut buffer = vec![];
+        super::send(&mut output, responses, None, &mut buffer).await.unwrap();
+        // Two writes per line before responses were batched.
+        assert_eq!(output.writes, 1);
+        let written = String::from_utf8(output.written).unwrap();
+        assert!(written.starts_with("* 1 FETCH (FLAGS (\\Seen))\r\n* 2 FETCH"));
+        assert!(written.ends_with("* 1000 FETCH (FLAGS (\\Seen))\r\na1 OK FETCH completed.\r\n"));
+    }
+
+    #[async_std::test]
+    async fn test_literal_streamed() {
+        let body = futures::io::Cursor::new(b"Subject: big\r\n\r\nbody".to_vec());
+        let responses = vec![
+            Response::untagged("1 FETCH (UID 7 BODY[] ")
+                .with_literal(Literal::new(20, Box::pin(body)), " FLAGS (\\Seen))"),
+            Response::new("a1", ResponseStatus::OK, "FETCH completed."),
+        ];
+        let mut output = Recording::default();
+        let mut buffer = vec![];
+        super::send(&mut output, responses, None, &mut buffer).await.unwrap();
+        assert_eq!(
+            String::from_utf8(output.written).unwrap(),
+            "* 1 FETCH (UID 7 BODY[] {20}\r\nSubject: big\r\n\r\nbody FLAGS (\\Seen))\r\na1 OK FETCH completed.\r\n"
+        );
+
+        // A body shorter than announced closes the connection.
+        let short = futures::io::Cursor::new(b"body".to_vec());
+        let responses = vec![Response::untagged("1 FETCH (BODY[] ")
+            .with_literal(Literal::new(22, Box::pin(short)), ")")];
+        let mut output = Recording::default();
+        assert!(super::send(&mut output, responses, None, &mut buffer)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,495 @@
+//! Hosting several mail domains in one server.
+//!
+//! Accounts are named by their full address, and the part after the `@` picks the
+//! `MailDomain` the account belongs to. A domain may have its own data store, so its mail is
+//! kept apart from that of other domains, its own user store and authenticator, such as an
+//! LDAP directory run by the customer, and a quota for each of its users. Whatever a domain
+//! does not set, and accounts in no configured domain, fall back to the stores and
+//! authenticator the server was built with.
+//!
+//! `ServerBuilder::with_domains` puts `DomainStore`, `DomainUserStore` and
+//! `DomainAuthenticator` in front of those, so the handlers, the other frontends and
+//! `Server::user_store` all see the domains.
+//!
+//! Quotas are checked against the `Usage` the domains share, which `RecalculateUsage`
+//! corrects when it drifts from what the data stores hold. See `store::usage`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::auth::error::UserStoreError;
+use crate::auth::{Authenticate, AuthenticationPrincipal, User, UserStore};
+use crate::store::usage::Usage;
+use crate::store::{Batch, DataStore, Message, MessageBody, MessageMetadata, StoreError};
+use crate::util::Result;
+
+/// The domain of `username`, if it has one.
+pub fn domain_of(username: &str) -> Option<&str> {
+    username.rsplit_once('@').map(|(_, domain)| domain)
+}
+
+/// A mail domain and what is kept separately for it.
+#[derive(Clone)]
+pub struct MailDomain {
+    name: String,
+    data_store: Option<Arc<Box<dyn DataStore>>>,
+    user_store: Option<Arc<Box<dyn UserStore>>>,
+    authenticator: Option<Arc<Box<dyn Authenticate>>>,
+    quota: Option<u64>,
+}
+
+impl MailDomain {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_ascii_lowercase(),
+            data_store: None,
+            user_store: None,
+            authenticator: None,
+            quota: None,
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Keeps the mail of the domain in `data_store`, such as a `SqliteStore` with a root of
+    /// its own.
+    pub fn with_data_store<D: DataStore + 'static>(mut self, data_store: D) -> Self {
+        self.data_store = Some(Arc::new(Box::new(data_store)));
+        self
+    }
+    /// Keeps the accounts of the domain in `user_store`.
+    pub fn with_user_store<U: UserStore + 'static>(mut self, user_store: U) -> Self {
+        self.user_store = Some(Arc::new(Box::new(user_store)));
+        self
+    }
+    /// Logs the users of the domain in with `authenticator` rather than against its user
+    /// store.
+    pub fn with_authenticator<A: Authenticate + 'static>(mut self, authenticator: A) -> Self {
+        self.authenticator = Some(Arc::new(Box::new(authenticator)));
+        self
+    }
+    /// Refuses to store more than `bytes` of mail for each user of the domain.
+    pub fn with_quota(mut self, bytes: u64) -> Self {
+        self.quota = Some(bytes);
+        self
+    }
+    pub fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+}
+
+/// The domains a server hosts, by name. Names are compared case-insensitively.
+#[derive(Clone, Default)]
+pub struct Domains {
+    domains: HashMap<String, MailDomain>,
+    usage: Usage,
+}
+
+impl Domains {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_domain(mut self, domain: MailDomain) -> Self {
+        self.domains.insert(domain.name.clone(), domain);
+        self
+    }
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+    /// The names of every domain, in order.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.domains.keys().cloned().collect();
+        names.sort();
+        names
+    }
+    /// The usage of the users of every domain, as their quotas are checked against.
+    pub fn usage(&self) -> Usage {
+        self.usage.clone()
+    }
+    /// The domain `username` belongs to, if it is one of these.
+    pub fn get(&self, username: &str) -> Option<&MailDomain> {
+        let domain = domain_of(username)?.to_ascii_lowercase();
+        self.domains.get(&domain)
+    }
+}
+
+/// A data store which keeps the mail of each domain in the store of that domain, and checks
+/// the quota of the domain before storing a message.
+pub struct DomainStore {
+    default: Arc<Box<dyn DataStore>>,
+    domains: Domains,
+}
+
+impl DomainStore {
+    pub fn new(default: Arc<Box<dyn DataStore>>, domains: Domains) -> Self {
+        Self { default, domains }
+    }
+
+    fn store(&self, user: &str) -> &dyn DataStore {
+        let store = self
+            .domains
+            .get(user)
+            .and_then(|domain| domain.data_store.as_ref())
+            .unwrap_or(&self.default);
+        store.as_ref().as_ref()
+    }
+
+    /// The size of every message `user` has stored. Only the mailboxes whose usage is not
+    /// yet recorded are listed.
+    pub async fn usage(&self, user: &str) -> std::result::Result<u64, StoreError> {
+        let store = self.store(user);
+        let recorded = &self.domains.usage;
+        let mut usage = 0;
+        for mailbox in store.mailboxes(user).await? {
+            usage += match recorded.mailbox(user, &mailbox) {
+                Some(bytes) => bytes,
+                None => {
+                    let bytes = store
+                        .list(user, &mailbox)
+                        .await?
+                        .iter()
+                        .map(|message| message.size)
+                        .sum::<u64>();
+                    recorded.set(user, &mailbox, bytes);
+                    bytes
+                }
+            };
+        }
+        Ok(usage)
+    }
+}
+
+#[async_trait::async_trait]
+impl DataStore for DomainStore {
+    async fn append(
+        &self,
+        user: &str,
+        mailbox: &str,
+        message: Message,
+    ) -> std::result::Result<u32, StoreError> {
+        if let Some(quota) = self.domains.get(user).and_then(MailDomain::quota) {
+            if self.usage(user).await? + message.body.len() as u64 > quota {
+                return Err(StoreError::OverQuota(user.to_string()));
+            }
+        }
+        let size = message.body.len() as u64;
+        let uid = self.store(user).append(user, mailbox, message).await?;
+        self.domains.usage.add(user, mailbox, size);
+        Ok(uid)
+    }
+    async fn fetch(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+    ) -> std::result::Result<Vec<u8>, StoreError> {
+        self.store(user).fetch(user, mailbox, uid).await
+    }
+    async fn list(
+        &self,
+        user: &str,
+        mailbox: &str,
+    ) -> std::result::Result<Vec<MessageMetadata>, StoreError> {
+        self.store(user).list(user, mailbox).await
+    }
+    async fn expunge(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> std::result::Result<Vec<u32>, StoreError> {
+        let expunged = self.store(user).expunge(user, mailbox, uids).await?;
+        self.domains.usage.forget(user, mailbox);
+        Ok(expunged)
+    }
+    async fn mailboxes(&self, user: &str) -> std::result::Result<Vec<String>, StoreError> {
+        self.store(user).mailboxes(user).await
+    }
+    async fn apply(&self, user: &str, batch: Batch) -> std::result::Result<Vec<u32>, StoreError> {
+        if let Some(quota) = self.domains.get(user).and_then(MailDomain::quota) {
+            let adding: u64 = batch
+                .appends()
+                .iter()
+                .map(|(_, message)| message.body.len() as u64)
+                .sum();
+            if self.usage(user).await? + adding > quota {
+                return Err(StoreError::OverQuota(user.to_string()));
+            }
+        }
+        // The usage of every mailbox the batch touches is worked out again when next needed.
+        let mailboxes = batch.mailboxes();
+        let applied = self.store(user).apply(user, batch).await;
+        for mailbox in &mailboxes {
+            self.domains.usage.forget(user, mailbox);
+        }
+        applied
+    }
+    async fn fetch_header(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+    ) -> std::result::Result<Vec<u8>, StoreError> {
+        self.store(user).fetch_header(user, mailbox, uid).await
+    }
+    async fn fetch_range(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+        offset: u64,
+        length: u64,
+    ) -> std::result::Result<Vec<u8>, StoreError> {
+        self.store(user)
+            .fetch_range(user, mailbox, uid, offset, length)
+            .await
+    }
+    async fn open(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+    ) -> std::result::Result<(u64, MessageBody), StoreError> {
+        self.store(user).open(user, mailbox, uid).await
+    }
+}
+
+/// A user store which keeps the accounts of each domain in the user store of that domain.
+/// `list` names the accounts of every store.
+pub struct DomainUserStore {
+    default: Arc<Box<dyn UserStore>>,
+    domains: Domains,
+}
+
+impl DomainUserStore {
+    pub fn new(default: Arc<Box<dyn UserStore>>, domains: Domains) -> Self {
+        Self { default, domains }
+    }
+
+    fn store(&self, username: &str) -> &Arc<Box<dyn UserStore>> {
+        self.domains
+            .get(username)
+            .and_then(|domain| domain.user_store.as_ref())
+            .unwrap_or(&self.default)
+    }
+}
+
+#[async_trait::async_trait]
+impl UserStore for DomainUserStore {
+    async fn authenticate(&self, principal: Box<dyn AuthenticationPrincipal>) -> Result<User> {
+        self.store(&principal.principal())
+            .authenticate(principal)
+            .await
+    }
+    async fn get(&self, username: &str) -> Result<Option<User>> {
+        self.store(username).get(username).await
+    }
+    async fn canonical(&self, username: &str) -> Result<String> {
+        self.store(username).canonical(username).await
+    }
+    async fn add(&self, user: User) -> Result<()> {
+        self.store(&user.name()).add(user).await
+    }
+    async fn update(&self, user: User) -> Result<()> {
+        self.store(&user.name()).update(user).await
+    }
+    async fn remove(&self, username: &str) -> Result<()> {
+        self.store(username).remove(username).await
+    }
+    async fn set_password(&self, username: &str, password: &str) -> Result<()> {
+        self.store(username).set_password(username, password).await
+    }
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut names = self.default.list().await?;
+        for domain in self.domains.domains.values() {
+            if let Some(store) = &domain.user_store {
+                names.extend(store.list().await?);
+            }
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+    async fn add_alias(&self, alias: &str, username: &str) -> Result<()> {
+        let store = self.store(username);
+        if !Arc::ptr_eq(store, self.store(alias)) {
+            return Err(Box::new(UserStoreError::Backend(format!(
+                "{} and {} are kept in different user stores",
+                alias, username
+            ))));
+        }
+        store.add_alias(alias, username).await
+    }
+    async fn remove_alias(&self, alias: &str) -> Result<()> {
+        self.store(alias).remove_alias(alias).await
+    }
+}
+
+/// Logs the users of each domain with an authenticator of its own in with that, and everyone
+/// else in with the wrapped authenticator.
+pub struct DomainAuthenticator {
+    authenticator: Box<dyn Authenticate>,
+    domains: Domains,
+}
+
+impl DomainAuthenticator {
+    pub fn new(authenticator: Box<dyn Authenticate>, domains: Domains) -> Self {
+        Self {
+            authenticator,
+            domains,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Authenticate for DomainAuthenticator {
+    async fn authenticate(&self, principal: Box<dyn AuthenticationPrincipal>) -> Result<User> {
+        let domain = self.domains.get(&principal.principal());
+        match domain.and_then(|domain| domain.authenticator.clone()) {
+            Some(authenticator) => authenticator.authenticate(principal).await,
+            None => self.authenticator.authenticate(principal).await,
+        }
+    }
+    async fn canonical(&self, username: &str) -> String {
+        match self
+            .domains
+            .get(username)
+            .and_then(|domain| domain.authenticator.clone())
+        {
+            Some(authenticator) => authenticator.canonical(username).await,
+            None => self.authenticator.canonical(username).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{DomainAuthenticator, DomainStore, DomainUserStore, Domains, MailDomain};
+    use crate::auth::inmemory::{InMemoryAuthenticator, InMemoryUserStore};
+    use crate::auth::{Authenticate, AuthenticationPrincipal, BasicAuth, User, UserStore};
+    use crate::metrics::Metrics;
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::usage::RecalculateUsage;
+    use crate::store::{DataStore, Message, StoreError};
+    use crate::util::Result;
+
+    #[async_std::test]
+    async fn test_domain_stores() {
+        let example = Arc::new(InMemoryStore::new());
+        let domains = Domains::new().with_domain(
+            MailDomain::new("Example.com")
+                .with_data_store(example.clone())
+                .with_quota(10),
+        );
+        let default: Arc<Box<dyn DataStore>> = Arc::new(Box::new(InMemoryStore::new()));
+        let store = DomainStore::new(default.clone(), domains.clone());
+
+        store
+            .append("me@example.COM", "INBOX", Message::new(b"Mine"))
+            .await
+            .unwrap();
+        store
+            .append("me@example.org", "INBOX", Message::new(b"Theirs"))
+            .await
+            .unwrap();
+        assert_eq!(
+            example.mailboxes("me@example.COM").await.unwrap(),
+            ["INBOX"]
+        );
+        assert!(default
+            .mailboxes("me@example.COM")
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            default.mailboxes("me@example.org").await.unwrap(),
+            ["INBOX"]
+        );
+
+        // Four bytes are stored, so seven more would take the user past ten.
+        assert_eq!(store.usage("me@example.com").await.unwrap(), 4);
+        assert!(matches!(
+            store
+                .append("me@example.com", "INBOX", Message::new(b"1234567"))
+                .await,
+            Err(StoreError::OverQuota(..))
+        ));
+        store
+            .append("me@example.com", "INBOX", Message::new(b"123456"))
+            .await
+            .unwrap();
+
+        // Mail stored behind the back of the domain store is only counted once usage is
+        // recalculated.
+        example
+            .append("me@example.com", "INBOX", Message::new(b"!"))
+            .await
+            .unwrap();
+        assert_eq!(store.usage("me@example.com").await.unwrap(), 10);
+        RecalculateUsage::new(domains.usage())
+            .with_pause(Duration::ZERO)
+            .recalculate(&["me@example.com".to_string()], &store, &Metrics::new())
+            .await
+            .unwrap();
+        assert_eq!(store.usage("me@example.com").await.unwrap(), 11);
+    }
+
+    /// Lets anyone in, as a directory run by someone else might.
+    struct Anyone;
+
+    #[async_trait::async_trait]
+    impl Authenticate for Anyone {
+        async fn authenticate(&self, principal: Box<dyn AuthenticationPrincipal>) -> Result<User> {
+            Ok(User::new(&principal.principal(), "unused"))
+        }
+    }
+
+    #[async_std::test]
+    async fn test_domain_users() {
+        let domains =
+            Domains::new()
+                .with_domain(MailDomain::new("example.com").with_user_store(
+                    InMemoryUserStore::new().with_user("me@example.com", "password"),
+                ))
+                .with_domain(MailDomain::new("example.net").with_authenticator(Anyone));
+        let default: Arc<Box<dyn UserStore>> = Arc::new(Box::new(
+            InMemoryUserStore::new().with_user("me@example.org", "secret"),
+        ));
+        let users: Arc<Box<dyn UserStore>> = Arc::new(Box::new(DomainUserStore::new(
+            default.clone(),
+            domains.clone(),
+        )));
+
+        users
+            .add(User::new("you@example.com", "password"))
+            .await
+            .unwrap();
+        assert!(users.get("you@example.com").await.unwrap().is_some());
+        assert!(default.get("you@example.com").await.unwrap().is_none());
+        assert_eq!(
+            users.list().await.unwrap(),
+            ["me@example.com", "me@example.org", "you@example.com"]
+        );
+        assert!(users
+            .add_alias("alias@example.org", "me@example.com")
+            .await
+            .is_err());
+
+        let authenticator =
+            DomainAuthenticator::new(Box::new(InMemoryAuthenticator::new(users)), domains);
+        for (name, password) in [
+            ("me@example.com", "password"),
+            ("me@example.org", "secret"),
+            ("anyone@example.net", "anything"),
+        ] {
+            let login = BasicAuth::from(name, password);
+            let user = authenticator.authenticate(Box::new(login)).await.unwrap();
+            assert_eq!(user.name(), name);
+        }
+        let wrong = BasicAuth::from("me@example.com", "secret");
+        assert!(authenticator.authenticate(Box::new(wrong)).await.is_err());
+    }
+}
@@ -0,0 +1,231 @@
+//! Splits what a client sends into lines without copying them.
+//!
+//! A `Framed` reader owns a single buffer for the life of a connection. Lines are found in
+//! place and handed out as slices of it, and the space of a line is reused once the next one
+//! is read, so a session allocates nothing per line however many commands it sends. The
+//! buffer can be taken from a `BufferPool` so that new connections reuse the buffers of
+//! closed ones.
+
+use futures::{AsyncRead, AsyncReadExt};
+
+use crate::buffers::Buffer;
+use crate::connection::Line;
+
+/// How much is read from the client at once, and the size the buffer starts at.
+pub(crate) const CHUNK: usize = 8 * 1024;
+
+pub struct Framed<R> {
+    reader: R,
+    buffer: Buffer,
+    /// Where the current line starts.
+    start: usize,
+    /// Where the current line ends, which is where the data not yet handed out starts.
+    end: usize,
+    /// How much of `buffer` holds data read from the client.
+    filled: usize,
+}
+
+impl<R: AsyncRead + Unpin> Framed<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_buffer(reader, Buffer::new())
+    }
+
+    /// Reads into `buffer` rather than a buffer of its own. Anything already in it is dropped.
+    pub fn with_buffer(reader: R, mut buffer: Buffer) -> Self {
+        buffer.clear();
+        Self {
+            reader,
+            buffer,
+            start: 0,
+            end: 0,
+            filled: 0,
+        }
+    }
+
+    /// The line last read, with its line ending. Only its first `max` bytes are kept when it
+    /// was too long.
+    pub fn line(&self) -> &[u8] {
+        &self.buffer[self.start..self.end]
+    }
+
+    /// Returns the reader, dropping anything buffered which has not been handed out.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Returns the reader and the buffer, dropping anything buffered which has not been
+    /// handed out.
+    pub fn into_parts(self) -> (R, Buffer) {
+        (self.reader, self.buffer)
+    }
+
+    /// Reads the next line, keeping no more than `max` bytes of it. The previous line is
+    /// discarded. A line the client did not finish before closing the connection is still
+    /// returned.
+    pub async fn read_line(&mut self, max: usize) -> std::io::Result<Line> {
+        self.start = self.end;
+        let max = max.max(1);
+        let mut scanned = self.start;
+        let mut too_long = false;
+        loop {
+            if let Some(position) = self.buffer[scanned..self.filled]
+                .iter()
+                .position(|byte| *byte == b'\n')
+            {
+                let newline = scanned + position;
+                if !too_long && newline - self.start < max {
+                    self.end = newline + 1;
+                    return Ok(Line::Complete);
+                }
+                // Only the start of the line is kept, followed by whatever came after it.
+                let kept = self.start + max;
+                self.buffer.copy_within(newline + 1..self.filled, kept);
+                self.filled = kept + (self.filled - newline - 1);
+                self.end = kept;
+                return Ok(Line::TooLong);
+            }
+            if self.filled - self.start >= max {
+                // The rest of the line is dropped as it arrives.
+                too_long = true;
+                self.filled = self.start + max;
+            }
+            self.reserve();
+            scanned = self.filled;
+            let read = self.reader.read(&mut self.buffer[self.filled..]).await?;
+            if read == 0 {
+                if too_long || self.filled == self.start {
+                    self.end = self.start;
+                    return Ok(Line::End);
+                }
+                self.end = self.filled;
+                return Ok(Line::Complete);
+            }
+            self.filled += read;
+        }
+    }
+
+    /// Reads the `length` bytes which follow the line last read, such as the literal at the
+    /// end of an APPEND, and hands them out as the current line. The rest of the command is
+    /// read as the next line. Returns `Line::End` if the client closed the connection first.
+    pub async fn read_literal(&mut self, length: usize) -> std::io::Result<Line> {
+        self.start = self.end;
+        while self.filled - self.start < length {
+            self.reserve();
+            let read = self.reader.read(&mut self.buffer[self.filled..]).await?;
+            if read == 0 {
+                self.end = self.filled;
+                return Ok(Line::End);
+            }
+            self.filled += read;
+        }
+        self.end = self.start + length;
+        Ok(Line::Complete)
+    }
+
+    /// Reads and drops the `length` bytes which follow the line last read, such as a literal
+    /// too large to accept, holding no more than a chunk of them at a time. The current line
+    /// is left empty. Returns `Line::End` if the client closed the connection first.
+    pub async fn skip(&mut self, length: usize) -> std::io::Result<Line> {
+        let mut remaining = length;
+        loop {
+            let skipped = (self.filled - self.end).min(remaining);
+            self.end += skipped;
+            remaining -= skipped;
+            if remaining == 0 {
+                self.start = self.end;
+                return Ok(Line::Complete);
+            }
+            // Everything buffered has been skipped, so the buffer is reused from the start.
+            self.start = 0;
+            self.end = 0;
+            self.filled = 0;
+            self.reserve();
+            let read = self.reader.read(&mut self.buffer[self.filled..]).await?;
+            if read == 0 {
+                return Ok(Line::End);
+            }
+            self.filled += read;
+        }
+    }
+
+    /// Makes room for at least a chunk after the data buffered, moving the current line to
+    /// the front of the buffer before growing it.
+    fn reserve(&mut self) {
+        if self.buffer.len() - self.filled >= CHUNK {
+            return;
+        }
+        if self.start > 0 {
+            self.buffer.copy_within(self.start..self.filled, 0);
+            self.filled -= self.start;
+            self.end -= self.start.min(self.end);
+            self.start = 0;
+        }
+        if self.buffer.len() - self.filled < CHUNK {
+            self.buffer.resize(self.filled + CHUNK, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+
+    use super::Framed;
+    use crate::connection::Line;
+
+    #[async_std::test]
+    async fn test_lines() {
+        let input = b"a1 NOOP\r\na2 LOGIN user password\r\na3 LOGOUT".to_vec();
+        let mut framed = Framed::new(Cursor::new(input));
+        assert_eq!(framed.read_line(64).await.unwrap(), Line::Complete);
+        assert_eq!(framed.line(), b"a1 NOOP\r\n");
+        assert_eq!(framed.read_line(64).await.unwrap(), Line::Complete);
+        assert_eq!(framed.line(), b"a2 LOGIN user password\r\n");
+        assert_eq!(framed.read_line(64).await.unwrap(), Line::Complete);
+        assert_eq!(framed.line(), b"a3 LOGOUT");
+        assert_eq!(framed.read_line(64).await.unwrap(), Line::End);
+    }
+
+    #[async_std::test]
+    async fn test_too_long() {
+        let long = "x".repeat(3 * super::CHUNK);
+        let input = format!("a1 SEARCH {}\r\na2 NOOP\r\n", long).into_bytes();
+        let mut framed = Framed::new(Cursor::new(input));
+        assert_eq!(framed.read_line(16).await.unwrap(), Line::TooLong);
+        assert_eq!(framed.line(), b"a1 SEARCH xxxxxx");
+        assert_eq!(framed.read_line(16).await.unwrap(), Line::Complete);
+        assert_eq!(framed.line(), b"a2 NOOP\r\n");
+        assert_eq!(framed.read_line(16).await.unwrap(), Line::End);
+    }
+
+    #[async_std::test]
+    async fn test_literal() {
+        let body = "y".repeat(2 * super::CHUNK);
+        let input = format!("a1 APPEND INBOX {{{}}}\r\n{}\r\na2 NOOP\r\n", body.len(), body);
+        let mut framed = Framed::new(Cursor::new(input.into_bytes()));
+        assert_eq!(framed.read_line(64).await.unwrap(), Line::Complete);
+        assert_eq!(framed.read_literal(body.len()).await.unwrap(), Line::Complete);
+        assert_eq!(framed.line(), body.as_bytes());
+        assert_eq!(framed.read_line(64).await.unwrap(), Line::Complete);
+        assert_eq!(framed.line(), b"\r\n");
+        assert_eq!(framed.read_line(64).await.unwrap(), Line::Complete);
+        assert_eq!(framed.line(), b"a2 NOOP\r\n");
+        assert_eq!(framed.read_literal(1).await.unwrap(), Line::End);
+    }
+
+    #[async_std::test]
+    async fn test_skip() {
+        let body = "a9 NOOP\r\n".repeat(super::CHUNK);
+        let input = format!("a1 APPEND INBOX {{{}+}}\r\n{}\r\na2 NOOP\r\n", body.len(), body);
+        let mut framed = Framed::new(Cursor::new(input.into_bytes()));
+        assert_eq!(framed.read_line(64).await.unwrap(), Line::Complete);
+        assert_eq!(framed.skip(body.len()).await.unwrap(), Line::Complete);
+        assert_eq!(framed.line(), b"");
+        assert!(framed.buffer.len() <= 2 * super::CHUNK);
+        assert_eq!(framed.read_line(64).await.unwrap(), Line::Complete);
+        assert_eq!(framed.line(), b"\r\n");
+        assert_eq!(framed.read_line(64).await.unwrap(), Line::Complete);
+        assert_eq!(framed.line(), b"a2 NOOP\r\n");
+        assert_eq!(framed.skip(1).await.unwrap(), Line::End);
+    }
+}
@@ -0,0 +1,137 @@
+//! How the server introduces itself: the text of the greeting each connection is sent, the
+//! hostname it names, and the fields the ID command (RFC 2971) answers with.
+//!
+//! A connection to a listener with `Listener::with_preauth` is greeted with `* PREAUTH`
+//! rather than `* OK`, as it is logged in before it sends anything.
+
+use crate::server::{Response, ResponseStatus};
+
+/// The greeting text unless configured otherwise.
+pub const DEFAULT_TEXT: &str = "IMAP4rev2 server ready";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Greeting {
+    text: String,
+    hostname: Option<String>,
+    id: Vec<(String, String)>,
+}
+
+impl Default for Greeting {
+    fn default() -> Self {
+        Self {
+            text: DEFAULT_TEXT.to_string(),
+            hostname: None,
+            id: vec![
+                ("name".to_string(), "TreasurMAP".to_string()),
+                ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+            ],
+        }
+    }
+}
+
+impl Greeting {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Greets clients with `text` rather than `IMAP4rev2 server ready`.
+    pub fn with_text(mut self, text: &str) -> Self {
+        self.text = text.to_string();
+        self
+    }
+    /// Starts the greeting with `hostname`, as in `* OK mail.example.com IMAP4rev2 server
+    /// ready`.
+    pub fn with_hostname(mut self, hostname: &str) -> Self {
+        self.hostname = Some(hostname.to_string());
+        self
+    }
+    /// Answers ID with `value` for the field `name`, such as `vendor` or `support-url`,
+    /// replacing the value it had. An empty `value` leaves the field out, which is how the
+    /// default `name` and `version` can be hidden.
+    pub fn with_id_field(mut self, name: &str, value: &str) -> Self {
+        self.id
+            .retain(|(field, _)| !field.eq_ignore_ascii_case(name));
+        if !value.is_empty() {
+            self.id.push((name.to_string(), value.to_string()));
+        }
+        self
+    }
+
+    /// The text of the greeting, after any response code.
+    pub fn text(&self) -> String {
+        match &self.hostname {
+            Some(hostname) => format!("{} {}", hostname, self.text),
+            None => self.text.clone(),
+        }
+    }
+
+    /// The fields ID answers with, in the order they were set.
+    pub fn id(&self) -> &[(String, String)] {
+        &self.id
+    }
+
+    /// The greeting sent to a new connection, `* PREAUTH` if it is already logged in, with
+    /// the response `code` if there is one.
+    pub fn response(&self, preauthenticated: bool, code: Option<&str>) -> Response {
+        let text = match code {
+            Some(code) => format!("[{}] {}", code, self.text()),
+            None => self.text(),
+        };
+        match preauthenticated {
+            true => Response::untagged(&format!("PREAUTH {}", text)),
+            false => Response::new("*", ResponseStatus::OK, &text),
+        }
+    }
+
+    /// The untagged ID response, such as `* ID ("name" "TreasurMAP" "version" "0.1.0")`.
+    pub fn id_response(&self) -> Response {
+        if self.id.is_empty() {
+            return Response::untagged("ID NIL");
+        }
+        let fields: Vec<String> = self
+            .id
+            .iter()
+            .map(|(name, value)| format!("{} {}", quote(name), quote(value)))
+            .collect();
+        Response::untagged(&format!("ID ({})", fields.join(" ")))
+    }
+}
+
+/// `value` as an IMAP quoted string.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Greeting;
+    use crate::server::Response;
+
+    #[test]
+    fn test_greeting() {
+        let greeting = Greeting::new()
+            .with_text("Welcome")
+            .with_hostname("mail.example.com")
+            .with_id_field("version", "")
+            .with_id_field("support-url", "https://example.com/\"help\"");
+        assert_eq!(
+            greeting.response(false, None).to_string(),
+            "* OK mail.example.com Welcome"
+        );
+        assert_eq!(
+            greeting
+                .response(true, Some("CAPABILITY IMAP4rev2"))
+                .to_string(),
+            "* PREAUTH [CAPABILITY IMAP4rev2] mail.example.com Welcome"
+        );
+        assert_eq!(
+            greeting.id_response(),
+            Response::untagged(
+                "ID (\"name\" \"TreasurMAP\" \"support-url\" \"https://example.com/\\\"help\\\"\")"
+            )
+        );
+        let anonymous = Greeting::new()
+            .with_id_field("name", "")
+            .with_id_field("version", "");
+        assert_eq!(anonymous.id_response(), Response::untagged("ID NIL"));
+    }
+}
@@ -0,0 +1,489 @@
+// From RFC 9051 (https://www.ietf.org/rfc/rfc9051.html#name-append-command):
+//  C: A003 APPEND saved-messages (\Seen) {326}
+//  S: + Ready for literal data
+//  C: Date: Mon, 7 Feb 1994 21:52:25 -0800 (PST)
+//  C: ...
+//  S: A003 OK APPEND completed
+//
+// The flags and the date-time before the literal are optional. When given, the message is
+// stored with them, so FETCH FLAGS and INTERNALDATE answer with what the client sent, as a
+// client migrating mail or saving to Sent relies on. The OK carries the UIDVALIDITY of the
+// mailbox and the UID of the message as APPENDUID (RFC 4315).
+//
+// The client only sends a synchronizing literal (`{N}`) once it is asked to, so a command
+// which cannot succeed is refused without reading it. A non-synchronizing literal (`{N+}`)
+// follows the command line at once and is read before anything is refused, so it is not
+// mistaken for commands.
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use futures::{SinkExt, StreamExt};
+use tracing::Instrument;
+
+use crate::connection::{Context, Continuation, Request};
+use crate::handlers::{destination_error, mailbox_error, HandleCommand, Workers};
+use crate::index::message::{Envelope, MessageRecord};
+use crate::index::{Flag, Index, MailboxError, Owner, Permission};
+use crate::runtime::spawn;
+use crate::server::{Command, ParseError, Response, ResponseStatus};
+use crate::state::State;
+use crate::store::home::Home;
+use crate::store::name::resolve_mailbox;
+use crate::store::{DataStore, Message};
+use crate::util::{Receiver, Result, UtcTime};
+
+use super::Handle;
+
+/// The largest message accepted unless configured otherwise.
+pub const DEFAULT_MAX_SIZE: usize = 50 * 1024 * 1024;
+
+/// The arguments of an APPEND, such as `Sent (\Seen) "01-Feb-2024 10:00:00 +0000" {310}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Arguments {
+    mailbox: String,
+    flags: Vec<String>,
+    internal_date: Option<SystemTime>,
+    /// The length of the literal holding the message.
+    length: usize,
+    synchronizing: bool,
+}
+
+impl Arguments {
+    fn parse(command: &Command) -> std::result::Result<Self, ParseError> {
+        let count = command.num_args();
+        if count < 2 {
+            return Err(ParseError {});
+        }
+        let literal = command.arg(count - 1);
+        let literal = literal
+            .strip_prefix('{')
+            .and_then(|literal| literal.strip_suffix('}'))
+            .ok_or(ParseError {})?;
+        let (length, synchronizing) = match literal.strip_suffix('+') {
+            Some(length) => (length, false),
+            None => (literal, true),
+        };
+        let length = number(length)? as usize;
+        // The flags and the date-time were split on their spaces with the rest of the line.
+        let optional: Vec<String> = (1..count - 1).map(|i| command.arg(i)).collect();
+        let optional = optional.join(" ");
+        let (flags, rest) = match optional.strip_prefix('(') {
+            Some(list) => {
+                let (list, rest) = list.split_once(')').ok_or(ParseError {})?;
+                let flags = list
+                    .split_whitespace()
+                    .map(|flag| Flag::normalize(flag).ok_or(ParseError {}))
+                    .collect::<std::result::Result<Vec<String>, ParseError>>()?;
+                (flags, rest.trim_start())
+            }
+            None => (vec![], optional.as_str()),
+        };
+        let internal_date = match rest {
+            "" => None,
+            quoted => {
+                let value = quoted
+                    .strip_prefix('"')
+                    .and_then(|quoted| quoted.strip_suffix('"'))
+                    .ok_or(ParseError {})?;
+                Some(date_time(value)?)
+            }
+        };
+        Ok(Self {
+            mailbox: command.arg(0),
+            flags,
+            internal_date,
+            length,
+            synchronizing,
+        })
+    }
+}
+
+/// Parses a number of ASCII digits only, unlike `str::parse` which allows a sign.
+fn number(value: &str) -> std::result::Result<u64, ParseError> {
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(ParseError {});
+    }
+    value.parse().map_err(|_| ParseError {})
+}
+
+/// Parses an IMAP `date-time` without its quotes, such as ` 1-Feb-2024 10:00:00 +0100`.
+fn date_time(value: &str) -> std::result::Result<SystemTime, ParseError> {
+    const MONTHS: [&str; 12] = [
+        "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+    ];
+    let mut fields = value.split_whitespace();
+    let (date, time, zone) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+        (Some(date), Some(time), Some(zone), None) => (date, time, zone),
+        _ => return Err(ParseError {}),
+    };
+    let date: Vec<&str> = date.split('-').collect();
+    let time: Vec<&str> = time.split(':').collect();
+    let (day, month, year, hour, minute, second) = match (&date[..], &time[..]) {
+        ([day, month, year], [hour, minute, second])
+            if day.len() <= 2
+                && year.len() == 4
+                && [hour, minute, second].iter().all(|part| part.len() == 2) =>
+        {
+            (day, month, year, hour, minute, second)
+        }
+        _ => return Err(ParseError {}),
+    };
+    let month = MONTHS
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(month))
+        .ok_or(ParseError {})? as u32;
+    let local = UtcTime {
+        year: number(year)? as i64,
+        month: month + 1,
+        day: number(day)? as u32,
+        hour: number(hour)? as u32,
+        minute: number(minute)? as u32,
+        second: number(second)? as u32,
+    };
+    if !(1..=31).contains(&local.day) || local.hour > 23 || local.minute > 59 || local.second > 60 {
+        return Err(ParseError {});
+    }
+    let (ahead, offset) = match (zone.strip_prefix('+'), zone.strip_prefix('-')) {
+        (Some(offset), _) => (true, offset),
+        (_, Some(offset)) => (false, offset),
+        _ => return Err(ParseError {}),
+    };
+    if offset.len() != 4 {
+        return Err(ParseError {});
+    }
+    let offset = number(offset)?;
+    let (hours, minutes) = (offset / 100, offset % 100);
+    if minutes > 59 {
+        return Err(ParseError {});
+    }
+    // The zone is how far the local time is ahead of UTC.
+    let offset = Duration::from_secs(hours * 3600 + minutes * 60);
+    let local = SystemTime::from(local);
+    match ahead {
+        true => local.checked_sub(offset).ok_or(ParseError {}),
+        false => local.checked_add(offset).ok_or(ParseError {}),
+    }
+}
+
+#[derive(Clone)]
+pub struct AppendHandler {
+    index: Arc<Box<dyn Index>>,
+    store: Arc<Box<dyn DataStore>>,
+    max_size: usize,
+    workers: Workers,
+}
+
+impl AppendHandler {
+    #[must_use]
+    pub fn new(index: Arc<Box<dyn Index>>, store: Arc<Box<dyn DataStore>>) -> Self {
+        Self {
+            index,
+            store,
+            max_size: DEFAULT_MAX_SIZE,
+            workers: Workers::default(),
+        }
+    }
+    /// Refuses messages larger than `max_size` bytes with `[TOOBIG]`, rather than 50 MiB.
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+    /// Runs requests on `workers`, shared with the other handlers of the server, taking one
+    /// only while using the index and the store.
+    pub fn with_workers(mut self, workers: Workers) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    async fn append(
+        &self,
+        command: &Command,
+        context: &Context,
+        continuation: &Continuation,
+    ) -> Vec<Response> {
+        let tag = command.tag();
+        let owner = match context.owner() {
+            Some(owner) => owner,
+            None => return vec![State::of(context).rejection(command)],
+        };
+        let arguments = match Arguments::parse(command) {
+            Ok(arguments) => arguments,
+            Err(..) => {
+                return vec![Response::new(
+                    &tag,
+                    ResponseStatus::BAD,
+                    "invalid APPEND arguments",
+                )]
+            }
+        };
+        if arguments.length > self.max_size {
+            let message = format!("[TOOBIG] Messages are limited to {} bytes.", self.max_size);
+            if arguments.synchronizing {
+                return vec![Response::new(&tag, ResponseStatus::NO, &message)];
+            }
+            // The client sends a non-synchronizing literal without waiting to be asked, so
+            // it is read past before the refusal.
+            let _ = continuation.discard(arguments.length).await;
+            return vec![Response::new(&tag, ResponseStatus::BAD, &message)];
+        }
+        let mut literal = None;
+        if !arguments.synchronizing {
+            literal = Some(continuation.literal(arguments.length, false).await);
+        }
+        // Only the work on the backend takes a worker, so that clients slow to send their
+        // literal do not hold workers the other handlers of the server need.
+        let destination = self.destination(&tag, &owner, &arguments, context);
+        let mailbox = match self.workers.run(destination).await {
+            Ok(mailbox) => mailbox,
+            Err(refusal) => return vec![refusal],
+        };
+        let literal = match literal {
+            Some(literal) => literal,
+            None => continuation.literal(arguments.length, true).await,
+        };
+        let body = match literal {
+            Ok((body, rest)) if rest.is_empty() => body,
+            Ok(..) => {
+                let message = "APPEND takes a single message";
+                return vec![Response::new(&tag, ResponseStatus::BAD, message)];
+            }
+            Err(..) => {
+                let message = "APPEND needs the message as a literal";
+                return vec![Response::new(&tag, ResponseStatus::BAD, message)];
+            }
+        };
+        let stored = self.store_message(&owner, &mailbox, &arguments, &body);
+        match self.workers.run(stored).await {
+            Ok((validity, uid)) => {
+                let message = format!("[APPENDUID {} {}] APPEND completed.", validity, uid);
+                vec![Response::new(&tag, ResponseStatus::OK, &message)]
+            }
+            Err(e) => vec![mailbox_error(&tag, &e)],
+        }
+    }
+
+    /// Checks that the message may be appended to the mailbox named in `arguments`, and
+    /// returns its name as stored, or the response refusing it.
+    async fn destination(
+        &self,
+        tag: &str,
+        owner: &Owner,
+        arguments: &Arguments,
+        context: &Context,
+    ) -> std::result::Result<String, Response> {
+        if context.is_read_only() {
+            let message = "[NOPERM] Guests may not append messages.";
+            return Err(Response::new(tag, ResponseStatus::NO, message));
+        }
+        let mailbox = match resolve_mailbox(&arguments.mailbox) {
+            Ok(mailbox) => mailbox,
+            Err(e) => return Err(mailbox_error(tag, &MailboxError::from(e))),
+        };
+        match self
+            .index
+            .get_mailbox(owner, &mailbox, Permission::ReadWrite)
+            .await
+        {
+            Ok(..) => Ok(mailbox),
+            Err(e) => Err(destination_error(tag, &e)),
+        }
+    }
+
+    /// Stores `body` in `mailbox` with the flags and internal date of `arguments`, then
+    /// indexes it. Returns the UIDVALIDITY of the mailbox and the UID of the message.
+    async fn store_message(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        arguments: &Arguments,
+        body: &[u8],
+    ) -> std::result::Result<(u32, u32), MailboxError> {
+        let internal_date = arguments.internal_date.unwrap_or_else(SystemTime::now);
+        let flags: Vec<&str> = arguments.flags.iter().map(String::as_str).collect();
+        let message = Message::new(body)
+            .with_flags(flags)
+            .with_internal_date(internal_date);
+        let home = Home::new(self.store.clone(), owner.clone());
+        let uid = home.append(mailbox, message).await?;
+        let record = MessageRecord::new(uid, body.len() as u64, internal_date)
+            .with_flags(arguments.flags.clone())
+            .with_envelope(Envelope::parse(body));
+        self.index.add_message(owner, mailbox, record).await?;
+        let described = self
+            .index
+            .get_mailbox(owner, mailbox, Permission::ReadOnly)
+            .await?;
+        Ok((described.uid_validity, uid))
+    }
+}
+
+#[async_trait::async_trait]
+impl HandleCommand for AppendHandler {
+    fn name<'a>(&self) -> &'a str {
+        "APPEND"
+    }
+    async fn validate<'a>(&self, command: &'a Command) -> Result<()> {
+        if command.command() != self.name() {
+            return Ok(());
+        }
+        if command.num_args() < 2 {
+            return Err(Box::new(ParseError {}));
+        }
+        Ok(())
+    }
+    async fn handle<'a>(
+        &self,
+        command: &'a Command,
+        context: &'a Context,
+    ) -> Result<Vec<Response>> {
+        let continuation = Continuation::default();
+        Ok(self.append(command, context, &continuation).await)
+    }
+}
+
+#[async_trait::async_trait]
+impl Handle for AppendHandler {
+    fn command<'a>(&self) -> &'a str {
+        "APPEND"
+    }
+
+    async fn start(&mut self, mut requests: Receiver<Request>) -> Result<()> {
+        while let Some(mut request) = requests.next().await {
+            if self.validate(&request.command).await.is_err() {
+                request
+                    .responder
+                    .send(vec![Response::new(
+                        &request.command.tag(),
+                        ResponseStatus::BAD,
+                        "insufficient arguments",
+                    )])
+                    .await?;
+                continue;
+            }
+            let handler = self.clone();
+            let (span, timings) = (request.span.clone(), request.timings.clone());
+            spawn(
+                timings.scope(
+                    async move {
+                        let responses = handler
+                            .append(&request.command, &request.context, &request.continuation)
+                            .await;
+                        let _ = request.responder.send(responses).await;
+                    }
+                    .instrument(span),
+                ),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::{date_time, AppendHandler, Arguments};
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::{Index, Owner};
+    use crate::server::Command;
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::DataStore;
+
+    /// The command line of `line` as the connection parses it.
+    fn command(line: &str) -> Command {
+        Command::parse(line).unwrap()
+    }
+
+    #[test]
+    fn test_arguments() {
+        let arguments = Arguments::parse(&command(
+            "a1 APPEND Sent (\\Seen $Work) \"01-Feb-2024 10:00:00 +0000\" {310}",
+        ))
+        .unwrap();
+        assert_eq!(arguments.mailbox, "Sent");
+        assert_eq!(arguments.flags, vec!["\\Seen", "$Work"]);
+        assert_eq!(
+            arguments.internal_date,
+            Some(UNIX_EPOCH + Duration::from_secs(1_706_781_600))
+        );
+        assert_eq!((arguments.length, arguments.synchronizing), (310, true));
+
+        let arguments = Arguments::parse(&command("a1 APPEND INBOX {12+}")).unwrap();
+        assert_eq!(arguments.flags, Vec::<String>::new());
+        assert_eq!(arguments.internal_date, None);
+        assert_eq!((arguments.length, arguments.synchronizing), (12, false));
+
+        let arguments = Arguments::parse(&command(
+            "a1 APPEND INBOX () \" 1-Feb-2024 10:00:00 +0000\" {1}",
+        ))
+        .unwrap();
+        assert!(arguments.flags.is_empty());
+        assert!(arguments.internal_date.is_some());
+
+        for line in [
+            "a1 APPEND INBOX",
+            "a1 APPEND INBOX (\\Seen)",
+            "a1 APPEND INBOX {-1}",
+            "a1 APPEND INBOX (\\Recent) {1}",
+            "a1 APPEND INBOX (\\Seen {1}",
+            "a1 APPEND INBOX 01-Feb-2024 {1}",
+            "a1 APPEND INBOX \"yesterday\" {1}",
+        ] {
+            assert!(
+                Arguments::parse(&command(line)).is_err(),
+                "{:?} was accepted",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_date_time() {
+        let noon = UNIX_EPOCH + Duration::from_secs(1_706_788_800);
+        assert_eq!(date_time("01-Feb-2024 12:00:00 +0000").unwrap(), noon);
+        assert_eq!(date_time(" 1-feb-2024 13:30:00 +0130").unwrap(), noon);
+        assert_eq!(date_time("01-Feb-2024 04:00:00 -0800").unwrap(), noon);
+        for value in [
+            "01-Feb-2024 12:00:00",
+            "01-Foo-2024 12:00:00 +0000",
+            "32-Feb-2024 12:00:00 +0000",
+            "01-Feb-2024 24:00:00 +0000",
+            "01-Feb-2024 12:00 +0000",
+            "01-Feb-2024 12:00:00 0000",
+            "01-Feb-2024 12:00:00 +00:00",
+            "01-Feb-24 12:00:00 +0000",
+        ] {
+            assert!(date_time(value).is_err(), "{:?} was accepted", value);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_store_message_keeps_flags_and_date() {
+        let index: Arc<Box<dyn Index>> = Arc::new(Box::new(InMemoryIndex::new()));
+        let store: Arc<Box<dyn DataStore>> = Arc::new(Box::new(InMemoryStore::new()));
+        let handler = AppendHandler::new(index.clone(), store.clone());
+        let owner = Owner::new("username");
+        let arguments = Arguments::parse(&command(
+            "a1 APPEND inbox (\\Seen) \"01-Feb-2024 10:00:00 +0000\" {20}",
+        ))
+        .unwrap();
+        let body = b"Subject: Sent\r\n\r\nHi.";
+        let (_, uid) = handler
+            .store_message(&owner, "INBOX", &arguments, body)
+            .await
+            .unwrap();
+
+        let records = index.list_messages(&owner, "INBOX").await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].uid, uid);
+        assert_eq!(records[0].flags, vec!["\\Seen"]);
+        assert_eq!(records[0].internal_date, arguments.internal_date.unwrap());
+        assert_eq!(records[0].envelope.subject.as_deref(), Some("Sent"));
+        let stored = store.list("username", "INBOX").await.unwrap();
+        assert_eq!(stored[0].flags, vec!["\\Seen"]);
+        assert_eq!(stored[0].internal_date, arguments.internal_date.unwrap());
+        assert!(stored[0].internal_date < SystemTime::now());
+    }
+}
@@ -0,0 +1,278 @@
+// From RFC 9051 (https://www.ietf.org/rfc/rfc9051.html#name-authenticate-command) with the
+// initial response of RFC 4959 and the ANONYMOUS mechanism of RFC 4505:
+//  C: A001 AUTHENTICATE ANONYMOUS c2lyaEBleGFtcGxlLmNvbQ==
+//  S: A001 OK AUTHENTICATE completed.
+// or, without the initial response:
+//  C: A001 AUTHENTICATE ANONYMOUS
+//  S: +
+//  C: c2lyaEBleGFtcGxlLmNvbQ==
+//  S: A001 OK AUTHENTICATE completed.
+
+use futures::{SinkExt, StreamExt};
+use log::{info, warn};
+
+use crate::access::AccessControl;
+use crate::auth::User;
+use crate::connection::{Context, Continuation, Event, Request};
+use crate::handlers::capability::{code, Advertised};
+use crate::handlers::HandleCommand;
+use crate::mime::encoding::decode_base64;
+use crate::server::{Command, ParseError, Response, ResponseStatus};
+use crate::util::{Receiver, Result};
+
+use super::Handle;
+
+/// The longest trace information RFC 4505 allows, in characters.
+const MAX_TRACE: usize = 255;
+
+/// Handles AUTHENTICATE. Only the ANONYMOUS mechanism is offered, and only when a public
+/// namespace has been configured with `with_anonymous`. Guests get read-only access to that
+/// namespace's mailboxes.
+#[derive(Default)]
+pub struct AuthenticateHandler {
+    anonymous: Option<String>,
+    require_tls: bool,
+    access: AccessControl,
+    capabilities: Option<Advertised>,
+}
+
+impl AuthenticateHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Enables the ANONYMOUS mechanism, mapping guests to the mailboxes of `namespace`.
+    pub fn with_anonymous(mut self, namespace: &str) -> Self {
+        self.anonymous = Some(namespace.to_string());
+        self
+    }
+    /// Refuses mechanisms which send a password until the connection is encrypted.
+    pub fn with_require_tls(mut self, require_tls: bool) -> Self {
+        self.require_tls = require_tls;
+        self
+    }
+    /// Refuses guests from addresses `access` does not let the public namespace log in from.
+    pub fn with_access_control(mut self, access: AccessControl) -> Self {
+        self.access = access;
+        self
+    }
+    /// Lists the capabilities of the server in the OK of a successful exchange.
+    pub fn with_capabilities(mut self, capabilities: Advertised) -> Self {
+        self.capabilities = Some(capabilities);
+        self
+    }
+
+    async fn authenticate(
+        &self,
+        command: &Command,
+        context: &Context,
+        continuation: &Continuation,
+    ) -> (Response, Option<User>) {
+        let tag = command.tag();
+        let mechanism = command.arg(0).to_uppercase();
+        if self.require_tls && !context.is_secure() && mechanism != "ANONYMOUS" {
+            let message = format!(
+                "[PRIVACYREQUIRED] AUTHENTICATE {} is disabled until TLS is negotiated.",
+                mechanism
+            );
+            return (Response::new(&tag, ResponseStatus::NO, &message), None);
+        }
+        let namespace = match (mechanism.as_str(), &self.anonymous) {
+            ("ANONYMOUS", Some(namespace)) => namespace,
+            _ => {
+                let message = format!("unsupported authentication mechanism {}", mechanism);
+                return (Response::new(&tag, ResponseStatus::NO, &message), None);
+            }
+        };
+        if !self.access.authorizes(namespace, context.peer()) {
+            warn!(
+                "Refusing an anonymous login from {}: not allowed by the access policy",
+                context.describe_peer()
+            );
+            let message = "[AUTHORIZATIONFAILED] AUTHENTICATE ANONYMOUS is not allowed from here.";
+            return (Response::new(&tag, ResponseStatus::NO, message), None);
+        }
+        // Without an initial response the client is asked for one, which may be empty.
+        let response = match command.num_args() {
+            1 => match continuation.request("").await {
+                Ok(response) => response,
+                Err(..) => {
+                    let message = "AUTHENTICATE ANONYMOUS needs a response";
+                    return (Response::new(&tag, ResponseStatus::BAD, message), None);
+                }
+            },
+            _ => command.arg(1),
+        };
+        // `=` is an empty initial response, and `*` cancels the exchange.
+        let trace = match response.as_str() {
+            "*" => {
+                let message = "AUTHENTICATE cancelled.";
+                return (Response::new(&tag, ResponseStatus::BAD, message), None);
+            }
+            "=" | "" => String::new(),
+            encoded => String::from_utf8_lossy(&decode_base64(encoded.as_bytes())).to_string(),
+        };
+        if trace.chars().count() > MAX_TRACE {
+            let message = "[AUTHENTICATIONFAILED] trace information is too long";
+            return (Response::new(&tag, ResponseStatus::NO, message), None);
+        }
+        info!(
+            target: "audit",
+            "anonymous login from {} with trace {:?}",
+            context.peer().map_or("an unknown address".to_string(), |peer| peer.to_string()),
+            trace
+        );
+        let mut response = Response::new(&tag, ResponseStatus::OK, "AUTHENTICATE completed.");
+        if let Some(capabilities) = &self.capabilities {
+            response = response.with_code(&code(&capabilities.authenticated().await));
+        }
+        (response, Some(User::anonymous(namespace)))
+    }
+}
+
+#[async_trait::async_trait]
+impl HandleCommand for AuthenticateHandler {
+    fn name<'a>(&self) -> &'a str {
+        "AUTHENTICATE"
+    }
+    async fn validate<'a>(&self, command: &'a Command) -> Result<()> {
+        if command.command() != self.name() {
+            return Ok(());
+        }
+        if command.num_args() < 1 {
+            return Err(Box::new(ParseError {}));
+        }
+        Ok(())
+    }
+    async fn handle<'a>(
+        &self,
+        command: &'a Command,
+        context: &'a Context,
+    ) -> Result<Vec<Response>> {
+        let continuation = Continuation::default();
+        Ok(vec![self.authenticate(command, context, &continuation).await.0])
+    }
+}
+
+#[async_trait::async_trait]
+impl Handle for AuthenticateHandler {
+    fn command<'b>(&self) -> &'b str {
+        "AUTHENTICATE"
+    }
+    async fn start<'b>(&'b mut self, mut requests: Receiver<Request>) -> Result<()> {
+        while let Some(mut request) = requests.next().await {
+            if self.validate(&request.command).await.is_err() {
+                request
+                    .responder
+                    .send(vec![Response::new(
+                        &request.command.tag(),
+                        ResponseStatus::BAD,
+                        "insufficient arguments",
+                    )])
+                    .await?;
+                continue;
+            }
+            let (response, user) = self
+                .authenticate(&request.command, &request.context, &request.continuation)
+                .await;
+            if let Some(user) = user {
+                request.events.send(Event::AUTH(user)).await?;
+            }
+            request.responder.send(vec![response]).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::AuthenticateHandler;
+    use crate::access::{AccessControl, AccessPolicy, Rules};
+    use crate::connection::{Context, Event};
+    use crate::handlers::tests::test_handle;
+    use crate::handlers::HandleCommand;
+    use crate::server::{Command, Response, ResponseStatus};
+
+    #[async_std::test]
+    async fn test_anonymous() {
+        let handler = AuthenticateHandler::new().with_anonymous("public");
+        // "sirhc@example.com"
+        let command = Command::new(
+            "a1",
+            "AUTHENTICATE",
+            vec!["anonymous", "c2lyaEBleGFtcGxlLmNvbQ=="],
+        );
+        test_handle(
+            handler,
+            command,
+            |response| {
+                assert_eq!(
+                    response,
+                    vec![Response::new(
+                        "a1",
+                        ResponseStatus::OK,
+                        "AUTHENTICATE completed."
+                    )]
+                );
+            },
+            Some(|event| match event {
+                Event::AUTH(user) => {
+                    assert_eq!(user.name(), "public");
+                    assert!(user.is_anonymous());
+                }
+                _ => panic!("AuthenticateHandler should only send AUTH events"),
+            }),
+            None,
+        )
+        .await;
+    }
+
+    #[async_std::test]
+    async fn test_rejected() {
+        for (handler, args, status) in [
+            (
+                AuthenticateHandler::new(),
+                vec!["ANONYMOUS", "="],
+                ResponseStatus::NO,
+            ),
+            (
+                AuthenticateHandler::new().with_anonymous("public"),
+                vec!["PLAIN", "="],
+                ResponseStatus::NO,
+            ),
+            (
+                AuthenticateHandler::new().with_anonymous("public"),
+                vec!["ANONYMOUS"],
+                ResponseStatus::BAD,
+            ),
+        ] {
+            let command = Command::new("a1", "AUTHENTICATE", args);
+            test_handle(
+                handler,
+                command,
+                |response| assert_eq!(response[0].status(), Some(status)),
+                None::<fn(Event)>,
+                None,
+            )
+            .await;
+        }
+    }
+
+    #[async_std::test]
+    async fn test_anonymous_restricted_to_networks() {
+        let lan_only = Rules::new().allow("10.0.0.0/8").unwrap();
+        let access = AccessControl::new(AccessPolicy::new().with_user_rules("public", lan_only));
+        let handler = AuthenticateHandler::new()
+            .with_anonymous("public")
+            .with_access_control(access);
+        let command = Command::new("a1", "AUTHENTICATE", vec!["ANONYMOUS", "="]);
+
+        let outside = Context::default().with_peer(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        let response = handler.handle(&command, &outside).await.unwrap();
+        assert_eq!(response[0].status(), Some(ResponseStatus::NO));
+        let lan = Context::default().with_peer(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)));
+        let response = handler.handle(&command, &lan).await.unwrap();
+        assert_eq!(response[0].status(), Some(ResponseStatus::OK));
+    }
+}
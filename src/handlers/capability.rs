@@ -0,0 +1,198 @@
+use futures::{SinkExt, StreamExt};
+
+use crate::connection::{Context, Request};
+use crate::handlers::HandleCommand;
+use crate::plugin::Capabilities;
+use crate::server::{Command, Response, ResponseStatus};
+use crate::util::{Receiver, Result};
+
+use super::Handle;
+
+/// The capabilities of the server and of its plugins as a client is told them, which
+/// depends on whether its connection is encrypted and whether it has logged in. STARTTLS is
+/// listed until the connection is encrypted wherever the listener offers it.
+#[derive(Clone)]
+pub struct Advertised {
+    capabilities: Capabilities,
+    require_tls: bool,
+}
+
+impl Advertised {
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self {
+            capabilities,
+            require_tls: false,
+        }
+    }
+    /// Advertises LOGINDISABLED, and no mechanism which sends a password, until the
+    /// connection is encrypted.
+    pub fn with_require_tls(mut self, require_tls: bool) -> Self {
+        self.require_tls = require_tls;
+        self
+    }
+
+    /// The capabilities to list to a client in `context`.
+    pub async fn list(&self, context: &Context) -> Vec<String> {
+        let mut capabilities = self.capabilities.list().await;
+        if context.offers_starttls() && !context.is_secure() {
+            capabilities.push("STARTTLS".to_string());
+        }
+        if self.require_tls && !context.is_secure() {
+            capabilities.retain(|capability| {
+                !capability.starts_with("AUTH=")
+                    || capability.eq_ignore_ascii_case("AUTH=ANONYMOUS")
+            });
+            capabilities.push("LOGINDISABLED".to_string());
+        }
+        capabilities
+    }
+
+    /// The capabilities to list to a client once it has logged in, without the mechanisms
+    /// to log in with.
+    pub async fn authenticated(&self) -> Vec<String> {
+        let mut capabilities = self.capabilities.list().await;
+        capabilities.retain(|capability| !capability.starts_with("AUTH="));
+        capabilities
+    }
+}
+
+/// The `CAPABILITY` response code listing `capabilities`, which RFC 9051 lets the greeting
+/// and the OK of a login carry to save the client asking.
+pub(crate) fn code(capabilities: &[String]) -> String {
+    format!("CAPABILITY {}", capabilities.join(" "))
+}
+
+/// Handles CAPABILITY, listing those of the server and of its plugins at the time.
+pub struct CapabilityHandler {
+    advertised: Advertised,
+}
+
+impl CapabilityHandler {
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self {
+            advertised: Advertised::new(capabilities),
+        }
+    }
+    /// Advertises LOGINDISABLED, and no mechanism which sends a password, until the
+    /// connection is encrypted.
+    pub fn with_require_tls(mut self, require_tls: bool) -> Self {
+        self.advertised = self.advertised.with_require_tls(require_tls);
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl HandleCommand for CapabilityHandler {
+    fn name<'a>(&self) -> &'a str {
+        "CAPABILITY"
+    }
+    async fn validate<'a>(&self, command: &'a Command) -> Result<()> {
+        if command.num_args() != 0 {
+            return Err("CAPABILITY takes no arguments".into());
+        }
+        Ok(())
+    }
+    async fn handle<'a>(
+        &self,
+        command: &'a Command,
+        context: &'a Context,
+    ) -> Result<Vec<Response>> {
+        let capabilities = self.advertised.list(context).await;
+        Ok(vec![
+            Response::untagged(&code(&capabilities)),
+            Response::new(&command.tag(), ResponseStatus::OK, "CAPABILITY completed"),
+        ])
+    }
+}
+
+#[async_trait::async_trait]
+impl Handle for CapabilityHandler {
+    fn command<'a>(&self) -> &'a str {
+        "CAPABILITY"
+    }
+    async fn start<'a>(&'a mut self, mut requests: Receiver<Request>) -> Result<()> {
+        while let Some(mut request) = requests.next().await {
+            let responses = match self.validate(&request.command).await {
+                Ok(()) => self.handle(&request.command, &request.context).await?,
+                Err(e) => vec![Response::new(
+                    &request.command.tag(),
+                    ResponseStatus::BAD,
+                    &e.to_string(),
+                )],
+            };
+            request.responder.send(responses).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Advertised, CapabilityHandler};
+    use crate::connection::{Context, Event};
+    use crate::handlers::tests::test_handle;
+    use crate::handlers::HandleCommand;
+    use crate::plugin::Capabilities;
+    use crate::server::{Command, Response, ResponseStatus};
+
+    #[async_std::test]
+    async fn test_capability() {
+        let capabilities = Capabilities::new(vec!["IMAP4rev2"]);
+        capabilities.add("ID").await;
+        capabilities.add("id").await;
+        test_handle(
+            CapabilityHandler::new(capabilities),
+            Command::new("a1", "CAPABILITY", vec![]),
+            |responses| {
+                assert_eq!(
+                    responses,
+                    vec![
+                        Response::untagged("CAPABILITY IMAP4rev2 ID"),
+                        Response::new("a1", ResponseStatus::OK, "CAPABILITY completed"),
+                    ]
+                )
+            },
+            None::<fn(Event)>,
+            None,
+        )
+        .await;
+    }
+
+    #[async_std::test]
+    async fn test_login_disabled_before_tls() {
+        let capabilities = Capabilities::new(vec!["IMAP4rev2", "AUTH=PLAIN", "AUTH=ANONYMOUS"]);
+        let handler = CapabilityHandler::new(capabilities).with_require_tls(true);
+        let command = Command::new("a1", "CAPABILITY", vec![]);
+
+        let plaintext = handler.handle(&command, &Context::default()).await.unwrap();
+        assert_eq!(
+            plaintext[0],
+            Response::untagged("CAPABILITY IMAP4rev2 AUTH=ANONYMOUS LOGINDISABLED")
+        );
+        let offered = Context::default().with_starttls();
+        let upgradable = handler.handle(&command, &offered).await.unwrap();
+        assert_eq!(
+            upgradable[0],
+            Response::untagged("CAPABILITY IMAP4rev2 AUTH=ANONYMOUS STARTTLS LOGINDISABLED")
+        );
+        let upgraded = Context::default().with_starttls().with_secure();
+        let upgraded = handler.handle(&command, &upgraded).await.unwrap();
+        assert_eq!(
+            upgraded[0],
+            Response::untagged("CAPABILITY IMAP4rev2 AUTH=PLAIN AUTH=ANONYMOUS")
+        );
+        let secure = Context::default().with_secure();
+        let encrypted = handler.handle(&command, &secure).await.unwrap();
+        assert_eq!(
+            encrypted[0],
+            Response::untagged("CAPABILITY IMAP4rev2 AUTH=PLAIN AUTH=ANONYMOUS")
+        );
+    }
+
+    #[async_std::test]
+    async fn test_advertised_after_login() {
+        let capabilities = Capabilities::new(vec!["IMAP4rev2", "AUTH=ANONYMOUS", "ID"]);
+        let advertised = Advertised::new(capabilities).with_require_tls(true);
+        assert_eq!(advertised.authenticated().await, vec!["IMAP4rev2", "ID"]);
+    }
+}
@@ -0,0 +1,306 @@
+// From RFC 9051 (https://www.ietf.org/rfc/rfc9051.html#name-copy-command):
+//  C: A003 COPY 2:4 MEETING
+//  S: A003 OK COPY completed
+//
+// A destination which does not exist is answered with NO [TRYCREATE], so the client may
+// CREATE it and try again. Guests, who may only read, are refused with NO [NOPERM]. The copies are stored in one batch, so a COPY which fails leaves
+// none of them behind.
+
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use tracing::Instrument;
+
+use crate::connection::{Context, Request};
+use crate::handlers::sequence::SequenceSet;
+use crate::handlers::{destination_error, mailbox_error, HandleCommand, Workers};
+use crate::index::message::MessageRecord;
+use crate::index::{Index, MailboxError, Permission};
+use crate::server::{Command, ParseError, Response, ResponseStatus};
+use crate::state::State;
+use crate::store::home::Home;
+use crate::store::name::resolve_mailbox;
+use crate::store::{Batch, DataStore, Message};
+use crate::util::{Receiver, Result};
+
+use super::Handle;
+
+#[derive(Clone)]
+pub struct CopyHandler {
+    index: Arc<Box<dyn Index>>,
+    store: Arc<Box<dyn DataStore>>,
+    workers: Workers,
+}
+
+impl CopyHandler {
+    #[must_use]
+    pub fn new(index: Arc<Box<dyn Index>>, store: Arc<Box<dyn DataStore>>) -> Self {
+        Self {
+            index,
+            store,
+            workers: Workers::default(),
+        }
+    }
+    /// Runs requests on `workers`, shared with the other handlers of the server.
+    pub fn with_workers(mut self, workers: Workers) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    async fn copy(&self, command: &Command, context: &Context) -> Vec<Response> {
+        let tag = command.tag();
+        let owner = match context.owner() {
+            Some(owner) => owner,
+            None => return vec![State::of(context).rejection(command)],
+        };
+        let folder = match context.folder() {
+            Some(folder) => folder,
+            None => return vec![State::of(context).rejection(command)],
+        };
+        let sequence = match SequenceSet::parse(&command.arg(0)) {
+            Ok(sequence) => sequence,
+            Err(..) => {
+                return vec![Response::new(
+                    &tag,
+                    ResponseStatus::BAD,
+                    "invalid COPY arguments",
+                )]
+            }
+        };
+        if context.is_read_only() {
+            let message = "[NOPERM] Guests may not copy messages.";
+            return vec![Response::new(&tag, ResponseStatus::NO, message)];
+        }
+        let destination = match resolve_mailbox(&command.arg(1)) {
+            Ok(destination) => destination,
+            Err(e) => return vec![mailbox_error(&tag, &MailboxError::from(e))],
+        };
+        if let Err(e) = self
+            .index
+            .get_mailbox(&owner, &destination, Permission::ReadWrite)
+            .await
+        {
+            return vec![destination_error(&tag, &e)];
+        }
+        let records = match self.index.list_messages(&owner, &folder).await {
+            Ok(records) => records,
+            Err(e) => return vec![mailbox_error(&tag, &e)],
+        };
+        let home = Home::new(self.store.clone(), owner);
+        let copied: Vec<&MessageRecord> = sequence
+            .resolve(records.len() as u32)
+            .into_iter()
+            .map(|number| &records[number as usize - 1])
+            .collect();
+        if let Err(e) = self
+            .copy_messages(&home, &folder, &destination, &copied)
+            .await
+        {
+            return vec![mailbox_error(&tag, &e)];
+        }
+        vec![Response::new(&tag, ResponseStatus::OK, "COPY completed.")]
+    }
+
+    /// Appends the messages of `records` to `destination` all at once, keeping their flags
+    /// and internal dates, then indexes the copies.
+    async fn copy_messages(
+        &self,
+        home: &Home,
+        folder: &str,
+        destination: &str,
+        records: &[&MessageRecord],
+    ) -> std::result::Result<(), MailboxError> {
+        let mut batch = Batch::new();
+        for record in records {
+            let body = home.fetch(folder, record.uid).await?;
+            let flags: Vec<&str> = record.flags.iter().map(String::as_str).collect();
+            let message = Message::new(&body)
+                .with_flags(flags)
+                .with_internal_date(record.internal_date);
+            batch = batch.with_append(destination, message);
+        }
+        let uids = home.apply(batch).await?;
+        for (record, uid) in records.iter().zip(uids) {
+            let copied = MessageRecord::new(uid, record.size, record.internal_date)
+                .with_flags(record.flags.clone())
+                .with_envelope(record.envelope.clone());
+            self.index
+                .add_message(home.owner(), destination, copied)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl HandleCommand for CopyHandler {
+    fn name<'a>(&self) -> &'a str {
+        "COPY"
+    }
+    async fn validate<'a>(&self, command: &'a Command) -> Result<()> {
+        if command.command() != self.name() {
+            return Ok(());
+        }
+        if command.num_args() < 2 {
+            return Err(Box::new(ParseError {}));
+        }
+        Ok(())
+    }
+    async fn handle<'a>(
+        &self,
+        command: &'a Command,
+        context: &'a Context,
+    ) -> Result<Vec<Response>> {
+        Ok(self.copy(command, context).await)
+    }
+}
+
+#[async_trait::async_trait]
+impl Handle for CopyHandler {
+    fn command<'a>(&self) -> &'a str {
+        "COPY"
+    }
+
+    async fn start(&mut self, mut requests: Receiver<Request>) -> Result<()> {
+        while let Some(mut request) = requests.next().await {
+            if self.validate(&request.command).await.is_err() {
+                request
+                    .responder
+                    .send(vec![Response::new(
+                        &request.command.tag(),
+                        ResponseStatus::BAD,
+                        "insufficient arguments",
+                    )])
+                    .await?;
+                continue;
+            }
+            let handler = self.clone();
+            let (span, timings) = (request.span.clone(), request.timings.clone());
+            self.workers
+                .spawn(
+                    timings.scope(
+                        async move {
+                            let responses = handler.copy(&request.command, &request.context).await;
+                            let _ = request.responder.send(responses).await;
+                        }
+                        .instrument(span),
+                    ),
+                )
+                .await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use async_std::path::PathBuf;
+
+    use super::CopyHandler;
+    use crate::auth::User;
+    use crate::connection::Context;
+    use crate::handlers::HandleCommand;
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::message::MessageRecord;
+    use crate::index::{Index, Mailbox, Owner, Permission};
+    use crate::server::{Command, Response, ResponseStatus};
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message};
+
+    async fn copy_handler() -> (CopyHandler, Arc<Box<dyn Index>>, Arc<Box<dyn DataStore>>) {
+        let index: Arc<Box<dyn Index>> = Arc::new(Box::new(InMemoryIndex::new()));
+        let store: Arc<Box<dyn DataStore>> = Arc::new(Box::new(InMemoryStore::new()));
+        let owner = Owner::new("username");
+        for body in [&b"first"[..], &b"second"[..]] {
+            let uid = store
+                .append("username", "INBOX", Message::new(body))
+                .await
+                .unwrap();
+            let record = MessageRecord::new(uid, body.len() as u64, SystemTime::now())
+                .with_flags(vec!["\\Seen".to_string()]);
+            index.add_message(&owner, "INBOX", record).await.unwrap();
+        }
+        (CopyHandler::new(index.clone(), store.clone()), index, store)
+    }
+
+    fn selected() -> Context {
+        Context::of(
+            Some(User::new("username", "password")),
+            Some(PathBuf::from("INBOX")),
+        )
+    }
+
+    #[async_std::test]
+    async fn test_copy() {
+        let (handler, index, store) = copy_handler().await;
+        let owner = Owner::new("username");
+        let archive = Mailbox::new("Archive", 0, vec![], Permission::ReadWrite);
+        index.add_mailbox(&owner, archive).await.unwrap();
+
+        let command = Command::new("a1", "COPY", vec!["2", "Archive"]);
+        assert!(handler.validate(&command).await.is_ok());
+        let response = handler.handle(&command, &selected()).await.unwrap();
+        assert_eq!(
+            response,
+            vec![Response::new("a1", ResponseStatus::OK, "COPY completed.")]
+        );
+        let copied = index.list_messages(&owner, "Archive").await.unwrap();
+        assert_eq!(copied.len(), 1);
+        assert_eq!(copied[0].flags, vec!["\\Seen".to_string()]);
+        assert_eq!(
+            store
+                .fetch("username", "Archive", copied[0].uid)
+                .await
+                .unwrap(),
+            b"second".to_vec()
+        );
+    }
+
+    #[async_std::test]
+    async fn test_copy_to_missing_mailbox_asks_to_create_it() {
+        let (handler, index, _) = copy_handler().await;
+        let command = Command::new("a1", "COPY", vec!["1:*", "Sent"]);
+        let response = handler.handle(&command, &selected()).await.unwrap();
+        assert_eq!(
+            response,
+            vec![Response::new(
+                "a1",
+                ResponseStatus::NO,
+                "[TRYCREATE] Mailbox Sent does not exist"
+            )]
+        );
+        let owner = Owner::new("username");
+        assert!(index.list_messages(&owner, "Sent").await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_guests_may_not_copy() {
+        let (handler, index, _) = copy_handler().await;
+        let owner = Owner::new("username");
+        let archive = Mailbox::new("Archive", 0, vec![], Permission::ReadWrite);
+        index.add_mailbox(&owner, archive).await.unwrap();
+
+        let guest = Context::of(
+            Some(User::anonymous("username")),
+            Some(PathBuf::from("INBOX")),
+        );
+        let command = Command::new("a1", "COPY", vec!["1:*", "Archive"]);
+        let response = handler.handle(&command, &guest).await.unwrap();
+        assert_eq!(
+            response,
+            vec![Response::new(
+                "a1",
+                ResponseStatus::NO,
+                "[NOPERM] Guests may not copy messages."
+            )]
+        );
+        assert!(index
+            .list_messages(&owner, "Archive")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,333 @@
+// From RFC 9051 (https://www.ietf.org/rfc/rfc9051.html#name-expunge-command):
+//  C: A202 EXPUNGE
+//  S: * 3 EXPUNGE
+//  S: * 3 EXPUNGE
+//  S: * 5 EXPUNGE
+//  S: * 8 EXPUNGE
+//  S: A202 OK EXPUNGE completed
+//
+// and (https://www.ietf.org/rfc/rfc9051.html#name-uid-command):
+//  C: A003 UID EXPUNGE 3000:3002
+//  S: * 3 EXPUNGE
+//  S: * 3 EXPUNGE
+//  S: * 3 EXPUNGE
+//  S: A003 OK UID EXPUNGE completed
+//
+// UID EXPUNGE only removes the messages flagged \Deleted among the given UIDs, so a client
+// does not expunge messages another client has flagged meanwhile. Each EXPUNGE response gives
+// the sequence number of the message at the time it is sent, counting those expunged before
+// it. Messages are removed from the data store and then the index, whose journal records the
+// expunge for the other sessions with the mailbox selected.
+
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use tracing::Instrument;
+
+use crate::connection::{Context, Request};
+use crate::handlers::sequence::SequenceSet;
+use crate::handlers::{mailbox_error, read_only, HandleCommand, Workers};
+use crate::index::Index;
+use crate::server::{Command, ParseError, Response, ResponseStatus};
+use crate::state::State;
+use crate::store::home::Home;
+use crate::store::DataStore;
+use crate::util::{Receiver, Result};
+
+use super::Handle;
+
+#[derive(Clone)]
+pub struct ExpungeHandler {
+    index: Arc<Box<dyn Index>>,
+    store: Arc<Box<dyn DataStore>>,
+    uid: bool,
+    workers: Workers,
+}
+
+impl ExpungeHandler {
+    /// Handles EXPUNGE, removing every message flagged `\Deleted`.
+    #[must_use]
+    pub fn new(index: Arc<Box<dyn Index>>, store: Arc<Box<dyn DataStore>>) -> Self {
+        Self {
+            index,
+            store,
+            uid: false,
+            workers: Workers::default(),
+        }
+    }
+    /// Handles UID EXPUNGE, removing the messages flagged `\Deleted` among a set of UIDs.
+    /// Other UID commands are answered with BAD.
+    #[must_use]
+    pub fn uid(index: Arc<Box<dyn Index>>, store: Arc<Box<dyn DataStore>>) -> Self {
+        Self {
+            uid: true,
+            ..Self::new(index, store)
+        }
+    }
+    /// Runs requests on `workers`, shared with the other handlers of the server.
+    pub fn with_workers(mut self, workers: Workers) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    async fn expunge(&self, command: &Command, context: &Context) -> Vec<Response> {
+        let tag = command.tag();
+        if self.uid && !command.arg(0).eq_ignore_ascii_case("EXPUNGE") {
+            let message = format!("UID {} is not supported", command.arg(0).to_uppercase());
+            return vec![Response::new(&tag, ResponseStatus::BAD, &message)];
+        }
+        let owner = match context.owner() {
+            Some(owner) => owner,
+            None => return vec![State::of(context).rejection(command)],
+        };
+        let folder = match context.folder() {
+            Some(folder) => folder,
+            None => return vec![State::of(context).rejection(command)],
+        };
+        if let Some(refusal) = read_only(&tag, context) {
+            return vec![refusal];
+        }
+        let uids = match self.uid {
+            true => match SequenceSet::parse(&command.arg(1)) {
+                Ok(uids) => Some(uids),
+                Err(..) => {
+                    return vec![Response::new(
+                        &tag,
+                        ResponseStatus::BAD,
+                        "invalid UID EXPUNGE arguments",
+                    )]
+                }
+            },
+            false => None,
+        };
+        let records = match self.index.list_messages(&owner, &folder).await {
+            Ok(records) => records,
+            Err(e) => return vec![mailbox_error(&tag, &e)],
+        };
+        let largest = records.iter().map(|record| record.uid).max().unwrap_or(0);
+        let deleted: Vec<u32> = records
+            .iter()
+            .filter(|record| record.flags.iter().any(|flag| flag == "\\Deleted"))
+            .filter(|record| match &uids {
+                Some(uids) => uids.contains(record.uid, largest),
+                None => true,
+            })
+            .map(|record| record.uid)
+            .collect();
+
+        let mut responses = vec![];
+        if !deleted.is_empty() {
+            let home = Home::new(self.store.clone(), owner.clone());
+            let expunged = match home.expunge(&folder, &deleted).await {
+                Ok(expunged) => expunged,
+                Err(e) => return vec![mailbox_error(&tag, &e.into())],
+            };
+            let removed = match self.index.remove_messages(&owner, &folder, &expunged).await {
+                Ok(removed) => removed,
+                Err(e) => return vec![mailbox_error(&tag, &e)],
+            };
+            let mut earlier = 0;
+            for (position, record) in records.iter().enumerate() {
+                if removed.contains(&record.uid) {
+                    let number = position + 1 - earlier;
+                    responses.push(Response::untagged(&format!("{} EXPUNGE", number)));
+                    earlier += 1;
+                }
+            }
+        }
+        let completed = match self.uid {
+            true => "UID EXPUNGE completed.",
+            false => "EXPUNGE completed.",
+        };
+        responses.push(Response::new(&tag, ResponseStatus::OK, completed));
+        responses
+    }
+}
+
+#[async_trait::async_trait]
+impl HandleCommand for ExpungeHandler {
+    fn name<'a>(&self) -> &'a str {
+        match self.uid {
+            true => "UID",
+            false => "EXPUNGE",
+        }
+    }
+    async fn validate<'a>(&self, command: &'a Command) -> Result<()> {
+        if command.command() != self.name() {
+            return Ok(());
+        }
+        if self.uid && command.num_args() < 2 {
+            return Err(Box::new(ParseError {}));
+        }
+        Ok(())
+    }
+    async fn handle<'a>(
+        &self,
+        command: &'a Command,
+        context: &'a Context,
+    ) -> Result<Vec<Response>> {
+        Ok(self.expunge(command, context).await)
+    }
+}
+
+#[async_trait::async_trait]
+impl Handle for ExpungeHandler {
+    fn command<'a>(&self) -> &'a str {
+        self.name()
+    }
+
+    async fn start(&mut self, mut requests: Receiver<Request>) -> Result<()> {
+        while let Some(mut request) = requests.next().await {
+            if self.validate(&request.command).await.is_err() {
+                request
+                    .responder
+                    .send(vec![Response::new(
+                        &request.command.tag(),
+                        ResponseStatus::BAD,
+                        "insufficient arguments",
+                    )])
+                    .await?;
+                continue;
+            }
+            let handler = self.clone();
+            let (span, timings) = (request.span.clone(), request.timings.clone());
+            self.workers
+                .spawn(
+                    timings.scope(
+                        async move {
+                            let responses =
+                                handler.expunge(&request.command, &request.context).await;
+                            let _ = request.responder.send(responses).await;
+                        }
+                        .instrument(span),
+                    ),
+                )
+                .await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use async_std::path::PathBuf;
+
+    use super::ExpungeHandler;
+    use crate::auth::User;
+    use crate::connection::Context;
+    use crate::handlers::HandleCommand;
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::message::MessageRecord;
+    use crate::index::{Index, Owner, Permission};
+    use crate::server::{Command, Response, ResponseStatus};
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message};
+
+    fn selected() -> Context {
+        Context::of(
+            Some(User::new("username", "password")),
+            Some(PathBuf::from("INBOX")),
+        )
+    }
+
+    /// Five messages, of which 2, 3 and 5 are flagged `\Deleted`.
+    async fn mailbox() -> (Arc<Box<dyn Index>>, Arc<Box<dyn DataStore>>) {
+        let index: Arc<Box<dyn Index>> = Arc::new(Box::new(InMemoryIndex::new()));
+        let store: Arc<Box<dyn DataStore>> = Arc::new(Box::new(InMemoryStore::new()));
+        let owner = Owner::new("username");
+        for number in 1..=5 {
+            let uid = store
+                .append("username", "INBOX", Message::new(b"Subject: hi\r\n\r\nhi"))
+                .await
+                .unwrap();
+            let flags = match number {
+                2 | 3 | 5 => vec!["\\Deleted".to_string()],
+                _ => vec![],
+            };
+            let record = MessageRecord::new(uid, 17, SystemTime::now()).with_flags(flags);
+            index.add_message(&owner, "INBOX", record).await.unwrap();
+        }
+        (index, store)
+    }
+
+    async fn remaining(index: &Arc<Box<dyn Index>>, store: &Arc<Box<dyn DataStore>>) -> Vec<u32> {
+        let owner = Owner::new("username");
+        let records = index.list_messages(&owner, "INBOX").await.unwrap();
+        let stored = store.list("username", "INBOX").await.unwrap();
+        assert_eq!(records.len(), stored.len());
+        records.iter().map(|record| record.uid).collect()
+    }
+
+    #[async_std::test]
+    async fn test_expunge() {
+        let (index, store) = mailbox().await;
+        let handler = ExpungeHandler::new(index.clone(), store.clone());
+        let command = Command::new("a1", "EXPUNGE", vec![]);
+        assert!(handler.validate(&command).await.is_ok());
+        let response = handler.handle(&command, &selected()).await.unwrap();
+        assert_eq!(
+            response,
+            vec![
+                Response::untagged("2 EXPUNGE"),
+                Response::untagged("2 EXPUNGE"),
+                Response::untagged("3 EXPUNGE"),
+                Response::new("a1", ResponseStatus::OK, "EXPUNGE completed."),
+            ]
+        );
+        assert_eq!(remaining(&index, &store).await, vec![1, 4]);
+    }
+
+    #[async_std::test]
+    async fn test_uid_expunge_only_removes_the_given_uids() {
+        let (index, store) = mailbox().await;
+        let handler = ExpungeHandler::uid(index.clone(), store.clone());
+        let command = Command::new("a1", "UID", vec!["EXPUNGE", "1:3"]);
+        let response = handler.handle(&command, &selected()).await.unwrap();
+        assert_eq!(
+            response,
+            vec![
+                Response::untagged("2 EXPUNGE"),
+                Response::untagged("2 EXPUNGE"),
+                Response::new("a1", ResponseStatus::OK, "UID EXPUNGE completed."),
+            ]
+        );
+        assert_eq!(remaining(&index, &store).await, vec![1, 4, 5]);
+
+        let command = Command::new("a2", "UID", vec!["expunge", "4"]);
+        let response = handler.handle(&command, &selected()).await.unwrap();
+        assert_eq!(
+            response,
+            vec![Response::new(
+                "a2",
+                ResponseStatus::OK,
+                "UID EXPUNGE completed."
+            )]
+        );
+        let command = Command::new("a3", "UID", vec!["EXPUNGE"]);
+        assert!(handler.validate(&command).await.is_err());
+        let command = Command::new("a4", "UID", vec!["FETCH", "1", "FLAGS"]);
+        let response = handler.handle(&command, &selected()).await.unwrap();
+        assert_eq!(response[0].status(), Some(ResponseStatus::BAD));
+    }
+
+    #[async_std::test]
+    async fn test_expunge_refused_when_read_only() {
+        let (index, store) = mailbox().await;
+        let handler = ExpungeHandler::new(index.clone(), store.clone());
+        let command = Command::new("a1", "EXPUNGE", vec![]);
+        let examined = selected().with_permission(Permission::ReadOnly);
+        let response = handler.handle(&command, &examined).await.unwrap();
+        assert_eq!(
+            response,
+            vec![Response::new(
+                "a1",
+                ResponseStatus::NO,
+                "[READ-ONLY] The mailbox is open read-only."
+            )]
+        );
+        assert_eq!(remaining(&index, &store).await, vec![1, 2, 3, 4, 5]);
+    }
+}
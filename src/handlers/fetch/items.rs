@@ -0,0 +1,344 @@
+use std::fmt::Display;
+
+use crate::server::ParseError;
+
+/// A single FETCH data item (RFC 9051 section 6.4.5).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    Flags,
+    Uid,
+    Envelope,
+    InternalDate,
+    Rfc822Size,
+    Rfc822Header,
+    /// `RFC822`, equivalent to `BODY[]`.
+    Rfc822,
+    /// `RFC822.TEXT`, equivalent to `BODY[TEXT]`.
+    Rfc822Text,
+    BodyStructure,
+    /// The non-extensible form of BODYSTRUCTURE.
+    Body,
+    /// `BODY[<section>]`, or `BODY.PEEK[<section>]` when `peek` is set, optionally limited to
+    /// `count` octets starting at `offset` by a trailing `<offset.count>`.
+    Section {
+        section: Section,
+        peek: bool,
+        partial: Option<Partial>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partial {
+    pub offset: u32,
+    pub count: u32,
+}
+
+/// The section specification of a `BODY[...]` item, e.g. `1.2.HEADER.FIELDS (DATE FROM)`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Section {
+    /// The IMAP part number; empty for the whole message.
+    pub part: Vec<usize>,
+    pub text: Option<SectionText>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SectionText {
+    Header,
+    HeaderFields(Vec<String>),
+    HeaderFieldsNot(Vec<String>),
+    Text,
+    Mime,
+}
+
+impl Item {
+    /// Whether rendering this item needs the complete message from the data store. Only the
+    /// header section is loaded for `RFC822.HEADER`, and the other items come from the index.
+    pub fn needs_body(&self) -> bool {
+        matches!(
+            self,
+            Item::Rfc822
+                | Item::Rfc822Text
+                | Item::BodyStructure
+                | Item::Body
+                | Item::Section { .. }
+        ) && !self.is_ranged_message()
+    }
+
+    /// Whether this is a partial fetch of the whole message, which can be read from the data
+    /// store as a byte range without parsing the message.
+    pub fn is_ranged_message(&self) -> bool {
+        matches!(
+            self,
+            Item::Section {
+                section: Section { part, text: None },
+                partial: Some(_),
+                ..
+            } if part.is_empty()
+        )
+    }
+
+    /// Whether this is the whole message, `RFC822` or `BODY[]`, which can be sent as it is
+    /// read from the data store.
+    pub fn is_whole_message(&self) -> bool {
+        match self {
+            Item::Rfc822 => true,
+            Item::Section {
+                section: Section { part, text: None },
+                partial: None,
+                ..
+            } => part.is_empty(),
+            _ => false,
+        }
+    }
+}
+
+impl Display for Section {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let part: Vec<String> = self.part.iter().map(usize::to_string).collect();
+        write!(f, "{}", part.join("."))?;
+        let text = match &self.text {
+            None => return Ok(()),
+            Some(SectionText::Header) => "HEADER".to_string(),
+            Some(SectionText::HeaderFields(fields)) => {
+                format!("HEADER.FIELDS ({})", fields.join(" "))
+            }
+            Some(SectionText::HeaderFieldsNot(fields)) => {
+                format!("HEADER.FIELDS.NOT ({})", fields.join(" "))
+            }
+            Some(SectionText::Text) => "TEXT".to_string(),
+            Some(SectionText::Mime) => "MIME".to_string(),
+        };
+        if self.part.is_empty() {
+            write!(f, "{}", text)
+        } else {
+            write!(f, ".{}", text)
+        }
+    }
+}
+
+impl Section {
+    pub fn parse(spec: &str) -> Result<Self, ParseError> {
+        let mut section = Section::default();
+        let mut rest = spec.trim();
+        while let Some(number) = rest.split('.').next().and_then(|n| n.parse::<usize>().ok()) {
+            if number == 0 {
+                return Err(ParseError {});
+            }
+            section.part.push(number);
+            let length = rest.find('.').unwrap_or(rest.len());
+            rest = rest[length..].strip_prefix('.').unwrap_or("");
+        }
+        if rest.is_empty() {
+            return Ok(section);
+        }
+        let (name, fields) = match rest.split_once(' ') {
+            Some((name, fields)) => (name, Some(fields)),
+            None => (rest, None),
+        };
+        section.text = Some(match (name.to_ascii_uppercase().as_str(), fields) {
+            ("HEADER", None) => SectionText::Header,
+            ("TEXT", None) => SectionText::Text,
+            ("MIME", None) if !section.part.is_empty() => SectionText::Mime,
+            ("HEADER.FIELDS", Some(fields)) => SectionText::HeaderFields(field_names(fields)?),
+            ("HEADER.FIELDS.NOT", Some(fields)) => {
+                SectionText::HeaderFieldsNot(field_names(fields)?)
+            }
+            _ => return Err(ParseError {}),
+        });
+        Ok(section)
+    }
+}
+
+fn field_names(list: &str) -> Result<Vec<String>, ParseError> {
+    let names = list
+        .trim()
+        .strip_prefix('(')
+        .and_then(|list| list.strip_suffix(')'))
+        .ok_or(ParseError {})?;
+    let names: Vec<String> = names.split_whitespace().map(str::to_string).collect();
+    if names.is_empty() {
+        return Err(ParseError {});
+    }
+    Ok(names)
+}
+
+/// Parses the data items of a FETCH command: either a single item or a parenthesised list.
+pub fn parse(items: &str) -> Result<Vec<Item>, ParseError> {
+    let items = items.trim();
+    // RFC 9051 section 6.4.5: the macros may only be used on their own.
+    let fast = vec![Item::Flags, Item::InternalDate, Item::Rfc822Size];
+    match items.to_ascii_uppercase().as_str() {
+        "FAST" => return Ok(fast),
+        "ALL" => return Ok([fast, vec![Item::Envelope]].concat()),
+        "FULL" => return Ok([fast, vec![Item::Envelope, Item::Body]].concat()),
+        _ => {}
+    }
+    let items = match items.strip_prefix('(') {
+        Some(list) => list.strip_suffix(')').ok_or(ParseError {})?,
+        None => items,
+    };
+    let items = split(items)?
+        .into_iter()
+        .map(parse_item)
+        .collect::<Result<Vec<_>, _>>()?;
+    if items.is_empty() {
+        return Err(ParseError {});
+    }
+    Ok(items)
+}
+
+fn parse_item(item: &str) -> Result<Item, ParseError> {
+    let upper = item.to_ascii_uppercase();
+    match upper.as_str() {
+        "FLAGS" => return Ok(Item::Flags),
+        "UID" => return Ok(Item::Uid),
+        "ENVELOPE" => return Ok(Item::Envelope),
+        "INTERNALDATE" => return Ok(Item::InternalDate),
+        "RFC822.SIZE" => return Ok(Item::Rfc822Size),
+        "RFC822.HEADER" => return Ok(Item::Rfc822Header),
+        "RFC822" => return Ok(Item::Rfc822),
+        "RFC822.TEXT" => return Ok(Item::Rfc822Text),
+        "BODYSTRUCTURE" => return Ok(Item::BodyStructure),
+        "BODY" => return Ok(Item::Body),
+        _ => {}
+    }
+    let (peek, prefix) = if upper.starts_with("BODY.PEEK[") {
+        (true, "BODY.PEEK[".len())
+    } else if upper.starts_with("BODY[") {
+        (false, "BODY[".len())
+    } else {
+        return Err(ParseError {});
+    };
+    let (spec, partial) = match item.strip_suffix('>') {
+        Some(rest) => {
+            let (spec, range) = rest.rsplit_once('<').ok_or(ParseError {})?;
+            let (offset, count) = range.split_once('.').ok_or(ParseError {})?;
+            let partial = Partial {
+                offset: offset.parse().map_err(|_| ParseError {})?,
+                count: count.parse().map_err(|_| ParseError {})?,
+            };
+            (spec, Some(partial))
+        }
+        None => (item, None),
+    };
+    let spec = spec[prefix..].strip_suffix(']').ok_or(ParseError {})?;
+    Ok(Item::Section {
+        section: Section::parse(spec)?,
+        peek,
+        partial,
+    })
+}
+
+/// Splits a list of items on spaces, keeping the spaces inside `[...]` sections.
+fn split(items: &str) -> Result<Vec<&str>, ParseError> {
+    let mut split = vec![];
+    let (mut start, mut depth) = (0, 0usize);
+    for (position, c) in items.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth = depth.checked_sub(1).ok_or(ParseError {})?,
+            ' ' if depth == 0 => {
+                if position > start {
+                    split.push(&items[start..position]);
+                }
+                start = position + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err(ParseError {});
+    }
+    if start < items.len() {
+        split.push(&items[start..]);
+    }
+    Ok(split)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Item, Partial, Section, SectionText};
+
+    #[test]
+    fn test_parse_items() {
+        let items =
+            parse("(FLAGS body.peek[1.2.HEADER.FIELDS (Date From)] ENVELOPE BODYSTRUCTURE)")
+                .unwrap();
+        assert_eq!(
+            items,
+            vec![
+                Item::Flags,
+                Item::Section {
+                    section: Section {
+                        part: vec![1, 2],
+                        text: Some(SectionText::HeaderFields(vec![
+                            "Date".to_string(),
+                            "From".to_string()
+                        ])),
+                    },
+                    peek: true,
+                    partial: None,
+                },
+                Item::Envelope,
+                Item::BodyStructure,
+            ]
+        );
+        assert_eq!(parse("BODY").unwrap(), vec![Item::Body]);
+        assert_eq!(
+            parse("all").unwrap(),
+            vec![
+                Item::Flags,
+                Item::InternalDate,
+                Item::Rfc822Size,
+                Item::Envelope
+            ]
+        );
+        assert_eq!(
+            parse("(RFC822.SIZE RFC822.HEADER)").unwrap(),
+            vec![Item::Rfc822Size, Item::Rfc822Header]
+        );
+        assert_eq!(
+            parse("BODY[]").unwrap(),
+            vec![Item::Section {
+                section: Section::default(),
+                peek: false,
+                partial: None,
+            }]
+        );
+        let ranged = parse("BODY.PEEK[]<10.200>").unwrap();
+        assert_eq!(
+            ranged,
+            vec![Item::Section {
+                section: Section::default(),
+                peek: true,
+                partial: Some(Partial {
+                    offset: 10,
+                    count: 200
+                }),
+            }]
+        );
+        assert!(ranged[0].is_ranged_message() && !ranged[0].needs_body());
+        assert!(parse("BODY[1]<0.1>").unwrap()[0].needs_body());
+        for invalid in [
+            "",
+            "()",
+            "BODY[",
+            "BODY[MIME]",
+            "BODY[0]",
+            "BODY[HEADER.FIELDS ()]",
+            "RANDOM",
+            "(FAST)",
+            "BODY[]<1>",
+            "BODY[]<a.1>",
+        ] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_section_round_trip() {
+        for spec in ["", "TEXT", "3", "2.1.MIME", "HEADER.FIELDS.NOT (Subject)"] {
+            assert_eq!(Section::parse(spec).unwrap().to_string(), spec);
+        }
+    }
+}
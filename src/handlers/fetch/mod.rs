@@ -0,0 +1,590 @@
+// From RFC 9051 (https://www.ietf.org/rfc/rfc9051.html#name-fetch-command):
+// C: A654 FETCH 2:4 (FLAGS BODY[HEADER.FIELDS (DATE FROM)])
+// S: * 2 FETCH
+// + From: someone@example.com
+// + To: someone_else@example.com
+// + Subject: An RFC 822 formatted message
+// +
+// + This is a test email body.
+// S: * 3 FETCH ....
+// S: * 4 FETCH ....
+// S: A654 OK FETCH completed
+
+pub mod items;
+pub mod structure;
+
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use tracing::Instrument;
+
+use crate::connection::{Context, Request};
+use crate::handlers::sequence::SequenceSet;
+use crate::handlers::{mailbox_error, HandleCommand, Workers};
+use crate::index::message::MessageRecord;
+use crate::index::{Index, MailboxError};
+use crate::mime::Part;
+use crate::server::{Command, Literal, ParseError, Response, ResponseStatus};
+use crate::state::State;
+use crate::store::home::Home;
+use crate::store::{slice, DataStore};
+use crate::util::{Receiver, Result};
+
+use self::items::{Item, Section, SectionText};
+use self::structure::{body_structure, date_time, envelope};
+
+use super::Handle;
+
+/// Messages at least this large are streamed from the data store when the whole message is
+/// fetched, rather than loaded into the response.
+const STREAM_THRESHOLD: u64 = 1024 * 1024;
+
+/// Responses are sent as text, so a body which is not valid UTF-8 is sent with replacement
+/// characters, and the literal length counts the bytes actually sent.
+fn literal(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    format!("{{{}}}\r\n{}", text.len(), text)
+}
+
+/// Returns the contents of a `BODY[...]` section, or `None` if the message has no such part.
+fn section<'a>(message: &Part<'a>, section: &Section) -> Option<Cow<'a, [u8]>> {
+    let part = message.part(&section.part)?;
+    // HEADER and TEXT of a numbered part refer to the message encapsulated in it.
+    let encapsulated = match section.part.is_empty() {
+        true => Some(part),
+        false => part.message.as_deref(),
+    };
+    let contents = match &section.text {
+        None if section.part.is_empty() => part.raw(),
+        None => part.body(),
+        Some(SectionText::Mime) => part.headers().raw(),
+        Some(SectionText::Header) => encapsulated?.headers().raw(),
+        Some(SectionText::Text) => encapsulated?.body(),
+        Some(SectionText::HeaderFields(names)) => {
+            return Some(Cow::Owned(header_fields(encapsulated?, names, true)))
+        }
+        Some(SectionText::HeaderFieldsNot(names)) => {
+            return Some(Cow::Owned(header_fields(encapsulated?, names, false)))
+        }
+    };
+    Some(Cow::Borrowed(contents))
+}
+
+fn header_fields(part: &Part, names: &[String], included: bool) -> Vec<u8> {
+    let mut fields: Vec<u8> = part
+        .headers()
+        .fields()
+        .into_iter()
+        .filter(|field| {
+            names
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(field.name))
+                == included
+        })
+        .flat_map(|field| field.raw.iter().copied())
+        .collect();
+    fields.extend_from_slice(b"\r\n");
+    fields
+}
+
+/// The position of the whole message among `items` if it should be streamed: the message is
+/// large, and nothing else needs it loaded.
+fn streamed(record: &MessageRecord, items: &[Item]) -> Option<usize> {
+    if record.size < STREAM_THRESHOLD {
+        return None;
+    }
+    let position = items.iter().position(Item::is_whole_message)?;
+    // Any other item loading the message, including a second copy of it, would defeat the
+    // point.
+    let loaded = items
+        .iter()
+        .enumerate()
+        .any(|(index, item)| index != position && item.needs_body());
+    (!loaded).then_some(position)
+}
+
+#[derive(Clone)]
+pub struct FetchHandler {
+    index: Arc<Box<dyn Index>>,
+    store: Arc<Box<dyn DataStore>>,
+    workers: Workers,
+}
+
+impl FetchHandler {
+    #[must_use]
+    pub fn new(index: Arc<Box<dyn Index>>, store: Arc<Box<dyn DataStore>>) -> Self {
+        Self {
+            index,
+            store,
+            workers: Workers::default(),
+        }
+    }
+    /// Runs requests on `workers`, shared with the other handlers of the server.
+    pub fn with_workers(mut self, workers: Workers) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    async fn fetch(&self, command: &Command, context: &Context) -> Vec<Response> {
+        let tag = command.tag();
+        let owner = match context.owner() {
+            Some(owner) => owner,
+            None => return vec![State::of(context).rejection(command)],
+        };
+        let folder = match context.folder() {
+            Some(folder) => folder,
+            None => return vec![State::of(context).rejection(command)],
+        };
+        let arguments: Vec<String> = (1..command.num_args()).map(|i| command.arg(i)).collect();
+        let (sequence, items) = match (
+            SequenceSet::parse(&command.arg(0)),
+            items::parse(&arguments.join(" ")),
+        ) {
+            (Ok(sequence), Ok(items)) => (sequence, items),
+            _ => {
+                return vec![Response::new(
+                    &tag,
+                    ResponseStatus::BAD,
+                    "invalid FETCH arguments",
+                )]
+            }
+        };
+        let records = match self.index.list_messages(&owner, &folder).await {
+            Ok(records) => records,
+            Err(e) => return vec![mailbox_error(&tag, &e)],
+        };
+        let home = Home::new(self.store.clone(), owner);
+
+        let mut responses = vec![];
+        for number in sequence.resolve(records.len() as u32) {
+            let record = &records[number as usize - 1];
+            if let Some(position) = streamed(record, &items) {
+                match self
+                    .stream_message(&home, &folder, number, record, &items, position)
+                    .await
+                {
+                    Ok(response) => responses.push(response),
+                    Err(e) => return vec![mailbox_error(&tag, &e)],
+                }
+                continue;
+            }
+            match self.fetch_message(&home, &folder, record, &items).await {
+                Ok(attributes) => responses.push(
+                    Response::from(&format!("* {} FETCH ({})", number, attributes.join(" ")))
+                        .unwrap(),
+                ),
+                Err(e) => return vec![mailbox_error(&tag, &e)],
+            }
+        }
+        responses.push(Response::new(&tag, ResponseStatus::OK, "FETCH completed."));
+        responses
+    }
+
+    /// Renders every item but the whole message at `position` as usual, and sends the
+    /// message itself as a literal read from the data store as it is written to the client.
+    async fn stream_message(
+        &self,
+        home: &Home,
+        folder: &str,
+        number: u32,
+        record: &MessageRecord,
+        items: &[Item],
+        position: usize,
+    ) -> std::result::Result<Response, MailboxError> {
+        let mut others = items.to_vec();
+        let whole = others.remove(position);
+        let attributes = self.fetch_message(home, folder, record, &others).await?;
+        let (before, after) = attributes.split_at(position);
+        let (length, body) = home.open(folder, record.uid).await?;
+        let name = match whole {
+            Item::Rfc822 => "RFC822",
+            _ => "BODY[]",
+        };
+        let before: String = before.iter().map(|item| format!("{} ", item)).collect();
+        let after: String = after.iter().map(|item| format!(" {}", item)).collect();
+        Ok(
+            Response::untagged(&format!("{} FETCH ({}{} ", number, before, name))
+                .with_literal(Literal::new(length, body), &format!("{})", after)),
+        )
+    }
+
+    async fn fetch_message(
+        &self,
+        home: &Home,
+        folder: &str,
+        record: &MessageRecord,
+        items: &[Item],
+    ) -> std::result::Result<Vec<String>, MailboxError> {
+        let body = if items.iter().any(Item::needs_body) {
+            home.fetch(folder, record.uid).await
+        } else if items.contains(&Item::Rfc822Header) {
+            home.fetch_header(folder, record.uid).await
+        } else {
+            Ok(vec![])
+        };
+        let body = body?;
+        let message = Part::parse(&body);
+        let mut attributes = vec![];
+        for item in items {
+            attributes.push(match item {
+                Item::Flags => format!("FLAGS ({})", record.flags.join(" ")),
+                Item::Uid => format!("UID {}", record.uid),
+                Item::Envelope => format!("ENVELOPE {}", envelope(&record.envelope)),
+                Item::InternalDate => format!("INTERNALDATE {}", date_time(record.internal_date)),
+                Item::Rfc822Size => format!("RFC822.SIZE {}", record.size),
+                Item::Rfc822Header => format!("RFC822.HEADER {}", literal(message.headers().raw())),
+                Item::Rfc822 => format!("RFC822 {}", literal(message.raw())),
+                Item::Rfc822Text => format!("RFC822.TEXT {}", literal(message.body())),
+                Item::BodyStructure => format!("BODYSTRUCTURE {}", body_structure(&message, true)),
+                Item::Body => format!("BODY {}", body_structure(&message, false)),
+                Item::Section {
+                    section: spec,
+                    partial: Some(partial),
+                    ..
+                } => {
+                    let contents = match item.is_ranged_message() {
+                        true => Some(
+                            home.fetch_range(
+                                folder,
+                                record.uid,
+                                partial.offset as u64,
+                                partial.count as u64,
+                            )
+                            .await?,
+                        ),
+                        false => section(&message, spec).map(|contents| {
+                            slice(&contents, partial.offset as u64, partial.count as u64).to_vec()
+                        }),
+                    };
+                    let contents = contents.map(|contents| literal(&contents));
+                    format!(
+                        "BODY[{}]<{}> {}",
+                        spec,
+                        partial.offset,
+                        contents.as_deref().unwrap_or("NIL")
+                    )
+                }
+                Item::Section { section: spec, .. } => match section(&message, spec) {
+                    Some(contents) => format!("BODY[{}] {}", spec, literal(&contents)),
+                    None => format!("BODY[{}] NIL", spec),
+                },
+            });
+        }
+        Ok(attributes)
+    }
+}
+
+#[async_trait::async_trait]
+impl HandleCommand for FetchHandler {
+    fn name<'a>(&self) -> &'a str {
+        "FETCH"
+    }
+    async fn validate<'a>(&self, command: &'a Command) -> Result<()> {
+        if command.command() != self.name() {
+            return Ok(());
+        }
+        if command.num_args() < 2 {
+            return Err(Box::new(ParseError {}));
+        }
+        Ok(())
+    }
+    async fn handle<'a>(
+        &self,
+        command: &'a Command,
+        context: &'a Context,
+    ) -> Result<Vec<Response>> {
+        Ok(self.fetch(command, context).await)
+    }
+}
+#[async_trait::async_trait]
+impl Handle for FetchHandler {
+    fn command<'a>(&self) -> &'a str {
+        "FETCH"
+    }
+
+    async fn start(&mut self, mut requests: Receiver<Request>) -> Result<()> {
+        while let Some(mut request) = requests.next().await {
+            if self.validate(&request.command).await.is_err() {
+                request
+                    .responder
+                    .send(vec![Response::new(
+                        &request.command.tag(),
+                        ResponseStatus::BAD,
+                        "insufficient arguments",
+                    )])
+                    .await?;
+                continue;
+            }
+            // Each FETCH runs on its own task, so that a slow one does not hold up the
+            // others.
+            let handler = self.clone();
+            let (span, timings) = (request.span.clone(), request.timings.clone());
+            self.workers
+                .spawn(
+                    timings.scope(
+                        async move {
+                            let responses = handler.fetch(&request.command, &request.context).await;
+                            let _ = request.responder.send(responses).await;
+                        }
+                        .instrument(span),
+                    ),
+                )
+                .await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_std::path::PathBuf;
+    use futures::AsyncReadExt;
+
+    use super::FetchHandler;
+    use crate::auth::User;
+    use crate::connection::Context;
+    use crate::handlers::tests::test_handle;
+    use crate::handlers::HandleCommand;
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::reindex::reindex;
+    use crate::index::{Index, Owner};
+    use crate::server::{Command, Response, ResponseStatus};
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message};
+
+    const MESSAGE: &[u8] = b"From: someone@example.com\r\n\
+Subject: An RFC 822 formatted message\r\n\
+\r\n\
+This is a test email body.";
+
+    async fn fetch_handler() -> FetchHandler {
+        let store: Box<dyn DataStore> = Box::new(InMemoryStore::new());
+        let index: Box<dyn Index> = Box::new(InMemoryIndex::new());
+        store
+            .append(
+                "username",
+                "INBOX",
+                Message::new(MESSAGE).with_flags(vec!["\\Seen"]),
+            )
+            .await
+            .unwrap();
+        reindex(
+            store.as_ref(),
+            index.as_ref(),
+            &Owner::new("username"),
+            |_| {},
+        )
+        .await
+        .unwrap();
+        FetchHandler::new(Arc::new(index), Arc::new(store))
+    }
+
+    fn selected() -> Context {
+        Context::of(
+            Some(User::new("username", "password")),
+            Some(PathBuf::from("INBOX")),
+        )
+    }
+
+    #[async_std::test]
+    async fn test_fetch_success() {
+        let fetch_handler = fetch_handler().await;
+        let fetch_command = Command::new("a1", "FETCH", vec!["1", "BODY[TEXT]"]);
+        let valid = fetch_handler.validate(&fetch_command).await;
+        assert!(valid.is_ok());
+        let response = fetch_handler.handle(&fetch_command, &selected()).await;
+        fetch_success(response.unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_fetch_handle() {
+        let handler = fetch_handler().await;
+        let command = Command::new("a1", "FETCH", vec!["1", "BODY[TEXT]"]);
+
+        let mut f = Some(|_event| {});
+        f.take();
+        test_handle(handler, command, fetch_success, f, Some(selected())).await;
+    }
+
+    #[async_std::test]
+    async fn test_fetch_body_structure() {
+        let handler = fetch_handler().await;
+        let command = Command::new(
+            "a1",
+            "FETCH",
+            vec!["1:*", "(UID", "FLAGS", "BODYSTRUCTURE", "BODY)"],
+        );
+        let response = handler.handle(&command, &selected()).await.unwrap();
+        assert_eq!(
+            response,
+            vec![
+                Response::from("* 1 FETCH (UID 1 FLAGS (\\Seen) \
+BODYSTRUCTURE (\"TEXT\" \"PLAIN\" (\"CHARSET\" \"us-ascii\") NIL NIL \"7BIT\" 26 1 NIL NIL NIL NIL) \
+BODY (\"TEXT\" \"PLAIN\" (\"CHARSET\" \"us-ascii\") NIL NIL \"7BIT\" 26 1))")
+                .unwrap(),
+                Response::new("a1", ResponseStatus::OK, "FETCH completed."),
+            ]
+        );
+
+        let command = Command::new(
+            "a1",
+            "FETCH",
+            vec!["1", "BODY.PEEK[HEADER.FIELDS", "(SUBJECT)]"],
+        );
+        let response = handler.handle(&command, &selected()).await.unwrap();
+        assert_eq!(
+            response[0],
+            Response::from("* 1 FETCH (BODY[HEADER.FIELDS (SUBJECT)] {41}\r\nSubject: An RFC 822 formatted message\r\n\r\n)")
+                .unwrap()
+        );
+
+        let command = Command::new("a1", "FETCH", vec!["1", "(BODY[1.2]"]);
+        let response = handler.handle(&command, &selected()).await.unwrap();
+        assert_eq!(
+            response,
+            vec![Response::new(
+                "a1",
+                ResponseStatus::BAD,
+                "invalid FETCH arguments"
+            )]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_fetch_size_and_header() {
+        let handler = fetch_handler().await;
+        let command = Command::new("a1", "FETCH", vec!["1", "(RFC822.SIZE", "RFC822.HEADER)"]);
+        let response = handler.handle(&command, &selected()).await.unwrap();
+        assert_eq!(
+            response[0],
+            Response::from(
+                "* 1 FETCH (RFC822.SIZE 94 RFC822.HEADER {68}\r\n\
+From: someone@example.com\r\nSubject: An RFC 822 formatted message\r\n\r\n)"
+            )
+            .unwrap()
+        );
+    }
+
+    #[async_std::test]
+    async fn test_partial_fetch() {
+        let handler = fetch_handler().await;
+        let command = Command::new(
+            "a1",
+            "FETCH",
+            vec![
+                "1",
+                "(BODY.PEEK[]<6.7>",
+                "BODY[TEXT]<10.100>",
+                "BODY[1]<200.10>)",
+            ],
+        );
+        let response = handler.handle(&command, &selected()).await.unwrap();
+        assert_eq!(
+            response[0],
+            Response::from(
+                "* 1 FETCH (BODY[]<6> {7}\r\nsomeone \
+BODY[TEXT]<10> {16}\r\ntest email body. BODY[1]<200> {0}\r\n)"
+            )
+            .unwrap()
+        );
+    }
+
+    #[async_std::test]
+    async fn test_large_message_streamed() {
+        let store: Box<dyn DataStore> = Box::new(InMemoryStore::new());
+        let index: Box<dyn Index> = Box::new(InMemoryIndex::new());
+        let mut large = MESSAGE.to_vec();
+        large.resize(2 * super::STREAM_THRESHOLD as usize, b'x');
+        store
+            .append("username", "INBOX", Message::new(&large))
+            .await
+            .unwrap();
+        reindex(
+            store.as_ref(),
+            index.as_ref(),
+            &Owner::new("username"),
+            |_| {},
+        )
+        .await
+        .unwrap();
+        let handler = FetchHandler::new(Arc::new(index), Arc::new(store));
+
+        let command = Command::new("a1", "FETCH", vec!["1", "(UID", "BODY.PEEK[]", "FLAGS)"]);
+        let response = handler.handle(&command, &selected()).await.unwrap();
+        assert_eq!(
+            response[0].message(),
+            format!("1 FETCH (UID 1 BODY[] {{{}}}", large.len())
+        );
+        let (literal, rest) = response[0].literal().unwrap();
+        assert_eq!(rest, " FLAGS ())");
+        let mut body = vec![];
+        literal
+            .take()
+            .unwrap()
+            .read_to_end(&mut body)
+            .await
+            .unwrap();
+        assert_eq!(body, large);
+
+        // Loading the message for another item means it is sent from memory as before.
+        let command = Command::new("a1", "FETCH", vec!["1", "(RFC822", "BODYSTRUCTURE)"]);
+        let response = handler.handle(&command, &selected()).await.unwrap();
+        assert!(response[0].literal().is_none());
+    }
+
+    #[async_std::test]
+    async fn test_cannot_fetch_if_unselected() {
+        let handler = fetch_handler().await;
+        let command = Command::new("a1", "FETCH", vec!["1", "FLAGS"]);
+        let ctx = Context::of(Some(User::new("username", "password")), None);
+
+        let mut f = Some(|_event| {});
+        f.take();
+        test_handle(
+            handler,
+            command,
+            |response| {
+                assert_eq!(response.len(), 1);
+                assert_eq!(
+                    response[0],
+                    Response::new(
+                        "a1",
+                        ResponseStatus::NO,
+                        "cannot FETCH before SELECT. Please SELECT a folder."
+                    )
+                )
+            },
+            f,
+            Some(ctx),
+        )
+        .await;
+    }
+
+    #[async_std::test]
+    async fn test_cannot_fetch_if_unauthenticated() {
+        let handler = fetch_handler().await;
+        let command = Command::new("a1", "FETCH", vec!["1", "FLAGS"]);
+        let ctx = Context::of(None, Some(PathBuf::from("/this/is/a/folder")));
+
+        let mut f = Some(|_event| {});
+        f.take();
+        test_handle(handler, command, |response| {
+            assert_eq!(response.len(), 1);
+            assert_eq!(response[0], Response::new("a1", ResponseStatus::NO, "cannot FETCH when un-authenticated. Please authenticate using LOGIN or AUTHENTICATE."))
+        }, f, Some(ctx)).await;
+    }
+
+    fn fetch_success(response: Vec<Response>) {
+        assert_eq!(
+            response,
+            vec!(
+                Response::from("* 1 FETCH (BODY[TEXT] {26}\r\nThis is a test email body.)")
+                    .unwrap(),
+                Response::new("a1", ResponseStatus::OK, "FETCH completed.")
+            )
+        );
+    }
+}
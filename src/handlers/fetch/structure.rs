@@ -0,0 +1,264 @@
+use std::time::SystemTime;
+
+use crate::index::message::Envelope;
+use crate::mime::address::Address;
+use crate::mime::header::Parameterised;
+use crate::mime::Part;
+use crate::util::UtcTime;
+
+/// Renders `value` as an IMAP string: quoted when possible, otherwise as a literal.
+pub fn string(value: &str) -> String {
+    if value.contains(['\r', '\n']) || !value.is_ascii() {
+        return format!("{{{}}}\r\n{}", value.len(), value);
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Renders an optional value as an IMAP `nstring`.
+pub fn nstring(value: Option<&str>) -> String {
+    match value {
+        Some(value) => string(value),
+        None => "NIL".to_string(),
+    }
+}
+
+fn list(values: Vec<String>) -> String {
+    match values.is_empty() {
+        true => "NIL".to_string(),
+        false => format!("({})", values.join(" ")),
+    }
+}
+
+/// Renders a time as an IMAP `date-time`, e.g. `" 7-Feb-1994 21:52:25 +0000"`.
+pub fn date_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let time = UtcTime::from(time);
+    format!(
+        "\"{:>2}-{}-{:04} {:02}:{:02}:{:02} +0000\"",
+        time.day,
+        MONTHS[time.month as usize - 1],
+        time.year,
+        time.hour,
+        time.minute,
+        time.second
+    )
+}
+
+/// Renders the BODYSTRUCTURE of a part (RFC 9051 section 7.5.2). Without `extensible`, the
+/// result is the shorter BODY form which omits the extension data.
+pub fn body_structure(part: &Part, extensible: bool) -> String {
+    if part.is_multipart() {
+        return multipart(part, extensible);
+    }
+    let content_type = &part.content_type;
+    let mut fields = vec![
+        string(&content_type.kind.to_ascii_uppercase()),
+        string(&content_type.subtype.to_ascii_uppercase()),
+        parameters(&content_type.parameters),
+        nstring(part.headers().get("Content-ID").as_deref()),
+        nstring(part.headers().get("Content-Description").as_deref()),
+        string(&part.encoding().to_ascii_uppercase()),
+        part.body().len().to_string(),
+    ];
+    if let Some(message) = &part.message {
+        fields.push(envelope(&Envelope::parse(message.raw())));
+        fields.push(body_structure(message, extensible));
+        fields.push(part.lines().to_string());
+    } else if content_type.kind == "text" {
+        fields.push(part.lines().to_string());
+    }
+    if extensible {
+        fields.push(nstring(part.headers().get("Content-MD5").as_deref()));
+        fields.extend(extension(part));
+    }
+    format!("({})", fields.join(" "))
+}
+
+fn multipart(part: &Part, extensible: bool) -> String {
+    let children: String = part
+        .children
+        .iter()
+        .map(|child| body_structure(child, extensible))
+        .collect();
+    // A multipart with no parts cannot be represented, so describe it as an empty text part.
+    let children = match children.is_empty() {
+        true => "(\"TEXT\" \"PLAIN\" NIL NIL NIL \"7BIT\" 0 0)".to_string(),
+        false => children,
+    };
+    let mut fields = vec![
+        children,
+        string(&part.content_type.subtype.to_ascii_uppercase()),
+    ];
+    if extensible {
+        fields.push(parameters(&part.content_type.parameters));
+        fields.extend(extension(part));
+    }
+    format!("({})", fields.join(" "))
+}
+
+/// The disposition, language and location fields shared by every extensible body.
+fn extension(part: &Part) -> Vec<String> {
+    let disposition = match part.disposition() {
+        Some(Parameterised { value, parameters }) => format!(
+            "({} {})",
+            string(&value.to_ascii_uppercase()),
+            self::parameters(&parameters)
+        ),
+        None => "NIL".to_string(),
+    };
+    let languages: Vec<String> = part
+        .headers()
+        .get("Content-Language")
+        .map(|languages| {
+            languages
+                .split(',')
+                .map(str::trim)
+                .filter(|language| !language.is_empty())
+                .map(string)
+                .collect()
+        })
+        .unwrap_or_default();
+    let language = match languages.len() {
+        1 => languages[0].clone(),
+        _ => list(languages),
+    };
+    vec![
+        disposition,
+        language,
+        nstring(part.headers().get("Content-Location").as_deref()),
+    ]
+}
+
+fn parameters(parameters: &[(String, String)]) -> String {
+    list(
+        parameters
+            .iter()
+            .map(|(name, value)| {
+                format!("{} {}", string(&name.to_ascii_uppercase()), string(value))
+            })
+            .collect(),
+    )
+}
+
+/// Renders an ENVELOPE structure (RFC 9051 section 7.5.2).
+pub fn envelope(envelope: &Envelope) -> String {
+    let fields = [
+        nstring(envelope.date.as_deref()),
+        nstring(envelope.subject.as_deref()),
+        addresses(&envelope.from),
+        addresses(&envelope.sender),
+        addresses(&envelope.reply_to),
+        addresses(&envelope.to),
+        addresses(&envelope.cc),
+        addresses(&envelope.bcc),
+        nstring(envelope.in_reply_to.as_deref()),
+        nstring(envelope.message_id.as_deref()),
+    ];
+    format!("({})", fields.join(" "))
+}
+
+/// Renders an address list. Unlike other lists, addresses are not separated by spaces.
+fn addresses(addresses: &[Address]) -> String {
+    if addresses.is_empty() {
+        return "NIL".to_string();
+    }
+    let addresses: String = addresses
+        .iter()
+        .map(|address| {
+            format!(
+                "({} {} {} {})",
+                nstring(address.name.as_deref()),
+                nstring(address.route.as_deref()),
+                nstring(address.mailbox.as_deref()),
+                nstring(address.host.as_deref())
+            )
+        })
+        .collect();
+    format!("({})", addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{body_structure, date_time, envelope, string};
+    use crate::index::message::Envelope;
+    use crate::mime::Part;
+
+    const MESSAGE: &[u8] = b"From: Me <me@example.com>\r\n\
+Content-Type: multipart/mixed; boundary=outer\r\n\
+\r\n\
+--outer\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+Content-Language: en, fr\r\n\
+\r\n\
+hello\r\n\
+world\r\n\
+--outer\r\n\
+Content-Type: message/rfc822\r\n\
+\r\n\
+Subject: inner\r\n\
+From: you@example.com\r\n\
+\r\n\
+hi\r\n\
+--outer\r\n\
+Content-Type: application/pdf; name=\"a.pdf\"\r\n\
+Content-Transfer-Encoding: base64\r\n\
+Content-Disposition: attachment; filename=\"a.pdf\"\r\n\
+Content-MD5: Q2hlY2sgSW50ZWdyaXR5IQ==\r\n\
+\r\n\
+JVBERi0=\r\n\
+--outer--\r\n";
+
+    #[test]
+    fn test_body_structure() {
+        let message = Part::parse(MESSAGE);
+        assert_eq!(
+            body_structure(&message, true),
+            "((\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"7BIT\" 12 2 NIL NIL (\"en\" \"fr\") NIL)\
+(\"MESSAGE\" \"RFC822\" NIL NIL NIL \"7BIT\" 43 \
+(NIL \"inner\" ((NIL NIL \"you\" \"example.com\")) ((NIL NIL \"you\" \"example.com\")) ((NIL NIL \"you\" \"example.com\")) NIL NIL NIL NIL NIL) \
+(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"us-ascii\") NIL NIL \"7BIT\" 2 1 NIL NIL NIL NIL) 4 NIL NIL NIL NIL)\
+(\"APPLICATION\" \"PDF\" (\"NAME\" \"a.pdf\") NIL NIL \"BASE64\" 8 \"Q2hlY2sgSW50ZWdyaXR5IQ==\" \
+(\"ATTACHMENT\" (\"FILENAME\" \"a.pdf\")) NIL NIL) \
+\"MIXED\" (\"BOUNDARY\" \"outer\") NIL NIL NIL)"
+        );
+        assert_eq!(
+            body_structure(message.part(&[1]).unwrap(), false),
+            "(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"7BIT\" 12 2)"
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(string("a \"b\" \\"), "\"a \\\"b\\\" \\\\\"");
+        assert_eq!(string("café"), "{5}\r\ncafé");
+    }
+
+    #[test]
+    fn test_envelope() {
+        let message = b"Date: Mon, 7 Feb 1994 21:52:25 -0800\r\n\
+From: =?ISO-8859-1?Q?Andr=E9?= <andre@example.com>\r\n\
+To: undisclosed-recipients:;\r\n\
+Subject: =?utf-8?B?Y2Fmw6k=?=\r\n\
+\r\n";
+        assert_eq!(
+            envelope(&Envelope::parse(message)),
+            "(\"Mon, 7 Feb 1994 21:52:25 -0800\" {5}\r\ncafé \
+(({6}\r\nAndré NIL \"andre\" \"example.com\")) \
+(({6}\r\nAndré NIL \"andre\" \"example.com\")) \
+(({6}\r\nAndré NIL \"andre\" \"example.com\")) \
+((NIL NIL \"undisclosed-recipients\" NIL)(NIL NIL NIL NIL)) NIL NIL NIL NIL)"
+        );
+    }
+
+    #[test]
+    fn test_date_time() {
+        assert_eq!(
+            date_time(UNIX_EPOCH + Duration::from_secs(760657945)),
+            "\" 7-Feb-1994 21:52:25 +0000\""
+        );
+    }
+}
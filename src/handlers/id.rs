@@ -0,0 +1,110 @@
+// From RFC 2971 (https://www.rfc-editor.org/rfc/rfc2971.html#section-3.3):
+//  C: a023 ID ("name" "sodr" "version" "19.34" "vendor" "Pink Floyd Music Limited")
+//  S: * ID NIL
+//  S: a023 OK ID completed
+//
+// The client's fields are only logged; the server answers with the fields of its
+// `Greeting`. ID is allowed in every state, before logging in included.
+
+use futures::{SinkExt, StreamExt};
+use tracing::debug;
+
+use crate::connection::{Context, Request};
+use crate::greeting::Greeting;
+use crate::handlers::HandleCommand;
+use crate::server::{Command, ParseError, Response, ResponseStatus};
+use crate::util::{Receiver, Result};
+
+use super::Handle;
+
+pub struct IdHandler {
+    greeting: Greeting,
+}
+
+impl IdHandler {
+    #[must_use]
+    pub fn new(greeting: Greeting) -> Self {
+        Self { greeting }
+    }
+}
+
+#[async_trait::async_trait]
+impl HandleCommand for IdHandler {
+    fn name<'a>(&self) -> &'a str {
+        "ID"
+    }
+    async fn validate<'a>(&self, command: &'a Command) -> Result<()> {
+        if command.command() != self.name() {
+            return Ok(());
+        }
+        if command.num_args() < 1 {
+            return Err(Box::new(ParseError {}));
+        }
+        Ok(())
+    }
+    async fn handle<'a>(&self, command: &'a Command, _: &'a Context) -> Result<Vec<Response>> {
+        let fields: Vec<String> = (0..command.num_args()).map(|i| command.arg(i)).collect();
+        debug!("Client identified itself as {}", fields.join(" "));
+        Ok(vec![
+            self.greeting.id_response(),
+            Response::new(&command.tag(), ResponseStatus::OK, "ID completed."),
+        ])
+    }
+}
+
+#[async_trait::async_trait]
+impl Handle for IdHandler {
+    fn command<'a>(&self) -> &'a str {
+        "ID"
+    }
+    async fn start<'a>(&'a mut self, mut requests: Receiver<Request>) -> Result<()> {
+        while let Some(mut request) = requests.next().await {
+            if self.validate(&request.command).await.is_err() {
+                request
+                    .responder
+                    .send(vec![Response::new(
+                        &request.command.tag(),
+                        ResponseStatus::BAD,
+                        "insufficient arguments",
+                    )])
+                    .await?;
+                continue;
+            }
+            let response = self.handle(&request.command, &request.context).await?;
+            request.responder.send(response).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdHandler;
+    use crate::connection::Event;
+    use crate::greeting::Greeting;
+    use crate::handlers::tests::test_handle;
+    use crate::server::{Command, Response, ResponseStatus};
+
+    #[async_std::test]
+    async fn test_id() {
+        let greeting = Greeting::new()
+            .with_id_field("version", "")
+            .with_id_field("vendor", "Example");
+        test_handle(
+            IdHandler::new(greeting),
+            Command::new("a1", "ID", vec!["(\"name\"", "\"sodr\")"]),
+            |responses| {
+                assert_eq!(
+                    responses,
+                    vec![
+                        Response::untagged("ID (\"name\" \"TreasurMAP\" \"vendor\" \"Example\")"),
+                        Response::new("a1", ResponseStatus::OK, "ID completed."),
+                    ]
+                )
+            },
+            None::<fn(Event)>,
+            None,
+        )
+        .await;
+    }
+}
@@ -1,10 +1,15 @@
 use std::sync::Arc;
+use std::time::Duration;
 
+use async_std::task::sleep;
 use futures::{SinkExt, StreamExt};
+use tracing::Instrument;
 
-use crate::auth::{Authenticate, BasicAuth};
-use crate::connection::{Event, Request};
+use crate::auth::{Authenticate, BasicAuth, User};
+use crate::connection::{Context, Event, Request};
+use crate::handlers::capability::{code, Advertised};
 use crate::handlers::HandleCommand;
+use crate::runtime::spawn;
 use crate::server::{Command, ParseError, Response, ResponseStatus};
 use crate::util::{Receiver, Result};
 
@@ -12,6 +17,8 @@ use super::Handle;
 
 pub struct LoginHandler {
     authenticator: Arc<Box<dyn Authenticate>>,
+    require_tls: bool,
+    capabilities: Option<Advertised>,
 }
 #[async_trait::async_trait]
 impl HandleCommand for LoginHandler {
@@ -20,39 +27,116 @@ impl HandleCommand for LoginHandler {
     }
     async fn validate<'a>(&self, command: &'a Command) -> Result<()> {
         if command.command() != self.name() {
-            ()
+            return Ok(());
         }
         if command.num_args() < 2 {
             return Err(Box::new(ParseError {}));
         }
         Ok(())
     }
-    async fn handle<'a>(&self, command: &'a Command) -> Result<Vec<Response>> {
-        // TODO: implement user database lookup
-        // TODO: add user to some state management
-        let mut _user = command.arg(0);
-        let _password = &command.arg(1);
-        _user = _user.replace("\"", "");
-        Ok(vec![Response::new(
-            &command.tag(),
-            ResponseStatus::OK,
-            "LOGIN completed.",
-        )])
+    async fn handle<'a>(
+        &self,
+        command: &'a Command,
+        context: &'a Context,
+    ) -> Result<Vec<Response>> {
+        if self.is_disabled(context) {
+            return Ok(vec![disabled(&command.tag())]);
+        }
+        match self.login(command, context).await {
+            Ok(user) => Ok(vec![self.welcome(&command.tag(), &user).await]),
+            Err(delay) => {
+                sleep(delay).await;
+                Ok(vec![failed(&command.tag())])
+            }
+        }
     }
 }
 impl LoginHandler {
     pub fn new(authenticator: Arc<Box<dyn Authenticate>>) -> Self {
-        LoginHandler { authenticator }
+        LoginHandler {
+            authenticator,
+            require_tls: false,
+            capabilities: None,
+        }
     }
+    /// Refuses LOGIN until the connection is encrypted.
+    pub fn with_require_tls(mut self, require_tls: bool) -> Self {
+        self.require_tls = require_tls;
+        self
+    }
+    /// Lists the capabilities of the server in the OK of a successful login.
+    pub fn with_capabilities(mut self, capabilities: Advertised) -> Self {
+        self.capabilities = Some(capabilities);
+        self
+    }
+    fn is_disabled(&self, context: &Context) -> bool {
+        self.require_tls && !context.is_secure()
+    }
+
+    /// Authenticates the user named by a LOGIN, returning how long to wait before answering
+    /// if they could not be, whether because of their password or because they are locked out.
+    async fn login(
+        &self,
+        command: &Command,
+        context: &Context,
+    ) -> std::result::Result<User, Duration> {
+        let user = command.arg(0).replace('"', "");
+        let password = command.arg(1);
+        self.authenticator
+            .login(Box::new(BasicAuth::from(&user, &password)), context.peer())
+            .await
+    }
+
+    async fn welcome(&self, tag: &str, user: &User) -> Response {
+        match &self.capabilities {
+            Some(capabilities) => {
+                welcome(tag, user).with_code(&code(&capabilities.authenticated().await))
+            }
+            None => welcome(tag, user),
+        }
+    }
+}
+
+fn welcome(tag: &str, user: &User) -> Response {
+    let message = format!("LOGIN completed. Welcome {}.", user.name());
+    Response::new(tag, ResponseStatus::OK, &message)
 }
+
+fn failed(tag: &str) -> Response {
+    Response::new(
+        tag,
+        ResponseStatus::NO,
+        "[AUTHENTICATIONFAILED] LOGIN failed.",
+    )
+}
+
+fn disabled(tag: &str) -> Response {
+    Response::new(
+        tag,
+        ResponseStatus::NO,
+        "[PRIVACYREQUIRED] LOGIN is disabled until TLS is negotiated.",
+    )
+}
+
+/// Answers a failed login after `delay`, without holding up logins on other connections.
+fn reject(request: Request, delay: Duration) {
+    let tag = request.command.tag();
+    let mut responder = request.responder;
+    spawn(async move {
+        sleep(delay).await;
+        // The client may have gone away while we waited, which is fine.
+        let _ = responder.send(vec![failed(&tag)]).await;
+    });
+}
+
 #[async_trait::async_trait]
-impl<'a> Handle for LoginHandler {
+impl Handle for LoginHandler {
     fn command<'b>(&self) -> &'b str {
         "LOGIN"
     }
     async fn start<'b>(&'b mut self, mut requests: Receiver<Request>) -> Result<()> {
         while let Some(mut request) = requests.next().await {
-            if let Err(..) = self.validate(&request.command).await {
+            if self.validate(&request.command).await.is_err() {
                 request
                     .responder
                     .send(vec![Response::new(
@@ -63,37 +147,22 @@ impl<'a> Handle for LoginHandler {
                     .await?;
                 continue;
             }
-            let mut user = request.command.arg(0);
-            let password = &request.command.arg(1);
-            user = user.replace("\"", "");
-            // TODO: handle password hashing error
-            let response = self
-                .authenticator
-                .authenticate(Box::new(BasicAuth::from(&user, &password)))
-                .await;
-            match response {
-                Ok(result) => {
-                    let message = format!("LOGIN completed. Welcome {}.", &result.name());
-                    request.events.send(Event::AUTH(result)).await?;
-                    request
-                        .responder
-                        .send(vec![Response::new(
-                            &request.command.tag(),
-                            ResponseStatus::OK,
-                            &message,
-                        )])
-                        .await?;
-                }
-                Err(..) => {
-                    request
-                        .responder
-                        .send(vec![Response::new(
-                            &request.command.tag(),
-                            ResponseStatus::BAD,
-                            "LOGIN failed.",
-                        )])
-                        .await?;
+            if self.is_disabled(&request.context) {
+                let response = disabled(&request.command.tag());
+                request.responder.send(vec![response]).await?;
+                continue;
+            }
+            let login = self
+                .login(&request.command, &request.context)
+                .instrument(request.span.clone());
+            let login = request.timings.scope(login).await;
+            match login {
+                Ok(user) => {
+                    let response = self.welcome(&request.command.tag(), &user).await;
+                    request.events.send(Event::AUTH(user)).await?;
+                    request.responder.send(vec![response]).await?;
                 }
+                Err(delay) => reject(request, delay),
             }
         }
         Ok(())
@@ -102,13 +171,24 @@ impl<'a> Handle for LoginHandler {
 
 #[cfg(test)]
 mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
     use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use async_std::task::spawn;
+    use futures::channel::mpsc::unbounded;
+    use futures::{SinkExt, StreamExt};
 
     use super::LoginHandler;
+    use crate::access::{AccessControl, AccessPolicy, Rules};
     use crate::auth::error::UserDoesNotExist;
+    use crate::auth::throttle::{Throttle, ThrottledAuthenticator};
     use crate::auth::{Authenticate, AuthenticationPrincipal, User};
-    use crate::connection::Event;
+    use crate::connection::{Context, Event, Request};
+    use crate::handlers::capability::Advertised;
     use crate::handlers::tests::test_handle;
+    use crate::handlers::{Handle, HandleCommand};
+    use crate::plugin::Capabilities;
     use crate::server::{Command, Response, ResponseStatus};
     use crate::util::Result;
 
@@ -118,7 +198,7 @@ mod tests {
     #[async_trait::async_trait]
     impl Authenticate for TestAuthenticator {
         async fn authenticate(&self, user: Box<dyn AuthenticationPrincipal>) -> Result<User> {
-            if user.principal() == EMAIL {
+            if user.principal() == EMAIL && user.password().as_deref() == Some("password") {
                 return Ok(User::new(&user.principal(), "password"));
             }
             return Err(UserDoesNotExist::new(&user.principal()));
@@ -166,7 +246,7 @@ mod tests {
     }
 
     fn login_success(response: Vec<Response>) {
-        assert_eq!(response.len(), 1 as usize);
+        assert_eq!(response.len(), 1);
         let reply = &response[0];
         assert_eq!(
             reply,
@@ -178,6 +258,24 @@ mod tests {
         );
     }
 
+    #[async_std::test]
+    async fn test_login_lists_capabilities() {
+        let authenticator: Arc<Box<dyn Authenticate>> = Arc::new(Box::new(TestAuthenticator {}));
+        let capabilities = Capabilities::new(vec!["IMAP4rev2", "AUTH=ANONYMOUS", "ID"]);
+        let handler =
+            LoginHandler::new(authenticator).with_capabilities(Advertised::new(capabilities));
+        let command = Command::new("a1", "LOGIN", vec![EMAIL, "password"]);
+        let responses = handler.handle(&command, &Context::default()).await.unwrap();
+        assert_eq!(
+            responses,
+            vec![Response::new(
+                "a1",
+                ResponseStatus::OK,
+                "[CAPABILITY IMAP4rev2 ID] LOGIN completed. Welcome my@email.com."
+            )]
+        );
+    }
+
     #[async_std::test]
     async fn test_login_bad_user() {
         let login_command = Command::new("a1", "LOGIN", vec!["not.a.user@domain.com", "password"]);
@@ -190,7 +288,7 @@ mod tests {
         test_login(
             login_command,
             |response| {
-                assert_eq!(response.len(), 1 as usize);
+                assert_eq!(response.len(), 1);
                 let reply = &response[0];
                 assert_eq!(
                     reply,
@@ -203,11 +301,108 @@ mod tests {
     }
 
     fn login_failed(response: Vec<Response>) {
-        assert_eq!(response.len(), 1 as usize);
+        assert_eq!(response.len(), 1);
         let reply = &response[0];
         assert_eq!(
             reply,
-            &Response::new("a1", ResponseStatus::BAD, "LOGIN failed.")
+            &Response::new(
+                "a1",
+                ResponseStatus::NO,
+                "[AUTHENTICATIONFAILED] LOGIN failed."
+            )
+        );
+    }
+
+    #[async_std::test]
+    async fn test_handle_command_authenticates() {
+        let authenticator: Arc<Box<dyn Authenticate>> = Arc::new(Box::new(TestAuthenticator {}));
+        let handler = LoginHandler::new(authenticator);
+        let context = Context::default();
+
+        let command = Command::new("a1", "LOGIN", vec![EMAIL, "wrong"]);
+        login_failed(handler.handle(&command, &context).await.unwrap());
+        let command = Command::new("a1", "LOGIN", vec![EMAIL, "password"]);
+        login_success(handler.handle(&command, &context).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_login_requires_tls() {
+        let authenticator: Arc<Box<dyn Authenticate>> = Arc::new(Box::new(TestAuthenticator {}));
+        let handler = LoginHandler::new(authenticator).with_require_tls(true);
+        let command = Command::new("a1", "LOGIN", vec![EMAIL, "password"]);
+
+        let response = handler.handle(&command, &Context::default()).await.unwrap();
+        assert_eq!(
+            response,
+            vec![Response::new(
+                "a1",
+                ResponseStatus::NO,
+                "[PRIVACYREQUIRED] LOGIN is disabled until TLS is negotiated."
+            )]
         );
+        let secure = Context::default().with_secure();
+        login_success(handler.handle(&command, &secure).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_login_restricted_to_networks() {
+        let lan_only = Rules::new().allow("10.0.0.0/8").unwrap();
+        let access = AccessControl::new(AccessPolicy::new().with_user_rules(EMAIL, lan_only));
+        let throttle = Throttle::new().with_delay(Duration::ZERO, Duration::ZERO);
+        let authenticator = ThrottledAuthenticator::new(Box::new(TestAuthenticator {}), throttle)
+            .with_access_control(access.clone());
+        let handler = LoginHandler::new(Arc::new(Box::new(authenticator)));
+        let command = Command::new("a1", "LOGIN", vec![EMAIL, "password"]);
+
+        // A refusal reads the same as a wrong password, so it does not confirm the password.
+        let outside = Context::default().with_peer(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        login_failed(handler.handle(&command, &outside).await.unwrap());
+        let lan = Context::default().with_peer(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)));
+        login_success(handler.handle(&command, &lan).await.unwrap());
+
+        access.reload(AccessPolicy::new());
+        login_success(handler.handle(&command, &outside).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_login_throttled() {
+        let throttle = Throttle::new()
+            .with_thresholds(2, 10)
+            .with_delay(Duration::from_millis(50), Duration::from_millis(80));
+        let authenticator: Arc<Box<dyn Authenticate>> = Arc::new(Box::new(
+            ThrottledAuthenticator::new(Box::new(TestAuthenticator {}), throttle),
+        ));
+        let mut handler = LoginHandler::new(authenticator);
+        let (mut requests, receiver) = unbounded();
+        let handle = spawn(async move { handler.start(receiver).await });
+        let (responder, mut responses) = unbounded();
+        let (events, _events) = unbounded();
+        let context = Context::default().with_peer(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        let login = |password: &str| {
+            let command = Command::new("a1", "LOGIN", vec![EMAIL, password]);
+            requests
+                .unbounded_send(Request {
+                    command,
+                    responder: responder.clone(),
+                    events: events.clone(),
+                    context: context.clone(),
+                    span: tracing::Span::none(),
+                    continuation: Default::default(),
+                    timings: Default::default(),
+                })
+                .unwrap();
+        };
+        // Failures are answered after 50ms, then 80ms, and the account is then locked out so
+        // even the right password fails.
+        for (password, delay) in [("wrong", 50), ("wrong", 80), ("password", 80)] {
+            let start = Instant::now();
+            login(password);
+            let response = responses.next().await.unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(delay));
+            assert_eq!(response[0].status(), Some(ResponseStatus::NO));
+        }
+        requests.close().await.unwrap();
+        handle.await.unwrap();
     }
 }
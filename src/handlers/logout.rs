@@ -1,6 +1,6 @@
 use futures::{SinkExt, StreamExt};
 
-use crate::connection::{Request, Event};
+use crate::connection::{Context, Request, Event};
 use crate::handlers::HandleCommand;
 use crate::server::{Command, Response, ResponseStatus};
 use crate::util::{Receiver, Result};
@@ -15,16 +15,20 @@ impl HandleCommand for LogoutHandler {
     }
     async fn validate<'a>(&self, command: &'a Command) -> Result<()> {
         if command.command() != self.name() {
-            ()
+            return Ok(());
         }
         Ok(())
     }
-    async fn handle<'a>(&self, command: &'a Command) -> Result<Vec<Response>> {
-        Ok(vec![Response::new(
-            &command.tag(),
-            ResponseStatus::OK,
-            "LOGOUT completed. Goodbye!",
-        )])
+    async fn handle<'a>(&self, command: &'a Command, _: &'a Context) -> Result<Vec<Response>> {
+        // RFC 9051 requires the untagged BYE before the tagged OK.
+        Ok(vec![
+            Response::untagged("BYE IMAP4rev2 server logging out"),
+            Response::new(
+                &command.tag(),
+                ResponseStatus::OK,
+                "LOGOUT completed. Goodbye!",
+            ),
+        ])
     }
 }
 #[async_trait::async_trait]
@@ -34,7 +38,7 @@ impl Handle for LogoutHandler {
     }
     async fn start<'b>(&'b mut self, mut requests: Receiver<Request>) -> Result<()> {
         while let Some(mut request) = requests.next().await {
-            if let Err(..) = self.validate(&request.command).await {
+            if self.validate(&request.command).await.is_err() {
                 request
                     .responder
                     .send(vec![Response::new(
@@ -45,15 +49,9 @@ impl Handle for LogoutHandler {
                     .await?;
                 continue;
             }
+            let response = self.handle(&request.command, &request.context).await?;
             request.events.send(Event::UNAUTH()).await?;
-            request
-                .responder
-                .send(vec![Response::new(
-                    &request.command.tag(),
-                    ResponseStatus::OK,
-                    "LOGOUT completed. Goodbye!",
-                )])
-                .await?;
+            request.responder.send(response).await?;
         }
         Ok(())
     }
@@ -62,7 +60,7 @@ impl Handle for LogoutHandler {
 #[cfg(test)]
 mod tests {
     use super::LogoutHandler;
-    use crate::connection::Event;
+    use crate::connection::{Context, Event};
     use crate::handlers::tests::test_handle;
     use crate::handlers::HandleCommand;
     use crate::server::{Command, Response, ResponseStatus};
@@ -72,8 +70,8 @@ mod tests {
         let logout_handler = LogoutHandler {};
         let logout_command = Command::new("a1", "LOGOUT", vec![]);
         let valid = logout_handler.validate(&logout_command).await;
-        assert_eq!(valid.is_ok(), true);
-        let response = logout_handler.handle(&logout_command).await;
+        assert!(valid.is_ok());
+        let response = logout_handler.handle(&logout_command, &Context::default()).await;
         logout_success(response.unwrap());
     }
 
@@ -92,15 +90,16 @@ mod tests {
     }
 
     fn logout_success(response: Vec<Response>) {
-        assert_eq!(response.len(), 1 as usize);
-        let reply = &response[0];
         assert_eq!(
-            reply,
-            &Response::new(
-                "a1",
-                ResponseStatus::OK,
-                "LOGOUT completed. Goodbye!"
-            )
+            response,
+            vec![
+                Response::untagged("BYE IMAP4rev2 server logging out"),
+                Response::new(
+                    "a1",
+                    ResponseStatus::OK,
+                    "LOGOUT completed. Goodbye!"
+                ),
+            ]
         );
     }
 }
@@ -1,16 +1,161 @@
+pub mod append;
+pub mod authenticate;
+pub mod capability;
+pub mod copy;
+pub mod expunge;
 pub mod fetch;
+pub mod id;
 pub mod login;
 pub mod logout;
+pub mod search;
 pub mod select;
+pub mod sequence;
+pub mod store;
 
+use std::error::Error;
+use std::fmt::Display;
+use std::future::Future;
 use std::sync::Arc;
 
-use async_lock::RwLock;
+use async_lock::{RwLock, Semaphore};
+use futures::{SinkExt, StreamExt};
+use tracing::{error, Instrument};
 
-use crate::connection::Request;
-use crate::server::{Command, Response, ResponseStatus};
+use crate::connection::{Context, Request};
+use crate::index::MailboxError;
+use crate::runtime::spawn;
+use crate::server::{Command, ParseError, Response, ResponseStatus};
 use crate::util::{Receiver, Result};
 
+/// Builds the tagged NO response for a failed mailbox operation, carrying the matching response
+/// code (e.g. `[NONEXISTENT]`) when there is one.
+pub fn mailbox_error(tag: &str, error: &MailboxError) -> Response {
+    let message = match error.code() {
+        Some(code) => format!("[{}] {}", code, error),
+        None => error.to_string(),
+    };
+    Response::new(tag, ResponseStatus::NO, &message)
+}
+
+/// Builds the tagged NO response for a failed operation on the destination of an APPEND or
+/// COPY. One which does not exist is answered with `[TRYCREATE]`, so the client knows it may
+/// create it and try again.
+pub fn destination_error(tag: &str, error: &MailboxError) -> Response {
+    match error {
+        MailboxError::DoesNotExist(..) => {
+            Response::new(tag, ResponseStatus::NO, &format!("[TRYCREATE] {}", error))
+        }
+        _ => mailbox_error(tag, error),
+    }
+}
+
+/// Builds the tagged BAD response to a command no handler is registered for.
+pub fn unknown_command(command: &Command) -> Response {
+    let message = format!("Command '{}' unknown", command.command());
+    Response::new(&command.tag(), ResponseStatus::BAD, &message)
+}
+
+/// Builds the tagged NO response refusing a command which would change the selected
+/// mailbox when it was opened read-only, or returns `None` if it may be changed.
+pub fn read_only(tag: &str, context: &Context) -> Option<Response> {
+    let read_only = context.is_selected_read_only() || context.is_read_only();
+    read_only.then(|| {
+        Response::new(
+            tag,
+            ResponseStatus::NO,
+            "[READ-ONLY] The mailbox is open read-only.",
+        )
+    })
+}
+
+/// Why a `HandleCommand` could not answer a command. Returned from `handle`, it decides how
+/// a `DelegatingCommandHandler` answers instead; any other error is treated as `Server`.
+#[derive(Debug)]
+pub enum HandlerError {
+    /// The command was malformed or not allowed, and is answered with BAD and the reason.
+    Client(String),
+    /// The server failed to carry out the command. It is logged and answered with
+    /// `NO [SERVERBUG]`, so the reason is never shown to the client.
+    Server(String),
+    /// The handler declined the command, which is passed to the next one named after it.
+    NotHandled,
+}
+impl Error for HandlerError {}
+impl Display for HandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandlerError::Client(reason) | HandlerError::Server(reason) => write!(f, "{}", reason),
+            HandlerError::NotHandled => write!(f, "Command was not handled"),
+        }
+    }
+}
+
+/// The response to `command` when its handler failed with `e`.
+fn failure(command: &Command, e: &(dyn Error + Send + Sync + 'static)) -> Response {
+    let tag = command.tag();
+    if let Some(HandlerError::Client(reason)) = e.downcast_ref::<HandlerError>() {
+        return Response::new(&tag, ResponseStatus::BAD, reason);
+    }
+    if e.is::<ParseError>() {
+        return Response::new(&tag, ResponseStatus::BAD, "invalid arguments");
+    }
+    if let Some(e) = e.downcast_ref::<MailboxError>() {
+        return mailbox_error(&tag, e);
+    }
+    error!("{} {} failed: {}", tag, command.command(), e);
+    let message = format!("[SERVERBUG] {} failed.", command.command());
+    Response::new(&tag, ResponseStatus::NO, &message)
+}
+
+/// How many requests the handlers of a server work on at once unless configured otherwise.
+pub const DEFAULT_WORKERS: usize = 256;
+
+/// Runs the requests a handler takes on tasks of their own, so that a slow one, such as a
+/// SEARCH of a huge mailbox, does not hold up the same command from other connections. No
+/// more than the limit run at once across every handler sharing the `Workers`; past it, a
+/// handler waits for one to finish before taking its next request.
+///
+/// The responses of each request are still sent together, and a connection only sends
+/// commands which may run at the same time to handlers together, so each connection sees
+/// its responses in the order it would otherwise.
+#[derive(Clone)]
+pub struct Workers {
+    permits: Arc<Semaphore>,
+}
+
+impl Default for Workers {
+    fn default() -> Self {
+        Self::new(DEFAULT_WORKERS)
+    }
+}
+
+impl Workers {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(limit.max(1))),
+        }
+    }
+
+    /// Runs `request` on its own task once fewer than the limit are running.
+    pub async fn spawn<F>(&self, request: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let permit = self.permits.acquire_arc().await;
+        spawn(async move {
+            request.await;
+            drop(permit);
+        });
+    }
+
+    /// Runs `work` once fewer than the limit are running, for a request which also waits on
+    /// the client and so should not hold a worker meanwhile.
+    pub async fn run<F: Future>(&self, work: F) -> F::Output {
+        let _permit = self.permits.acquire_arc().await;
+        work.await
+    }
+}
+
 #[async_trait::async_trait]
 pub trait Handle: Send + Sync {
     fn command<'a>(&self) -> &'a str;
@@ -21,11 +166,15 @@ pub trait Handle: Send + Sync {
 pub trait HandleCommand {
     fn name<'a>(&self) -> &'a str;
     async fn validate<'a>(&self, command: &'a Command) -> Result<()>;
-    async fn handle<'a>(&self, command: &'a Command) -> Result<Vec<Response>>;
+    async fn handle<'a>(&self, command: &'a Command, context: &'a Context) -> Result<Vec<Response>>;
 }
 
+/// Runs several `HandleCommand`s as one handler. A server dispatches each command any of
+/// them is named after to it.
+#[derive(Clone)]
 pub struct DelegatingCommandHandler {
     handlers: Arc<RwLock<Vec<Box<dyn HandleCommand + Send + Sync>>>>,
+    workers: Workers,
 }
 
 #[async_trait::async_trait]
@@ -39,29 +188,34 @@ impl HandleCommand for DelegatingCommandHandler {
             if handler.name() != command.command() {
                 continue;
             }
-            match handler.validate(&command).await {
+            match handler.validate(command).await {
                 Ok(..) => continue,
                 Err(e) => return Err(e),
             }
         }
         Ok(())
     }
-    async fn handle<'a>(&self, command: &'a Command) -> Result<Vec<Response>> {
+    async fn handle<'a>(&self, command: &'a Command, context: &'a Context) -> Result<Vec<Response>> {
         let read_lock = &*self.handlers.read().await;
         for handler in read_lock {
             if handler.name() != command.command() {
                 continue;
             }
-            match handler.handle(&command).await {
+            match handler.handle(command, context).await {
                 Ok(response) => return Ok(response),
-                Err(..) => continue,
+                Err(e) => match e.downcast_ref::<HandlerError>() {
+                    Some(HandlerError::NotHandled) => continue,
+                    _ => return Ok(vec![failure(command, &*e)]),
+                },
             }
         }
-        Ok(vec![Response::new(
-            &command.tag(),
-            ResponseStatus::NO,
-            "Command unknown",
-        )])
+        Ok(vec![unknown_command(command)])
+    }
+}
+
+impl Default for DelegatingCommandHandler {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -69,11 +223,73 @@ impl DelegatingCommandHandler {
     pub fn new() -> DelegatingCommandHandler {
         DelegatingCommandHandler {
             handlers: Arc::new(RwLock::new(Vec::new())),
+            workers: Workers::default(),
         }
     }
+    /// Runs commands on `workers` rather than on workers of its own.
+    pub fn with_workers(mut self, workers: Workers) -> Self {
+        self.workers = workers;
+        self
+    }
     pub async fn register_command<T: HandleCommand + Send + Sync + 'static>(&self, handler: T) {
+        self.register_boxed(Box::new(handler)).await
+    }
+    pub async fn register_boxed(&self, handler: Box<dyn HandleCommand + Send + Sync>) {
         let write_lock = &mut *self.handlers.write().await;
-        write_lock.push(Box::new(handler));
+        write_lock.push(handler);
+    }
+    /// The names of the commands registered.
+    pub async fn commands(&self) -> Vec<String> {
+        let mut commands: Vec<String> = self
+            .handlers
+            .read()
+            .await
+            .iter()
+            .map(|handler| handler.name().to_string())
+            .collect();
+        commands.sort();
+        commands.dedup();
+        commands
+    }
+}
+
+#[async_trait::async_trait]
+impl Handle for DelegatingCommandHandler {
+    fn command<'a>(&self) -> &'a str {
+        ""
+    }
+    async fn start<'a>(&'a mut self, mut requests: Receiver<Request>) -> Result<()> {
+        while let Some(mut request) = requests.next().await {
+            if self.validate(&request.command).await.is_err() {
+                request
+                    .responder
+                    .send(vec![Response::new(
+                        &request.command.tag(),
+                        ResponseStatus::BAD,
+                        "insufficient arguments",
+                    )])
+                    .await?;
+                continue;
+            }
+            let handler = self.clone();
+            let (span, timings) = (request.span.clone(), request.timings.clone());
+            self.workers
+                .spawn(
+                    timings.scope(
+                        async move {
+                            let responses =
+                                match handler.handle(&request.command, &request.context).await {
+                                    Ok(responses) => responses,
+                                    Err(e) => vec![failure(&request.command, &*e)],
+                                };
+                            let _ = request.responder.send(responses).await;
+                        }
+                        .instrument(span),
+                    ),
+                )
+                .await;
+        }
+        Ok(())
     }
 }
 
@@ -87,10 +303,11 @@ pub mod tests {
 
     use crate::{
         connection::{Context, Event, Request},
-        server::{Command, Response},
+        index::MailboxError,
+        server::{Command, Response, ResponseStatus},
     };
 
-    use super::Handle;
+    use super::{DelegatingCommandHandler, Handle, HandleCommand, HandlerError, Workers};
 
     pub async fn test_handle<
         T: Handle + Send + Sync + 'static,
@@ -121,6 +338,9 @@ pub mod tests {
             responder,
             context: state.unwrap_or_default(),
             events,
+            span: tracing::Span::none(),
+            continuation: Default::default(),
+            timings: Default::default(),
         };
         requests.send(login_request).await.unwrap();
         if let Some(response) = responses.next().await {
@@ -132,7 +352,7 @@ pub mod tests {
                 None => panic!("At least one event should have been sent"),
             },
             None => {
-                if let Some(_) = event_handler.next().await {
+                if event_handler.next().await.is_some() {
                     panic!("No event should have been sent.")
                 }
             }
@@ -140,4 +360,112 @@ pub mod tests {
         drop(requests);
         handle.await.unwrap();
     }
+
+    /// A NOOP handler answering with whatever its function returns.
+    struct Failing(fn() -> crate::util::Result<Vec<Response>>);
+
+    #[async_trait::async_trait]
+    impl HandleCommand for Failing {
+        fn name<'a>(&self) -> &'a str {
+            "NOOP"
+        }
+        async fn validate<'a>(&self, _: &'a Command) -> crate::util::Result<()> {
+            Ok(())
+        }
+        async fn handle<'a>(
+            &self,
+            _: &'a Command,
+            _: &'a Context,
+        ) -> crate::util::Result<Vec<Response>> {
+            (self.0)()
+        }
+    }
+
+    async fn answer(handler: Failing, command: &str) -> Vec<Response> {
+        let commands = DelegatingCommandHandler::new();
+        commands.register_command(handler).await;
+        commands
+            .register_command(Failing(|| {
+                Ok(vec![Response::new(
+                    "a1",
+                    ResponseStatus::OK,
+                    "NOOP completed",
+                )])
+            }))
+            .await;
+        let command = Command::new("a1", command, vec![]);
+        commands
+            .handle(&command, &Context::default())
+            .await
+            .unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_declined_commands_fall_through() {
+        let response = answer(Failing(|| Err(Box::new(HandlerError::NotHandled))), "NOOP").await;
+        assert_eq!(
+            response,
+            vec![Response::new("a1", ResponseStatus::OK, "NOOP completed")]
+        );
+        let response = answer(Failing(|| Err(Box::new(HandlerError::NotHandled))), "NOPE").await;
+        assert_eq!(
+            response,
+            vec![Response::new("a1", ResponseStatus::BAD, "Command 'NOPE' unknown")]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_failures_are_answered_deliberately() {
+        let client = Failing(|| Err(Box::new(HandlerError::Client("no such range".to_string()))));
+        assert_eq!(
+            answer(client, "NOOP").await,
+            vec![Response::new("a1", ResponseStatus::BAD, "no such range")]
+        );
+        let mailbox = Failing(|| Err(Box::new(MailboxError::DoesNotExist("INBOX".to_string()))));
+        assert_eq!(
+            answer(mailbox, "NOOP").await,
+            vec![Response::new(
+                "a1",
+                ResponseStatus::NO,
+                "[NONEXISTENT] Mailbox INBOX does not exist"
+            )]
+        );
+        let server = Failing(|| Err(Box::new(HandlerError::Server("disk full".to_string()))));
+        assert_eq!(
+            answer(server, "NOOP").await,
+            vec![Response::new(
+                "a1",
+                ResponseStatus::NO,
+                "[SERVERBUG] NOOP failed."
+            )]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_workers_limit_concurrency() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let workers = Workers::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let (done, mut finished) = unbounded();
+        for _ in 0..6 {
+            let (running, most, done) = (running.clone(), most.clone(), done.clone());
+            workers
+                .spawn(async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    async_std::task::sleep(Duration::from_millis(10)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    done.unbounded_send(()).unwrap();
+                })
+                .await;
+        }
+        for _ in 0..6 {
+            finished.next().await.unwrap();
+        }
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
 }
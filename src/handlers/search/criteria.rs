@@ -0,0 +1,374 @@
+use std::time::{Duration, SystemTime};
+
+use crate::handlers::sequence::SequenceSet;
+use crate::index::message::{MessageQuery, MessageRecord};
+use crate::mime::encoding::decode_words;
+use crate::mime::Part;
+use crate::server::ParseError;
+use crate::util::UtcTime;
+
+/// Criteria nested deeper than this, with parentheses, `NOT` or `OR`, are refused rather than
+/// parsed, so a hostile command cannot exhaust the stack.
+const MAX_DEPTH: usize = 32;
+
+/// A parsed SEARCH command: the optional `CHARSET` and the criteria, which must all match.
+#[derive(Debug, Clone)]
+pub struct Search {
+    pub charset: Option<String>,
+    pub criteria: Criterion,
+}
+
+/// A search key (RFC 9051 section 6.4.4).
+#[derive(Debug, Clone)]
+pub enum Criterion {
+    All,
+    /// Keys answered from the index record alone: flags, sizes and internal dates.
+    Record(MessageQuery),
+    Sequence(SequenceSet),
+    Uid(SequenceSet),
+    /// `HEADER`, or one of `FROM`, `TO`, `CC`, `BCC` and `SUBJECT`.
+    Header(String, String),
+    Body(String),
+    Text(String),
+    Not(Box<Criterion>),
+    Or(Box<Criterion>, Box<Criterion>),
+    And(Vec<Criterion>),
+}
+
+/// A message being matched against the criteria, with its position in the mailbox.
+pub struct Candidate<'a> {
+    pub number: u32,
+    pub record: &'a MessageRecord,
+    pub message: &'a Part<'a>,
+    /// The number of messages in the mailbox, which `*` resolves to.
+    pub count: u32,
+    /// The largest UID in the mailbox, which `*` resolves to in a UID set.
+    pub largest_uid: u32,
+}
+
+impl Criterion {
+    /// Whether matching needs the message body from the data store.
+    pub fn needs_body(&self) -> bool {
+        match self {
+            Criterion::Body(_) | Criterion::Text(_) => true,
+            Criterion::Not(criterion) => criterion.needs_body(),
+            Criterion::Or(left, right) => left.needs_body() || right.needs_body(),
+            Criterion::And(criteria) => criteria.iter().any(Criterion::needs_body),
+            _ => false,
+        }
+    }
+
+    /// Whether matching needs the message headers from the data store.
+    pub fn needs_header(&self) -> bool {
+        match self {
+            Criterion::Header(..) => true,
+            Criterion::Not(criterion) => criterion.needs_header(),
+            Criterion::Or(left, right) => left.needs_header() || right.needs_header(),
+            Criterion::And(criteria) => criteria.iter().any(Criterion::needs_header),
+            _ => false,
+        }
+    }
+
+    /// Strings are compared case-insensitively after decoding headers and bodies to Unicode.
+    pub fn matches(&self, candidate: &Candidate) -> bool {
+        match self {
+            Criterion::All => true,
+            Criterion::Record(query) => query.matches(candidate.record),
+            Criterion::Sequence(set) => set.contains(candidate.number, candidate.count),
+            Criterion::Uid(set) => set.contains(candidate.record.uid, candidate.largest_uid),
+            Criterion::Header(name, value) => header_contains(candidate.message, name, value),
+            Criterion::Body(value) => body_contains(candidate.message, &value.to_lowercase()),
+            Criterion::Text(value) => {
+                let value = value.to_lowercase();
+                headers_text(candidate.message).contains(&value)
+                    || body_contains(candidate.message, &value)
+            }
+            Criterion::Not(criterion) => !criterion.matches(candidate),
+            Criterion::Or(left, right) => left.matches(candidate) || right.matches(candidate),
+            Criterion::And(criteria) => criteria.iter().all(|c| c.matches(candidate)),
+        }
+    }
+}
+
+fn header_contains(message: &Part, name: &str, value: &str) -> bool {
+    let value = value.to_lowercase();
+    message
+        .headers()
+        .get_all(name)
+        .iter()
+        .any(|field| decode_words(field).to_lowercase().contains(&value))
+}
+
+fn headers_text(message: &Part) -> String {
+    message
+        .headers()
+        .fields()
+        .iter()
+        .map(|field| format!("{}: {}\n", field.name, decode_words(&field.value())))
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Searches the text parts of a message, including those of encapsulated messages. Other
+/// parts, such as attachments, are not searched.
+fn body_contains(part: &Part, value: &str) -> bool {
+    if part.is_multipart() {
+        return part
+            .children
+            .iter()
+            .any(|child| body_contains(child, value));
+    }
+    if let Some(message) = &part.message {
+        return headers_text(message).contains(value) || body_contains(message, value);
+    }
+    part.content_type.kind == "text" && part.text().to_lowercase().contains(value)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Open,
+    Close,
+    /// An atom or quoted string.
+    Word(String),
+}
+
+fn tokenize(arguments: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = vec![];
+    let mut chars = arguments.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => {}
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '"' => {
+                let mut word = String::new();
+                loop {
+                    match chars.next().ok_or(ParseError {})? {
+                        '"' => break,
+                        '\\' => word.push(chars.next().ok_or(ParseError {})?),
+                        c => word.push(c),
+                    }
+                }
+                tokens.push(Token::Word(word));
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|c| !matches!(c, ' ' | '(' | ')' | '"')) {
+                    word.push(c);
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses the arguments of a SEARCH command, e.g. `CHARSET UTF-8 OR SEEN (FROM "Jane" 1:5)`.
+pub fn parse(arguments: &str) -> Result<Search, ParseError> {
+    let tokens = tokenize(arguments)?;
+    let mut tokens = tokens.iter().peekable();
+    let mut charset = None;
+    if let Some(Token::Word(word)) = tokens.peek() {
+        if word.eq_ignore_ascii_case("CHARSET") {
+            tokens.next();
+            charset = Some(word_of(tokens.next())?);
+        }
+    }
+    let mut criteria = vec![];
+    while tokens.peek().is_some() {
+        criteria.push(criterion(&mut tokens, 0)?);
+    }
+    if criteria.is_empty() {
+        return Err(ParseError {});
+    }
+    Ok(Search {
+        charset,
+        criteria: Criterion::And(criteria),
+    })
+}
+
+fn word_of(token: Option<&Token>) -> Result<String, ParseError> {
+    match token {
+        Some(Token::Word(word)) => Ok(word.clone()),
+        _ => Err(ParseError {}),
+    }
+}
+
+fn criterion<'a, I: Iterator<Item = &'a Token>>(
+    tokens: &mut std::iter::Peekable<I>,
+    depth: usize,
+) -> Result<Criterion, ParseError> {
+    if depth >= MAX_DEPTH {
+        return Err(ParseError {});
+    }
+    let key = match tokens.next() {
+        Some(Token::Open) => {
+            let mut criteria = vec![];
+            while tokens.peek() != Some(&&Token::Close) {
+                criteria.push(criterion(tokens, depth + 1)?);
+            }
+            tokens.next();
+            if criteria.is_empty() {
+                return Err(ParseError {});
+            }
+            return Ok(Criterion::And(criteria));
+        }
+        Some(Token::Word(key)) => key.to_ascii_uppercase(),
+        _ => return Err(ParseError {}),
+    };
+    let flag = |flag: &str| Criterion::Record(MessageQuery::new().with_flag(flag));
+    let no_flag = |flag: &str| Criterion::Record(MessageQuery::new().without_flag(flag));
+    Ok(match key.as_str() {
+        "ALL" => Criterion::All,
+        "ANSWERED" => flag("\\Answered"),
+        "DELETED" => flag("\\Deleted"),
+        "DRAFT" => flag("\\Draft"),
+        "FLAGGED" => flag("\\Flagged"),
+        "SEEN" => flag("\\Seen"),
+        "UNANSWERED" => no_flag("\\Answered"),
+        "UNDELETED" => no_flag("\\Deleted"),
+        "UNDRAFT" => no_flag("\\Draft"),
+        "UNFLAGGED" => no_flag("\\Flagged"),
+        "UNSEEN" => no_flag("\\Seen"),
+        "KEYWORD" => flag(&word_of(tokens.next())?),
+        "UNKEYWORD" => no_flag(&word_of(tokens.next())?),
+        "LARGER" => Criterion::Record(MessageQuery::new().larger_than(number(tokens.next())?)),
+        "SMALLER" => Criterion::Record(MessageQuery::new().smaller_than(number(tokens.next())?)),
+        "BEFORE" => Criterion::Record(MessageQuery::new().before(date(tokens.next())?)),
+        "SINCE" => Criterion::Record(MessageQuery::new().since(date(tokens.next())?)),
+        "ON" => {
+            let day = date(tokens.next())?;
+            Criterion::Record(
+                MessageQuery::new()
+                    .since(day)
+                    .before(day + Duration::from_secs(86400)),
+            )
+        }
+        "FROM" | "TO" | "CC" | "BCC" | "SUBJECT" => {
+            Criterion::Header(key.clone(), word_of(tokens.next())?)
+        }
+        "HEADER" => Criterion::Header(word_of(tokens.next())?, word_of(tokens.next())?),
+        "BODY" => Criterion::Body(word_of(tokens.next())?),
+        "TEXT" => Criterion::Text(word_of(tokens.next())?),
+        "UID" => Criterion::Uid(SequenceSet::parse(&word_of(tokens.next())?)?),
+        "NOT" => Criterion::Not(Box::new(criterion(tokens, depth + 1)?)),
+        "OR" => Criterion::Or(
+            Box::new(criterion(tokens, depth + 1)?),
+            Box::new(criterion(tokens, depth + 1)?),
+        ),
+        _ => Criterion::Sequence(SequenceSet::parse(&key)?),
+    })
+}
+
+fn number(token: Option<&Token>) -> Result<u64, ParseError> {
+    word_of(token)?.parse().map_err(|_| ParseError {})
+}
+
+/// Parses an IMAP `date` such as `1-Feb-1994` as midnight UTC.
+fn date(token: Option<&Token>) -> Result<SystemTime, ParseError> {
+    const MONTHS: [&str; 12] = [
+        "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+    ];
+    let value = word_of(token)?;
+    let mut fields = value.splitn(3, '-');
+    let (day, month, year) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(day), Some(month), Some(year)) => (day, month, year),
+        _ => return Err(ParseError {}),
+    };
+    let day: u32 = day.parse().map_err(|_| ParseError {})?;
+    let month = MONTHS
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(month))
+        .ok_or(ParseError {})? as u32;
+    if !(1..=31).contains(&day) || year.len() != 4 {
+        return Err(ParseError {});
+    }
+    Ok(SystemTime::from(UtcTime {
+        year: year.parse().map_err(|_| ParseError {})?,
+        month: month + 1,
+        day,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{parse, Candidate, Criterion};
+    use crate::index::message::MessageRecord;
+    use crate::mime::Part;
+
+    const MESSAGE: &[u8] = b"From: =?ISO-8859-1?Q?Andr=E9?= <andre@example.com>\r\n\
+Subject: =?windows-1252?Q?=93Caf=E9=94?=\r\n\
+Content-Type: multipart/mixed; boundary=b\r\n\
+\r\n\
+--b\r\n\
+Content-Type: text/plain; charset=iso-8859-2\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+Spotkanie w =A3odzi\r\n\
+--b\r\n\
+Content-Type: application/octet-stream\r\n\
+\r\n\
+hidden\r\n\
+--b--\r\n";
+
+    fn matches(criteria: &str) -> bool {
+        let record = MessageRecord::new(7, 300, UNIX_EPOCH + Duration::from_secs(760657945))
+            .with_flags(vec!["\\Seen".to_string()]);
+        let message = Part::parse(MESSAGE);
+        let candidate = Candidate {
+            number: 2,
+            record: &record,
+            message: &message,
+            count: 3,
+            largest_uid: 9,
+        };
+        parse(criteria).unwrap().criteria.matches(&candidate)
+    }
+
+    #[test]
+    fn test_search_matching() {
+        assert!(matches("ALL"));
+        assert!(matches("FROM andré SUBJECT \"“café”\""));
+        assert!(matches("BODY łODZI"));
+        assert!(matches("TEXT andre@example"));
+        assert!(!matches("BODY hidden"));
+        assert!(matches("SEEN UNFLAGGED LARGER 200 SMALLER 301"));
+        assert!(matches("ON 7-Feb-1994 SINCE 1-feb-1994 BEFORE 8-Feb-1994"));
+        assert!(!matches("BEFORE 7-Feb-1994"));
+        assert!(matches("2:* UID 5:*"));
+        assert!(!matches("UID 8:*"));
+        assert!(matches("OR UNSEEN (NOT DELETED HEADER Content-Type mixed)"));
+        assert!(!matches("NOT (SEEN)"));
+    }
+
+    #[test]
+    fn test_search_parse() {
+        let search = parse("CHARSET ISO-8859-2 TEXT \"a \\\"b\\\"\"").unwrap();
+        assert_eq!(search.charset.as_deref(), Some("ISO-8859-2"));
+        assert!(search.criteria.needs_body());
+        assert!(!parse("SUBJECT x").unwrap().criteria.needs_body());
+        assert!(matches!(
+            parse("SUBJECT x").unwrap().criteria,
+            Criterion::And(criteria) if criteria[0].needs_header()
+        ));
+        for invalid in [
+            "",
+            "CHARSET",
+            "FROM",
+            "(",
+            "()",
+            "OR SEEN",
+            "SINCE 31-Foo-2020",
+            "BOGUS",
+        ] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+        assert!(parse(&"NOT ".repeat(10_000)).is_err());
+        assert!(parse(&format!("{}SEEN{}", "(".repeat(8), ")".repeat(8))).is_ok());
+    }
+}
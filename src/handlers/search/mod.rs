@@ -0,0 +1,324 @@
+// From RFC 9051 (https://www.ietf.org/rfc/rfc9051.html#name-search-command):
+// C: A282 SEARCH FLAGGED SINCE 1-Feb-1994 NOT FROM "Smith"
+// S: * SEARCH 2 84 882
+// S: A282 OK SEARCH completed
+// C: A284 SEARCH CHARSET UTF-8 TEXT "Ελληνικά"
+// S: * SEARCH 43
+// S: A284 OK SEARCH completed
+
+pub mod criteria;
+
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use tracing::Instrument;
+
+use crate::connection::{Context, Request};
+use crate::handlers::{mailbox_error, HandleCommand, Workers};
+use crate::index::{Index, MailboxError};
+use crate::mime::{charset, Part};
+use crate::server::{Command, ParseError, Response, ResponseStatus};
+use crate::state::State;
+use crate::store::home::Home;
+use crate::store::DataStore;
+use crate::util::{Receiver, Result};
+
+use self::criteria::Candidate;
+
+use super::Handle;
+
+fn bad_charset(tag: &str) -> Response {
+    Response::new(
+        tag,
+        ResponseStatus::NO,
+        &format!(
+            "[BADCHARSET ({})] unsupported charset",
+            charset::SUPPORTED.join(" ")
+        ),
+    )
+}
+
+#[derive(Clone)]
+pub struct SearchHandler {
+    index: Arc<Box<dyn Index>>,
+    store: Arc<Box<dyn DataStore>>,
+    workers: Workers,
+}
+
+impl SearchHandler {
+    #[must_use]
+    pub fn new(index: Arc<Box<dyn Index>>, store: Arc<Box<dyn DataStore>>) -> Self {
+        Self {
+            index,
+            store,
+            workers: Workers::default(),
+        }
+    }
+    /// Runs requests on `workers`, shared with the other handlers of the server.
+    pub fn with_workers(mut self, workers: Workers) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Commands arrive as text, so search strings are already Unicode whichever supported
+    /// `CHARSET` the client names; the charset only has to be one we can decode messages from.
+    async fn search(&self, command: &Command, context: &Context) -> Vec<Response> {
+        let tag = command.tag();
+        let owner = match context.owner() {
+            Some(owner) => owner,
+            None => return vec![State::of(context).rejection(command)],
+        };
+        let folder = match context.folder() {
+            Some(folder) => folder,
+            None => return vec![State::of(context).rejection(command)],
+        };
+        let arguments: Vec<String> = (0..command.num_args()).map(|i| command.arg(i)).collect();
+        let search = match criteria::parse(&arguments.join(" ")) {
+            Ok(search) => search,
+            Err(_) => {
+                return vec![Response::new(
+                    &tag,
+                    ResponseStatus::BAD,
+                    "invalid SEARCH arguments",
+                )]
+            }
+        };
+        if let Some(name) = &search.charset {
+            if !charset::is_supported(name) {
+                return vec![bad_charset(&tag)];
+            }
+        }
+        let records = match self.index.list_messages(&owner, &folder).await {
+            Ok(records) => records,
+            Err(e) => return vec![mailbox_error(&tag, &e)],
+        };
+
+        let home = Home::new(self.store.clone(), owner);
+
+        let largest_uid = records.last().map(|record| record.uid).unwrap_or(0);
+        let mut found = vec![];
+        for (position, record) in records.iter().enumerate() {
+            let body = if search.criteria.needs_body() {
+                home.fetch(&folder, record.uid).await
+            } else if search.criteria.needs_header() {
+                home.fetch_header(&folder, record.uid).await
+            } else {
+                Ok(vec![])
+            };
+            let body = match body {
+                Ok(body) => body,
+                Err(e) => return vec![mailbox_error(&tag, &MailboxError::from(e))],
+            };
+            let message = Part::parse(&body);
+            let candidate = Candidate {
+                number: position as u32 + 1,
+                record,
+                message: &message,
+                count: records.len() as u32,
+                largest_uid,
+            };
+            if search.criteria.matches(&candidate) {
+                found.push((position + 1).to_string());
+            }
+        }
+        let mut results = vec!["SEARCH".to_string()];
+        results.extend(found);
+        vec![
+            Response::untagged(&results.join(" ")),
+            Response::new(&tag, ResponseStatus::OK, "SEARCH completed."),
+        ]
+    }
+}
+
+#[async_trait::async_trait]
+impl HandleCommand for SearchHandler {
+    fn name<'a>(&self) -> &'a str {
+        "SEARCH"
+    }
+    async fn validate<'a>(&self, command: &'a Command) -> Result<()> {
+        if command.command() != self.name() {
+            return Ok(());
+        }
+        if command.num_args() < 1 {
+            return Err(Box::new(ParseError {}));
+        }
+        Ok(())
+    }
+    async fn handle<'a>(
+        &self,
+        command: &'a Command,
+        context: &'a Context,
+    ) -> Result<Vec<Response>> {
+        Ok(self.search(command, context).await)
+    }
+}
+
+#[async_trait::async_trait]
+impl Handle for SearchHandler {
+    fn command<'a>(&self) -> &'a str {
+        "SEARCH"
+    }
+
+    async fn start(&mut self, mut requests: Receiver<Request>) -> Result<()> {
+        while let Some(mut request) = requests.next().await {
+            if self.validate(&request.command).await.is_err() {
+                request
+                    .responder
+                    .send(vec![Response::new(
+                        &request.command.tag(),
+                        ResponseStatus::BAD,
+                        "insufficient arguments",
+                    )])
+                    .await?;
+                continue;
+            }
+            // Each SEARCH runs on its own task, so that a slow one does not hold up the
+            // others.
+            let handler = self.clone();
+            let (span, timings) = (request.span.clone(), request.timings.clone());
+            self.workers
+                .spawn(
+                    timings.scope(
+                        async move {
+                            let responses =
+                                handler.search(&request.command, &request.context).await;
+                            let _ = request.responder.send(responses).await;
+                        }
+                        .instrument(span),
+                    ),
+                )
+                .await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_std::path::PathBuf;
+
+    use super::SearchHandler;
+    use crate::auth::User;
+    use crate::connection::Context;
+    use crate::handlers::tests::test_handle;
+    use crate::handlers::HandleCommand;
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::reindex::reindex;
+    use crate::index::{Index, Owner};
+    use crate::server::{Command, Response, ResponseStatus};
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message};
+
+    async fn search_handler() -> SearchHandler {
+        let store: Box<dyn DataStore> = Box::new(InMemoryStore::new());
+        let index: Box<dyn Index> = Box::new(InMemoryIndex::new());
+        let messages: [&[u8]; 3] = [
+            b"Subject: hello\r\n\r\nplain body",
+            b"Subject: =?ISO-8859-1?Q?R=E9sum=E9?=\r\n\
+Content-Type: text/plain; charset=windows-1252\r\n\r\nna\xefve \x80 prices",
+            b"Subject: other\r\n\r\nnothing here",
+        ];
+        for message in messages {
+            store
+                .append("username", "INBOX", Message::new(message))
+                .await
+                .unwrap();
+        }
+        reindex(
+            store.as_ref(),
+            index.as_ref(),
+            &Owner::new("username"),
+            |_| {},
+        )
+        .await
+        .unwrap();
+        SearchHandler::new(Arc::new(index), Arc::new(store))
+    }
+
+    fn selected() -> Context {
+        Context::of(
+            Some(User::new("username", "password")),
+            Some(PathBuf::from("INBOX")),
+        )
+    }
+
+    async fn search(handler: &SearchHandler, args: Vec<&str>) -> Vec<Response> {
+        let command = Command::new("a1", "SEARCH", args);
+        handler.handle(&command, &selected()).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_search_charsets() {
+        let handler = search_handler().await;
+        assert_eq!(
+            search(
+                &handler,
+                vec!["CHARSET", "UTF-8", "BODY", "NAÏVE", "TEXT", "€"]
+            )
+            .await,
+            vec![
+                Response::untagged("SEARCH 2"),
+                Response::new("a1", ResponseStatus::OK, "SEARCH completed."),
+            ]
+        );
+        assert_eq!(
+            search(&handler, vec!["OR", "SUBJECT", "résumé", "BODY", "plain"]).await[0],
+            Response::untagged("SEARCH 1 2")
+        );
+        assert_eq!(
+            search(&handler, vec!["NOT", "1:2"]).await[0],
+            Response::untagged("SEARCH 3")
+        );
+        assert_eq!(
+            search(&handler, vec!["SUBJECT", "missing"]).await[0],
+            Response::untagged("SEARCH")
+        );
+        let response = search(&handler, vec!["CHARSET", "X-UNKNOWN", "ALL"]).await;
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].status(), Some(ResponseStatus::NO));
+        assert!(response[0]
+            .message()
+            .starts_with("[BADCHARSET (UTF-8 US-ASCII"));
+        assert_eq!(
+            search(&handler, vec!["FROM"]).await,
+            vec![Response::new(
+                "a1",
+                ResponseStatus::BAD,
+                "invalid SEARCH arguments"
+            )]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_search_handle() {
+        let handler = search_handler().await;
+        let command = Command::new("a1", "SEARCH", vec!["UNSEEN", "SUBJECT", "other"]);
+
+        let mut f = Some(|_event| {});
+        f.take();
+        test_handle(
+            handler,
+            command,
+            |responses| assert_eq!(responses[0], Response::untagged("SEARCH 3")),
+            f,
+            Some(selected()),
+        )
+        .await;
+    }
+
+    #[async_std::test]
+    async fn test_search_unselected() {
+        let handler = search_handler().await;
+        let command = Command::new("a1", "SEARCH", vec!["ALL"]);
+        let response = handler
+            .handle(
+                &command,
+                &Context::of(Some(User::new("username", "password")), None),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response[0].status(), Some(ResponseStatus::NO));
+    }
+}
@@ -12,17 +12,85 @@ use std::sync::Arc;
 
 use async_std::path::PathBuf;
 use futures::{SinkExt, StreamExt};
+use tracing::Instrument;
 
-use crate::connection::{Event, self};
-use crate::handlers::HandleCommand;
-use crate::index::{Index, Permission};
+use crate::connection::{Context, Event, self};
+use crate::handlers::fetch::structure::string;
+use crate::handlers::{mailbox_error, HandleCommand};
+use crate::index::{Index, Mailbox, MailboxError, Permission};
 use crate::server::{Command, ParseError, Response, ResponseStatus};
+use crate::state::State;
+use crate::store::name::{resolve_mailbox, DELIMITER};
 use crate::util::{Receiver, Result};
 
 use super::Handle;
 
+/// The FLAGS and PERMANENTFLAGS lists of a mailbox. Clients may create keywords (`\*`) in
+/// mailboxes they can write to, and cannot change any flag in those they cannot.
+fn flag_lists(mailbox: &Mailbox) -> (String, String) {
+    let flags: Vec<&str> = mailbox.flags.iter().map(|flag| flag.value.as_str()).collect();
+    let permanent: Vec<&str> = match mailbox.permission {
+        Permission::ReadOnly => vec![],
+        Permission::ReadWrite => mailbox
+            .flags
+            .iter()
+            .filter(|flag| flag.permanent)
+            .map(|flag| flag.value.as_str())
+            .chain(["\\*"])
+            .collect(),
+    };
+    (flags.join(" "), permanent.join(" "))
+}
+
+/// The mailbox name as an `astring`, quoted unless it is an atom.
+fn astring(name: &str) -> String {
+    let atom = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !"(){%*\"\\".contains(c));
+    match atom {
+        true => name.to_string(),
+        false => string(name),
+    }
+}
+
+fn selected(tag: &str, verb: &str, mailbox: &Mailbox) -> Vec<Response> {
+    let (flags, permanent) = flag_lists(mailbox);
+    let name = mailbox.name.to_string_lossy();
+    vec![
+        Response::untagged(&format!("{} EXISTS", mailbox.count)),
+        Response::new(
+            "*",
+            ResponseStatus::OK,
+            &format!("[UIDVALIDITY {}] UIDs valid", mailbox.uid_validity),
+        ),
+        Response::new(
+            "*",
+            ResponseStatus::OK,
+            &format!("[UIDNEXT {}] Predicted next UID", mailbox.uid_next),
+        ),
+        Response::untagged(&format!("FLAGS ({})", flags)),
+        Response::new(
+            "*",
+            ResponseStatus::OK,
+            &format!("[PERMANENTFLAGS ({})] Flags permitted.", permanent),
+        ),
+        Response::untagged(&format!("LIST () \"{}\" {}", DELIMITER, astring(&name))),
+        Response::new(
+            tag,
+            ResponseStatus::OK,
+            &match mailbox.permission {
+                Permission::ReadOnly => format!("[READ-ONLY] {} completed.", verb),
+                Permission::ReadWrite => format!("[READ-WRITE] {} completed.", verb),
+            },
+        ),
+    ]
+}
+
+/// Handles SELECT, or EXAMINE, which opens the mailbox read-only.
 pub struct SelectHandler {
     index: Arc<Box<dyn Index>>,
+    examine: bool,
 }
 
 impl SelectHandler {
@@ -31,6 +99,22 @@ impl SelectHandler {
     ) -> Self {
         Self {
             index,
+            examine: false,
+        }
+    }
+    #[must_use]
+    pub fn examine(index: Arc<Box<dyn Index>>) -> Self {
+        Self {
+            index,
+            examine: true,
+        }
+    }
+
+    fn permission(&self, context: &Context) -> Permission {
+        if self.examine || context.is_read_only() {
+            Permission::ReadOnly
+        } else {
+            Permission::ReadWrite
         }
     }
 }
@@ -38,7 +122,10 @@ impl SelectHandler {
 #[async_trait::async_trait]
 impl HandleCommand for SelectHandler {
     fn name<'a>(&self) -> &'a str {
-        "SELECT"
+        match self.examine {
+            true => "EXAMINE",
+            false => "SELECT",
+        }
     }
     async fn validate<'a>(&self, command: &'a Command) -> Result<()> {
         if command.command() != self.name() {
@@ -49,30 +136,29 @@ impl HandleCommand for SelectHandler {
         }
         Ok(())
     }
-    async fn handle<'a>(&self, command: &'a Command) -> Result<Vec<Response>> {
-        Ok(vec![
-            Response::from("* 172 EXISTS").unwrap(),
-            Response::from("* OK [UIDVALIDITY 3857529045] UIDs valid").unwrap(),
-            Response::from("* OK [UIDNEXT 4392] Predicted next UID").unwrap(),
-            Response::from("* FLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft)").unwrap(),
-            Response::from("* OK [PERMANENTFLAGS (\\Deleted \\Seen \\*)] Limited").unwrap(),
-            Response::from("* LIST () \"/\" INBOX").unwrap(),
-            Response::new(
-                &command.tag(),
-                ResponseStatus::OK,
-                "[READ-WRITE] SELECT completed.",
-            ),
-        ])
+    async fn handle<'a>(&self, command: &'a Command, context: &'a Context) -> Result<Vec<Response>> {
+        let owner = match context.owner() {
+            Some(owner) => owner,
+            None => return Ok(vec![State::of(context).rejection(command)]),
+        };
+        let folder = match resolve_mailbox(&command.arg(0)) {
+            Ok(folder) => folder,
+            Err(e) => return Ok(vec![mailbox_error(&command.tag(), &MailboxError::from(e))]),
+        };
+        match self.index.get_mailbox(&owner, &folder, self.permission(context)).await {
+            Ok(mailbox) => Ok(selected(&command.tag(), self.name(), &mailbox)),
+            Err(e) => Ok(vec![mailbox_error(&command.tag(), &e)]),
+        }
     }
 }
 #[async_trait::async_trait]
 impl Handle for SelectHandler {
     fn command<'b>(&self) -> &'b str {
-        "SELECT"
+        self.name()
     }
     async fn start<'b>(&'b mut self, mut requests: Receiver<connection::Request>) -> Result<()> {
         while let Some(mut request) = requests.next().await {
-            if let Err(..) = self.validate(&request.command).await {
+            if self.validate(&request.command).await.is_err() {
                 request
                     .responder
                     .send(vec![Response::new(
@@ -83,50 +169,44 @@ impl Handle for SelectHandler {
                     .await?;
                 continue;
             }
-            if !request.context.is_authenticated() {
-                request.responder.send(vec![Response::new("a1", ResponseStatus::NO, "cannot SELECT when un-authenticated. Please authenticate using LOGIN or AUTHENTICATE.")]).await?;
-                continue;
-            }
-            let folder = request.command.arg(0);
-            
-            let mailbox = self.index.get_mailbox(&folder, Permission::ReadWrite).await;
+            let owner = match request.context.owner() {
+                Some(owner) => owner,
+                None => {
+                    let rejection = State::of(&request.context).rejection(&request.command);
+                    request.responder.send(vec![rejection]).await?;
+                    continue;
+                }
+            };
+            let folder = match resolve_mailbox(&request.command.arg(0)) {
+                Ok(folder) => folder,
+                Err(e) => {
+                    let error = mailbox_error(&request.command.tag(), &MailboxError::from(e));
+                    request.responder.send(vec![error]).await?;
+                    continue;
+                }
+            };
+
+            let mailbox = self
+                .index
+                .get_mailbox(&owner, &folder, self.permission(&request.context))
+                .instrument(request.span.clone());
+            let mailbox = request.timings.scope(mailbox).await;
 
             match mailbox {
                 Ok(mailbox) => {
                     request
                         .events
-                        .send(Event::SELECT(PathBuf::from(folder.clone())))
+                        .send(Event::SELECT(PathBuf::from(folder.clone()), mailbox.permission))
                         .await?;
                     request
                         .responder
-                        .send(vec![
-                            Response::from(&format!("* {} EXISTS", &mailbox.count)).unwrap(),
-                            Response::from("* OK [UIDVALIDITY 3857529045] UIDs valid").unwrap(),
-                            Response::from("* OK [UIDNEXT 4392] Predicted next UID").unwrap(),
-                            Response::from(
-                                "* FLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft)",
-                            )
-                            .unwrap(),
-                            Response::from("* OK [PERMANENTFLAGS (\\Deleted \\Seen \\*)] Limited")
-                                .unwrap(),
-                            Response::from(&format!("* LIST () \"/\" {}", folder)).unwrap(),
-                            Response::new(
-                                &request.command.tag(),
-                                ResponseStatus::OK,
-                                "[READ-WRITE] SELECT completed.",
-                            ),
-                        ])
+                        .send(selected(&request.command.tag(), self.name(), &mailbox))
                         .await?;
                 }
-                // TODO: parse MailboxError response
-                Err(..) => {
+                Err(e) => {
                     request
                         .responder
-                        .send(vec![Response::new(
-                            &request.command.tag(),
-                            ResponseStatus::NO,
-                            "No such mailbox",
-                        )])
+                        .send(vec![mailbox_error(&request.command.tag(), &e)])
                         .await?;
                 }
             }
@@ -138,6 +218,7 @@ impl Handle for SelectHandler {
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
+    use std::time::SystemTime;
 
     use async_std::path::PathBuf;
 
@@ -146,27 +227,55 @@ mod tests {
     use crate::connection::{Context, Event};
     use crate::handlers::tests::test_handle;
     use crate::handlers::HandleCommand;
-    use crate::index::{Mailbox, Permission, Index, MailboxError};
+    use crate::index::{Flag, Mailbox, Permission, Index, MailboxError, Owner};
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::journal::JournalEntry;
+    use crate::index::message::MessageRecord;
+    use crate::index::uid::UidState;
     use crate::server::{Command, Response, ResponseStatus};
+    use crate::util::Receiver;
 
     const EXISTING_MAILBOX: &str = "INBOX";
+    /// A mailbox the user may see but not read, as an index keeping access rights refuses.
+    const UNREADABLE_MAILBOX: &str = "Private";
     struct TestIndex {}
     
     #[async_trait::async_trait]
     impl Index for TestIndex {
-        async fn add_mailbox(&self, _: Mailbox) -> Result<(), MailboxError> {
+        async fn add_mailbox(&self, _: &Owner, _: Mailbox) -> Result<(), MailboxError> {
             panic!("Cannot add new mailboxes")
         }
-        async fn get_mailbox(&self, name: &str, permission: Permission) -> Result<Mailbox, MailboxError> {
-            if name == EXISTING_MAILBOX {
+        async fn add_message(&self, _: &Owner, _: &str, _: MessageRecord) -> Result<MessageRecord, MailboxError> {
+            panic!("Cannot add new messages")
+        }
+        async fn list_messages(&self, _: &Owner, _: &str) -> Result<Vec<MessageRecord>, MailboxError> {
+            panic!("Cannot list messages")
+        }
+        async fn set_flags(&self, _: &Owner, _: &str, _: u32, _: Vec<String>) -> Result<MessageRecord, MailboxError> {
+            panic!("Cannot set flags")
+        }
+        async fn remove_messages(&self, _: &Owner, _: &str, _: &[u32]) -> Result<Vec<u32>, MailboxError> {
+            panic!("Cannot remove messages")
+        }
+        async fn changes_since(&self, _: &Owner, _: &str, _: u64) -> Result<Vec<JournalEntry>, MailboxError> {
+            panic!("Cannot read the journal")
+        }
+        async fn watch(&self, _: &Owner, _: &str) -> Result<Receiver<JournalEntry>, MailboxError> {
+            panic!("Cannot watch mailboxes")
+        }
+        async fn get_mailbox(&self, owner: &Owner, name: &str, permission: Permission) -> Result<Mailbox, MailboxError> {
+            if owner.name() == "username" && name == EXISTING_MAILBOX {
                 return Ok(Mailbox::new(
                                 EXISTING_MAILBOX,
                                 172,
-                                vec![],
+                                Flag::system(),
                                 permission,
-                            ))
+                            ).with_uid_state(UidState { uid_validity: 3857529045, uid_next: 4392 }))
+            }
+            if name == UNREADABLE_MAILBOX {
+                return Err(MailboxError::InsufficientPermissions(name.to_string(), owner.name().to_string(), "read".to_string()))
             }
-            return Err(MailboxError::DoesNotExist(name.clone().to_string()))
+            return Err(MailboxError::DoesNotExist(name.to_string()))
         }
     }
 
@@ -191,8 +300,10 @@ mod tests {
         let select_handler = SelectHandler::new(Arc::new(Box::new(index)));
         let select_command = Command::new("a1", "SELECT", vec!["INBOX"]);
         let valid = select_handler.validate(&select_command).await;
-        assert_eq!(valid.is_ok(), true);
-        let response = select_handler.handle(&select_command).await;
+        assert!(valid.is_ok());
+        let response = select_handler
+            .handle(&select_command, &Context::of(Some(User::new("username", "password")), None))
+            .await;
         select_success(response.unwrap());
     }
 
@@ -206,8 +317,9 @@ mod tests {
             Some(ctx),
             select_success,
             Some(|event| match event {
-                Event::SELECT(folder) => {
-                    assert_eq!(folder, PathBuf::from("INBOX"))
+                Event::SELECT(folder, permission) => {
+                    assert_eq!(folder, PathBuf::from("INBOX"));
+                    assert!(matches!(permission, Permission::ReadWrite));
                 }
                 _ => {
                     panic!("SELECT command should only send SELECT events");
@@ -229,6 +341,115 @@ mod tests {
         }, f).await;
     }
 
+    #[async_std::test]
+    async fn test_anonymous_select_is_read_only() {
+        let index = TestIndex{};
+        let select_handler = SelectHandler::new(Arc::new(Box::new(index)));
+        let select_command = Command::new("a1", "SELECT", vec!["INBOX"]);
+        let response = select_handler
+            .handle(&select_command, &Context::of(Some(User::anonymous("username")), None))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.last(),
+            Some(&Response::new("a1", ResponseStatus::OK, "[READ-ONLY] SELECT completed."))
+        );
+    }
+
+    #[async_std::test]
+    async fn test_examine_is_read_only() {
+        let command = Command::new("a1", "EXAMINE", vec!["INBOX"]);
+        let handler = SelectHandler::examine(Arc::new(Box::new(TestIndex {})));
+        let ctx = Context::of(Some(User::new("username", "password")), None);
+        test_handle(
+            handler,
+            command,
+            |response| {
+                assert_eq!(
+                    response[4],
+                    Response::from("* OK [PERMANENTFLAGS ()] Flags permitted.").unwrap()
+                );
+                assert_eq!(
+                    response.last(),
+                    Some(&Response::new("a1", ResponseStatus::OK, "[READ-ONLY] EXAMINE completed."))
+                );
+            },
+            Some(|event| {
+                assert!(matches!(event, Event::SELECT(_, Permission::ReadOnly)));
+            }),
+            Some(ctx),
+        )
+        .await;
+    }
+
+    #[async_std::test]
+    async fn test_select_reports_the_mailbox_record() {
+        let index: Arc<Box<dyn Index>> = Arc::new(Box::new(InMemoryIndex::new()));
+        let owner = Owner::new("username");
+        let mailbox = Mailbox::new("Work Items", 0, vec![], Permission::ReadWrite);
+        index.add_mailbox(&owner, mailbox).await.unwrap();
+        for uid in [1, 2] {
+            let record = MessageRecord::new(uid, 10, SystemTime::now());
+            index.add_message(&owner, "Work Items", record).await.unwrap();
+        }
+        let uid_validity = index
+            .get_mailbox(&owner, "Work Items", Permission::ReadOnly)
+            .await
+            .unwrap()
+            .uid_validity;
+
+        let handler = SelectHandler::new(index);
+        let command = Command::new("a1", "SELECT", vec!["Work Items"]);
+        let ctx = Context::of(Some(User::new("username", "password")), None);
+        let response = handler.handle(&command, &ctx).await.unwrap();
+        assert_eq!(response[0], Response::untagged("2 EXISTS"));
+        assert_eq!(
+            response[1],
+            Response::new("*", ResponseStatus::OK, &format!("[UIDVALIDITY {}] UIDs valid", uid_validity))
+        );
+        assert_eq!(
+            response[2],
+            Response::new("*", ResponseStatus::OK, "[UIDNEXT 3] Predicted next UID")
+        );
+        assert_eq!(response[5], Response::untagged("LIST () \"/\" \"Work Items\""));
+    }
+
+    #[async_std::test]
+    async fn test_select_is_scoped_to_owner() {
+        let command = Command::new("a1", "SELECT", vec!["INBOX"]);
+
+        let ctx = Context::of(Some(User::new("someone_else", "password")), None);
+        let mut f = Some(|_event| {});
+        f.take();
+        test_select(command, Some(ctx), |response| {
+            assert_eq!(response, vec![Response::new("a1", ResponseStatus::NO, "[NONEXISTENT] Mailbox INBOX does not exist")]);
+        }, f).await;
+    }
+
+    #[async_std::test]
+    async fn test_select_without_read_permission() {
+        let command = Command::new("a1", "SELECT", vec![UNREADABLE_MAILBOX]);
+
+        let ctx = Context::of(Some(User::new("username", "password")), None);
+        let mut f = Some(|_event| {});
+        f.take();
+        test_select(command, Some(ctx), |response| {
+            assert_eq!(response, vec![Response::new("a1", ResponseStatus::NO, "[NOPERM] User username does not have sufficient permissions to read on mailbox Private")]);
+        }, f).await;
+    }
+
+    #[async_std::test]
+    async fn test_select_rejects_traversal() {
+        let command = Command::new("a1", "SELECT", vec!["../someone_else/INBOX"]);
+
+        let ctx = Context::of(Some(User::new("username", "password")), None);
+        let mut f = Some(|_event| {});
+        f.take();
+        test_select(command, Some(ctx), |response| {
+            assert_eq!(response, vec![Response::new("a1", ResponseStatus::NO, "[CANNOT] Mailbox name ../someone_else/INBOX is not allowed")]);
+        }, f).await;
+    }
+
     #[async_std::test]
     async fn test_select_bad_args() {
         let command = Command::new("a1", "SELECT", vec![]);
@@ -257,7 +478,7 @@ mod tests {
                 Response::from("* OK [UIDVALIDITY 3857529045] UIDs valid").unwrap(),
                 Response::from("* OK [UIDNEXT 4392] Predicted next UID").unwrap(),
                 Response::from("* FLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft)").unwrap(),
-                Response::from("* OK [PERMANENTFLAGS (\\Deleted \\Seen \\*)] Limited").unwrap(),
+                Response::from("* OK [PERMANENTFLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft \\*)] Flags permitted.").unwrap(),
                 Response::from("* LIST () \"/\" INBOX").unwrap(),
                 Response::new("a1", ResponseStatus::OK, "[READ-WRITE] SELECT completed.")
             )
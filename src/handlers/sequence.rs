@@ -0,0 +1,107 @@
+use std::fmt::{self, Display};
+
+use crate::server::ParseError;
+
+/// An IMAP sequence set such as `1:3,7,10:*` (RFC 9051 section 9, `sequence-set`).
+///
+/// `*` stands for the largest number in use, so a set is only resolved against a concrete
+/// mailbox in `contains` and `resolve`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequenceSet {
+    ranges: Vec<(Bound, Bound)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Bound {
+    Number(u32),
+    Largest,
+}
+
+impl Bound {
+    fn parse(value: &str) -> Result<Self, ParseError> {
+        match value {
+            "*" => Ok(Bound::Largest),
+            _ => match value.parse::<u32>() {
+                Ok(0) | Err(_) => Err(ParseError {}),
+                Ok(number) => Ok(Bound::Number(number)),
+            },
+        }
+    }
+    fn resolve(self, largest: u32) -> u32 {
+        match self {
+            Bound::Number(number) => number,
+            Bound::Largest => largest,
+        }
+    }
+}
+
+impl SequenceSet {
+    pub fn parse(value: &str) -> Result<Self, ParseError> {
+        let ranges = value
+            .split(',')
+            .map(|range| match range.split_once(':') {
+                Some((first, last)) => Ok((Bound::parse(first)?, Bound::parse(last)?)),
+                None => Bound::parse(range).map(|bound| (bound, bound)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { ranges })
+    }
+
+    pub fn contains(&self, number: u32, largest: u32) -> bool {
+        self.ranges.iter().any(|(first, last)| {
+            let (first, last) = (first.resolve(largest), last.resolve(largest));
+            first.min(last) <= number && number <= first.max(last)
+        })
+    }
+
+    /// Returns every number in the set which is no larger than `largest`, in ascending order
+    /// and without duplicates.
+    pub fn resolve(&self, largest: u32) -> Vec<u32> {
+        (1..=largest)
+            .filter(|number| self.contains(*number, largest))
+            .collect()
+    }
+}
+
+impl Display for Bound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bound::Number(number) => write!(f, "{}", number),
+            Bound::Largest => write!(f, "*"),
+        }
+    }
+}
+
+impl Display for SequenceSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (position, (first, last)) in self.ranges.iter().enumerate() {
+            if position > 0 {
+                write!(f, ",")?;
+            }
+            if first == last {
+                write!(f, "{}", first)?;
+            } else {
+                write!(f, "{}:{}", first, last)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SequenceSet;
+
+    #[test]
+    fn test_sequence_sets() {
+        let set = SequenceSet::parse("4:2,7,9:*").unwrap();
+        assert_eq!(set.resolve(10), vec![2, 3, 4, 7, 9, 10]);
+        assert_eq!(set.resolve(3), vec![2, 3]);
+        assert!(set.contains(12, 12));
+        assert!(SequenceSet::parse("*").unwrap().contains(5, 5));
+        assert_eq!(set.to_string(), "4:2,7,9:*");
+        for invalid in ["", "0", "1:", "a", "1,,2"] {
+            assert!(SequenceSet::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
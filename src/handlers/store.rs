@@ -0,0 +1,387 @@
+// From RFC 9051 (https://www.ietf.org/rfc/rfc9051.html#name-store-command):
+//  C: A003 STORE 2:4 +FLAGS (\Deleted)
+//  S: * 2 FETCH (FLAGS (\Deleted \Seen))
+//  S: * 3 FETCH (FLAGS (\Deleted))
+//  S: * 4 FETCH (FLAGS (\Deleted \Flagged \Seen))
+//  S: A003 OK STORE completed
+//
+// Another session may change the flags of a message between STORE reading them and writing
+// the new ones. The write only goes through if the MODSEQ of the message is still the one
+// read, otherwise the update is applied again to the flags the message has now. As the
+// client's idea of those flags is out of date, it is sent them even for FLAGS.SILENT.
+
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use tracing::Instrument;
+
+use crate::connection::{Context, Request};
+use crate::handlers::sequence::SequenceSet;
+use crate::handlers::{mailbox_error, read_only, HandleCommand, Workers};
+use crate::index::message::MessageRecord;
+use crate::index::{Flag, Index, MailboxError, Owner};
+use crate::server::{Command, ParseError, Response, ResponseStatus};
+use crate::state::State;
+use crate::util::{Receiver, Result};
+
+use super::Handle;
+
+/// How many times the flags of a message are written before giving up on a message which
+/// keeps being changed by other sessions.
+const MAX_ATTEMPTS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Replace,
+    Add,
+    Remove,
+}
+
+/// The data item and flags of a STORE, such as `+FLAGS.SILENT (\Seen $Work)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Update {
+    operation: Operation,
+    silent: bool,
+    flags: Vec<String>,
+}
+
+impl Update {
+    /// Parses the arguments after the sequence set. The flags may be given as a list or
+    /// bare, and must be system flags or keywords.
+    fn parse(arguments: &[String]) -> std::result::Result<Self, ParseError> {
+        let (item, flags) = arguments.split_first().ok_or(ParseError {})?;
+        let item = item.to_uppercase();
+        let (operation, item) = match item.as_bytes().first() {
+            Some(b'+') => (Operation::Add, &item[1..]),
+            Some(b'-') => (Operation::Remove, &item[1..]),
+            _ => (Operation::Replace, &item[..]),
+        };
+        let silent = match item {
+            "FLAGS" => false,
+            "FLAGS.SILENT" => true,
+            _ => return Err(ParseError {}),
+        };
+        let list = flags.join(" ");
+        let list = match list.strip_prefix('(') {
+            Some(rest) => rest.strip_suffix(')').ok_or(ParseError {})?,
+            None => list.as_str(),
+        };
+        let flags = list
+            .split_whitespace()
+            .map(|flag| Flag::normalize(flag).ok_or(ParseError {}))
+            .collect::<std::result::Result<Vec<String>, ParseError>>()?;
+        Ok(Self {
+            operation,
+            silent,
+            flags,
+        })
+    }
+
+    /// The flags of a message which had `current` once the update is applied.
+    fn apply(&self, current: &[String]) -> Vec<String> {
+        let named = |flag: &String| self.flags.iter().any(|f| f.eq_ignore_ascii_case(flag));
+        let mut flags: Vec<String> = match self.operation {
+            Operation::Replace => vec![],
+            Operation::Add => current.to_vec(),
+            Operation::Remove => current
+                .iter()
+                .filter(|flag| !named(*flag))
+                .cloned()
+                .collect(),
+        };
+        if self.operation != Operation::Remove {
+            for flag in &self.flags {
+                if !flags.iter().any(|f| f.eq_ignore_ascii_case(flag)) {
+                    flags.push(flag.clone());
+                }
+            }
+        }
+        flags
+    }
+}
+
+#[derive(Clone)]
+pub struct StoreHandler {
+    index: Arc<Box<dyn Index>>,
+    workers: Workers,
+}
+
+impl StoreHandler {
+    #[must_use]
+    pub fn new(index: Arc<Box<dyn Index>>) -> Self {
+        Self {
+            index,
+            workers: Workers::default(),
+        }
+    }
+    /// Runs requests on `workers`, shared with the other handlers of the server.
+    pub fn with_workers(mut self, workers: Workers) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    async fn store(&self, command: &Command, context: &Context) -> Vec<Response> {
+        let tag = command.tag();
+        let owner = match context.owner() {
+            Some(owner) => owner,
+            None => return vec![State::of(context).rejection(command)],
+        };
+        let folder = match context.folder() {
+            Some(folder) => folder,
+            None => return vec![State::of(context).rejection(command)],
+        };
+        if let Some(refusal) = read_only(&tag, context) {
+            return vec![refusal];
+        }
+        let arguments: Vec<String> = (1..command.num_args()).map(|i| command.arg(i)).collect();
+        let (sequence, update) = match (
+            SequenceSet::parse(&command.arg(0)),
+            Update::parse(&arguments),
+        ) {
+            (Ok(sequence), Ok(update)) => (sequence, update),
+            _ => {
+                return vec![Response::new(
+                    &tag,
+                    ResponseStatus::BAD,
+                    "invalid STORE arguments",
+                )]
+            }
+        };
+        let records = match self.index.list_messages(&owner, &folder).await {
+            Ok(records) => records,
+            Err(e) => return vec![mailbox_error(&tag, &e)],
+        };
+
+        let mut responses = vec![];
+        for number in sequence.resolve(records.len() as u32) {
+            let record = records[number as usize - 1].clone();
+            let (record, conflicted) = match self.flag(&owner, &folder, record, &update).await {
+                Ok(flagged) => flagged,
+                Err(e) => return vec![mailbox_error(&tag, &e)],
+            };
+            if !update.silent || conflicted {
+                responses.push(Response::untagged(&format!(
+                    "{} FETCH (FLAGS ({}))",
+                    number,
+                    record.flags.join(" ")
+                )));
+            }
+        }
+        responses.push(Response::new(&tag, ResponseStatus::OK, "STORE completed."));
+        responses
+    }
+
+    /// Applies `update` to the message of `record`, as it was read. Returns the message as
+    /// updated and whether another session had changed it since it was read.
+    async fn flag(
+        &self,
+        owner: &Owner,
+        folder: &str,
+        mut record: MessageRecord,
+        update: &Update,
+    ) -> std::result::Result<(MessageRecord, bool), MailboxError> {
+        let (mut attempts, mut conflicted) = (1, false);
+        loop {
+            let flags = update.apply(&record.flags);
+            let set = self
+                .index
+                .set_flags_unchanged_since(owner, folder, record.uid, record.modseq, flags)
+                .await;
+            match set {
+                Ok(record) => return Ok((record, conflicted)),
+                Err(MailboxError::Modified(..)) if attempts < MAX_ATTEMPTS => {
+                    attempts += 1;
+                    conflicted = true;
+                    record = self.index.get_message(owner, folder, record.uid).await?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl HandleCommand for StoreHandler {
+    fn name<'a>(&self) -> &'a str {
+        "STORE"
+    }
+    async fn validate<'a>(&self, command: &'a Command) -> Result<()> {
+        if command.command() != self.name() {
+            return Ok(());
+        }
+        if command.num_args() < 3 {
+            return Err(Box::new(ParseError {}));
+        }
+        Ok(())
+    }
+    async fn handle<'a>(
+        &self,
+        command: &'a Command,
+        context: &'a Context,
+    ) -> Result<Vec<Response>> {
+        Ok(self.store(command, context).await)
+    }
+}
+
+#[async_trait::async_trait]
+impl Handle for StoreHandler {
+    fn command<'a>(&self) -> &'a str {
+        "STORE"
+    }
+
+    async fn start(&mut self, mut requests: Receiver<Request>) -> Result<()> {
+        while let Some(mut request) = requests.next().await {
+            if self.validate(&request.command).await.is_err() {
+                request
+                    .responder
+                    .send(vec![Response::new(
+                        &request.command.tag(),
+                        ResponseStatus::BAD,
+                        "insufficient arguments",
+                    )])
+                    .await?;
+                continue;
+            }
+            let handler = self.clone();
+            let (span, timings) = (request.span.clone(), request.timings.clone());
+            self.workers
+                .spawn(
+                    timings.scope(
+                        async move {
+                            let responses = handler.store(&request.command, &request.context).await;
+                            let _ = request.responder.send(responses).await;
+                        }
+                        .instrument(span),
+                    ),
+                )
+                .await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use async_std::path::PathBuf;
+
+    use super::{StoreHandler, Update};
+    use crate::auth::User;
+    use crate::connection::Context;
+    use crate::handlers::HandleCommand;
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::message::MessageRecord;
+    use crate::index::{Index, Owner, Permission};
+    use crate::server::{Command, Response, ResponseStatus};
+
+    fn selected() -> Context {
+        Context::of(
+            Some(User::new("username", "password")),
+            Some(PathBuf::from("INBOX")),
+        )
+    }
+
+    async fn store_handler() -> (StoreHandler, Arc<Box<dyn Index>>) {
+        let index: Arc<Box<dyn Index>> = Arc::new(Box::new(InMemoryIndex::new()));
+        let owner = Owner::new("username");
+        for uid in [1, 2] {
+            let record = MessageRecord::new(uid, 10, SystemTime::now())
+                .with_flags(vec!["\\Seen".to_string()]);
+            index.add_message(&owner, "INBOX", record).await.unwrap();
+        }
+        (StoreHandler::new(index.clone()), index)
+    }
+
+    #[async_std::test]
+    async fn test_store_keywords() {
+        let (handler, index) = store_handler().await;
+        let command = Command::new("a1", "STORE", vec!["1:*", "+FLAGS", "(\\flagged", "$Work)"]);
+        assert!(handler.validate(&command).await.is_ok());
+        let response = handler.handle(&command, &selected()).await.unwrap();
+        assert_eq!(
+            response,
+            vec![
+                Response::untagged("1 FETCH (FLAGS (\\Seen \\Flagged $Work))"),
+                Response::untagged("2 FETCH (FLAGS (\\Seen \\Flagged $Work))"),
+                Response::new("a1", ResponseStatus::OK, "STORE completed."),
+            ]
+        );
+
+        let command = Command::new("a2", "STORE", vec!["2", "-FLAGS.SILENT", "\\Seen", "$work"]);
+        let response = handler.handle(&command, &selected()).await.unwrap();
+        assert_eq!(
+            response,
+            vec![Response::new("a2", ResponseStatus::OK, "STORE completed.")]
+        );
+        let owner = Owner::new("username");
+        let record = index.get_message(&owner, "INBOX", 2).await.unwrap();
+        assert_eq!(record.flags, vec!["\\Flagged".to_string()]);
+
+        let inbox = index
+            .get_mailbox(&owner, "INBOX", Permission::ReadWrite)
+            .await
+            .unwrap();
+        assert!(inbox.flags.iter().any(|flag| flag.value == "$Work"));
+    }
+
+    #[async_std::test]
+    async fn test_store_reapplies_after_conflict() {
+        let (handler, index) = store_handler().await;
+        let owner = Owner::new("username");
+        let read = index.get_message(&owner, "INBOX", 1).await.unwrap();
+        // Another session flags the message after this one has read it.
+        let answered = vec!["\\Seen".to_string(), "\\Answered".to_string()];
+        index.set_flags(&owner, "INBOX", 1, answered).await.unwrap();
+
+        let arguments = ["+FLAGS.SILENT".to_string(), "\\Flagged".to_string()];
+        let update = Update::parse(&arguments).unwrap();
+        let (record, conflicted) = handler.flag(&owner, "INBOX", read, &update).await.unwrap();
+        assert!(conflicted);
+        assert_eq!(record.flags, vec!["\\Seen", "\\Answered", "\\Flagged"]);
+
+        let read = index.get_message(&owner, "INBOX", 2).await.unwrap();
+        let (_, conflicted) = handler.flag(&owner, "INBOX", read, &update).await.unwrap();
+        assert!(!conflicted);
+    }
+
+    #[async_std::test]
+    async fn test_store_refused_when_read_only() {
+        let (handler, index) = store_handler().await;
+        let command = Command::new("a1", "STORE", vec!["1", "+FLAGS", "(\\Deleted)"]);
+        let examined = selected().with_permission(Permission::ReadOnly);
+        let response = handler.handle(&command, &examined).await.unwrap();
+        assert_eq!(
+            response,
+            vec![Response::new(
+                "a1",
+                ResponseStatus::NO,
+                "[READ-ONLY] The mailbox is open read-only."
+            )]
+        );
+        let record = index.get_message(&Owner::new("username"), "INBOX", 1).await.unwrap();
+        assert_eq!(record.flags, vec!["\\Seen".to_string()]);
+    }
+
+    #[async_std::test]
+    async fn test_store_rejects_invalid_flags() {
+        let (handler, _) = store_handler().await;
+        for arguments in [
+            vec!["1", "FLAGS", "(\\Recent)"],
+            vec!["1", "FLAGS", "(bad*keyword)"],
+            vec!["1", "LABELS", "(x)"],
+        ] {
+            let command = Command::new("a1", "STORE", arguments);
+            let response = handler.handle(&command, &selected()).await.unwrap();
+            assert_eq!(
+                response,
+                vec![Response::new(
+                    "a1",
+                    ResponseStatus::BAD,
+                    "invalid STORE arguments"
+                )]
+            );
+        }
+    }
+}
@@ -0,0 +1,489 @@
+//! Exporting and deleting whole accounts, for requests from their owners.
+//!
+//! `export_account` writes every mailbox of a user to a single tar archive, as a Maildir++
+//! tree or as one mboxrd file per mailbox, together with a `manifest.json` listing each
+//! message with its UID, flags, internal date, size and the file holding it. `delete_account`
+//! removes the user, their aliases, their messages from the data store and their records from
+//! the index. Both report progress after each message, as `reindex` does.
+
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_std::fs::{self, File};
+use async_std::io::BufWriter;
+use futures::{AsyncWrite, AsyncWriteExt};
+use serde::Serialize;
+
+use super::transfer::{maildir_name, mbox_message};
+use super::{Index, MailboxError, Owner};
+use crate::auth::UserStore;
+use crate::store::{DataStore, MessageMetadata, StoreError};
+use crate::util::{rfc3339, Result};
+
+const INBOX: &str = "INBOX";
+const BLOCK: usize = 512;
+/// How many messages are expunged at once when deleting an account.
+const BATCH: usize = 256;
+
+/// How the mailboxes are laid out in an account archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    /// A Maildir++ tree under `Maildir/`, as `export_maildir` writes it.
+    Maildir,
+    /// One mboxrd file per mailbox under `mbox/`, as `export_mbox` writes them.
+    Mbox,
+}
+
+/// Reported after each message is exported or deleted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Progress {
+    pub mailbox: String,
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountSummary {
+    pub mailboxes: usize,
+    pub messages: usize,
+    /// The size of the messages, before any framing the archive adds.
+    pub bytes: u64,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    user: &'a str,
+    exported_at: String,
+    format: ArchiveFormat,
+    mailboxes: Vec<MailboxEntry>,
+}
+
+#[derive(Serialize)]
+struct MailboxEntry {
+    name: String,
+    messages: Vec<MessageEntry>,
+}
+
+#[derive(Serialize)]
+struct MessageEntry {
+    uid: u32,
+    flags: Vec<String>,
+    internal_date: String,
+    size: u64,
+    /// The file in the archive holding the message. For mbox archives this is the mailbox's
+    /// file, in which messages appear in the order of the manifest.
+    file: String,
+}
+
+impl MessageEntry {
+    fn new(message: &MessageMetadata, file: String) -> Self {
+        Self {
+            uid: message.uid,
+            flags: message.flags.clone(),
+            internal_date: rfc3339(message.internal_date),
+            size: message.size,
+            file,
+        }
+    }
+}
+
+/// Writes a POSIX ustar archive.
+struct Tarball<W> {
+    output: W,
+}
+
+impl<W: AsyncWrite + Unpin> Tarball<W> {
+    fn new(output: W) -> Self {
+        Self { output }
+    }
+
+    async fn directory(&mut self, path: &str, modified: SystemTime) -> io::Result<()> {
+        let header = header(&format!("{}/", path), 0, modified, b'5')?;
+        self.output.write_all(&header).await
+    }
+
+    async fn file(&mut self, path: &str, data: &[u8], modified: SystemTime) -> io::Result<()> {
+        let header = header(path, data.len() as u64, modified, b'0')?;
+        self.output.write_all(&header).await?;
+        self.output.write_all(data).await?;
+        self.pad(data.len() as u64).await
+    }
+
+    /// Writes the header for a file of `size` bytes whose contents the caller writes to
+    /// `output` next, followed by a call to `pad`.
+    async fn start_file(&mut self, path: &str, size: u64, modified: SystemTime) -> io::Result<()> {
+        let header = header(path, size, modified, b'0')?;
+        self.output.write_all(&header).await
+    }
+
+    /// Fills the last block of a file of `size` bytes.
+    async fn pad(&mut self, size: u64) -> io::Result<()> {
+        let remainder = size as usize % BLOCK;
+        if remainder > 0 {
+            self.output.write_all(&[0; BLOCK][remainder..]).await?;
+        }
+        Ok(())
+    }
+
+    /// Ends the archive with two empty blocks.
+    async fn finish(mut self) -> io::Result<()> {
+        self.output.write_all(&[0; 2 * BLOCK]).await?;
+        self.output.flush().await
+    }
+}
+
+/// A ustar header. Paths longer than the name field are split at a `/` into the prefix field.
+fn header(path: &str, size: u64, modified: SystemTime, kind: u8) -> io::Result<[u8; BLOCK]> {
+    let (prefix, name) = match path.len() {
+        0..=100 => ("", path),
+        _ => path
+            .char_indices()
+            .filter(|(at, c)| *c == '/' && *at <= 155 && path.len() - at - 1 <= 100)
+            .map(|(at, _)| (&path[..at], &path[at + 1..]))
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is too long for a tar archive", path),
+                )
+            })?,
+    };
+    let seconds = modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mode: &[u8] = match kind {
+        b'5' => b"0000755\0",
+        _ => b"0000644\0",
+    };
+    let mut header = [0; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(mode);
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+    header[136..148].copy_from_slice(format!("{:011o}\0", seconds).as_bytes());
+    header[148..156].copy_from_slice(b"        ");
+    header[156] = kind;
+    header[257..265].copy_from_slice(b"ustar\x0000");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    let checksum: u32 = header.iter().map(|byte| *byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    Ok(header)
+}
+
+/// Exports every mailbox `owner` has in `store` to a tar archive at `path`, replacing any file
+/// there, in the given `format` and with a `manifest.json` describing the messages.
+///
+/// The archive is written as the messages are read, except that each mbox file is assembled
+/// in a scratch file next to `path` first, since its size has to be known before it is added.
+pub async fn export_account<F>(
+    store: &dyn DataStore,
+    owner: &Owner,
+    format: ArchiveFormat,
+    path: &Path,
+    mut progress: F,
+) -> Result<AccountSummary>
+where
+    F: FnMut(&Progress),
+{
+    let exported_at = SystemTime::now();
+    let mut tarball = Tarball::new(BufWriter::new(File::create(path).await?));
+    let scratch = path.with_extension("mbox.part");
+    let mut summary = AccountSummary::default();
+    let mut manifest = Manifest {
+        user: owner.name(),
+        exported_at: rfc3339(exported_at),
+        format,
+        mailboxes: vec![],
+    };
+    if format == ArchiveFormat::Maildir {
+        tarball.directory("Maildir", exported_at).await?;
+    }
+    for mailbox in store.mailboxes(owner.name()).await? {
+        let messages = store.list(owner.name(), &mailbox).await?;
+        let total = messages.len();
+        let mut entries = Vec::with_capacity(total);
+        match format {
+            ArchiveFormat::Maildir => {
+                let folder = match mailbox.as_str() {
+                    INBOX => "Maildir".to_string(),
+                    _ => format!("Maildir/.{}", mailbox),
+                };
+                if mailbox != INBOX {
+                    tarball.directory(&folder, exported_at).await?;
+                }
+                for directory in ["cur", "new", "tmp"] {
+                    let directory = format!("{}/{}", folder, directory);
+                    tarball.directory(&directory, exported_at).await?;
+                }
+                for (position, message) in messages.iter().enumerate() {
+                    let body = store.fetch(owner.name(), &mailbox, message.uid).await?;
+                    let file = format!("{}/cur/{}", folder, maildir_name(message));
+                    tarball.file(&file, &body, message.internal_date).await?;
+                    entries.push(MessageEntry::new(message, file));
+                    summary.bytes += body.len() as u64;
+                    progress(&Progress {
+                        mailbox: mailbox.clone(),
+                        done: position + 1,
+                        total,
+                    });
+                }
+            }
+            ArchiveFormat::Mbox => {
+                let file = format!("mbox/{}.mbox", mailbox);
+                let mut output = BufWriter::new(File::create(&scratch).await?);
+                for (position, message) in messages.iter().enumerate() {
+                    let body = store.fetch(owner.name(), &mailbox, message.uid).await?;
+                    output.write_all(&mbox_message(message, &body)).await?;
+                    entries.push(MessageEntry::new(message, file.clone()));
+                    summary.bytes += body.len() as u64;
+                    progress(&Progress {
+                        mailbox: mailbox.clone(),
+                        done: position + 1,
+                        total,
+                    });
+                }
+                output.flush().await?;
+                drop(output);
+                let size = fs::metadata(&scratch).await?.len();
+                tarball.start_file(&file, size, exported_at).await?;
+                futures::io::copy(File::open(&scratch).await?, &mut tarball.output).await?;
+                tarball.pad(size).await?;
+                fs::remove_file(&scratch).await?;
+            }
+        }
+        manifest.mailboxes.push(MailboxEntry {
+            name: mailbox,
+            messages: entries,
+        });
+        summary.mailboxes += 1;
+        summary.messages += total;
+    }
+    let manifest = serde_json::to_vec_pretty(&manifest)?;
+    tarball
+        .file("manifest.json", &manifest, exported_at)
+        .await?;
+    tarball.finish().await?;
+    Ok(summary)
+}
+
+/// Irrevocably deletes the account of `owner`: the user and their aliases from `users`, every
+/// message they have in `store`, and their message records in `index`.
+///
+/// The user is removed first, so that they cannot log in and add mail while their messages are
+/// deleted, and a user who no longer exists is not an error, so a deletion which failed part
+/// of the way through can be run again to finish it. Neither the store nor the index can drop
+/// a mailbox, so the now empty mailbox records remain in the index. Blobs in an `ObjectStore`
+/// are reclaimed by the next run of its `BlobCollector`; other stores delete them as they
+/// expunge.
+pub async fn delete_account<F>(
+    store: &dyn DataStore,
+    index: &dyn Index,
+    users: &dyn UserStore,
+    owner: &Owner,
+    mut progress: F,
+) -> Result<AccountSummary>
+where
+    F: FnMut(&Progress),
+{
+    if users.get(owner.name()).await?.is_some() {
+        users.remove(owner.name()).await?;
+    }
+    let mut mailboxes = store.mailboxes(owner.name()).await?;
+    if !mailboxes.iter().any(|mailbox| mailbox == INBOX) {
+        mailboxes.push(INBOX.to_string());
+    }
+    let mut summary = AccountSummary::default();
+    for mailbox in mailboxes {
+        let messages = match store.list(owner.name(), &mailbox).await {
+            Err(StoreError::MailboxDoesNotExist(_)) => vec![],
+            messages => messages?,
+        };
+        let total = messages.len();
+        let mut done = 0;
+        for batch in messages.chunks(BATCH) {
+            let uids: Vec<u32> = batch.iter().map(|message| message.uid).collect();
+            store.expunge(owner.name(), &mailbox, &uids).await?;
+            done += batch.len();
+            summary.bytes += batch.iter().map(|message| message.size).sum::<u64>();
+            progress(&Progress {
+                mailbox: mailbox.clone(),
+                done,
+                total,
+            });
+        }
+        // Records the store had already lost are removed along with the rest.
+        match index.list_messages(owner, &mailbox).await {
+            Err(MailboxError::DoesNotExist(_)) => {}
+            Err(e) => return Err(Box::new(e)),
+            Ok(records) => {
+                let uids: Vec<u32> = records.iter().map(|record| record.uid).collect();
+                index.remove_messages(owner, &mailbox, &uids).await?;
+            }
+        }
+        if total > 0 {
+            summary.mailboxes += 1;
+            summary.messages += total;
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{delete_account, export_account, ArchiveFormat, Progress};
+    use crate::auth::inmemory::InMemoryUserStore;
+    use crate::auth::{User, UserStore};
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::message::MessageRecord;
+    use crate::index::{Index, Owner};
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message};
+
+    /// The files of a tar archive, by path.
+    fn untar(archive: &[u8]) -> HashMap<String, Vec<u8>> {
+        let field = |block: &[u8], range: std::ops::Range<usize>| {
+            let bytes = &block[range];
+            let end = bytes
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap_or(bytes.len());
+            String::from_utf8(bytes[..end].to_vec()).unwrap()
+        };
+        let mut files = HashMap::new();
+        let mut blocks = archive.chunks(512);
+        while let Some(block) = blocks.next() {
+            if block.iter().all(|byte| *byte == 0) {
+                break;
+            }
+            let mut sum: u32 = block.iter().map(|byte| *byte as u32).sum();
+            sum -= block[148..156].iter().map(|byte| *byte as u32).sum::<u32>();
+            sum += 8 * b' ' as u32;
+            let checksum = u32::from_str_radix(field(block, 148..154).trim(), 8).unwrap();
+            assert_eq!(checksum, sum);
+            let size = usize::from_str_radix(&field(block, 124..135), 8).unwrap();
+            let (prefix, name) = (field(block, 345..500), field(block, 0..100));
+            let path = match prefix.is_empty() {
+                true => name,
+                false => format!("{}/{}", prefix, name),
+            };
+            let mut data = vec![];
+            for _ in 0..(size + 511) / 512 {
+                data.extend_from_slice(blocks.next().unwrap());
+            }
+            data.truncate(size);
+            files.insert(path, data);
+        }
+        files
+    }
+
+    async fn account() -> InMemoryStore {
+        let store = InMemoryStore::new();
+        store
+            .append(
+                "me",
+                "INBOX",
+                Message::new(b"Subject: hello\r\n\r\nbody\r\n").with_flags(vec!["\\Seen"]),
+            )
+            .await
+            .unwrap();
+        let long = "Projects/".repeat(14) + "Old";
+        store
+            .append("me", &long, Message::new(b"Subject: old\r\n\r\n"))
+            .await
+            .unwrap();
+        store
+            .append("you", "INBOX", Message::new(b"not mine"))
+            .await
+            .unwrap();
+        store
+    }
+
+    #[async_std::test]
+    async fn test_export_account() {
+        let store = account().await;
+        let me = Owner::new("me");
+        let path =
+            std::env::temp_dir().join(format!("treasurmap-account-{}.tar", std::process::id()));
+
+        let mut reported = vec![];
+        let summary = export_account(&store, &me, ArchiveFormat::Maildir, &path, |progress| {
+            reported.push(progress.clone())
+        })
+        .await
+        .unwrap();
+        assert_eq!((summary.mailboxes, summary.messages), (2, 2));
+        assert_eq!(
+            reported[0],
+            Progress {
+                mailbox: "INBOX".to_string(),
+                done: 1,
+                total: 1
+            }
+        );
+        let files = untar(&std::fs::read(&path).unwrap());
+        let manifest: serde_json::Value = serde_json::from_slice(&files["manifest.json"]).unwrap();
+        assert_eq!(manifest["user"], "me");
+        assert_eq!(manifest["format"], "maildir");
+        let inbox = &manifest["mailboxes"][0]["messages"][0];
+        assert_eq!(inbox["flags"][0], "\\Seen");
+        let file = inbox["file"].as_str().unwrap();
+        assert!(file.starts_with("Maildir/cur/") && file.ends_with(":2,S"));
+        assert_eq!(files[file], b"Subject: hello\r\n\r\nbody\r\n");
+        // The path of the deeply nested mailbox needs the prefix field.
+        let old = manifest["mailboxes"][1]["messages"][0]["file"]
+            .as_str()
+            .unwrap();
+        assert!(old.len() > 100);
+        assert_eq!(files[old], b"Subject: old\r\n\r\n");
+
+        export_account(&store, &me, ArchiveFormat::Mbox, &path, |_| {})
+            .await
+            .unwrap();
+        let files = untar(&std::fs::read(&path).unwrap());
+        let inbox = String::from_utf8_lossy(&files["mbox/INBOX.mbox"]).to_string();
+        assert!(inbox.starts_with("From MAILER-DAEMON "));
+        assert!(inbox.ends_with("\nSubject: hello\nStatus: RO\n\nbody\n\n"));
+        assert!(!inbox.contains("not mine"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_delete_account() {
+        let store = account().await;
+        let index = InMemoryIndex::new();
+        let me = Owner::new("me");
+        let record = MessageRecord::new(1, 24, std::time::SystemTime::now());
+        index.add_message(&me, "INBOX", record).await.unwrap();
+        let users = InMemoryUserStore::new();
+        users.add(User::new("me", "secret")).await.unwrap();
+        users.add(User::new("you", "secret")).await.unwrap();
+        users.add_alias("me@example.com", "me").await.unwrap();
+
+        let mut reported = 0;
+        let summary = delete_account(&store, &index, &users, &me, |_| reported += 1)
+            .await
+            .unwrap();
+        assert_eq!((summary.mailboxes, summary.messages, reported), (2, 2, 2));
+        assert!(users.get("me").await.unwrap().is_none());
+        assert!(users.get("you").await.unwrap().is_some());
+        assert!(store
+            .list("me", "INBOX")
+            .await
+            .unwrap_or_default()
+            .is_empty());
+        assert!(index.list_messages(&me, "INBOX").await.unwrap().is_empty());
+        assert_eq!(store.list("you", "INBOX").await.unwrap().len(), 1);
+
+        // Running it again finds nothing left to delete.
+        let summary = delete_account(&store, &index, &users, &me, |_| {})
+            .await
+            .unwrap();
+        assert_eq!(summary.messages, 0);
+    }
+}
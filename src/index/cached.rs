@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::journal::JournalEntry;
+use super::message::{MessageQuery, MessageRecord};
+use super::{Index, Mailbox, MailboxError, Owner, Permission};
+use crate::store::name::normalize;
+use crate::util::Receiver;
+
+type MailboxKey = (Owner, String);
+
+fn key(owner: &Owner, name: &str) -> MailboxKey {
+    (owner.clone(), normalize(name))
+}
+
+/// What is known of a mailbox, kept until its journal records a change.
+struct Entry {
+    /// The mailbox as opened with each permission asked for, since the backend decides what
+    /// each owner may open it for.
+    mailboxes: Vec<Mailbox>,
+    messages: Option<Vec<MessageRecord>>,
+    changes: Receiver<JournalEntry>,
+    used: u64,
+}
+
+impl Entry {
+    /// The mailbox counts as one record, with one more for each message.
+    fn size(&self) -> usize {
+        1 + self.messages.as_ref().map_or(0, Vec::len)
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<MailboxKey, Entry>,
+    size: usize,
+    clock: u64,
+}
+
+impl Cache {
+    /// The entry of a mailbox, unless the mailbox has changed since it was cached.
+    fn get(&mut self, key: &MailboxKey) -> Option<&Entry> {
+        // Any journal entry, or the journal going away, means the entry is out of date.
+        let stale = self.entries.get_mut(key)?.changes.try_next().is_ok();
+        if stale {
+            self.remove(key);
+            return None;
+        }
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.used = self.clock;
+        Some(entry)
+    }
+
+    fn remove(&mut self, key: &MailboxKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.size -= entry.size();
+        }
+    }
+
+    /// Updates the entry of a mailbox, creating it if `changes` is given, then evicts the
+    /// least recently used entries until the cache is within `capacity`.
+    fn update(
+        &mut self,
+        key: MailboxKey,
+        changes: Option<Receiver<JournalEntry>>,
+        capacity: usize,
+        fill: impl FnOnce(&mut Entry),
+    ) {
+        if !self.entries.contains_key(&key) {
+            let changes = match changes {
+                Some(changes) => changes,
+                None => return,
+            };
+            let entry = Entry {
+                mailboxes: vec![],
+                messages: None,
+                changes,
+                used: 0,
+            };
+            self.size += entry.size();
+            self.entries.insert(key.clone(), entry);
+        }
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(&key) {
+            self.size -= entry.size();
+            fill(entry);
+            entry.used = self.clock;
+            self.size += entry.size();
+        }
+        while self.size > capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => self.remove(&oldest),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Keeps the mailboxes and message records of an index in memory, so that clients polling
+/// with SELECT, STATUS or NOOP are answered without asking the backend each time.
+///
+/// The cached view of a mailbox is dropped as soon as its change journal records anything,
+/// whether the change was made through the cache or not, so the backend must publish changes
+/// to `Index::watch` for every writer sharing it. At most `capacity` records are kept, counting
+/// each mailbox and each of its cached messages, and the least recently used mailboxes are
+/// dropped to make room.
+pub struct CachedIndex {
+    inner: Box<dyn Index>,
+    capacity: usize,
+    cache: Mutex<Cache>,
+}
+
+impl CachedIndex {
+    pub fn new(inner: Box<dyn Index>, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+
+    fn with_cache<T>(&self, operation: impl FnOnce(&mut Cache) -> T) -> T {
+        match self.cache.lock() {
+            Ok(mut cache) => operation(&mut cache),
+            Err(poisoned) => operation(&mut poisoned.into_inner()),
+        }
+    }
+
+    /// Starts watching a mailbox which is not cached yet, so that changes made while it is
+    /// being read from the backend are not missed. Nothing is cached when it cannot be watched.
+    async fn watch_uncached(
+        &self,
+        owner: &Owner,
+        name: &str,
+        key: &MailboxKey,
+    ) -> Option<Receiver<JournalEntry>> {
+        if self.with_cache(|cache| cache.entries.contains_key(key)) {
+            return None;
+        }
+        self.inner.watch(owner, name).await.ok()
+    }
+
+    fn invalidate(&self, owner: &Owner, name: &str) {
+        self.with_cache(|cache| cache.remove(&key(owner, name)));
+    }
+}
+
+#[async_trait::async_trait]
+impl Index for CachedIndex {
+    async fn add_mailbox(&self, owner: &Owner, mailbox: Mailbox) -> Result<(), MailboxError> {
+        let name = mailbox.name.to_string_lossy().to_string();
+        let added = self.inner.add_mailbox(owner, mailbox).await;
+        self.invalidate(owner, &name);
+        added
+    }
+    async fn get_mailbox(
+        &self,
+        owner: &Owner,
+        name: &str,
+        permission: Permission,
+    ) -> Result<Mailbox, MailboxError> {
+        let key = key(owner, name);
+        let cached = self.with_cache(|cache| {
+            cache.get(&key).and_then(|entry| {
+                entry
+                    .mailboxes
+                    .iter()
+                    .find(|mailbox| mailbox.permission == permission)
+                    .cloned()
+            })
+        });
+        if let Some(mailbox) = cached {
+            return Ok(mailbox);
+        }
+        let changes = self.watch_uncached(owner, name, &key).await;
+        let mailbox = self.inner.get_mailbox(owner, name, permission).await?;
+        let copy = mailbox.clone();
+        self.with_cache(|cache| {
+            cache.update(key, changes, self.capacity, |entry| {
+                entry
+                    .mailboxes
+                    .retain(|mailbox| mailbox.permission != permission);
+                entry.mailboxes.push(copy)
+            })
+        });
+        Ok(mailbox)
+    }
+    async fn add_message(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        message: MessageRecord,
+    ) -> Result<MessageRecord, MailboxError> {
+        let added = self.inner.add_message(owner, mailbox, message).await;
+        self.invalidate(owner, mailbox);
+        added
+    }
+    async fn list_messages(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+    ) -> Result<Vec<MessageRecord>, MailboxError> {
+        let key = key(owner, mailbox);
+        let cached =
+            self.with_cache(|cache| cache.get(&key).and_then(|entry| entry.messages.clone()));
+        if let Some(messages) = cached {
+            return Ok(messages);
+        }
+        let changes = self.watch_uncached(owner, mailbox, &key).await;
+        let messages = self.inner.list_messages(owner, mailbox).await?;
+        if messages.len() < self.capacity {
+            let copy = messages.clone();
+            self.with_cache(|cache| {
+                cache.update(key, changes, self.capacity, |entry| {
+                    entry.messages = Some(copy)
+                })
+            });
+        }
+        Ok(messages)
+    }
+    async fn set_flags(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+        flags: Vec<String>,
+    ) -> Result<MessageRecord, MailboxError> {
+        let set = self.inner.set_flags(owner, mailbox, uid, flags).await;
+        self.invalidate(owner, mailbox);
+        set
+    }
+    async fn set_flags_unchanged_since(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+        modseq: u64,
+        flags: Vec<String>,
+    ) -> Result<MessageRecord, MailboxError> {
+        let set = self
+            .inner
+            .set_flags_unchanged_since(owner, mailbox, uid, modseq, flags)
+            .await;
+        self.invalidate(owner, mailbox);
+        set
+    }
+    async fn remove_messages(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>, MailboxError> {
+        let removed = self.inner.remove_messages(owner, mailbox, uids).await;
+        self.invalidate(owner, mailbox);
+        removed
+    }
+    async fn changes_since(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        modseq: u64,
+    ) -> Result<Vec<JournalEntry>, MailboxError> {
+        self.inner.changes_since(owner, mailbox, modseq).await
+    }
+    async fn watch(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+    ) -> Result<Receiver<JournalEntry>, MailboxError> {
+        self.inner.watch(owner, mailbox).await
+    }
+    async fn compact_journals(&self) -> Result<(), MailboxError> {
+        self.inner.compact_journals().await
+    }
+    async fn get_message(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<MessageRecord, MailboxError> {
+        let key = key(owner, mailbox);
+        let cached = self.with_cache(|cache| {
+            cache
+                .get(&key)
+                .and_then(|entry| entry.messages.as_ref())
+                .map(|messages| messages.iter().find(|message| message.uid == uid).cloned())
+        });
+        match cached {
+            Some(message) => {
+                message.ok_or_else(|| MailboxError::MessageDoesNotExist(mailbox.to_string(), uid))
+            }
+            None => self.inner.get_message(owner, mailbox, uid).await,
+        }
+    }
+    async fn query_messages(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        query: &MessageQuery,
+    ) -> Result<Vec<MessageRecord>, MailboxError> {
+        let key = key(owner, mailbox);
+        let cached = self.with_cache(|cache| {
+            cache
+                .get(&key)
+                .and_then(|entry| entry.messages.as_ref())
+                .map(|messages| {
+                    messages
+                        .iter()
+                        .filter(|message| query.matches(message))
+                        .cloned()
+                        .collect()
+                })
+        });
+        match cached {
+            Some(messages) => Ok(messages),
+            None => self.inner.query_messages(owner, mailbox, query).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use super::CachedIndex;
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::message::MessageRecord;
+    use crate::index::metered::MeteredIndex;
+    use crate::index::{Index, Owner, Permission};
+    use crate::metrics::Metrics;
+
+    #[async_std::test]
+    async fn test_cached_until_changed() {
+        let metrics = Arc::new(Metrics::new());
+        let backend = Arc::new(InMemoryIndex::new());
+        let metered = MeteredIndex::new(Box::new(backend.clone()), metrics.clone());
+        let index = CachedIndex::new(Box::new(metered), 100);
+        let owner = Owner::new("username");
+
+        for _ in 0..3 {
+            let inbox = index
+                .get_mailbox(&owner, "inbox", Permission::ReadOnly)
+                .await
+                .unwrap();
+            assert_eq!(inbox.count, 0);
+            assert!(index
+                .list_messages(&owner, "INBOX")
+                .await
+                .unwrap()
+                .is_empty());
+        }
+        assert_eq!(metrics.mailbox_operations("get_mailbox"), 1);
+        assert_eq!(metrics.mailbox_operations("list_messages"), 1);
+
+        // A change made by another writer of the backend reaches the cache through the journal.
+        backend
+            .add_message(
+                &owner,
+                "INBOX",
+                MessageRecord::new(1, 10, SystemTime::now()),
+            )
+            .await
+            .unwrap();
+        let inbox = index
+            .get_mailbox(&owner, "INBOX", Permission::ReadWrite)
+            .await
+            .unwrap();
+        assert_eq!(inbox.count, 1);
+        assert_eq!(metrics.mailbox_operations("get_mailbox"), 2);
+        assert_eq!(index.list_messages(&owner, "INBOX").await.unwrap().len(), 1);
+        assert_eq!(index.get_message(&owner, "INBOX", 1).await.unwrap().uid, 1);
+        assert_eq!(metrics.mailbox_operations("list_messages"), 2);
+        assert_eq!(metrics.mailbox_operations("get_message"), 0);
+
+        index
+            .set_flags(&owner, "INBOX", 1, vec!["\\Seen".to_string()])
+            .await
+            .unwrap();
+        let message = index.get_message(&owner, "INBOX", 1).await.unwrap();
+        assert_eq!(message.flags, vec!["\\Seen".to_string()]);
+    }
+
+    #[async_std::test]
+    async fn test_least_recently_used_evicted() {
+        let metrics = Arc::new(Metrics::new());
+        let metered = MeteredIndex::new(Box::new(InMemoryIndex::new()), metrics.clone());
+        let index = CachedIndex::new(Box::new(metered), 2);
+        let (alice, bob, carol) = (Owner::new("alice"), Owner::new("bob"), Owner::new("carol"));
+        for owner in [&alice, &bob, &alice, &carol, &alice, &bob] {
+            index
+                .get_mailbox(owner, "INBOX", Permission::ReadOnly)
+                .await
+                .unwrap();
+        }
+        // Bob was evicted to make room for Carol, while Alice kept being used.
+        assert_eq!(metrics.mailbox_operations("get_mailbox"), 4);
+    }
+
+    #[async_std::test]
+    async fn test_cached_by_permission() {
+        let metrics = Arc::new(Metrics::new());
+        let metered = MeteredIndex::new(Box::new(InMemoryIndex::new()), metrics.clone());
+        let index = CachedIndex::new(Box::new(metered), 100);
+        let owner = Owner::new("username");
+        for permission in [
+            Permission::ReadOnly,
+            Permission::ReadOnly,
+            Permission::ReadWrite,
+        ] {
+            let inbox = index
+                .get_mailbox(&owner, "INBOX", permission)
+                .await
+                .unwrap();
+            assert_eq!(inbox.permission, permission);
+        }
+        // Opening it for writing is left to the backend, even with a read-only view cached.
+        assert_eq!(metrics.mailbox_operations("get_mailbox"), 2);
+        let inbox = index
+            .get_mailbox(&owner, "INBOX", Permission::ReadWrite)
+            .await
+            .unwrap();
+        assert_eq!(inbox.permission, Permission::ReadWrite);
+        assert_eq!(metrics.mailbox_operations("get_mailbox"), 2);
+    }
+}
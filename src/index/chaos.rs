@@ -0,0 +1,216 @@
+use crate::testing::{Fault, Faults};
+use crate::util::Receiver;
+
+use super::journal::JournalEntry;
+use super::message::{MessageQuery, MessageRecord};
+use super::{Index, Mailbox, MailboxError, Owner, Permission};
+
+fn injected(operation: &str) -> MailboxError {
+    MailboxError::Storage(format!("injected failure in {}", operation))
+}
+
+/// Makes the calls to an index fail, stall or stop part way as `faults` says, to test how the
+/// server copes with a misbehaving backend.
+///
+/// A partial `remove_messages` removes the first half of the messages before failing, and
+/// other partial writes are made in full but reported as failed, as when a backend commits
+/// a change and then loses the connection before answering. Reads fail outright.
+pub struct ChaosIndex {
+    inner: Box<dyn Index>,
+    faults: Faults,
+}
+
+impl ChaosIndex {
+    pub fn new(inner: Box<dyn Index>, faults: Faults) -> Self {
+        Self { inner, faults }
+    }
+
+    async fn check(&self, operation: &str) -> Result<(), MailboxError> {
+        match self.faults.inject(operation).await {
+            Some(..) => Err(injected(operation)),
+            None => Ok(()),
+        }
+    }
+
+    /// Makes a write with `write`, which the fault may stop from being made or report as
+    /// failed once made.
+    async fn write<T, F>(&self, operation: &str, write: F) -> Result<T, MailboxError>
+    where
+        F: std::future::Future<Output = Result<T, MailboxError>>,
+    {
+        match self.faults.inject(operation).await {
+            Some(Fault::Error) => Err(injected(operation)),
+            Some(Fault::Partial) => {
+                write.await?;
+                Err(injected(operation))
+            }
+            None => write.await,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Index for ChaosIndex {
+    async fn add_mailbox(&self, owner: &Owner, mailbox: Mailbox) -> Result<(), MailboxError> {
+        self.write("add_mailbox", self.inner.add_mailbox(owner, mailbox))
+            .await
+    }
+    async fn get_mailbox(
+        &self,
+        owner: &Owner,
+        name: &str,
+        permission: Permission,
+    ) -> Result<Mailbox, MailboxError> {
+        self.check("get_mailbox").await?;
+        self.inner.get_mailbox(owner, name, permission).await
+    }
+    async fn add_message(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        message: MessageRecord,
+    ) -> Result<MessageRecord, MailboxError> {
+        self.write(
+            "add_message",
+            self.inner.add_message(owner, mailbox, message),
+        )
+        .await
+    }
+    async fn list_messages(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+    ) -> Result<Vec<MessageRecord>, MailboxError> {
+        self.check("list_messages").await?;
+        self.inner.list_messages(owner, mailbox).await
+    }
+    async fn set_flags(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+        flags: Vec<String>,
+    ) -> Result<MessageRecord, MailboxError> {
+        self.write(
+            "set_flags",
+            self.inner.set_flags(owner, mailbox, uid, flags),
+        )
+        .await
+    }
+    async fn set_flags_unchanged_since(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+        modseq: u64,
+        flags: Vec<String>,
+    ) -> Result<MessageRecord, MailboxError> {
+        self.write(
+            "set_flags",
+            self.inner
+                .set_flags_unchanged_since(owner, mailbox, uid, modseq, flags),
+        )
+        .await
+    }
+    async fn remove_messages(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>, MailboxError> {
+        match self.faults.inject("remove_messages").await {
+            Some(Fault::Error) => Err(injected("remove_messages")),
+            Some(Fault::Partial) => {
+                let half = &uids[..uids.len() / 2];
+                self.inner.remove_messages(owner, mailbox, half).await?;
+                Err(injected("remove_messages"))
+            }
+            None => self.inner.remove_messages(owner, mailbox, uids).await,
+        }
+    }
+    async fn changes_since(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        modseq: u64,
+    ) -> Result<Vec<JournalEntry>, MailboxError> {
+        self.check("changes_since").await?;
+        self.inner.changes_since(owner, mailbox, modseq).await
+    }
+    async fn watch(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+    ) -> Result<Receiver<JournalEntry>, MailboxError> {
+        self.check("watch").await?;
+        self.inner.watch(owner, mailbox).await
+    }
+    async fn compact_journals(&self) -> Result<(), MailboxError> {
+        self.write("compact_journals", self.inner.compact_journals())
+            .await
+    }
+    async fn get_message(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<MessageRecord, MailboxError> {
+        self.check("get_message").await?;
+        self.inner.get_message(owner, mailbox, uid).await
+    }
+    async fn query_messages(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        query: &MessageQuery,
+    ) -> Result<Vec<MessageRecord>, MailboxError> {
+        self.check("query_messages").await?;
+        self.inner.query_messages(owner, mailbox, query).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::ChaosIndex;
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::message::MessageRecord;
+    use crate::index::{Index, MailboxError, Owner};
+    use crate::testing::Faults;
+
+    #[async_std::test]
+    async fn test_partial_writes() {
+        let owner = Owner::new("user");
+        let faults = Faults::new()
+            .with_partial_writes(1.0)
+            .with_latency(Duration::from_millis(20))
+            .only(&["set_flags", "remove_messages"]);
+        let index = ChaosIndex::new(Box::new(InMemoryIndex::new()), faults.clone());
+        for uid in 1..=4 {
+            let record = MessageRecord::new(uid, 10, SystemTime::now());
+            index.add_message(&owner, "INBOX", record).await.unwrap();
+        }
+
+        let start = Instant::now();
+        let set = index
+            .set_flags(&owner, "INBOX", 1, vec!["\\Seen".to_string()])
+            .await;
+        assert!(matches!(set, Err(MailboxError::Storage(..))));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        // The flags were set despite the error.
+        let message = index.get_message(&owner, "INBOX", 1).await.unwrap();
+        assert_eq!(message.flags, vec!["\\Seen".to_string()]);
+
+        assert!(index
+            .remove_messages(&owner, "INBOX", &[1, 2, 3, 4])
+            .await
+            .is_err());
+        assert_eq!(faults.injected(), 2);
+
+        // Half of the messages were removed despite the error too.
+        let left = index.list_messages(&owner, "INBOX").await.unwrap();
+        let uids: Vec<u32> = left.iter().map(|record| record.uid).collect();
+        assert_eq!(uids, vec![3, 4]);
+    }
+}
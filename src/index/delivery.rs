@@ -0,0 +1,387 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use async_std::fs;
+use async_std::task::sleep;
+use futures::future;
+use futures::StreamExt;
+use log::{debug, warn};
+
+use super::message::{Envelope, MessageRecord};
+use super::{Index, Mailbox, MailboxError, Owner, Permission};
+use crate::shutdown::Shutdown;
+use crate::store::{DataStore, Message};
+use crate::util::Result;
+
+/// Picks up messages an external MDA, such as procmail or an LDA, delivers into Maildir `new/`
+/// directories, so that they appear in the server's mailboxes as soon as they arrive.
+///
+/// The root holds a Maildir++ directory per user: `<root>/<user>/new` for INBOX and
+/// `<root>/<user>/.<mailbox>/new` for the others. Each message found is appended to the data
+/// store, which assigns its UID, and recorded in the index, which tells sessions watching the
+/// mailbox. The file is then moved to `cur/`, as a mail client reading the Maildir would.
+#[derive(Debug, Clone)]
+pub struct MaildirDelivery {
+    root: PathBuf,
+    interval: Duration,
+}
+
+/// A `new/` directory and whose mailbox it delivers to.
+struct Maildrop {
+    owner: Owner,
+    mailbox: String,
+    directory: PathBuf,
+}
+
+impl MaildirDelivery {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            interval: Duration::from_secs(5),
+        }
+    }
+    /// How often the directories are scanned. On Linux, deliveries are noticed at once
+    /// through inotify and this only bounds how long new users and mailboxes take to be
+    /// watched.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Delivers every message waiting in a `new/` directory, returning how many were.
+    pub async fn deliver(&self, store: &dyn DataStore, index: &dyn Index) -> Result<usize> {
+        let mut delivered = 0;
+        for maildrop in self.maildrops().await? {
+            delivered += self.deliver_to(&maildrop, store, index).await?;
+        }
+        Ok(delivered)
+    }
+
+    /// Delivers messages as they arrive until `shutdown` is triggered.
+    pub async fn watch(
+        self,
+        store: Arc<Box<dyn DataStore>>,
+        index: Arc<Box<dyn Index>>,
+        shutdown: Shutdown,
+    ) {
+        #[cfg(target_os = "linux")]
+        let inotify = match inotify::Inotify::new() {
+            Ok(inotify) => Some(Arc::new(inotify)),
+            Err(e) => {
+                warn!(
+                    "Polling {} for deliveries, as inotify failed: {}",
+                    self.root.display(),
+                    e
+                );
+                None
+            }
+        };
+        while !shutdown.is_triggered() {
+            let maildrops = match self.maildrops().await {
+                Ok(maildrops) => maildrops,
+                Err(e) => {
+                    warn!(
+                        "Could not look for deliveries in {}: {}",
+                        self.root.display(),
+                        e
+                    );
+                    vec![]
+                }
+            };
+            for maildrop in &maildrops {
+                match self
+                    .deliver_to(maildrop, store.as_ref().as_ref(), index.as_ref().as_ref())
+                    .await
+                {
+                    Ok(0) => {}
+                    Ok(delivered) => debug!(
+                        "Delivered {} messages from {}",
+                        delivered,
+                        maildrop.directory.display()
+                    ),
+                    Err(e) => warn!(
+                        "Could not deliver from {}: {}",
+                        maildrop.directory.display(),
+                        e
+                    ),
+                }
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(inotify) = &inotify {
+                for maildrop in &maildrops {
+                    if let Err(e) = inotify.watch(&maildrop.directory) {
+                        warn!("Could not watch {}: {}", maildrop.directory.display(), e);
+                    }
+                }
+                let inotify = inotify.clone();
+                let interval = self.interval;
+                let _ = crate::runtime::spawn_blocking(move || inotify.wait(interval)).await;
+                continue;
+            }
+            let _ = future::select(Box::pin(sleep(self.interval)), Box::pin(shutdown.wait())).await;
+        }
+    }
+
+    /// The `new/` directories under the root.
+    async fn maildrops(&self) -> io::Result<Vec<Maildrop>> {
+        let mut maildrops = vec![];
+        let mut users = match fs::read_dir(&self.root).await {
+            Ok(users) => users,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(maildrops),
+            Err(e) => return Err(e),
+        };
+        while let Some(user) = users.next().await {
+            let user = user?;
+            if !user.file_type().await?.is_dir() {
+                continue;
+            }
+            let owner = Owner::new(&user.file_name().to_string_lossy());
+            let home = Path::new(user.path().as_os_str()).to_path_buf();
+            maildrops.push(Maildrop {
+                owner: owner.clone(),
+                mailbox: "INBOX".to_string(),
+                directory: home.join("new"),
+            });
+            let mut folders = fs::read_dir(&home).await?;
+            while let Some(folder) = folders.next().await {
+                let folder = folder?;
+                let name = folder.file_name().to_string_lossy().to_string();
+                match name.strip_prefix('.') {
+                    Some(mailbox) if !mailbox.is_empty() && mailbox != "." => {
+                        maildrops.push(Maildrop {
+                            owner: owner.clone(),
+                            mailbox: mailbox.to_string(),
+                            directory: Path::new(folder.path().as_os_str()).join("new"),
+                        })
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(maildrops)
+    }
+
+    async fn deliver_to(
+        &self,
+        maildrop: &Maildrop,
+        store: &dyn DataStore,
+        index: &dyn Index,
+    ) -> Result<usize> {
+        let mut messages = match fs::read_dir(&maildrop.directory).await {
+            Ok(messages) => messages,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(Box::new(e)),
+        };
+        let mut delivered = 0;
+        while let Some(message) = messages.next().await {
+            let message = message?;
+            let name = message.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') || !message.file_type().await?.is_file() {
+                continue;
+            }
+            deliver_file(
+                maildrop,
+                Path::new(message.path().as_os_str()),
+                &name,
+                store,
+                index,
+            )
+            .await?;
+            delivered += 1;
+        }
+        Ok(delivered)
+    }
+}
+
+async fn deliver_file(
+    maildrop: &Maildrop,
+    path: &Path,
+    name: &str,
+    store: &dyn DataStore,
+    index: &dyn Index,
+) -> Result<()> {
+    let body = fs::read(path).await?;
+    let internal_date = fs::metadata(path)
+        .await?
+        .modified()
+        .unwrap_or_else(|_| SystemTime::now());
+    match index
+        .get_mailbox(&maildrop.owner, &maildrop.mailbox, Permission::ReadWrite)
+        .await
+    {
+        Err(MailboxError::DoesNotExist(_)) => {
+            let mailbox = Mailbox::new(&maildrop.mailbox, 0, vec![], Permission::ReadWrite);
+            index.add_mailbox(&maildrop.owner, mailbox).await?
+        }
+        Err(e) => return Err(Box::new(e)),
+        Ok(_) => {}
+    }
+    let message = Message::new(&body).with_internal_date(internal_date);
+    let uid = store
+        .append(maildrop.owner.name(), &maildrop.mailbox, message)
+        .await?;
+    let record = MessageRecord::new(uid, body.len() as u64, internal_date)
+        .with_envelope(Envelope::parse(&body));
+    index
+        .add_message(&maildrop.owner, &maildrop.mailbox, record)
+        .await?;
+
+    // A Maildir message which has been seen by a client moves to cur/, gaining the info
+    // suffix of its flags.
+    let cur = path
+        .parent()
+        .and_then(Path::parent)
+        .map(|folder| folder.join("cur"))
+        .ok_or("a delivered message is not in a Maildir")?;
+    fs::create_dir_all(&cur).await?;
+    fs::rename(path, cur.join(format!("{}:2,", name))).await?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+    use std::path::Path;
+    use std::time::Duration;
+
+    /// Wakes the delivery watcher when a message is moved or written into a watched
+    /// directory.
+    pub(super) struct Inotify {
+        fd: OwnedFd,
+    }
+
+    impl Inotify {
+        pub(super) fn new() -> io::Result<Self> {
+            // SAFETY: inotify_init1 has no memory safety requirements.
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: the descriptor was just opened and nothing else owns it.
+            Ok(Self {
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            })
+        }
+
+        /// Watches `directory`. Watching a directory twice is harmless.
+        pub(super) fn watch(&self, directory: &Path) -> io::Result<()> {
+            let path = CString::new(directory.as_os_str().as_bytes())?;
+            let events = libc::IN_MOVED_TO | libc::IN_CLOSE_WRITE;
+            // SAFETY: the path is a valid C string for the duration of the call.
+            let watch =
+                unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), events) };
+            match watch {
+                // The directory may not have been created yet.
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::NotFound => Ok(()),
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        }
+
+        /// Blocks until something happens in a watched directory or `timeout` passes.
+        pub(super) fn wait(&self, timeout: Duration) -> io::Result<()> {
+            let mut poll = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+            // SAFETY: poll is given one valid pollfd.
+            if unsafe { libc::poll(&mut poll, 1, timeout) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // The events themselves do not matter, as every directory is scanned.
+            let mut buffer = [0u8; 4096];
+            // SAFETY: read writes at most the length of the buffer into it.
+            while unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr().cast(),
+                    buffer.len(),
+                )
+            } > 0
+            {}
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::StreamExt;
+
+    use super::MaildirDelivery;
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::{Index, Mailbox, Owner, Permission};
+    use crate::shutdown::Shutdown;
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::DataStore;
+
+    fn maildir(name: &str) -> std::path::PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "treasurmap-delivery-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        root
+    }
+
+    #[async_std::test]
+    async fn test_deliver() {
+        let root = maildir("deliver");
+        std::fs::create_dir_all(root.join("me/new")).unwrap();
+        std::fs::create_dir_all(root.join("me/.Archive/new")).unwrap();
+        std::fs::write(root.join("me/new/1.host"), b"Subject: hello\r\n\r\nbody").unwrap();
+        std::fs::write(root.join("me/.Archive/new/2.host"), b"Subject: old\r\n\r\n").unwrap();
+        // Messages still being written are left alone.
+        std::fs::write(root.join("me/new/.3.host"), b"Subject: partial").unwrap();
+
+        let store = InMemoryStore::new();
+        let index = InMemoryIndex::new();
+        let delivery = MaildirDelivery::new(&root);
+        assert_eq!(delivery.deliver(&store, &index).await.unwrap(), 2);
+        assert_eq!(delivery.deliver(&store, &index).await.unwrap(), 0);
+
+        let me = Owner::new("me");
+        let inbox = index.list_messages(&me, "INBOX").await.unwrap();
+        assert_eq!(inbox.len(), 1);
+        assert_eq!(inbox[0].envelope.subject.as_deref(), Some("hello"));
+        let stored = store.fetch("me", "INBOX", inbox[0].uid).await.unwrap();
+        assert_eq!(stored, b"Subject: hello\r\n\r\nbody");
+        assert_eq!(index.list_messages(&me, "Archive").await.unwrap().len(), 1);
+        assert!(root.join("me/cur/1.host:2,").exists());
+        assert!(!root.join("me/new/1.host").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_watch() {
+        let root = maildir("watch");
+        std::fs::create_dir_all(root.join("me/new")).unwrap();
+        let store: Arc<Box<dyn DataStore>> = Arc::new(Box::new(InMemoryStore::new()));
+        let index: Arc<Box<dyn Index>> = Arc::new(Box::new(InMemoryIndex::new()));
+        let me = Owner::new("me");
+        let inbox = Mailbox::new("INBOX", 0, vec![], Permission::ReadWrite);
+        index.add_mailbox(&me, inbox).await.unwrap();
+        let mut changes = index.watch(&me, "INBOX").await.unwrap();
+
+        let shutdown = Shutdown::new();
+        let delivery = MaildirDelivery::new(&root).with_interval(Duration::from_millis(50));
+        let watching = async_std::task::spawn(delivery.watch(store, index, shutdown.clone()));
+        std::fs::write(root.join("me/new/1.host"), b"Subject: hello\r\n\r\n").unwrap();
+        let change = async_std::future::timeout(Duration::from_secs(5), changes.next()).await;
+        assert!(change.unwrap().is_some());
+        shutdown.trigger();
+        watching.await;
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,18 +1,188 @@
-use std::{collections::HashMap, error::Error, fmt::Display};
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap},
+    error::Error,
+    fmt::Display,
+    hash::BuildHasher,
+};
 
 use async_lock::RwLock;
 
-use super::{Index, Mailbox, MailboxError, Permission};
+use super::journal::{Change, Journal, JournalEntry, Retention};
+use super::message::MessageRecord;
+use super::uid::{BucketUidAllocator, UidAllocator};
+use super::{Flag, Index, Mailbox, MailboxError, Owner, Permission};
+use crate::store::name::normalize;
+use crate::store::object::InMemoryBucket;
+use crate::util::{Receiver, Sender};
+
+type MailboxKey = (Owner, String);
+
+/// How many locks the mailboxes are spread over unless `with_shards` says otherwise.
+const DEFAULT_SHARDS: usize = 16;
+
+/// A map of mailboxes split over several locks by a hash of the owner and mailbox name, so that
+/// a long operation on one mailbox only holds up the mailboxes which share its lock.
+struct Shards<T> {
+    shards: Vec<RwLock<HashMap<MailboxKey, T>>>,
+    hasher: RandomState,
+}
+
+impl<T> Shards<T> {
+    fn new(count: usize) -> Self {
+        Self {
+            shards: (0..count.max(1)).map(|_| RwLock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+    /// The lock of the shard holding `key`.
+    fn of(&self, key: &MailboxKey) -> &RwLock<HashMap<MailboxKey, T>> {
+        let hash = self.hasher.hash_one(key) as usize;
+        &self.shards[hash % self.shards.len()]
+    }
+    fn all(&self) -> impl Iterator<Item = &RwLock<HashMap<MailboxKey, T>>> {
+        self.shards.iter()
+    }
+}
 
 pub struct InMemoryIndex {
-    mailboxes: RwLock<HashMap<String, Mailbox>>,
+    mailboxes: Shards<Mailbox>,
+    messages: Shards<StoredMessages>,
+    watchers: Shards<Vec<Sender<JournalEntry>>>,
+    uids: Box<dyn UidAllocator>,
+    retention: Retention,
+}
+
+#[derive(Default)]
+struct StoredMessages {
+    highest_modseq: u64,
+    records: BTreeMap<u32, MessageRecord>,
+    journal: Journal,
+    /// Every keyword set on a message of the mailbox, kept once the messages are gone.
+    keywords: Vec<String>,
+}
+
+impl StoredMessages {
+    fn record(&mut self, change: Change) -> JournalEntry {
+        self.highest_modseq += 1;
+        self.journal.record(self.highest_modseq, change)
+    }
+    fn learn_keywords(&mut self, flags: &[String]) {
+        for flag in flags.iter().filter(|flag| Flag::is_keyword(flag)) {
+            if !self.keywords.iter().any(|known| known.eq_ignore_ascii_case(flag)) {
+                self.keywords.push(flag.clone());
+            }
+        }
+    }
+}
+
+fn key(owner: &Owner, name: &str) -> MailboxKey {
+    (owner.clone(), normalize(name))
+}
+
+impl Default for InMemoryIndex {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl InMemoryIndex {
     pub fn new() -> Self {
         Self {
-            mailboxes: RwLock::new(HashMap::new()),
+            mailboxes: Shards::new(DEFAULT_SHARDS),
+            messages: Shards::new(DEFAULT_SHARDS),
+            watchers: Shards::new(DEFAULT_SHARDS),
+            uids: Box::new(BucketUidAllocator::new(InMemoryBucket::new())),
+            retention: Retention::default(),
+        }
+    }
+    /// Replaces the default in-memory `UidAllocator`, e.g. with one backed by a `FileBucket` so
+    /// UIDNEXT and UIDVALIDITY survive restarts.
+    pub fn with_uid_allocator<U: UidAllocator + 'static>(mut self, uids: U) -> Self {
+        self.uids = Box::new(uids);
+        self
+    }
+    /// Spreads the mailboxes over `count` locks rather than 16. More locks let more users be
+    /// served at once. Any mailboxes already added are forgotten.
+    pub fn with_shards(mut self, count: usize) -> Self {
+        self.mailboxes = Shards::new(count);
+        self.messages = Shards::new(count);
+        self.watchers = Shards::new(count);
+        self
+    }
+    /// Sets how much change history is kept for each mailbox.
+    pub fn with_journal_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Compacts the change journal of every mailbox. See `Journal::compact`.
+    pub async fn compact_journals(&self) {
+        for shard in self.messages.all() {
+            for stored in shard.write().await.values_mut() {
+                stored.journal.compact();
+            }
+        }
+    }
+
+    /// Sends a journal entry to everyone watching the mailbox, forgetting watchers which have
+    /// gone away.
+    async fn publish(&self, key: &MailboxKey, entry: JournalEntry) {
+        if let Some(watchers) = self.watchers.of(key).write().await.get_mut(key) {
+            watchers.retain(|watcher| watcher.unbounded_send(entry.clone()).is_ok());
+        }
+    }
+
+    /// Fills in the parts of a mailbox which are derived from its messages and UID state.
+    async fn describe(&self, owner: &Owner, mailbox: Mailbox) -> Result<Mailbox, MailboxError> {
+        let key = key(owner, &mailbox.name.to_string_lossy());
+        let state = self.uids.state(owner, &key.1).await?;
+        let messages = self.messages.of(&key).read().await;
+        let (count, highest_modseq) = messages
+            .get(&key)
+            .map(|stored| (stored.records.len() as u64, stored.highest_modseq))
+            .unwrap_or((0, 0));
+        let mut flags = Flag::system();
+        if let Some(stored) = messages.get(&key) {
+            flags.extend(stored.keywords.iter().map(|keyword| Flag::new(keyword)));
+        }
+        Ok(Mailbox {
+            count,
+            flags,
+            highest_modseq,
+            ..mailbox.with_uid_state(state)
+        })
+    }
+
+    /// Replaces the flags of a message, if it has not changed since `unchanged_since`.
+    async fn update_flags(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+        unchanged_since: Option<u64>,
+        flags: Vec<String>,
+    ) -> Result<MessageRecord, MailboxError> {
+        let key = key(owner, mailbox);
+        let mut messages = self.messages.of(&key).write().await;
+        let stored = messages
+            .get_mut(&key)
+            .filter(|stored| stored.records.contains_key(&uid))
+            .ok_or_else(|| MailboxError::MessageDoesNotExist(mailbox.to_string(), uid))?;
+        if unchanged_since.is_some_and(|modseq| stored.records[&uid].modseq > modseq) {
+            return Err(MailboxError::Modified(mailbox.to_string(), uid));
         }
+        stored.learn_keywords(&flags);
+        let entry = stored.record(Change::Flags(uid, flags.clone()));
+        let record = stored
+            .records
+            .get_mut(&uid)
+            .expect("the message was checked to exist above");
+        record.flags = flags;
+        record.modseq = entry.modseq;
+        let record = record.clone();
+        drop(messages);
+        self.publish(&key, entry).await;
+        Ok(record)
     }
 }
 
@@ -29,47 +199,380 @@ impl Display for MailboxDoesNotExist {
 
 #[async_trait::async_trait]
 impl Index for InMemoryIndex {
-    async fn add_mailbox(&self, mailbox: Mailbox) -> Result<(), MailboxError> {
-        let mut write_lock = self.mailboxes.write().await;
-        if let Some(..) = write_lock.get(mailbox.name.to_str().unwrap()) {
-            return Err(MailboxError::Exists(
-                mailbox.name.clone().to_str().unwrap().to_string(),
-            ));
+    async fn add_mailbox(&self, owner: &Owner, mailbox: Mailbox) -> Result<(), MailboxError> {
+        let key = key(owner, &mailbox.name.to_string_lossy());
+        let mut write_lock = self.mailboxes.of(&key).write().await;
+        if write_lock.contains_key(&key) {
+            return Err(MailboxError::Exists(key.1));
         };
-        write_lock.insert(
-            mailbox
-                .name
-                .to_str()
-                .expect("Cannot convert folder to string")
-                .to_string(),
-            Mailbox::new(
-                &mailbox.name.to_string_lossy(),
-                0,
-                vec![],
-                Permission::ReadOnly,
-            ),
-        );
+        // Any UIDs previously handed out under this name belong to a deleted mailbox.
+        self.uids.recreate(owner, &key.1).await?;
+        let mailbox = Mailbox::new(&key.1, 0, vec![], Permission::ReadOnly);
+        write_lock.insert(key, mailbox);
         Ok(())
     }
     async fn get_mailbox(
         &self,
+        owner: &Owner,
         name: &str,
         permission: Permission,
     ) -> Result<Mailbox, MailboxError> {
-        let read_lock = self.mailboxes.read().await;
-        match read_lock.get(name) {
-            Some(mailbox) => Ok(Mailbox {
-                permission,
-                ..mailbox.clone()
-            }),
+        let key = key(owner, name);
+        let read_lock = self.mailboxes.of(&key).read().await;
+        match read_lock.get(&key) {
+            Some(mailbox) => {
+                let mailbox = Mailbox {
+                    permission,
+                    ..mailbox.clone()
+                };
+                drop(read_lock);
+                self.describe(owner, mailbox).await
+            }
             None => {
-                if "INBOX".eq_ignore_ascii_case(name) {
+                if key.1 == "INBOX" {
                     drop(read_lock);
-                    self.add_mailbox(Mailbox::new("INBOX", 0, vec![], Permission::ReadOnly)).await?;
-                    return Ok(self.mailboxes.read().await.get("INBOX").expect("INBOX has already been inserted so there should be no issue retrieving the inbox from the mailboxes map").clone())
+                    // INBOX is created implicitly, so keep any UID state it already has rather
+                    // than treating it as a recreated mailbox.
+                    self.uids.state(owner, "INBOX").await?;
+                    let inbox = Mailbox::new("INBOX", 0, vec![], Permission::ReadOnly);
+                    let inbox = self
+                        .mailboxes
+                        .of(&key)
+                        .write()
+                        .await
+                        .entry(key)
+                        .or_insert(inbox)
+                        .clone();
+                    return self.describe(owner, inbox).await;
                 }
                 Err(MailboxError::DoesNotExist(name.to_string()))
-        },
+            }
         }
     }
+    async fn add_message(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        message: MessageRecord,
+    ) -> Result<MessageRecord, MailboxError> {
+        self.get_mailbox(owner, mailbox, Permission::ReadWrite).await?;
+        let key = key(owner, mailbox);
+        self.uids.advance(owner, &key.1, message.uid).await?;
+        let mut messages = self.messages.of(&key).write().await;
+        let stored = messages.entry(key.clone()).or_insert_with(|| StoredMessages {
+            journal: Journal::new(self.retention),
+            ..Default::default()
+        });
+        stored.learn_keywords(&message.flags);
+        let entry = stored.record(Change::Append(message.uid));
+        let message = MessageRecord {
+            modseq: entry.modseq,
+            ..message
+        };
+        stored.records.insert(message.uid, message.clone());
+        drop(messages);
+        self.publish(&key, entry).await;
+        Ok(message)
+    }
+    async fn list_messages(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+    ) -> Result<Vec<MessageRecord>, MailboxError> {
+        self.get_mailbox(owner, mailbox, Permission::ReadOnly).await?;
+        let key = key(owner, mailbox);
+        Ok(self
+            .messages
+            .of(&key)
+            .read()
+            .await
+            .get(&key)
+            .map(|stored| stored.records.values().cloned().collect())
+            .unwrap_or_default())
+    }
+    async fn set_flags(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+        flags: Vec<String>,
+    ) -> Result<MessageRecord, MailboxError> {
+        self.update_flags(owner, mailbox, uid, None, flags).await
+    }
+    async fn set_flags_unchanged_since(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+        modseq: u64,
+        flags: Vec<String>,
+    ) -> Result<MessageRecord, MailboxError> {
+        self.update_flags(owner, mailbox, uid, Some(modseq), flags).await
+    }
+    async fn remove_messages(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>, MailboxError> {
+        let key = key(owner, mailbox);
+        let mut messages = self.messages.of(&key).write().await;
+        let stored = match messages.get_mut(&key) {
+            Some(stored) => stored,
+            None => return Ok(vec![]),
+        };
+        let removed: Vec<u32> = uids
+            .iter()
+            .filter(|uid| stored.records.remove(uid).is_some())
+            .copied()
+            .collect();
+        if removed.is_empty() {
+            return Ok(removed);
+        }
+        let entry = stored.record(Change::Expunge(removed.clone()));
+        drop(messages);
+        self.publish(&key, entry).await;
+        Ok(removed)
+    }
+    async fn changes_since(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        modseq: u64,
+    ) -> Result<Vec<JournalEntry>, MailboxError> {
+        self.get_mailbox(owner, mailbox, Permission::ReadOnly).await?;
+        let key = key(owner, mailbox);
+        match self.messages.of(&key).read().await.get(&key) {
+            Some(stored) => stored
+                .journal
+                .since(modseq)
+                .ok_or_else(|| MailboxError::HistoryUnavailable(mailbox.to_string(), modseq)),
+            None => Ok(vec![]),
+        }
+    }
+    async fn watch(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+    ) -> Result<Receiver<JournalEntry>, MailboxError> {
+        self.get_mailbox(owner, mailbox, Permission::ReadOnly).await?;
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let key = key(owner, mailbox);
+        self.watchers
+            .of(&key)
+            .write()
+            .await
+            .entry(key)
+            .or_default()
+            .push(sender);
+        Ok(receiver)
+    }
+    async fn compact_journals(&self) -> Result<(), MailboxError> {
+        InMemoryIndex::compact_journals(self).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::InMemoryIndex;
+    use futures::StreamExt;
+
+    use crate::index::journal::{Change, Retention};
+    use crate::index::message::{MessageQuery, MessageRecord};
+    use crate::index::{GetMailboxRequest, Index, Mailbox, MailboxError, Owner, Permission};
+
+    #[async_std::test]
+    async fn test_inbox_keeps_uid_validity() {
+        let me = Owner::new("me");
+        let index = InMemoryIndex::new();
+        let first = index.get_mailbox(&me, "INBOX", Permission::ReadWrite).await.unwrap();
+        let second = index.get_mailbox(&me, "INBOX", Permission::ReadWrite).await.unwrap();
+        assert_ne!(first.uid_validity, 0);
+        assert_eq!(first.uid_validity, second.uid_validity);
+        assert_eq!(second.uid_next, 1);
+    }
+
+    #[async_std::test]
+    async fn test_created_mailbox_gets_fresh_uid_state() {
+        let me = Owner::new("me");
+        let index = InMemoryIndex::new();
+        index
+            .add_mailbox(&me, Mailbox::new("Drafts", 0, vec![], Permission::ReadWrite))
+            .await
+            .unwrap();
+        let drafts = index.get_mailbox(&me, "Drafts", Permission::ReadWrite).await.unwrap();
+        assert_ne!(drafts.uid_validity, 0);
+        assert_eq!(drafts.uid_next, 1);
+    }
+
+    #[async_std::test]
+    async fn test_message_records() {
+        let me = Owner::new("me");
+        let index = InMemoryIndex::new();
+        for uid in [3, 5, 9] {
+            index
+                .add_message(&me, "INBOX", MessageRecord::new(uid, 100 * uid as u64, SystemTime::now()))
+                .await
+                .unwrap();
+        }
+        let flagged = index
+            .set_flags(&me, "inbox", 5, vec!["\\Flagged".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(flagged.modseq, 4);
+        assert!(matches!(
+            index.set_flags_unchanged_since(&me, "INBOX", 5, 3, vec![]).await,
+            Err(MailboxError::Modified(_, 5))
+        ));
+
+        let inbox = index.get_mailbox(&me, "INBOX", Permission::ReadOnly).await.unwrap();
+        assert_eq!(inbox.count, 3);
+        assert_eq!(inbox.uid_next, 10);
+        assert_eq!(inbox.highest_modseq, 4);
+
+        let uids = |records: Vec<MessageRecord>| records.iter().map(|r| r.uid).collect::<Vec<_>>();
+        assert_eq!(uids(index.list_messages(&me, "INBOX").await.unwrap()), vec![3, 5, 9]);
+        assert_eq!(
+            uids(index.query_messages(&me, "INBOX", &MessageQuery::new().larger_than(400)).await.unwrap()),
+            vec![5, 9]
+        );
+        assert_eq!(
+            uids(index.query_messages(&me, "INBOX", &MessageQuery::new().changed_since(3)).await.unwrap()),
+            vec![5]
+        );
+
+        assert_eq!(index.remove_messages(&me, "INBOX", &[3, 4]).await.unwrap(), vec![3]);
+        assert!(matches!(
+            index.get_message(&me, "INBOX", 3).await,
+            Err(MailboxError::MessageDoesNotExist(_, 3))
+        ));
+        assert!(index.list_messages(&me, "Missing").await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_keywords_are_kept() {
+        let me = Owner::new("me");
+        let index = InMemoryIndex::new();
+        let record = MessageRecord::new(1, 10, SystemTime::now()).with_flags(vec!["$Forwarded".to_string()]);
+        index.add_message(&me, "INBOX", record).await.unwrap();
+        index
+            .set_flags(&me, "INBOX", 1, vec!["\\Seen".to_string(), "$forwarded".to_string(), "Work".to_string()])
+            .await
+            .unwrap();
+        index.remove_messages(&me, "INBOX", &[1]).await.unwrap();
+
+        let inbox = index.get_mailbox(&me, "INBOX", Permission::ReadWrite).await.unwrap();
+        let flags: Vec<String> = inbox.flags.iter().map(|flag| flag.value.clone()).collect();
+        assert_eq!(
+            flags,
+            vec!["\\Answered", "\\Flagged", "\\Deleted", "\\Seen", "\\Draft", "$Forwarded", "Work"]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_mailboxes_are_scoped_by_owner() {
+        let index = InMemoryIndex::new();
+        let (alice, bob) = (Owner::new("alice"), Owner::new("bob"));
+        index
+            .add_message(&alice, "INBOX", MessageRecord::new(1, 10, SystemTime::now()))
+            .await
+            .unwrap();
+        index
+            .add_mailbox(&alice, Mailbox::new("Private", 0, vec![], Permission::ReadWrite))
+            .await
+            .unwrap();
+
+        let inbox = index.get_mailbox(&bob, "INBOX", Permission::ReadOnly).await.unwrap();
+        assert_eq!(inbox.count, 0);
+        assert!(index.list_messages(&bob, "INBOX").await.unwrap().is_empty());
+        assert!(matches!(
+            index.get_mailbox(&bob, "Private", Permission::ReadOnly).await,
+            Err(MailboxError::DoesNotExist(_))
+        ));
+        assert_eq!(
+            index.get_mailbox(&alice, "inbox", Permission::ReadOnly).await.unwrap().count,
+            1
+        );
+    }
+
+    #[async_std::test]
+    async fn test_journal_and_watchers() {
+        let me = Owner::new("me");
+        let index = InMemoryIndex::new().with_journal_retention(Retention {
+            max_entries: Some(2),
+            max_age: None,
+        });
+        let mut watcher = index.watch(&me, "INBOX").await.unwrap();
+        for uid in [1, 2] {
+            index
+                .add_message(&me, "INBOX", MessageRecord::new(uid, 1, SystemTime::now()))
+                .await
+                .unwrap();
+        }
+        index
+            .set_flags(&me, "INBOX", 1, vec!["\\Seen".to_string()])
+            .await
+            .unwrap();
+        index.remove_messages(&me, "INBOX", &[2]).await.unwrap();
+
+        let changes = index.changes_since(&me, "INBOX", 2).await.unwrap();
+        assert_eq!(
+            changes.into_iter().map(|e| (e.modseq, e.change)).collect::<Vec<_>>(),
+            vec![
+                (3, Change::Flags(1, vec!["\\Seen".to_string()])),
+                (4, Change::Expunge(vec![2]))
+            ]
+        );
+        assert!(matches!(
+            index.changes_since(&me, "INBOX", 1).await,
+            Err(MailboxError::HistoryUnavailable(_, 1))
+        ));
+        let seen: Vec<u64> = watcher.by_ref().take(4).map(|e| e.modseq).collect().await;
+        assert_eq!(seen, vec![1, 2, 3, 4]);
+    }
+
+    #[async_std::test]
+    async fn test_shards() {
+        for shards in [1, 3] {
+            let index = InMemoryIndex::new().with_shards(shards);
+            for user in 0..20 {
+                let owner = Owner::new(&format!("user{}", user));
+                for uid in 1..=user {
+                    index
+                        .add_message(&owner, "INBOX", MessageRecord::new(uid, 1, SystemTime::now()))
+                        .await
+                        .unwrap();
+                }
+            }
+            index.compact_journals().await;
+            for user in 0..20 {
+                let owner = Owner::new(&format!("user{}", user));
+                let inbox = index.get_mailbox(&owner, "INBOX", Permission::ReadOnly).await.unwrap();
+                assert_eq!(inbox.count, user as u64);
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn test_request_channel_carries_errors() {
+        let index = InMemoryIndex::new();
+        let (requests, receiver) = futures::channel::mpsc::unbounded();
+        let (responder, response) = futures::channel::oneshot::channel();
+        requests
+            .unbounded_send(GetMailboxRequest {
+                owner: Owner::new("me"),
+                name: "Missing".to_string(),
+                responder,
+                permission: Permission::ReadOnly,
+            })
+            .unwrap();
+        drop(requests);
+        index.start(receiver).await.unwrap();
+        let error = response.await.unwrap().unwrap_err();
+        assert!(matches!(error, MailboxError::DoesNotExist(_)));
+        assert_eq!(error.code(), Some("NONEXISTENT"));
+    }
 }
@@ -0,0 +1,209 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, SystemTime};
+
+/// A single change to a mailbox.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Append(u32),
+    Flags(u32, Vec<String>),
+    Expunge(Vec<u32>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub modseq: u64,
+    pub at: SystemTime,
+    pub change: Change,
+}
+
+/// How much history a `Journal` keeps. Entries beyond either limit are dropped, oldest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    pub max_entries: Option<usize>,
+    pub max_age: Option<Duration>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_entries: Some(10_000),
+            max_age: None,
+        }
+    }
+}
+
+/// The append-only change log of one mailbox, ordered by MODSEQ.
+///
+/// Consumers such as CONDSTORE/QRESYNC, IDLE and other sessions with the mailbox selected ask
+/// for the changes after the MODSEQ they last saw. Once retention has dropped entries newer than
+/// that, `since` returns `None` and the consumer has to fall back to a full resynchronisation.
+#[derive(Debug, Clone, Default)]
+pub struct Journal {
+    entries: VecDeque<JournalEntry>,
+    retention: Retention,
+    /// The highest MODSEQ whose entry has been dropped by retention.
+    floor: u64,
+}
+
+impl Journal {
+    pub fn new(retention: Retention) -> Self {
+        Self {
+            retention,
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&mut self, modseq: u64, change: Change) -> JournalEntry {
+        let entry = JournalEntry {
+            modseq,
+            at: SystemTime::now(),
+            change,
+        };
+        self.entries.push_back(entry.clone());
+        self.enforce_retention();
+        entry
+    }
+
+    /// Returns every entry with a MODSEQ greater than `modseq`, or `None` if some of them have
+    /// already been discarded.
+    pub fn since(&self, modseq: u64) -> Option<Vec<JournalEntry>> {
+        if modseq < self.floor {
+            return None;
+        }
+        Some(
+            self.entries
+                .iter()
+                .filter(|entry| entry.modseq > modseq)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Rewrites the journal so each message appears at most once, without changing what any
+    /// consumer would conclude from `since`. See `redundant`.
+    pub fn compact(&mut self) {
+        let redundant = redundant(self.entries.iter());
+        self.entries
+            .retain(|entry| !redundant.contains(&entry.modseq));
+    }
+
+    fn enforce_retention(&mut self) {
+        let oldest = self
+            .retention
+            .max_age
+            .and_then(|age| SystemTime::now().checked_sub(age));
+        while let Some(first) = self.entries.front() {
+            let too_many = self
+                .retention
+                .max_entries
+                .is_some_and(|max| self.entries.len() > max);
+            let too_old = oldest.is_some_and(|oldest| first.at < oldest);
+            if !too_many && !too_old {
+                break;
+            }
+            self.floor = first.modseq;
+            self.entries.pop_front();
+        }
+    }
+}
+
+/// The MODSEQs of the entries in a journal which compaction drops: only the newest flag
+/// change of a message is kept, and appends and flag changes of messages which were later
+/// expunged are dropped.
+pub(crate) fn redundant<'a>(
+    entries: impl Iterator<Item = &'a JournalEntry> + Clone,
+) -> HashSet<u64> {
+    let mut expunged = HashSet::new();
+    let mut flagged = HashMap::new();
+    for entry in entries.clone() {
+        match &entry.change {
+            Change::Expunge(uids) => expunged.extend(uids.iter().copied()),
+            Change::Flags(uid, _) => {
+                flagged.insert(*uid, entry.modseq);
+            }
+            Change::Append(_) => {}
+        }
+    }
+    entries
+        .filter(|entry| match &entry.change {
+            Change::Append(uid) => expunged.contains(uid),
+            Change::Flags(uid, _) => {
+                expunged.contains(uid) || flagged.get(uid) != Some(&entry.modseq)
+            }
+            Change::Expunge(_) => false,
+        })
+        .map(|entry| entry.modseq)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Change, Journal, Retention};
+
+    #[test]
+    fn test_since_and_retention() {
+        let mut journal = Journal::new(Retention {
+            max_entries: Some(3),
+            max_age: None,
+        });
+        for modseq in 1..=4 {
+            journal.record(modseq, Change::Append(modseq as u32));
+        }
+        assert_eq!(journal.len(), 3);
+        assert!(journal.since(0).is_none());
+        let changes = journal.since(2).unwrap();
+        assert_eq!(
+            changes.iter().map(|e| e.modseq).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert!(journal.since(4).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_max_age() {
+        let mut journal = Journal::new(Retention {
+            max_entries: None,
+            max_age: Some(Duration::from_secs(60)),
+        });
+        journal.record(1, Change::Append(1));
+        journal.entries[0].at -= Duration::from_secs(120);
+        journal.record(2, Change::Append(2));
+        assert!(journal.since(0).is_none());
+        assert_eq!(journal.since(1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_compaction_keeps_the_outcome() {
+        let mut journal = Journal::default();
+        journal.record(1, Change::Append(1));
+        journal.record(2, Change::Append(2));
+        journal.record(3, Change::Flags(1, vec!["\\Seen".to_string()]));
+        journal.record(4, Change::Flags(2, vec!["\\Seen".to_string()]));
+        journal.record(5, Change::Flags(1, vec![]));
+        journal.record(6, Change::Expunge(vec![2]));
+        journal.compact();
+        assert_eq!(
+            journal
+                .since(0)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.change)
+                .collect::<Vec<_>>(),
+            vec![
+                Change::Append(1),
+                Change::Flags(1, vec![]),
+                Change::Expunge(vec![2])
+            ]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::mime::address::Address;
+use crate::mime::encoding::decode_words;
+use crate::mime::header::Headers;
+use crate::mime::split_header;
+
+/// The envelope fields of a message, as returned by FETCH ENVELOPE. The subject and display
+/// names have their RFC 2047 encoded words decoded.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub date: Option<String>,
+    pub subject: Option<String>,
+    pub from: Vec<Address>,
+    pub sender: Vec<Address>,
+    pub reply_to: Vec<Address>,
+    pub to: Vec<Address>,
+    pub cc: Vec<Address>,
+    pub bcc: Vec<Address>,
+    pub in_reply_to: Option<String>,
+    pub message_id: Option<String>,
+}
+
+impl Envelope {
+    /// Builds an envelope from the header section of an RFC 5322 message. Parsing stops at the
+    /// first empty line, so the full message may be passed in.
+    pub fn parse(message: &[u8]) -> Self {
+        let mut envelope = Envelope::default();
+        let (header, _) = split_header(message);
+        for field in Headers::new(header).fields() {
+            let value = field.value();
+            match field.name.to_ascii_lowercase().as_str() {
+                "date" => envelope.date = Some(value),
+                "subject" => envelope.subject = Some(decode_words(&value)),
+                "from" => envelope.from = Address::parse_list(&value),
+                "sender" => envelope.sender = Address::parse_list(&value),
+                "reply-to" => envelope.reply_to = Address::parse_list(&value),
+                "to" => envelope.to = Address::parse_list(&value),
+                "cc" => envelope.cc = Address::parse_list(&value),
+                "bcc" => envelope.bcc = Address::parse_list(&value),
+                "in-reply-to" => envelope.in_reply_to = Some(value),
+                "message-id" => envelope.message_id = Some(value),
+                _ => {}
+            }
+        }
+        // RFC 9051 section 7.5.2: Sender and Reply-To default to From when absent.
+        if envelope.sender.is_empty() {
+            envelope.sender = envelope.from.clone();
+        }
+        if envelope.reply_to.is_empty() {
+            envelope.reply_to = envelope.from.clone();
+        }
+        envelope
+    }
+}
+
+/// Everything the index knows about a single message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageRecord {
+    pub uid: u32,
+    pub flags: Vec<String>,
+    pub size: u64,
+    pub internal_date: SystemTime,
+    pub envelope: Envelope,
+    /// The MODSEQ of the last change to this message, assigned by the index.
+    pub modseq: u64,
+}
+
+impl MessageRecord {
+    pub fn new(uid: u32, size: u64, internal_date: SystemTime) -> Self {
+        Self {
+            uid,
+            flags: vec![],
+            size,
+            internal_date,
+            envelope: Envelope::default(),
+            modseq: 0,
+        }
+    }
+    pub fn with_flags(mut self, flags: Vec<String>) -> Self {
+        self.flags = flags;
+        self
+    }
+    pub fn with_envelope(mut self, envelope: Envelope) -> Self {
+        self.envelope = envelope;
+        self
+    }
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f.eq_ignore_ascii_case(flag))
+    }
+}
+
+/// Criteria for `Index::query_messages`. Every criterion which is set must match.
+#[derive(Debug, Clone, Default)]
+pub struct MessageQuery {
+    pub uids: Option<Vec<(u32, u32)>>,
+    pub flagged: Vec<String>,
+    pub unflagged: Vec<String>,
+    pub larger: Option<u64>,
+    pub smaller: Option<u64>,
+    pub since: Option<SystemTime>,
+    pub before: Option<SystemTime>,
+    pub changed_since: Option<u64>,
+}
+
+impl MessageQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Restricts the query to an inclusive UID range. May be called more than once.
+    pub fn with_uid_range(mut self, first: u32, last: u32) -> Self {
+        self.uids
+            .get_or_insert_with(Vec::new)
+            .push((first.min(last), first.max(last)));
+        self
+    }
+    pub fn with_flag(mut self, flag: &str) -> Self {
+        self.flagged.push(flag.to_string());
+        self
+    }
+    pub fn without_flag(mut self, flag: &str) -> Self {
+        self.unflagged.push(flag.to_string());
+        self
+    }
+    pub fn larger_than(mut self, size: u64) -> Self {
+        self.larger = Some(size);
+        self
+    }
+    pub fn smaller_than(mut self, size: u64) -> Self {
+        self.smaller = Some(size);
+        self
+    }
+    pub fn since(mut self, date: SystemTime) -> Self {
+        self.since = Some(date);
+        self
+    }
+    pub fn before(mut self, date: SystemTime) -> Self {
+        self.before = Some(date);
+        self
+    }
+    pub fn changed_since(mut self, modseq: u64) -> Self {
+        self.changed_since = Some(modseq);
+        self
+    }
+
+    pub fn matches(&self, message: &MessageRecord) -> bool {
+        if let Some(ranges) = &self.uids {
+            if !ranges
+                .iter()
+                .any(|(first, last)| (*first..=*last).contains(&message.uid))
+            {
+                return false;
+            }
+        }
+        self.flagged.iter().all(|flag| message.has_flag(flag))
+            && !self.unflagged.iter().any(|flag| message.has_flag(flag))
+            && self.larger.is_none_or(|size| message.size > size)
+            && self.smaller.is_none_or(|size| message.size < size)
+            && self.since.is_none_or(|date| message.internal_date >= date)
+            && self.before.is_none_or(|date| message.internal_date < date)
+            && self
+                .changed_since
+                .is_none_or(|modseq| message.modseq > modseq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{Envelope, MessageQuery, MessageRecord};
+    use crate::mime::address::Address;
+
+    #[test]
+    fn test_envelope_from_headers() {
+        let message = b"From: \"Doe, Jane\" <jane@example.com>\r\nTo: a@example.com, b@example.com\r\nSubject: =?utf-8?Q?Quarterly?=\r\n report\r\nMessage-ID: <1@example.com>\r\n\r\nTo: not-a-header@example.com\r\n";
+        let envelope = Envelope::parse(message);
+        assert_eq!(
+            envelope.from,
+            vec![Address::new("jane", "example.com").with_name("Doe, Jane")]
+        );
+        assert_eq!(envelope.sender, envelope.from);
+        assert_eq!(
+            envelope.to,
+            vec![
+                Address::new("a", "example.com"),
+                Address::new("b", "example.com")
+            ]
+        );
+        assert_eq!(envelope.subject.as_deref(), Some("Quarterly report"));
+        assert_eq!(envelope.message_id.as_deref(), Some("<1@example.com>"));
+        assert!(envelope.cc.is_empty());
+    }
+
+    #[test]
+    fn test_query_matches() {
+        let now = SystemTime::now();
+        let message = MessageRecord::new(7, 2048, now).with_flags(vec!["\\Seen".to_string()]);
+        assert!(MessageQuery::new()
+            .with_uid_range(10, 5)
+            .with_flag("\\seen")
+            .larger_than(1024)
+            .since(now - Duration::from_secs(60))
+            .matches(&message));
+        assert!(!MessageQuery::new().without_flag("\\Seen").matches(&message));
+        assert!(!MessageQuery::new().with_uid_range(1, 6).matches(&message));
+        assert!(!MessageQuery::new().changed_since(0).matches(&message));
+    }
+}
@@ -0,0 +1,218 @@
+use std::sync::Arc;
+
+use futures::channel::mpsc::UnboundedReceiver;
+
+use crate::metrics::Metrics;
+use crate::slowlog::{measure, Stage};
+
+use super::journal::JournalEntry;
+use super::message::{MessageQuery, MessageRecord};
+use super::{GetMailboxRequest, Index, Mailbox, MailboxError, Owner, Permission};
+
+/// Counts the calls made to an index in `Metrics`, by method, and charges the time they take
+/// to the command making them for the slow log.
+pub struct MeteredIndex {
+    inner: Box<dyn Index>,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredIndex {
+    pub fn new(inner: Box<dyn Index>, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+#[async_trait::async_trait]
+impl Index for MeteredIndex {
+    async fn add_mailbox(&self, owner: &Owner, mailbox: Mailbox) -> Result<(), MailboxError> {
+        self.metrics.mailbox_operation("add_mailbox");
+        measure(
+            Stage::Index,
+            "add_mailbox",
+            self.inner.add_mailbox(owner, mailbox),
+        )
+        .await
+    }
+    async fn get_mailbox(
+        &self,
+        owner: &Owner,
+        name: &str,
+        permission: Permission,
+    ) -> Result<Mailbox, MailboxError> {
+        self.metrics.mailbox_operation("get_mailbox");
+        measure(
+            Stage::Index,
+            "get_mailbox",
+            self.inner.get_mailbox(owner, name, permission),
+        )
+        .await
+    }
+    async fn add_message(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        message: MessageRecord,
+    ) -> Result<MessageRecord, MailboxError> {
+        self.metrics.mailbox_operation("add_message");
+        measure(
+            Stage::Index,
+            "add_message",
+            self.inner.add_message(owner, mailbox, message),
+        )
+        .await
+    }
+    async fn list_messages(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+    ) -> Result<Vec<MessageRecord>, MailboxError> {
+        self.metrics.mailbox_operation("list_messages");
+        measure(
+            Stage::Index,
+            "list_messages",
+            self.inner.list_messages(owner, mailbox),
+        )
+        .await
+    }
+    async fn set_flags(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+        flags: Vec<String>,
+    ) -> Result<MessageRecord, MailboxError> {
+        self.metrics.mailbox_operation("set_flags");
+        measure(
+            Stage::Index,
+            "set_flags",
+            self.inner.set_flags(owner, mailbox, uid, flags),
+        )
+        .await
+    }
+    async fn set_flags_unchanged_since(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+        modseq: u64,
+        flags: Vec<String>,
+    ) -> Result<MessageRecord, MailboxError> {
+        self.metrics.mailbox_operation("set_flags");
+        measure(
+            Stage::Index,
+            "set_flags",
+            self.inner
+                .set_flags_unchanged_since(owner, mailbox, uid, modseq, flags),
+        )
+        .await
+    }
+    async fn remove_messages(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>, MailboxError> {
+        self.metrics.mailbox_operation("remove_messages");
+        measure(
+            Stage::Index,
+            "remove_messages",
+            self.inner.remove_messages(owner, mailbox, uids),
+        )
+        .await
+    }
+    async fn changes_since(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        modseq: u64,
+    ) -> Result<Vec<JournalEntry>, MailboxError> {
+        self.metrics.mailbox_operation("changes_since");
+        measure(
+            Stage::Index,
+            "changes_since",
+            self.inner.changes_since(owner, mailbox, modseq),
+        )
+        .await
+    }
+    async fn watch(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+    ) -> Result<crate::util::Receiver<JournalEntry>, MailboxError> {
+        self.metrics.mailbox_operation("watch");
+        measure(Stage::Index, "watch", self.inner.watch(owner, mailbox)).await
+    }
+    async fn compact_journals(&self) -> Result<(), MailboxError> {
+        self.metrics.mailbox_operation("compact_journals");
+        measure(
+            Stage::Index,
+            "compact_journals",
+            self.inner.compact_journals(),
+        )
+        .await
+    }
+    async fn get_message(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<MessageRecord, MailboxError> {
+        self.metrics.mailbox_operation("get_message");
+        measure(
+            Stage::Index,
+            "get_message",
+            self.inner.get_message(owner, mailbox, uid),
+        )
+        .await
+    }
+    async fn query_messages(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        query: &MessageQuery,
+    ) -> Result<Vec<MessageRecord>, MailboxError> {
+        self.metrics.mailbox_operation("query_messages");
+        measure(
+            Stage::Index,
+            "query_messages",
+            self.inner.query_messages(owner, mailbox, query),
+        )
+        .await
+    }
+    async fn start(
+        &self,
+        requests: UnboundedReceiver<GetMailboxRequest>,
+    ) -> crate::util::Result<()> {
+        self.inner.start(requests).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::MeteredIndex;
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::{Index, Mailbox, Owner, Permission};
+    use crate::metrics::Metrics;
+
+    #[async_std::test]
+    async fn test_counts_operations() {
+        let metrics = Arc::new(Metrics::new());
+        let index = MeteredIndex::new(Box::new(InMemoryIndex::new()), metrics.clone());
+        let owner = Owner::new("username");
+        let inbox = Mailbox::new("INBOX", 0, vec![], Permission::ReadWrite);
+        index.add_mailbox(&owner, inbox).await.unwrap();
+        index
+            .get_mailbox(&owner, "INBOX", Permission::ReadOnly)
+            .await
+            .unwrap();
+        assert!(index
+            .get_mailbox(&owner, "Missing", Permission::ReadOnly)
+            .await
+            .is_err());
+        assert_eq!(metrics.mailbox_operations("add_mailbox"), 1);
+        assert_eq!(metrics.mailbox_operations("get_mailbox"), 2);
+        assert_eq!(metrics.mailbox_operations("list_messages"), 0);
+    }
+}
@@ -1,12 +1,31 @@
+pub mod account;
+pub mod cached;
+pub mod chaos;
+pub mod delivery;
 pub mod inmemory;
+pub mod journal;
+pub mod message;
+pub mod metered;
+pub mod reindex;
+pub mod retention;
+pub mod sqlite;
+pub mod transfer;
+pub mod uid;
 
-use std::{error::Error, fmt::Display};
+use std::{error::Error, fmt::Display, sync::Arc};
 
 use async_std::path::PathBuf;
 use futures::{channel::{mpsc::UnboundedReceiver, oneshot::Sender}, StreamExt};
 use log::warn;
 
-#[derive(Debug, Clone, Copy)]
+use crate::auth::User;
+use crate::store::StoreError;
+
+use self::journal::JournalEntry;
+use self::message::{MessageQuery, MessageRecord};
+use self::uid::UidState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Permission {
     ReadOnly,
     ReadWrite,
@@ -18,6 +37,9 @@ pub struct Mailbox {
     pub count: u64,
     pub flags: Vec<Flag>,
     pub permission: Permission,
+    pub uid_validity: u32,
+    pub uid_next: u32,
+    pub highest_modseq: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -26,10 +48,68 @@ pub struct Flag {
     pub permanent: bool,
 }
 
+/// The flags defined by RFC 9051 which a client may set, as opposed to keywords of its own.
+pub const SYSTEM_FLAGS: [&str; 5] = ["\\Answered", "\\Flagged", "\\Deleted", "\\Seen", "\\Draft"];
+
+impl Flag {
+    pub fn new(value: &str) -> Self {
+        Self { value: value.to_string(), permanent: true }
+    }
+    /// The system flags, which every mailbox keeps.
+    pub fn system() -> Vec<Flag> {
+        SYSTEM_FLAGS.iter().map(|flag| Flag::new(flag)).collect()
+    }
+    /// Returns the flag a client named, with system flags in their usual case, or `None` if
+    /// it is neither a system flag nor a valid keyword. Flags are matched case-insensitively.
+    pub fn normalize(value: &str) -> Option<String> {
+        if let Some(system) = SYSTEM_FLAGS.iter().find(|flag| flag.eq_ignore_ascii_case(value)) {
+            return Some(system.to_string());
+        }
+        // A keyword is an atom: no specials, wildcards or backslashes.
+        let valid = !value.is_empty()
+            && value
+                .bytes()
+                .all(|byte| byte > b' ' && byte < 0x7f && !b"(){%*\"\\]".contains(&byte));
+        valid.then(|| value.to_string())
+    }
+    pub fn is_keyword(value: &str) -> bool {
+        !value.starts_with('\\')
+    }
+}
+
+/// The user a mailbox belongs to. Every index operation is scoped to an owner, so two users
+/// selecting INBOX see two different mailboxes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Owner {
+    name: String,
+}
+
+impl Owner {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_string() }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl From<&User> for Owner {
+    fn from(user: &User) -> Self {
+        Owner::new(&user.name())
+    }
+}
+
+impl Display for Owner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
 #[derive(Debug)]
 pub struct GetMailboxRequest {
+    pub owner: Owner,
     pub name: String,
-    pub responder: Sender<Option<Mailbox>>,
+    pub responder: Sender<Result<Mailbox, MailboxError>>,
     pub permission: Permission,
 }
 
@@ -40,8 +120,16 @@ impl Mailbox {
             count,
             flags,
             permission,
+            uid_validity: 0,
+            uid_next: 1,
+            highest_modseq: 0,
         }
     }
+    pub fn with_uid_state(mut self, state: UidState) -> Self {
+        self.uid_validity = state.uid_validity;
+        self.uid_next = state.uid_next;
+        self
+    }
 }
 
 #[derive(Debug)]
@@ -49,8 +137,29 @@ pub enum MailboxError {
     Exists(String),
     DoesNotExist(String),
     InsufficientPermissions(String, String, String),
+    MessageDoesNotExist(String, u32),
+    HistoryUnavailable(String, u64),
+    /// The message was changed by someone else after the MODSEQ it was expected to have.
+    Modified(String, u32),
+    InvalidName(String),
+    OverQuota(String),
+    Storage(String),
 }
 impl Error for MailboxError {}
+impl MailboxError {
+    /// The RFC 5530 response code describing this error, if there is one.
+    pub fn code(&self) -> Option<&'static str> {
+        match self {
+            MailboxError::Exists(..) => Some("ALREADYEXISTS"),
+            MailboxError::DoesNotExist(..) | MailboxError::MessageDoesNotExist(..) => Some("NONEXISTENT"),
+            MailboxError::InsufficientPermissions(..) => Some("NOPERM"),
+            MailboxError::HistoryUnavailable(..) | MailboxError::Modified(..) => None,
+            MailboxError::InvalidName(..) => Some("CANNOT"),
+            MailboxError::OverQuota(..) => Some("OVERQUOTA"),
+            MailboxError::Storage(..) => Some("UNAVAILABLE"),
+        }
+    }
+}
 impl Display for MailboxError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -62,29 +171,137 @@ impl Display for MailboxError {
             },
             MailboxError::InsufficientPermissions(name, username, requested) => {
                 write!(f, "User {} does not have sufficient permissions to {} on mailbox {}", username, requested, name)
+            },
+            MailboxError::MessageDoesNotExist(name, uid) => {
+                write!(f, "Message {} does not exist in mailbox {}", uid, name)
+            },
+            MailboxError::HistoryUnavailable(name, modseq) => {
+                write!(f, "Changes to mailbox {} since MODSEQ {} are no longer available", name, modseq)
+            },
+            MailboxError::Modified(name, uid) => {
+                write!(f, "Message {} in mailbox {} has changed in the meantime", uid, name)
+            },
+            MailboxError::InvalidName(name) => {
+                write!(f, "Mailbox name {} is not allowed", name)
+            },
+            MailboxError::OverQuota(user) => {
+                write!(f, "User {} is over their storage quota", user)
+            },
+            MailboxError::Storage(message) => {
+                write!(f, "Mailbox storage error: {}", message)
             }
         }
     }
 }
+impl From<StoreError> for MailboxError {
+    fn from(e: StoreError) -> Self {
+        match e {
+            StoreError::InvalidName(name) => MailboxError::InvalidName(name),
+            StoreError::OverQuota(user) => MailboxError::OverQuota(user),
+            e => MailboxError::Storage(e.to_string()),
+        }
+    }
+}
 
 #[async_trait::async_trait]
 pub trait Index: Sync + Send {
-    async fn add_mailbox(&self, mailbox: Mailbox) -> Result<(), MailboxError>;
-    async fn get_mailbox(&self, name: &str, permission: Permission) -> Result<Mailbox, MailboxError>;
+    async fn add_mailbox(&self, owner: &Owner, mailbox: Mailbox) -> Result<(), MailboxError>;
+    async fn get_mailbox(&self, owner: &Owner, name: &str, permission: Permission) -> Result<Mailbox, MailboxError>;
+    /// Records a message stored under `message.uid` and returns it with its MODSEQ assigned.
+    async fn add_message(&self, owner: &Owner, mailbox: &str, message: MessageRecord) -> Result<MessageRecord, MailboxError>;
+    /// Returns every message in a mailbox in ascending UID order, so the position of a record
+    /// is its message sequence number minus one.
+    async fn list_messages(&self, owner: &Owner, mailbox: &str) -> Result<Vec<MessageRecord>, MailboxError>;
+    /// Replaces the flags of a message, assigning it a new MODSEQ.
+    async fn set_flags(&self, owner: &Owner, mailbox: &str, uid: u32, flags: Vec<String>) -> Result<MessageRecord, MailboxError>;
+    /// Replaces the flags of a message as `set_flags` does, unless its MODSEQ is greater than
+    /// `modseq`, in which case it is left as it is and `MailboxError::Modified` is returned.
+    /// Indexes should check and set at once; by default another change can slip in between.
+    async fn set_flags_unchanged_since(&self, owner: &Owner, mailbox: &str, uid: u32, modseq: u64, flags: Vec<String>) -> Result<MessageRecord, MailboxError> {
+        if self.get_message(owner, mailbox, uid).await?.modseq > modseq {
+            return Err(MailboxError::Modified(mailbox.to_string(), uid));
+        }
+        self.set_flags(owner, mailbox, uid, flags).await
+    }
+    /// Removes messages from the index and returns the UIDs which were present.
+    async fn remove_messages(&self, owner: &Owner, mailbox: &str, uids: &[u32]) -> Result<Vec<u32>, MailboxError>;
+    /// Returns the journal entries of a mailbox with a MODSEQ greater than `modseq`, or
+    /// `MailboxError::HistoryUnavailable` if retention has already discarded some of them.
+    async fn changes_since(&self, owner: &Owner, mailbox: &str, modseq: u64) -> Result<Vec<JournalEntry>, MailboxError>;
+    /// Subscribes to the journal entries recorded for a mailbox from now on.
+    async fn watch(&self, owner: &Owner, mailbox: &str) -> Result<crate::util::Receiver<JournalEntry>, MailboxError>;
+    /// Rewrites the change journals to drop entries no consumer needs, for indexes which keep
+    /// them. See `journal::Journal::compact`.
+    async fn compact_journals(&self) -> Result<(), MailboxError> {
+        Ok(())
+    }
+    async fn get_message(&self, owner: &Owner, mailbox: &str, uid: u32) -> Result<MessageRecord, MailboxError> {
+        self.list_messages(owner, mailbox)
+            .await?
+            .into_iter()
+            .find(|message| message.uid == uid)
+            .ok_or_else(|| MailboxError::MessageDoesNotExist(mailbox.to_string(), uid))
+    }
+    async fn query_messages(&self, owner: &Owner, mailbox: &str, query: &MessageQuery) -> Result<Vec<MessageRecord>, MailboxError> {
+        Ok(self.list_messages(owner, mailbox)
+            .await?
+            .into_iter()
+            .filter(|message| query.matches(message))
+            .collect())
+    }
     async fn start(&self, mut requests: UnboundedReceiver<GetMailboxRequest>) -> crate::util::Result<()> {
         while let Some(request) = requests.next().await {
-            match self.get_mailbox(&request.name, request.permission).await {
-                Ok(mailbox) => {
-                    request.responder.send(Some(mailbox)).unwrap();
-                },
-                Err(e) => {
-                    // TODO: send MailboxError
-                    warn!("{}", e);
-                    request.responder.send(None).unwrap();
-                },
+            let mailbox = self.get_mailbox(&request.owner, &request.name, request.permission).await;
+            if let Err(e) = &mailbox {
+                warn!("{}", e);
+            }
+            if request.responder.send(mailbox).is_err() {
+                warn!("Mailbox {} was looked up for a requester which has gone away", request.name);
             }
-            
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[async_trait::async_trait]
+impl<I: Index + ?Sized> Index for Arc<I> {
+    async fn add_mailbox(&self, owner: &Owner, mailbox: Mailbox) -> Result<(), MailboxError> {
+        (**self).add_mailbox(owner, mailbox).await
+    }
+    async fn get_mailbox(&self, owner: &Owner, name: &str, permission: Permission) -> Result<Mailbox, MailboxError> {
+        (**self).get_mailbox(owner, name, permission).await
+    }
+    async fn add_message(&self, owner: &Owner, mailbox: &str, message: MessageRecord) -> Result<MessageRecord, MailboxError> {
+        (**self).add_message(owner, mailbox, message).await
+    }
+    async fn list_messages(&self, owner: &Owner, mailbox: &str) -> Result<Vec<MessageRecord>, MailboxError> {
+        (**self).list_messages(owner, mailbox).await
+    }
+    async fn set_flags(&self, owner: &Owner, mailbox: &str, uid: u32, flags: Vec<String>) -> Result<MessageRecord, MailboxError> {
+        (**self).set_flags(owner, mailbox, uid, flags).await
+    }
+    async fn set_flags_unchanged_since(&self, owner: &Owner, mailbox: &str, uid: u32, modseq: u64, flags: Vec<String>) -> Result<MessageRecord, MailboxError> {
+        (**self).set_flags_unchanged_since(owner, mailbox, uid, modseq, flags).await
+    }
+    async fn remove_messages(&self, owner: &Owner, mailbox: &str, uids: &[u32]) -> Result<Vec<u32>, MailboxError> {
+        (**self).remove_messages(owner, mailbox, uids).await
+    }
+    async fn changes_since(&self, owner: &Owner, mailbox: &str, modseq: u64) -> Result<Vec<JournalEntry>, MailboxError> {
+        (**self).changes_since(owner, mailbox, modseq).await
+    }
+    async fn watch(&self, owner: &Owner, mailbox: &str) -> Result<crate::util::Receiver<JournalEntry>, MailboxError> {
+        (**self).watch(owner, mailbox).await
+    }
+    async fn compact_journals(&self) -> Result<(), MailboxError> {
+        (**self).compact_journals().await
+    }
+    async fn get_message(&self, owner: &Owner, mailbox: &str, uid: u32) -> Result<MessageRecord, MailboxError> {
+        (**self).get_message(owner, mailbox, uid).await
+    }
+    async fn query_messages(&self, owner: &Owner, mailbox: &str, query: &MessageQuery) -> Result<Vec<MessageRecord>, MailboxError> {
+        (**self).query_messages(owner, mailbox, query).await
+    }
+    async fn start(&self, requests: UnboundedReceiver<GetMailboxRequest>) -> crate::util::Result<()> {
+        (**self).start(requests).await
+    }
+}
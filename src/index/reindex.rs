@@ -0,0 +1,156 @@
+use std::collections::HashSet;
+
+use super::message::{Envelope, MessageRecord};
+use super::{Index, Mailbox, MailboxError, Owner, Permission};
+use crate::store::DataStore;
+use crate::util::Result;
+
+/// Reported after each message is indexed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Progress {
+    pub mailbox: String,
+    pub indexed: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReindexSummary {
+    pub mailboxes: usize,
+    pub messages: usize,
+    /// Records which were in the index but no longer have a message in the store.
+    pub removed: usize,
+}
+
+/// Rebuilds the index entries for every mailbox `owner` has in `store`.
+///
+/// Mailboxes missing from the index are created, every stored message is (re-)recorded under
+/// its existing UID with its flags, size, internal date and envelope, and index records with no
+/// stored message are dropped. Nothing else needs to be running, so this can be used to recover
+/// an index while the server is offline.
+pub async fn reindex<F>(
+    store: &dyn DataStore,
+    index: &dyn Index,
+    owner: &Owner,
+    mut progress: F,
+) -> Result<ReindexSummary>
+where
+    F: FnMut(&Progress),
+{
+    let mut summary = ReindexSummary::default();
+    for name in store.mailboxes(owner.name()).await? {
+        match index.get_mailbox(owner, &name, Permission::ReadWrite).await {
+            Err(MailboxError::DoesNotExist(_)) => {
+                index
+                    .add_mailbox(owner, Mailbox::new(&name, 0, vec![], Permission::ReadWrite))
+                    .await?
+            }
+            Err(e) => return Err(Box::new(e)),
+            Ok(_) => {}
+        }
+
+        let messages = store.list(owner.name(), &name).await?;
+        let stored: HashSet<u32> = messages.iter().map(|message| message.uid).collect();
+        let stale: Vec<u32> = index
+            .list_messages(owner, &name)
+            .await?
+            .iter()
+            .map(|record| record.uid)
+            .filter(|uid| !stored.contains(uid))
+            .collect();
+        summary.removed += index.remove_messages(owner, &name, &stale).await?.len();
+
+        let total = messages.len();
+        for (position, message) in messages.into_iter().enumerate() {
+            let body = store.fetch(owner.name(), &name, message.uid).await?;
+            let record = MessageRecord::new(message.uid, message.size, message.internal_date)
+                .with_flags(message.flags)
+                .with_envelope(Envelope::parse(&body));
+            index.add_message(owner, &name, record).await?;
+            progress(&Progress {
+                mailbox: name.clone(),
+                indexed: position + 1,
+                total,
+            });
+        }
+        summary.mailboxes += 1;
+        summary.messages += total;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::{reindex, ReindexSummary};
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::message::MessageRecord;
+    use crate::index::{Index, Owner, Permission};
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message};
+
+    #[async_std::test]
+    async fn test_reindex_from_store() {
+        let me = Owner::new("me");
+        let store = InMemoryStore::new();
+        store
+            .append(
+                "me",
+                "INBOX",
+                Message::new(b"Subject: hello\r\n\r\nbody").with_flags(vec!["\\Seen"]),
+            )
+            .await
+            .unwrap();
+        store
+            .append("me", "Archive", Message::new(b"Subject: old\r\n\r\n"))
+            .await
+            .unwrap();
+        store
+            .append("me", "Archive", Message::new(b"Subject: older\r\n\r\n"))
+            .await
+            .unwrap();
+        store
+            .append("you", "INBOX", Message::new(b"not mine"))
+            .await
+            .unwrap();
+
+        let index = InMemoryIndex::new();
+        index
+            .add_message(&me, "INBOX", MessageRecord::new(7, 1, SystemTime::now()))
+            .await
+            .unwrap();
+        let mut reported = vec![];
+        let summary = reindex(&store, &index, &me, |progress| {
+            reported.push((progress.mailbox.clone(), progress.indexed, progress.total))
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            summary,
+            ReindexSummary {
+                mailboxes: 2,
+                messages: 3,
+                removed: 1
+            }
+        );
+        assert_eq!(
+            reported,
+            vec![
+                ("Archive".to_string(), 1, 2),
+                ("Archive".to_string(), 2, 2),
+                ("INBOX".to_string(), 1, 1)
+            ]
+        );
+        let inbox = index.list_messages(&me, "INBOX").await.unwrap();
+        assert_eq!(inbox.len(), 1);
+        assert!(inbox[0].has_flag("\\Seen"));
+        assert_eq!(inbox[0].envelope.subject.as_deref(), Some("hello"));
+        let archive = index
+            .get_mailbox(&me, "Archive", Permission::ReadOnly)
+            .await
+            .unwrap();
+        assert_eq!(archive.count, 2);
+        assert_eq!(archive.uid_next, 3);
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use log::{debug, info};
+
+use super::{Index, MailboxError, Owner};
+use crate::metrics::Metrics;
+use crate::scheduler::Job;
+use crate::server::Components;
+use crate::store::DataStore;
+use crate::util::Result;
+
+/// Expunges messages which have sat in folders such as Trash and Junk for longer than a set
+/// age, judged by their internal date.
+///
+/// Messages are removed from the data store and then the index, which records the expunge in
+/// the journal of the mailbox, so sessions with it selected learn of it as they would of an
+/// EXPUNGE by another client: as untagged EXPUNGE responses, or VANISHED with QRESYNC.
+///
+/// Users can be given their own ages for a folder, or have it kept forever, with
+/// `with_user_override`.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    folders: HashMap<String, Duration>,
+    overrides: HashMap<(String, String), Option<Duration>>,
+    interval: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RetentionPolicy {
+    pub fn new() -> Self {
+        Self {
+            folders: HashMap::new(),
+            overrides: HashMap::new(),
+            interval: Duration::from_secs(60 * 60),
+        }
+    }
+    /// Expunges messages older than `max_age` from the folder `name` of every user.
+    pub fn with_folder(mut self, name: &str, max_age: Duration) -> Self {
+        self.folders.insert(name.to_string(), max_age);
+        self
+    }
+    /// Replaces the age of the folder `name` for `user`, or exempts it when `max_age` is
+    /// `None`. Folders with no policy of their own can be given one this way too.
+    pub fn with_user_override(mut self, user: &str, name: &str, max_age: Option<Duration>) -> Self {
+        self.overrides
+            .insert((user.to_string(), name.to_string()), max_age);
+        self
+    }
+    /// How often every folder is checked, hourly by default.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// The age past which messages in the folder `name` of `owner` are expunged, if any.
+    pub fn max_age(&self, owner: &Owner, name: &str) -> Option<Duration> {
+        match self
+            .overrides
+            .get(&(owner.name().to_string(), name.to_string()))
+        {
+            Some(max_age) => *max_age,
+            None => self.folders.get(name).copied(),
+        }
+    }
+
+    /// Expunges every message past its age from the folders of `users`, returning how many
+    /// were.
+    pub async fn expunge(
+        &self,
+        users: &[String],
+        store: &dyn DataStore,
+        index: &dyn Index,
+    ) -> Result<usize> {
+        let mut expunged = 0;
+        for user in users {
+            let owner = Owner::new(user);
+            let mut folders: Vec<&String> = self.folders.keys().collect();
+            folders.extend(
+                self.overrides
+                    .keys()
+                    .filter(|(name, _)| name == user)
+                    .map(|(_, folder)| folder),
+            );
+            folders.sort();
+            folders.dedup();
+            for folder in folders {
+                if let Some(max_age) = self.max_age(&owner, folder) {
+                    expunged += expunge_older(&owner, folder, max_age, store, index).await?;
+                }
+            }
+        }
+        Ok(expunged)
+    }
+}
+
+#[async_trait::async_trait]
+impl Job for RetentionPolicy {
+    fn name(&self) -> &str {
+        "retention"
+    }
+    async fn run(&self, components: &Components, _: &Metrics) -> Result<()> {
+        let users = components.user_store.list().await?;
+        let store = components.data_store.as_ref().as_ref();
+        let index = components.index.as_ref().as_ref();
+        let expunged = self.expunge(&users, store, index).await?;
+        if expunged > 0 {
+            info!("Expunged {} messages past their retention", expunged);
+        }
+        Ok(())
+    }
+}
+
+async fn expunge_older(
+    owner: &Owner,
+    folder: &str,
+    max_age: Duration,
+    store: &dyn DataStore,
+    index: &dyn Index,
+) -> Result<usize> {
+    let records = match index.list_messages(owner, folder).await {
+        Ok(records) => records,
+        Err(MailboxError::DoesNotExist(..)) => return Ok(0),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let oldest = SystemTime::now()
+        .checked_sub(max_age)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let expired: Vec<u32> = records
+        .iter()
+        .filter(|record| record.internal_date < oldest)
+        .map(|record| record.uid)
+        .collect();
+    if expired.is_empty() {
+        return Ok(0);
+    }
+    let removed = store.expunge(owner.name(), folder, &expired).await?;
+    index.remove_messages(owner, folder, &removed).await?;
+    debug!(
+        "Expunged {} messages from {} of {}",
+        removed.len(),
+        folder,
+        owner
+    );
+    Ok(removed.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use futures::StreamExt;
+
+    use super::RetentionPolicy;
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::journal::Change;
+    use crate::index::message::MessageRecord;
+    use crate::index::{Index, Mailbox, Owner, Permission};
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message};
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    async fn add(
+        store: &InMemoryStore,
+        index: &InMemoryIndex,
+        user: &str,
+        folder: &str,
+        age: Duration,
+    ) -> u32 {
+        let date = SystemTime::now() - age;
+        let message = Message::new(b"Subject: old\r\n\r\n").with_internal_date(date);
+        let uid = store.append(user, folder, message).await.unwrap();
+        let owner = Owner::new(user);
+        if index
+            .get_mailbox(&owner, folder, Permission::ReadOnly)
+            .await
+            .is_err()
+        {
+            let mailbox = Mailbox::new(folder, 0, vec![], Permission::ReadWrite);
+            index.add_mailbox(&owner, mailbox).await.unwrap();
+        }
+        index
+            .add_message(&owner, folder, MessageRecord::new(uid, 16, date))
+            .await
+            .unwrap();
+        uid
+    }
+
+    #[async_std::test]
+    async fn test_expunge() {
+        let (store, index) = (InMemoryStore::new(), InMemoryIndex::new());
+        let old = add(&store, &index, "me", "Trash", 40 * DAY).await;
+        add(&store, &index, "me", "Trash", DAY).await;
+        add(&store, &index, "me", "Junk", 10 * DAY).await;
+        add(&store, &index, "me", "INBOX", 400 * DAY).await;
+        add(&store, &index, "hoarder", "Trash", 40 * DAY).await;
+        add(&store, &index, "hoarder", "Junk", 10 * DAY).await;
+        let me = Owner::new("me");
+        let mut changes = index.watch(&me, "Trash").await.unwrap();
+
+        let policy = RetentionPolicy::new()
+            .with_folder("Trash", 30 * DAY)
+            .with_folder("Junk", 7 * DAY)
+            .with_user_override("hoarder", "Trash", None)
+            .with_user_override("hoarder", "Junk", Some(14 * DAY));
+        let hoarder = Owner::new("hoarder");
+        assert_eq!(policy.max_age(&me, "Trash"), Some(30 * DAY));
+        assert_eq!(policy.max_age(&hoarder, "Trash"), None);
+        assert_eq!(policy.max_age(&me, "INBOX"), None);
+
+        let users = vec![
+            "me".to_string(),
+            "hoarder".to_string(),
+            "nobody".to_string(),
+        ];
+        assert_eq!(policy.expunge(&users, &store, &index).await.unwrap(), 2);
+        assert_eq!(policy.expunge(&users, &store, &index).await.unwrap(), 0);
+
+        assert_eq!(index.list_messages(&me, "Trash").await.unwrap().len(), 1);
+        assert!(index.list_messages(&me, "Junk").await.unwrap().is_empty());
+        assert_eq!(index.list_messages(&me, "INBOX").await.unwrap().len(), 1);
+        assert_eq!(store.list("me", "Trash").await.unwrap().len(), 1);
+        assert_eq!(
+            index.list_messages(&hoarder, "Trash").await.unwrap().len(),
+            1
+        );
+        assert_eq!(
+            index.list_messages(&hoarder, "Junk").await.unwrap().len(),
+            1
+        );
+        assert_eq!(
+            changes.next().await.unwrap().change,
+            Change::Expunge(vec![old])
+        );
+    }
+}
@@ -0,0 +1,931 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_std::task::sleep;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::future;
+use futures::StreamExt;
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+use tracing::warn;
+
+use super::journal::{redundant, Change, JournalEntry, Retention};
+use super::message::MessageRecord;
+use super::{Flag, Index, Mailbox, MailboxError, Owner, Permission};
+use crate::runtime::{spawn, spawn_blocking};
+use crate::store::name::normalize;
+use crate::util::{Receiver, Sender};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS mailboxes (
+    owner TEXT NOT NULL,
+    name TEXT NOT NULL,
+    uid_validity INTEGER NOT NULL,
+    uid_next INTEGER NOT NULL,
+    highest_modseq INTEGER NOT NULL DEFAULT 0,
+    journal_floor INTEGER NOT NULL DEFAULT 0,
+    keywords TEXT NOT NULL DEFAULT '',
+    PRIMARY KEY (owner, name)
+);
+CREATE TABLE IF NOT EXISTS messages (
+    owner TEXT NOT NULL,
+    mailbox TEXT NOT NULL,
+    uid INTEGER NOT NULL,
+    flags TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    internal_date INTEGER NOT NULL,
+    envelope TEXT NOT NULL,
+    modseq INTEGER NOT NULL,
+    PRIMARY KEY (owner, mailbox, uid)
+);
+CREATE TABLE IF NOT EXISTS journal (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    owner TEXT NOT NULL,
+    mailbox TEXT NOT NULL,
+    modseq INTEGER NOT NULL,
+    at INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    uids TEXT NOT NULL,
+    flags TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS journal_by_mailbox ON journal (owner, mailbox, modseq);
+";
+
+/// How long a write waits for another server to release the database before failing.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+type MailboxKey = (String, String);
+
+/// The sessions watching mailboxes, and how far through the journal table they have been
+/// sent.
+#[derive(Default)]
+struct Watchers {
+    senders: Mutex<HashMap<MailboxKey, Vec<Sender<JournalEntry>>>>,
+    /// The id of the last journal row sent to the watchers.
+    last_seen: AtomicI64,
+    /// Wakes the poller early, after a change made by this server. `None` until something
+    /// watches a mailbox.
+    wake: Mutex<Option<UnboundedSender<()>>>,
+}
+
+/// An `Index` keeping mailboxes, message records, flags and the change journal in a SQLite
+/// database which several servers can share, so that any of them can serve any user.
+///
+/// Every change is made in one transaction holding the database's write lock, which moves
+/// UIDNEXT past the message's UID, takes the mailbox's next MODSEQ and journals the change,
+/// so servers never hand out the same MODSEQ twice or see a change half made. UIDs are
+/// assigned by the data store, which must be shared too, such as a `SqliteStore` on the same
+/// volume.
+///
+/// SQLite has nothing like Postgres's LISTEN/NOTIFY, so changes made by other servers are
+/// found by polling the journal table, every 250ms by default, and sent to the sessions
+/// watching the mailbox as untagged responses. Changes made by this server are sent at once.
+/// Journal retention is applied when the journals are compacted, rather than as changes are
+/// made.
+pub struct SqliteIndex {
+    connection: Arc<Mutex<Connection>>,
+    watchers: Arc<Watchers>,
+    retention: Retention,
+    poll_interval: Duration,
+}
+
+fn storage<E: Display>(e: E) -> MailboxError {
+    MailboxError::Storage(e.to_string())
+}
+
+fn to_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+fn from_millis(millis: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .map(|word| word.to_string())
+        .collect()
+}
+
+/// The kind, UIDs and flags columns of a journal row.
+fn encode(change: &Change) -> (&'static str, String, String) {
+    let join = |uids: &[u32]| {
+        uids.iter()
+            .map(|uid| uid.to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    match change {
+        Change::Append(uid) => ("append", uid.to_string(), String::new()),
+        Change::Flags(uid, flags) => ("flags", uid.to_string(), flags.join(" ")),
+        Change::Expunge(uids) => ("expunge", join(uids), String::new()),
+    }
+}
+
+fn decode(kind: &str, uids: &str, flags: &str) -> Result<Change, MailboxError> {
+    let corrupt = || MailboxError::Storage(format!("corrupt journal entry of kind {}", kind));
+    let uids = uids
+        .split_whitespace()
+        .map(|uid| uid.parse().map_err(|_| corrupt()))
+        .collect::<Result<Vec<u32>, _>>()?;
+    match (kind, uids.as_slice()) {
+        ("append", [uid]) => Ok(Change::Append(*uid)),
+        ("flags", [uid]) => Ok(Change::Flags(*uid, words(flags))),
+        ("expunge", _) => Ok(Change::Expunge(uids)),
+        _ => Err(corrupt()),
+    }
+}
+
+/// Creates INBOX for `owner` unless it exists. Every user has one, whether or not anything
+/// has been delivered to it.
+fn ensure_inbox(connection: &Connection, owner: &str) -> Result<(), MailboxError> {
+    connection
+        .execute(
+            "INSERT OR IGNORE INTO mailboxes (owner, name, uid_validity, uid_next) VALUES (?1, 'INBOX', ?2, 1)",
+            params![owner, uid_validity()],
+        )
+        .map_err(storage)?;
+    Ok(())
+}
+
+fn uid_validity() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as u32)
+        .unwrap_or(1)
+        .max(1)
+}
+
+fn exists(connection: &Connection, owner: &str, name: &str) -> Result<bool, MailboxError> {
+    if name == "INBOX" {
+        ensure_inbox(connection, owner)?;
+        return Ok(true);
+    }
+    Ok(connection
+        .query_row(
+            "SELECT 1 FROM mailboxes WHERE owner = ?1 AND name = ?2",
+            params![owner, name],
+            |_| Ok(()),
+        )
+        .optional()
+        .map_err(storage)?
+        .is_some())
+}
+
+fn describe(
+    connection: &Connection,
+    owner: &str,
+    name: &str,
+    permission: Permission,
+) -> Result<Option<Mailbox>, MailboxError> {
+    if name == "INBOX" {
+        ensure_inbox(connection, owner)?;
+    }
+    let found: Option<(u32, u32, i64, String)> = connection
+        .query_row(
+            "SELECT uid_validity, uid_next, highest_modseq, keywords FROM mailboxes WHERE owner = ?1 AND name = ?2",
+            params![owner, name],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()
+        .map_err(storage)?;
+    let Some((uid_validity, uid_next, highest_modseq, keywords)) = found else {
+        return Ok(None);
+    };
+    let count: i64 = connection
+        .query_row(
+            "SELECT COUNT(*) FROM messages WHERE owner = ?1 AND mailbox = ?2",
+            params![owner, name],
+            |row| row.get(0),
+        )
+        .map_err(storage)?;
+    let mut flags = Flag::system();
+    flags.extend(words(&keywords).iter().map(|keyword| Flag::new(keyword)));
+    Ok(Some(Mailbox {
+        uid_validity,
+        uid_next,
+        highest_modseq: highest_modseq as u64,
+        ..Mailbox::new(name, count as u64, flags, permission)
+    }))
+}
+
+/// Adds the keywords among `flags` to those the mailbox has seen.
+fn learn_keywords(
+    transaction: &Transaction,
+    owner: &str,
+    mailbox: &str,
+    flags: &[String],
+) -> Result<(), MailboxError> {
+    let known: String = transaction
+        .query_row(
+            "SELECT keywords FROM mailboxes WHERE owner = ?1 AND name = ?2",
+            params![owner, mailbox],
+            |row| row.get(0),
+        )
+        .map_err(storage)?;
+    let mut keywords = words(&known);
+    for flag in flags.iter().filter(|flag| Flag::is_keyword(flag)) {
+        if !keywords
+            .iter()
+            .any(|known| known.eq_ignore_ascii_case(flag))
+        {
+            keywords.push(flag.clone());
+        }
+    }
+    if keywords.len() > words(&known).len() {
+        transaction
+            .execute(
+                "UPDATE mailboxes SET keywords = ?3 WHERE owner = ?1 AND name = ?2",
+                params![owner, mailbox, keywords.join(" ")],
+            )
+            .map_err(storage)?;
+    }
+    Ok(())
+}
+
+/// Takes the next MODSEQ of a mailbox and journals `change` under it.
+fn record(
+    transaction: &Transaction,
+    owner: &str,
+    mailbox: &str,
+    change: &Change,
+) -> Result<u64, MailboxError> {
+    let modseq: i64 = transaction
+        .query_row(
+            "UPDATE mailboxes SET highest_modseq = highest_modseq + 1 WHERE owner = ?1 AND name = ?2 RETURNING highest_modseq",
+            params![owner, mailbox],
+            |row| row.get(0),
+        )
+        .map_err(storage)?;
+    let (kind, uids, flags) = encode(change);
+    transaction
+        .execute(
+            "INSERT INTO journal (owner, mailbox, modseq, at, kind, uids, flags) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![owner, mailbox, modseq, to_millis(SystemTime::now()), kind, uids, flags],
+        )
+        .map_err(storage)?;
+    Ok(modseq as u64)
+}
+
+/// The message records of a mailbox matching `filter`, a condition on the `uid` column.
+fn messages(
+    connection: &Connection,
+    owner: &str,
+    mailbox: &str,
+    filter: &str,
+    uid: u32,
+) -> Result<Vec<MessageRecord>, MailboxError> {
+    let mut statement = connection
+        .prepare(&format!(
+            "SELECT uid, flags, size, internal_date, envelope, modseq FROM messages WHERE owner = ?1 AND mailbox = ?2 AND {} ORDER BY uid",
+            filter
+        ))
+        .map_err(storage)?;
+    let rows = statement
+        .query_map(params![owner, mailbox, uid], |row| {
+            Ok((
+                row.get::<_, u32>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, i64>(5)?,
+            ))
+        })
+        .map_err(storage)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(storage)?;
+    rows.into_iter()
+        .map(|(uid, flags, size, internal_date, envelope, modseq)| {
+            Ok(MessageRecord {
+                uid,
+                flags: words(&flags),
+                size: size as u64,
+                internal_date: from_millis(internal_date),
+                envelope: serde_json::from_str(&envelope).map_err(storage)?,
+                modseq: modseq as u64,
+            })
+        })
+        .collect()
+}
+
+/// The journal rows matching `filter`, with the mailbox each belongs to.
+fn journal(
+    connection: &Connection,
+    filter: &str,
+    parameters: impl rusqlite::Params,
+) -> Result<Vec<(i64, MailboxKey, JournalEntry)>, MailboxError> {
+    let mut statement = connection
+        .prepare(&format!(
+            "SELECT id, owner, mailbox, modseq, at, kind, uids, flags FROM journal WHERE {} ORDER BY id",
+            filter
+        ))
+        .map_err(storage)?;
+    let rows = statement
+        .query_map(parameters, |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                (row.get::<_, String>(1)?, row.get::<_, String>(2)?),
+                row.get::<_, i64>(3)?,
+                row.get::<_, i64>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, String>(6)?,
+                row.get::<_, String>(7)?,
+            ))
+        })
+        .map_err(storage)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(storage)?;
+    rows.into_iter()
+        .map(|(id, key, modseq, at, kind, uids, flags)| {
+            let entry = JournalEntry {
+                modseq: modseq as u64,
+                at: from_millis(at),
+                change: decode(&kind, &uids, &flags)?,
+            };
+            Ok((id, key, entry))
+        })
+        .collect()
+}
+
+/// Drops the journal entries of a mailbox which `retention` no longer keeps, and those
+/// compaction makes redundant.
+fn compact(
+    transaction: &Transaction,
+    owner: &str,
+    mailbox: &str,
+    retention: Retention,
+) -> Result<(), MailboxError> {
+    let entries: Vec<JournalEntry> = journal(
+        transaction,
+        "owner = ?1 AND mailbox = ?2",
+        params![owner, mailbox],
+    )?
+    .into_iter()
+    .map(|(_, _, entry)| entry)
+    .collect();
+    let oldest = retention
+        .max_age
+        .and_then(|age| SystemTime::now().checked_sub(age));
+    let excess = retention
+        .max_entries
+        .map_or(0, |max| entries.len().saturating_sub(max));
+    let floor = entries
+        .iter()
+        .enumerate()
+        .take_while(|(position, entry)| {
+            *position < excess || oldest.is_some_and(|oldest| entry.at < oldest)
+        })
+        .map(|(_, entry)| entry.modseq)
+        .last();
+    let mut dropped = redundant(entries.iter());
+    if let Some(floor) = floor {
+        dropped.extend(
+            entries
+                .iter()
+                .map(|entry| entry.modseq)
+                .filter(|modseq| *modseq <= floor),
+        );
+        transaction
+            .execute(
+                "UPDATE mailboxes SET journal_floor = max(journal_floor, ?3) WHERE owner = ?1 AND name = ?2",
+                params![owner, mailbox, floor as i64],
+            )
+            .map_err(storage)?;
+    }
+    for modseq in dropped {
+        transaction
+            .execute(
+                "DELETE FROM journal WHERE owner = ?1 AND mailbox = ?2 AND modseq = ?3",
+                params![owner, mailbox, modseq as i64],
+            )
+            .map_err(storage)?;
+    }
+    Ok(())
+}
+
+impl SqliteIndex {
+    /// Opens the database at `path`, creating it if need be. Every server sharing the index
+    /// opens the same file.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, MailboxError> {
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent).map_err(storage)?;
+        }
+        let connection = Connection::open(path).map_err(storage)?;
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .map_err(storage)?;
+        connection.busy_timeout(BUSY_TIMEOUT).map_err(storage)?;
+        connection.execute_batch(SCHEMA).map_err(storage)?;
+        // Watchers are sent the changes made from now on.
+        let last_seen: i64 = connection
+            .query_row("SELECT COALESCE(MAX(id), 0) FROM journal", [], |row| {
+                row.get(0)
+            })
+            .map_err(storage)?;
+        let watchers = Watchers {
+            last_seen: AtomicI64::new(last_seen),
+            ..Default::default()
+        };
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            watchers: Arc::new(watchers),
+            retention: Retention::default(),
+            poll_interval: Duration::from_millis(250),
+        })
+    }
+    /// Sets how much change history is kept for each mailbox.
+    pub fn with_journal_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+    /// How often the journal is read for changes made by other servers.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    async fn run<T, F>(&self, operation: F) -> Result<T, MailboxError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, MailboxError> + Send + 'static,
+    {
+        run(&self.connection, operation).await
+    }
+
+    /// Sends the change just made to those watching, without waiting for the next poll.
+    fn wake(&self) {
+        if let Ok(wake) = self.watchers.wake.lock() {
+            if let Some(wake) = wake.as_ref() {
+                let _ = wake.unbounded_send(());
+            }
+        }
+    }
+
+    /// Replaces the flags of a message, if it has not changed since `unchanged_since`.
+    async fn update_flags(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+        unchanged_since: Option<u64>,
+        flags: Vec<String>,
+    ) -> Result<MessageRecord, MailboxError> {
+        let (owner, requested) = (owner.name().to_string(), mailbox.to_string());
+        let record = self
+            .run(move |connection| {
+                let name = normalize(&requested);
+                let transaction = connection
+                    .transaction_with_behavior(TransactionBehavior::Immediate)
+                    .map_err(storage)?;
+                let current = match messages(&transaction, &owner, &name, "uid = ?3", uid)?.pop() {
+                    Some(current) => current,
+                    None => return Err(MailboxError::MessageDoesNotExist(requested, uid)),
+                };
+                if unchanged_since.is_some_and(|modseq| current.modseq > modseq) {
+                    return Err(MailboxError::Modified(requested, uid));
+                }
+                learn_keywords(&transaction, &owner, &name, &flags)?;
+                let change = Change::Flags(uid, flags.clone());
+                let modseq = record(&transaction, &owner, &name, &change)?;
+                transaction
+                    .execute(
+                        "UPDATE messages SET flags = ?4, modseq = ?5 WHERE owner = ?1 AND mailbox = ?2 AND uid = ?3",
+                        params![owner, name, uid, flags.join(" "), modseq as i64],
+                    )
+                    .map_err(storage)?;
+                let updated = messages(&transaction, &owner, &name, "uid = ?3", uid)?.pop();
+                transaction.commit().map_err(storage)?;
+                updated.ok_or(MailboxError::MessageDoesNotExist(requested, uid))
+            })
+            .await?;
+        self.wake();
+        Ok(record)
+    }
+}
+
+async fn run<T, F>(connection: &Arc<Mutex<Connection>>, operation: F) -> Result<T, MailboxError>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Connection) -> Result<T, MailboxError> + Send + 'static,
+{
+    let connection = connection.clone();
+    spawn_blocking(move || {
+        let mut connection = connection.lock().map_err(storage)?;
+        operation(&mut connection)
+    })
+    .await
+}
+
+/// Sends the journal rows written since the last poll, by any server, to those watching
+/// their mailboxes, until the index is dropped.
+async fn poll(
+    connection: Weak<Mutex<Connection>>,
+    watchers: Arc<Watchers>,
+    interval: Duration,
+    mut wake: UnboundedReceiver<()>,
+) {
+    loop {
+        let _ = future::select(Box::pin(sleep(interval)), wake.next()).await;
+        let Some(connection) = connection.upgrade() else {
+            return;
+        };
+        let last_seen = watchers.last_seen.load(Ordering::SeqCst);
+        let rows = run(&connection, move |connection| {
+            journal(connection, "id > ?1", params![last_seen])
+        })
+        .await;
+        let rows = match rows {
+            Ok(rows) => rows,
+            Err(e) => {
+                warn!("Could not read the journal for changes: {}", e);
+                continue;
+            }
+        };
+        let mut senders = match watchers.senders.lock() {
+            Ok(senders) => senders,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for (id, key, entry) in rows {
+            if let Some(watching) = senders.get_mut(&key) {
+                watching.retain(|sender| sender.unbounded_send(entry.clone()).is_ok());
+            }
+            watchers.last_seen.store(id, Ordering::SeqCst);
+        }
+        senders.retain(|_, watching| !watching.is_empty());
+    }
+}
+
+#[async_trait::async_trait]
+impl Index for SqliteIndex {
+    async fn add_mailbox(&self, owner: &Owner, mailbox: Mailbox) -> Result<(), MailboxError> {
+        let owner = owner.name().to_string();
+        let name = normalize(&mailbox.name.to_string_lossy());
+        self.run(move |connection| {
+            let added = connection
+                .execute(
+                    "INSERT OR IGNORE INTO mailboxes (owner, name, uid_validity, uid_next) VALUES (?1, ?2, ?3, 1)",
+                    params![owner, name, uid_validity()],
+                )
+                .map_err(storage)?;
+            if added == 0 {
+                return Err(MailboxError::Exists(name));
+            }
+            Ok(())
+        })
+        .await
+    }
+    async fn get_mailbox(
+        &self,
+        owner: &Owner,
+        name: &str,
+        permission: Permission,
+    ) -> Result<Mailbox, MailboxError> {
+        let (owner, requested) = (owner.name().to_string(), name.to_string());
+        self.run(move |connection| {
+            describe(connection, &owner, &normalize(&requested), permission)?
+                .ok_or(MailboxError::DoesNotExist(requested))
+        })
+        .await
+    }
+    async fn add_message(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        message: MessageRecord,
+    ) -> Result<MessageRecord, MailboxError> {
+        let (owner, requested) = (owner.name().to_string(), mailbox.to_string());
+        let envelope = serde_json::to_string(&message.envelope).map_err(storage)?;
+        let added = self
+            .run(move |connection| {
+                let name = normalize(&requested);
+                let transaction = connection
+                    .transaction_with_behavior(TransactionBehavior::Immediate)
+                    .map_err(storage)?;
+                if !exists(&transaction, &owner, &name)? {
+                    return Err(MailboxError::DoesNotExist(requested));
+                }
+                transaction
+                    .execute(
+                        "UPDATE mailboxes SET uid_next = max(uid_next, ?3) WHERE owner = ?1 AND name = ?2",
+                        params![owner, name, message.uid as i64 + 1],
+                    )
+                    .map_err(storage)?;
+                learn_keywords(&transaction, &owner, &name, &message.flags)?;
+                let modseq = record(&transaction, &owner, &name, &Change::Append(message.uid))?;
+                transaction
+                    .execute(
+                        "INSERT OR REPLACE INTO messages (owner, mailbox, uid, flags, size, internal_date, envelope, modseq) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                        params![
+                            owner,
+                            name,
+                            message.uid,
+                            message.flags.join(" "),
+                            message.size as i64,
+                            to_millis(message.internal_date),
+                            envelope,
+                            modseq as i64
+                        ],
+                    )
+                    .map_err(storage)?;
+                transaction.commit().map_err(storage)?;
+                Ok(MessageRecord { modseq, ..message })
+            })
+            .await?;
+        self.wake();
+        Ok(added)
+    }
+    async fn list_messages(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+    ) -> Result<Vec<MessageRecord>, MailboxError> {
+        let (owner, requested) = (owner.name().to_string(), mailbox.to_string());
+        self.run(move |connection| {
+            let name = normalize(&requested);
+            if !exists(connection, &owner, &name)? {
+                return Err(MailboxError::DoesNotExist(requested));
+            }
+            messages(connection, &owner, &name, "uid >= ?3", 0)
+        })
+        .await
+    }
+    async fn get_message(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<MessageRecord, MailboxError> {
+        let (owner, requested) = (owner.name().to_string(), mailbox.to_string());
+        self.run(move |connection| {
+            let name = normalize(&requested);
+            if !exists(connection, &owner, &name)? {
+                return Err(MailboxError::DoesNotExist(requested));
+            }
+            messages(connection, &owner, &name, "uid = ?3", uid)?
+                .pop()
+                .ok_or(MailboxError::MessageDoesNotExist(requested, uid))
+        })
+        .await
+    }
+    async fn set_flags(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+        flags: Vec<String>,
+    ) -> Result<MessageRecord, MailboxError> {
+        self.update_flags(owner, mailbox, uid, None, flags).await
+    }
+    async fn set_flags_unchanged_since(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+        modseq: u64,
+        flags: Vec<String>,
+    ) -> Result<MessageRecord, MailboxError> {
+        self.update_flags(owner, mailbox, uid, Some(modseq), flags)
+            .await
+    }
+    async fn remove_messages(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>, MailboxError> {
+        let (owner, name) = (owner.name().to_string(), normalize(mailbox));
+        let uids = uids.to_vec();
+        let removed = self
+            .run(move |connection| {
+                let transaction = connection
+                    .transaction_with_behavior(TransactionBehavior::Immediate)
+                    .map_err(storage)?;
+                let mut removed = vec![];
+                for uid in uids {
+                    let deleted = transaction
+                        .execute(
+                            "DELETE FROM messages WHERE owner = ?1 AND mailbox = ?2 AND uid = ?3",
+                            params![owner, name, uid],
+                        )
+                        .map_err(storage)?;
+                    if deleted > 0 {
+                        removed.push(uid);
+                    }
+                }
+                if !removed.is_empty() {
+                    record(
+                        &transaction,
+                        &owner,
+                        &name,
+                        &Change::Expunge(removed.clone()),
+                    )?;
+                }
+                transaction.commit().map_err(storage)?;
+                Ok(removed)
+            })
+            .await?;
+        if !removed.is_empty() {
+            self.wake();
+        }
+        Ok(removed)
+    }
+    async fn changes_since(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        modseq: u64,
+    ) -> Result<Vec<JournalEntry>, MailboxError> {
+        let (owner, requested) = (owner.name().to_string(), mailbox.to_string());
+        self.run(move |connection| {
+            let name = normalize(&requested);
+            if name == "INBOX" {
+                ensure_inbox(connection, &owner)?;
+            }
+            let floor: i64 = connection
+                .query_row(
+                    "SELECT journal_floor FROM mailboxes WHERE owner = ?1 AND name = ?2",
+                    params![owner, name],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(storage)?
+                .ok_or_else(|| MailboxError::DoesNotExist(requested.clone()))?;
+            if modseq < floor as u64 {
+                return Err(MailboxError::HistoryUnavailable(requested, modseq));
+            }
+            Ok(journal(
+                connection,
+                "owner = ?1 AND mailbox = ?2 AND modseq > ?3",
+                params![owner, name, modseq as i64],
+            )?
+            .into_iter()
+            .map(|(_, _, entry)| entry)
+            .collect())
+        })
+        .await
+    }
+    async fn watch(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+    ) -> Result<Receiver<JournalEntry>, MailboxError> {
+        self.get_mailbox(owner, mailbox, Permission::ReadOnly)
+            .await?;
+        let (sender, receiver) = unbounded();
+        let key = (owner.name().to_string(), normalize(mailbox));
+        if let Ok(mut senders) = self.watchers.senders.lock() {
+            senders.entry(key).or_default().push(sender);
+        }
+        let mut wake = self.watchers.wake.lock().map_err(storage)?;
+        if wake.is_none() {
+            let (sender, receiver) = unbounded();
+            *wake = Some(sender);
+            spawn(poll(
+                Arc::downgrade(&self.connection),
+                self.watchers.clone(),
+                self.poll_interval,
+                receiver,
+            ));
+        }
+        Ok(receiver)
+    }
+    async fn compact_journals(&self) -> Result<(), MailboxError> {
+        let retention = self.retention;
+        self.run(move |connection| {
+            let mailboxes = {
+                let mut statement = connection
+                    .prepare("SELECT owner, name FROM mailboxes")
+                    .map_err(storage)?;
+                let rows = statement
+                    .query_map([], |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                    })
+                    .map_err(storage)?;
+                rows.collect::<Result<Vec<_>, _>>().map_err(storage)?
+            };
+            for (owner, name) in mailboxes {
+                let transaction = connection
+                    .transaction_with_behavior(TransactionBehavior::Immediate)
+                    .map_err(storage)?;
+                compact(&transaction, &owner, &name, retention)?;
+                transaction.commit().map_err(storage)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    use futures::StreamExt;
+
+    use super::SqliteIndex;
+    use crate::index::journal::{Change, Retention};
+    use crate::index::message::{Envelope, MessageRecord};
+    use crate::index::{Index, Mailbox, MailboxError, Owner, Permission};
+
+    fn temp_database(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "treasurmap-sqlite-index-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        root.join("index.sqlite")
+    }
+
+    #[async_std::test]
+    async fn test_servers_share_the_index() {
+        let path = temp_database("shared");
+        let me = Owner::new("me");
+        // Two servers with the same database.
+        let first = SqliteIndex::open(&path)
+            .unwrap()
+            .with_poll_interval(Duration::from_millis(20));
+        let second = SqliteIndex::open(&path).unwrap();
+        let mut changes = first.watch(&me, "INBOX").await.unwrap();
+
+        let envelope = Envelope::parse(b"Subject: hi\r\n\r\n");
+        let record = MessageRecord::new(4, 10, SystemTime::now())
+            .with_flags(vec!["$Work".to_string()])
+            .with_envelope(envelope.clone());
+        let added = second.add_message(&me, "inbox", record).await.unwrap();
+        assert_eq!(added.modseq, 1);
+        second
+            .set_flags(&me, "INBOX", 4, vec!["\\Seen".to_string()])
+            .await
+            .unwrap();
+
+        // The first server sees what the second did, as it happens and when it asks.
+        assert_eq!(changes.next().await.unwrap().change, Change::Append(4));
+        assert_eq!(
+            changes.next().await.unwrap().change,
+            Change::Flags(4, vec!["\\Seen".to_string()])
+        );
+        let inbox = first
+            .get_mailbox(&me, "INBOX", Permission::ReadOnly)
+            .await
+            .unwrap();
+        assert_eq!(
+            (inbox.count, inbox.uid_next, inbox.highest_modseq),
+            (1, 5, 2)
+        );
+        assert!(inbox.flags.iter().any(|flag| flag.value == "$Work"));
+        let message = first.get_message(&me, "INBOX", 4).await.unwrap();
+        assert_eq!(message.envelope, envelope);
+        assert_eq!(message.flags, vec!["\\Seen".to_string()]);
+
+        first
+            .add_mailbox(&me, Mailbox::new("Work", 0, vec![], Permission::ReadWrite))
+            .await
+            .unwrap();
+        assert!(matches!(
+            second
+                .add_mailbox(&me, Mailbox::new("Work", 0, vec![], Permission::ReadWrite))
+                .await,
+            Err(MailboxError::Exists(..))
+        ));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_journal_retention_and_compaction() {
+        let path = temp_database("journal");
+        let me = Owner::new("me");
+        let index = SqliteIndex::open(&path)
+            .unwrap()
+            .with_journal_retention(Retention {
+                max_entries: Some(2),
+                max_age: None,
+            });
+        for uid in 1..=3 {
+            let record = MessageRecord::new(uid, 10, SystemTime::now());
+            index.add_message(&me, "INBOX", record).await.unwrap();
+        }
+        index.remove_messages(&me, "INBOX", &[3]).await.unwrap();
+        assert_eq!(index.changes_since(&me, "INBOX", 0).await.unwrap().len(), 4);
+
+        index.compact_journals().await.unwrap();
+        assert!(matches!(
+            index.changes_since(&me, "INBOX", 0).await,
+            Err(MailboxError::HistoryUnavailable(..))
+        ));
+        // The append of the expunged message is redundant, so only the expunge is left.
+        let changes = index.changes_since(&me, "INBOX", 2).await.unwrap();
+        assert_eq!(
+            changes.into_iter().map(|e| e.change).collect::<Vec<_>>(),
+            vec![Change::Expunge(vec![3])]
+        );
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,587 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_std::fs::{self, File};
+use async_std::io::{BufReader, BufWriter};
+use futures::{AsyncBufReadExt, AsyncWriteExt, StreamExt};
+
+use super::message::{Envelope, MessageRecord};
+use super::{Index, Mailbox, MailboxError, Owner, Permission};
+use crate::mime::split_header;
+use crate::store::{DataStore, Message, MessageMetadata};
+use crate::util::{Result, UtcTime};
+
+const INBOX: &str = "INBOX";
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+/// How many mailboxes and messages were imported or exported.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferSummary {
+    pub mailboxes: usize,
+    pub messages: usize,
+}
+
+/// Imports the messages of the mbox file at `path` into `mailbox`, creating it if needed.
+///
+/// The file is read as mboxrd, so `>From ` lines are unquoted once. Each message keeps the date
+/// of its `From ` line as its internal date and the flags of its `Status` and `X-Status`
+/// headers, which are dropped from the stored message.
+pub async fn import_mbox(
+    path: &Path,
+    store: &dyn DataStore,
+    index: &dyn Index,
+    owner: &Owner,
+    mailbox: &str,
+) -> Result<TransferSummary> {
+    let mut input = BufReader::new(File::open(path).await?);
+    let mut summary = TransferSummary {
+        mailboxes: 1,
+        messages: 0,
+    };
+    ensure_mailbox(index, owner, mailbox).await?;
+    let mut line = vec![];
+    let mut current: Option<(SystemTime, Vec<u8>)> = None;
+    loop {
+        line.clear();
+        let read = input.read_until(b'\n', &mut line).await?;
+        let separator = line.starts_with(b"From ");
+        if read == 0 || separator {
+            if let Some((internal_date, body)) = current.take() {
+                import_message(store, index, owner, mailbox, &body, internal_date).await?;
+                summary.messages += 1;
+            }
+            if read == 0 {
+                return Ok(summary);
+            }
+            let date = parse_separator_date(&String::from_utf8_lossy(&line));
+            current = Some((date.unwrap_or_else(SystemTime::now), vec![]));
+            continue;
+        }
+        // Text before the first separator is not part of any message.
+        let Some((_, body)) = current.as_mut() else {
+            continue;
+        };
+        let content = line.strip_suffix(b"\n").unwrap_or(&line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        let unquoted = match content.iter().position(|byte| *byte != b'>') {
+            Some(quotes) if quotes > 0 && content[quotes..].starts_with(b"From ") => &content[1..],
+            _ => content,
+        };
+        body.extend_from_slice(unquoted);
+        body.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Imports a Maildir++ tree, such as `~/Maildir`: the messages in its `cur/` and `new/`
+/// directories go to INBOX, and those of each `.<name>` folder to the mailbox `name`.
+///
+/// Each message keeps the flags of its info suffix, and the modification time of its file as
+/// its internal date. The tree is left as it was.
+pub async fn import_maildir(
+    root: &Path,
+    store: &dyn DataStore,
+    index: &dyn Index,
+    owner: &Owner,
+) -> Result<TransferSummary> {
+    let mut folders = vec![(INBOX.to_string(), root.to_path_buf())];
+    let mut entries = fs::read_dir(root).await?;
+    while let Some(entry) = entries.next().await {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let mailbox = match name.strip_prefix('.') {
+            Some(mailbox) if !mailbox.is_empty() && mailbox != "." => mailbox,
+            _ => continue,
+        };
+        if entry.file_type().await?.is_dir() {
+            let folder = Path::new(entry.path().as_os_str()).to_path_buf();
+            folders.push((mailbox.to_string(), folder));
+        }
+    }
+    folders[1..].sort();
+
+    let mut summary = TransferSummary::default();
+    for (mailbox, folder) in folders {
+        ensure_mailbox(index, owner, &mailbox).await?;
+        summary.mailboxes += 1;
+        for directory in ["cur", "new"] {
+            let mut files = match fs::read_dir(folder.join(directory)).await {
+                Ok(files) => files,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(Box::new(e)),
+            };
+            let mut names = vec![];
+            while let Some(file) = files.next().await {
+                let file = file?;
+                let name = file.file_name().to_string_lossy().to_string();
+                if !name.starts_with('.') && file.file_type().await?.is_file() {
+                    names.push(name);
+                }
+            }
+            // Maildir names start with the time of delivery, so this keeps the order.
+            names.sort();
+            for name in names {
+                let path = folder.join(directory).join(&name);
+                let body = fs::read(&path).await?;
+                let internal_date = fs::metadata(&path)
+                    .await?
+                    .modified()
+                    .unwrap_or_else(|_| SystemTime::now());
+                let flags = match name.rsplit_once(":2,") {
+                    Some((_, info)) => maildir_flags(info),
+                    None => vec![],
+                };
+                let message = Message::new(&body)
+                    .with_internal_date(internal_date)
+                    .with_flags(flags.iter().map(String::as_str).collect());
+                append(store, index, owner, &mailbox, message).await?;
+                summary.messages += 1;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+/// Exports `mailbox` from `store` to an mboxrd file at `path`, replacing any file there.
+/// Flags are written as `Status` and `X-Status` headers, and line endings as LF.
+pub async fn export_mbox(
+    store: &dyn DataStore,
+    owner: &Owner,
+    mailbox: &str,
+    path: &Path,
+) -> Result<TransferSummary> {
+    let mut output = BufWriter::new(File::create(path).await?);
+    let messages = store.list(owner.name(), mailbox).await?;
+    for message in &messages {
+        let body = store.fetch(owner.name(), mailbox, message.uid).await?;
+        output.write_all(&mbox_message(message, &body)).await?;
+    }
+    output.flush().await?;
+    Ok(TransferSummary {
+        mailboxes: 1,
+        messages: messages.len(),
+    })
+}
+
+/// Exports `mailbox`, or every mailbox of `owner` when there is none, from `store` to a
+/// Maildir++ tree at `root`, laid out as `import_maildir` reads it. Flags are written in the
+/// info suffix of each message, and internal dates as modification times.
+pub async fn export_maildir(
+    store: &dyn DataStore,
+    owner: &Owner,
+    mailbox: Option<&str>,
+    root: &Path,
+) -> Result<TransferSummary> {
+    let mailboxes = match mailbox {
+        Some(mailbox) => vec![mailbox.to_string()],
+        None => store.mailboxes(owner.name()).await?,
+    };
+    let mut summary = TransferSummary::default();
+    for mailbox in mailboxes {
+        let folder: PathBuf = match mailbox.as_str() {
+            INBOX => root.to_path_buf(),
+            _ => root.join(format!(".{}", mailbox)),
+        };
+        for directory in ["cur", "new", "tmp"] {
+            fs::create_dir_all(folder.join(directory)).await?;
+        }
+        let messages = store.list(owner.name(), &mailbox).await?;
+        for message in &messages {
+            let body = store.fetch(owner.name(), &mailbox, message.uid).await?;
+            let path = folder.join("cur").join(maildir_name(message));
+            fs::write(&path, &body).await?;
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_modified(message.internal_date)?;
+        }
+        summary.mailboxes += 1;
+        summary.messages += messages.len();
+    }
+    Ok(summary)
+}
+
+/// `message` as it is written to an mboxrd file, from its `From ` line to the blank line
+/// after it.
+pub(crate) fn mbox_message(message: &MessageMetadata, body: &[u8]) -> Vec<u8> {
+    let (header, content) = split_header(body);
+    let header = header.strip_suffix(b"\n").unwrap_or(header);
+    let header = header.strip_suffix(b"\r").unwrap_or(header);
+    let mut text = format!("From MAILER-DAEMON {}\n", asctime(message.internal_date)).into_bytes();
+    for line in lines(header) {
+        text.extend_from_slice(line);
+        text.push(b'\n');
+    }
+    let (status, x_status) = mbox_status(&message.flags);
+    text.extend_from_slice(format!("Status: {}\n", status).as_bytes());
+    if !x_status.is_empty() {
+        text.extend_from_slice(format!("X-Status: {}\n", x_status).as_bytes());
+    }
+    text.push(b'\n');
+    for line in lines(content) {
+        let quotes = line.iter().take_while(|byte| **byte == b'>').count();
+        if line[quotes..].starts_with(b"From ") {
+            text.push(b'>');
+        }
+        text.extend_from_slice(line);
+        text.push(b'\n');
+    }
+    text.push(b'\n');
+    text
+}
+
+/// The name of `message` in the `cur` directory of a Maildir, with its flags in the info
+/// suffix.
+pub(crate) fn maildir_name(message: &MessageMetadata) -> String {
+    let seconds = message
+        .internal_date
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    format!(
+        "{}.U{}.treasurmap:2,{}",
+        seconds,
+        message.uid,
+        maildir_info(&message.flags)
+    )
+}
+
+async fn ensure_mailbox(index: &dyn Index, owner: &Owner, mailbox: &str) -> Result<()> {
+    match index
+        .get_mailbox(owner, mailbox, Permission::ReadWrite)
+        .await
+    {
+        Err(MailboxError::DoesNotExist(_)) => {
+            let created = Mailbox::new(mailbox, 0, vec![], Permission::ReadWrite);
+            index.add_mailbox(owner, created).await?;
+            Ok(())
+        }
+        Err(e) => Err(Box::new(e)),
+        Ok(_) => Ok(()),
+    }
+}
+
+async fn append(
+    store: &dyn DataStore,
+    index: &dyn Index,
+    owner: &Owner,
+    mailbox: &str,
+    message: Message,
+) -> Result<()> {
+    let (size, internal_date, flags) = (
+        message.body.len() as u64,
+        message.internal_date,
+        message.flags.clone(),
+    );
+    let envelope = Envelope::parse(&message.body);
+    let uid = store.append(owner.name(), mailbox, message).await?;
+    let record = MessageRecord::new(uid, size, internal_date)
+        .with_flags(flags)
+        .with_envelope(envelope);
+    index.add_message(owner, mailbox, record).await?;
+    Ok(())
+}
+
+/// Stores an mbox message, taking its flags from its status headers.
+async fn import_message(
+    store: &dyn DataStore,
+    index: &dyn Index,
+    owner: &Owner,
+    mailbox: &str,
+    body: &[u8],
+    internal_date: SystemTime,
+) -> Result<()> {
+    // The blank line separating messages belongs to the mbox rather than the message.
+    let body = body.strip_suffix(b"\r\n").unwrap_or(body);
+    let (header, content) = split_header(body);
+    let header = header.strip_suffix(b"\n").unwrap_or(header);
+    let header = header.strip_suffix(b"\r").unwrap_or(header);
+    let mut kept = vec![];
+    let mut flags = vec![];
+    let mut status = false;
+    for line in lines(header) {
+        let continued = line.starts_with(b" ") || line.starts_with(b"\t");
+        if continued && status {
+            continue;
+        }
+        let field = String::from_utf8_lossy(line);
+        status = match field.split_once(':') {
+            Some((name, value)) if name.eq_ignore_ascii_case("Status") => {
+                flags.extend(value.trim().chars().filter_map(|c| match c {
+                    'R' => Some("\\Seen"),
+                    _ => None,
+                }));
+                true
+            }
+            Some((name, value)) if name.eq_ignore_ascii_case("X-Status") => {
+                flags.extend(value.trim().chars().filter_map(|c| match c {
+                    'A' => Some("\\Answered"),
+                    'F' => Some("\\Flagged"),
+                    'T' => Some("\\Draft"),
+                    'D' => Some("\\Deleted"),
+                    _ => None,
+                }));
+                true
+            }
+            _ => false,
+        };
+        if !status {
+            kept.extend_from_slice(line);
+            kept.extend_from_slice(b"\r\n");
+        }
+    }
+    kept.extend_from_slice(b"\r\n");
+    kept.extend_from_slice(content);
+    let message = Message::new(&kept)
+        .with_internal_date(internal_date)
+        .with_flags(flags);
+    append(store, index, owner, mailbox, message).await
+}
+
+/// The lines of `text`, without their line endings. An empty last line is not included.
+fn lines(text: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let text = text.strip_suffix(b"\n").unwrap_or(text);
+    text.split(|byte| *byte == b'\n')
+        .filter(move |_| !text.is_empty())
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+/// The flags of a Maildir info suffix, such as `FS`.
+fn maildir_flags(info: &str) -> Vec<String> {
+    info.chars()
+        .filter_map(|c| match c {
+            'D' => Some("\\Draft"),
+            'F' => Some("\\Flagged"),
+            'P' => Some("$Forwarded"),
+            'R' => Some("\\Answered"),
+            'S' => Some("\\Seen"),
+            'T' => Some("\\Deleted"),
+            _ => None,
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+fn maildir_info(flags: &[String]) -> String {
+    let mut info: Vec<char> = flags
+        .iter()
+        .filter_map(|flag| match flag.to_ascii_lowercase().as_str() {
+            "\\draft" => Some('D'),
+            "\\flagged" => Some('F'),
+            "$forwarded" => Some('P'),
+            "\\answered" => Some('R'),
+            "\\seen" => Some('S'),
+            "\\deleted" => Some('T'),
+            _ => None,
+        })
+        .collect();
+    // Maildir requires the flags in ASCII order.
+    info.sort();
+    info.dedup();
+    info.into_iter().collect()
+}
+
+/// The `Status` and `X-Status` values for `flags`. Exported messages are all old, `O`.
+fn mbox_status(flags: &[String]) -> (String, String) {
+    let has = |wanted: &str| flags.iter().any(|flag| flag.eq_ignore_ascii_case(wanted));
+    let status = match has("\\Seen") {
+        true => "RO",
+        false => "O",
+    };
+    let x_status: String = [
+        ("\\Answered", 'A'),
+        ("\\Flagged", 'F'),
+        ("\\Draft", 'T'),
+        ("\\Deleted", 'D'),
+    ]
+    .iter()
+    .filter(|(flag, _)| has(flag))
+    .map(|(_, letter)| *letter)
+    .collect();
+    (status.to_string(), x_status)
+}
+
+/// Formats `time` as the UTC asctime date of an mbox `From ` line, such as
+/// `Thu Jan  1 00:00:00 1970`.
+fn asctime(time: SystemTime) -> String {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400;
+    let utc = UtcTime::from(time);
+    format!(
+        "{} {} {:>2} {:02}:{:02}:{:02} {}",
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[utc.month as usize - 1],
+        utc.day,
+        utc.hour,
+        utc.minute,
+        utc.second,
+        utc.year
+    )
+}
+
+/// The date of a `From sender Thu Jan  1 00:00:00 1970` line, read as UTC.
+fn parse_separator_date(line: &str) -> Option<SystemTime> {
+    let mut words = line.split_whitespace().skip(3);
+    let month = MONTHS
+        .iter()
+        .position(|month| Some(*month) == words.next())? as u32
+        + 1;
+    let day = words.next()?.parse().ok()?;
+    let mut time = words.next()?.splitn(3, ':').map(str::parse::<u32>);
+    let (hour, minute) = (time.next()?.ok()?, time.next()?.ok()?);
+    let second = time.next().and_then(|second| second.ok()).unwrap_or(0);
+    // Some writers put a time zone before the year.
+    let year = words.find_map(|word| match word.len() {
+        4 => word.parse().ok(),
+        _ => None,
+    })?;
+    let utc = UtcTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    };
+    Some(utc.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{
+        asctime, export_maildir, export_mbox, import_maildir, import_mbox, parse_separator_date,
+    };
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::{Index, Owner};
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message};
+
+    fn scratch(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "treasurmap-transfer-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(820_631_134);
+        assert_eq!(asctime(time), "Wed Jan  3 01:05:34 1996");
+        let line = "From ann@example.com Wed Jan  3 01:05:34 1996\n";
+        assert_eq!(parse_separator_date(line), Some(time));
+        assert_eq!(
+            parse_separator_date("From ann@example.com Wed Jan  3 01:05:34 +0000 1996"),
+            Some(time)
+        );
+        assert_eq!(parse_separator_date("From nobody"), None);
+    }
+
+    #[async_std::test]
+    async fn test_mbox() {
+        let path = scratch("mbox");
+        let mbox = "Preamble\n\
+            From ann@example.com Wed Jan  3 01:05:34 1996\n\
+            Subject: one\n\
+            Status: RO\n\
+            X-Status: F\n\
+            \n\
+            >From the start\n\
+            \n\
+            From bob@example.com Thu Jan  4 01:05:34 1996\n\
+            Subject: two\n\
+            \n\
+            second\n";
+        std::fs::write(&path, mbox).unwrap();
+        let store = InMemoryStore::new();
+        let index = InMemoryIndex::new();
+        let me = Owner::new("me");
+        let summary = import_mbox(&path, &store, &index, &me, "Old")
+            .await
+            .unwrap();
+        assert_eq!(summary.messages, 2);
+
+        let records = index.list_messages(&me, "Old").await.unwrap();
+        assert_eq!(records[0].envelope.subject.as_deref(), Some("one"));
+        assert_eq!(records[0].flags, ["\\Seen", "\\Flagged"]);
+        assert_eq!(
+            records[0].internal_date,
+            UNIX_EPOCH + Duration::from_secs(820_631_134)
+        );
+        assert!(records[1].flags.is_empty());
+        let body = store.fetch("me", "Old", records[0].uid).await.unwrap();
+        assert_eq!(body, b"Subject: one\r\n\r\nFrom the start\r\n");
+
+        let exported = scratch("mbox-export");
+        export_mbox(&store, &me, "Old", &exported).await.unwrap();
+        let text = std::fs::read_to_string(&exported).unwrap();
+        assert!(text.starts_with(
+            "From MAILER-DAEMON Wed Jan  3 01:05:34 1996\nSubject: one\nStatus: RO\nX-Status: F\n\n>From the start\n\n"
+        ));
+        assert!(text.ends_with("Status: O\n\nsecond\n\n"));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&exported).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_maildir() {
+        let store = InMemoryStore::new();
+        let me = Owner::new("me");
+        store
+            .append(
+                "me",
+                "INBOX",
+                Message::new(b"Subject: a\r\n\r\n").with_flags(vec!["\\Seen", "\\Answered"]),
+            )
+            .await
+            .unwrap();
+        let date = UNIX_EPOCH + Duration::from_secs(820_631_134);
+        store
+            .append(
+                "me",
+                "Archive",
+                Message::new(b"Subject: b\r\n\r\n").with_internal_date(date),
+            )
+            .await
+            .unwrap();
+
+        let root = scratch("maildir");
+        let summary = export_maildir(&store, &me, None, &root).await.unwrap();
+        assert_eq!((summary.mailboxes, summary.messages), (2, 2));
+        assert!(root.join("cur/").read_dir().unwrap().any(|file| {
+            file.unwrap()
+                .file_name()
+                .to_string_lossy()
+                .ends_with(":2,RS")
+        }));
+        assert!(root.join(".Archive/tmp").is_dir());
+
+        let imported = InMemoryStore::new();
+        let index = InMemoryIndex::new();
+        let summary = import_maildir(&root, &imported, &index, &me).await.unwrap();
+        assert_eq!((summary.mailboxes, summary.messages), (2, 2));
+        let inbox = index.list_messages(&me, "INBOX").await.unwrap();
+        assert_eq!(inbox[0].flags, ["\\Answered", "\\Seen"]);
+        let archive = index.list_messages(&me, "Archive").await.unwrap();
+        assert_eq!(archive[0].internal_date, date);
+        assert_eq!(
+            imported
+                .fetch("me", "Archive", archive[0].uid)
+                .await
+                .unwrap(),
+            b"Subject: b\r\n\r\n"
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
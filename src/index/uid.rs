@@ -0,0 +1,244 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_lock::Mutex;
+
+use super::{MailboxError, Owner};
+use crate::store::object::Bucket;
+use crate::store::{escape, StoreError};
+
+/// The UID state of a mailbox as advertised by SELECT and STATUS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UidState {
+    pub uid_validity: u32,
+    pub uid_next: u32,
+}
+
+/// Hands out message UIDs and tracks UIDVALIDITY for each mailbox.
+///
+/// UIDs returned by `allocate` are never reused for the lifetime of a UIDVALIDITY, including
+/// across restarts for persistent implementations. `recreate` starts a mailbox over with a
+/// UIDVALIDITY strictly greater than any previously used for that name, so clients drop their
+/// cached UIDs.
+#[async_trait::async_trait]
+pub trait UidAllocator: Sync + Send {
+    /// Returns the current state of a mailbox, initialising it if it has never been seen.
+    async fn state(&self, owner: &Owner, mailbox: &str) -> Result<UidState, MailboxError>;
+    /// Reserves the next UID of a mailbox.
+    async fn allocate(&self, owner: &Owner, mailbox: &str) -> Result<u32, MailboxError>;
+    /// Resets a mailbox to UIDNEXT 1 under a new UIDVALIDITY.
+    async fn recreate(&self, owner: &Owner, mailbox: &str) -> Result<UidState, MailboxError>;
+    /// Moves UIDNEXT past `uid`, for UIDs which were assigned elsewhere (e.g. by a `DataStore`).
+    async fn advance(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<UidState, MailboxError>;
+}
+
+/// A `UidAllocator` persisting one small record per mailbox in a `Bucket`, under
+/// `uids/<owner>/<mailbox>`. With a `FileBucket` the state survives restarts.
+pub struct BucketUidAllocator<B: Bucket> {
+    bucket: B,
+    lock: Mutex<()>,
+}
+
+impl<B: Bucket> BucketUidAllocator<B> {
+    pub fn new(bucket: B) -> Self {
+        Self {
+            bucket,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn key(owner: &Owner, mailbox: &str) -> String {
+        format!("uids/{}/{}", escape(owner.name()), escape(mailbox))
+    }
+
+    async fn load(&self, owner: &Owner, mailbox: &str) -> Result<Option<UidState>, MailboxError> {
+        let data = match self
+            .bucket
+            .get(&Self::key(owner, mailbox))
+            .await
+            .map_err(storage)?
+        {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let corrupt = || MailboxError::Storage(format!("corrupt UID state for {}", mailbox));
+        let text = String::from_utf8(data).map_err(|_| corrupt())?;
+        let (uid_validity, uid_next) = text.trim().split_once(' ').ok_or_else(corrupt)?;
+        Ok(Some(UidState {
+            uid_validity: uid_validity.parse().map_err(|_| corrupt())?,
+            uid_next: uid_next.parse().map_err(|_| corrupt())?,
+        }))
+    }
+
+    async fn save(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        state: UidState,
+    ) -> Result<(), MailboxError> {
+        self.bucket
+            .put(
+                &Self::key(owner, mailbox),
+                format!("{} {}\n", state.uid_validity, state.uid_next).into_bytes(),
+            )
+            .await
+            .map_err(storage)
+    }
+
+    async fn initialise(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        previous: Option<UidState>,
+    ) -> Result<UidState, MailboxError> {
+        let state = UidState {
+            uid_validity: next_validity(previous.map(|state| state.uid_validity)),
+            uid_next: 1,
+        };
+        self.save(owner, mailbox, state).await?;
+        Ok(state)
+    }
+}
+
+fn storage(e: StoreError) -> MailboxError {
+    MailboxError::Storage(e.to_string())
+}
+
+/// Picks a UIDVALIDITY from the current time, as suggested by RFC 9051, while staying strictly
+/// above `previous` in case the clock has gone backwards or a mailbox is recreated twice within
+/// the same second.
+fn next_validity(previous: Option<u32>) -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as u32)
+        .unwrap_or(1);
+    match previous {
+        Some(previous) => now.max(previous.saturating_add(1)),
+        None => now.max(1),
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Bucket> UidAllocator for BucketUidAllocator<B> {
+    async fn state(&self, owner: &Owner, mailbox: &str) -> Result<UidState, MailboxError> {
+        let _guard = self.lock.lock().await;
+        match self.load(owner, mailbox).await? {
+            Some(state) => Ok(state),
+            None => self.initialise(owner, mailbox, None).await,
+        }
+    }
+
+    async fn allocate(&self, owner: &Owner, mailbox: &str) -> Result<u32, MailboxError> {
+        let _guard = self.lock.lock().await;
+        let state = match self.load(owner, mailbox).await? {
+            Some(state) => state,
+            None => self.initialise(owner, mailbox, None).await?,
+        };
+        // Persist the bumped UIDNEXT before handing out the UID so a crash can never cause it to
+        // be allocated twice.
+        self.save(
+            owner,
+            mailbox,
+            UidState {
+                uid_next: state.uid_next + 1,
+                ..state
+            },
+        )
+        .await?;
+        Ok(state.uid_next)
+    }
+
+    async fn recreate(&self, owner: &Owner, mailbox: &str) -> Result<UidState, MailboxError> {
+        let _guard = self.lock.lock().await;
+        let previous = self.load(owner, mailbox).await?;
+        self.initialise(owner, mailbox, previous).await
+    }
+
+    async fn advance(
+        &self,
+        owner: &Owner,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<UidState, MailboxError> {
+        let _guard = self.lock.lock().await;
+        let state = match self.load(owner, mailbox).await? {
+            Some(state) => state,
+            None => self.initialise(owner, mailbox, None).await?,
+        };
+        if state.uid_next > uid {
+            return Ok(state);
+        }
+        let state = UidState {
+            uid_next: uid + 1,
+            ..state
+        };
+        self.save(owner, mailbox, state).await?;
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{BucketUidAllocator, UidAllocator};
+    use crate::index::Owner;
+    use crate::store::object::{FileBucket, InMemoryBucket};
+
+    #[async_std::test]
+    async fn test_uids_are_monotonic_across_restarts() {
+        let me = Owner::new("me");
+        let root = std::env::temp_dir().join(format!("treasurmap-uids-{}", std::process::id()));
+        let allocator = BucketUidAllocator::new(FileBucket::new(&root));
+        let initial = allocator.state(&me, "INBOX").await.unwrap();
+        assert_eq!(initial.uid_next, 1);
+        assert_eq!(allocator.allocate(&me, "INBOX").await.unwrap(), 1);
+        assert_eq!(allocator.allocate(&me, "INBOX").await.unwrap(), 2);
+
+        let restarted = BucketUidAllocator::new(FileBucket::new(&root));
+        assert_eq!(restarted.allocate(&me, "INBOX").await.unwrap(), 3);
+        let state = restarted.state(&me, "INBOX").await.unwrap();
+        assert_eq!(state.uid_validity, initial.uid_validity);
+        assert_eq!(state.uid_next, 4);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_recreate_bumps_uid_validity() {
+        let me = Owner::new("me");
+        let allocator = BucketUidAllocator::new(Arc::new(InMemoryBucket::new()));
+        let initial = allocator.state(&me, "Archive").await.unwrap();
+        allocator.allocate(&me, "Archive").await.unwrap();
+
+        let recreated = allocator.recreate(&me, "Archive").await.unwrap();
+        assert!(recreated.uid_validity > initial.uid_validity);
+        assert_eq!(recreated.uid_next, 1);
+        assert!(
+            allocator
+                .recreate(&me, "Archive")
+                .await
+                .unwrap()
+                .uid_validity
+                > recreated.uid_validity
+        );
+    }
+
+    #[async_std::test]
+    async fn test_advance_skips_assigned_uids() {
+        let me = Owner::new("me");
+        let allocator = BucketUidAllocator::new(InMemoryBucket::new());
+        assert_eq!(
+            allocator.advance(&me, "INBOX", 41).await.unwrap().uid_next,
+            42
+        );
+        assert_eq!(
+            allocator.advance(&me, "INBOX", 7).await.unwrap().uid_next,
+            42
+        );
+        assert_eq!(allocator.allocate(&me, "INBOX").await.unwrap(), 42);
+    }
+}
@@ -0,0 +1,229 @@
+use std::io;
+
+use futures::{AsyncBufRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::connection::{read_line, Line};
+
+/// The longest line of a request head.
+const MAX_LINE_LENGTH: usize = 8 * 1024;
+/// The most header fields read from a request.
+const MAX_HEADERS: usize = 100;
+
+/// An HTTP/1.1 request, with its body read in full.
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+    query: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// The value of the header field `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+    /// The percent-decoded value of the query parameter `name`.
+    pub fn parameter(&self, name: &str) -> Option<String> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| percent_decode(value))
+    }
+}
+
+/// What went wrong reading a request.
+#[derive(Debug)]
+pub(crate) enum RequestError {
+    Io(io::Error),
+    /// The request could not be parsed, or was over a limit.
+    Malformed(&'static str),
+    TooLarge,
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> Self {
+        RequestError::Io(e)
+    }
+}
+
+/// Reads the next request, or `None` if the client closed the connection before sending one.
+/// Bodies longer than `max_body` are refused without being read.
+pub(crate) async fn read_request<R: AsyncBufRead + Unpin>(
+    input: &mut R,
+    max_body: usize,
+) -> Result<Option<Request>, RequestError> {
+    let mut line = vec![];
+    match read_line(input, &mut line, MAX_LINE_LENGTH).await? {
+        Line::End => return Ok(None),
+        Line::TooLong => return Err(RequestError::Malformed("request line too long")),
+        Line::Complete => {}
+    }
+    let request_line = String::from_utf8_lossy(&line).trim_end().to_string();
+    let mut parts = request_line.split(' ');
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method.to_string(), target)
+        }
+        _ => return Err(RequestError::Malformed("invalid request line")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method,
+        path: percent_decode(path),
+        query: query.to_string(),
+        headers: vec![],
+        body: vec![],
+    };
+    loop {
+        line.clear();
+        match read_line(input, &mut line, MAX_LINE_LENGTH).await? {
+            Line::Complete => {}
+            Line::TooLong => return Err(RequestError::Malformed("header too long")),
+            Line::End => return Err(RequestError::Malformed("incomplete request")),
+        }
+        let field = String::from_utf8_lossy(&line);
+        let field = field.trim_end();
+        if field.is_empty() {
+            break;
+        }
+        if request.headers.len() == MAX_HEADERS {
+            return Err(RequestError::Malformed("too many headers"));
+        }
+        let (name, value) = field
+            .split_once(':')
+            .ok_or(RequestError::Malformed("invalid header"))?;
+        request
+            .headers
+            .push((name.trim().to_string(), value.trim().to_string()));
+    }
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(RequestError::Malformed(
+            "chunked requests are not supported",
+        ));
+    }
+    let length = match request.header("Content-Length") {
+        Some(length) => length
+            .parse::<usize>()
+            .map_err(|_| RequestError::Malformed("invalid Content-Length"))?,
+        None => 0,
+    };
+    if length > max_body {
+        return Err(RequestError::TooLarge);
+    }
+    request.body = vec![0; length];
+    input.read_exact(&mut request.body).await?;
+    Ok(Some(request))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A response, sent with `Connection: close`.
+pub(crate) struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: &'static str, content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", content_type.to_string())],
+            body,
+        }
+    }
+    pub fn json(status: &'static str, value: &serde_json::Value) -> Self {
+        Self::new(status, "application/json", value.to_string().into_bytes())
+    }
+    /// An RFC 7807 problem, as JMAP reports request-level errors.
+    pub fn problem(status: &'static str, kind: &str, detail: &str) -> Self {
+        let code: u16 = status[..3].parse().unwrap_or(500);
+        let problem = serde_json::json!({ "type": kind, "status": code, "detail": detail });
+        Self::new(
+            status,
+            "application/problem+json",
+            problem.to_string().into_bytes(),
+        )
+    }
+    pub fn with_header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+    pub async fn send<W: AsyncWrite + Unpin>(self, output: &mut W) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        ));
+        output.write_all(head.as_bytes()).await?;
+        output.write_all(&self.body).await?;
+        output.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::io::BufReader;
+
+    use super::{read_request, RequestError};
+
+    #[async_std::test]
+    async fn test_read_request() {
+        let raw: &[u8] =
+            b"POST /jmap/api?types=a%2Cb&x=1 HTTP/1.1\r\nHost: mail\r\ncontent-length: 2\r\n\r\n{}";
+        let request = read_request(&mut BufReader::new(raw), 1024)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/jmap/api");
+        assert_eq!(request.parameter("types").as_deref(), Some("a,b"));
+        assert_eq!(request.header("Content-Length"), Some("2"));
+        assert_eq!(request.body, b"{}");
+
+        let empty: &[u8] = b"";
+        assert!(read_request(&mut BufReader::new(empty), 1024)
+            .await
+            .unwrap()
+            .is_none());
+        let large: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 2048\r\n\r\n";
+        assert!(matches!(
+            read_request(&mut BufReader::new(large), 1024).await,
+            Err(RequestError::TooLarge)
+        ));
+    }
+}
@@ -0,0 +1,906 @@
+//! The JMAP methods: Core/echo, and the Mailbox, Email and Thread methods of RFC 8621 which
+//! read mail. Mailboxes are presented flat, named by their full path, and every email is a
+//! thread of its own.
+
+use std::cmp::Ordering;
+use std::time::SystemTime;
+
+use serde_json::{json, Map, Value};
+
+use crate::index::message::MessageRecord;
+use crate::index::{Mailbox, MailboxError, Owner, Permission};
+use crate::mime::address::Address;
+use crate::mime::Part;
+use crate::server::Components;
+use crate::util::{rfc3339, UtcTime};
+
+pub(crate) const CORE: &str = "urn:ietf:params:jmap:core";
+pub(crate) const MAIL: &str = "urn:ietf:params:jmap:mail";
+
+/// The most objects a single /get or /query returns.
+pub(crate) const MAX_OBJECTS: usize = 500;
+/// The most method calls in one request.
+pub(crate) const MAX_CALLS: usize = 16;
+
+const INBOX: &str = "INBOX";
+/// The longest preview of an email, in characters.
+const PREVIEW_LENGTH: usize = 256;
+
+const EMAIL_PROPERTIES: &[&str] = &[
+    "id",
+    "blobId",
+    "threadId",
+    "mailboxIds",
+    "keywords",
+    "size",
+    "receivedAt",
+    "messageId",
+    "inReplyTo",
+    "sender",
+    "from",
+    "to",
+    "cc",
+    "bcc",
+    "replyTo",
+    "subject",
+    "hasAttachment",
+    "preview",
+];
+
+// JMAP ids are limited to URL-safe characters, so those made from mailbox and user names,
+// which are not, hold the names in hex.
+
+pub(crate) fn account_id(owner: &Owner) -> String {
+    format!("a{}", hex(owner.name()))
+}
+
+fn mailbox_id(mailbox: &str) -> String {
+    format!("m{}", hex(mailbox))
+}
+
+fn email_id(mailbox: &str, uid: u32) -> String {
+    format!("e{}-{}", hex(mailbox), uid)
+}
+
+fn parse_mailbox_id(id: &str) -> Option<String> {
+    unhex(id.strip_prefix('m')?)
+}
+
+/// The mailbox and UID of the email `id`.
+pub(crate) fn parse_email_id(id: &str) -> Option<(String, u32)> {
+    let (mailbox, uid) = id.strip_prefix('e')?.split_once('-')?;
+    Some((unhex(mailbox)?, uid.parse().ok()?))
+}
+
+fn hex(name: &str) -> String {
+    name.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(hex: &str) -> Option<String> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// A method call which failed, answered with an `error` response.
+#[derive(Debug)]
+pub(crate) struct MethodError {
+    kind: &'static str,
+    description: Option<String>,
+}
+
+impl MethodError {
+    fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            description: None,
+        }
+    }
+    fn invalid(description: &str) -> Self {
+        Self {
+            kind: "invalidArguments",
+            description: Some(description.to_string()),
+        }
+    }
+    fn to_json(&self) -> Value {
+        let mut error = json!({ "type": self.kind });
+        if let Some(description) = &self.description {
+            error["description"] = json!(description);
+        }
+        error
+    }
+}
+
+impl From<MailboxError> for MethodError {
+    fn from(e: MailboxError) -> Self {
+        Self {
+            kind: "serverFail",
+            description: Some(e.to_string()),
+        }
+    }
+}
+
+/// A request which could not be processed at all, as `(type, detail)`.
+pub(crate) type RequestError = (&'static str, String);
+
+/// The mail of one user, as a JMAP account.
+pub(crate) struct Account<'a> {
+    pub owner: Owner,
+    pub components: &'a Components,
+}
+
+impl<'a> Account<'a> {
+    pub fn new(owner: Owner, components: &'a Components) -> Self {
+        Self { owner, components }
+    }
+
+    pub fn id(&self) -> String {
+        account_id(&self.owner)
+    }
+
+    /// The mailboxes of the user, which always include INBOX.
+    pub async fn mailboxes(&self) -> Result<Vec<Mailbox>, MailboxError> {
+        let mut names = self
+            .components
+            .data_store
+            .mailboxes(self.owner.name())
+            .await
+            .map_err(|e| MailboxError::Storage(e.to_string()))?;
+        if !names.iter().any(|name| name == INBOX) {
+            names.insert(0, INBOX.to_string());
+        }
+        let mut mailboxes = vec![];
+        for name in names {
+            let index = &self.components.index;
+            match index
+                .get_mailbox(&self.owner, &name, Permission::ReadOnly)
+                .await
+            {
+                Ok(mailbox) => mailboxes.push(mailbox),
+                Err(MailboxError::DoesNotExist(..)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(mailboxes)
+    }
+
+    /// The state of the account, which changes whenever a message is added, changed or
+    /// removed.
+    pub async fn state(&self) -> Result<String, MailboxError> {
+        let mailboxes = self.mailboxes().await?;
+        let modseq: u64 = mailboxes.iter().map(|mailbox| mailbox.highest_modseq).sum();
+        Ok(format!("{}-{}", mailboxes.len(), modseq))
+    }
+
+    /// Runs the method calls of `request` in order, resolving references to the results of
+    /// earlier calls, and returns the response object.
+    pub async fn process(&self, request: Value) -> Result<Value, RequestError> {
+        let not_request =
+            |detail: &str| ("urn:ietf:params:jmap:error:notRequest", detail.to_string());
+        let using = request["using"]
+            .as_array()
+            .ok_or_else(|| not_request("using is missing"))?;
+        for capability in using {
+            match capability.as_str() {
+                Some(CORE | MAIL) => {}
+                Some(other) => {
+                    return Err((
+                        "urn:ietf:params:jmap:error:unknownCapability",
+                        other.to_string(),
+                    ))
+                }
+                None => return Err(not_request("using must hold strings")),
+            }
+        }
+        let calls = request["methodCalls"]
+            .as_array()
+            .ok_or_else(|| not_request("methodCalls is missing"))?;
+        if calls.len() > MAX_CALLS {
+            return Err((
+                "urn:ietf:params:jmap:error:limit",
+                "maxCallsInRequest".to_string(),
+            ));
+        }
+        let mut responses: Vec<Value> = vec![];
+        for call in calls {
+            let (name, arguments, call_id) = match call.as_array().map(Vec::as_slice) {
+                Some([Value::String(name), arguments, Value::String(call_id)]) => {
+                    (name, arguments, call_id)
+                }
+                _ => {
+                    return Err(not_request(
+                        "each method call must be [name, arguments, id]",
+                    ))
+                }
+            };
+            let result = match resolve(arguments, &responses) {
+                Ok(arguments) => self.call(name, arguments).await,
+                Err(e) => Err(e),
+            };
+            responses.push(match result {
+                Ok(response) => json!([name, response, call_id]),
+                Err(e) => json!(["error", e.to_json(), call_id]),
+            });
+        }
+        let state = self.state().await.unwrap_or_default();
+        Ok(json!({ "methodResponses": responses, "sessionState": state }))
+    }
+
+    async fn call(&self, name: &str, arguments: Value) -> Result<Value, MethodError> {
+        if name == "Core/echo" {
+            return Ok(arguments);
+        }
+        if !name.contains('/') {
+            return Err(MethodError::new("unknownMethod"));
+        }
+        match arguments["accountId"].as_str() {
+            Some(id) if id == self.id() => {}
+            Some(..) => return Err(MethodError::new("accountNotFound")),
+            None => return Err(MethodError::invalid("accountId is missing")),
+        }
+        match name {
+            "Mailbox/get" => self.mailbox_get(&arguments).await,
+            "Mailbox/query" => self.mailbox_query(&arguments).await,
+            "Email/get" => self.email_get(&arguments).await,
+            "Email/query" => self.email_query(&arguments).await,
+            "Thread/get" => self.thread_get(&arguments).await,
+            _ => Err(MethodError::new("unknownMethod")),
+        }
+    }
+
+    async fn mailbox_get(&self, arguments: &Value) -> Result<Value, MethodError> {
+        let ids = ids(arguments)?;
+        let mut list = vec![];
+        let mut found = vec![];
+        for mailbox in self.mailboxes().await? {
+            let name = mailbox.name.to_string_lossy().to_string();
+            let id = mailbox_id(&name);
+            if ids.as_ref().is_some_and(|ids| !ids.contains(&id)) {
+                continue;
+            }
+            let messages = self
+                .components
+                .index
+                .list_messages(&self.owner, &name)
+                .await?;
+            let unread = messages.iter().filter(|message| !is_seen(message)).count();
+            let object = json!({
+                "id": id,
+                "name": name,
+                "parentId": null,
+                "role": if name == INBOX { json!("inbox") } else { json!(null) },
+                "sortOrder": if name == INBOX { 0 } else { 1 },
+                "totalEmails": messages.len(),
+                "unreadEmails": unread,
+                "totalThreads": messages.len(),
+                "unreadThreads": unread,
+                "myRights": {
+                    "mayReadItems": true,
+                    "mayAddItems": false,
+                    "mayRemoveItems": false,
+                    "maySetSeen": false,
+                    "maySetKeywords": false,
+                    "mayCreateChild": false,
+                    "mayRename": false,
+                    "mayDelete": false,
+                    "maySubmit": false,
+                },
+                "isSubscribed": true,
+            });
+            list.push(select(object, arguments, None)?);
+            found.push(id);
+        }
+        let not_found: Vec<String> = ids
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|id| !found.contains(id))
+            .collect();
+        Ok(json!({
+            "accountId": self.id(),
+            "state": self.state().await?,
+            "list": list,
+            "notFound": not_found,
+        }))
+    }
+
+    async fn mailbox_query(&self, arguments: &Value) -> Result<Value, MethodError> {
+        let filter = filter(
+            arguments,
+            &["parentId", "name", "role", "hasAnyRole", "isSubscribed"],
+        )?;
+        let mut ids = vec![];
+        for mailbox in self.mailboxes().await? {
+            let name = mailbox.name.to_string_lossy().to_string();
+            let role = (name == INBOX).then_some("inbox");
+            let matches = filter.iter().all(|(key, value)| match key.as_str() {
+                "parentId" => value.is_null(),
+                "name" => value
+                    .as_str()
+                    .is_some_and(|part| name.to_lowercase().contains(&part.to_lowercase())),
+                "role" => value.as_str() == role,
+                "hasAnyRole" => value.as_bool() == Some(role.is_some()),
+                _ => value.as_bool() == Some(true),
+            });
+            if matches {
+                ids.push((role.is_none(), name));
+            }
+        }
+        // The inbox sorts first, then the rest by name.
+        ids.sort();
+        let ids: Vec<String> = ids.into_iter().map(|(_, name)| mailbox_id(&name)).collect();
+        Ok(json!({
+            "accountId": self.id(),
+            "queryState": self.state().await?,
+            "canCalculateChanges": false,
+            "position": 0,
+            "total": ids.len(),
+            "ids": ids,
+        }))
+    }
+
+    async fn email_get(&self, arguments: &Value) -> Result<Value, MethodError> {
+        let ids = match ids(arguments)? {
+            Some(ids) => ids,
+            None => {
+                let mut ids = vec![];
+                for mailbox in self.mailboxes().await? {
+                    let name = mailbox.name.to_string_lossy().to_string();
+                    let messages = self
+                        .components
+                        .index
+                        .list_messages(&self.owner, &name)
+                        .await?;
+                    ids.extend(messages.iter().map(|message| email_id(&name, message.uid)));
+                }
+                ids
+            }
+        };
+        if ids.len() > MAX_OBJECTS {
+            return Err(MethodError::new("requestTooLarge"));
+        }
+        let mut list = vec![];
+        let mut not_found = vec![];
+        for id in ids {
+            let (mailbox, uid) = match parse_email_id(&id) {
+                Some(parsed) => parsed,
+                None => {
+                    not_found.push(id);
+                    continue;
+                }
+            };
+            match self
+                .components
+                .index
+                .get_message(&self.owner, &mailbox, uid)
+                .await
+            {
+                Ok(message) => list.push(self.email(&mailbox, &message, arguments).await?),
+                Err(MailboxError::DoesNotExist(..) | MailboxError::MessageDoesNotExist(..)) => {
+                    not_found.push(id)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(json!({
+            "accountId": self.id(),
+            "state": self.state().await?,
+            "list": list,
+            "notFound": not_found,
+        }))
+    }
+
+    async fn email(
+        &self,
+        mailbox: &str,
+        message: &MessageRecord,
+        arguments: &Value,
+    ) -> Result<Value, MethodError> {
+        let id = email_id(mailbox, message.uid);
+        let envelope = &message.envelope;
+        let mut object = json!({
+            "id": id,
+            "blobId": id,
+            "threadId": id,
+            "mailboxIds": { mailbox_id(mailbox): true },
+            "keywords": keywords(&message.flags),
+            "size": message.size,
+            "receivedAt": rfc3339(message.internal_date),
+            "messageId": message_ids(envelope.message_id.as_deref()),
+            "inReplyTo": message_ids(envelope.in_reply_to.as_deref()),
+            "sender": addresses(&envelope.sender),
+            "from": addresses(&envelope.from),
+            "to": addresses(&envelope.to),
+            "cc": addresses(&envelope.cc),
+            "bcc": addresses(&envelope.bcc),
+            "replyTo": addresses(&envelope.reply_to),
+            "subject": envelope.subject,
+        });
+        // Only the properties needing the body have it fetched.
+        let wanted = |property: &str| match arguments["properties"].as_array() {
+            Some(properties) => properties.iter().any(|wanted| wanted == property),
+            None => true,
+        };
+        if wanted("preview") || wanted("hasAttachment") {
+            let body = self
+                .components
+                .data_store
+                .fetch(self.owner.name(), mailbox, message.uid)
+                .await
+                .map_err(|e| MethodError {
+                    kind: "serverFail",
+                    description: Some(e.to_string()),
+                })?;
+            let part = Part::parse(&body);
+            object["preview"] = json!(preview(&part));
+            object["hasAttachment"] = json!(has_attachment(&part));
+        }
+        select(object, arguments, Some(EMAIL_PROPERTIES))
+    }
+
+    async fn email_query(&self, arguments: &Value) -> Result<Value, MethodError> {
+        let filter = filter(
+            arguments,
+            &[
+                "inMailbox",
+                "hasKeyword",
+                "notKeyword",
+                "before",
+                "after",
+                "minSize",
+                "maxSize",
+            ],
+        )?;
+        let date = |key: &str| match filter.get(key) {
+            Some(value) => value
+                .as_str()
+                .and_then(parse_utc_date)
+                .map(Some)
+                .ok_or_else(|| MethodError::invalid(&format!("{} must be a UTCDate", key))),
+            None => Ok(None),
+        };
+        let (before, after) = (date("before")?, date("after")?);
+        let keyword = |key: &str| {
+            filter
+                .get(key)
+                .and_then(Value::as_str)
+                .map(str::to_lowercase)
+        };
+        let (has_keyword, not_keyword) = (keyword("hasKeyword"), keyword("notKeyword"));
+        let min_size = filter.get("minSize").and_then(Value::as_u64);
+        let max_size = filter.get("maxSize").and_then(Value::as_u64);
+        let mailboxes: Vec<String> = match filter.get("inMailbox") {
+            Some(id) => match id.as_str().and_then(parse_mailbox_id) {
+                Some(name) => vec![name],
+                None => return Err(MethodError::invalid("inMailbox is not a mailbox")),
+            },
+            None => self
+                .mailboxes()
+                .await?
+                .iter()
+                .map(|mailbox| mailbox.name.to_string_lossy().to_string())
+                .collect(),
+        };
+
+        let mut found: Vec<(String, MessageRecord)> = vec![];
+        for mailbox in mailboxes {
+            let messages = match self
+                .components
+                .index
+                .list_messages(&self.owner, &mailbox)
+                .await
+            {
+                Ok(messages) => messages,
+                Err(MailboxError::DoesNotExist(..)) => vec![],
+                Err(e) => return Err(e.into()),
+            };
+            for message in messages {
+                let keywords = keywords(&message.flags);
+                let matches = has_keyword
+                    .as_ref()
+                    .is_none_or(|k| keywords.get(k).is_some())
+                    && not_keyword
+                        .as_ref()
+                        .is_none_or(|k| keywords.get(k).is_none())
+                    && before.is_none_or(|before| message.internal_date < before)
+                    && after.is_none_or(|after| message.internal_date >= after)
+                    && min_size.is_none_or(|min| message.size >= min)
+                    && max_size.is_none_or(|max| message.size < max);
+                if matches {
+                    found.push((mailbox.clone(), message));
+                }
+            }
+        }
+        let comparators = sort(arguments)?;
+        found.sort_by(|(_, a), (_, b)| {
+            comparators
+                .iter()
+                .map(|(property, ascending)| {
+                    let order = match property.as_str() {
+                        "size" => a.size.cmp(&b.size),
+                        _ => a.internal_date.cmp(&b.internal_date),
+                    };
+                    if *ascending {
+                        order
+                    } else {
+                        order.reverse()
+                    }
+                })
+                .find(|order| *order != Ordering::Equal)
+                .unwrap_or(Ordering::Equal)
+        });
+
+        if arguments
+            .get("anchor")
+            .is_some_and(|anchor| !anchor.is_null())
+        {
+            return Err(MethodError::invalid("anchor is not supported"));
+        }
+        let total = found.len();
+        let position = match arguments["position"].as_i64().unwrap_or(0) {
+            position if position < 0 => total.saturating_sub(position.unsigned_abs() as usize),
+            position => (position as usize).min(total),
+        };
+        let limit = match arguments["limit"].as_u64() {
+            Some(limit) => (limit as usize).min(MAX_OBJECTS),
+            None => MAX_OBJECTS,
+        };
+        let ids: Vec<String> = found
+            .iter()
+            .skip(position)
+            .take(limit)
+            .map(|(mailbox, message)| email_id(mailbox, message.uid))
+            .collect();
+        let mut response = json!({
+            "accountId": self.id(),
+            "queryState": self.state().await?,
+            "canCalculateChanges": false,
+            "position": position,
+            "ids": ids,
+        });
+        if arguments["calculateTotal"].as_bool() == Some(true) {
+            response["total"] = json!(total);
+        }
+        if arguments["limit"]
+            .as_u64()
+            .is_some_and(|asked| asked as usize > limit)
+        {
+            response["limit"] = json!(limit);
+        }
+        Ok(response)
+    }
+
+    async fn thread_get(&self, arguments: &Value) -> Result<Value, MethodError> {
+        let ids = ids(arguments)?.ok_or_else(|| MethodError::new("requestTooLarge"))?;
+        if ids.len() > MAX_OBJECTS {
+            return Err(MethodError::new("requestTooLarge"));
+        }
+        let mut list = vec![];
+        let mut not_found = vec![];
+        for id in ids {
+            let exists = match parse_email_id(&id) {
+                Some((mailbox, uid)) => self
+                    .components
+                    .index
+                    .get_message(&self.owner, &mailbox, uid)
+                    .await
+                    .is_ok(),
+                None => false,
+            };
+            match exists {
+                true => list.push(json!({ "id": id, "emailIds": [id] })),
+                false => not_found.push(id),
+            }
+        }
+        Ok(json!({
+            "accountId": self.id(),
+            "state": self.state().await?,
+            "list": list,
+            "notFound": not_found,
+        }))
+    }
+}
+
+/// Replaces the arguments named `#name` by the result they reference, per RFC 8620 section
+/// 3.7.
+fn resolve(arguments: &Value, responses: &[Value]) -> Result<Value, MethodError> {
+    let arguments = arguments
+        .as_object()
+        .ok_or_else(|| MethodError::invalid("arguments must be an object"))?;
+    let mut resolved = Map::new();
+    for (key, value) in arguments {
+        let name = match key.strip_prefix('#') {
+            Some(name) => name,
+            None => {
+                resolved.insert(key.clone(), value.clone());
+                continue;
+            }
+        };
+        if arguments.contains_key(name) {
+            return Err(MethodError::invalid(&format!(
+                "both {} and #{} given",
+                name, name
+            )));
+        }
+        let invalid = || MethodError::new("invalidResultReference");
+        let (result_of, method, path) = match (
+            value["resultOf"].as_str(),
+            value["name"].as_str(),
+            value["path"].as_str(),
+        ) {
+            (Some(result_of), Some(method), Some(path)) => (result_of, method, path),
+            _ => return Err(invalid()),
+        };
+        let response = responses
+            .iter()
+            .find(|response| response[2] == result_of && response[0] == method)
+            .ok_or_else(invalid)?;
+        let tokens: Vec<String> = path
+            .split('/')
+            .skip(1)
+            .map(|token| token.replace("~1", "/").replace("~0", "~"))
+            .collect();
+        let value = evaluate(&response[1], &tokens).ok_or_else(invalid)?;
+        resolved.insert(name.to_string(), value);
+    }
+    Ok(Value::Object(resolved))
+}
+
+/// Evaluates a JSON pointer, where `*` maps over the items of an array and flattens the
+/// arrays it finds.
+fn evaluate(value: &Value, tokens: &[String]) -> Option<Value> {
+    let (first, rest) = match tokens.split_first() {
+        Some(split) => split,
+        None => return Some(value.clone()),
+    };
+    match value {
+        Value::Array(items) if first == "*" => {
+            let mut flattened = vec![];
+            for item in items {
+                match evaluate(item, rest)? {
+                    Value::Array(inner) => flattened.extend(inner),
+                    value => flattened.push(value),
+                }
+            }
+            Some(Value::Array(flattened))
+        }
+        Value::Array(items) => evaluate(items.get(first.parse::<usize>().ok()?)?, rest),
+        Value::Object(map) => evaluate(map.get(first)?, rest),
+        _ => None,
+    }
+}
+
+/// The `ids` argument, or `None` for every object.
+fn ids(arguments: &Value) -> Result<Option<Vec<String>>, MethodError> {
+    match &arguments["ids"] {
+        Value::Null => Ok(None),
+        Value::Array(ids) => ids
+            .iter()
+            .map(|id| id.as_str().map(str::to_string))
+            .collect::<Option<Vec<_>>>()
+            .map(Some)
+            .ok_or_else(|| MethodError::invalid("ids must be strings")),
+        _ => Err(MethodError::invalid("ids must be an array")),
+    }
+}
+
+/// Keeps the `properties` asked for, always with the id. Properties other than `known`, or
+/// than those of `object` when there is no such list, are refused.
+fn select(object: Value, arguments: &Value, known: Option<&[&str]>) -> Result<Value, MethodError> {
+    let properties = match arguments["properties"].as_array() {
+        Some(properties) => properties,
+        None => return Ok(object),
+    };
+    let mut selected = Map::new();
+    selected.insert("id".to_string(), object["id"].clone());
+    for property in properties {
+        let property = property
+            .as_str()
+            .ok_or_else(|| MethodError::invalid("properties must be strings"))?;
+        let supported = match known {
+            Some(known) => known.contains(&property),
+            None => object.get(property).is_some(),
+        };
+        if !supported {
+            return Err(MethodError::invalid(&format!(
+                "unknown property {}",
+                property
+            )));
+        }
+        selected.insert(property.to_string(), object[property].clone());
+    }
+    Ok(Value::Object(selected))
+}
+
+/// The conditions of a `filter` argument, which may only use `supported` properties.
+fn filter(arguments: &Value, supported: &[&str]) -> Result<Map<String, Value>, MethodError> {
+    let filter = match &arguments["filter"] {
+        Value::Null => return Ok(Map::new()),
+        Value::Object(filter) => filter,
+        _ => return Err(MethodError::invalid("filter must be an object")),
+    };
+    match filter.keys().find(|key| !supported.contains(&key.as_str())) {
+        Some(key) => Err(MethodError {
+            kind: "unsupportedFilter",
+            description: Some(format!("{} is not supported", key)),
+        }),
+        None => Ok(filter.clone()),
+    }
+}
+
+/// The comparators of a `sort` argument, as properties and whether they ascend. Emails are
+/// listed newest first by default.
+fn sort(arguments: &Value) -> Result<Vec<(String, bool)>, MethodError> {
+    let comparators = match &arguments["sort"] {
+        Value::Null => return Ok(vec![("receivedAt".to_string(), false)]),
+        Value::Array(comparators) => comparators,
+        _ => return Err(MethodError::invalid("sort must be an array")),
+    };
+    comparators
+        .iter()
+        .map(|comparator| match comparator["property"].as_str() {
+            Some(property @ ("receivedAt" | "size")) => Ok((
+                property.to_string(),
+                comparator["isAscending"].as_bool().unwrap_or(true),
+            )),
+            _ => Err(MethodError::new("unsupportedSort")),
+        })
+        .collect()
+}
+
+fn is_seen(message: &MessageRecord) -> bool {
+    message
+        .flags
+        .iter()
+        .any(|flag| flag.eq_ignore_ascii_case("\\Seen"))
+}
+
+/// The JMAP keywords of the IMAP `flags`, per RFC 8621 section 4.1.1.
+fn keywords(flags: &[String]) -> Map<String, Value> {
+    let mut keywords = Map::new();
+    for flag in flags {
+        let keyword = match flag.to_ascii_lowercase().as_str() {
+            "\\seen" => "$seen".to_string(),
+            "\\flagged" => "$flagged".to_string(),
+            "\\answered" => "$answered".to_string(),
+            "\\draft" => "$draft".to_string(),
+            other if other.starts_with('\\') => continue,
+            other => other.to_string(),
+        };
+        keywords.insert(keyword, json!(true));
+    }
+    keywords
+}
+
+fn addresses(addresses: &[Address]) -> Value {
+    let addresses: Vec<Value> = addresses
+        .iter()
+        .filter_map(|address| match (&address.mailbox, &address.host) {
+            (Some(mailbox), Some(host)) => Some(json!({
+                "name": address.name,
+                "email": format!("{}@{}", mailbox, host),
+            })),
+            // The markers of address groups.
+            _ => None,
+        })
+        .collect();
+    match addresses.is_empty() {
+        true => Value::Null,
+        false => Value::Array(addresses),
+    }
+}
+
+/// The message ids of a Message-ID or In-Reply-To field, without their angle brackets.
+fn message_ids(field: Option<&str>) -> Value {
+    let ids: Vec<&str> = field
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(|id| id.trim_start_matches('<').trim_end_matches('>'))
+        .filter(|id| !id.is_empty())
+        .collect();
+    match ids.is_empty() {
+        true => Value::Null,
+        false => json!(ids),
+    }
+}
+
+fn is_attachment(part: &Part) -> bool {
+    let disposition = part.disposition();
+    disposition.is_some_and(|disposition| disposition.value.eq_ignore_ascii_case("attachment"))
+        || !(part.content_type.kind == "text" || part.is_multipart())
+}
+
+fn has_attachment(part: &Part) -> bool {
+    match part.is_multipart() {
+        true => part.children.iter().any(has_attachment),
+        false => is_attachment(part),
+    }
+}
+
+/// The start of the first plain text part, with its whitespace collapsed.
+fn preview(part: &Part) -> String {
+    fn text<'a>(part: &'a Part<'a>) -> Option<&'a Part<'a>> {
+        match part.is_multipart() {
+            true => part.children.iter().find_map(text),
+            false => {
+                (part.content_type.is("text", "plain") && !is_attachment(part)).then_some(part)
+            }
+        }
+    }
+    let text = text(part).map(Part::text).unwrap_or_default();
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(PREVIEW_LENGTH)
+        .collect()
+}
+
+/// Parses a UTCDate, such as `2024-05-01T12:00:00Z`.
+fn parse_utc_date(date: &str) -> Option<SystemTime> {
+    let (day, time) = date.strip_suffix('Z')?.split_once('T')?;
+    let mut day = day.splitn(3, '-').map(str::parse::<u32>);
+    let time = time.split('.').next()?;
+    let mut time = time.splitn(3, ':').map(str::parse::<u32>);
+    let next = |parts: &mut dyn Iterator<Item = Result<u32, _>>| parts.next()?.ok();
+    let utc = UtcTime {
+        year: next(&mut day)? as i64,
+        month: next(&mut day)?,
+        day: next(&mut day)?,
+        hour: next(&mut time)?,
+        minute: next(&mut time)?,
+        second: next(&mut time)?,
+    };
+    Some(utc.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{account_id, email_id, evaluate, keywords, parse_email_id, parse_utc_date};
+    use crate::index::Owner;
+    use crate::util::rfc3339;
+
+    #[test]
+    fn test_ids() {
+        let id = email_id("Archive/2024", 7);
+        assert!(id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+        assert_eq!(parse_email_id(&id), Some(("Archive/2024".to_string(), 7)));
+        assert_eq!(parse_email_id("e4-1"), None);
+        assert_eq!(account_id(&Owner::new("me")), "a6d65");
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let response = json!({ "list": [{ "emailIds": ["a", "b"] }, { "emailIds": ["c"] }] });
+        let tokens: Vec<String> = ["list", "*", "emailIds"]
+            .iter()
+            .map(|t| t.to_string())
+            .collect();
+        assert_eq!(evaluate(&response, &tokens), Some(json!(["a", "b", "c"])));
+        assert_eq!(evaluate(&response, &["missing".to_string()]), None);
+    }
+
+    #[test]
+    fn test_keywords() {
+        let flags = vec![
+            "\\Seen".to_string(),
+            "\\Recent".to_string(),
+            "Work".to_string(),
+        ];
+        let keywords = keywords(&flags);
+        assert_eq!(keywords.keys().collect::<Vec<_>>(), ["$seen", "work"]);
+    }
+
+    #[test]
+    fn test_parse_utc_date() {
+        let date = parse_utc_date("2024-05-01T12:30:05Z").unwrap();
+        assert_eq!(rfc3339(date), "2024-05-01T12:30:05Z");
+        assert!(parse_utc_date("2024-05-01").is_none());
+    }
+}
@@ -0,0 +1,643 @@
+//! An experimental JMAP (RFC 8620, RFC 8621) frontend to the same index and data store as
+//! IMAP, for clients which would rather speak JSON over HTTP. It serves
+//!
+//! - session discovery at `/.well-known/jmap`,
+//! - read-only Mailbox, Email and Thread methods at `/jmap/api`,
+//! - messages as blobs at `/jmap/download/{accountId}/{blobId}/{name}`,
+//! - and pushes state changes with EventSource at `/jmap/eventsource`.
+//!
+//! Clients authenticate every request with HTTP Basic authentication, against the same
+//! authenticator as LOGIN and with the same throttling and access policy. When the server
+//! requires TLS, listeners without it refuse every request.
+
+mod http;
+mod mail;
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use async_std::future::timeout;
+use async_std::io::BufReader;
+use async_std::net::TcpListener;
+use async_std::task::sleep;
+use futures::future::{self, Either};
+use futures::stream::select_all;
+use futures::{AsyncReadExt, AsyncWrite, AsyncWriteExt, StreamExt};
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+
+use self::http::{read_request, Request, RequestError, Response};
+use self::mail::{account_id, parse_email_id, Account, CORE, MAIL, MAX_CALLS, MAX_OBJECTS};
+use crate::auth::{BasicAuth, User};
+use crate::index::Owner;
+use crate::listener::Io;
+#[cfg(feature = "tls")]
+use crate::listener::TlsAcceptor;
+use crate::mime::encoding::decode_base64;
+use crate::runtime::spawn;
+use crate::server::Components;
+use crate::shutdown::Shutdown;
+use crate::util::Result;
+
+/// The largest request body accepted.
+const MAX_REQUEST_SIZE: usize = 10 * 1024 * 1024;
+/// How long a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// The fewest seconds between the pings of an event source.
+const MIN_PING: u64 = 10;
+
+/// An address JMAP clients connect to.
+#[derive(Clone)]
+pub struct JmapListener {
+    address: String,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsAcceptor>,
+    require_tls: bool,
+}
+
+impl JmapListener {
+    pub fn tcp(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            require_tls: false,
+        }
+    }
+    /// Serves HTTPS rather than HTTP, as JMAP clients other than those under test require.
+    #[cfg(feature = "tls")]
+    pub fn with_implicit_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+    /// Refuses Basic authentication, and so every request, unless the listener serves HTTPS,
+    /// as set by `ServerBuilder::with_require_tls`.
+    pub(crate) fn with_require_tls(mut self, require_tls: bool) -> Self {
+        self.require_tls = require_tls;
+        self
+    }
+    pub(crate) async fn bind(self) -> std::io::Result<BoundJmap> {
+        let socket = TcpListener::bind(&self.address).await?;
+        Ok(BoundJmap {
+            listener: self,
+            socket,
+        })
+    }
+    fn is_secure(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return true;
+        }
+        false
+    }
+    fn scheme(&self) -> &'static str {
+        if self.is_secure() {
+            "https"
+        } else {
+            "http"
+        }
+    }
+}
+
+/// A JMAP listener whose socket has been bound.
+pub(crate) struct BoundJmap {
+    listener: JmapListener,
+    socket: TcpListener,
+}
+
+impl BoundJmap {
+    pub(crate) fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Accepts clients until `shutdown` is triggered, answering each on its own task.
+    pub(crate) async fn serve(self, components: Components, shutdown: Shutdown) {
+        info!("JMAP server started listening on {}", self.listener.address);
+        let mut incoming = self.socket.incoming();
+        loop {
+            let stream = match future::select(incoming.next(), Box::pin(shutdown.wait())).await {
+                Either::Left((Some(Ok(stream)), _)) => stream,
+                Either::Left((Some(Err(e)), _)) => {
+                    warn!("Could not accept a JMAP connection: {}", e);
+                    continue;
+                }
+                Either::Left((None, _)) | Either::Right(..) => break,
+            };
+            let listener = self.listener.clone();
+            let components = components.clone();
+            let shutdown = shutdown.clone();
+            spawn(async move {
+                let peer = stream.peer_addr().ok().map(|peer| peer.ip());
+                #[cfg(feature = "tls")]
+                if let Some(acceptor) = &listener.tls {
+                    let stream = acceptor.accept(stream).await?;
+                    return exchange(Box::new(stream), &components, &shutdown, &listener, peer)
+                        .await;
+                }
+                exchange(Box::new(stream), &components, &shutdown, &listener, peer).await
+            });
+        }
+        info!("JMAP server stopped listening on {}", self.listener.address);
+    }
+}
+
+/// Answers one request from `peer`, then closes the connection.
+async fn exchange(
+    stream: Box<dyn Io>,
+    components: &Components,
+    shutdown: &Shutdown,
+    listener: &JmapListener,
+    peer: Option<IpAddr>,
+) -> Result<()> {
+    let (input, mut output) = stream.split();
+    let mut input = BufReader::new(input);
+    let request = match timeout(REQUEST_TIMEOUT, read_request(&mut input, MAX_REQUEST_SIZE)).await {
+        Ok(Ok(Some(request))) => request,
+        Ok(Ok(None)) | Err(..) => return Ok(()),
+        Ok(Err(RequestError::Io(e))) => return Err(Box::new(e)),
+        Ok(Err(RequestError::Malformed(reason))) => {
+            let response =
+                Response::new("400 Bad Request", "text/plain", reason.as_bytes().to_vec());
+            return Ok(response.send(&mut output).await?);
+        }
+        Ok(Err(RequestError::TooLarge)) => {
+            let response = Response::problem(
+                "413 Payload Too Large",
+                "urn:ietf:params:jmap:error:limit",
+                "maxSizeRequest",
+            );
+            return Ok(response.send(&mut output).await?);
+        }
+    };
+    debug!("JMAP {} {}", request.method, request.path);
+    if listener.require_tls && !listener.is_secure() {
+        let response = Response::new(
+            "403 Forbidden",
+            "text/plain",
+            b"Basic authentication needs HTTPS".to_vec(),
+        );
+        return Ok(response.send(&mut output).await?);
+    }
+    let user = match authenticate(&request, components, peer).await {
+        Some(user) => user,
+        None => {
+            let response =
+                Response::new("401 Unauthorized", "text/plain", b"Unauthorized".to_vec())
+                    .with_header(
+                        "WWW-Authenticate",
+                        "Basic realm=\"TreasurMAP\", charset=\"UTF-8\"",
+                    );
+            return Ok(response.send(&mut output).await?);
+        }
+    };
+    let account = Account::new(Owner::from(&user), components);
+    let segments: Vec<&str> = request.path.split('/').skip(1).collect();
+    let response = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", [".well-known", "jmap"] | ["jmap", "session"]) => {
+            let base = format!(
+                "{}://{}",
+                listener.scheme(),
+                request.header("Host").unwrap_or("localhost")
+            );
+            let state = account.state().await?;
+            Response::json("200 OK", &session(&user, &base, &state))
+        }
+        ("POST", ["jmap", "api"]) => api(&account, &request).await,
+        ("GET", ["jmap", "download", account_id, blob_id, _]) if *account_id == account.id() => {
+            download(&account, blob_id, &request).await
+        }
+        ("GET", ["jmap", "eventsource"]) => {
+            return Ok(event_source(&account, &request, &mut output, shutdown).await?);
+        }
+        ("GET" | "POST", _) => Response::new("404 Not Found", "text/plain", b"Not found".to_vec()),
+        _ => Response::new(
+            "405 Method Not Allowed",
+            "text/plain",
+            b"Method not allowed".to_vec(),
+        ),
+    };
+    Ok(response.send(&mut output).await?)
+}
+
+/// The user named in the Basic credentials of `request` from `peer`, if they are right.
+/// Wrong credentials are only answered after the delay the authenticator asks for.
+async fn authenticate(
+    request: &Request,
+    components: &Components,
+    peer: Option<IpAddr>,
+) -> Option<User> {
+    let credentials = request.header("Authorization")?;
+    let (scheme, encoded) = credentials.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(decode_base64(encoded.trim().as_bytes())).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    let principal = Box::new(BasicAuth::from(username, password));
+    match components.authenticator.login(principal, peer).await {
+        Ok(user) => Some(user),
+        Err(delay) => {
+            sleep(delay).await;
+            None
+        }
+    }
+}
+
+/// The session resource of RFC 8620 section 2.
+fn session(user: &User, base: &str, state: &str) -> Value {
+    let account = account_id(&Owner::from(user));
+    json!({
+        "capabilities": {
+            CORE: {
+                "maxSizeUpload": 0,
+                "maxConcurrentUpload": 1,
+                "maxSizeRequest": MAX_REQUEST_SIZE,
+                "maxConcurrentRequests": 4,
+                "maxCallsInRequest": MAX_CALLS,
+                "maxObjectsInGet": MAX_OBJECTS,
+                "maxObjectsInSet": 0,
+                "collationAlgorithms": ["i;ascii-casemap"],
+            },
+            MAIL: {},
+        },
+        "accounts": {
+            account.clone(): {
+                "name": user.name(),
+                "isPersonal": true,
+                "isReadOnly": true,
+                "accountCapabilities": {
+                    MAIL: {
+                        "maxMailboxesPerEmail": 1,
+                        "maxMailboxDepth": null,
+                        "maxSizeMailboxName": 255,
+                        "maxSizeAttachmentsPerEmail": 0,
+                        "emailQuerySortOptions": ["receivedAt", "size"],
+                        "mayCreateTopLevelMailbox": false,
+                    },
+                },
+            },
+        },
+        "primaryAccounts": { CORE: account, MAIL: account },
+        "username": user.name(),
+        "apiUrl": format!("{}/jmap/api", base),
+        "downloadUrl": format!("{}/jmap/download/{{accountId}}/{{blobId}}/{{name}}?accept={{type}}", base),
+        "uploadUrl": format!("{}/jmap/upload/{{accountId}}/", base),
+        "eventSourceUrl": format!("{}/jmap/eventsource?types={{types}}&closeafter={{closeafter}}&ping={{ping}}", base),
+        "state": state,
+    })
+}
+
+async fn api(account: &Account<'_>, request: &Request) -> Response {
+    let body: Value = match serde_json::from_slice(&request.body) {
+        Ok(body) => body,
+        Err(e) => {
+            return Response::problem(
+                "400 Bad Request",
+                "urn:ietf:params:jmap:error:notJSON",
+                &e.to_string(),
+            )
+        }
+    };
+    match account.process(body).await {
+        Ok(response) => Response::json("200 OK", &response),
+        Err((kind, detail)) => Response::problem("400 Bad Request", kind, &detail),
+    }
+}
+
+/// Sends the message `blob_id`, which is also the id of its email.
+async fn download(account: &Account<'_>, blob_id: &str, request: &Request) -> Response {
+    let not_found = || Response::new("404 Not Found", "text/plain", b"Not found".to_vec());
+    let (mailbox, uid) = match parse_email_id(blob_id) {
+        Some(parsed) => parsed,
+        None => return not_found(),
+    };
+    let owner = &account.owner;
+    match account
+        .components
+        .data_store
+        .fetch(owner.name(), &mailbox, uid)
+        .await
+    {
+        Ok(body) => {
+            let content_type = request
+                .parameter("accept")
+                .filter(|accept| !accept.is_empty())
+                .unwrap_or_else(|| "message/rfc822".to_string());
+            Response::new("200 OK", &content_type, body)
+                .with_header("Cache-Control", "private, immutable, max-age=31536000")
+        }
+        Err(..) => not_found(),
+    }
+}
+
+/// Pushes a StateChange whenever the account changes, per RFC 8620 section 7.3, until the
+/// client goes away or the server shuts down. Only the mailboxes the account had when the
+/// client connected are watched.
+async fn event_source<W: AsyncWrite + Unpin>(
+    account: &Account<'_>,
+    request: &Request,
+    output: &mut W,
+    shutdown: &Shutdown,
+) -> std::io::Result<()> {
+    let types = request
+        .parameter("types")
+        .unwrap_or_else(|| "*".to_string());
+    let types: Vec<&str> = ["Mailbox", "Email", "Thread"]
+        .into_iter()
+        .filter(|kind| types == "*" || types.split(',').any(|wanted| wanted == *kind))
+        .collect();
+    let close_after = request.parameter("closeafter").as_deref() == Some("state");
+    let ping = match request
+        .parameter("ping")
+        .and_then(|ping| ping.parse::<u64>().ok())
+    {
+        Some(0) | None => None,
+        Some(ping) => Some(Duration::from_secs(ping.max(MIN_PING))),
+    };
+
+    let mut watches = vec![];
+    for mailbox in account.mailboxes().await.unwrap_or_default() {
+        let name = mailbox.name.to_string_lossy().to_string();
+        let index = &account.components.index;
+        match index.watch(&account.owner, &name).await {
+            Ok(watch) => watches.push(watch),
+            Err(e) => warn!("Could not watch {} for JMAP push: {}", name, e),
+        }
+    }
+    let mut changes = select_all(watches);
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    output.write_all(head.as_bytes()).await?;
+    output.flush().await?;
+
+    let mut last = account.state().await.unwrap_or_default();
+    let mut watching = true;
+    loop {
+        let change = async {
+            match watching {
+                true => changes.next().await,
+                false => future::pending().await,
+            }
+        };
+        let wait = async {
+            match ping {
+                Some(ping) => async_std::task::sleep(ping).await,
+                None => future::pending().await,
+            }
+        };
+        let event = match future::select(
+            Box::pin(shutdown.wait()),
+            future::select(Box::pin(change), Box::pin(wait)),
+        )
+        .await
+        {
+            Either::Left(..) => return Ok(()),
+            Either::Right((Either::Left((Some(..), _)), _)) => {
+                let state = account.state().await.unwrap_or_default();
+                if state == last || types.is_empty() {
+                    continue;
+                }
+                last = state;
+                let changed: serde_json::Map<String, Value> = types
+                    .iter()
+                    .map(|kind| (kind.to_string(), json!(last)))
+                    .collect();
+                let change =
+                    json!({ "@type": "StateChange", "changed": { account.id(): changed } });
+                format!("event: state\ndata: {}\n\n", change)
+            }
+            Either::Right((Either::Left((None, _)), _)) => {
+                watching = false;
+                continue;
+            }
+            Either::Right((Either::Right(..), _)) => {
+                let interval = ping.map_or(0, |ping| ping.as_secs());
+                format!("event: ping\ndata: {}\n\n", json!({ "interval": interval }))
+            }
+        };
+        output.write_all(event.as_bytes()).await?;
+        output.flush().await?;
+        if close_after && event.starts_with("event: state") {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, SystemTime};
+
+    use async_std::net::TcpStream;
+    use futures::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, StreamExt};
+    use serde_json::{json, Value};
+
+    use super::JmapListener;
+    use crate::auth::inmemory::InMemoryUserStore;
+    use crate::auth::{User, UserStore};
+    use crate::index::delivery::MaildirDelivery;
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::message::{Envelope, MessageRecord};
+    use crate::index::{Index, Owner};
+    use crate::mime::encoding::encode_base64;
+    use crate::server::{Configuration, Server, ServerBuilder};
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message};
+
+    const MESSAGE: &[u8] = b"From: Ann <ann@example.com>\r\nTo: me@example.com\r\nSubject: Hello\r\nMessage-ID: <1@example.com>\r\n\r\nSee you\r\n  soon.\r\n";
+
+    async fn deliver(store: &dyn DataStore, index: &dyn Index, body: &[u8]) {
+        let me = Owner::new("me");
+        let uid = store
+            .append("me", "INBOX", Message::new(body))
+            .await
+            .unwrap();
+        let mut record = MessageRecord::new(uid, body.len() as u64, SystemTime::now());
+        record.envelope = Envelope::parse(body);
+        index.add_message(&me, "INBOX", record).await.unwrap();
+    }
+
+    async fn server() -> Server {
+        let users = InMemoryUserStore::new();
+        users.add(User::new("me", "password")).await.unwrap();
+        let store = InMemoryStore::new();
+        let index = InMemoryIndex::new();
+        deliver(&store, &index, MESSAGE).await;
+        ServerBuilder::new()
+            .with_configuration(Configuration::default().with_listeners(vec![]))
+            .with_user_store(users)
+            .with_data_store(store)
+            .with_index(index)
+            .with_jmap_listener(JmapListener::tcp("127.0.0.1:0"))
+            .bind()
+            .await
+            .unwrap()
+    }
+
+    /// Sends a request as `me`, returning the status line and body of the response.
+    async fn request(server: SocketAddr, method: &str, path: &str, body: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        let credentials = encode_base64(b"me:password");
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: mail\r\nAuthorization: Basic {}\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            credentials,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    }
+
+    async fn call(server: SocketAddr, calls: Value) -> Vec<Value> {
+        let body = json!({ "using": ["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"], "methodCalls": calls });
+        let (status, body) = request(server, "POST", "/jmap/api", &body.to_string()).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let body: Value = serde_json::from_str(&body).unwrap();
+        body["methodResponses"].as_array().unwrap().clone()
+    }
+
+    #[async_std::test]
+    async fn test_jmap() {
+        let server = server().await;
+        let (address, shutdown) = (server.jmap_addresses()[0], server.shutdown());
+        let running = async_std::task::spawn(server.listen());
+        let server = address;
+
+        let (status, session) = request(server, "GET", "/.well-known/jmap", "").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let session: Value = serde_json::from_str(&session).unwrap();
+        assert_eq!(session["apiUrl"], "http://mail/jmap/api");
+        let account = session["primaryAccounts"]["urn:ietf:params:jmap:mail"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let responses = call(
+            server,
+            json!([
+                ["Mailbox/query", { "accountId": account, "filter": { "role": "inbox" } }, "0"],
+                ["Mailbox/get", { "accountId": account, "#ids": { "resultOf": "0", "name": "Mailbox/query", "path": "/ids" } }, "1"],
+                ["Email/query", { "accountId": account, "filter": { "notKeyword": "$seen" }, "calculateTotal": true }, "2"],
+                ["Email/get", { "accountId": account, "#ids": { "resultOf": "2", "name": "Email/query", "path": "/ids" }, "properties": ["subject", "from", "messageId", "preview"] }, "3"],
+                ["Email/get", { "accountId": "elsewhere", "ids": [] }, "4"],
+                ["Email/set", { "accountId": account }, "5"],
+            ]),
+        )
+        .await;
+        let inbox = &responses[1][1]["list"][0];
+        assert_eq!(inbox["role"], "inbox");
+        assert_eq!(inbox["totalEmails"], 1);
+        assert_eq!(inbox["unreadEmails"], 1);
+        assert_eq!(responses[2][1]["total"], 1);
+        let email = &responses[3][1]["list"][0];
+        assert_eq!(email["subject"], "Hello");
+        assert_eq!(
+            email["from"],
+            json!([{ "name": "Ann", "email": "ann@example.com" }])
+        );
+        assert_eq!(email["messageId"], json!(["1@example.com"]));
+        assert_eq!(email["preview"], "See you soon.");
+        assert!(email.get("to").is_none());
+        assert_eq!(
+            responses[4],
+            json!(["error", { "type": "accountNotFound" }, "4"])
+        );
+        assert_eq!(responses[5][1]["type"], "unknownMethod");
+
+        let blob = email["id"].as_str().unwrap();
+        let path = format!("/jmap/download/{}/{}/message.eml", account, blob);
+        let (status, body) = request(server, "GET", &path, "").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body.as_bytes(), MESSAGE);
+
+        let (status, _) = request(server, "POST", "/jmap/api", "{").await;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        stream
+            .write_all(b"GET /.well-known/jmap HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        shutdown.trigger();
+        running.await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_jmap_requires_tls() {
+        let users = InMemoryUserStore::new();
+        users.add(User::new("me", "password")).await.unwrap();
+        let server = ServerBuilder::new()
+            .with_configuration(Configuration::default().with_listeners(vec![]))
+            .with_user_store(users)
+            .with_require_tls(true)
+            .with_jmap_listener(JmapListener::tcp("127.0.0.1:0"))
+            .bind()
+            .await
+            .unwrap();
+        let (address, shutdown) = (server.jmap_addresses()[0], server.shutdown());
+        let running = async_std::task::spawn(server.listen());
+
+        // The credentials are refused without being checked, as they went in the clear.
+        let (status, _) = request(address, "GET", "/.well-known/jmap", "").await;
+        assert_eq!(status, "HTTP/1.1 403 Forbidden");
+        shutdown.trigger();
+        running.await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_event_source() {
+        let root =
+            std::env::temp_dir().join(format!("treasurmap-jmap-push-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("me/new")).unwrap();
+        let users = InMemoryUserStore::new();
+        users.add(User::new("me", "password")).await.unwrap();
+        let delivery = MaildirDelivery::new(&root).with_interval(Duration::from_millis(50));
+        let server = ServerBuilder::new()
+            .with_configuration(Configuration::default().with_listeners(vec![]))
+            .with_user_store(users)
+            .with_jmap_listener(JmapListener::tcp("127.0.0.1:0"))
+            .with_maildir_delivery(delivery)
+            .bind()
+            .await
+            .unwrap();
+        let shutdown = server.shutdown();
+        let address = server.jmap_addresses()[0];
+        let running = async_std::task::spawn(server.listen());
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let credentials = encode_base64(b"me:password");
+        let request = format!(
+            "GET /jmap/eventsource?types=Email&closeafter=state&ping=0 HTTP/1.1\r\nAuthorization: Basic {}\r\n\r\n",
+            credentials
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut lines = futures::io::BufReader::new(stream).lines();
+        while !lines.next().await.unwrap().unwrap().is_empty() {}
+
+        std::fs::write(root.join("me/new/1.host"), MESSAGE).unwrap();
+        assert_eq!(lines.next().await.unwrap().unwrap(), "event: state");
+        let data = lines.next().await.unwrap().unwrap();
+        let change: Value = serde_json::from_str(data.strip_prefix("data: ").unwrap()).unwrap();
+        assert_eq!(change["@type"], "StateChange");
+        let changed = change["changed"]
+            .as_object()
+            .unwrap()
+            .values()
+            .next()
+            .unwrap();
+        assert!(changed.get("Email").is_some() && changed.get("Mailbox").is_none());
+        assert_eq!(lines.next().await.unwrap().unwrap(), "");
+        assert!(lines.next().await.is_none());
+
+        shutdown.trigger();
+        running.await.unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
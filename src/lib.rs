@@ -1,6 +1,39 @@
 pub mod server;
+pub mod access;
+pub mod audit;
+pub mod broadcast;
+pub mod buffers;
 pub mod connection;
+pub mod domains;
+pub mod framing;
+pub mod greeting;
+pub mod jmap;
+pub mod limits;
+pub mod listener;
+pub mod metrics;
+pub mod notify;
+pub mod runtime;
+pub mod scheduler;
+pub mod secrets;
+pub mod sessions;
+pub mod slowlog;
+pub mod middleware;
+pub mod plugin;
+pub mod pop3;
+#[cfg(unix)]
+pub mod privileges;
+pub mod replay;
+pub mod replication;
+pub mod shutdown;
+pub mod state;
+#[cfg(unix)]
+pub mod systemd;
+pub mod telemetry;
+pub mod testing;
 pub mod util;
 pub mod handlers;
 pub mod auth;
+pub mod capture;
 pub mod index;
+pub mod store;
+pub mod mime;
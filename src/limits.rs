@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What a client is told when it is over a connection limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rejection {
+    /// Sends `* BYE Too many connections` before closing. Listeners with implicit TLS close
+    /// without it, as it would have to be sent before the handshake.
+    #[default]
+    Bye,
+    /// Closes the connection without a word.
+    Close,
+}
+
+/// Caps on the connections open across every listener of a server, with counters of what
+/// was accepted and rejected.
+///
+/// Unlike `Listener::with_max_connections`, which makes further clients wait to be accepted,
+/// clients over these limits are turned away at once, so that a single client opening
+/// hundreds of sockets cannot keep everyone else waiting.
+#[derive(Debug, Default)]
+pub struct ConnectionLimits {
+    total: Option<usize>,
+    per_address: Option<usize>,
+    rejection: Rejection,
+    open: AtomicUsize,
+    addresses: Mutex<HashMap<IpAddr, usize>>,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl ConnectionLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The number of connections open at once across all listeners.
+    pub fn with_max_connections(mut self, total: usize) -> Self {
+        self.total = Some(total);
+        self
+    }
+    /// The number of connections open at once from one client address. Unix socket clients
+    /// have no address and are only held to the total.
+    pub fn with_max_connections_per_address(mut self, per_address: usize) -> Self {
+        self.per_address = Some(per_address);
+        self
+    }
+    pub fn with_rejection(mut self, rejection: Rejection) -> Self {
+        self.rejection = rejection;
+        self
+    }
+    pub fn rejection(&self) -> Rejection {
+        self.rejection
+    }
+
+    /// Counts a new connection from `peer` if it is within the limits. The connection counts
+    /// as open until the returned `Admission` is dropped.
+    pub fn admit(self: &Arc<Self>, peer: Option<IpAddr>) -> Option<Admission> {
+        let mut addresses = match self.addresses.lock() {
+            Ok(addresses) => addresses,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let open = self.open.load(Ordering::SeqCst);
+        let from_peer = peer.map_or(0, |peer| addresses.get(&peer).copied().unwrap_or(0));
+        let over_total = self.total.is_some_and(|total| open >= total);
+        let over_address = peer.is_some() && self.per_address.is_some_and(|max| from_peer >= max);
+        if over_total || over_address {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        if let Some(peer) = peer {
+            *addresses.entry(peer).or_insert(0) += 1;
+        }
+        self.open.fetch_add(1, Ordering::SeqCst);
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        Some(Admission {
+            limits: self.clone(),
+            peer,
+        })
+    }
+
+    /// The number of connections currently open.
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::SeqCst)
+    }
+    /// The number of connections currently open from `address`.
+    pub fn open_from(&self, address: IpAddr) -> usize {
+        self.addresses
+            .lock()
+            .map_or(0, |addresses| addresses.get(&address).copied().unwrap_or(0))
+    }
+    /// The number of connections admitted since the server started.
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+    /// The number of connections turned away since the server started.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+/// An open connection, counted against the limits until dropped.
+pub struct Admission {
+    limits: Arc<ConnectionLimits>,
+    peer: Option<IpAddr>,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        let mut addresses = match self.limits.addresses.lock() {
+            Ok(addresses) => addresses,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some(peer) = self.peer {
+            if let Some(count) = addresses.get_mut(&peer) {
+                *count -= 1;
+                if *count == 0 {
+                    addresses.remove(&peer);
+                }
+            }
+        }
+        self.limits.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// What happens to commands beyond a session's `RateLimit`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Excess {
+    /// Stops reading from the client until the command is within the rate.
+    #[default]
+    Delay,
+    /// Answers the command with `NO [LIMIT]` without running it.
+    Reject,
+}
+
+/// A token bucket limiting the commands of each session: up to `burst` commands at once,
+/// refilled at `per_second`.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    burst: f64,
+    per_second: f64,
+    excess: Excess,
+}
+
+impl RateLimit {
+    pub fn new(burst: u32, per_second: f64) -> Self {
+        Self {
+            burst: f64::from(burst.max(1)),
+            per_second,
+            excess: Excess::default(),
+        }
+    }
+    pub fn with_excess(mut self, excess: Excess) -> Self {
+        self.excess = excess;
+        self
+    }
+    pub fn excess(&self) -> Excess {
+        self.excess
+    }
+    pub(crate) fn bucket(&self) -> TokenBucket {
+        TokenBucket {
+            limit: *self,
+            tokens: self.burst,
+            updated: Instant::now(),
+        }
+    }
+}
+
+/// The commands one session has left under its `RateLimit`.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn limit(&self) -> &RateLimit {
+        &self.limit
+    }
+    /// Takes a token for a command, or says how long until there will be one.
+    pub(crate) fn take(&mut self) -> std::result::Result<(), Duration> {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.limit.per_second;
+        self.tokens = (self.tokens + refill).min(self.limit.burst);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait = (1.0 - self.tokens) / self.limit.per_second;
+        Err(Duration::try_from_secs_f64(wait).unwrap_or(Duration::MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+
+    use super::{ConnectionLimits, RateLimit};
+
+    const ADDRESS: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+    const OTHER: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)));
+
+    #[test]
+    fn test_admit() {
+        let limits = Arc::new(
+            ConnectionLimits::new()
+                .with_max_connections(4)
+                .with_max_connections_per_address(2),
+        );
+        let first = limits.admit(ADDRESS).unwrap();
+        let _second = limits.admit(ADDRESS).unwrap();
+        assert!(limits.admit(ADDRESS).is_none());
+        let _other = limits.admit(OTHER).unwrap();
+        let _local = limits.admit(None).unwrap();
+        // The total is reached, whatever the address.
+        assert!(limits.admit(None).is_none());
+        assert_eq!(limits.open(), 4);
+        assert_eq!(limits.open_from(ADDRESS.unwrap()), 2);
+
+        drop(first);
+        assert_eq!(limits.open_from(ADDRESS.unwrap()), 1);
+        assert!(limits.admit(ADDRESS).is_some());
+        assert_eq!(limits.accepted(), 5);
+        assert_eq!(limits.rejected(), 2);
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = RateLimit::new(2, 10.0).bucket();
+        assert!(bucket.take().is_ok());
+        assert!(bucket.take().is_ok());
+        let wait = bucket.take().unwrap_err();
+        assert!(wait > std::time::Duration::ZERO);
+        assert!(wait <= std::time::Duration::from_millis(100));
+        std::thread::sleep(wait);
+        assert!(bucket.take().is_ok());
+    }
+}
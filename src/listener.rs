@@ -0,0 +1,896 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_listen::{error_hint, ListenExt};
+use async_std::future::timeout;
+use async_std::io::{Read, Write};
+use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use async_std::prelude::*;
+use futures::future::{self, Either};
+use futures::stream::select_all;
+use log::{info, trace, warn};
+
+use crate::access::AccessControl;
+use crate::audit::AuditLog;
+use crate::auth::User;
+use crate::broadcast::Broadcast;
+use crate::buffers::BufferPool;
+use crate::capture::Capture;
+use crate::connection::{
+    Connection, Context, Timeouts, DEFAULT_MAX_IN_FLIGHT, DEFAULT_MAX_LINE_LENGTH,
+};
+use crate::framing::CHUNK;
+use crate::greeting::Greeting;
+use crate::handlers::capability::Advertised;
+use crate::limits::{ConnectionLimits, RateLimit, Rejection};
+use crate::metrics::{Counted, Metrics};
+use crate::middleware::Pipeline;
+use crate::runtime::{spawn, JoinHandle};
+use crate::server::Handlers;
+use crate::sessions::Sessions;
+use crate::shutdown::Shutdown;
+use crate::slowlog::SlowLog;
+use crate::util::Result;
+
+/// A bidirectional byte stream a connection can be served over.
+pub trait Io: Read + Write + Send + Unpin {}
+impl<T: Read + Write + Send + Unpin> Io for T {}
+
+#[cfg(feature = "tls")]
+pub use async_native_tls::TlsAcceptor;
+
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    /// A TCP address such as `0.0.0.0:143` or `[::1]:993`.
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// A socket already bound by the parent process, such as systemd, by its file descriptor
+    /// and name.
+    #[cfg(unix)]
+    Inherited(std::os::unix::io::RawFd, String),
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Tcp(address) => write!(f, "{}", address),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "{}", path.display()),
+            #[cfg(unix)]
+            Endpoint::Inherited(fd, name) => write!(f, "inherited socket {} (fd {})", name, fd),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Encryption {
+    None,
+    #[cfg(feature = "tls")]
+    StartTls(TlsAcceptor),
+    #[cfg(feature = "tls")]
+    Implicit(TlsAcceptor),
+}
+
+/// One address the server accepts connections on, with its own settings. A server may have
+/// any number of listeners, all serving the same handlers.
+#[derive(Clone)]
+pub struct Listener {
+    endpoint: Endpoint,
+    encryption: Encryption,
+    max_connections: usize,
+    error_timeout: Duration,
+    timeouts: Timeouts,
+    max_line_length: usize,
+    max_in_flight: usize,
+    rate_limit: Option<RateLimit>,
+    capture: Option<Capture>,
+    preauth: Option<String>,
+    /// The TCP addresses listened on besides the one of `endpoint`.
+    addresses: Vec<String>,
+    reuse_port: bool,
+}
+
+impl Listener {
+    pub fn tcp(address: &str) -> Self {
+        Self::on(Endpoint::Tcp(address.to_string()))
+    }
+    /// Listens on a Unix domain socket, replacing a stale socket left at `path`.
+    #[cfg(unix)]
+    pub fn unix<P: Into<PathBuf>>(path: P) -> Self {
+        Self::on(Endpoint::Unix(path.into()))
+    }
+    /// Accepts connections on the TCP or Unix socket already bound at `fd`, such as one
+    /// passed by systemd. See `systemd::listeners`. The listener takes ownership of `fd`,
+    /// which must not be used by anything else.
+    #[cfg(unix)]
+    pub fn inherited(fd: std::os::unix::io::RawFd, name: &str) -> Self {
+        Self::on(Endpoint::Inherited(fd, name.to_string()))
+    }
+    fn on(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            encryption: Encryption::None,
+            max_connections: 100,
+            error_timeout: Duration::from_millis(500),
+            timeouts: Timeouts::default(),
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            rate_limit: None,
+            capture: None,
+            preauth: None,
+            addresses: vec![],
+            reuse_port: false,
+        }
+    }
+    /// Offers STARTTLS on this listener, e.g. on port 143.
+    #[cfg(feature = "tls")]
+    pub fn with_starttls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.encryption = Encryption::StartTls(acceptor);
+        self
+    }
+    /// Negotiates TLS as soon as a client connects, e.g. on port 993.
+    #[cfg(feature = "tls")]
+    pub fn with_implicit_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.encryption = Encryption::Implicit(acceptor);
+        self
+    }
+    /// The number of connections served at once. Further clients wait to be accepted.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+    /// How long to pause accepting after an error such as running out of file descriptors.
+    pub fn with_error_timeout(mut self, error_timeout: Duration) -> Self {
+        self.error_timeout = error_timeout;
+        self
+    }
+    /// How long connections may stay idle before they are logged out.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+    /// The longest command line accepted, in bytes.
+    pub fn with_max_line_length(mut self, max_line_length: usize) -> Self {
+        self.max_line_length = max_line_length;
+        self
+    }
+    /// The number of commands each connection may run at once.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+    /// Limits how fast each client may send commands.
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+    /// Captures the protocol exchange of every connection, with credentials redacted.
+    pub fn with_capture(mut self, capture: Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+    /// Logs every connection in as `user` before it sends anything, greeting it with
+    /// `* PREAUTH`. Only for sockets no one but trusted clients can reach, such as the Unix
+    /// socket a webmail backend connects over: the user is neither looked up nor throttled.
+    pub fn with_preauth(mut self, user: &str) -> Self {
+        self.preauth = Some(user.to_string());
+        self
+    }
+    /// Also accepts connections on the TCP `address`, such as `[::]:143` beside
+    /// `0.0.0.0:143`, with the same settings. Connections to every address count towards
+    /// the one `with_max_connections`. IPv6 sockets of a listener with several addresses
+    /// only accept IPv6, so that they do not clash with the IPv4 ones.
+    pub fn with_address(mut self, address: &str) -> Self {
+        self.addresses.push(address.to_string());
+        self
+    }
+    /// Binds TCP sockets with `SO_REUSEPORT`, so that several listeners, in this server or
+    /// in other processes, can bind the same address and have the kernel share the
+    /// connections out between their accept loops. Only supported on Unix.
+    pub fn with_reuse_port(mut self, reuse_port: bool) -> Self {
+        self.reuse_port = reuse_port;
+        self
+    }
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    pub(crate) async fn bind(self) -> Result<Bound> {
+        if !self.addresses.is_empty() && !matches!(self.endpoint, Endpoint::Tcp(..)) {
+            let message = format!("{} cannot have more addresses", self.endpoint);
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                message,
+            )));
+        }
+        let socket = match &self.endpoint {
+            Endpoint::Tcp(address) => {
+                let v6_only = !self.addresses.is_empty();
+                let mut sockets = vec![];
+                for address in std::iter::once(address).chain(&self.addresses) {
+                    sockets.push(bind_tcp(address, self.reuse_port, v6_only).await?);
+                }
+                Socket::Tcp(sockets)
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+                Socket::Unix(async_std::os::unix::net::UnixListener::bind(path).await?)
+            }
+            #[cfg(unix)]
+            Endpoint::Inherited(fd, _) => inherit(*fd)?,
+        };
+        Ok(Bound {
+            listener: self,
+            socket,
+        })
+    }
+}
+
+/// Binds a TCP socket to the first address `address` resolves to which it can be bound to,
+/// setting `SO_REUSEPORT` and `IPV6_V6ONLY` when asked to.
+async fn bind_tcp(address: &str, reuse_port: bool, v6_only: bool) -> std::io::Result<TcpListener> {
+    let mut failure = None;
+    for address in address.to_socket_addrs().await? {
+        let bound = match reuse_port || (v6_only && address.is_ipv6()) {
+            true => options::bind(address, reuse_port, v6_only).map(TcpListener::from),
+            false => TcpListener::bind(address).await,
+        };
+        match bound {
+            Ok(socket) => return Ok(socket),
+            Err(e) => failure = Some(e),
+        }
+    }
+    Err(failure.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} did not resolve to any address", address),
+        )
+    }))
+}
+
+/// Takes over the listening socket at `fd`, finding whether it is a TCP or a Unix socket from
+/// its address.
+#[cfg(unix)]
+fn inherit(fd: std::os::unix::io::RawFd) -> std::io::Result<Socket> {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    // SAFETY: the listener was given ownership of the descriptor, and binds it only once.
+    let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    if tcp.local_addr().is_ok() {
+        tcp.set_nonblocking(true)?;
+        return Ok(Socket::Tcp(vec![TcpListener::from(tcp)]));
+    }
+    // SAFETY: the descriptor is released by the TCP listener, so it is still owned once.
+    let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+    unix.local_addr()?;
+    unix.set_nonblocking(true)?;
+    Ok(Socket::Unix(unix.into()))
+}
+
+#[cfg(unix)]
+mod options {
+    use std::io;
+    use std::net::{SocketAddr, TcpListener};
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+    /// How many connections the kernel queues until they are accepted, as std asks for.
+    const BACKLOG: libc::c_int = 128;
+
+    /// Binds a listening TCP socket to `address` as `TcpListener::bind` does, with the
+    /// options it cannot set before binding.
+    pub(super) fn bind(
+        address: SocketAddr,
+        reuse_port: bool,
+        v6_only: bool,
+    ) -> io::Result<TcpListener> {
+        let domain = match address {
+            SocketAddr::V4(..) => libc::AF_INET,
+            SocketAddr::V6(..) => libc::AF_INET6,
+        };
+        // SAFETY: socket has no memory safety requirements.
+        let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the descriptor was just opened and nothing else owns it.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // SAFETY: fcntl is given a valid descriptor.
+        if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // std sets SO_REUSEADDR too, so that a restarted server can bind at once.
+        enable(&fd, libc::SOL_SOCKET, libc::SO_REUSEADDR)?;
+        if reuse_port {
+            enable(&fd, libc::SOL_SOCKET, libc::SO_REUSEPORT)?;
+        }
+        if v6_only && address.is_ipv6() {
+            enable(&fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY)?;
+        }
+        let (storage, length) = socket_address(address);
+        // SAFETY: `storage` holds a socket address of `length` bytes.
+        let bound = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                (&storage as *const libc::sockaddr_storage).cast(),
+                length,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: listen is given a valid, bound descriptor.
+        if unsafe { libc::listen(fd.as_raw_fd(), BACKLOG) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let listener = TcpListener::from(fd);
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    }
+
+    fn enable(fd: &OwnedFd, level: libc::c_int, option: libc::c_int) -> io::Result<()> {
+        let on: libc::c_int = 1;
+        // SAFETY: the value is a c_int of the length given.
+        let set = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                level,
+                option,
+                (&on as *const libc::c_int).cast(),
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        match set {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    fn socket_address(address: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        // SAFETY: socket address structures are valid when zeroed.
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let length = match address {
+            SocketAddr::V4(address) => {
+                let v4 = libc::sockaddr_in {
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: address.port().to_be(),
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from_ne_bytes(address.ip().octets()),
+                    },
+                    // SAFETY: as above.
+                    ..unsafe { std::mem::zeroed() }
+                };
+                // SAFETY: sockaddr_storage is large and aligned enough for any address.
+                unsafe {
+                    std::ptr::write((&mut storage as *mut libc::sockaddr_storage).cast(), v4)
+                };
+                std::mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(address) => {
+                let v6 = libc::sockaddr_in6 {
+                    sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                    sin6_port: address.port().to_be(),
+                    sin6_flowinfo: address.flowinfo(),
+                    sin6_addr: libc::in6_addr {
+                        s6_addr: address.ip().octets(),
+                    },
+                    sin6_scope_id: address.scope_id(),
+                    // SAFETY: as above.
+                    ..unsafe { std::mem::zeroed() }
+                };
+                // SAFETY: as above.
+                unsafe {
+                    std::ptr::write((&mut storage as *mut libc::sockaddr_storage).cast(), v6)
+                };
+                std::mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, length as libc::socklen_t)
+    }
+}
+
+#[cfg(not(unix))]
+mod options {
+    use std::io;
+    use std::net::{SocketAddr, TcpListener};
+
+    /// IPv6 sockets only accept IPv6 unless told otherwise, so only `SO_REUSEPORT` is
+    /// missing here.
+    pub(super) fn bind(
+        address: SocketAddr,
+        reuse_port: bool,
+        _v6_only: bool,
+    ) -> io::Result<TcpListener> {
+        if reuse_port {
+            let message = "SO_REUSEPORT is only supported on Unix";
+            return Err(io::Error::new(io::ErrorKind::Unsupported, message));
+        }
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    }
+}
+
+enum Socket {
+    /// One socket for each address of a TCP listener.
+    Tcp(Vec<TcpListener>),
+    #[cfg(unix)]
+    Unix(async_std::os::unix::net::UnixListener),
+}
+
+/// A listener whose socket has been bound.
+pub(crate) struct Bound {
+    listener: Listener,
+    socket: Socket,
+}
+
+/// What every listener of a server shares.
+#[derive(Clone)]
+pub(crate) struct Shared {
+    pub(crate) handlers: Arc<Handlers>,
+    pub(crate) shutdown: Shutdown,
+    pub(crate) broadcast: Broadcast,
+    pub(crate) sessions: Sessions,
+    /// How long connections get to finish their commands on shutdown.
+    pub(crate) drain: Duration,
+    pub(crate) limits: Arc<ConnectionLimits>,
+    pub(crate) access: AccessControl,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) audit: Option<Arc<dyn AuditLog>>,
+    pub(crate) slow_log: Option<SlowLog>,
+    pub(crate) capabilities: Advertised,
+    pub(crate) greeting: Greeting,
+    pub(crate) middleware: Pipeline,
+}
+
+impl Bound {
+    /// The addresses of its TCP sockets, which tell the ports bound for port 0.
+    pub(crate) fn local_addrs(&self) -> Vec<SocketAddr> {
+        match &self.socket {
+            Socket::Tcp(sockets) => sockets
+                .iter()
+                .filter_map(|socket| socket.local_addr().ok())
+                .collect(),
+            #[cfg(unix)]
+            Socket::Unix(..) => vec![],
+        }
+    }
+
+    /// Accepts connections until the socket fails or `shutdown` is triggered, serving each on
+    /// its own task. On shutdown, connections get `drain` to finish the commands they are
+    /// running before they are cancelled. Clients over `limits` are turned away, and those
+    /// served are recorded in `metrics`.
+    pub(crate) async fn serve(self, shared: Shared) -> Result<()> {
+        let listener = self.listener;
+        let connections = match self.socket {
+            Socket::Tcp(sockets) => {
+                let peer = |stream: &TcpStream| stream.peer_addr().ok().map(|peer| peer.ip());
+                let incoming = select_all(sockets.iter().map(TcpListener::incoming));
+                accept(&listener, incoming, peer, &shared).await
+            }
+            #[cfg(unix)]
+            Socket::Unix(socket) => accept(&listener, socket.incoming(), |_| None, &shared).await,
+        };
+        let drain = shared.drain;
+        let mut connections: Vec<_> = connections.into_iter().map(Some).collect();
+        let finished = timeout(drain, async {
+            for connection in connections.iter_mut() {
+                if let Some(handle) = connection {
+                    let _ = handle.await;
+                    connection.take();
+                }
+            }
+        })
+        .await;
+        if finished.is_err() {
+            warn!(
+                "Cancelling connections to {} still open after {:?}",
+                listener.endpoint, drain
+            );
+            for handle in connections.into_iter().flatten() {
+                handle.cancel().await;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn accept<S, T, P>(
+    listener: &Listener,
+    incoming: S,
+    peer: P,
+    shared: &Shared,
+) -> Vec<JoinHandle<Result<()>>>
+where
+    S: Stream<Item = std::io::Result<T>> + Unpin,
+    T: Io + 'static,
+    P: Fn(&T) -> Option<IpAddr>,
+{
+    let endpoint = listener.endpoint.clone();
+    let mut incoming = incoming
+        .log_warnings(|e| {
+            warn!(
+                "An error ocurred while accepting a new connection on {}: {}. {}",
+                endpoint,
+                e,
+                error_hint(e)
+            )
+        })
+        .handle_errors(listener.error_timeout)
+        .backpressure(listener.max_connections);
+    info!("Server started listening on {}", listener.endpoint);
+    let Shared {
+        shutdown,
+        limits,
+        access,
+        metrics,
+        ..
+    } = shared;
+    // Room for the longest line with a read on either side of it, so that buffers rarely
+    // need to grow.
+    let buffers = BufferPool::new(listener.max_line_length + 2 * CHUNK);
+
+    let mut connections = vec![];
+    loop {
+        let (token, stream) = match future::select(incoming.next(), Box::pin(shutdown.wait())).await
+        {
+            Either::Left((Some(accepted), _)) => accepted,
+            Either::Left((None, _)) => break,
+            Either::Right(..) => {
+                info!("Server stopped listening on {}", listener.endpoint);
+                break;
+            }
+        };
+        let peer = peer(&stream);
+        let mut context = match &listener.preauth {
+            Some(user) => Context::of(Some(User::trusted(user)), None),
+            None => Context::default(),
+        };
+        if let Some(peer) = peer {
+            context = context.with_peer(peer);
+        }
+        if !access.admits(peer) {
+            warn!(
+                "Rejecting a connection to {} from {}: not allowed by the access policy",
+                listener.endpoint,
+                context.describe_peer()
+            );
+            spawn(reject(
+                stream,
+                Rejection::Bye,
+                listener.encryption.clone(),
+                "Access denied",
+            ));
+            continue;
+        }
+        let admission = match limits.admit(peer) {
+            Some(admission) => admission,
+            None => {
+                warn!(
+                    "Rejecting a connection to {} from {}: too many connections",
+                    listener.endpoint,
+                    context.describe_peer()
+                );
+                spawn(reject(
+                    stream,
+                    limits.rejection(),
+                    listener.encryption.clone(),
+                    "Too many connections",
+                ));
+                continue;
+            }
+        };
+        trace!(
+            "New connection to {} from {}",
+            listener.endpoint,
+            context.describe_peer()
+        );
+        let handlers = shared.handlers.clone();
+        let audit = shared.audit.clone();
+        let slow_log = shared.slow_log;
+        let capabilities = shared.capabilities.clone();
+        let greeting = shared.greeting.clone();
+        let middleware = shared.middleware.clone();
+        let encryption = listener.encryption.clone();
+        let timeouts = listener.timeouts;
+        let rate_limit = listener.rate_limit;
+        let capture = listener.capture.clone();
+        let max_line_length = listener.max_line_length;
+        let max_in_flight = listener.max_in_flight;
+        let shutdown = shutdown.clone();
+        let broadcast = shared.broadcast.clone();
+        let sessions = shared.sessions.clone();
+        let buffers = buffers.clone();
+        let metrics = metrics.clone();
+        let active = metrics.connection();
+        let stream = Counted::new(stream, metrics.clone());
+        connections.push(spawn(async move {
+            let _holder = (token, admission, active);
+            let connection = match encryption {
+                Encryption::None => Connection::new(Box::new(stream), context).await?,
+                #[cfg(feature = "tls")]
+                Encryption::StartTls(acceptor) => {
+                    Connection::new(Box::new(stream), context.with_starttls())
+                        .await?
+                        .with_starttls(acceptor)
+                }
+                #[cfg(feature = "tls")]
+                Encryption::Implicit(acceptor) => {
+                    let stream = acceptor.accept(stream).await?;
+                    Connection::new(Box::new(stream), context.with_secure()).await?
+                }
+            };
+            let connection = match rate_limit {
+                Some(rate_limit) => connection.with_rate_limit(rate_limit),
+                None => connection,
+            };
+            let connection = match audit {
+                Some(audit) => connection.with_audit_log(audit),
+                None => connection,
+            };
+            let connection = match capture {
+                Some(capture) => connection.with_capture(capture),
+                None => connection,
+            };
+            let connection = match slow_log {
+                Some(slow_log) => connection.with_slow_log(slow_log),
+                None => connection,
+            };
+            connection
+                .with_timeouts(timeouts)
+                .with_max_line_length(max_line_length)
+                .with_max_in_flight(max_in_flight)
+                .with_shutdown(shutdown)
+                .with_broadcast(&broadcast)
+                .with_sessions(&sessions)
+                .with_capabilities(capabilities)
+                .with_greeting(greeting)
+                .with_buffer_pool(buffers)
+                .with_metrics(metrics)
+                .with_middleware(middleware)
+                .handle(handlers)
+                .await
+        }));
+    }
+    connections
+}
+
+/// Turns away a client which is over the connection limits or not allowed to connect,
+/// saying why in a `* BYE` unless `rejection` says otherwise.
+async fn reject<T: Io>(
+    mut stream: T,
+    rejection: Rejection,
+    encryption: Encryption,
+    reason: &'static str,
+) {
+    let bye = match (rejection, encryption) {
+        (Rejection::Close, _) => false,
+        #[cfg(feature = "tls")]
+        (_, Encryption::Implicit(..)) => false,
+        _ => true,
+    };
+    if bye {
+        let bye = format!("* BYE {}\r\n", reason);
+        let write = stream.write_all(bye.as_bytes());
+        let _ = timeout(Duration::from_secs(1), write).await;
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use async_std::io::BufReader;
+    use async_std::net::TcpStream;
+    use async_std::os::unix::net::UnixStream;
+    use async_std::prelude::*;
+    use async_std::task::spawn;
+
+    use std::time::Duration;
+
+    use async_std::task::sleep;
+    use futures::SinkExt;
+
+    use super::Listener;
+    use crate::connection::Request;
+    use crate::greeting::Greeting;
+    use crate::handlers::Handle;
+    use crate::limits::ConnectionLimits;
+    use crate::server::{Response, ResponseStatus, ServerBuilder};
+    use crate::util::{Receiver, Result};
+
+    fn socket(name: &str) -> std::path::PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "treasurmap-listeners-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        directory.join("imap.sock")
+    }
+
+    /// Takes a while to answer, to be in flight when the server shuts down.
+    struct SlowHandler;
+
+    #[async_trait::async_trait]
+    impl Handle for SlowHandler {
+        fn command<'a>(&self) -> &'a str {
+            "SLOW"
+        }
+        async fn start<'a>(&'a mut self, mut requests: Receiver<Request>) -> Result<()> {
+            while let Some(mut request) = requests.next().await {
+                sleep(Duration::from_millis(100)).await;
+                let tag = request.command.tag();
+                let response = Response::new(&tag, ResponseStatus::OK, "SLOW completed.");
+                request.responder.send(vec![response]).await?;
+            }
+            Ok(())
+        }
+    }
+
+    #[async_std::test]
+    async fn test_multiple_listeners() {
+        let directory =
+            std::env::temp_dir().join(format!("treasurmap-listeners-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let sockets = [directory.join("first.sock"), directory.join("second.sock")];
+        // A socket left behind by an earlier run is replaced.
+        std::os::unix::net::UnixListener::bind(&sockets[0]).ok();
+
+        let server = ServerBuilder::new()
+            .with_listener(Listener::unix(&sockets[0]))
+            .with_listener(Listener::unix(&sockets[1]).with_max_connections(1))
+            .bind()
+            .await
+            .unwrap();
+        spawn(server.listen());
+        for socket in &sockets {
+            let stream = UnixStream::connect(socket).await.unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            let greeting = lines.next().await.unwrap().unwrap();
+            assert_eq!(
+                greeting,
+                "* OK [CAPABILITY IMAP4rev2 ID] IMAP4rev2 server ready"
+            );
+        }
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[async_std::test]
+    async fn test_addresses_and_reuse_port() {
+        let first = ServerBuilder::new()
+            .with_listener(
+                Listener::tcp("127.0.0.1:0")
+                    .with_address("127.0.0.1:0")
+                    .with_reuse_port(true),
+            )
+            .bind()
+            .await
+            .unwrap();
+        let addresses = first.addresses();
+        assert_eq!(addresses.len(), 2);
+        // Another server binds the same port, and the kernel shares connections out.
+        let second = ServerBuilder::new()
+            .with_listener(Listener::tcp(&addresses[0].to_string()).with_reuse_port(true))
+            .bind()
+            .await
+            .unwrap();
+        assert_eq!(second.addresses(), vec![addresses[0]]);
+        spawn(first.listen());
+        spawn(second.listen());
+        for address in addresses.iter().chain(&addresses) {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            let greeting = lines.next().await.unwrap().unwrap();
+            assert_eq!(
+                greeting,
+                "* OK [CAPABILITY IMAP4rev2 ID] IMAP4rev2 server ready"
+            );
+        }
+
+        let unix = Listener::unix(socket("addresses")).with_address("127.0.0.1:0");
+        assert!(ServerBuilder::new()
+            .with_listener(unix)
+            .bind()
+            .await
+            .is_err());
+    }
+
+    #[async_std::test]
+    async fn test_preauth() {
+        let socket = socket("preauth");
+        let server = ServerBuilder::new()
+            .with_listener(Listener::unix(&socket).with_preauth("webmail@example.com"))
+            .with_greeting(Greeting::new().with_hostname("mail.example.com"))
+            .bind()
+            .await
+            .unwrap();
+        let sessions = server.sessions();
+        spawn(server.listen());
+
+        let mut stream = UnixStream::connect(&socket).await.unwrap();
+        let mut lines = BufReader::new(stream.clone()).lines();
+        assert_eq!(
+            lines.next().await.unwrap().unwrap(),
+            "* PREAUTH [CAPABILITY IMAP4rev2 ID] mail.example.com IMAP4rev2 server ready"
+        );
+        stream.write_all(b"a1 NOOP\r\n").await.unwrap();
+        lines.next().await.unwrap().unwrap();
+        let session = sessions.list().pop().unwrap();
+        assert_eq!(session.user.as_deref(), Some("webmail@example.com"));
+        let _ = std::fs::remove_dir_all(socket.parent().unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_shutdown() {
+        let socket = socket("shutdown");
+        let server = ServerBuilder::new()
+            .with_listener(Listener::unix(&socket))
+            .with_handler(SlowHandler)
+            .with_drain_timeout(Duration::from_secs(5))
+            .bind()
+            .await
+            .unwrap();
+        let shutdown = server.shutdown();
+        let running = spawn(server.listen());
+
+        let mut stream = UnixStream::connect(&socket).await.unwrap();
+        let mut lines = BufReader::new(stream.clone()).lines();
+        lines.next().await.unwrap().unwrap();
+        stream.write_all(b"a1 SLOW\r\n").await.unwrap();
+        sleep(Duration::from_millis(20)).await;
+        shutdown.trigger();
+
+        // The command in flight is still answered before the connection closes. SLOW is not a
+        // command known to run alongside others, so the connection waits for it before saying
+        // BYE.
+        let mut received = vec![];
+        while let Some(line) = lines.next().await {
+            received.push(line.unwrap());
+        }
+        assert_eq!(
+            received,
+            vec!["a1 OK SLOW completed.", "* BYE Server shutting down"]
+        );
+        running.await.unwrap();
+        assert!(UnixStream::connect(&socket).await.is_err());
+        let _ = std::fs::remove_dir_all(socket.parent().unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_connection_limits() {
+        let socket = socket("limits");
+        let server = ServerBuilder::new()
+            .with_listener(Listener::unix(&socket))
+            .with_connection_limits(ConnectionLimits::new().with_max_connections(1))
+            .bind()
+            .await
+            .unwrap();
+        let limits = server.connection_limits();
+        spawn(server.listen());
+
+        let first = UnixStream::connect(&socket).await.unwrap();
+        let mut lines = BufReader::new(first.clone()).lines();
+        assert_eq!(
+            lines.next().await.unwrap().unwrap(),
+            "* OK [CAPABILITY IMAP4rev2 ID] IMAP4rev2 server ready"
+        );
+
+        let second = UnixStream::connect(&socket).await.unwrap();
+        let mut received = vec![];
+        let mut lines = BufReader::new(second).lines();
+        while let Some(line) = lines.next().await {
+            received.push(line.unwrap());
+        }
+        assert_eq!(received, vec!["* BYE Too many connections"]);
+        assert_eq!(limits.open(), 1);
+        assert_eq!(limits.rejected(), 1);
+        drop(first);
+        let _ = std::fs::remove_dir_all(socket.parent().unwrap());
+    }
+}
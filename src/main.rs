@@ -1,7 +1,603 @@
-use async_std::task;
-use imaprust::util::Result;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use log::warn;
+
+use imaprust::auth::sqlite::SqliteUserStore;
+use imaprust::auth::{User, UserStore};
+use imaprust::domains::domain_of;
+use imaprust::greeting::Greeting;
+use imaprust::index::account::{delete_account, export_account, ArchiveFormat, Progress};
+use imaprust::index::inmemory::InMemoryIndex;
+use imaprust::index::journal::Retention;
+use imaprust::index::reindex::reindex;
+use imaprust::index::sqlite::SqliteIndex;
+use imaprust::index::transfer::{export_maildir, export_mbox, import_maildir, import_mbox};
+use imaprust::index::uid::BucketUidAllocator;
+use imaprust::index::{Index, Owner};
+use imaprust::jmap::JmapListener;
+use imaprust::listener::Listener;
+use imaprust::notify::{Nats, Notifier, Webhook};
+use imaprust::pop3::Pop3Listener;
+#[cfg(unix)]
+use imaprust::privileges::Privileges;
+use imaprust::server::Configuration;
+use imaprust::replay::{proxy, replay, Recording};
+use imaprust::runtime::{block_on, spawn};
+use imaprust::scheduler::CompactJournals;
 use imaprust::server::ServerBuilder;
+use imaprust::slowlog::SlowLog;
+use imaprust::telemetry::Telemetry;
+use imaprust::store::object::{FileBucket, ObjectStore};
+use imaprust::store::sqlite::SqliteStore;
+use imaprust::store::DataStore;
+#[cfg(unix)]
+use imaprust::systemd;
+use imaprust::util::Result;
+
+/// How often the change journals of the index are compacted.
+const JOURNAL_COMPACTION: Duration = Duration::from_secs(60 * 60);
+
+const USAGE: &str = "Usage:
+    imap_rust [options]         start the IMAP server
+    imap_rust reindex [options] rebuild the index from a data store
+    imap_rust import [options]  import an mbox file or Maildir into a data store
+    imap_rust export [options]  export mailboxes from a data store to mbox or Maildir
+    imap_rust account-export [options]  export a whole account to a tar archive
+    imap_rust account-delete [options]  delete an account and all of its mail
+    imap_rust proxy --listen <address> --upstream <address> --record <dir>
+    imap_rust replay --listen <address> <file>
+    imap_rust useradd --users <file> <name> [password]
+    imap_rust userdel --users <file> <name>
+    imap_rust passwd --users <file> <name> [password]
+    imap_rust users --users <file> [--domain <domain>]
+    imap_rust alias --users <file> <alias> <name>
+    imap_rust unalias --users <file> <alias>
+    imap_rust sessions --control <socket> [terminate <id> | terminate-user <name>]
+    imap_rust usage --control <socket> [<name>]
+
+Server options:
+    --users <file>     authenticate against a SqliteUserStore in <file>
+    --sqlite <dir>     keep messages in a SqliteStore rooted at <dir>
+    --index <file>     keep the index in a SqliteIndex in <file>; servers given the same
+                       --sqlite and --index share their mailboxes
+    --journal-entries <n>
+                       keep the last <n> changes of each mailbox (default 10000), from which
+                       replicas catch up; those further behind are sent every record again
+    --journal-age <seconds>
+                       also drop changes older than <seconds>
+    --listen <address> serve IMAP on <address>, such as 0.0.0.0:143 (repeatable, so that
+                       [::]:143 can be served too; default 127.0.0.1:3143)
+    --accept-loops <n> bind each --listen address <n> times with SO_REUSEPORT, accepting
+                       on every socket at once; other servers given --accept-loops may
+                       listen on the same addresses
+    --hostname <name>  name the server <name> in the greeting
+    --greeting <text>  greet clients with <text> rather than IMAP4rev2 server ready
+    --pop3 <address>   also serve the INBOX of each user over POP3 on <address>
+    --jmap <address>   also serve mail over JMAP (experimental, read-only) on <address>
+    --webhook <url>    POST a JSON event for each change to a mailbox to <url> (repeatable)
+    --nats <address>   publish the same events to the treasurmap.events subject of the
+                       NATS server at <address> (repeatable)
+    --run-as <user>    switch to <user> once the listeners are bound
+    --group <group>    switch to <group> rather than the primary group of --run-as
+    --chroot <dir>     confine the server to <dir> once the listeners are bound
+    --control <socket> answer the sessions and usage commands on the Unix socket <socket>
+    --slow-command <ms>
+                       log commands which take longer than <ms> milliseconds (default 1000)
+    --large-response <bytes>
+                       log commands which answer with more than <bytes> (default 16 MiB)
+    --otlp <url>       export traces and metrics to the OpenTelemetry collector at <url>,
+                       such as http://localhost:4318
+
+sessions lists the open sessions of the server listening on --control, or terminates one
+session or every session of a user with * BYE. usage recalculates the storage used by
+<name>, or by every user, correcting the usage quotas are checked against, and prints what
+it found.
+
+User management reads the password from standard input when it is not given. Accounts are
+named by their full address; users --domain lists only the accounts of <domain>.
+
+Reindex options:
+    --user <name>      user whose mailboxes are indexed (repeatable, required)
+    --sqlite <dir>     read messages from a SqliteStore rooted at <dir>
+    --objects <dir>    read messages from an ObjectStore in a FileBucket at <dir>
+    --uids <dir>       persist UIDNEXT and UIDVALIDITY in <dir>
+
+Import and export options:
+    --user <name>      user whose mailboxes are imported or exported (required)
+    --sqlite <dir>     use a SqliteStore rooted at <dir>
+    --objects <dir>    use an ObjectStore in a FileBucket at <dir>
+    --mbox <file>      import or export the mbox <file>, to or from --mailbox
+    --maildir <dir>    import or export the Maildir++ tree <dir>
+    --mailbox <name>   the mailbox to import an mbox into or to export; Maildir exports
+                       the whole account without it
+
+Account options:
+    --user <name>      the account to export or delete (required)
+    --sqlite <dir>     use a SqliteStore rooted at <dir>
+    --objects <dir>    use an ObjectStore in a FileBucket at <dir>
+    --output <file>    write the export to the tar archive <file> (required for export)
+    --format <format>  lay the export out as maildir (the default) or mbox, with a
+                       manifest.json describing every message
+    --users <file>     the SqliteUserStore to remove the user from (required for delete)
+    --index <file>     also remove the user's records from the SqliteIndex in <file>
+    Deleting cannot be undone. Blobs of an ObjectStore are reclaimed by its next collection.
+
+Proxy and replay:
+    proxy passes each connection to <address> through to the IMAP server at --upstream,
+    recording the session with credentials redacted to a new file in <dir>. replay answers
+    every connection the way the server did in the recording <file>. Neither uses TLS.";
 
 pub(crate) fn main() -> Result<()> {
-    task::block_on(ServerBuilder::new().listen())
-}
\ No newline at end of file
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None | Some(
+            "--users" | "--sqlite" | "--index" | "--journal-entries" | "--journal-age"
+            | "--listen" | "--accept-loops" | "--hostname" | "--greeting" | "--pop3"
+            | "--jmap" | "--webhook" | "--nats" | "--run-as" | "--group" | "--chroot"
+            | "--control" | "--slow-command" | "--large-response" | "--otlp",
+        ) => {
+            block_on(run_server(&args))
+        }
+        Some("reindex") => block_on(run_reindex(&args[1..])),
+        Some(command @ ("import" | "export")) => block_on(run_transfer(command, &args[1..])),
+        Some(command @ ("account-export" | "account-delete")) => {
+            block_on(run_account(command, &args[1..]))
+        }
+        Some("proxy") => block_on(run_proxy(&args[1..])),
+        Some("replay") => block_on(run_replay(&args[1..])),
+        #[cfg(unix)]
+        Some("sessions") => block_on(run_sessions(&args[1..])),
+        #[cfg(unix)]
+        Some("usage") => block_on(run_usage(&args[1..])),
+        Some(command @ ("useradd" | "userdel" | "passwd" | "users" | "alias" | "unalias")) => {
+            block_on(run_user_command(command, &args[1..]))
+        }
+        Some("help" | "--help" | "-h") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Some(other) => usage(&format!("unknown command {}", other)),
+    }
+}
+
+fn usage<T>(problem: &str) -> Result<T> {
+    eprintln!("{}\n\n{}", problem, USAGE);
+    exit(2)
+}
+
+/// Runs the server until SIGTERM or SIGINT, then shuts it down gracefully. Under systemd, the
+/// server listens on the sockets it was passed and reports when it is ready and stopping.
+async fn serve(builder: ServerBuilder) -> Result<()> {
+    #[cfg(unix)]
+    let builder = systemd::listeners()
+        .into_iter()
+        .fold(builder, |builder, listener| builder.with_listener(listener));
+    let server = builder.bind().await?;
+    #[cfg(unix)]
+    {
+        let shutdown = server.shutdown();
+        shutdown.trigger_on_signals()?;
+        systemd::notify("READY=1")?;
+        spawn(async move {
+            shutdown.wait().await;
+            let _ = systemd::notify("STOPPING=1");
+        });
+    }
+    server.listen().await
+}
+
+async fn run_server(args: &[String]) -> Result<()> {
+    let mut builder = ServerBuilder::new();
+    let mut notifier: Option<Notifier> = None;
+    let mut slow_log: Option<SlowLog> = None;
+    let mut configuration: Option<Configuration> = None;
+    let mut index: Option<&String> = None;
+    let mut retention = Retention::default();
+    let mut greeting: Option<Greeting> = None;
+    let mut listen: Vec<&String> = vec![];
+    let mut accept_loops: Option<usize> = None;
+    #[cfg(unix)]
+    let mut privileges: Option<Privileges> = None;
+    #[cfg(unix)]
+    let mut control = None;
+    let mut options = args.iter();
+    while let Some(option) = options.next() {
+        let value = match options.next() {
+            Some(value) => value,
+            None => return usage(&format!("{} requires a value", option)),
+        };
+        match option.as_str() {
+            "--users" => builder = builder.with_user_store(SqliteUserStore::open(value)?),
+            "--sqlite" => builder = builder.with_data_store(SqliteStore::new(value)),
+            "--index" => index = Some(value),
+            "--journal-entries" => match value.parse() {
+                Ok(entries) => retention.max_entries = Some(entries),
+                Err(_) => return usage(&format!("invalid number of entries {}", value)),
+            },
+            "--journal-age" => match value.parse() {
+                Ok(seconds) => retention.max_age = Some(Duration::from_secs(seconds)),
+                Err(_) => return usage(&format!("invalid age {}", value)),
+            },
+            "--listen" => listen.push(value),
+            "--accept-loops" => match value.parse() {
+                Ok(loops) if loops > 0 => accept_loops = Some(loops),
+                _ => return usage(&format!("invalid number of accept loops {}", value)),
+            },
+            "--hostname" => greeting = Some(greeting.unwrap_or_default().with_hostname(value)),
+            "--greeting" => greeting = Some(greeting.unwrap_or_default().with_text(value)),
+            "--pop3" => builder = builder.with_pop3_listener(Pop3Listener::tcp(value)),
+            "--jmap" => builder = builder.with_jmap_listener(JmapListener::tcp(value)),
+            "--webhook" => notifier = Some(notifier.unwrap_or_default().with_sink(Webhook::new(value)?)),
+            "--nats" => notifier = Some(notifier.unwrap_or_default().with_sink(Nats::new(value))),
+            "--slow-command" => match value.parse() {
+                Ok(ms) => {
+                    let duration = Duration::from_millis(ms);
+                    slow_log = Some(slow_log.unwrap_or_default().with_duration(duration));
+                }
+                Err(_) => return usage(&format!("invalid duration {}", value)),
+            },
+            "--large-response" => match value.parse() {
+                Ok(bytes) => {
+                    slow_log = Some(slow_log.unwrap_or_default().with_response_size(bytes));
+                }
+                Err(_) => return usage(&format!("invalid size {}", value)),
+            },
+            "--otlp" => {
+                let telemetry = Telemetry::new(value)?;
+                configuration = Some(configuration.unwrap_or_default().with_telemetry(telemetry));
+            }
+            #[cfg(unix)]
+            "--run-as" => privileges = Some(privileges.unwrap_or_default().with_user(value)),
+            #[cfg(unix)]
+            "--group" => privileges = Some(privileges.unwrap_or_default().with_group(value)),
+            #[cfg(unix)]
+            "--chroot" => privileges = Some(privileges.unwrap_or_default().with_chroot(value)),
+            #[cfg(unix)]
+            "--control" => control = Some(value),
+            _ => return usage(&format!("unknown option {}", option)),
+        }
+    }
+    builder = match index {
+        Some(path) => {
+            builder.with_index(SqliteIndex::open(path)?.with_journal_retention(retention))
+        }
+        None => builder.with_index(InMemoryIndex::new().with_journal_retention(retention)),
+    };
+    // The SqliteIndex only drops what retention no longer keeps when its journals are compacted.
+    builder = builder.with_job(CompactJournals, JOURNAL_COMPACTION);
+    if let Some(greeting) = greeting {
+        builder = builder.with_greeting(greeting);
+    }
+    if let Some((first, rest)) = listen.split_first() {
+        let listener = rest
+            .iter()
+            .fold(Listener::tcp(first), |listener, address| listener.with_address(address))
+            .with_reuse_port(accept_loops.is_some());
+        let listeners = vec![listener; accept_loops.unwrap_or(1)];
+        configuration = Some(configuration.unwrap_or_default().with_listeners(listeners));
+    }
+    if let Some(notifier) = notifier {
+        builder = builder.with_notifier(notifier);
+    }
+    if let Some(slow_log) = slow_log {
+        builder = builder.with_slow_log(slow_log);
+    }
+    #[cfg(unix)]
+    if let Some(privileges) = privileges {
+        configuration = Some(configuration.unwrap_or_default().with_privileges(privileges));
+    }
+    #[cfg(unix)]
+    if let Some(control) = control {
+        configuration = Some(configuration.unwrap_or_default().with_control_socket(control));
+    }
+    if let Some(configuration) = configuration {
+        builder = builder.with_configuration(configuration);
+    }
+    serve(builder).await
+}
+
+async fn run_user_command(command: &str, args: &[String]) -> Result<()> {
+    let (path, rest) = match args {
+        [option, path, rest @ ..] if option == "--users" => (path, rest),
+        _ => return usage(&format!("{} requires --users <file>", command)),
+    };
+    let store = SqliteUserStore::open(path)?;
+    match (command, rest) {
+        ("users", []) => {
+            for name in store.list().await? {
+                println!("{}", name);
+            }
+        }
+        ("users", [option, domain]) if option == "--domain" => {
+            for name in store.list().await? {
+                if domain_of(&name).is_some_and(|name| name.eq_ignore_ascii_case(domain)) {
+                    println!("{}", name);
+                }
+            }
+        }
+        ("userdel", [name]) => store.remove(name).await?,
+        ("alias", [alias, name]) => store.add_alias(alias, name).await?,
+        ("unalias", [alias]) => store.remove_alias(alias).await?,
+        ("useradd" | "passwd", [name, password @ ..]) if password.len() <= 1 => {
+            let password = match password.first() {
+                Some(password) => password.clone(),
+                None => read_password()?,
+            };
+            match command {
+                "useradd" => store.add(User::new(name, &password)).await?,
+                _ => store.set_password(name, &password).await?,
+            }
+        }
+        _ => return usage(&format!("invalid arguments for {}", command)),
+    }
+    Ok(())
+}
+
+/// Reads a password from the first line of standard input, so it stays out of shell history.
+fn read_password() -> Result<String> {
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return usage("a password is required");
+    }
+    Ok(password.to_string())
+}
+
+async fn run_reindex(args: &[String]) -> Result<()> {
+    let mut users = vec![];
+    let mut store: Option<Box<dyn DataStore>> = None;
+    let mut index = InMemoryIndex::new();
+    let mut options = args.iter();
+    while let Some(option) = options.next() {
+        let value = match options.next() {
+            Some(value) => value,
+            None => return usage(&format!("{} requires a value", option)),
+        };
+        match option.as_str() {
+            "--user" => users.push(value.clone()),
+            "--sqlite" | "--objects" => store = Some(open_store(option, value)),
+            "--uids" => {
+                index = index.with_uid_allocator(BucketUidAllocator::new(FileBucket::new(value)))
+            }
+            _ => return usage(&format!("unknown option {}", option)),
+        }
+    }
+    let store = match store {
+        Some(store) => store,
+        None => return usage("a data store is required"),
+    };
+    if users.is_empty() {
+        return usage("at least one --user is required");
+    }
+
+    for user in users {
+        let summary = reindex(store.as_ref(), &index, &Owner::new(&user), |progress| {
+            eprint!(
+                "\r{}: {} {}/{}",
+                user, progress.mailbox, progress.indexed, progress.total
+            );
+            if progress.indexed == progress.total {
+                eprintln!();
+            }
+        })
+        .await?;
+        println!(
+            "{}: indexed {} messages in {} mailboxes, removed {} stale records",
+            user, summary.messages, summary.mailboxes, summary.removed
+        );
+    }
+    Ok(())
+}
+
+/// The data store named by a `--sqlite` or `--objects` option.
+fn open_store(option: &str, value: &str) -> Box<dyn DataStore> {
+    match option {
+        "--sqlite" => Box::new(SqliteStore::new(value)),
+        _ => Box::new(ObjectStore::new(FileBucket::new(value))),
+    }
+}
+
+async fn run_transfer(command: &str, args: &[String]) -> Result<()> {
+    let mut user = None;
+    let mut store: Option<Box<dyn DataStore>> = None;
+    let (mut mbox, mut maildir, mut mailbox) = (None, None, None);
+    let mut options = args.iter();
+    while let Some(option) = options.next() {
+        let value = match options.next() {
+            Some(value) => value,
+            None => return usage(&format!("{} requires a value", option)),
+        };
+        match option.as_str() {
+            "--user" => user = Some(Owner::new(value)),
+            "--sqlite" | "--objects" => store = Some(open_store(option, value)),
+            "--mbox" => mbox = Some(Path::new(value)),
+            "--maildir" => maildir = Some(Path::new(value)),
+            "--mailbox" => mailbox = Some(value.as_str()),
+            _ => return usage(&format!("unknown option {}", option)),
+        }
+    }
+    let (user, store) = match (user, store) {
+        (Some(user), Some(store)) => (user, store),
+        (None, _) => return usage(&format!("{} requires --user", command)),
+        (_, None) => return usage("a data store is required"),
+    };
+    // Only the data store is written. Its index can be rebuilt from it, as reindex does.
+    let index = InMemoryIndex::new();
+    let store = store.as_ref();
+    let summary = match (command, mbox, maildir, mailbox) {
+        ("import", Some(mbox), None, mailbox) => {
+            import_mbox(mbox, store, &index, &user, mailbox.unwrap_or("INBOX")).await?
+        }
+        ("import", None, Some(maildir), None) => import_maildir(maildir, store, &index, &user).await?,
+        ("export", Some(mbox), None, Some(mailbox)) => export_mbox(store, &user, mailbox, mbox).await?,
+        ("export", None, Some(maildir), mailbox) => export_maildir(store, &user, mailbox, maildir).await?,
+        _ => return usage(&format!("invalid arguments for {}", command)),
+    };
+    println!(
+        "{}: {}ed {} messages in {} mailboxes",
+        user, command, summary.messages, summary.mailboxes
+    );
+    Ok(())
+}
+
+async fn run_account(command: &str, args: &[String]) -> Result<()> {
+    let mut user = None;
+    let mut store: Option<Box<dyn DataStore>> = None;
+    let mut users = None;
+    let mut index: Box<dyn Index> = Box::new(InMemoryIndex::new());
+    let (mut output, mut format) = (None, ArchiveFormat::Maildir);
+    let mut options = args.iter();
+    while let Some(option) = options.next() {
+        let value = match options.next() {
+            Some(value) => value,
+            None => return usage(&format!("{} requires a value", option)),
+        };
+        match option.as_str() {
+            "--user" => user = Some(Owner::new(value)),
+            "--sqlite" | "--objects" => store = Some(open_store(option, value)),
+            "--users" => users = Some(SqliteUserStore::open(value)?),
+            "--index" => index = Box::new(SqliteIndex::open(value)?),
+            "--output" => output = Some(Path::new(value)),
+            "--format" => {
+                format = match value.as_str() {
+                    "maildir" => ArchiveFormat::Maildir,
+                    "mbox" => ArchiveFormat::Mbox,
+                    _ => return usage(&format!("unknown format {}", value)),
+                }
+            }
+            _ => return usage(&format!("unknown option {}", option)),
+        }
+    }
+    let (user, store) = match (user, store) {
+        (Some(user), Some(store)) => (user, store),
+        (None, _) => return usage(&format!("{} requires --user", command)),
+        (_, None) => return usage("a data store is required"),
+    };
+    let report = |progress: &Progress| {
+        eprint!("\r{}: {} {}/{}", user, progress.mailbox, progress.done, progress.total);
+        if progress.done == progress.total {
+            eprintln!();
+        }
+    };
+    let (summary, done) = match (command, output, users) {
+        ("account-export", Some(output), None) => (
+            export_account(store.as_ref(), &user, format, output, report).await?,
+            "exported",
+        ),
+        ("account-delete", None, Some(users)) => (
+            delete_account(store.as_ref(), index.as_ref(), &users, &user, report).await?,
+            "deleted",
+        ),
+        _ => return usage(&format!("invalid arguments for {}", command)),
+    };
+    println!(
+        "{}: {} {} messages ({} bytes) in {} mailboxes",
+        user, done, summary.messages, summary.bytes, summary.mailboxes
+    );
+    Ok(())
+}
+
+/// Sends one request to the control socket of a running server and prints the answer.
+#[cfg(unix)]
+async fn run_sessions(args: &[String]) -> Result<()> {
+    let (path, request) = match args {
+        [option, path] if option == "--control" => (path, "LIST".to_string()),
+        [option, path, command, id] if option == "--control" && command == "terminate" => {
+            (path, format!("TERMINATE {}", id))
+        }
+        [option, path, command, user] if option == "--control" && command == "terminate-user" => {
+            (path, format!("TERMINATE-USER {}", user))
+        }
+        _ => return usage("invalid arguments for sessions"),
+    };
+    let answer = ask_control(path, &request).await?;
+    if request == "LIST" {
+        println!("ID\tUSER\tPEER\tMAILBOX\tIDLE\tCOMMANDS");
+    }
+    print!("{}", answer);
+    Ok(())
+}
+
+/// Asks the server listening on a control socket to recalculate usage and prints the answer.
+#[cfg(unix)]
+async fn run_usage(args: &[String]) -> Result<()> {
+    let (path, request) = match args {
+        [option, path] if option == "--control" => (path, "RECALCULATE-USAGE".to_string()),
+        [option, path, user] if option == "--control" => {
+            (path, format!("RECALCULATE-USAGE {}", user))
+        }
+        _ => return usage("invalid arguments for usage"),
+    };
+    let answer = ask_control(path, &request).await?;
+    if let Some(problem) = answer.strip_prefix("ERROR ") {
+        return Err(problem.trim_end().into());
+    }
+    println!("USER\tMAILBOXES\tBYTES\tCORRECTED\tDRIFT");
+    print!("{}", answer);
+    Ok(())
+}
+
+/// Sends `request` to the control socket at `path` and reads the answer.
+#[cfg(unix)]
+async fn ask_control(path: &str, request: &str) -> Result<String> {
+    use async_std::os::unix::net::UnixStream;
+
+    let mut stream = UnixStream::connect(path).await?;
+    stream.write_all(format!("{}\n", request).as_bytes()).await?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).await?;
+    Ok(answer)
+}
+
+async fn run_proxy(args: &[String]) -> Result<()> {
+    let (listen, upstream, directory) = match args {
+        [a, listen, b, upstream, c, directory]
+            if a == "--listen" && b == "--upstream" && c == "--record" =>
+        {
+            (listen, upstream.clone(), PathBuf::from(directory))
+        }
+        _ => return usage("invalid arguments for proxy"),
+    };
+    let listener = TcpListener::bind(listen).await?;
+    let mut incoming = listener.incoming();
+    while let Some(client) = incoming.next().await {
+        let client = client?;
+        let (upstream, directory) = (upstream.clone(), directory.clone());
+        spawn(async move {
+            let proxied = match TcpStream::connect(&upstream).await {
+                Ok(server) => proxy(client, server, &directory).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = proxied {
+                warn!("Could not proxy a session to {}: {}", upstream, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn run_replay(args: &[String]) -> Result<()> {
+    let (listen, path) = match args {
+        [option, listen, path] if option == "--listen" => (listen, Path::new(path)),
+        _ => return usage("invalid arguments for replay"),
+    };
+    let recording = Arc::new(Recording::load(path)?);
+    let listener = TcpListener::bind(listen).await?;
+    let mut incoming = listener.incoming();
+    while let Some(client) = incoming.next().await {
+        let (client, recording) = (client?, recording.clone());
+        spawn(async move {
+            if let Err(e) = replay(client, &recording).await {
+                warn!("Could not replay a session: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,661 @@
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_std::future::timeout;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use futures::future::{self, Either};
+use futures::{AsyncRead, AsyncWrite};
+use tracing::{debug, warn};
+
+use crate::runtime::spawn;
+use crate::shutdown::Shutdown;
+
+/// The upper bounds, in seconds, of the buckets of the command latency histogram.
+pub(crate) const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The longest HTTP request head read from a metrics client.
+const MAX_REQUEST_LENGTH: usize = 8 * 1024;
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Histogram {
+    /// Observations in each of `LATENCY_BUCKETS`, not cumulative.
+    pub(crate) buckets: [u64; LATENCY_BUCKETS.len()],
+    pub(crate) count: u64,
+    pub(crate) sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// How the values of a metric are to be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Value {
+    Int(u64),
+    Float(f64),
+    Histogram(Histogram),
+}
+
+/// One value of a metric, told apart from its others by its labels.
+#[derive(Debug, Clone)]
+pub(crate) struct Point {
+    pub(crate) labels: Vec<(&'static str, String)>,
+    pub(crate) value: Value,
+}
+
+/// A metric and its values, see `Metrics::families`.
+#[derive(Debug, Clone)]
+pub(crate) struct Family {
+    pub(crate) name: &'static str,
+    pub(crate) kind: Kind,
+    pub(crate) help: &'static str,
+    pub(crate) points: Vec<Point>,
+}
+
+/// Counts what the server is doing, for scraping by Prometheus.
+///
+/// Command rates are exported as counters, from which Prometheus derives commands per
+/// second with `rate()`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    connections: AtomicUsize,
+    commands: Mutex<BTreeMap<String, u64>>,
+    latencies: Mutex<BTreeMap<String, Histogram>>,
+    auth_failures: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    mailbox_operations: Mutex<BTreeMap<&'static str, u64>>,
+    /// Runs of scheduled jobs, by job and whether they succeeded.
+    job_runs: Mutex<BTreeMap<(String, bool), u64>>,
+    job_seconds: Mutex<BTreeMap<String, f64>>,
+    blobs_deleted: AtomicU64,
+    blob_bytes_reclaimed: AtomicU64,
+    /// The bytes the last dry run of blob garbage collection would have reclaimed.
+    blob_bytes_reclaimable: AtomicU64,
+    replicated_changes: AtomicU64,
+    /// How far behind the standby was in the last run of replication, in milliseconds.
+    replication_lag: AtomicU64,
+    /// Mailboxes whose recorded usage recalculation found wrong, and by how many bytes.
+    usage_corrections: AtomicU64,
+    usage_drift: AtomicU64,
+}
+
+fn increment<K: Ord>(counters: &Mutex<BTreeMap<K, u64>>, key: K) {
+    let mut counters = match counters.lock() {
+        Ok(counters) => counters,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    *counters.entry(key).or_insert(0) += 1;
+}
+
+fn count<K: Ord + Borrow<Q>, Q: Ord + ?Sized>(counters: &Mutex<BTreeMap<K, u64>>, key: &Q) -> u64 {
+    counters
+        .lock()
+        .map_or(0, |counters| counters.get(key).copied().unwrap_or(0))
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a connection as open until the returned guard is dropped.
+    pub fn connection(self: &Arc<Self>) -> ActiveConnection {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        ActiveConnection {
+            metrics: self.clone(),
+        }
+    }
+    /// Counts a command received from a client, whether or not it is run.
+    pub fn command(&self, verb: &str) {
+        increment(&self.commands, verb.to_uppercase());
+    }
+    /// Records how long a command took, from being dispatched to its handler finishing.
+    pub fn command_completed(&self, verb: &str, latency: Duration) {
+        let mut latencies = match self.latencies.lock() {
+            Ok(latencies) => latencies,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        latencies
+            .entry(verb.to_uppercase())
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+    pub fn auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn received(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    /// Counts a call to the index, named after the `Index` method.
+    pub fn mailbox_operation(&self, operation: &'static str) {
+        increment(&self.mailbox_operations, operation);
+    }
+    /// Records a run of the scheduled job `job` and how long it took.
+    pub fn job_completed(&self, job: &str, succeeded: bool, duration: Duration) {
+        increment(&self.job_runs, (job.to_string(), succeeded));
+        let mut seconds = match self.job_seconds.lock() {
+            Ok(seconds) => seconds,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *seconds.entry(job.to_string()).or_insert(0.0) += duration.as_secs_f64();
+    }
+    /// Records a run of blob garbage collection which deleted `blobs` orphaned blobs of
+    /// `bytes` in all, or would have if it was a dry run.
+    pub fn blobs_collected(&self, blobs: u64, bytes: u64, dry_run: bool) {
+        if dry_run {
+            self.blob_bytes_reclaimable.store(bytes, Ordering::Relaxed);
+            return;
+        }
+        self.blobs_deleted.fetch_add(blobs, Ordering::Relaxed);
+        self.blob_bytes_reclaimed
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+    /// Records a run of replication which shipped `changes` to the standby, the oldest of
+    /// which was made `lag` before it was shipped.
+    pub fn replicated(&self, changes: u64, lag: Duration) {
+        self.replicated_changes
+            .fetch_add(changes, Ordering::Relaxed);
+        self.replication_lag
+            .store(lag.as_millis() as u64, Ordering::Relaxed);
+    }
+    /// Records that recalculating usage found the usage of a mailbox off by `drift` bytes.
+    pub fn usage_corrected(&self, drift: u64) {
+        self.usage_corrections.fetch_add(1, Ordering::Relaxed);
+        self.usage_drift.fetch_add(drift, Ordering::Relaxed);
+    }
+
+    /// The number of connections currently open.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+    /// The number of `verb` commands received.
+    pub fn commands(&self, verb: &str) -> u64 {
+        count(&self.commands, verb)
+    }
+    pub fn auth_failures(&self) -> u64 {
+        self.auth_failures.load(Ordering::Relaxed)
+    }
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+    /// The number of calls to the `operation` method of the index.
+    pub fn mailbox_operations(&self, operation: &str) -> u64 {
+        count(&self.mailbox_operations, operation)
+    }
+    /// The number of runs of the scheduled job `job` which succeeded, or failed.
+    pub fn job_runs(&self, job: &str, succeeded: bool) -> u64 {
+        count(&self.job_runs, &(job.to_string(), succeeded))
+    }
+
+    /// The number of orphaned blobs garbage collection has deleted.
+    pub fn blobs_deleted(&self) -> u64 {
+        self.blobs_deleted.load(Ordering::Relaxed)
+    }
+    /// The size of the orphaned blobs garbage collection has deleted.
+    pub fn blob_bytes_reclaimed(&self) -> u64 {
+        self.blob_bytes_reclaimed.load(Ordering::Relaxed)
+    }
+    /// The number of changes shipped to the standby.
+    pub fn replicated_changes(&self) -> u64 {
+        self.replicated_changes.load(Ordering::Relaxed)
+    }
+    /// How far behind the standby was when replication last ran.
+    pub fn replication_lag(&self) -> Duration {
+        Duration::from_millis(self.replication_lag.load(Ordering::Relaxed))
+    }
+    /// The number of mailboxes whose usage recalculation has corrected.
+    pub fn usage_corrections(&self) -> u64 {
+        self.usage_corrections.load(Ordering::Relaxed)
+    }
+    /// The bytes by which the usage of the mailboxes corrected had drifted.
+    pub fn usage_drift(&self) -> u64 {
+        self.usage_drift.load(Ordering::Relaxed)
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for family in self.families() {
+            header(&mut out, family.name, family.kind.name(), family.help);
+            for point in family.points {
+                let labels: Vec<String> = point
+                    .labels
+                    .iter()
+                    .map(|(name, value)| format!("{}=\"{}\"", name, value))
+                    .collect();
+                let braced = |labels: &[String]| match labels.is_empty() {
+                    true => String::new(),
+                    false => format!("{{{}}}", labels.join(",")),
+                };
+                let name = family.name;
+                match point.value {
+                    Value::Int(value) => {
+                        let _ = writeln!(out, "{}{} {}", name, braced(&labels), value);
+                    }
+                    Value::Float(value) => {
+                        let _ = writeln!(out, "{}{} {}", name, braced(&labels), value);
+                    }
+                    Value::Histogram(histogram) => {
+                        let mut cumulative = 0u64;
+                        let bounds = LATENCY_BUCKETS.iter().map(|bound| bound.to_string());
+                        let counts = histogram.buckets.iter().map(|count| {
+                            cumulative += *count;
+                            cumulative
+                        });
+                        let overflow = std::iter::once(("+Inf".to_string(), histogram.count));
+                        for (bound, count) in bounds.zip(counts).chain(overflow) {
+                            let mut labels = labels.clone();
+                            labels.push(format!("le=\"{}\"", bound));
+                            let _ = writeln!(out, "{}_bucket{} {}", name, braced(&labels), count);
+                        }
+                        let labels = braced(&labels);
+                        let _ = writeln!(out, "{}_sum{} {}", name, labels, histogram.sum);
+                        let _ = writeln!(out, "{}_count{} {}", name, labels, histogram.count);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Every metric with its current values, for rendering or exporting.
+    pub(crate) fn families(&self) -> Vec<Family> {
+        let single = |value: Value| vec![Point { labels: vec![], value }];
+        let mut families = vec![Family {
+            name: "imap_connections",
+            kind: Kind::Gauge,
+            help: "Connections currently open.",
+            points: single(Value::Int(self.connections() as u64)),
+        }];
+
+        let commands = self.commands.lock().map_or(vec![], |commands| {
+            commands
+                .iter()
+                .map(|(verb, count)| Point {
+                    labels: vec![("command", verb.clone())],
+                    value: Value::Int(*count),
+                })
+                .collect()
+        });
+        families.push(Family {
+            name: "imap_commands_total",
+            kind: Kind::Counter,
+            help: "Commands received, by verb.",
+            points: commands,
+        });
+        let latencies = self.latencies.lock().map_or(vec![], |latencies| {
+            latencies
+                .iter()
+                .map(|(verb, histogram)| Point {
+                    labels: vec![("command", verb.clone())],
+                    value: Value::Histogram(histogram.clone()),
+                })
+                .collect()
+        });
+        families.push(Family {
+            name: "imap_command_duration_seconds",
+            kind: Kind::Histogram,
+            help: "Time taken to run commands, by verb.",
+            points: latencies,
+        });
+
+        for (name, help, value) in [
+            (
+                "imap_auth_failures_total",
+                "Failed LOGIN and AUTHENTICATE commands.",
+                self.auth_failures(),
+            ),
+            (
+                "imap_received_bytes_total",
+                "Bytes read from clients.",
+                self.bytes_in(),
+            ),
+            (
+                "imap_sent_bytes_total",
+                "Bytes written to clients.",
+                self.bytes_out(),
+            ),
+        ] {
+            families.push(Family {
+                name,
+                kind: Kind::Counter,
+                help,
+                points: single(Value::Int(value)),
+            });
+        }
+
+        let operations = self.mailbox_operations.lock().map_or(vec![], |operations| {
+            operations
+                .iter()
+                .map(|(operation, count)| Point {
+                    labels: vec![("operation", operation.to_string())],
+                    value: Value::Int(*count),
+                })
+                .collect()
+        });
+        families.push(Family {
+            name: "imap_mailbox_operations_total",
+            kind: Kind::Counter,
+            help: "Calls to the index, by operation.",
+            points: operations,
+        });
+
+        let runs = self.job_runs.lock().map_or(vec![], |runs| {
+            runs.iter()
+                .map(|((job, succeeded), count)| {
+                    let outcome = if *succeeded { "ok" } else { "error" };
+                    Point {
+                        labels: vec![("job", job.clone()), ("outcome", outcome.to_string())],
+                        value: Value::Int(*count),
+                    }
+                })
+                .collect()
+        });
+        families.push(Family {
+            name: "imap_job_runs_total",
+            kind: Kind::Counter,
+            help: "Runs of scheduled jobs, by job and outcome.",
+            points: runs,
+        });
+        let seconds = self.job_seconds.lock().map_or(vec![], |seconds| {
+            seconds
+                .iter()
+                .map(|(job, seconds)| Point {
+                    labels: vec![("job", job.clone())],
+                    value: Value::Float(*seconds),
+                })
+                .collect()
+        });
+        families.push(Family {
+            name: "imap_job_duration_seconds_total",
+            kind: Kind::Counter,
+            help: "Time spent running scheduled jobs, by job.",
+            points: seconds,
+        });
+
+        for (name, kind, help, value) in [
+            (
+                "imap_blob_gc_deleted_total",
+                Kind::Counter,
+                "Orphaned blobs deleted by garbage collection.",
+                self.blobs_deleted(),
+            ),
+            (
+                "imap_blob_gc_reclaimed_bytes_total",
+                Kind::Counter,
+                "Bytes reclaimed by deleting orphaned blobs.",
+                self.blob_bytes_reclaimed(),
+            ),
+            (
+                "imap_blob_gc_reclaimable_bytes",
+                Kind::Gauge,
+                "Bytes the last dry run of blob garbage collection would have reclaimed.",
+                self.blob_bytes_reclaimable.load(Ordering::Relaxed),
+            ),
+            (
+                "imap_replicated_changes_total",
+                Kind::Counter,
+                "Mailbox changes shipped to the standby.",
+                self.replicated_changes(),
+            ),
+            (
+                "imap_usage_corrections_total",
+                Kind::Counter,
+                "Mailboxes whose recorded storage usage was found wrong and corrected.",
+                self.usage_corrections(),
+            ),
+            (
+                "imap_usage_drift_bytes_total",
+                Kind::Counter,
+                "Bytes by which the storage usage corrected had drifted.",
+                self.usage_drift(),
+            ),
+        ] {
+            families.push(Family {
+                name,
+                kind,
+                help,
+                points: single(Value::Int(value)),
+            });
+        }
+        families.push(Family {
+            name: "imap_replication_lag_seconds",
+            kind: Kind::Gauge,
+            help: "How long the oldest change shipped in the last run of replication had waited.",
+            points: single(Value::Float(self.replication_lag().as_secs_f64())),
+        });
+        families
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+/// An open connection, counted in the metrics until dropped.
+pub struct ActiveConnection {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.metrics.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A stream counting the bytes read from and written to it in `Metrics`.
+pub struct Counted<T> {
+    inner: T,
+    metrics: Arc<Metrics>,
+}
+
+impl<T> Counted<T> {
+    pub fn new(inner: T, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let read = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(bytes)) = read {
+            self.metrics.received(bytes);
+        }
+        read
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let written = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(bytes)) = written {
+            self.metrics.sent(bytes);
+        }
+        written
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Serves `metrics` over HTTP at `/metrics` until `shutdown` is triggered.
+pub(crate) async fn serve(listener: TcpListener, metrics: Arc<Metrics>, shutdown: Shutdown) {
+    loop {
+        let accepted =
+            match future::select(Box::pin(listener.accept()), Box::pin(shutdown.wait())).await {
+                Either::Left((accepted, _)) => accepted,
+                Either::Right(..) => return,
+            };
+        match accepted {
+            Ok((stream, _)) => {
+                let metrics = metrics.clone();
+                spawn(async move {
+                    let scrape = timeout(Duration::from_secs(10), scrape(stream, &metrics));
+                    match scrape.await {
+                        Ok(Err(e)) => debug!("Could not answer a metrics request: {}", e),
+                        Err(..) => debug!("Timed out answering a metrics request"),
+                        Ok(Ok(())) => {}
+                    }
+                });
+            }
+            Err(e) => {
+                warn!("Could not accept a metrics connection: {}", e);
+                async_std::task::sleep(Duration::from_millis(500)).await;
+            }
+        }
+    }
+}
+
+/// Answers one HTTP request, then closes the connection.
+async fn scrape(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    let mut head = vec![];
+    let mut buffer = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 || head.len() + read > MAX_REQUEST_LENGTH {
+            break;
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::prelude::*;
+    use async_std::task::spawn;
+
+    use super::{serve, Counted, Metrics};
+    use crate::shutdown::Shutdown;
+
+    #[test]
+    fn test_render() {
+        let metrics = Arc::new(Metrics::new());
+        let _connection = metrics.connection();
+        metrics.command("fetch");
+        metrics.command("FETCH");
+        metrics.command_completed("FETCH", Duration::from_millis(20));
+        metrics.command_completed("FETCH", Duration::from_secs(20));
+        metrics.auth_failure();
+        metrics.mailbox_operation("list_messages");
+        metrics.replicated(3, Duration::from_millis(1500));
+
+        let rendered = metrics.render();
+        for line in [
+            "# TYPE imap_connections gauge",
+            "imap_connections 1",
+            "imap_commands_total{command=\"FETCH\"} 2",
+            "imap_command_duration_seconds_bucket{command=\"FETCH\",le=\"0.01\"} 0",
+            "imap_command_duration_seconds_bucket{command=\"FETCH\",le=\"0.025\"} 1",
+            "imap_command_duration_seconds_bucket{command=\"FETCH\",le=\"10\"} 1",
+            "imap_command_duration_seconds_bucket{command=\"FETCH\",le=\"+Inf\"} 2",
+            "imap_command_duration_seconds_count{command=\"FETCH\"} 2",
+            "imap_auth_failures_total 1",
+            "imap_mailbox_operations_total{operation=\"list_messages\"} 1",
+            "imap_replicated_changes_total 3",
+            "imap_replication_lag_seconds 1.5",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "missing {}", line);
+        }
+        drop(_connection);
+        assert_eq!(metrics.connections(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_scrape() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::new());
+        let shutdown = Shutdown::new();
+        let server = spawn(serve(listener, metrics.clone(), shutdown.clone()));
+
+        // Bytes through a counted stream are recorded.
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut stream = Counted::new(stream, metrics.clone());
+        let request = b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        stream.write_all(request).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("imap_connections 0\n"));
+        assert_eq!(metrics.bytes_out(), request.len() as u64);
+        assert_eq!(metrics.bytes_in(), response.len() as u64);
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        shutdown.trigger();
+        server.await;
+    }
+}
@@ -0,0 +1,181 @@
+use std::sync::Arc;
+
+use futures::channel::mpsc::unbounded;
+use futures::{SinkExt, StreamExt};
+use tracing::warn;
+
+use crate::connection::Request;
+use crate::server::{Response, ResponseStatus};
+use crate::util::{Result, Sender};
+
+/// Code run around the dispatch of every command to its handler, for concerns such as
+/// logging, metrics, rate limiting or access control which no one handler owns.
+///
+/// A middleware may change the request before passing it to `next`, change the responses
+/// `next` returns, or answer the command itself without calling `next` at all. Events the
+/// handler sends still reach the connection directly.
+#[async_trait::async_trait]
+pub trait Middleware: Send + Sync {
+    async fn call(&self, request: Request, next: Next) -> Result<Vec<Response>>;
+}
+
+/// The rest of the pipeline after a middleware: the middleware added after it, then the
+/// handler.
+pub struct Next {
+    middleware: Arc<Vec<Box<dyn Middleware>>>,
+    position: usize,
+    handler: Sender<Request>,
+}
+
+impl Next {
+    pub async fn run(self, request: Request) -> Result<Vec<Response>> {
+        match self.middleware.get(self.position) {
+            Some(middleware) => {
+                let next = Next {
+                    middleware: self.middleware.clone(),
+                    position: self.position + 1,
+                    handler: self.handler,
+                };
+                middleware.call(request, next).await
+            }
+            None => dispatch(self.handler, request).await,
+        }
+    }
+}
+
+/// Sends `request` to its handler and collects every response it gives.
+async fn dispatch(mut handler: Sender<Request>, mut request: Request) -> Result<Vec<Response>> {
+    let (responder, responses) = unbounded();
+    request.responder = responder;
+    handler.send(request).await?;
+    Ok(responses.concat().await)
+}
+
+/// The middleware of a server, in the order they were added. The first is the outermost,
+/// seeing requests first and responses last.
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    middleware: Arc<Vec<Box<dyn Middleware>>>,
+}
+
+impl Pipeline {
+    pub fn new(middleware: Vec<Box<dyn Middleware>>) -> Self {
+        Self {
+            middleware: Arc::new(middleware),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.middleware.is_empty()
+    }
+    /// Runs `request` through the middleware to `handler`, returning the responses to send.
+    pub async fn run(&self, handler: Sender<Request>, request: Request) -> Result<Vec<Response>> {
+        let next = Next {
+            middleware: self.middleware.clone(),
+            position: 0,
+            handler,
+        };
+        next.run(request).await
+    }
+
+    /// Runs `request` through the pipeline and sends the responses to its responder. A
+    /// failing middleware fails the command, not the connection.
+    pub(crate) async fn answer(&self, handler: Sender<Request>, request: Request) {
+        let mut responder = request.responder.clone();
+        let tag = request.command.tag();
+        let responses = match self.run(handler, request).await {
+            Ok(responses) => responses,
+            Err(e) => {
+                warn!("Could not run a command through the middleware: {}", e);
+                let message = "[SERVERBUG] The command could not be completed.";
+                vec![Response::new(&tag, ResponseStatus::NO, message)]
+            }
+        };
+        let _ = responder.send(responses).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::spawn;
+    use futures::channel::mpsc::unbounded;
+    use futures::StreamExt;
+
+    use super::{Middleware, Next, Pipeline};
+    use crate::connection::{Context, Event, Request};
+    use crate::handlers::logout::LogoutHandler;
+    use crate::handlers::Handle;
+    use crate::server::{Command, Response, ResponseStatus};
+    use crate::util::Result;
+
+    /// Adds an untagged response before those of the rest of the pipeline.
+    struct Announce(&'static str);
+
+    #[async_trait::async_trait]
+    impl Middleware for Announce {
+        async fn call(&self, request: Request, next: Next) -> Result<Vec<Response>> {
+            let mut responses = vec![Response::untagged(self.0)];
+            responses.extend(next.run(request).await?);
+            Ok(responses)
+        }
+    }
+
+    /// Answers every command itself.
+    struct Deny;
+
+    #[async_trait::async_trait]
+    impl Middleware for Deny {
+        async fn call(&self, request: Request, _next: Next) -> Result<Vec<Response>> {
+            let tag = request.command.tag();
+            Ok(vec![Response::new(&tag, ResponseStatus::NO, "Denied")])
+        }
+    }
+
+    async fn run(pipeline: Pipeline) -> (Vec<Response>, Vec<Event>) {
+        let (handler, requests) = unbounded();
+        spawn(async move { LogoutHandler {}.start(requests).await });
+        let (responder, _responses) = unbounded();
+        let (events, updates) = unbounded();
+        let request = Request {
+            command: Command::new("a1", "LOGOUT", vec![]),
+            responder,
+            events,
+            context: Context::default(),
+            span: tracing::Span::none(),
+            continuation: Default::default(),
+            timings: Default::default(),
+        };
+        let responses = pipeline.run(handler, request).await.unwrap();
+        (responses, updates.collect::<Vec<_>>().await)
+    }
+
+    #[async_std::test]
+    async fn test_pipeline() {
+        let pipeline = Pipeline::new(vec![
+            Box::new(Announce("first")),
+            Box::new(Announce("second")),
+        ]);
+        let (responses, events) = run(pipeline).await;
+        assert_eq!(
+            responses,
+            vec![
+                Response::untagged("first"),
+                Response::untagged("second"),
+                Response::untagged("BYE IMAP4rev2 server logging out"),
+                Response::new("a1", ResponseStatus::OK, "LOGOUT completed. Goodbye!"),
+            ]
+        );
+        // The handler's events are not held up by the middleware.
+        assert!(matches!(events[..], [Event::UNAUTH()]));
+
+        let pipeline = Pipeline::new(vec![Box::new(Announce("first")), Box::new(Deny)]);
+        let (responses, events) = run(pipeline).await;
+        assert_eq!(
+            responses,
+            vec![
+                Response::untagged("first"),
+                Response::new("a1", ResponseStatus::NO, "Denied"),
+            ]
+        );
+        assert!(events.is_empty());
+    }
+}
@@ -0,0 +1,216 @@
+use serde::{Deserialize, Serialize};
+
+use super::encoding::decode_words;
+
+/// One entry of an RFC 5322 address list, in the shape IMAP uses for ENVELOPE addresses.
+///
+/// A group is represented by a start marker, whose `mailbox` is the group name and whose
+/// `host` is `None`, followed by its members and an end marker with every field `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Address {
+    pub name: Option<String>,
+    /// The obsolete source route, e.g. `@relay.example.com:`.
+    pub route: Option<String>,
+    pub mailbox: Option<String>,
+    pub host: Option<String>,
+}
+
+impl Address {
+    pub fn new(mailbox: &str, host: &str) -> Self {
+        Self {
+            mailbox: Some(mailbox.to_string()),
+            host: Some(host.to_string()),
+            ..Default::default()
+        }
+    }
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+    pub fn group_start(name: &str) -> Self {
+        Self {
+            mailbox: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+    pub fn group_end() -> Self {
+        Self::default()
+    }
+
+    /// Parses an address list header value such as `Jane <jane@example.com>, team: a@b.c;`.
+    /// Display names are unquoted and have their encoded words decoded.
+    pub fn parse_list(value: &str) -> Vec<Address> {
+        let mut addresses = vec![];
+        let mut current = String::new();
+        let (mut quoted, mut escaped, mut comment, mut angle, mut group) =
+            (false, false, 0usize, false, false);
+        for c in value.chars() {
+            if escaped {
+                escaped = false;
+                current.push(c);
+                continue;
+            }
+            let top_level = !quoted && comment == 0 && !angle;
+            match c {
+                '\\' if quoted || comment > 0 => escaped = true,
+                '"' if comment == 0 => quoted = !quoted,
+                '(' if !quoted => comment += 1,
+                ')' if !quoted && comment > 0 => comment -= 1,
+                '<' if !quoted && comment == 0 => angle = true,
+                '>' if !quoted && comment == 0 => angle = false,
+                ':' if top_level && !group => {
+                    addresses.push(Address::group_start(&phrase(&current)));
+                    current.clear();
+                    group = true;
+                    continue;
+                }
+                ',' if top_level => {
+                    addresses.extend(mailbox(&current));
+                    current.clear();
+                    continue;
+                }
+                ';' if top_level && group => {
+                    addresses.extend(mailbox(&current));
+                    addresses.push(Address::group_end());
+                    current.clear();
+                    group = false;
+                    continue;
+                }
+                _ => {}
+            }
+            current.push(c);
+        }
+        addresses.extend(mailbox(&current));
+        if group {
+            addresses.push(Address::group_end());
+        }
+        addresses
+    }
+}
+
+/// Parses a single `name <addr-spec>` or bare `addr-spec`, returning `None` for empty input.
+fn mailbox(value: &str) -> Option<Address> {
+    let (outside, comments) = strip_comments(value);
+    let outside = outside.trim();
+    if outside.is_empty() {
+        return None;
+    }
+    let (name, spec) = match (outside.find('<'), outside.rfind('>')) {
+        (Some(open), Some(close)) if open < close => {
+            (phrase(&outside[..open]), &outside[open + 1..close])
+        }
+        // `jane@example.com (Jane Doe)` uses the comment as the display name.
+        _ => (phrase(&comments), outside),
+    };
+    let (route, spec) = match spec.split_once(':') {
+        Some((route, spec)) => (Some(route.trim().to_string()), spec),
+        None => (None, spec),
+    };
+    let spec = spec.trim();
+    let (mailbox, host) = match spec.rfind('@') {
+        Some(at) => (&spec[..at], Some(spec[at + 1..].trim().to_string())),
+        None => (spec, None),
+    };
+    Some(Address {
+        name: Some(name).filter(|name| !name.is_empty()),
+        route,
+        mailbox: Some(unquote(mailbox.trim())),
+        host,
+    })
+}
+
+/// Separates the text outside comments from the text inside them.
+fn strip_comments(value: &str) -> (String, String) {
+    let (mut outside, mut inside) = (String::new(), String::new());
+    let (mut quoted, mut escaped, mut depth) = (false, false, 0usize);
+    for c in value.chars() {
+        let target = if depth > 0 { &mut inside } else { &mut outside };
+        if escaped {
+            escaped = false;
+            target.push(c);
+            continue;
+        }
+        match c {
+            '\\' if quoted || depth > 0 => {
+                escaped = true;
+                if depth == 0 {
+                    target.push(c);
+                }
+            }
+            '"' if depth == 0 => {
+                quoted = !quoted;
+                target.push(c);
+            }
+            '(' if !quoted => {
+                if depth > 0 {
+                    target.push(c);
+                }
+                depth += 1;
+            }
+            ')' if !quoted && depth > 0 => {
+                depth -= 1;
+                if depth > 0 {
+                    target.push(c);
+                }
+            }
+            _ => target.push(c),
+        }
+    }
+    (outside, inside)
+}
+
+/// Turns a display name into plain text: quotes removed, whitespace collapsed and encoded
+/// words decoded.
+fn phrase(value: &str) -> String {
+    let words: Vec<&str> = value.split_whitespace().collect();
+    decode_words(&unquote(&words.join(" ")))
+}
+
+fn unquote(value: &str) -> String {
+    let mut unquoted = String::with_capacity(value.len());
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            _ if escaped => {
+                escaped = false;
+                unquoted.push(c);
+            }
+            '\\' => escaped = true,
+            '"' => {}
+            _ => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Address;
+
+    #[test]
+    fn test_address_list() {
+        let addresses = Address::parse_list(
+            "\"Doe, Jane\" <jane@example.com>, =?utf-8?Q?Ren=C3=A9?= <rene@example.com>,\
+             bob@example.com (Bob), team: a@example.com, <@relay.example:b@example.com>;, root",
+        );
+        let mut relayed = Address::new("b", "example.com");
+        relayed.route = Some("@relay.example".to_string());
+        assert_eq!(
+            addresses,
+            vec![
+                Address::new("jane", "example.com").with_name("Doe, Jane"),
+                Address::new("rene", "example.com").with_name("René"),
+                Address::new("bob", "example.com").with_name("Bob"),
+                Address::group_start("team"),
+                Address::new("a", "example.com"),
+                relayed,
+                Address::group_end(),
+                Address {
+                    mailbox: Some("root".to_string()),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert!(Address::parse_list(" ").is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+use super::charset;
+
+/// Decodes base64, skipping line breaks and any other characters outside the alphabet as
+/// RFC 2045 section 6.8 requires.
+pub fn decode_base64(data: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(data.len() * 3 / 4);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in data {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => continue,
+        };
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    decoded
+}
+
+pub fn encode_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let buffer = chunk.iter().enumerate().fold(0u32, |buffer, (i, byte)| {
+            buffer | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(buffer >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decodes quoted-printable (RFC 2045 section 6.7), including soft line breaks. Malformed
+/// escapes are kept as they are.
+pub fn decode_quoted_printable(data: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut position = 0;
+    while position < data.len() {
+        let byte = data[position];
+        if byte != b'=' {
+            decoded.push(byte);
+            position += 1;
+            continue;
+        }
+        let rest = &data[position + 1..];
+        let whitespace = rest
+            .iter()
+            .take_while(|byte| **byte == b' ' || **byte == b'\t')
+            .count();
+        if rest[whitespace..].starts_with(b"\r\n") {
+            position += 1 + whitespace + 2;
+        } else if rest[whitespace..].starts_with(b"\n") || whitespace == rest.len() {
+            position += 1 + whitespace + 1;
+        } else if let Some(value) = rest
+            .get(..2)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            decoded.push(value);
+            position += 3;
+        } else {
+            decoded.push(byte);
+            position += 1;
+        }
+    }
+    decoded
+}
+
+/// Decodes the RFC 2047 encoded words (`=?charset?B|Q?text?=`) in a header value. Whitespace
+/// between two adjacent encoded words is dropped, and encoded words in an unsupported charset
+/// are left as they are.
+pub fn decode_words(value: &str) -> String {
+    let mut decoded = String::with_capacity(value.len());
+    let mut rest = value;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let (before, candidate) = rest.split_at(start);
+        match decode_word(candidate) {
+            Some((word, length)) => {
+                if !(after_word && before.chars().all(char::is_whitespace)) {
+                    decoded.push_str(before);
+                }
+                decoded.push_str(&word);
+                after_word = true;
+                rest = &candidate[length..];
+            }
+            None => {
+                decoded.push_str(before);
+                decoded.push_str("=?");
+                after_word = false;
+                rest = &candidate[2..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Decodes the encoded word at the start of `value`, returning it with the number of bytes it
+/// took up.
+fn decode_word(value: &str) -> Option<(String, usize)> {
+    let mut fields = value.strip_prefix("=?")?.splitn(3, '?');
+    let (charset, encoding, rest) = (fields.next()?, fields.next()?, fields.next()?);
+    let text = &rest[..rest.find("?=")?];
+    if text.contains(char::is_whitespace) {
+        return None;
+    }
+    let bytes = match encoding {
+        "B" | "b" => decode_base64(text.as_bytes()),
+        "Q" | "q" => decode_quoted_printable(text.replace('_', " ").as_bytes()),
+        _ => return None,
+    };
+    // RFC 2231 allows a language suffix on the charset, e.g. `utf-8*en`.
+    let word = charset::decode(charset.split('*').next()?, &bytes)?;
+    let length = "=?".len() + charset.len() + encoding.len() + text.len() + "??".len() + "?=".len();
+    Some((word, length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_base64, decode_quoted_printable, decode_words, encode_base64};
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(decode_base64(b"aGVs\r\nbG8h"), b"hello!");
+        assert_eq!(decode_base64(b"aGk="), b"hi");
+        for input in [&b""[..], b"a", b"ab", b"abc", b"abcd"] {
+            assert_eq!(decode_base64(encode_base64(input).as_bytes()), input);
+        }
+        assert_eq!(encode_base64(b"hi"), "aGk=");
+    }
+
+    #[test]
+    fn test_quoted_printable() {
+        assert_eq!(
+            decode_quoted_printable(b"caf=C3=A9 soft=\r\nbreak =ZZ"),
+            "café softbreak =ZZ".as_bytes()
+        );
+        // `from_str_radix` would take the sign as part of the number.
+        assert_eq!(decode_quoted_printable(b"=+1"), b"=+1");
+    }
+
+    #[test]
+    fn test_encoded_words() {
+        assert_eq!(
+            decode_words(
+                "=?UTF-8?Q?caf=C3=A9?= =?utf-8?B?w6k=?= and =?ISO-8859-1?Q?na=EFve_idea?="
+            ),
+            "caféé and naïve idea"
+        );
+        assert_eq!(
+            decode_words("=?x-unknown?Q?a?= =?utf-8?Q?"),
+            "=?x-unknown?Q?a?= =?utf-8?Q?"
+        );
+    }
+}
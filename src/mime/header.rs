@@ -0,0 +1,265 @@
+/// A single header field, borrowed from the message it was parsed from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field<'a> {
+    pub name: &'a str,
+    /// The complete field including continuation lines and the trailing line break, as needed
+    /// for `BODY[HEADER.FIELDS (...)]`.
+    pub raw: &'a [u8],
+    colon: usize,
+}
+
+impl<'a> Field<'a> {
+    /// The unfolded value of the field with surrounding whitespace removed.
+    pub fn value(&self) -> String {
+        let raw = String::from_utf8_lossy(&self.raw[self.colon + 1..]);
+        raw.split('\n')
+            .map(|line| line.trim_matches(|c: char| c == '\r' || c == ' ' || c == '\t'))
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// The header section of a message or MIME part, including the blank line which ends it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Headers<'a> {
+    raw: &'a [u8],
+}
+
+impl<'a> Headers<'a> {
+    pub fn new(raw: &'a [u8]) -> Self {
+        Self { raw }
+    }
+
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    pub fn fields(&self) -> Vec<Field<'a>> {
+        let mut fields: Vec<Field<'a>> = vec![];
+        let mut start = 0;
+        for (line_start, line) in lines(self.raw) {
+            let content = trim_line_break(line);
+            if content.is_empty() {
+                break;
+            }
+            if content[0] == b' ' || content[0] == b'\t' {
+                // A continuation line extends the previous field.
+                if let Some(field) = fields.last_mut() {
+                    field.raw = &self.raw[start..line_start + line.len()];
+                }
+                continue;
+            }
+            let colon = match content.iter().position(|byte| *byte == b':') {
+                Some(colon) => colon,
+                None => continue,
+            };
+            if let Ok(name) = std::str::from_utf8(&content[..colon]) {
+                start = line_start;
+                fields.push(Field {
+                    name: name.trim_end(),
+                    raw: &self.raw[start..line_start + line.len()],
+                    colon,
+                });
+            }
+        }
+        fields
+    }
+
+    /// Returns the unfolded value of the first field called `name`, ignoring case.
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.fields()
+            .into_iter()
+            .find(|field| field.name.eq_ignore_ascii_case(name))
+            .map(|field| field.value())
+    }
+
+    pub fn get_all(&self, name: &str) -> Vec<String> {
+        self.fields()
+            .into_iter()
+            .filter(|field| field.name.eq_ignore_ascii_case(name))
+            .map(|field| field.value())
+            .collect()
+    }
+}
+
+/// Iterates over the lines of `data` with their offsets. Each line keeps its line break.
+pub(crate) fn lines(data: &[u8]) -> impl Iterator<Item = (usize, &[u8])> {
+    let mut start = 0;
+    std::iter::from_fn(move || {
+        if start >= data.len() {
+            return None;
+        }
+        let end = data[start..]
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map(|newline| start + newline + 1)
+            .unwrap_or(data.len());
+        let line = (start, &data[start..end]);
+        start = end;
+        Some(line)
+    })
+}
+
+pub(crate) fn trim_line_break(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// A header value of the form `value; name=param; ...`, as used by Content-Type and
+/// Content-Disposition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Parameterised {
+    pub value: String,
+    pub parameters: Vec<(String, String)>,
+}
+
+impl Parameterised {
+    pub fn parse(header: &str) -> Self {
+        let mut segments = split_unquoted(header, ';').into_iter();
+        let value = segments
+            .next()
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default();
+        let parameters = segments
+            .filter_map(|segment| {
+                let (name, value) = segment.split_once('=')?;
+                Some((name.trim().to_ascii_lowercase(), unquote(value.trim())))
+            })
+            .collect();
+        Self { value, parameters }
+    }
+
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(parameter, _)| parameter.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A parsed Content-Type, with the type and subtype lower-cased.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType {
+    pub kind: String,
+    pub subtype: String,
+    pub parameters: Vec<(String, String)>,
+}
+
+impl Default for ContentType {
+    /// RFC 2045 section 5.2: `text/plain; charset=us-ascii`.
+    fn default() -> Self {
+        Self {
+            kind: "text".to_string(),
+            subtype: "plain".to_string(),
+            parameters: vec![("charset".to_string(), "us-ascii".to_string())],
+        }
+    }
+}
+
+impl ContentType {
+    pub fn new(kind: &str, subtype: &str) -> Self {
+        Self {
+            kind: kind.to_ascii_lowercase(),
+            subtype: subtype.to_ascii_lowercase(),
+            parameters: vec![],
+        }
+    }
+
+    /// Parses a Content-Type value, returning `None` if it has no `type/subtype`.
+    pub fn parse(header: &str) -> Option<Self> {
+        let parsed = Parameterised::parse(header);
+        let (kind, subtype) = parsed.value.split_once('/')?;
+        let (kind, subtype) = (kind.trim(), subtype.trim());
+        if kind.is_empty() || subtype.is_empty() {
+            return None;
+        }
+        Some(Self {
+            parameters: parsed.parameters,
+            ..Self::new(kind, subtype)
+        })
+    }
+
+    pub fn is(&self, kind: &str, subtype: &str) -> bool {
+        self.kind.eq_ignore_ascii_case(kind) && self.subtype.eq_ignore_ascii_case(subtype)
+    }
+
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(parameter, _)| parameter.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut segments = vec![];
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (position, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            _ if c == separator && !quoted => {
+                segments.push(&value[start..position]);
+                start = position + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    segments.push(&value[start..]);
+    segments
+}
+
+fn unquote(value: &str) -> String {
+    match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        Some(quoted) => {
+            let mut unquoted = String::with_capacity(quoted.len());
+            let mut characters = quoted.chars();
+            while let Some(c) = characters.next() {
+                match c {
+                    '\\' => unquoted.extend(characters.next()),
+                    _ => unquoted.push(c),
+                }
+            }
+            unquoted
+        }
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ContentType, Headers, Parameterised};
+
+    #[test]
+    fn test_fields_keep_their_raw_bytes() {
+        let raw = b"Subject: a long\r\n  subject\r\nFrom: me@example.com\r\n\r\n";
+        let headers = Headers::new(raw);
+        let fields = headers.fields();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0].name, "Subject");
+        assert_eq!(fields[0].raw, b"Subject: a long\r\n  subject\r\n");
+        assert_eq!(fields[0].value(), "a long subject");
+        assert_eq!(headers.get("from").as_deref(), Some("me@example.com"));
+        assert_eq!(headers.get("To"), None);
+    }
+
+    #[test]
+    fn test_content_type() {
+        let content_type =
+            ContentType::parse("Multipart/Mixed; boundary=\"a; \\\"b\\\"\"; charset=utf-8")
+                .unwrap();
+        assert!(content_type.is("multipart", "mixed"));
+        assert_eq!(content_type.parameter("BOUNDARY"), Some("a; \"b\""));
+        assert_eq!(content_type.parameter("charset"), Some("utf-8"));
+        assert_eq!(ContentType::parse("garbage"), None);
+
+        let disposition = Parameterised::parse("attachment; filename=report.pdf");
+        assert_eq!(disposition.value, "attachment");
+        assert_eq!(disposition.parameter("filename"), Some("report.pdf"));
+    }
+}
@@ -0,0 +1,286 @@
+pub mod address;
+pub mod charset;
+pub mod encoding;
+pub mod header;
+
+use std::borrow::Cow;
+
+use self::encoding::{decode_base64, decode_quoted_printable};
+use self::header::{lines, trim_line_break, ContentType, Headers, Parameterised};
+
+/// Multipart nesting deeper than this is treated as an opaque leaf rather than parsed, so a
+/// hostile message cannot exhaust the stack.
+const MAX_DEPTH: usize = 32;
+
+/// A node in the MIME part tree of an RFC 5322 message.
+///
+/// Every slice borrows from the message the tree was parsed from, so attachments are never
+/// copied; `decoded` only does work when a part's content is actually needed.
+#[derive(Debug, Clone)]
+pub struct Part<'a> {
+    raw: &'a [u8],
+    headers: Headers<'a>,
+    body: &'a [u8],
+    pub content_type: ContentType,
+    /// The sub-parts of a `multipart/*` part.
+    pub children: Vec<Part<'a>>,
+    /// The encapsulated message of a `message/rfc822` part.
+    pub message: Option<Box<Part<'a>>>,
+}
+
+impl<'a> Part<'a> {
+    /// Parses a complete message.
+    pub fn parse(raw: &'a [u8]) -> Self {
+        Self::parse_part(raw, ContentType::default(), 0)
+    }
+
+    fn parse_part(raw: &'a [u8], default: ContentType, depth: usize) -> Self {
+        let (header, body) = split_header(raw);
+        let headers = Headers::new(header);
+        let content_type = headers
+            .get("Content-Type")
+            .and_then(|value| ContentType::parse(&value))
+            .unwrap_or(default);
+        let mut part = Part {
+            raw,
+            headers,
+            body,
+            content_type,
+            children: vec![],
+            message: None,
+        };
+        if depth >= MAX_DEPTH {
+            return part;
+        }
+        if part.content_type.kind == "multipart" {
+            if let Some(boundary) = part.content_type.parameter("boundary") {
+                // RFC 2046 section 5.1.5: parts of a digest default to message/rfc822.
+                let default = if part.content_type.subtype == "digest" {
+                    ContentType::new("message", "rfc822")
+                } else {
+                    ContentType::default()
+                };
+                part.children = split_multipart(body, boundary)
+                    .into_iter()
+                    .map(|child| Self::parse_part(child, default.clone(), depth + 1))
+                    .collect();
+            }
+        } else if part.content_type.is("message", "rfc822") {
+            part.message = Some(Box::new(Self::parse_part(
+                body,
+                ContentType::default(),
+                depth + 1,
+            )));
+        }
+        part
+    }
+
+    /// The complete part, headers included.
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    pub fn headers(&self) -> &Headers<'a> {
+        &self.headers
+    }
+
+    /// The undecoded body of the part.
+    pub fn body(&self) -> &'a [u8] {
+        self.body
+    }
+
+    pub fn is_multipart(&self) -> bool {
+        self.content_type.kind == "multipart"
+    }
+
+    /// The lower-cased Content-Transfer-Encoding, defaulting to `7bit`.
+    pub fn encoding(&self) -> String {
+        self.headers
+            .get("Content-Transfer-Encoding")
+            .map(|encoding| encoding.trim().to_ascii_lowercase())
+            .unwrap_or_else(|| "7bit".to_string())
+    }
+
+    /// The body with its Content-Transfer-Encoding removed. Only base64 and quoted-printable
+    /// bodies are copied.
+    pub fn decoded(&self) -> Cow<'a, [u8]> {
+        match self.encoding().as_str() {
+            "base64" => Cow::Owned(decode_base64(self.body)),
+            "quoted-printable" => Cow::Owned(decode_quoted_printable(self.body)),
+            _ => Cow::Borrowed(self.body),
+        }
+    }
+
+    /// The decoded body as text, converted from the charset named in the Content-Type. US-ASCII
+    /// and unsupported charsets are read as UTF-8, since mislabelled 8-bit text is common.
+    pub fn text(&self) -> String {
+        let decoded = self.decoded();
+        self.content_type
+            .parameter("charset")
+            .filter(|name| !name.eq_ignore_ascii_case("us-ascii"))
+            .and_then(|name| charset::decode(name, &decoded))
+            .unwrap_or_else(|| String::from_utf8_lossy(&decoded).into_owned())
+    }
+
+    pub fn disposition(&self) -> Option<Parameterised> {
+        self.headers
+            .get("Content-Disposition")
+            .map(|value| Parameterised::parse(&value))
+    }
+
+    /// The number of lines in the body, as reported in BODYSTRUCTURE for text parts.
+    pub fn lines(&self) -> usize {
+        lines(self.body).count()
+    }
+
+    /// Looks up a part by its IMAP section number, e.g. `[2, 1]` for `BODY[2.1]`. An empty path
+    /// refers to this part.
+    pub fn part(&self, path: &[usize]) -> Option<&Part<'a>> {
+        let (first, rest) = match path.split_first() {
+            Some(split) => split,
+            None => return Some(self),
+        };
+        if self.is_multipart() {
+            return self.children.get(first.checked_sub(1)?)?.part(rest);
+        }
+        if let Some(message) = &self.message {
+            return message.part(path);
+        }
+        // A non-multipart message has a single part, numbered 1.
+        match (first, rest.is_empty()) {
+            (1, true) => Some(self),
+            _ => None,
+        }
+    }
+}
+
+/// Splits a message or part at the blank line ending its header section. The blank line stays
+/// with the headers.
+pub(crate) fn split_header(raw: &[u8]) -> (&[u8], &[u8]) {
+    for (start, line) in lines(raw) {
+        if trim_line_break(line).is_empty() {
+            let end = start + line.len();
+            return (&raw[..end], &raw[end..]);
+        }
+    }
+    (raw, &raw[raw.len()..])
+}
+
+/// Returns the body parts between the `--boundary` delimiter lines of a multipart body. The line
+/// break before each delimiter belongs to the delimiter (RFC 2046 section 5.1.1).
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = vec![];
+    let mut current: Option<usize> = None;
+    for (start, line) in lines(body) {
+        let content = trim_line_break(line);
+        let rest = match content.strip_prefix(delimiter.as_bytes()) {
+            Some(rest) => rest,
+            None => continue,
+        };
+        let closing = rest.starts_with(b"--");
+        let rest = if closing { &rest[2..] } else { rest };
+        if !rest.iter().all(|byte| *byte == b' ' || *byte == b'\t') {
+            continue;
+        }
+        if let Some(part_start) = current.take() {
+            let end = body[..start]
+                .strip_suffix(b"\r\n")
+                .or_else(|| body[..start].strip_suffix(b"\n"))
+                .map(|before| before.len())
+                .unwrap_or(start)
+                .max(part_start);
+            parts.push(&body[part_start..end]);
+        }
+        if closing {
+            return parts;
+        }
+        current = Some(start + line.len());
+    }
+    // Tolerate a missing closing delimiter by ending the last part at the end of the body.
+    if let Some(part_start) = current {
+        parts.push(&body[part_start..]);
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Part;
+
+    const MESSAGE: &[u8] = b"From: me@example.com\r\n\
+Content-Type: multipart/mixed; boundary=outer\r\n\
+\r\n\
+preamble\r\n\
+--outer\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+caf=C3=A9\r\n\
+--outer\r\n\
+Content-Type: message/rfc822\r\n\
+\r\n\
+Subject: forwarded\r\n\
+Content-Type: multipart/alternative; boundary=\"inner\"\r\n\
+\r\n\
+--inner\r\n\
+\r\n\
+plain\r\n\
+--inner\r\n\
+Content-Type: text/html\r\n\
+\r\n\
+<p>html</p>\r\n\
+--inner--\r\n\
+--outer\r\n\
+Content-Type: application/pdf\r\n\
+Content-Transfer-Encoding: base64\r\n\
+Content-Disposition: attachment; filename=\"a.pdf\"\r\n\
+\r\n\
+JVBERi0=\r\n\
+--outer--\r\n\
+epilogue\r\n";
+
+    #[test]
+    fn test_part_tree() {
+        let message = Part::parse(MESSAGE);
+        assert!(message.content_type.is("multipart", "mixed"));
+        assert_eq!(message.children.len(), 3);
+
+        let text = message.part(&[1]).unwrap();
+        assert_eq!(text.body(), b"caf=C3=A9");
+        assert_eq!(text.decoded().as_ref(), "café".as_bytes());
+        assert_eq!(text.content_type.parameter("charset"), Some("utf-8"));
+
+        let forwarded = message.part(&[2]).unwrap();
+        assert!(forwarded.content_type.is("message", "rfc822"));
+        let encapsulated = forwarded.message.as_ref().unwrap();
+        assert_eq!(
+            encapsulated.headers().get("Subject").as_deref(),
+            Some("forwarded")
+        );
+        let plain = message.part(&[2, 1]).unwrap();
+        assert!(plain.content_type.is("text", "plain"));
+        assert_eq!(plain.body(), b"plain");
+        assert_eq!(message.part(&[2, 2]).unwrap().body(), b"<p>html</p>");
+        assert!(message.part(&[2, 3]).is_none());
+
+        let attachment = message.part(&[3]).unwrap();
+        assert_eq!(attachment.decoded().as_ref(), b"%PDF-");
+        let disposition = attachment.disposition().unwrap();
+        assert_eq!(disposition.value, "attachment");
+        assert_eq!(disposition.parameter("filename"), Some("a.pdf"));
+        assert!(message.part(&[0]).is_none());
+        assert!(message.part(&[4]).is_none());
+    }
+
+    #[test]
+    fn test_single_part_message() {
+        let raw = b"Subject: hi\n\nline one\nline two\n";
+        let message = Part::parse(raw);
+        assert!(message.content_type.is("text", "plain"));
+        assert_eq!(message.headers().raw(), b"Subject: hi\n\n");
+        assert_eq!(message.lines(), 2);
+        assert!(std::ptr::eq(message.part(&[1]).unwrap(), &message));
+        assert!(message.part(&[1, 1]).is_none());
+    }
+}
@@ -0,0 +1,526 @@
+//! Publishes changes to mailboxes as JSON events, so downstream automation can react to new
+//! mail without polling IMAP.
+//!
+//! A `Notifier` follows the change journal of every mailbox of every user and hands each
+//! event to its sinks: HTTP webhooks, which are sent a POST per event, and NATS subjects.
+//! Kafka is not spoken directly; a NATS bridge can forward the subject to a topic.
+//!
+//! Events look like
+//!
+//! ```json
+//! {"type":"message.appended","user":"me","mailbox":"INBOX","uid":4,"modseq":12,"at":"2024-05-01T09:30:00Z"}
+//! ```
+//!
+//! with a `type` of `mailbox.created`, `message.appended`, `message.flags` (with the new
+//! `flags`) or `message.expunged` (with the `uids` removed). The journal has no entry for a
+//! new mailbox, so mailboxes are found by listing those of each user every interval, and the
+//! journal of a mailbox created since the notifier started is replayed from its beginning.
+//! Events are delivered at most once: a sink which fails is logged and the event dropped.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use async_std::future::timeout;
+use async_std::io::BufReader;
+use async_std::net::TcpStream;
+use futures::future::{self, Either};
+use futures::stream::{BoxStream, SelectAll};
+use futures::{AsyncBufReadExt, AsyncWriteExt, StreamExt};
+use serde_json::{json, Value};
+use tracing::{debug, warn};
+
+use crate::index::journal::{Change, JournalEntry};
+use crate::index::Owner;
+use crate::server::Components;
+use crate::shutdown::Shutdown;
+use crate::util::{rfc3339, Result};
+
+/// How long a sink has to accept an event.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something which happened to a mailbox.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    MailboxCreated {
+        owner: Owner,
+        mailbox: String,
+        at: SystemTime,
+    },
+    Changed {
+        owner: Owner,
+        mailbox: String,
+        entry: JournalEntry,
+    },
+}
+
+impl Event {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Event::MailboxCreated { owner, mailbox, at } => json!({
+                "type": "mailbox.created",
+                "user": owner.name(),
+                "mailbox": mailbox,
+                "at": rfc3339(*at),
+            }),
+            Event::Changed {
+                owner,
+                mailbox,
+                entry,
+            } => {
+                let mut event = json!({
+                    "user": owner.name(),
+                    "mailbox": mailbox,
+                    "modseq": entry.modseq,
+                    "at": rfc3339(entry.at),
+                });
+                let details = match &entry.change {
+                    Change::Append(uid) => json!({ "type": "message.appended", "uid": uid }),
+                    Change::Flags(uid, flags) => {
+                        json!({ "type": "message.flags", "uid": uid, "flags": flags })
+                    }
+                    Change::Expunge(uids) => json!({ "type": "message.expunged", "uids": uids }),
+                };
+                if let (Some(event), Value::Object(details)) = (event.as_object_mut(), details) {
+                    event.extend(details);
+                }
+                event
+            }
+        }
+    }
+}
+
+/// Somewhere events are sent.
+#[async_trait::async_trait]
+pub trait Publish: Send + Sync {
+    async fn publish(&mut self, event: &Value) -> Result<()>;
+}
+
+/// POSTs each event as `application/json` to a URL, expecting a 2xx status back. Only plain
+/// `http://` URLs are supported.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    address: String,
+    host: String,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("Webhook URL {} is not an http:// URL", url))?;
+        let (host, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(format!("Webhook URL {} has no host", url).into());
+        }
+        let address = match host.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => host.to_string(),
+            _ => format!("{}:80", host),
+        };
+        Ok(Self {
+            address,
+            host: host.to_string(),
+            path: path.to_string(),
+            headers: vec![],
+        })
+    }
+    /// Sends `name: value` with every request, such as a token the receiver checks.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl Publish for Webhook {
+    async fn publish(&mut self, event: &Value) -> Result<()> {
+        let body = event.to_string();
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.path,
+            self.host,
+            body.len()
+        );
+        for (name, value) in &self.headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        request.push_str(&body);
+
+        let mut stream = TcpStream::connect(&self.address).await?;
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status).await?;
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(format!(
+                "Webhook http://{}{} answered {}",
+                self.host,
+                self.path,
+                status.trim_end()
+            )
+            .into()),
+        }
+    }
+}
+
+/// Publishes each event to a NATS subject, `treasurmap.events` unless configured otherwise.
+/// The connection is opened on the first event and reopened after a failure.
+pub struct Nats {
+    address: String,
+    subject: String,
+    connection: Option<BufReader<TcpStream>>,
+}
+
+impl Nats {
+    pub fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            subject: "treasurmap.events".to_string(),
+            connection: None,
+        }
+    }
+    pub fn with_subject(mut self, subject: &str) -> Self {
+        self.subject = subject.to_string();
+        self
+    }
+
+    async fn connect(&self) -> Result<BufReader<TcpStream>> {
+        let mut connection = BufReader::new(TcpStream::connect(&self.address).await?);
+        let mut info = String::new();
+        connection.read_line(&mut info).await?;
+        if !info.starts_with("INFO ") {
+            return Err(format!("NATS server {} did not send INFO", self.address).into());
+        }
+        let connect = json!({ "verbose": false, "pedantic": false, "name": "treasurmap" });
+        let connect = format!("CONNECT {}\r\n", connect);
+        connection.get_mut().write_all(connect.as_bytes()).await?;
+        Ok(connection)
+    }
+}
+
+#[async_trait::async_trait]
+impl Publish for Nats {
+    async fn publish(&mut self, event: &Value) -> Result<()> {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => self.connect().await?,
+        };
+        let payload = event.to_string();
+        // The PING makes the server answer once it has processed the PUB before it.
+        let message = format!(
+            "PUB {} {}\r\n{}\r\nPING\r\n",
+            self.subject,
+            payload.len(),
+            payload
+        );
+        connection.get_mut().write_all(message.as_bytes()).await?;
+        loop {
+            let mut line = String::new();
+            if connection.read_line(&mut line).await? == 0 {
+                return Err(format!("NATS server {} closed the connection", self.address).into());
+            }
+            match line.trim_end() {
+                "PONG" => break,
+                "PING" => connection.get_mut().write_all(b"PONG\r\n").await?,
+                error if error.starts_with("-ERR") => {
+                    return Err(format!("NATS server {} answered {}", self.address, error).into())
+                }
+                _ => {}
+            }
+        }
+        self.connection = Some(connection);
+        Ok(())
+    }
+}
+
+/// Sends the changes to every mailbox to its sinks while the server runs.
+pub struct Notifier {
+    sinks: Vec<Box<dyn Publish>>,
+    interval: Duration,
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Self {
+            sinks: vec![],
+            interval: Duration::from_secs(5),
+        }
+    }
+    pub fn with_sink<P: Publish + 'static>(mut self, sink: P) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+    /// How often users are listed for mailboxes to watch, which bounds how long a new
+    /// mailbox takes to be announced.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Publishes events until `shutdown` is triggered.
+    pub async fn run(mut self, components: Components, shutdown: Shutdown) {
+        // The highest MODSEQ published for each watched mailbox.
+        let mut watched: HashMap<(Owner, String), u64> = HashMap::new();
+        let mut changes: SelectAll<BoxStream<'static, (Owner, String, JournalEntry)>> =
+            SelectAll::new();
+        let mut starting = true;
+        while !shutdown.is_triggered() {
+            for (owner, mailbox) in components.mailboxes().await {
+                let key = (owner.clone(), mailbox.clone());
+                if watched.contains_key(&key) {
+                    continue;
+                }
+                // Mailboxes the data store knows of but the index does not yet are retried
+                // on the next scan.
+                let watch = match components.index.watch(&owner, &mailbox).await {
+                    Ok(watch) => watch,
+                    Err(_) => continue,
+                };
+                let mut published = 0;
+                if !starting {
+                    let created = Event::MailboxCreated {
+                        owner: owner.clone(),
+                        mailbox: mailbox.clone(),
+                        at: SystemTime::now(),
+                    };
+                    self.publish(&created).await;
+                    let history = components.index.changes_since(&owner, &mailbox, 0).await;
+                    for entry in history.unwrap_or_default() {
+                        published = entry.modseq;
+                        let (owner, mailbox) = key.clone();
+                        self.publish(&Event::Changed {
+                            owner,
+                            mailbox,
+                            entry,
+                        })
+                        .await;
+                    }
+                }
+                watched.insert(key.clone(), published);
+                changes.push(
+                    watch
+                        .map(move |entry| (key.0.clone(), key.1.clone(), entry))
+                        .boxed(),
+                );
+            }
+            starting = false;
+
+            let scan = Instant::now() + self.interval;
+            loop {
+                let remaining = scan.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break;
+                }
+                let next = async {
+                    match changes.is_empty() {
+                        true => future::pending().await,
+                        false => changes.next().await,
+                    }
+                };
+                let (owner, mailbox, entry) = match future::select(
+                    Box::pin(shutdown.wait()),
+                    Box::pin(timeout(remaining, next)),
+                )
+                .await
+                {
+                    Either::Left(..) => return,
+                    Either::Right((Ok(Some(change)), _)) => change,
+                    Either::Right((Ok(None) | Err(_), _)) => break,
+                };
+                let key = (owner, mailbox);
+                match watched.get_mut(&key) {
+                    Some(published) if *published >= entry.modseq => continue,
+                    Some(published) => *published = entry.modseq,
+                    None => continue,
+                }
+                let (owner, mailbox) = key;
+                self.publish(&Event::Changed {
+                    owner,
+                    mailbox,
+                    entry,
+                })
+                .await;
+            }
+        }
+    }
+
+    /// Every mailbox of every user, with INBOX whether or not it has been stored to.
+    async fn publish(&mut self, event: &Event) {
+        let event = event.to_json();
+        debug!("Publishing {}", event);
+        for sink in self.sinks.iter_mut() {
+            match timeout(PUBLISH_TIMEOUT, sink.publish(&event)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("Could not publish {}: {}", event, e),
+                Err(_) => warn!("Timed out publishing {}", event),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_std::io::BufReader;
+    use async_std::net::TcpListener;
+    use futures::channel::mpsc::{unbounded, UnboundedSender};
+    use futures::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, StreamExt};
+    use serde_json::{json, Value};
+
+    use super::{Nats, Notifier, Publish, Webhook};
+    use crate::auth::inmemory::{InMemoryAuthenticator, InMemoryUserStore};
+    use crate::auth::{Authenticate, User, UserStore};
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::message::MessageRecord;
+    use crate::index::{Index, Mailbox, Owner, Permission};
+    use crate::server::Components;
+    use crate::shutdown::Shutdown;
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message};
+
+    /// Answers every request with 204 and passes on its body.
+    async fn receive_webhooks(listener: TcpListener, bodies: UnboundedSender<Value>) {
+        while let Some(Ok(stream)) = listener.incoming().next().await {
+            let mut reader = BufReader::new(stream.clone());
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                if line.trim_end().is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("Content-Length: ") {
+                    length = value.trim_end().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).await.unwrap();
+            let mut stream = stream;
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            bodies
+                .unbounded_send(serde_json::from_slice(&body).unwrap())
+                .unwrap();
+        }
+    }
+
+    #[async_std::test]
+    async fn test_webhook() {
+        let users = InMemoryUserStore::new();
+        users.add(User::new("me", "password")).await.unwrap();
+        let user_store: Arc<Box<dyn UserStore>> = Arc::new(Box::new(users));
+        let components = Components {
+            index: Arc::new(Box::new(InMemoryIndex::new()) as Box<dyn Index>),
+            data_store: Arc::new(Box::new(InMemoryStore::new()) as Box<dyn DataStore>),
+            user_store: user_store.clone(),
+            authenticator: Arc::new(
+                Box::new(InMemoryAuthenticator::new(user_store)) as Box<dyn Authenticate>
+            ),
+        };
+        let me = Owner::new("me");
+        let (index, store) = (&components.index, &components.data_store);
+        let uid = store
+            .append("me", "INBOX", Message::new(b"Hi"))
+            .await
+            .unwrap();
+        let record = MessageRecord::new(uid, 2, std::time::SystemTime::now());
+        index.add_message(&me, "INBOX", record).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hooks/mail", listener.local_addr().unwrap());
+        let (bodies, mut events) = unbounded();
+        async_std::task::spawn(receive_webhooks(listener, bodies));
+        let notifier = Notifier::new()
+            .with_sink(Webhook::new(&url).unwrap().with_header("X-Token", "secret"))
+            .with_interval(Duration::from_millis(50));
+        let shutdown = Shutdown::new();
+        let running = async_std::task::spawn(notifier.run(components.clone(), shutdown.clone()));
+        // Let the first scan watch INBOX, so changes to it are not mistaken for history.
+        async_std::task::sleep(Duration::from_millis(100)).await;
+
+        let flags = vec!["\\Seen".to_string()];
+        index.set_flags(&me, "INBOX", uid, flags).await.unwrap();
+        let event = events.next().await.unwrap();
+        assert_eq!(event["type"], "message.flags");
+        assert_eq!(event["user"], "me");
+        assert_eq!(event["mailbox"], "INBOX");
+        assert_eq!(event["uid"], json!(uid));
+        assert_eq!(event["flags"], json!(["\\Seen"]));
+
+        let work = Mailbox::new("Work", 0, vec![], Permission::ReadWrite);
+        index.add_mailbox(&me, work).await.unwrap();
+        let uid = store
+            .append("me", "Work", Message::new(b"Hi"))
+            .await
+            .unwrap();
+        let record = MessageRecord::new(uid, 2, std::time::SystemTime::now());
+        index.add_message(&me, "Work", record).await.unwrap();
+        let event = events.next().await.unwrap();
+        assert_eq!(event["type"], "mailbox.created");
+        assert_eq!(event["mailbox"], "Work");
+        let event = events.next().await.unwrap();
+        assert_eq!(event["type"], "message.appended");
+        assert_eq!(event["mailbox"], "Work");
+        assert_eq!(event["uid"], json!(uid));
+        assert!(event["modseq"].as_u64().unwrap() > 0);
+
+        index.remove_messages(&me, "Work", &[uid]).await.unwrap();
+        let event = events.next().await.unwrap();
+        assert_eq!(event["type"], "message.expunged");
+        assert_eq!(event["uids"], json!([uid]));
+
+        shutdown.trigger();
+        running.await;
+    }
+
+    #[async_std::test]
+    async fn test_nats() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = async_std::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream.clone());
+            let mut stream = stream;
+            stream.write_all(b"INFO {}\r\n").await.unwrap();
+            let mut received = vec![];
+            let mut line = String::new();
+            while reader.read_line(&mut line).await.unwrap() > 0 {
+                if line == "PING\r\n" {
+                    stream.write_all(b"PONG\r\n").await.unwrap();
+                }
+                received.push(std::mem::take(&mut line));
+                if received.len() == 7 {
+                    break;
+                }
+            }
+            received
+        });
+
+        let mut nats = Nats::new(&address).with_subject("mail.events");
+        nats.publish(&json!({ "type": "a" })).await.unwrap();
+        nats.publish(&json!({ "type": "b" })).await.unwrap();
+        let received = server.await;
+        assert!(received[0].starts_with("CONNECT {"));
+        assert_eq!(received[1], "PUB mail.events 12\r\n");
+        assert_eq!(received[2], "{\"type\":\"a\"}\r\n");
+        assert_eq!(received[3], "PING\r\n");
+        assert_eq!(received[4], "PUB mail.events 12\r\n");
+        assert_eq!(received[6], "PING\r\n");
+    }
+}
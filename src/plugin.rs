@@ -0,0 +1,256 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::Display;
+use std::sync::Arc;
+
+use async_lock::{Mutex, RwLock};
+
+use crate::handlers::{DelegatingCommandHandler, HandleCommand};
+use crate::util::Result;
+
+/// An IMAP extension shipped outside this crate: the commands it adds, the capabilities it
+/// advertises for them, and optionally a section of the server configuration.
+pub trait Plugin: Send + Sync {
+    /// The name of the plugin, which is also that of its configuration section.
+    fn name(&self) -> &str;
+    /// The capabilities to advertise while the plugin is registered, such as `ID`.
+    fn capabilities(&self) -> Vec<String> {
+        vec![]
+    }
+    /// Applies the plugin's section of the configuration, when there is one. Called before
+    /// the plugin is registered.
+    fn configure(&mut self, _section: &serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+    /// The handlers of the commands the plugin adds.
+    fn commands(&self) -> Vec<Box<dyn HandleCommand + Send + Sync>>;
+}
+
+/// The capabilities a server advertises in answer to CAPABILITY.
+#[derive(Clone, Default)]
+pub struct Capabilities {
+    capabilities: Arc<RwLock<Vec<String>>>,
+}
+
+impl Capabilities {
+    pub fn new(capabilities: Vec<&str>) -> Self {
+        Self {
+            capabilities: Arc::new(RwLock::new(
+                capabilities.into_iter().map(str::to_string).collect(),
+            )),
+        }
+    }
+    /// Advertises `capability`, unless it already is.
+    pub async fn add(&self, capability: &str) {
+        let mut capabilities = self.capabilities.write().await;
+        if !capabilities
+            .iter()
+            .any(|known| known.eq_ignore_ascii_case(capability))
+        {
+            capabilities.push(capability.to_string());
+        }
+    }
+    pub async fn list(&self) -> Vec<String> {
+        self.capabilities.read().await.clone()
+    }
+}
+
+#[derive(Debug)]
+pub enum PluginError {
+    /// The command is already handled by the server or by another plugin.
+    CommandTaken(String, String),
+    Configuration(String, String),
+}
+impl Error for PluginError {}
+impl Display for PluginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PluginError::CommandTaken(plugin, command) => {
+                write!(
+                    f,
+                    "Plugin {} adds {}, which is already handled",
+                    plugin, command
+                )
+            }
+            PluginError::Configuration(plugin, reason) => {
+                write!(f, "Plugin {} could not be configured: {}", plugin, reason)
+            }
+        }
+    }
+}
+
+/// The plugins of a server, through which more can be registered while it runs. Commands
+/// with no handler of their own are passed to those of the plugins.
+#[derive(Clone)]
+pub struct Extensions {
+    commands: DelegatingCommandHandler,
+    capabilities: Capabilities,
+    /// The commands handled by the server itself, which plugins may not take over.
+    reserved: Arc<HashSet<String>>,
+    registering: Arc<Mutex<()>>,
+}
+
+impl Extensions {
+    pub(crate) fn new(
+        commands: DelegatingCommandHandler,
+        capabilities: Capabilities,
+        reserved: HashSet<String>,
+    ) -> Self {
+        Self {
+            commands,
+            capabilities,
+            reserved: Arc::new(reserved),
+            registering: Arc::new(Mutex::new(())),
+        }
+    }
+    /// Adds the commands and capabilities of `plugin`. Nothing is added if any of its
+    /// commands is already handled.
+    pub async fn register<P: Plugin + 'static>(
+        &self,
+        plugin: P,
+    ) -> std::result::Result<(), PluginError> {
+        self.register_boxed(Box::new(plugin)).await
+    }
+    pub(crate) async fn register_boxed(
+        &self,
+        plugin: Box<dyn Plugin>,
+    ) -> std::result::Result<(), PluginError> {
+        // Registrations are made one at a time, so that two plugins cannot both claim a
+        // command.
+        let _registering = self.registering.lock().await;
+        let handled = self.commands.commands().await;
+        let commands = plugin.commands();
+        for command in &commands {
+            let name = command.name().to_uppercase();
+            if self.reserved.contains(&name) || handled.contains(&name) {
+                return Err(PluginError::CommandTaken(plugin.name().to_string(), name));
+            }
+        }
+        for command in commands {
+            self.commands.register_boxed(command).await;
+        }
+        for capability in plugin.capabilities() {
+            self.capabilities.add(&capability).await;
+        }
+        Ok(())
+    }
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities.clone()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use async_std::io::BufReader;
+    use async_std::os::unix::net::UnixStream;
+    use futures::{AsyncBufReadExt, AsyncWriteExt, StreamExt};
+
+    use super::{Plugin, PluginError};
+    use crate::connection::Context;
+    use crate::handlers::HandleCommand;
+    use crate::server::{Command, Configuration, Response, ResponseStatus, Server, ServerBuilder};
+    use crate::util::Result;
+
+    /// Answers a command with a greeting from its configuration.
+    #[derive(Clone)]
+    struct Greeter {
+        command: &'static str,
+        greeting: String,
+    }
+
+    #[async_trait::async_trait]
+    impl HandleCommand for Greeter {
+        fn name<'a>(&self) -> &'a str {
+            self.command
+        }
+        async fn validate<'a>(&self, _: &'a Command) -> Result<()> {
+            Ok(())
+        }
+        async fn handle<'a>(&self, command: &'a Command, _: &'a Context) -> Result<Vec<Response>> {
+            let tag = command.tag();
+            Ok(vec![Response::new(
+                &tag,
+                ResponseStatus::OK,
+                &self.greeting,
+            )])
+        }
+    }
+
+    impl Plugin for Greeter {
+        fn name(&self) -> &str {
+            "greeter"
+        }
+        fn capabilities(&self) -> Vec<String> {
+            vec![format!("X-{}", self.command)]
+        }
+        fn configure(&mut self, section: &serde_json::Value) -> Result<()> {
+            let greeting = section["greeting"].as_str().ok_or("greeting is missing")?;
+            self.greeting = greeting.to_string();
+            Ok(())
+        }
+        fn commands(&self) -> Vec<Box<dyn HandleCommand + Send + Sync>> {
+            vec![Box::new(self.clone())]
+        }
+    }
+
+    fn greeter(command: &'static str) -> Greeter {
+        Greeter {
+            command,
+            greeting: "Hello".to_string(),
+        }
+    }
+
+    /// Sends `commands` over a connection to `server`, returning what it answers after the
+    /// greeting.
+    async fn exchange(server: &Server, commands: &str) -> Vec<String> {
+        let (mut client, theirs) = UnixStream::pair().unwrap();
+        let mut lines = BufReader::new(client.clone()).lines();
+        let served = server.serve_stream(theirs);
+        let client = async move {
+            lines.next().await.unwrap().unwrap();
+            client.write_all(commands.as_bytes()).await.unwrap();
+            client.write_all(b"z LOGOUT\r\n").await.unwrap();
+            let mut received = vec![];
+            while let Some(line) = lines.next().await {
+                received.push(line.unwrap());
+            }
+            received
+        };
+        let (served, received) = futures::join!(served, client);
+        served.unwrap();
+        received
+    }
+
+    #[async_std::test]
+    async fn test_plugins() {
+        let configuration = Configuration::default()
+            .with_listeners(vec![])
+            .with_section("greeter", serde_json::json!({ "greeting": "Configured" }));
+        let server = ServerBuilder::new()
+            .with_configuration(configuration)
+            .with_plugin(greeter("HELLO"))
+            .bind()
+            .await
+            .unwrap();
+        let received = exchange(&server, "a1 CAPABILITY\r\na2 HELLO\r\n").await;
+        assert_eq!(received[0], "* CAPABILITY IMAP4rev2 ID X-HELLO");
+        assert_eq!(received[2], "a2 OK Configured");
+
+        // Plugins registered while the server runs are used by the next commands.
+        let extensions = server.extensions();
+        extensions.register(greeter("HOWDY")).await.unwrap();
+        let taken = extensions.register(greeter("LOGIN")).await;
+        assert!(matches!(taken, Err(PluginError::CommandTaken(_, command)) if command == "LOGIN"));
+        let received = exchange(&server, "a1 CAPABILITY\r\na2 HOWDY\r\n").await;
+        assert_eq!(received[0], "* CAPABILITY IMAP4rev2 ID X-HELLO X-HOWDY");
+        assert_eq!(received[2], "a2 OK Hello");
+
+        let clash = ServerBuilder::new()
+            .with_configuration(Configuration::default().with_listeners(vec![]))
+            .with_plugin(greeter("HELLO"))
+            .with_plugin(greeter("HELLO"))
+            .bind()
+            .await;
+        assert!(clash.is_err());
+    }
+}
@@ -0,0 +1,670 @@
+//! A POP3 frontend (RFC 1939) to the INBOX of each user, served from the same data store,
+//! index and authenticator as IMAP, for clients and devices which only speak POP3.
+//!
+//!  S: +OK POP3 server ready
+//!  C: USER me@example.com
+//!  S: +OK
+//!  C: PASS secret
+//!  S: +OK 2 messages
+//!  C: RETR 1
+//!  S: +OK 120 octets
+//!  S: <the message, dot-stuffed>
+//!  S: .
+//!  C: DELE 1
+//!  S: +OK message 1 deleted
+//!  C: QUIT
+//!  S: +OK 1 messages removed
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use async_std::future::timeout;
+use async_std::io::BufReader;
+use async_std::net::TcpListener;
+use async_std::task::sleep;
+use futures::future::{self, Either};
+use futures::io::WriteHalf;
+use futures::{AsyncReadExt, AsyncWriteExt, StreamExt};
+use log::{debug, info, warn};
+
+use crate::auth::BasicAuth;
+use crate::connection::{read_line, Line};
+use crate::index::{MailboxError, Owner, Permission};
+use crate::listener::Io;
+#[cfg(feature = "tls")]
+use crate::listener::TlsAcceptor;
+use crate::runtime::spawn;
+use crate::server::Components;
+use crate::shutdown::Shutdown;
+use crate::util::Result;
+
+const INBOX: &str = "INBOX";
+
+/// The longest command accepted. RFC 2449 allows 255 octets.
+const MAX_LINE_LENGTH: usize = 512;
+
+/// An address POP3 clients connect to.
+#[derive(Clone)]
+pub struct Pop3Listener {
+    address: String,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsAcceptor>,
+    idle: Duration,
+    require_tls: bool,
+}
+
+impl Pop3Listener {
+    pub fn tcp(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            // RFC 1939 section 3 asks for at least ten minutes.
+            idle: Duration::from_secs(10 * 60),
+            require_tls: false,
+        }
+    }
+    /// Negotiates TLS as soon as a client connects, e.g. on port 995.
+    #[cfg(feature = "tls")]
+    pub fn with_implicit_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+    /// How long a client may stay silent before it is disconnected.
+    pub fn with_idle_timeout(mut self, idle: Duration) -> Self {
+        self.idle = idle;
+        self
+    }
+    /// Refuses USER and PASS on connections which are not encrypted, as set by
+    /// `ServerBuilder::with_require_tls`.
+    pub(crate) fn with_require_tls(mut self, require_tls: bool) -> Self {
+        self.require_tls = require_tls;
+        self
+    }
+    pub(crate) async fn bind(self) -> std::io::Result<BoundPop3> {
+        let socket = TcpListener::bind(&self.address).await?;
+        Ok(BoundPop3 {
+            listener: self,
+            socket,
+        })
+    }
+}
+
+/// A POP3 listener whose socket has been bound.
+pub(crate) struct BoundPop3 {
+    listener: Pop3Listener,
+    socket: TcpListener,
+}
+
+impl BoundPop3 {
+    pub(crate) fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Accepts clients until `shutdown` is triggered, serving each on its own task.
+    pub(crate) async fn serve(self, components: Components, shutdown: Shutdown) {
+        info!("POP3 server started listening on {}", self.listener.address);
+        let mut incoming = self.socket.incoming();
+        loop {
+            let stream = match future::select(incoming.next(), Box::pin(shutdown.wait())).await {
+                Either::Left((Some(Ok(stream)), _)) => stream,
+                Either::Left((Some(Err(e)), _)) => {
+                    warn!("Could not accept a POP3 connection: {}", e);
+                    continue;
+                }
+                Either::Left((None, _)) | Either::Right(..) => break,
+            };
+            let listener = self.listener.clone();
+            let components = components.clone();
+            spawn(async move {
+                let peer = stream.peer_addr().ok().map(|peer| peer.ip());
+                #[cfg(feature = "tls")]
+                if let Some(acceptor) = &listener.tls {
+                    let stream = acceptor.accept(stream).await?;
+                    return session(Box::new(stream), &components, &listener, peer, true).await;
+                }
+                session(Box::new(stream), &components, &listener, peer, false).await
+            });
+        }
+        info!("POP3 server stopped listening on {}", self.listener.address);
+    }
+}
+
+/// A message of the maildrop, numbered by its position from 1.
+struct Entry {
+    uid: u32,
+    size: u64,
+    deleted: bool,
+}
+
+/// The INBOX of a user as it was when they logged in, as POP3 numbers messages for the whole
+/// session.
+struct Maildrop {
+    owner: Owner,
+    uid_validity: u32,
+    messages: Vec<Entry>,
+}
+
+impl Maildrop {
+    async fn open(owner: Owner, components: &Components) -> Result<Self> {
+        let index = &components.index;
+        let (uid_validity, records) = match index
+            .get_mailbox(&owner, INBOX, Permission::ReadWrite)
+            .await
+        {
+            Ok(mailbox) => (
+                mailbox.uid_validity,
+                index.list_messages(&owner, INBOX).await?,
+            ),
+            // A user who has never received mail has an empty maildrop.
+            Err(MailboxError::DoesNotExist(..)) => (0, vec![]),
+            Err(e) => return Err(Box::new(e)),
+        };
+        let mut messages: Vec<Entry> = records
+            .into_iter()
+            .map(|record| Entry {
+                uid: record.uid,
+                size: record.size,
+                deleted: false,
+            })
+            .collect();
+        messages.sort_by_key(|entry| entry.uid);
+        Ok(Self {
+            owner,
+            uid_validity,
+            messages,
+        })
+    }
+    /// The message numbered `number` by the client, unless it is deleted.
+    fn get(&self, number: &str) -> Option<(usize, &Entry)> {
+        let number: usize = number.parse().ok()?;
+        let entry = self.messages.get(number.checked_sub(1)?)?;
+        (!entry.deleted).then_some((number, entry))
+    }
+    fn live(&self) -> impl Iterator<Item = (usize, &Entry)> {
+        self.messages
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| !entry.deleted)
+            .map(|(position, entry)| (position + 1, entry))
+    }
+    fn unique_id(&self, entry: &Entry) -> String {
+        format!("{}.{}", self.uid_validity, entry.uid)
+    }
+    /// Removes the messages deleted in the session, returning how many were.
+    async fn update(&self, components: &Components) -> Result<usize> {
+        let deleted: Vec<u32> = self
+            .messages
+            .iter()
+            .filter(|entry| entry.deleted)
+            .map(|entry| entry.uid)
+            .collect();
+        if deleted.is_empty() {
+            return Ok(0);
+        }
+        let removed = components
+            .data_store
+            .expunge(self.owner.name(), INBOX, &deleted)
+            .await?;
+        components
+            .index
+            .remove_messages(&self.owner, INBOX, &removed)
+            .await?;
+        Ok(removed.len())
+    }
+}
+
+enum State {
+    Authorization { user: Option<String> },
+    Transaction(Maildrop),
+}
+
+/// What to send in answer to a command.
+enum Reply {
+    Ok(String),
+    Err(String),
+    /// A positive answer followed by a multi-line body.
+    Lines(String, Vec<Vec<u8>>),
+}
+
+fn ok(message: &str) -> Reply {
+    Reply::Ok(message.to_string())
+}
+
+fn err(message: &str) -> Reply {
+    Reply::Err(message.to_string())
+}
+
+const CAPABILITIES: &[&str] = &["USER", "TOP", "UIDL", "RESP-CODES", "AUTH-RESP-CODE"];
+
+/// Serves one POP3 client at `peer` until it quits or goes quiet for the idle timeout of
+/// `listener`. Logins are throttled and checked against the access policy like IMAP ones.
+async fn session(
+    stream: Box<dyn Io>,
+    components: &Components,
+    listener: &Pop3Listener,
+    peer: Option<IpAddr>,
+    secure: bool,
+) -> Result<()> {
+    let idle = listener.idle;
+    let disabled = listener.require_tls && !secure;
+    let (input, mut output) = stream.split();
+    let mut input = BufReader::new(input);
+    send(&mut output, ok("POP3 server ready")).await?;
+    let mut state = State::Authorization { user: None };
+    let mut line = vec![];
+    loop {
+        line.clear();
+        let read = match timeout(idle, read_line(&mut input, &mut line, MAX_LINE_LENGTH)).await {
+            Ok(read) => read?,
+            Err(..) => {
+                debug!("Closing an idle POP3 connection");
+                return Ok(());
+            }
+        };
+        let command = match read {
+            Line::End => return Ok(()),
+            Line::TooLong => {
+                send(&mut output, err("Line too long")).await?;
+                continue;
+            }
+            Line::Complete => String::from_utf8_lossy(&line).trim_end().to_string(),
+        };
+        let mut words = command.splitn(2, ' ');
+        let keyword = words.next().unwrap_or_default().to_uppercase();
+        let argument = words.next().unwrap_or_default();
+        let args: Vec<&str> = argument.split(' ').filter(|arg| !arg.is_empty()).collect();
+        if keyword == "QUIT" {
+            // Deleted messages are only removed when a session in the TRANSACTION state
+            // quits, per RFC 1939 section 6.
+            let reply = match &state {
+                State::Transaction(maildrop) => match maildrop.update(components).await {
+                    Ok(removed) => Reply::Ok(format!("{} messages removed", removed)),
+                    Err(e) => {
+                        warn!("Could not remove deleted POP3 messages: {}", e);
+                        err("Some deleted messages were not removed")
+                    }
+                },
+                State::Authorization { .. } => ok("Goodbye"),
+            };
+            send(&mut output, reply).await?;
+            return Ok(());
+        }
+        if keyword == "CAPA" {
+            // USER is only advertised while it may be used, per RFC 2449 section 6.4.
+            let capabilities = CAPABILITIES
+                .iter()
+                .filter(|capability| !(disabled && **capability == "USER"))
+                .map(|capability| capability.as_bytes().to_vec());
+            send(
+                &mut output,
+                Reply::Lines(
+                    "Capability list follows".to_string(),
+                    capabilities.collect(),
+                ),
+            )
+            .await?;
+            continue;
+        }
+        let refused = disabled && matches!(keyword.as_str(), "USER" | "PASS");
+        let reply = match &mut state {
+            State::Authorization { .. } if refused => {
+                err("Logging in needs an encrypted connection")
+            }
+            State::Authorization { user } => match (keyword.as_str(), user.as_ref()) {
+                ("USER", _) if !argument.is_empty() => {
+                    *user = Some(argument.to_string());
+                    ok("")
+                }
+                ("PASS", Some(name)) => {
+                    let principal = BasicAuth::from(name, argument);
+                    match components
+                        .authenticator
+                        .login(Box::new(principal), peer)
+                        .await
+                    {
+                        Ok(authenticated) => {
+                            let maildrop =
+                                Maildrop::open(Owner::from(&authenticated), components).await?;
+                            let reply = Reply::Ok(format!("{} messages", maildrop.live().count()));
+                            state = State::Transaction(maildrop);
+                            reply
+                        }
+                        Err(delay) => {
+                            sleep(delay).await;
+                            *user = None;
+                            err("[AUTH] Invalid username or password")
+                        }
+                    }
+                }
+                ("PASS", None) => err("USER first"),
+                _ => err("Not allowed before logging in"),
+            },
+            State::Transaction(maildrop) => {
+                transaction(maildrop, &keyword, &args, components).await?
+            }
+        };
+        send(&mut output, reply).await?;
+    }
+}
+
+async fn transaction(
+    maildrop: &mut Maildrop,
+    keyword: &str,
+    args: &[&str],
+    components: &Components,
+) -> Result<Reply> {
+    const NO_SUCH_MESSAGE: &str = "No such message";
+    Ok(match (keyword, args) {
+        ("STAT", []) => {
+            let size: u64 = maildrop.live().map(|(_, entry)| entry.size).sum();
+            Reply::Ok(format!("{} {}", maildrop.live().count(), size))
+        }
+        ("LIST", []) => {
+            let lines = maildrop
+                .live()
+                .map(|(number, entry)| format!("{} {}", number, entry.size).into_bytes());
+            Reply::Lines(
+                format!("{} messages", maildrop.live().count()),
+                lines.collect(),
+            )
+        }
+        ("LIST", [number]) => match maildrop.get(number) {
+            Some((number, entry)) => Reply::Ok(format!("{} {}", number, entry.size)),
+            None => err(NO_SUCH_MESSAGE),
+        },
+        ("UIDL", []) => {
+            let lines = maildrop.live().map(|(number, entry)| {
+                format!("{} {}", number, maildrop.unique_id(entry)).into_bytes()
+            });
+            Reply::Lines(String::new(), lines.collect())
+        }
+        ("UIDL", [number]) => match maildrop.get(number) {
+            Some((number, entry)) => Reply::Ok(format!("{} {}", number, maildrop.unique_id(entry))),
+            None => err(NO_SUCH_MESSAGE),
+        },
+        ("RETR", [number]) => match maildrop.get(number) {
+            Some((_, entry)) => {
+                let body = components
+                    .data_store
+                    .fetch(maildrop.owner.name(), INBOX, entry.uid)
+                    .await?;
+                Reply::Lines(format!("{} octets", entry.size), lines(&body, None))
+            }
+            None => err(NO_SUCH_MESSAGE),
+        },
+        ("TOP", [number, count]) => match (maildrop.get(number), count.parse()) {
+            (Some((_, entry)), Ok(count)) => {
+                let body = components
+                    .data_store
+                    .fetch(maildrop.owner.name(), INBOX, entry.uid)
+                    .await?;
+                Reply::Lines(String::new(), lines(&body, Some(count)))
+            }
+            (None, _) => err(NO_SUCH_MESSAGE),
+            (_, Err(..)) => err("Invalid number of lines"),
+        },
+        ("DELE", [number]) => match maildrop.get(number).map(|(number, _)| number) {
+            Some(number) => {
+                maildrop.messages[number - 1].deleted = true;
+                Reply::Ok(format!("message {} deleted", number))
+            }
+            None => err(NO_SUCH_MESSAGE),
+        },
+        ("RSET", []) => {
+            maildrop
+                .messages
+                .iter_mut()
+                .for_each(|entry| entry.deleted = false);
+            Reply::Ok(format!("{} messages", maildrop.messages.len()))
+        }
+        ("NOOP", []) => ok(""),
+        ("STAT" | "LIST" | "UIDL" | "RETR" | "TOP" | "DELE" | "RSET" | "NOOP", _) => {
+            err("Invalid arguments")
+        }
+        _ => err("Unknown command"),
+    })
+}
+
+/// The lines of `message`, or of its header and the first `body_lines` lines of its body.
+fn lines(message: &[u8], body_lines: Option<usize>) -> Vec<Vec<u8>> {
+    let mut lines = vec![];
+    let mut in_body = false;
+    let mut remaining = body_lines.unwrap_or(usize::MAX);
+    let message = message.strip_suffix(b"\n").unwrap_or(message);
+    for line in message.split(|byte| *byte == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if in_body {
+            if remaining == 0 {
+                break;
+            }
+            remaining -= 1;
+        }
+        in_body |= line.is_empty();
+        lines.push(line.to_vec());
+    }
+    lines
+}
+
+async fn send(output: &mut WriteHalf<Box<dyn Io>>, reply: Reply) -> std::io::Result<()> {
+    let mut buffer = vec![];
+    let status = |buffer: &mut Vec<u8>, status: &str, message: &str| {
+        buffer.extend_from_slice(status.as_bytes());
+        if !message.is_empty() {
+            buffer.push(b' ');
+            buffer.extend_from_slice(message.as_bytes());
+        }
+        buffer.extend_from_slice(b"\r\n");
+    };
+    match reply {
+        Reply::Ok(message) => status(&mut buffer, "+OK", &message),
+        Reply::Err(message) => status(&mut buffer, "-ERR", &message),
+        Reply::Lines(message, lines) => {
+            status(&mut buffer, "+OK", &message);
+            for line in lines {
+                // Lines starting with the terminator are byte-stuffed, per RFC 1939 section 3.
+                if line.starts_with(b".") {
+                    buffer.push(b'.');
+                }
+                buffer.extend_from_slice(&line);
+                buffer.extend_from_slice(b"\r\n");
+            }
+            buffer.extend_from_slice(b".\r\n");
+        }
+    }
+    output.write_all(&buffer).await?;
+    output.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use async_std::io::BufReader;
+    use async_std::net::TcpStream;
+    use futures::io::Lines;
+    use futures::{AsyncBufReadExt, AsyncWriteExt, StreamExt};
+
+    use super::Pop3Listener;
+    use crate::auth::inmemory::InMemoryUserStore;
+    use crate::auth::throttle::Throttle;
+    use crate::auth::{User, UserStore};
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::message::MessageRecord;
+    use crate::index::{Index, Mailbox, Owner, Permission};
+    use crate::server::{Configuration, ServerBuilder};
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message};
+
+    /// Sends `command` and returns the next `count` lines received.
+    async fn exchange(
+        client: &TcpStream,
+        lines: &mut Lines<BufReader<TcpStream>>,
+        command: &str,
+        count: usize,
+    ) -> Vec<String> {
+        let mut client = client.clone();
+        client.write_all(command.as_bytes()).await.unwrap();
+        let mut received = vec![];
+        for _ in 0..count {
+            received.push(lines.next().await.unwrap().unwrap());
+        }
+        received
+    }
+
+    const FIRST: &[u8] = b"Subject: one\r\n\r\nfirst\r\n.dotted\r\nlast\r\n";
+    const SECOND: &[u8] = b"Subject: two\r\n\r\nsecond\r\n";
+
+    #[async_std::test]
+    async fn test_pop3() {
+        let users = InMemoryUserStore::new();
+        users.add(User::new("me", "password")).await.unwrap();
+        let store = InMemoryStore::new();
+        let index = InMemoryIndex::new();
+        let me = Owner::new("me");
+        let inbox = Mailbox::new("INBOX", 0, vec![], Permission::ReadWrite);
+        index.add_mailbox(&me, inbox).await.unwrap();
+        for body in [FIRST, SECOND] {
+            let uid = store
+                .append("me", "INBOX", Message::new(body))
+                .await
+                .unwrap();
+            let record = MessageRecord::new(uid, body.len() as u64, SystemTime::now());
+            index.add_message(&me, "INBOX", record).await.unwrap();
+        }
+        let server = ServerBuilder::new()
+            .with_configuration(Configuration::default().with_listeners(vec![]))
+            .with_user_store(users)
+            .with_data_store(store)
+            .with_index(index)
+            .with_pop3_listener(Pop3Listener::tcp("127.0.0.1:0"))
+            .bind()
+            .await
+            .unwrap();
+        let address = server.pop3_addresses()[0];
+        let shutdown = server.shutdown();
+        let running = async_std::task::spawn(server.listen());
+
+        let client = TcpStream::connect(address).await.unwrap();
+        let mut lines = BufReader::new(client.clone()).lines();
+        assert_eq!(
+            exchange(&client, &mut lines, "", 1).await,
+            ["+OK POP3 server ready"]
+        );
+        assert_eq!(
+            exchange(&client, &mut lines, "STAT\r\n", 1).await,
+            ["-ERR Not allowed before logging in"]
+        );
+        assert_eq!(
+            exchange(&client, &mut lines, "USER me\r\nPASS wrong\r\n", 2).await[1],
+            "-ERR [AUTH] Invalid username or password"
+        );
+        assert_eq!(
+            exchange(&client, &mut lines, "USER me\r\nPASS password\r\n", 2).await,
+            ["+OK", "+OK 2 messages"]
+        );
+        assert_eq!(
+            exchange(&client, &mut lines, "STAT\r\n", 1).await,
+            [format!("+OK 2 {}", FIRST.len() + SECOND.len())]
+        );
+        let listing = exchange(&client, &mut lines, "LIST\r\n", 4).await;
+        assert_eq!(listing[1], format!("1 {}", FIRST.len()));
+        assert_eq!(listing[3], ".");
+        let unique_id = exchange(&client, &mut lines, "UIDL 2\r\n", 1).await;
+        assert!(unique_id[0].starts_with("+OK 2 ") && unique_id[0].ends_with(".2"));
+        assert_eq!(
+            exchange(&client, &mut lines, "RETR 1\r\n", 7).await,
+            [
+                &format!("+OK {} octets", FIRST.len()),
+                "Subject: one",
+                "",
+                "first",
+                "..dotted",
+                "last",
+                "."
+            ]
+        );
+        assert_eq!(
+            exchange(&client, &mut lines, "TOP 1 1\r\n", 5).await[1..],
+            ["Subject: one", "", "first", "."]
+        );
+        assert_eq!(
+            exchange(&client, &mut lines, "DELE 1\r\n", 1).await,
+            ["+OK message 1 deleted"]
+        );
+        assert_eq!(
+            exchange(&client, &mut lines, "RETR 1\r\n", 1).await,
+            ["-ERR No such message"]
+        );
+        assert_eq!(
+            exchange(&client, &mut lines, "QUIT\r\n", 1).await,
+            ["+OK 1 messages removed"]
+        );
+        assert!(lines.next().await.is_none());
+        shutdown.trigger();
+        running.await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_pop3_login_policy() {
+        let users = InMemoryUserStore::new();
+        users.add(User::new("me", "password")).await.unwrap();
+        let throttle = Throttle::new()
+            .with_thresholds(2, 10)
+            .with_delay(Duration::ZERO, Duration::ZERO);
+        let server = ServerBuilder::new()
+            .with_configuration(Configuration::default().with_listeners(vec![]))
+            .with_user_store(users)
+            .with_throttle(throttle)
+            .with_pop3_listener(Pop3Listener::tcp("127.0.0.1:0"))
+            .bind()
+            .await
+            .unwrap();
+        let address = server.pop3_addresses()[0];
+        let shutdown = server.shutdown();
+        let running = async_std::task::spawn(server.listen());
+
+        let client = TcpStream::connect(address).await.unwrap();
+        let mut lines = BufReader::new(client.clone()).lines();
+        exchange(&client, &mut lines, "", 1).await;
+        for _ in 0..2 {
+            exchange(&client, &mut lines, "USER me\r\nPASS wrong\r\n", 2).await;
+        }
+        // Locked out by the throttle shared with IMAP, so even the right password fails.
+        assert_eq!(
+            exchange(&client, &mut lines, "USER me\r\nPASS password\r\n", 2).await[1],
+            "-ERR [AUTH] Invalid username or password"
+        );
+        shutdown.trigger();
+        running.await.unwrap();
+
+        let users = InMemoryUserStore::new();
+        users.add(User::new("me", "password")).await.unwrap();
+        let server = ServerBuilder::new()
+            .with_configuration(Configuration::default().with_listeners(vec![]))
+            .with_user_store(users)
+            .with_require_tls(true)
+            .with_pop3_listener(Pop3Listener::tcp("127.0.0.1:0"))
+            .bind()
+            .await
+            .unwrap();
+        let address = server.pop3_addresses()[0];
+        let shutdown = server.shutdown();
+        let running = async_std::task::spawn(server.listen());
+
+        let client = TcpStream::connect(address).await.unwrap();
+        let mut lines = BufReader::new(client.clone()).lines();
+        exchange(&client, &mut lines, "", 1).await;
+        let capabilities = exchange(&client, &mut lines, "CAPA\r\n", 6).await;
+        assert!(!capabilities.contains(&"USER".to_string()));
+        assert_eq!(
+            exchange(&client, &mut lines, "USER me\r\nPASS password\r\n", 2).await,
+            [
+                "-ERR Logging in needs an encrypted connection",
+                "-ERR Logging in needs an encrypted connection"
+            ]
+        );
+        shutdown.trigger();
+        running.await.unwrap();
+    }
+}
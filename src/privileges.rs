@@ -0,0 +1,193 @@
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The user and group the server switches to once its listeners are bound, so that it can
+/// bind privileged ports as root without keeping root while it serves clients. The server
+/// may also be confined to the mail root with chroot.
+#[derive(Debug, Clone, Default)]
+pub struct Privileges {
+    user: Option<String>,
+    group: Option<String>,
+    chroot: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum PrivilegeError {
+    UnknownUser(String),
+    UnknownGroup(String),
+    /// A step of dropping privileges which failed, such as `chroot to /var/mail`.
+    Failed(String, io::Error),
+}
+impl Error for PrivilegeError {}
+impl Display for PrivilegeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrivilegeError::UnknownUser(user) => write!(f, "User {} does not exist", user),
+            PrivilegeError::UnknownGroup(group) => write!(f, "Group {} does not exist", group),
+            PrivilegeError::Failed(step, e) => write!(f, "Could not {}: {}", step, e),
+        }
+    }
+}
+
+impl Privileges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Runs as `user`, by name or number, and in its primary group unless `with_group` says
+    /// otherwise.
+    pub fn with_user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_string());
+        self
+    }
+    /// Runs in `group`, by name or number.
+    pub fn with_group(mut self, group: &str) -> Self {
+        self.group = Some(group.to_string());
+        self
+    }
+    /// Confines the server to `root`, usually the mail root. The paths of stores opened
+    /// after binding are then taken inside it.
+    pub fn with_chroot<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.chroot = Some(root.into());
+        self
+    }
+
+    /// Drops the privileges of the process. Users and groups are looked up first, as their
+    /// databases are usually outside the chroot.
+    pub fn drop(&self) -> Result<(), PrivilegeError> {
+        let user = match &self.user {
+            Some(user) => Some(lookup_user(user)?),
+            None => None,
+        };
+        let gid = match (&self.group, user) {
+            (Some(group), _) => Some(lookup_group(group)?),
+            (None, Some((_, gid))) => Some(gid),
+            (None, None) => None,
+        };
+        if let Some(root) = &self.chroot {
+            chroot(root)?;
+        }
+        if let Some(gid) = gid {
+            // Supplementary groups can only be changed, and only need to be, by root.
+            // SAFETY: the call reads one group id from a valid pointer.
+            if unsafe { libc::geteuid() } == 0 && unsafe { libc::setgroups(1, &gid) } != 0 {
+                return Err(failed("clear the supplementary groups"));
+            }
+            // SAFETY: setgid has no memory safety requirements.
+            if unsafe { libc::setgid(gid) } != 0 {
+                return Err(failed(&format!("switch to group {}", gid)));
+            }
+        }
+        if let Some((uid, _)) = user {
+            // SAFETY: setuid has no memory safety requirements.
+            if unsafe { libc::setuid(uid) } != 0 {
+                return Err(failed(&format!("switch to user {}", uid)));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn failed(step: &str) -> PrivilegeError {
+    PrivilegeError::Failed(step.to_string(), io::Error::last_os_error())
+}
+
+fn chroot(root: &Path) -> Result<(), PrivilegeError> {
+    let step = || format!("chroot to {}", root.display());
+    std::os::unix::fs::chroot(root).map_err(|e| PrivilegeError::Failed(step(), e))?;
+    std::env::set_current_dir("/").map_err(|e| PrivilegeError::Failed(step(), e))
+}
+
+/// The size of the buffer for the strings of a user or group entry, which is plenty for any
+/// real one.
+const ENTRY_BUFFER: usize = 16 * 1024;
+
+/// The uid and primary gid of `user`.
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t), PrivilegeError> {
+    let unknown = || PrivilegeError::UnknownUser(user.to_string());
+    if let Ok(uid) = user.parse() {
+        // SAFETY: getpwuid_r writes only within the entry and buffer given.
+        return lookup(unknown, |entry, buffer, result| unsafe {
+            libc::getpwuid_r(uid, entry, buffer.as_mut_ptr(), buffer.len(), result)
+        })
+        .map(|entry: libc::passwd| (uid, entry.pw_gid))
+        .or_else(|e| match e {
+            // A number without an entry, as in containers, runs in the group of the same id.
+            PrivilegeError::UnknownUser(..) => Ok((uid, uid)),
+            e => Err(e),
+        });
+    }
+    let name = CString::new(user).map_err(|_| unknown())?;
+    // SAFETY: getpwnam_r writes only within the entry and buffer given.
+    let entry: libc::passwd = lookup(unknown, |entry, buffer, result| unsafe {
+        libc::getpwnam_r(name.as_ptr(), entry, buffer.as_mut_ptr(), buffer.len(), result)
+    })?;
+    Ok((entry.pw_uid, entry.pw_gid))
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t, PrivilegeError> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let unknown = || PrivilegeError::UnknownGroup(group.to_string());
+    let name = CString::new(group).map_err(|_| unknown())?;
+    // SAFETY: getgrnam_r writes only within the entry and buffer given.
+    let entry: libc::group = lookup(unknown, |entry, buffer, result| unsafe {
+        libc::getgrnam_r(name.as_ptr(), entry, buffer.as_mut_ptr(), buffer.len(), result)
+    })?;
+    Ok(entry.gr_gid)
+}
+
+/// Runs one of the reentrant lookups of the user and group databases.
+fn lookup<T, U, F>(unknown: U, call: F) -> Result<T, PrivilegeError>
+where
+    U: Fn() -> PrivilegeError,
+    F: FnOnce(*mut T, &mut [libc::c_char], *mut *mut T) -> libc::c_int,
+{
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER];
+    // SAFETY: the entries are C structs of integers and pointers, for which zero is valid.
+    let mut entry: T = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let code = call(&mut entry, &mut buffer, &mut result);
+    match (code, result.is_null()) {
+        (0, false) => Ok(entry),
+        (0, true) => Err(unknown()),
+        (code, _) => Err(PrivilegeError::Failed(
+            "look up the user and group databases".to_string(),
+            io::Error::from_raw_os_error(code),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lookup_group, lookup_user, PrivilegeError, Privileges};
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_user("0").unwrap(), (0, 0));
+        assert_eq!(lookup_user("54321").unwrap().0, 54321);
+        assert!(matches!(
+            lookup_user("treasurmap-no-such-user"),
+            Err(PrivilegeError::UnknownUser(..))
+        ));
+        assert_eq!(lookup_group("54321").unwrap(), 54321);
+        assert!(matches!(
+            lookup_group("treasurmap-no-such-group"),
+            Err(PrivilegeError::UnknownGroup(..))
+        ));
+    }
+
+    #[test]
+    fn test_unknown_user_changes_nothing() {
+        let privileges = Privileges::new()
+            .with_user("treasurmap-no-such-user")
+            .with_chroot("/nonexistent");
+        assert!(matches!(privileges.drop(), Err(PrivilegeError::UnknownUser(..))));
+        // Nothing to drop is not an error.
+        Privileges::new().drop().unwrap();
+    }
+}
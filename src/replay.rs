@@ -0,0 +1,242 @@
+//! Records sessions with another IMAP server, and plays them back.
+//!
+//! `proxy` sits between a client and another server, passing everything through unchanged
+//! while writing the exchange to a capture file with credentials redacted, in the same
+//! `C: `/`S: ` form as `Capture::Directory`. Given that file, `replay` answers a client the way
+//! the other server did, so an interoperability problem a user reports with their client can
+//! be reproduced without access to their server or their account.
+//!
+//! Replay is line by line: after each line the client sends, the lines the server sent
+//! before the client's next line are written back. Responses the server sent on its own, such
+//! as those during IDLE, are therefore sent as soon as the line before them is read. Tags
+//! the client chooses differently from the recording are substituted in tagged responses.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use futures::future::{self, Either};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::warn;
+
+use crate::capture::{redact, Capture, Transcript, REDACTED};
+use crate::connection::{Line, DEFAULT_MAX_LINE_LENGTH};
+use crate::framing::Framed;
+use crate::util::{uuid, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+    Client(String),
+    Server(String),
+}
+
+/// A recorded exchange, as written by `proxy` or a `Capture::Directory`.
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    steps: Vec<Step>,
+}
+
+impl Recording {
+    /// Reads the `C: ` and `S: ` lines of `script`, ignoring any others.
+    pub fn parse(script: &str) -> Self {
+        let steps = script
+            .lines()
+            .filter_map(|line| match line.split_at(line.len().min(3)) {
+                ("C: ", line) => Some(Step::Client(line.to_string())),
+                ("S: ", line) => Some(Step::Server(line.to_string())),
+                _ => None,
+            })
+            .collect();
+        Self { steps }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+}
+
+/// Copies what `from` sends to `to`, passing each complete line to `record` without its CRLF.
+async fn pump<R, W>(mut from: R, mut to: W, record: impl Fn(&str)) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut chunk = vec![0; 8 * 1024];
+    let mut pending = vec![];
+    loop {
+        let read = from.read(&mut chunk).await?;
+        if read == 0 {
+            if !pending.is_empty() {
+                record(&String::from_utf8_lossy(&pending));
+            }
+            return to.close().await;
+        }
+        to.write_all(&chunk[..read]).await?;
+        to.flush().await?;
+        pending.extend_from_slice(&chunk[..read]);
+        while let Some(end) = pending.windows(2).position(|pair| pair == b"\r\n") {
+            let line: Vec<u8> = pending.drain(..end + 2).collect();
+            record(&String::from_utf8_lossy(&line[..end]));
+        }
+    }
+}
+
+/// Passes a session between `client` and `upstream` through unchanged, recording it to a new
+/// file in `directory` named after a fresh UUID. Returns once either side closes.
+pub async fn proxy<C, U>(client: C, upstream: U, directory: &Path) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let capture = Capture::Directory(directory.to_path_buf());
+    let transcript = Transcript::open(&capture, &uuid())?;
+    let (client_in, client_out) = client.split();
+    let (upstream_in, upstream_out) = upstream.split();
+    let requests = pump(client_in, upstream_out, |line| transcript.client(line));
+    let responses = pump(upstream_in, client_out, |line| transcript.server(line));
+    match future::select(Box::pin(requests), Box::pin(responses)).await {
+        Either::Left((done, _)) | Either::Right((done, _)) => Ok(done?),
+    }
+}
+
+/// Splits the tag off a line, if it has one.
+fn tag(line: &str) -> Option<(&str, &str)> {
+    line.split_once(' ')
+}
+
+/// `line` with its tag replaced by the one the client used for the command, if it differs.
+fn retag(line: &str, tags: &HashMap<String, String>) -> String {
+    match tag(line).and_then(|(recorded, rest)| Some((tags.get(recorded)?, rest))) {
+        Some((used, rest)) => format!("{} {}", used, rest),
+        None => line.to_string(),
+    }
+}
+
+/// Whether `received` from the client is the line the recording has, which may have been
+/// redacted and may have another tag.
+fn same(recorded: &str, received: &str) -> bool {
+    if recorded == REDACTED {
+        return true;
+    }
+    let received = redact(received);
+    match (tag(recorded), tag(&received)) {
+        (Some((_, recorded)), Some((_, received))) => recorded == received,
+        _ => recorded == received,
+    }
+}
+
+/// Answers a client over `stream` with the responses in `recording`, as if it were the
+/// server the recording was made with. Returns once the recording or the client ends.
+pub async fn replay<S>(stream: S, recording: &Recording) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (input, mut output) = stream.split();
+    let mut input = Framed::new(input);
+    let mut steps = recording.steps.iter().peekable();
+    // Recorded tags, by the tag the client used instead.
+    let mut tags: HashMap<String, String> = HashMap::new();
+    loop {
+        while let Some(Step::Server(line)) = steps.peek() {
+            let line = retag(line, &tags);
+            output.write_all(line.as_bytes()).await?;
+            output.write_all(b"\r\n").await?;
+            steps.next();
+        }
+        output.flush().await?;
+        let recorded = match steps.next() {
+            Some(Step::Client(line)) => line,
+            _ => return Ok(()),
+        };
+        if input.read_line(DEFAULT_MAX_LINE_LENGTH).await? == Line::End {
+            return Ok(());
+        }
+        let received = String::from_utf8_lossy(input.line());
+        let received = received.trim_end_matches(['\r', '\n']);
+        if !same(recorded, received) {
+            warn!(
+                "The client sent `{}` where the recording has `{}`",
+                redact(received),
+                recorded
+            );
+        }
+        if let (Some((recorded, _)), Some((used, _))) = (tag(recorded), tag(received)) {
+            if recorded != used {
+                tags.insert(recorded.to_string(), used.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn test_record_and_replay() {
+        use async_std::io::BufReader;
+        use async_std::os::unix::net::UnixStream;
+        use futures::{AsyncBufReadExt, AsyncWriteExt, StreamExt};
+
+        use super::{proxy, replay, Recording};
+
+        let directory =
+            std::env::temp_dir().join(format!("treasurmap-replay-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+
+        // A server which greets, answers the LOGIN and closes.
+        let (mut server, upstream) = UnixStream::pair().unwrap();
+        let (mut client, proxied) = UnixStream::pair().unwrap();
+        let recording = {
+            let directory = directory.clone();
+            async_std::task::spawn(async move { proxy(proxied, upstream, &directory).await })
+        };
+        server.write_all(b"* OK ready\r\n").await.unwrap();
+        let mut from_server = BufReader::new(client.clone()).lines();
+        assert_eq!(from_server.next().await.unwrap().unwrap(), "* OK ready");
+        client
+            .write_all(b"a1 LOGIN me@example.com secret\r\n")
+            .await
+            .unwrap();
+        let mut from_client = BufReader::new(server.clone()).lines();
+        assert_eq!(
+            from_client.next().await.unwrap().unwrap(),
+            "a1 LOGIN me@example.com secret"
+        );
+        server
+            .write_all(b"a1 OK LOGIN completed\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            from_server.next().await.unwrap().unwrap(),
+            "a1 OK LOGIN completed"
+        );
+        drop((server, from_client));
+        recording.await.unwrap();
+
+        let file = std::fs::read_dir(&directory)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let script = std::fs::read_to_string(&file).unwrap();
+        assert_eq!(
+            script,
+            "S: * OK ready\nC: a1 LOGIN [redacted]\nS: a1 OK LOGIN completed\n"
+        );
+
+        // The replayed server answers another client, with its own tags, the same way.
+        let recording = Recording::parse(&script);
+        let (mut client, replayed) = UnixStream::pair().unwrap();
+        let replaying = async_std::task::spawn(async move { replay(replayed, &recording).await });
+        let mut lines = BufReader::new(client.clone()).lines();
+        assert_eq!(lines.next().await.unwrap().unwrap(), "* OK ready");
+        client
+            .write_all(b"x LOGIN other password\r\n")
+            .await
+            .unwrap();
+        assert_eq!(lines.next().await.unwrap().unwrap(), "x OK LOGIN completed");
+        replaying.await.unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,620 @@
+//! Ships every mailbox's change journal and message bodies to a standby server, which can be
+//! promoted to take over when the primary is lost.
+//!
+//! On the primary a `Replica` runs as a scheduled job. Each run it looks for the mailboxes of
+//! every user and sends the standby what has changed in each since the last run, as JSON
+//! records, one per line, over TCP:
+//!
+//! ```json
+//! {"type":"append","user":"me","mailbox":"INBOX","uid":4,"flags":["\\Seen"],"internal_date":1714555800000,"body":"U3ViamVjdDog..."}
+//! ```
+//!
+//! The types are `reset`, which empties the standby's copy of a mailbox, `append`, with the
+//! body in base64 and the internal date in milliseconds since the epoch, `flags` and
+//! `expunge`, with the `uids` removed. The standby answers each record with `OK`, or `NO` and
+//! why it could not apply it. A mailbox is first sent whole, as a `reset` and an `append` for
+//! each message, and then as the changes in its journal. A mailbox whose journal no longer
+//! goes back far enough, and every mailbox after the connection to the standby fails, is
+//! sent whole again. Replication is asynchronous: a change is on the standby at most an
+//! interval, and the time to ship it, after the primary made it.
+//!
+//! A `Standby` applies the records to its own data store and index. Its stores assign their
+//! own UIDs, which are only the primary's if the standby started empty, and it keeps which
+//! UID of the primary each of its messages has for as long as the primary stays connected.
+//! The standby trusts whatever connects to it, so its port must only be reachable from the
+//! primary.
+//!
+//! `imap_replication_lag_seconds` is how long the oldest change shipped in the last run had
+//! been waiting for, and `imap_replicated_changes_total` counts the records shipped. Runs
+//! which failed, because the standby was unreachable or refused a record, are counted as
+//! errors of the `replicate` job.
+//!
+//! To promote the standby:
+//!
+//! 1. Stop the primary, or at least stop clients from reaching it, so that it makes no
+//!    changes the standby will not have.
+//! 2. Trigger the `Shutdown` the standby was started with, so it stops applying records.
+//! 3. Build the new primary with `Standby::promote`, which returns a `ServerBuilder` with the
+//!    standby's stores, add the user store, authenticator and anything else the old primary
+//!    had, and start it where clients will find it.
+//!
+//! Clients which cached UIDs from the old primary should resynchronise, since the standby's
+//! UIDs may differ from those they saw.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_lock::Mutex;
+use async_std::future::timeout;
+use async_std::io::BufReader;
+use async_std::net::{TcpListener, TcpStream};
+use futures::future::{self, Either};
+use futures::io::Lines;
+use futures::{AsyncBufReadExt, AsyncWriteExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::index::journal::{Change, JournalEntry};
+use crate::index::message::{Envelope, MessageRecord};
+use crate::index::{Index, Mailbox, MailboxError, Owner, Permission};
+use crate::metrics::Metrics;
+use crate::mime::encoding::{decode_base64, encode_base64};
+use crate::runtime::spawn;
+use crate::scheduler::Job;
+use crate::server::{Components, ServerBuilder};
+use crate::shutdown::Shutdown;
+use crate::store::{DataStore, Message, StoreError};
+use crate::util::Result;
+
+/// How long the standby has to acknowledge the records of a mailbox.
+const SHIP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A change to one mailbox, as shipped to the standby.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Record {
+    Reset {
+        user: String,
+        mailbox: String,
+    },
+    Append {
+        user: String,
+        mailbox: String,
+        uid: u32,
+        flags: Vec<String>,
+        internal_date: u64,
+        body: String,
+    },
+    Flags {
+        user: String,
+        mailbox: String,
+        uid: u32,
+        flags: Vec<String>,
+    },
+    Expunge {
+        user: String,
+        mailbox: String,
+        uids: Vec<u32>,
+    },
+}
+
+/// The connection to the standby, and how far each mailbox has been shipped over it.
+struct Link {
+    reader: Lines<BufReader<TcpStream>>,
+    writer: TcpStream,
+    /// The MODSEQ of the last change shipped, by mailbox.
+    shipped: HashMap<(Owner, String), u64>,
+}
+
+impl Link {
+    async fn connect(address: &str) -> Result<Self> {
+        let stream = TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            reader: BufReader::new(stream.clone()).lines(),
+            writer: stream,
+            shipped: HashMap::new(),
+        })
+    }
+
+    /// Sends `records` and waits for the standby to have applied all of them.
+    async fn send(&mut self, records: &[Record]) -> Result<()> {
+        for record in records {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            self.writer.write_all(&line).await?;
+        }
+        self.writer.flush().await?;
+        for _ in records {
+            match self.reader.next().await {
+                Some(Ok(answer)) if answer == "OK" => {}
+                Some(Ok(answer)) => {
+                    return Err(format!("The standby refused a change: {}", answer).into())
+                }
+                Some(Err(e)) => return Err(Box::new(e)),
+                None => return Err("The standby closed the connection".into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Ships the changes to every mailbox to a standby listening at an address, as a scheduled
+/// job. See the module documentation.
+pub struct Replica {
+    address: String,
+    interval: Duration,
+    link: Mutex<Option<Link>>,
+}
+
+impl Replica {
+    pub fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            interval: Duration::from_secs(1),
+            link: Mutex::new(None),
+        }
+    }
+    /// How often changes are shipped, every second by default.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Ships what has changed in every mailbox, returning the number of records shipped and
+    /// how long the oldest change among them had waited.
+    async fn ship(&self, link: &mut Link, components: &Components) -> Result<(u64, Duration)> {
+        let (mut shipped, mut lag) = (0, Duration::ZERO);
+        let now = SystemTime::now();
+        for (owner, mailbox) in components.mailboxes().await {
+            let key = (owner.clone(), mailbox.clone());
+            let changes = match link.shipped.get(&key) {
+                Some(modseq) => {
+                    match components
+                        .index
+                        .changes_since(&owner, &mailbox, *modseq)
+                        .await
+                    {
+                        Ok(changes) => Some(changes),
+                        Err(MailboxError::HistoryUnavailable(..)) => None,
+                        Err(MailboxError::DoesNotExist(..)) => continue,
+                        Err(e) => return Err(Box::new(e)),
+                    }
+                }
+                None => None,
+            };
+            let (records, modseq) = match changes {
+                Some(changes) => {
+                    let Some(last) = changes.last().map(|entry| entry.modseq) else {
+                        continue;
+                    };
+                    if let Some(oldest) = changes.first() {
+                        lag = lag.max(now.duration_since(oldest.at).unwrap_or_default());
+                    }
+                    (records(components, &owner, &mailbox, &changes).await?, last)
+                }
+                None => match snapshot(components, &owner, &mailbox).await? {
+                    Some(snapshot) => snapshot,
+                    None => continue,
+                },
+            };
+            timeout(SHIP_TIMEOUT, link.send(&records))
+                .await
+                .map_err(|_| format!("Timed out shipping {} of {}", mailbox, owner.name()))??;
+            shipped += records.len() as u64;
+            link.shipped.insert(key, modseq);
+        }
+        Ok((shipped, lag))
+    }
+}
+
+#[async_trait::async_trait]
+impl Job for Replica {
+    fn name(&self) -> &str {
+        "replicate"
+    }
+    async fn run(&self, components: &Components, metrics: &Metrics) -> Result<()> {
+        let mut link = self.link.lock().await;
+        let connected = match link.take() {
+            Some(connected) => connected,
+            None => {
+                debug!("Connecting to the standby at {}", self.address);
+                Link::connect(&self.address).await?
+            }
+        };
+        let connected = link.insert(connected);
+        match self.ship(connected, components).await {
+            Ok((shipped, lag)) => {
+                metrics.replicated(shipped, lag);
+                Ok(())
+            }
+            Err(e) => {
+                // The standby may have applied some of what was sent, so start again from
+                // whole mailboxes over a new connection.
+                *link = None;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// The records which make the standby's copy of a mailbox the same as the primary's, and the
+/// MODSEQ they bring it up to. `None` if the mailbox does not exist in the index.
+async fn snapshot(
+    components: &Components,
+    owner: &Owner,
+    mailbox: &str,
+) -> Result<Option<(Vec<Record>, u64)>> {
+    // The MODSEQ is read first, so changes made while the messages are listed are shipped
+    // again rather than missed. The standby ignores messages it already has.
+    let modseq = match components
+        .index
+        .get_mailbox(owner, mailbox, Permission::ReadOnly)
+        .await
+    {
+        Ok(found) => found.highest_modseq,
+        Err(MailboxError::DoesNotExist(..)) => return Ok(None),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let messages = components.index.list_messages(owner, mailbox).await?;
+    let mut records = vec![Record::Reset {
+        user: owner.name().to_string(),
+        mailbox: mailbox.to_string(),
+    }];
+    for message in messages {
+        if let Some(record) = append(components, owner, mailbox, message).await? {
+            records.push(record);
+        }
+    }
+    Ok(Some((records, modseq)))
+}
+
+/// The records for `changes` to a mailbox.
+async fn records(
+    components: &Components,
+    owner: &Owner,
+    mailbox: &str,
+    changes: &[JournalEntry],
+) -> Result<Vec<Record>> {
+    let (user, name) = (owner.name().to_string(), mailbox.to_string());
+    let mut records = vec![];
+    for entry in changes {
+        match &entry.change {
+            Change::Append(uid) => {
+                let message = match components.index.get_message(owner, mailbox, *uid).await {
+                    Ok(message) => message,
+                    // Expunged since, which a later change says.
+                    Err(MailboxError::MessageDoesNotExist(..)) => continue,
+                    Err(e) => return Err(Box::new(e)),
+                };
+                records.extend(append(components, owner, mailbox, message).await?);
+            }
+            Change::Flags(uid, flags) => records.push(Record::Flags {
+                user: user.clone(),
+                mailbox: name.clone(),
+                uid: *uid,
+                flags: flags.clone(),
+            }),
+            Change::Expunge(uids) => records.push(Record::Expunge {
+                user: user.clone(),
+                mailbox: name.clone(),
+                uids: uids.clone(),
+            }),
+        }
+    }
+    Ok(records)
+}
+
+/// The record appending `message` with its body, or `None` if the body has been expunged.
+async fn append(
+    components: &Components,
+    owner: &Owner,
+    mailbox: &str,
+    message: MessageRecord,
+) -> Result<Option<Record>> {
+    let body = match components
+        .data_store
+        .fetch(owner.name(), mailbox, message.uid)
+        .await
+    {
+        Ok(body) => body,
+        Err(StoreError::MessageDoesNotExist(..)) => return Ok(None),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let internal_date = message
+        .internal_date
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    Ok(Some(Record::Append {
+        user: owner.name().to_string(),
+        mailbox: mailbox.to_string(),
+        uid: message.uid,
+        flags: message.flags,
+        internal_date,
+        body: encode_base64(&body),
+    }))
+}
+
+/// Applies the records a `Replica` ships to a data store and index of its own, so that they
+/// can be served from if the primary is lost. See the module documentation.
+#[derive(Clone)]
+pub struct Standby {
+    data_store: Arc<dyn DataStore>,
+    index: Arc<dyn Index>,
+    /// The standby's UID of each message, by user, mailbox and the primary's UID.
+    uids: Arc<Mutex<HashMap<(String, String), HashMap<u32, u32>>>>,
+}
+
+impl Standby {
+    pub fn new(data_store: Arc<dyn DataStore>, index: Arc<dyn Index>) -> Self {
+        Self {
+            data_store,
+            index,
+            uids: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Accepts connections from the primary on `listener` and applies what it ships until
+    /// `shutdown` is triggered.
+    pub async fn serve(&self, listener: TcpListener, shutdown: Shutdown) {
+        loop {
+            let accepted = match future::select(
+                Box::pin(listener.accept()),
+                Box::pin(shutdown.wait()),
+            )
+            .await
+            {
+                Either::Left((accepted, _)) => accepted,
+                Either::Right(..) => return,
+            };
+            match accepted {
+                Ok((stream, peer)) => {
+                    debug!("Replicating from {}", peer);
+                    let standby = self.clone();
+                    let shutdown = shutdown.clone();
+                    spawn(async move {
+                        let follow = standby.follow(stream);
+                        match future::select(Box::pin(follow), Box::pin(shutdown.wait())).await {
+                            Either::Left((Err(e), _)) => {
+                                warn!("Stopped replicating from {}: {}", peer, e)
+                            }
+                            Either::Left((Ok(()), _)) | Either::Right(..) => {}
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("Could not accept a connection from the primary: {}", e);
+                    async_std::task::sleep(Duration::from_millis(500)).await;
+                }
+            }
+        }
+    }
+
+    /// A builder for a server with the standby's stores, once `serve` has stopped. See the
+    /// module documentation.
+    pub fn promote(self) -> ServerBuilder {
+        ServerBuilder::new()
+            .with_data_store(self.data_store)
+            .with_index(self.index)
+    }
+
+    /// Applies the records sent over `stream` until the primary closes it.
+    async fn follow(&self, stream: TcpStream) -> Result<()> {
+        let mut lines = BufReader::new(stream.clone()).lines();
+        let mut writer = stream;
+        while let Some(line) = lines.next().await {
+            let answer = match serde_json::from_str(&line?) {
+                Ok(record) => match self.apply(record).await {
+                    Ok(()) => "OK".to_string(),
+                    Err(e) => format!("NO {}", e),
+                },
+                Err(e) => format!("NO {}", e),
+            };
+            writer.write_all(format!("{}\n", answer).as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    async fn apply(&self, record: Record) -> Result<()> {
+        let mut uids = self.uids.lock().await;
+        match record {
+            Record::Reset { user, mailbox } => {
+                let uids = uids.entry((user.clone(), mailbox.clone())).or_default();
+                uids.clear();
+                let existing = match self.data_store.list(&user, &mailbox).await {
+                    Ok(existing) => existing,
+                    Err(StoreError::MailboxDoesNotExist(..)) => return Ok(()),
+                    Err(e) => return Err(Box::new(e)),
+                };
+                let existing: Vec<u32> = existing.iter().map(|message| message.uid).collect();
+                self.data_store.expunge(&user, &mailbox, &existing).await?;
+                self.index
+                    .remove_messages(&Owner::new(&user), &mailbox, &existing)
+                    .await?;
+            }
+            Record::Append {
+                user,
+                mailbox,
+                uid,
+                flags,
+                internal_date,
+                body,
+            } => {
+                let uids = uids.entry((user.clone(), mailbox.clone())).or_default();
+                if uids.contains_key(&uid) {
+                    return Ok(());
+                }
+                let owner = Owner::new(&user);
+                match self
+                    .index
+                    .get_mailbox(&owner, &mailbox, Permission::ReadWrite)
+                    .await
+                {
+                    Err(MailboxError::DoesNotExist(_)) => {
+                        let created = Mailbox::new(&mailbox, 0, vec![], Permission::ReadWrite);
+                        self.index.add_mailbox(&owner, created).await?
+                    }
+                    Err(e) => return Err(Box::new(e)),
+                    Ok(_) => {}
+                }
+                let body = decode_base64(body.as_bytes());
+                let internal_date = UNIX_EPOCH + Duration::from_millis(internal_date);
+                let message = Message {
+                    flags: flags.clone(),
+                    internal_date,
+                    body: body.clone(),
+                };
+                let local = self.data_store.append(&user, &mailbox, message).await?;
+                let record = MessageRecord::new(local, body.len() as u64, internal_date)
+                    .with_flags(flags)
+                    .with_envelope(Envelope::parse(&body));
+                self.index.add_message(&owner, &mailbox, record).await?;
+                uids.insert(uid, local);
+            }
+            Record::Flags {
+                user,
+                mailbox,
+                uid,
+                flags,
+            } => {
+                // A message the standby does not have was expunged before it was shipped.
+                if let Some(local) = uids
+                    .get(&(user.clone(), mailbox.clone()))
+                    .and_then(|uids| uids.get(&uid))
+                {
+                    self.index
+                        .set_flags(&Owner::new(&user), &mailbox, *local, flags)
+                        .await?;
+                }
+            }
+            Record::Expunge {
+                user,
+                mailbox,
+                uids: expunged,
+            } => {
+                let uids = uids.entry((user.clone(), mailbox.clone())).or_default();
+                let local: Vec<u32> = expunged.iter().filter_map(|uid| uids.remove(uid)).collect();
+                if local.is_empty() {
+                    return Ok(());
+                }
+                self.data_store.expunge(&user, &mailbox, &local).await?;
+                self.index
+                    .remove_messages(&Owner::new(&user), &mailbox, &local)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use async_std::net::TcpListener;
+
+    use super::{Replica, Standby};
+    use crate::auth::inmemory::{InMemoryAuthenticator, InMemoryUserStore};
+    use crate::auth::{Authenticate, User, UserStore};
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::message::MessageRecord;
+    use crate::index::{Index, Owner};
+    use crate::metrics::Metrics;
+    use crate::scheduler::Job;
+    use crate::server::Components;
+    use crate::shutdown::Shutdown;
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message};
+
+    #[async_std::test]
+    async fn test_replicate_to_standby() {
+        let users = InMemoryUserStore::new();
+        users.add(User::new("me", "password")).await.unwrap();
+        let user_store: Arc<Box<dyn UserStore>> = Arc::new(Box::new(users));
+        let primary = Components {
+            index: Arc::new(Box::new(InMemoryIndex::new()) as Box<dyn Index>),
+            data_store: Arc::new(Box::new(InMemoryStore::new()) as Box<dyn DataStore>),
+            user_store: user_store.clone(),
+            authenticator: Arc::new(
+                Box::new(InMemoryAuthenticator::new(user_store)) as Box<dyn Authenticate>
+            ),
+        };
+        let me = Owner::new("me");
+        let mut uids = vec![];
+        for body in [&b"Subject: one\r\n\r\n1"[..], b"Subject: two\r\n\r\n2"] {
+            let uid = primary
+                .data_store
+                .append("me", "INBOX", Message::new(body))
+                .await
+                .unwrap();
+            let record = MessageRecord::new(uid, body.len() as u64, SystemTime::now());
+            primary
+                .index
+                .add_message(&me, "INBOX", record)
+                .await
+                .unwrap();
+            uids.push(uid);
+        }
+
+        let index: Arc<dyn Index> = Arc::new(InMemoryIndex::new());
+        let data_store: Arc<dyn DataStore> = Arc::new(InMemoryStore::new());
+        let standby = Standby::new(data_store.clone(), index.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let shutdown = Shutdown::new();
+        let serving = {
+            let (standby, shutdown) = (standby.clone(), shutdown.clone());
+            async_std::task::spawn(async move { standby.serve(listener, shutdown).await })
+        };
+
+        // The first run sends INBOX whole.
+        let replica = Replica::new(&address);
+        let metrics = Metrics::new();
+        replica.run(&primary, &metrics).await.unwrap();
+        assert_eq!(metrics.replicated_changes(), 3);
+        let copied = index.list_messages(&me, "INBOX").await.unwrap();
+        assert_eq!(copied.len(), 2);
+        assert_eq!(copied[1].envelope.subject.as_deref(), Some("two"));
+        assert_eq!(
+            data_store
+                .fetch("me", "INBOX", copied[0].uid)
+                .await
+                .unwrap(),
+            b"Subject: one\r\n\r\n1"
+        );
+
+        // Later runs send the changes in the journal.
+        let seen = vec!["\\Seen".to_string()];
+        primary
+            .index
+            .set_flags(&me, "INBOX", uids[0], seen.clone())
+            .await
+            .unwrap();
+        primary
+            .data_store
+            .expunge("me", "INBOX", &uids[1..])
+            .await
+            .unwrap();
+        primary
+            .index
+            .remove_messages(&me, "INBOX", &uids[1..])
+            .await
+            .unwrap();
+        replica.run(&primary, &metrics).await.unwrap();
+        assert_eq!(metrics.replicated_changes(), 5);
+        let copied = index.list_messages(&me, "INBOX").await.unwrap();
+        assert_eq!(copied.len(), 1);
+        assert_eq!(copied[0].flags, seen);
+        assert_eq!(data_store.list("me", "INBOX").await.unwrap().len(), 1);
+
+        shutdown.trigger();
+        serving.await;
+        let _promoted = standby.promote();
+    }
+}
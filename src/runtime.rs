@@ -0,0 +1,151 @@
+//! The executor the server runs its tasks on.
+//!
+//! By default that is async-std's. With the `tokio` feature, tasks are spawned on the tokio
+//! runtime the server is started in instead, so applications which already run tokio do not
+//! need a second executor. Sockets and timers come from async-std either way: their reactor
+//! runs on a thread of its own and works under any executor.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A handle to a spawned task, which resolves to its output. Dropping it detaches the task.
+pub struct JoinHandle<T> {
+    #[cfg(not(feature = "tokio"))]
+    inner: async_std::task::JoinHandle<T>,
+    #[cfg(feature = "tokio")]
+    inner: tokio::task::JoinHandle<T>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Stops the task at its next await point.
+    pub async fn cancel(self) {
+        #[cfg(not(feature = "tokio"))]
+        self.inner.cancel().await;
+        #[cfg(feature = "tokio")]
+        {
+            self.inner.abort();
+            let _ = self.inner.await;
+        }
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    #[cfg(not(feature = "tokio"))]
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        Pin::new(&mut self.inner).poll(cx)
+    }
+
+    #[cfg(feature = "tokio")]
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        match Pin::new(&mut self.inner).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(output)) => Poll::Ready(output),
+            // As with async-std, a panic in the task resumes in whoever awaits it. Tasks are
+            // only cancelled through `cancel`, which does not poll the handle afterwards.
+            Poll::Ready(Err(e)) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
+
+/// Runs `future` on its own task.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    JoinHandle {
+        #[cfg(not(feature = "tokio"))]
+        inner: async_std::task::spawn(future),
+        #[cfg(feature = "tokio")]
+        inner: tokio::task::spawn(future),
+    }
+}
+
+/// Runs `function` on a thread where blocking is allowed, such as for disk or database IO.
+pub fn spawn_blocking<F, T>(function: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    JoinHandle {
+        #[cfg(not(feature = "tokio"))]
+        inner: async_std::task::spawn_blocking(function),
+        #[cfg(feature = "tokio")]
+        inner: tokio::task::spawn_blocking(function),
+    }
+}
+
+/// Runs `future` to completion on the current thread, starting a tokio runtime for it with
+/// the `tokio` feature.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    #[cfg(not(feature = "tokio"))]
+    return async_std::task::block_on(future);
+    #[cfg(feature = "tokio")]
+    return tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Could not start the tokio runtime")
+        .block_on(future);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{block_on, spawn, spawn_blocking};
+
+    #[test]
+    fn test_spawn() {
+        let output = block_on(async {
+            let blocking = spawn_blocking(|| 2);
+            spawn(async move { blocking.await * 21 }).await
+        });
+        assert_eq!(output, 42);
+    }
+
+    #[cfg(all(feature = "tokio", unix))]
+    #[test]
+    fn test_server_on_tokio() {
+        use async_std::io::BufReader;
+        use async_std::os::unix::net::UnixStream;
+        use async_std::prelude::*;
+
+        use crate::listener::Listener;
+        use crate::server::ServerBuilder;
+
+        let directory =
+            std::env::temp_dir().join(format!("treasurmap-runtime-tokio-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let socket = directory.join("imap.sock");
+        let received = block_on(async {
+            let server = ServerBuilder::new()
+                .with_listener(Listener::unix(&socket))
+                .bind()
+                .await
+                .unwrap();
+            let shutdown = server.shutdown();
+            let running = spawn(server.listen());
+
+            let mut stream = UnixStream::connect(&socket).await.unwrap();
+            let mut lines = BufReader::new(stream.clone()).lines();
+            lines.next().await.unwrap().unwrap();
+            stream.write_all(b"a1 LOGOUT\r\n").await.unwrap();
+            let mut received = vec![];
+            while let Some(line) = lines.next().await {
+                received.push(line.unwrap());
+            }
+            shutdown.trigger();
+            running.await.unwrap();
+            received
+        });
+        assert_eq!(
+            received,
+            vec![
+                "* BYE IMAP4rev2 server logging out",
+                "a1 OK LOGOUT completed. Goodbye!"
+            ]
+        );
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,225 @@
+//! Runs the server's periodic maintenance, such as retention and journal compaction, on one
+//! schedule.
+//!
+//! Each job runs on its own task, every interval give or take some jitter so that jobs, and
+//! servers sharing a backend, do not all start at once. A job never overlaps itself. Runs and
+//! the time they take are counted in `Metrics` by job. Once the server shuts down no job is
+//! started again, and `Scheduler::run` returns when those running have finished.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_std::task::sleep;
+use futures::future::{self, join_all, Either};
+use tracing::{debug, warn};
+
+use crate::metrics::Metrics;
+use crate::runtime::spawn;
+use crate::server::Components;
+use crate::shutdown::Shutdown;
+use crate::util::Result;
+
+/// Something the scheduler runs periodically.
+#[async_trait::async_trait]
+pub trait Job: Send + Sync {
+    /// Names the job in logs and metrics.
+    fn name(&self) -> &str;
+    /// Runs the job once. Jobs can count what they did in `metrics` beyond the runs the
+    /// scheduler counts itself.
+    async fn run(&self, components: &Components, metrics: &Metrics) -> Result<()>;
+}
+
+/// Compacts the change journal of every mailbox. See `Index::compact_journals`.
+pub struct CompactJournals;
+
+#[async_trait::async_trait]
+impl Job for CompactJournals {
+    fn name(&self) -> &str {
+        "compact_journals"
+    }
+    async fn run(&self, components: &Components, _: &Metrics) -> Result<()> {
+        Ok(components.index.compact_journals().await?)
+    }
+}
+
+struct Scheduled {
+    job: Box<dyn Job>,
+    interval: Duration,
+}
+
+/// The jobs of a server and how often they run.
+pub struct Scheduler {
+    jobs: Vec<Scheduled>,
+    jitter: f64,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            jobs: vec![],
+            jitter: 0.1,
+        }
+    }
+    /// Runs `job` every `interval`.
+    pub fn with_job<J: Job + 'static>(mut self, job: J, interval: Duration) -> Self {
+        self.jobs.push(Scheduled {
+            job: Box::new(job),
+            interval,
+        });
+        self
+    }
+    /// How far each wait may be from its interval, as a fraction of it: 0.1 by default, so a
+    /// job every hour runs between 54 and 66 minutes after the last. The first run of each job
+    /// comes after a random part of this, rather than a whole interval after the server starts.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// Runs the jobs until `shutdown` is triggered.
+    pub async fn run(self, components: Components, metrics: Arc<Metrics>, shutdown: Shutdown) {
+        let jitter = self.jitter;
+        let tasks = self.jobs.into_iter().map(|scheduled| {
+            let (components, metrics, shutdown) =
+                (components.clone(), metrics.clone(), shutdown.clone());
+            spawn(async move {
+                let mut wait = scheduled.interval.mul_f64(jitter * random());
+                loop {
+                    match future::select(Box::pin(sleep(wait)), Box::pin(shutdown.wait())).await {
+                        Either::Left(..) if !shutdown.is_triggered() => {}
+                        _ => return,
+                    }
+                    let (name, started) = (scheduled.job.name(), Instant::now());
+                    let outcome = scheduled.job.run(&components, &metrics).await;
+                    let took = started.elapsed();
+                    metrics.job_completed(name, outcome.is_ok(), took);
+                    match outcome {
+                        Ok(()) => debug!("Job {} finished in {:?}", name, took),
+                        Err(e) => warn!("Job {} failed: {}", name, e),
+                    }
+                    wait = jittered(scheduled.interval, jitter);
+                }
+            })
+        });
+        join_all(tasks.collect::<Vec<_>>()).await;
+    }
+}
+
+/// `interval`, moved by up to `jitter` of it either way.
+fn jittered(interval: Duration, jitter: f64) -> Duration {
+    interval.mul_f64(1.0 + jitter * (2.0 * random() - 1.0))
+}
+
+/// A number in [0, 1). Jitter only needs to differ between jobs and servers, so the clock
+/// will do if the system has no randomness to give.
+pub(crate) fn random() -> f64 {
+    let mut bytes = [0u8; 8];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        bytes = u64::from(nanos).to_le_bytes();
+    }
+    (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_std::task::sleep;
+
+    use super::{jittered, Job, Scheduler};
+    use crate::auth::inmemory::{InMemoryAuthenticator, InMemoryUserStore};
+    use crate::auth::{Authenticate, UserStore};
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::Index;
+    use crate::metrics::Metrics;
+    use crate::server::Components;
+    use crate::shutdown::Shutdown;
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::DataStore;
+    use crate::util::Result;
+
+    /// Counts its runs, failing every other one and taking a while over each.
+    struct Counter {
+        runs: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl Job for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+        async fn run(&self, _: &Components, _: &Metrics) -> Result<()> {
+            sleep(Duration::from_millis(20)).await;
+            match self.runs.fetch_add(1, Ordering::SeqCst) % 2 {
+                0 => Ok(()),
+                _ => Err("odd run".into()),
+            }
+        }
+    }
+
+    fn components() -> Components {
+        let users: Arc<Box<dyn UserStore>> = Arc::new(Box::new(InMemoryUserStore::new()));
+        Components {
+            index: Arc::new(Box::new(InMemoryIndex::new()) as Box<dyn Index>),
+            data_store: Arc::new(Box::new(InMemoryStore::new()) as Box<dyn DataStore>),
+            user_store: users.clone(),
+            authenticator: Arc::new(
+                Box::new(InMemoryAuthenticator::new(users)) as Box<dyn Authenticate>
+            ),
+        }
+    }
+
+    #[test]
+    fn test_jitter() {
+        let interval = Duration::from_secs(100);
+        for _ in 0..100 {
+            let wait = jittered(interval, 0.1);
+            assert!(wait >= Duration::from_secs(90) && wait <= Duration::from_secs(110));
+        }
+        assert_eq!(jittered(interval, 0.0), interval);
+    }
+
+    #[async_std::test]
+    async fn test_scheduler() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let metrics = Arc::new(Metrics::new());
+        let shutdown = Shutdown::new();
+        let scheduler = Scheduler::new()
+            .with_job(Counter { runs: runs.clone() }, Duration::from_millis(10))
+            .with_jitter(0.5);
+        let running =
+            async_std::task::spawn(scheduler.run(components(), metrics.clone(), shutdown.clone()));
+        while runs.load(Ordering::SeqCst) < 4 {
+            sleep(Duration::from_millis(5)).await;
+        }
+        shutdown.trigger();
+        running.await;
+
+        // The run in progress at the shutdown was finished, and none was started after it.
+        let finished = runs.load(Ordering::SeqCst) as u64;
+        assert_eq!(
+            metrics.job_runs("counter", true) + metrics.job_runs("counter", false),
+            finished
+        );
+        assert!(metrics.job_runs("counter", false) >= 2);
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(runs.load(Ordering::SeqCst) as u64, finished);
+        assert!(metrics
+            .render()
+            .contains("imap_job_runs_total{job=\"counter\",outcome=\"error\"}"));
+    }
+}
@@ -0,0 +1,265 @@
+//! Resolves credentials such as database passwords, TLS keys and admin tokens from outside the
+//! configuration, so they need not be written into it.
+//!
+//! A secret is named by a reference of the form `<scheme>:<name>`:
+//!
+//! - `env:NAME` is the environment variable `NAME`, or the contents of the file named by
+//!   `NAME_FILE` when `NAME` is unset, following the convention of Docker and Kubernetes
+//!   secrets;
+//! - `file:/run/secrets/ldap` is the contents of the file;
+//! - any other scheme is looked up with the `SecretProvider` registered for it, such as
+//!   `vault:` with the `vault` feature.
+//!
+//! In a plugin's section of the configuration, an object with the single key `from_file`,
+//! `from_env` or `from_secret` is replaced by the secret named by its value: a path, an
+//! environment variable or a reference respectively. `{"bind_password": {"from_file":
+//! "/run/secrets/ldap"}}` is configured as `{"bind_password": "<contents of the file>"}`.
+//! A single trailing newline is dropped from anything read from a file.
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::util::Result;
+
+/// Looks up secrets in an external store, such as a vault or a cloud secret manager.
+#[async_trait::async_trait]
+pub trait SecretProvider: Send + Sync {
+    /// The scheme of the references the provider resolves, such as `vault`.
+    fn scheme(&self) -> &str;
+    /// The secret named by `name`, the part of the reference after the scheme.
+    async fn get(&self, name: &str) -> Result<String>;
+}
+
+/// Resolves secret references, see the module documentation.
+#[derive(Clone, Default)]
+pub struct Secrets {
+    providers: HashMap<String, Arc<dyn SecretProvider>>,
+}
+
+impl Secrets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Resolves references with the scheme of `provider`, replacing any registered before.
+    pub fn with_provider<P: SecretProvider + 'static>(mut self, provider: P) -> Self {
+        self.providers
+            .insert(provider.scheme().to_string(), Arc::new(provider));
+        self
+    }
+
+    /// The secret named by `reference`, such as `env:DB_PASSWORD` or `file:/run/secrets/tls.key`.
+    pub async fn resolve(&self, reference: &str) -> Result<String> {
+        let (scheme, name) = reference
+            .split_once(':')
+            .ok_or_else(|| format!("Secret reference {} has no scheme", reference))?;
+        match scheme {
+            "env" => environment(name).await,
+            "file" => read(name).await,
+            _ => match self.providers.get(scheme) {
+                Some(provider) => provider.get(name).await,
+                None => Err(format!("No secret provider for {} references", scheme).into()),
+            },
+        }
+    }
+
+    /// Replaces every reference object in `section`, at any depth, with the secret it names.
+    pub async fn resolve_section(&self, section: &mut Value) -> Result<()> {
+        let mut pending = vec![section];
+        while let Some(value) = pending.pop() {
+            if let Some(reference) = reference(value) {
+                *value = Value::String(self.resolve(&reference).await?);
+                continue;
+            }
+            match value {
+                Value::Object(map) => pending.extend(map.values_mut()),
+                Value::Array(values) => pending.extend(values.iter_mut()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The reference named by an object with the single key `from_file`, `from_env` or
+/// `from_secret`.
+fn reference(value: &Value) -> Option<String> {
+    let map = value.as_object().filter(|map| map.len() == 1)?;
+    let (key, name) = map.iter().next()?;
+    let name = name.as_str()?;
+    match key.as_str() {
+        "from_file" => Some(format!("file:{}", name)),
+        "from_env" => Some(format!("env:{}", name)),
+        "from_secret" => Some(name.to_string()),
+        _ => None,
+    }
+}
+
+async fn environment(name: &str) -> Result<String> {
+    match env::var(name) {
+        Ok(value) => Ok(value),
+        Err(env::VarError::NotPresent) => match env::var(format!("{}_FILE", name)) {
+            Ok(path) => read(&path).await,
+            Err(_) => Err(format!("Neither {} nor {}_FILE is set", name, name).into()),
+        },
+        Err(e) => Err(format!("{} is not valid: {}", name, e).into()),
+    }
+}
+
+async fn read(path: &str) -> Result<String> {
+    let mut contents = async_std::fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("Could not read {}: {}", path, e))?;
+    if contents.ends_with('\n') {
+        contents.pop();
+        if contents.ends_with('\r') {
+            contents.pop();
+        }
+    }
+    Ok(contents)
+}
+
+#[cfg(feature = "vault")]
+pub mod vault {
+    use async_std::net::TcpStream;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use serde_json::Value;
+
+    use super::SecretProvider;
+    use crate::util::Result;
+
+    /// Reads secrets from the KV version 2 engine of HashiCorp Vault, as `vault:<path>#<key>`
+    /// references such as `vault:imap/ldap#bind_password`.
+    ///
+    /// Only `http://` addresses are supported, so Vault should be reached through a local
+    /// agent or a sidecar rather than across the network.
+    pub struct Vault {
+        address: String,
+        host: String,
+        mount: String,
+        token: String,
+    }
+
+    impl Vault {
+        /// Reads from the engine mounted at `secret` of the server at `url`, authenticating
+        /// with `token`.
+        pub fn new(url: &str, token: &str) -> Result<Self> {
+            let host = url
+                .strip_prefix("http://")
+                .ok_or_else(|| format!("Vault URL {} is not an http:// URL", url))?
+                .trim_end_matches('/');
+            if host.is_empty() || host.contains('/') {
+                return Err(format!("Vault URL {} is not a server address", url).into());
+            }
+            let address = match host.rsplit_once(':') {
+                Some((_, port)) if port.parse::<u16>().is_ok() => host.to_string(),
+                _ => format!("{}:8200", host),
+            };
+            Ok(Self {
+                address,
+                host: host.to_string(),
+                mount: "secret".to_string(),
+                token: token.to_string(),
+            })
+        }
+        /// Reads from the KV engine mounted at `mount` instead of `secret`.
+        pub fn with_mount(mut self, mount: &str) -> Self {
+            self.mount = mount.trim_matches('/').to_string();
+            self
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl SecretProvider for Vault {
+        fn scheme(&self) -> &str {
+            "vault"
+        }
+        async fn get(&self, name: &str) -> Result<String> {
+            let (path, key) = name
+                .split_once('#')
+                .ok_or_else(|| format!("Vault reference {} names no key", name))?;
+            // HTTP/1.0, so the body is never chunked and ends when the connection closes.
+            let request = format!(
+                "GET /v1/{}/data/{} HTTP/1.0\r\nHost: {}\r\nX-Vault-Token: {}\r\n\r\n",
+                self.mount,
+                path.trim_matches('/'),
+                self.host,
+                self.token
+            );
+            let mut stream = TcpStream::connect(&self.address).await?;
+            stream.write_all(request.as_bytes()).await?;
+            stream.flush().await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+
+            let (head, body) = response
+                .split_once("\r\n\r\n")
+                .ok_or("Vault sent a malformed response")?;
+            let status = head.lines().next().unwrap_or_default();
+            match status.split(' ').nth(1) {
+                Some(code) if code.starts_with('2') => {}
+                _ => return Err(format!("Vault answered {} for {}", status, path).into()),
+            }
+            let document: Value = serde_json::from_str(body)?;
+            match document["data"]["data"][key].as_str() {
+                Some(secret) => Ok(secret.to_string()),
+                None => Err(format!("Vault secret {} has no key {}", path, key).into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{SecretProvider, Secrets};
+    use crate::util::Result;
+
+    struct Fixed;
+
+    #[async_trait::async_trait]
+    impl SecretProvider for Fixed {
+        fn scheme(&self) -> &str {
+            "fixed"
+        }
+        async fn get(&self, name: &str) -> Result<String> {
+            Ok(format!("secret of {}", name))
+        }
+    }
+
+    #[async_std::test]
+    async fn test_resolve_section() {
+        let path = std::env::temp_dir().join(format!("treasurmap-secrets-{}", std::process::id()));
+        std::fs::write(&path, "from a file\n").unwrap();
+        std::env::set_var("TREASURMAP_TEST_SECRET_FILE", &path);
+        std::env::set_var("TREASURMAP_TEST_TOKEN", "from the environment");
+
+        let secrets = Secrets::new().with_provider(Fixed);
+        let mut section = json!({
+            "url": "ldap://directory",
+            "bind_password": { "from_file": path.to_str().unwrap() },
+            "stores": [{ "secret_key": { "from_env": "TREASURMAP_TEST_SECRET" } }],
+            "admin": { "token": { "from_secret": "fixed:admin" }, "tls": { "port": 993 } },
+        });
+        secrets.resolve_section(&mut section).await.unwrap();
+        assert_eq!(
+            section,
+            json!({
+                "url": "ldap://directory",
+                "bind_password": "from a file",
+                "stores": [{ "secret_key": "from a file" }],
+                "admin": { "token": "secret of admin", "tls": { "port": 993 } },
+            })
+        );
+        assert_eq!(
+            secrets.resolve("env:TREASURMAP_TEST_TOKEN").await.unwrap(),
+            "from the environment"
+        );
+        assert!(secrets.resolve("env:TREASURMAP_TEST_UNSET").await.is_err());
+        assert!(secrets.resolve("vault:imap#password").await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
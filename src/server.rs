@@ -16,33 +16,83 @@
 
 // server.start()
 
-use std::any::Any;
-use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use async_listen::{error_hint, ListenExt};
 use async_std::net::TcpListener;
-use async_std::prelude::*;
-use async_std::task::{spawn, JoinHandle};
 use futures::channel::mpsc::unbounded;
 use futures::future::join_all;
-use log::{info, trace, warn};
+use futures::{AsyncRead, AsyncWrite};
+use tracing::{error, warn};
 
 use crate::auth::inmemory::{InMemoryUserStore, InMemoryAuthenticator};
+use crate::auth::master::{MasterUserAuthenticator, MasterUsers};
+use crate::auth::provision::{Provisioning, ProvisioningAuthenticator};
+use crate::auth::throttle::{Throttle, ThrottledAuthenticator};
+use crate::access::AccessControl;
+use crate::audit::{AuditLog, AuditLogs};
 use crate::auth::{UserStore, Authenticate};
-use crate::connection::{Connection, Request};
-use crate::handlers::Handle;
+use crate::connection::{Connection, Context, Request};
+use crate::domains::{DomainAuthenticator, DomainStore, DomainUserStore, Domains};
+use crate::greeting::Greeting;
+use crate::limits::ConnectionLimits;
+use crate::listener::{Bound, Listener, Shared};
+use crate::metrics::{Counted, Metrics};
+use crate::middleware::{Middleware, Pipeline};
+use crate::plugin::{Capabilities, Extensions, Plugin, PluginError};
+use crate::jmap::{BoundJmap, JmapListener};
+use crate::notify::Notifier;
+use crate::pop3::{BoundPop3, Pop3Listener};
+use crate::replication::Replica;
+#[cfg(unix)]
+use crate::privileges::Privileges;
+use crate::runtime::{spawn, JoinHandle};
+use crate::broadcast::Broadcast;
+use crate::sessions::Sessions;
+use crate::shutdown::Shutdown;
+use crate::slowlog::SlowLog;
+use crate::telemetry::{Exporter, Telemetry, SECTION as TELEMETRY};
+use crate::handlers::{DelegatingCommandHandler, Handle, Workers, DEFAULT_WORKERS};
+use crate::handlers::append::AppendHandler;
+use crate::handlers::capability::{Advertised, CapabilityHandler};
+use crate::handlers::copy::CopyHandler;
+use crate::handlers::expunge::ExpungeHandler;
+use crate::handlers::id::IdHandler;
+use crate::handlers::authenticate::AuthenticateHandler;
 use crate::handlers::fetch::FetchHandler;
 use crate::handlers::login::LoginHandler;
 use crate::handlers::logout::LogoutHandler;
+use crate::handlers::search::SearchHandler;
 use crate::handlers::select::SelectHandler;
+use crate::handlers::store::StoreHandler;
 use crate::index::inmemory::InMemoryIndex;
-use crate::index::Index;
+use crate::index::delivery::MaildirDelivery;
+use crate::index::retention::RetentionPolicy;
+use crate::scheduler::{Job, Scheduler};
+use crate::secrets::Secrets;
+use crate::index::metered::MeteredIndex;
+use crate::index::{Index, Owner};
+use crate::store::inmemory::InMemoryStore;
+use crate::store::name::normalize;
+use crate::store::timed::TimedStore;
+use crate::store::usage::{RecalculateUsage, UserUsage};
+use crate::store::{DataStore, MessageBody};
 use crate::util::{Receiver, Result, Sender};
 
+/// The channel to each command's handler, by command name. Commands with no handler of
+/// their own go to the one under `EXTENSIONS`.
+pub type Handlers = HashMap<String, Sender<Request>>;
+
+/// The key in `Handlers` of the handler for the commands of plugins, which can be registered
+/// while the server runs. No command can be named `*`.
+pub const EXTENSIONS: &str = "*";
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Command {
     tag: String,
@@ -101,11 +151,55 @@ impl ResponseStatus {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// A message body sent as a literal straight from the data store, so that it is never held in
+/// memory whole. The body can only be sent once; clones of a response share it.
+#[derive(Clone)]
+pub struct Literal {
+    length: u64,
+    body: Arc<Mutex<Option<MessageBody>>>,
+}
+
+impl Literal {
+    /// A literal of the first `length` bytes of `body`.
+    pub fn new(length: u64, body: MessageBody) -> Self {
+        Self {
+            length,
+            body: Arc::new(Mutex::new(Some(body))),
+        }
+    }
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+    /// Takes the body to send it, or nothing if it was already taken.
+    pub(crate) fn take(&self) -> Option<MessageBody> {
+        match self.body.lock() {
+            Ok(mut body) => body.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Literal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Literal({} bytes)", self.length)
+    }
+}
+
+/// Literals are only equal to their clones.
+impl PartialEq for Literal {
+    fn eq(&self, other: &Self) -> bool {
+        self.length == other.length && Arc::ptr_eq(&self.body, &other.body)
+    }
+}
+impl Eq for Literal {}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Response {
     tag: String,
     status: Option<ResponseStatus>,
     message: String,
+    /// A literal sent after the message, and the text which follows it.
+    literal: Option<(Literal, String)>,
 }
 
 impl Response {
@@ -114,17 +208,51 @@ impl Response {
             tag: tag.to_string(),
             status: Some(status),
             message: message.to_string(),
+            literal: None,
+        }
+    }
+    /// An untagged (`*`) response without a status, such as `* SEARCH 2 3`.
+    pub fn untagged(message: &str) -> Response {
+        Response {
+            tag: "*".to_string(),
+            status: None,
+            message: message.to_string(),
+            literal: None,
+        }
+    }
+    /// A continuation request (`+`), asking the client for more of a command.
+    pub fn continuation(message: &str) -> Response {
+        Response {
+            tag: "+".to_string(),
+            status: None,
+            message: message.to_string(),
+            literal: None,
         }
     }
+    /// An untagged OK whose text clients must show to the user, such as
+    /// `* OK [ALERT] The server will restart at 22:00 UTC`.
+    pub fn alert(message: &str) -> Response {
+        Response::new("*", ResponseStatus::OK, message).with_code("ALERT")
+    }
+    /// Starts the text of the response with a response code, such as `CLIENTBUG`.
+    pub fn with_code(mut self, code: &str) -> Response {
+        self.message = format!("[{}] {}", code, self.message);
+        self
+    }
+    /// Ends the text of the response with `literal`, whose body is streamed to the client
+    /// after it, followed by `rest`. `* 1 FETCH (BODY[] ` with a literal and a rest of `)` is
+    /// sent as `* 1 FETCH (BODY[] {<length>}`, the body, then `)`.
+    pub fn with_literal(mut self, literal: Literal, rest: &str) -> Response {
+        self.message = format!("{}{{{}}}", self.message, literal.length());
+        self.literal = Some((literal, rest.to_string()));
+        self
+    }
     pub fn from(string: &str) -> std::result::Result<Response, ParseError> {
         let components: Vec<String> = string.split(" ").map(|s| s.to_string()).collect();
         if components.len() < 3 {
             return Err(ParseError {});
         }
-        let status = match ResponseStatus::from(components[1].clone()) {
-            Ok(status) => Some(status),
-            Err(_) => None,
-        };
+        let status = ResponseStatus::from(components[1].clone()).ok();
         Ok(Response {
             tag: components[0].clone(),
             status,
@@ -132,6 +260,7 @@ impl Response {
                 Some(_) => components[2..].join(" "),
                 None => components[1..].join(" "),
             },
+            literal: None,
         })
     }
     pub fn tag(&self) -> String {
@@ -143,13 +272,29 @@ impl Response {
     pub fn message(&self) -> String {
         self.message.clone()
     }
+    /// The literal streamed after the text of the response, and the text which follows it.
+    pub fn literal(&self) -> Option<(&Literal, &str)> {
+        self.literal
+            .as_ref()
+            .map(|(literal, rest)| (literal, rest.as_str()))
+    }
+    /// The number of bytes the response takes on the wire, line endings included.
+    pub(crate) fn size(&self) -> u64 {
+        let status = self.status.map_or(0, |status| status.to_string().len() + 1);
+        let text = self.tag.len() + 1 + status + self.message.len() + 2;
+        let literal = self
+            .literal
+            .as_ref()
+            .map_or(0, |(literal, rest)| literal.length() + rest.len() as u64 + 2);
+        text as u64 + literal
+    }
 }
 
-impl ToString for Response {
-    fn to_string(&self) -> String {
+impl Display for Response {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
         match self.status {
-            Some(status) => format!("{} {} {}", self.tag, status, self.message),
-            None => format!("{} {}", self.tag, self.message),
+            Some(status) => write!(f, "{} {} {}", self.tag, status, self.message),
+            None => write!(f, "{} {}", self.tag, self.message),
         }
     }
 }
@@ -165,124 +310,403 @@ impl Error for ParseError {}
 
 impl Command {
     pub fn parse(cmd: &str) -> std::result::Result<Command, ParseError> {
-        let mut values: VecDeque<String> = cmd.split(" ").map(|s| s.to_string()).collect();
-        let tag = match values.pop_front() {
-            Some(t) => t,
+        // Each part is copied out of the line once, straight into the command.
+        let mut values = cmd.split(' ');
+        let tag = match values.next() {
+            Some(t) => t.to_string(),
             None => return Err(ParseError {}),
         };
-        let command = match values.pop_front() {
-            Some(c) => c,
+        let command = match values.next() {
+            Some(c) => c.to_string(),
             None => return Err(ParseError {}),
         };
-        values = values
-            .iter()
+        let args = values
             .map(|arg| {
                 let length = arg.len();
-                if arg.starts_with("\"") && arg.ends_with("\"")
-                    || arg.starts_with("'") && arg.ends_with("'")
+                if length > 1
+                    && (arg.starts_with('"') && arg.ends_with('"')
+                        || arg.starts_with('\'') && arg.ends_with('\''))
                 {
                     return arg[1..length - 1].to_string();
                 }
-                return arg.to_string();
+                arg.to_string()
             })
             .collect();
-        Ok(Command {
-            tag,
-            command,
-            args: Vec::from(values),
-        })
+        Ok(Command { tag, command, args })
     }
 }
 
-pub struct ServerConfiguration {
-    address: String,
-    max_connections: usize,
-    error_timeout: Duration,
-}
-
+/// Server-wide settings.
 pub struct Configuration {
-    server: ServerConfiguration,
+    listeners: Vec<Listener>,
+    metrics: Option<String>,
+    #[cfg(unix)]
+    control: Option<PathBuf>,
+    sections: HashMap<String, serde_json::Value>,
+    secrets: Secrets,
+    workers: usize,
+    telemetry: Option<Telemetry>,
+    #[cfg(unix)]
+    privileges: Option<Privileges>,
 }
 
-impl Default for ServerConfiguration {
+impl Default for Configuration {
     fn default() -> Self {
-        ServerConfiguration {
-            address: "127.0.0.1:3143".to_string(),
-            max_connections: 100,
-            error_timeout: Duration::from_millis(500),
+        Configuration {
+            listeners: vec![Listener::tcp("127.0.0.1:3143")],
+            metrics: None,
+            #[cfg(unix)]
+            control: None,
+            sections: HashMap::new(),
+            secrets: Secrets::new(),
+            workers: DEFAULT_WORKERS,
+            telemetry: None,
+            #[cfg(unix)]
+            privileges: None,
         }
     }
 }
 
-impl Default for Configuration {
-    fn default() -> Self {
-        Configuration {
-            server: ServerConfiguration::default(),
-        }
+impl Configuration {
+    pub fn with_listeners(mut self, listeners: Vec<Listener>) -> Self {
+        self.listeners = listeners;
+        self
+    }
+    /// Serves Prometheus metrics over HTTP at `/metrics` on `address`, such as
+    /// `127.0.0.1:9143`.
+    pub fn with_metrics(mut self, address: &str) -> Self {
+        self.metrics = Some(address.to_string());
+        self
+    }
+    /// Exports traces and metrics to an OpenTelemetry collector, unless a `telemetry`
+    /// section is set. See the `telemetry` module.
+    pub fn with_telemetry(mut self, telemetry: Telemetry) -> Self {
+        self.telemetry = Some(telemetry);
+        self
+    }
+    /// Answers requests to list and terminate sessions on the Unix socket at `path`, which is
+    /// replaced if it exists. Only its owner, the user the server binds as, may connect to it.
+    /// See the `sessions` module for what it accepts.
+    #[cfg(unix)]
+    pub fn with_control_socket<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.control = Some(path.into());
+        self
+    }
+    /// Drops to `privileges` once the listeners are bound, before the stores are used.
+    #[cfg(unix)]
+    pub fn with_privileges(mut self, privileges: Privileges) -> Self {
+        self.privileges = Some(privileges);
+        self
+    }
+    /// Sets the configuration of the plugin called `name`.
+    pub fn with_section(mut self, name: &str, section: serde_json::Value) -> Self {
+        self.sections.insert(name.to_string(), section);
+        self
+    }
+    /// Resolves the secret references in the plugin sections with `secrets`, which may have
+    /// providers such as Vault registered. Environment and file references are resolved
+    /// without it.
+    pub fn with_secrets(mut self, secrets: Secrets) -> Self {
+        self.secrets = secrets;
+        self
+    }
+    /// Works on at most `limit` FETCH, SEARCH, STORE, COPY and extension commands at once,
+    /// across every connection, rather than 256.
+    pub fn with_workers(mut self, limit: usize) -> Self {
+        self.workers = limit;
+        self
     }
 }
 
 pub struct Server {
-    config: Configuration,
-    listener: TcpListener,
-    handler: Arc<HashMap<String, Sender<Request>>>,
-    _user_store: Arc<Box<dyn UserStore>>,
-    _index: Arc<Box<dyn Index>>,
+    listeners: Vec<Bound>,
+    handler: Arc<Handlers>,
+    user_store: Arc<Box<dyn UserStore>>,
+    index: Arc<Box<dyn Index>>,
+    data_store: Arc<Box<dyn DataStore>>,
     handler_tasks: Vec<JoinHandle<Result<()>>>,
+    shutdown: Shutdown,
+    broadcast: Broadcast,
+    sessions: Sessions,
+    drain_timeout: Duration,
+    limits: Arc<ConnectionLimits>,
+    access: AccessControl,
+    metrics: Arc<Metrics>,
+    metrics_listener: Option<TcpListener>,
+    #[cfg(unix)]
+    control_listener: Option<async_std::os::unix::net::UnixListener>,
+    audit: Option<Arc<dyn AuditLog>>,
+    slow_log: Option<SlowLog>,
+    telemetry: Option<Exporter>,
+    /// What connections are told the server can do, in their greeting.
+    capabilities: Advertised,
+    greeting: Greeting,
+    middleware: Pipeline,
+    extensions: Extensions,
+    delivery: Option<MaildirDelivery>,
+    pop3: Vec<BoundPop3>,
+    jmap: Vec<BoundJmap>,
+    notifier: Option<Notifier>,
+    scheduler: Scheduler,
+    usage: RecalculateUsage,
+    components: Components,
 }
 
 impl Server {
+    /// The store the server authenticates against, for managing accounts while it runs.
+    pub fn user_store(&self) -> Arc<Box<dyn UserStore>> {
+        self.user_store.clone()
+    }
+    /// A handle which stops the server once triggered: listening stops, every connection is
+    /// sent `* BYE Server shutting down` and given the drain timeout to finish its commands,
+    /// and `listen` returns when the handlers have stopped.
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+    /// A handle for sending untagged responses to every open connection, such as an
+    /// `* OK [ALERT]` warning of planned maintenance.
+    pub fn broadcast(&self) -> Broadcast {
+        self.broadcast.clone()
+    }
+    /// A handle on the open sessions, for listing them and terminating them with `* BYE`.
+    pub fn sessions(&self) -> Sessions {
+        self.sessions.clone()
+    }
+    /// The connection limits shared by every listener, with counters of the connections
+    /// open, accepted and rejected.
+    pub fn connection_limits(&self) -> Arc<ConnectionLimits> {
+        self.limits.clone()
+    }
+    /// A handle on the access policy of every listener and of logins, for reloading it while
+    /// the server runs.
+    pub fn access_control(&self) -> AccessControl {
+        self.access.clone()
+    }
+    /// Recomputes the storage used by `user`, or by every user, from the data store and
+    /// corrects the usage quotas are checked against. See `store::usage`.
+    pub async fn recalculate_usage(&self, user: Option<&str>) -> Result<Vec<UserUsage>> {
+        self.usage
+            .recalculate_in(&self.components, &self.metrics, user)
+            .await
+    }
+    /// The metrics of the server, which are also served over HTTP when the configuration
+    /// asks for it.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+    /// The plugins of the server, for registering more while it runs.
+    pub fn extensions(&self) -> Extensions {
+        self.extensions.clone()
+    }
+    /// The address metrics are served on, if they are.
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_listener
+            .as_ref()
+            .and_then(|listener| listener.local_addr().ok())
+    }
+    /// The TCP addresses IMAP is served on.
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .flat_map(|listener| listener.local_addrs())
+            .collect()
+    }
+    /// The addresses POP3 is served on.
+    pub fn pop3_addresses(&self) -> Vec<SocketAddr> {
+        self.pop3
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect()
+    }
+    /// The addresses JMAP is served on.
+    pub fn jmap_addresses(&self) -> Vec<SocketAddr> {
+        self.jmap
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect()
+    }
+    /// Serves one connection over `stream` with the default listener settings, returning
+    /// once it closes. The server need not be listening, so tests and embedders can drive it
+    /// over in-memory pipes or streams they accepted themselves.
+    pub async fn serve_stream<S>(&self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let _active = self.metrics.connection();
+        let stream = Counted::new(stream, self.metrics.clone());
+        let mut connection = Connection::from_duplex(stream, Context::default())
+            .await?
+            .with_shutdown(self.shutdown.clone())
+            .with_broadcast(&self.broadcast)
+            .with_sessions(&self.sessions)
+            .with_capabilities(self.capabilities.clone())
+            .with_greeting(self.greeting.clone())
+            .with_metrics(self.metrics.clone())
+            .with_middleware(self.middleware.clone());
+        if let Some(audit) = &self.audit {
+            connection = connection.with_audit_log(audit.clone());
+        }
+        if let Some(slow_log) = self.slow_log {
+            connection = connection.with_slow_log(slow_log);
+        }
+        connection.handle(self.handler.clone()).await
+    }
     pub async fn listen(self) -> Result<()> {
-        trace!("Server starting on {}", &self.config.server.address);
-        let mut incoming = self
-            .listener
-            .incoming()
-            .log_warnings(|e| {
-                warn!(
-                    "An error ocurred while accepting a new connection {}. {}",
-                    e,
-                    error_hint(&e)
-                )
-            })
-            .handle_errors(self.config.server.error_timeout)
-            .backpressure(self.config.server.max_connections);
-        info!(
-            "Server started listening on {}",
-            &self.config.server.address
+        if let Some(listener) = self.metrics_listener {
+            spawn(crate::metrics::serve(listener, self.metrics.clone(), self.shutdown.clone()));
+        }
+        #[cfg(unix)]
+        if let Some(listener) = self.control_listener {
+            let control = crate::sessions::Control {
+                sessions: self.sessions.clone(),
+                usage: self.usage.clone(),
+                components: self.components.clone(),
+                metrics: self.metrics.clone(),
+            };
+            spawn(crate::sessions::serve_control(listener, control, self.shutdown.clone()));
+        }
+        if let Some(delivery) = self.delivery {
+            let (store, index) = (self.data_store.clone(), self.index.clone());
+            spawn(delivery.watch(store, index, self.shutdown.clone()));
+        }
+        if let Some(notifier) = self.notifier {
+            spawn(notifier.run(self.components.clone(), self.shutdown.clone()));
+        }
+        let pop3 = self.pop3.into_iter().map(|listener| {
+            spawn(listener.serve(self.components.clone(), self.shutdown.clone()))
+        });
+        let jmap = self.jmap.into_iter().map(|listener| {
+            spawn(listener.serve(self.components.clone(), self.shutdown.clone()))
+        });
+        let frontends = join_all(pop3.chain(jmap).collect::<Vec<_>>());
+        let jobs = self.scheduler.run(
+            self.components.clone(),
+            self.metrics.clone(),
+            self.shutdown.clone(),
         );
-
-        let mut connections = vec![];
-        while let Some((token, socket)) = incoming.next().await {
-            trace!("New connection from {}", &socket.peer_addr()?);
-            let handler = self.handler.clone();
-            connections.push(spawn(async move {
-                let _holder = token;
-                trace!(
-                    "Spawning handler for new connection from {}",
-                    &socket.peer_addr()?
-                );
-                let connection = Connection::new(socket).await?;
-                connection.handle(handler).await
-            }));
-        }
-        join_all(connections).await;
+        let shared = Shared {
+            handlers: self.handler.clone(),
+            shutdown: self.shutdown.clone(),
+            broadcast: self.broadcast.clone(),
+            sessions: self.sessions.clone(),
+            drain: self.drain_timeout,
+            limits: self.limits.clone(),
+            access: self.access.clone(),
+            metrics: self.metrics.clone(),
+            audit: self.audit.clone(),
+            slow_log: self.slow_log,
+            capabilities: self.capabilities.clone(),
+            greeting: self.greeting.clone(),
+            middleware: self.middleware.clone(),
+        };
+        let listeners = self.listeners.into_iter().map(|listener| {
+            let shared = shared.clone();
+            spawn(async move {
+                if let Err(e) = listener.serve(shared).await {
+                    error!("A listener stopped accepting connections: {}", e);
+                }
+            })
+        });
+        let mut telemetry = self.telemetry;
+        let (metrics, shutdown) = (self.metrics.clone(), self.shutdown.clone());
+        let exporting = async {
+            if let Some(exporter) = telemetry.as_mut() {
+                exporter.run(&metrics, &shutdown).await;
+            }
+        };
+        futures::join!(join_all(listeners.collect::<Vec<_>>()), frontends, jobs, exporting);
+        // Handlers stop once every channel to them is closed.
+        drop(shared);
+        drop(self.handler);
         join_all(self.handler_tasks).await;
+        // The spans of connections drained on shutdown go out last.
+        if let Some(exporter) = telemetry.as_mut() {
+            exporter.export(&metrics).await;
+        }
         Ok(())
     }
 }
 
+/// The stores and authenticator a server is built with, for handlers which need them.
+#[derive(Clone)]
+pub struct Components {
+    pub index: Arc<Box<dyn Index>>,
+    pub data_store: Arc<Box<dyn DataStore>>,
+    pub user_store: Arc<Box<dyn UserStore>>,
+    pub authenticator: Arc<Box<dyn Authenticate>>,
+}
+
+impl Components {
+    /// Every mailbox of every user: INBOX, which each user has whether or not it has been
+    /// created, and those the data store has. Users whose mailboxes cannot be listed are
+    /// logged and skipped.
+    pub(crate) async fn mailboxes(&self) -> Vec<(Owner, String)> {
+        let users = match self.user_store.list().await {
+            Ok(users) => users,
+            Err(e) => {
+                warn!("Could not list users to find their mailboxes: {}", e);
+                return vec![];
+            }
+        };
+        let mut mailboxes = vec![];
+        for user in users {
+            let owner = Owner::new(&user);
+            mailboxes.push((owner.clone(), "INBOX".to_string()));
+            match self.data_store.mailboxes(&user).await {
+                Ok(names) => mailboxes.extend(
+                    names
+                        .into_iter()
+                        .filter(|name| normalize(name) != "INBOX")
+                        .map(|name| (owner.clone(), name)),
+                ),
+                Err(e) => warn!("Could not list the mailboxes of {}: {}", user, e),
+            }
+        }
+        mailboxes
+    }
+}
+
+type HandlerFactory = Box<dyn FnOnce(&Components) -> Box<dyn Handle> + Send>;
+
 pub struct ServerBuilder {
     user_store: Option<Box<dyn UserStore>>,
-    // TODO: replace with DataStore trait
-    data_store: Option<Box<dyn Any>>,
+    data_store: Option<Box<dyn DataStore>>,
     index: Option<Box<dyn Index>>,
-    // TODO: replace with Middleware trait
-    middleware: Vec<Box<dyn Any>>,
+    middleware: Vec<Box<dyn Middleware>>,
     handlers: HashMap<String, Box<dyn Handle>>,
+    factories: Vec<HandlerFactory>,
+    command_handler: Option<DelegatingCommandHandler>,
+    plugins: Vec<Box<dyn Plugin>>,
+    delivery: Option<MaildirDelivery>,
+    pop3: Vec<Pop3Listener>,
+    jmap: Vec<JmapListener>,
+    notifier: Option<Notifier>,
+    scheduler: Scheduler,
+    /// How often usage is recalculated, if it is scheduled, and the pause between mailboxes.
+    usage_recalculation: (Option<Duration>, Duration),
     authenticator: Option<Box<dyn Authenticate>>,
+    domains: Domains,
+    provisioning: Option<Provisioning>,
+    throttle: Option<Throttle>,
+    master_users: Option<MasterUsers>,
+    anonymous: Option<String>,
+    require_tls: bool,
+    listeners: Vec<Listener>,
+    drain_timeout: Duration,
+    limits: Option<ConnectionLimits>,
+    access: AccessControl,
+    audit: Option<Arc<dyn AuditLog>>,
+    slow_log: Option<SlowLog>,
+    greeting: Greeting,
     configuration: Option<Configuration>,
 }
 
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ServerBuilder {
     #[must_use]
     pub fn new() -> Self {
@@ -292,7 +716,29 @@ impl ServerBuilder {
             index: None,
             middleware: vec![],
             handlers: HashMap::new(),
+            factories: vec![],
+            command_handler: None,
+            plugins: vec![],
+            delivery: None,
+            pop3: vec![],
+            jmap: vec![],
+            notifier: None,
+            scheduler: Scheduler::new(),
+            usage_recalculation: (None, Duration::from_millis(50)),
             authenticator: None,
+            domains: Domains::new(),
+            provisioning: None,
+            throttle: None,
+            master_users: None,
+            anonymous: None,
+            require_tls: false,
+            listeners: vec![],
+            drain_timeout: Duration::from_secs(30),
+            limits: None,
+            access: AccessControl::default(),
+            audit: None,
+            slow_log: None,
+            greeting: Greeting::default(),
             configuration: None,
         }
     }
@@ -300,8 +746,7 @@ impl ServerBuilder {
         self.user_store.replace(Box::new(user_store));
         self
     }
-    // TODO: replace with DataStore trait
-    pub fn with_data_store<D: Any>(mut self, data_store: D) -> Self {
+    pub fn with_data_store<D: DataStore + 'static>(mut self, data_store: D) -> Self {
         self.data_store.replace(Box::new(data_store));
         self
     }
@@ -313,14 +758,173 @@ impl ServerBuilder {
         self.authenticator.replace(Box::new(authenticator));
         self
     }
-    // TODO: replace with Middleware trait
-    pub fn with_middleware<M: Any>(mut self, middleware: M) -> Self {
+    /// Hosts `domains`, each of which may have its own data store, user store, authenticator
+    /// and quota. See `domains`.
+    pub fn with_domains(mut self, domains: Domains) -> Self {
+        self.domains = domains;
+        self
+    }
+    /// Replaces the default brute-force protection for logins, over IMAP, POP3 and JMAP alike.
+    pub fn with_throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle.replace(throttle);
+        self
+    }
+    /// Creates the mailboxes of `provisioning` for each user when they first log in. See
+    /// `ProvisioningAuthenticator`.
+    pub fn with_provisioning(mut self, provisioning: Provisioning) -> Self {
+        self.provisioning.replace(provisioning);
+        self
+    }
+    /// Lets these users log in as anyone else. See `MasterUserAuthenticator`.
+    pub fn with_master_users(mut self, master_users: MasterUsers) -> Self {
+        self.master_users.replace(master_users);
+        self
+    }
+    /// Offers AUTHENTICATE ANONYMOUS, giving guests read-only access to the mailboxes of
+    /// `namespace`.
+    pub fn with_anonymous(mut self, namespace: &str) -> Self {
+        self.anonymous.replace(namespace.to_string());
+        self
+    }
+    /// Greets connections, and answers ID, as `greeting` says. See `greeting`.
+    pub fn with_greeting(mut self, greeting: Greeting) -> Self {
+        self.greeting = greeting;
+        self
+    }
+    /// Refuses LOGIN, and AUTHENTICATE with mechanisms which send a password, until the
+    /// connection is encrypted, advertising LOGINDISABLED until then. POP3 and JMAP listeners
+    /// without implicit TLS refuse logins altogether.
+    pub fn with_require_tls(mut self, require_tls: bool) -> Self {
+        self.require_tls = require_tls;
+        self
+    }
+    /// Runs every command through `middleware` on its way to its handler. Middleware added
+    /// first sees requests first and responses last.
+    pub fn with_middleware<M: Middleware + 'static>(mut self, middleware: M) -> Self {
         self.middleware.push(Box::new(middleware));
         self
     }
+    /// Handles the command `handler` is for with it, in place of any default handler.
     pub fn with_handler<H: Handle + 'static>(mut self, handler: H) -> Self {
         self.handlers
-            .insert(handler.command().clone().to_string(), Box::new(handler));
+            .insert(handler.command().to_string(), Box::new(handler));
+        self
+    }
+    /// Like `with_handler`, for handlers built from the stores and authenticator the server
+    /// ends up with, which are only known once it is bound.
+    pub fn with_handler_from<H, F>(mut self, factory: F) -> Self
+    where
+        H: Handle + 'static,
+        F: FnOnce(&Components) -> H + Send + 'static,
+    {
+        self.factories
+            .push(Box::new(move |components| Box::new(factory(components))));
+        self
+    }
+    /// Handles each command registered with `handler` with it, unless another handler was
+    /// added for that command.
+    pub fn with_command_handler(mut self, handler: DelegatingCommandHandler) -> Self {
+        self.command_handler.replace(handler);
+        self
+    }
+    /// Adds the commands and capabilities of `plugin`, configured by its section of the
+    /// configuration. Binding fails if it adds a command which is already handled.
+    pub fn with_plugin<P: Plugin + 'static>(mut self, plugin: P) -> Self {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+    /// Adds the messages an external MDA delivers into the Maildirs of `delivery` to the
+    /// data store and index while the server listens.
+    pub fn with_maildir_delivery(mut self, delivery: MaildirDelivery) -> Self {
+        self.delivery = Some(delivery);
+        self
+    }
+    /// Serves the INBOX of each user over POP3 on `listener`, with the same stores and
+    /// authenticator as IMAP.
+    pub fn with_pop3_listener(mut self, listener: Pop3Listener) -> Self {
+        self.pop3.push(listener);
+        self
+    }
+    /// Serves JMAP over HTTP on `listener`, with the same stores and authenticator as IMAP.
+    /// The JMAP frontend is experimental and read-only.
+    pub fn with_jmap_listener(mut self, listener: JmapListener) -> Self {
+        self.jmap.push(listener);
+        self
+    }
+    /// Expunges old messages from the folders of `policy`, such as Trash and Junk, while the
+    /// server listens.
+    pub fn with_retention_policy(self, policy: RetentionPolicy) -> Self {
+        let interval = policy.interval();
+        self.with_job(policy, interval)
+    }
+    /// Ships the changes to every mailbox to the standby of `replica` while the server
+    /// listens. See `replication`.
+    pub fn with_replication(self, replica: Replica) -> Self {
+        let interval = replica.interval();
+        self.with_job(replica, interval)
+    }
+    /// Runs `job` every `interval` while the server listens. See `Scheduler`.
+    pub fn with_job<J: Job + 'static>(mut self, job: J, interval: Duration) -> Self {
+        self.scheduler = self.scheduler.with_job(job, interval);
+        self
+    }
+    /// Recalculates the storage used by every user every `interval`, correcting the usage
+    /// quotas are checked against, and waits `pause` after each mailbox. Usage can also be
+    /// recalculated when asked, with `Server::recalculate_usage` or over the control socket.
+    /// See `store::usage`.
+    pub fn with_usage_recalculation(mut self, interval: Duration, pause: Duration) -> Self {
+        self.usage_recalculation = (Some(interval), pause);
+        self
+    }
+    /// How far the waits between runs of jobs may stray from their intervals. See
+    /// `Scheduler::with_jitter`.
+    pub fn with_job_jitter(mut self, jitter: f64) -> Self {
+        self.scheduler = self.scheduler.with_jitter(jitter);
+        self
+    }
+    /// Publishes the changes to every mailbox to the sinks of `notifier` while the server
+    /// listens.
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+    /// Accepts connections on `listener`. Listeners added this way replace those in the
+    /// configuration.
+    pub fn with_listener(mut self, listener: Listener) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+    /// How long connections may take to finish their commands when the server shuts down.
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+    /// Turns away clients over `limits`, which apply across every listener.
+    pub fn with_connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+    /// Turns away clients which `access` does not let connect with `* BYE`, and refuses
+    /// logins it does not authorize as though the password were wrong. Keep a clone to reload
+    /// the policy later, or use `Server::access_control`.
+    pub fn with_access_control(mut self, access: AccessControl) -> Self {
+        self.access = access;
+        self
+    }
+    /// Records logins, logouts and changes to mailboxes in `audit`, as well as in any audit
+    /// logs given before, such as an `AuthFailureLog` for fail2ban next to a full log.
+    pub fn with_audit_log<A: AuditLog + 'static>(mut self, audit: A) -> Self {
+        let audit: Arc<dyn AuditLog> = Arc::new(audit);
+        self.audit = Some(match self.audit.take() {
+            Some(before) => Arc::new(AuditLogs::new().with_log(before).with_log(audit)),
+            None => audit,
+        });
+        self
+    }
+    /// Logs commands which take longer or answer with more than `slow_log` allows, with
+    /// where their time went. See the `slowlog` module.
+    pub fn with_slow_log(mut self, slow_log: SlowLog) -> Self {
+        self.slow_log = Some(slow_log);
         self
     }
     pub fn with_configuration(mut self, configuration: Configuration) -> Self {
@@ -328,26 +932,184 @@ impl ServerBuilder {
         self
     }
     pub async fn bind(mut self) -> Result<Server> {
-        let configuration = self.configuration.unwrap_or_else(Configuration::default);
-        let listener = TcpListener::bind(&configuration.server.address).await?;
+        let configuration = self.configuration.unwrap_or_default();
+        let listeners = match self.listeners.is_empty() {
+            true => configuration.listeners,
+            false => self.listeners,
+        };
+        let mut bound = vec![];
+        for listener in listeners {
+            bound.push(listener.bind().await?);
+        }
+        let mut pop3 = vec![];
+        for listener in self.pop3 {
+            pop3.push(listener.with_require_tls(self.require_tls).bind().await?);
+        }
+        let mut jmap = vec![];
+        for listener in self.jmap {
+            jmap.push(listener.with_require_tls(self.require_tls).bind().await?);
+        }
+        let metrics_listener = match &configuration.metrics {
+            Some(address) => Some(TcpListener::bind(address).await?),
+            None => None,
+        };
+        #[cfg(unix)]
+        let control_listener = match &configuration.control {
+            Some(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+                // Anyone who can connect may list and terminate sessions, so the socket is
+                // created for its owner alone rather than made so once others could connect.
+                let umask = unsafe { libc::umask(0o077) };
+                let listener = std::os::unix::net::UnixListener::bind(path);
+                unsafe { libc::umask(umask) };
+                Some(async_std::os::unix::net::UnixListener::from(listener?))
+            }
+            None => None,
+        };
+        let telemetry = match configuration.sections.get(TELEMETRY) {
+            Some(section) => {
+                let mut section = section.clone();
+                configuration.secrets.resolve_section(&mut section).await?;
+                Some(Telemetry::from_section(&section)?)
+            }
+            None => configuration.telemetry,
+        };
+        let telemetry = telemetry.map(Telemetry::start).transpose()?;
+        #[cfg(unix)]
+        if let Some(privileges) = &configuration.privileges {
+            privileges.drop()?;
+        }
+        let metrics = Arc::new(Metrics::new());
         
-        let user_store = Arc::new(self.user_store
+        let mut user_store = Arc::new(self.user_store
                     .unwrap_or_else(|| Box::new(InMemoryUserStore::new())));
-        let index = Arc::new(self.index.unwrap_or_else(|| Box::new(InMemoryIndex::new())));
-        let authenticator = Arc::new(self.authenticator.unwrap_or_else(|| Box::new(InMemoryAuthenticator::new(user_store.clone()))));
-        
-        // TODO: add default Handlers for IMAPv2rev4 spec (i.e. Login, Select, Fetch, Logout, etc.)
-        let select = Box::new(SelectHandler::new(index.clone()));
-        let login: Box<dyn Handle> = Box::new(LoginHandler::new(authenticator));
-        let fetch = Box::new(FetchHandler{});
-        let logout = Box::new(LogoutHandler{});
-        self.handlers.insert("LOGIN".to_string(), login);
-        self.handlers.insert("SELECT".to_string(), select);
-        self.handlers.insert("FETCH".to_string(), fetch);
-        self.handlers.insert("LOGOUT".to_string(), logout);
-        
+        let index = self.index.unwrap_or_else(|| Box::new(InMemoryIndex::new()));
+        let index: Arc<Box<dyn Index>> = Arc::new(Box::new(MeteredIndex::new(index, metrics.clone())));
+        let mut data_store = Arc::new(self.data_store.unwrap_or_else(|| Box::new(InMemoryStore::new())));
+        if !self.domains.is_empty() {
+            user_store = Arc::new(Box::new(DomainUserStore::new(user_store, self.domains.clone())));
+            data_store = Arc::new(Box::new(DomainStore::new(data_store, self.domains.clone())));
+        }
+        let data_store: Arc<Box<dyn DataStore>> = Arc::new(Box::new(TimedStore::new(data_store)));
+        let mut authenticator = self.authenticator.unwrap_or_else(|| Box::new(InMemoryAuthenticator::new(user_store.clone())));
+        let (usage_interval, usage_pause) = self.usage_recalculation;
+        let usage = RecalculateUsage::new(self.domains.usage()).with_pause(usage_pause);
+        if let Some(interval) = usage_interval {
+            self.scheduler = self.scheduler.with_job(usage.clone(), interval);
+        }
+        if !self.domains.is_empty() {
+            authenticator = Box::new(DomainAuthenticator::new(authenticator, self.domains));
+        }
+        if let Some(master_users) = self.master_users {
+            authenticator = Box::new(MasterUserAuthenticator::new(authenticator, user_store.clone(), master_users));
+        }
+        if let Some(provisioning) = self.provisioning {
+            authenticator = Box::new(ProvisioningAuthenticator::new(authenticator, index.clone(), provisioning));
+        }
+        let authenticator = ThrottledAuthenticator::new(authenticator, self.throttle.unwrap_or_default())
+            .with_access_control(self.access.clone());
+        let components = Components {
+            index: index.clone(),
+            data_store: data_store.clone(),
+            user_store: user_store.clone(),
+            authenticator: Arc::new(Box::new(authenticator)),
+        };
+
+        // Handlers added to the builder come first, then the command handler, and the
+        // defaults handle whatever commands are left.
+        for factory in self.factories {
+            let handler = factory(&components);
+            self.handlers.insert(handler.command().to_string(), handler);
+        }
+        let workers = Workers::new(configuration.workers);
+        let mut command_handler = self
+            .command_handler
+            .unwrap_or_default()
+            .with_workers(workers.clone());
+        let delegated = command_handler.commands().await;
+        let mut capabilities = vec!["IMAP4rev2", "ID"];
+        if self.anonymous.is_some() {
+            capabilities.push("AUTH=ANONYMOUS");
+        }
+        let capabilities = Capabilities::new(capabilities);
+        let advertised = Advertised::new(capabilities.clone()).with_require_tls(self.require_tls);
+        let mut defaults: Vec<Box<dyn Handle>> = vec![
+            Box::new(
+                LoginHandler::new(components.authenticator.clone())
+                    .with_require_tls(self.require_tls)
+                    .with_capabilities(advertised.clone()),
+            ),
+            Box::new(SelectHandler::new(index.clone())),
+            Box::new(SelectHandler::examine(index.clone())),
+            Box::new(
+                FetchHandler::new(index.clone(), data_store.clone()).with_workers(workers.clone()),
+            ),
+            Box::new(
+                SearchHandler::new(index.clone(), data_store.clone()).with_workers(workers.clone()),
+            ),
+            Box::new(StoreHandler::new(index.clone()).with_workers(workers.clone())),
+            Box::new(
+                CopyHandler::new(index.clone(), data_store.clone()).with_workers(workers.clone()),
+            ),
+            Box::new(
+                AppendHandler::new(index.clone(), data_store.clone())
+                    .with_workers(workers.clone()),
+            ),
+            Box::new(
+                ExpungeHandler::new(index.clone(), data_store.clone())
+                    .with_workers(workers.clone()),
+            ),
+            Box::new(
+                ExpungeHandler::uid(index.clone(), data_store.clone())
+                    .with_workers(workers.clone()),
+            ),
+            Box::new(LogoutHandler{}),
+            Box::new(IdHandler::new(self.greeting.clone())),
+        ];
+        let mut authenticate = AuthenticateHandler::new()
+            .with_require_tls(self.require_tls)
+            .with_access_control(self.access.clone())
+            .with_capabilities(advertised.clone());
+        if let Some(namespace) = &self.anonymous {
+            authenticate = authenticate.with_anonymous(namespace);
+        }
+        defaults.push(Box::new(authenticate));
+        defaults.push(Box::new(
+            CapabilityHandler::new(capabilities.clone()).with_require_tls(self.require_tls),
+        ));
+        for handler in defaults {
+            let command = handler.command().to_string();
+            if !delegated.contains(&command) {
+                self.handlers.entry(command).or_insert(handler);
+            }
+        }
+
+        let reserved = self.handlers.keys().cloned().collect();
+        let extensions = Extensions::new(command_handler.clone(), capabilities, reserved);
+        for mut plugin in self.plugins {
+            if let Some(section) = configuration.sections.get(plugin.name()) {
+                let name = plugin.name().to_string();
+                let configuration_error = |e: Box<dyn Error + Send + Sync>| {
+                    PluginError::Configuration(name.clone(), e.to_string())
+                };
+                let mut section = section.clone();
+                configuration
+                    .secrets
+                    .resolve_section(&mut section)
+                    .await
+                    .map_err(configuration_error)?;
+                plugin.configure(&section).map_err(configuration_error)?;
+            }
+            extensions.register_boxed(plugin).await?;
+        }
+
         let mut handler_tasks = vec![];
-        let handlers: HashMap<String, Sender<Request>> = self
+        let mut handlers: HashMap<String, Sender<Request>> = self
             .handlers
             .drain()
             .map(|(key, mut handler)| {
@@ -356,14 +1118,44 @@ impl ServerBuilder {
                 (key, sender)
             })
             .collect();
+        let (sender, requests): (Sender<Request>, Receiver<Request>) = unbounded();
+        handler_tasks.push(spawn(async move { command_handler.start(requests).await }));
+        for command in delegated {
+            handlers.entry(command).or_insert_with(|| sender.clone());
+        }
+        handlers.insert(EXTENSIONS.to_string(), sender);
 
         Ok(Server {
-            config: configuration,
-            listener,
+            listeners: bound,
             handler: Arc::new(handlers),
             handler_tasks,
-            _user_store: user_store,
-            _index: index,
+            user_store,
+            index,
+            data_store,
+            shutdown: Shutdown::new(),
+            broadcast: Broadcast::new(),
+            sessions: Sessions::new(),
+            drain_timeout: self.drain_timeout,
+            limits: Arc::new(self.limits.unwrap_or_default()),
+            access: self.access,
+            metrics,
+            metrics_listener,
+            #[cfg(unix)]
+            control_listener,
+            audit: self.audit,
+            slow_log: self.slow_log,
+            telemetry,
+            capabilities: advertised,
+            greeting: self.greeting,
+            middleware: Pipeline::new(self.middleware),
+            extensions,
+            delivery: self.delivery,
+            pop3,
+            jmap,
+            notifier: self.notifier,
+            scheduler: self.scheduler,
+            usage,
+            components,
         })
     }
     pub async fn listen(self) -> Result<()> {
@@ -374,7 +1166,145 @@ impl ServerBuilder {
 
 #[cfg(test)]
 mod tests {
-    use super::Command;
+    use futures::channel::mpsc::unbounded;
+    use futures::{SinkExt, StreamExt};
+
+    use super::{Command, Configuration, Response, ResponseStatus, Server, ServerBuilder};
+    use crate::connection::{Context, Request};
+    use crate::handlers::select::SelectHandler;
+    use crate::handlers::{DelegatingCommandHandler, Handle, HandleCommand};
+    use crate::util::{Receiver, Result};
+
+    /// Answers LOGOUT without the BYE of the default handler.
+    struct QuietLogout;
+
+    #[async_trait::async_trait]
+    impl Handle for QuietLogout {
+        fn command<'a>(&self) -> &'a str {
+            "LOGOUT"
+        }
+        async fn start<'a>(&'a mut self, mut requests: Receiver<Request>) -> Result<()> {
+            while let Some(mut request) = requests.next().await {
+                let tag = request.command.tag();
+                let responses = vec![Response::new(&tag, ResponseStatus::OK, "Quietly")];
+                request.responder.send(responses).await?;
+            }
+            Ok(())
+        }
+    }
+
+    struct Noop;
+
+    #[async_trait::async_trait]
+    impl HandleCommand for Noop {
+        fn name<'a>(&self) -> &'a str {
+            "NOOP"
+        }
+        async fn validate<'a>(&self, _: &'a Command) -> Result<()> {
+            Ok(())
+        }
+        async fn handle<'a>(&self, command: &'a Command, _: &'a Context) -> Result<Vec<Response>> {
+            let tag = command.tag();
+            Ok(vec![Response::new(&tag, ResponseStatus::OK, "NOOP completed")])
+        }
+    }
+
+    async fn send(server: &Server, command: Command) -> Vec<Response> {
+        let mut handler = server.handler[&command.command()].clone();
+        let (responder, responses) = unbounded();
+        let (events, _) = unbounded();
+        let request = Request {
+            command,
+            responder,
+            events,
+            context: Context::default(),
+            span: tracing::Span::none(),
+            continuation: Default::default(),
+            timings: Default::default(),
+        };
+        handler.send(request).await.unwrap();
+        responses.concat().await
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn test_serve_stream() {
+        use async_std::io::BufReader;
+        use async_std::os::unix::net::UnixStream;
+        use futures::{AsyncBufReadExt, AsyncWriteExt};
+
+        let server = ServerBuilder::new()
+            .with_configuration(Configuration::default().with_listeners(vec![]))
+            .bind()
+            .await
+            .unwrap();
+        let (mut client, theirs) = UnixStream::pair().unwrap();
+        let served = async_std::task::spawn(async move { server.serve_stream(theirs).await });
+
+        let mut lines = BufReader::new(client.clone()).lines();
+        let greeting = lines.next().await.unwrap().unwrap();
+        assert_eq!(greeting, "* OK [CAPABILITY IMAP4rev2 ID] IMAP4rev2 server ready");
+        client.write_all(b"a1 LOGOUT\r\n").await.unwrap();
+        let mut received = vec![];
+        while let Some(line) = lines.next().await {
+            received.push(line.unwrap());
+        }
+        assert_eq!(
+            received,
+            vec![
+                "* BYE IMAP4rev2 server logging out",
+                "a1 OK LOGOUT completed. Goodbye!"
+            ]
+        );
+        served.await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn test_control_socket_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = std::env::temp_dir().join(format!(
+            "treasurmap-control-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("control.sock");
+        let configuration = Configuration::default()
+            .with_listeners(vec![])
+            .with_control_socket(&path);
+        let _server = ServerBuilder::new()
+            .with_configuration(configuration)
+            .bind()
+            .await
+            .unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o077, 0);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_handler_precedence() {
+        let commands = DelegatingCommandHandler::new();
+        commands.register_command(Noop).await;
+        let server = ServerBuilder::new()
+            .with_configuration(Configuration::default().with_listeners(vec![]))
+            .with_handler(QuietLogout)
+            .with_handler_from(|components| SelectHandler::new(components.index.clone()))
+            .with_command_handler(commands)
+            .bind()
+            .await
+            .unwrap();
+
+        let logout = send(&server, Command::new("a1", "LOGOUT", vec![])).await;
+        assert_eq!(logout, vec![Response::new("a1", ResponseStatus::OK, "Quietly")]);
+        let noop = send(&server, Command::new("a2", "NOOP", vec![])).await;
+        assert_eq!(noop, vec![Response::new("a2", ResponseStatus::OK, "NOOP completed")]);
+        // The defaults still handle the commands nothing else does.
+        for command in ["LOGIN", "AUTHENTICATE", "SELECT", "EXAMINE", "FETCH", "SEARCH", "STORE", "COPY"] {
+            assert!(server.handler.contains_key(command), "{}", command);
+        }
+    }
 
     #[test]
     fn test_can_strip_quotes_from_command() {
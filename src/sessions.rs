@@ -0,0 +1,393 @@
+//! The open IMAP sessions of a server, for administrators.
+//!
+//! Every connection registers with the server's `Sessions` while it is open, recording who
+//! logged in, from where, which mailbox is selected, how many commands it has run and when it
+//! last ran one. A session can be terminated, which sends it `* BYE` and closes it, such as to
+//! stop a runaway sync client or to make sure a deleted account has no one left logged in.
+//!
+//! On Unix the registry can also be reached over a control socket, see
+//! `Configuration::with_control_socket`. It is created with a umask of 077, so that only
+//! the user owning it can reach the sessions of everyone else. Each connection to it sends
+//! one line and reads the answer until the server closes it:
+//!
+//! * `LIST` answers with a line per session: its ID, user, peer, selected mailbox, seconds
+//!   since it last ran a command and the number of commands it has run, separated by tabs,
+//!   with `-` for what the session does not have.
+//! * `TERMINATE <id>` answers with the number of sessions terminated, 0 or 1.
+//! * `TERMINATE-USER <user>` terminates every session of `<user>` and answers with how many
+//!   there were.
+//! * `RECALCULATE-USAGE [<user>]` recalculates the storage used by `<user>`, or by every
+//!   user, and answers once it has with a line per user: the user, their number of mailboxes,
+//!   the bytes they use, how many mailboxes had their usage corrected and by how many bytes
+//!   in all. See `store::usage`.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::connection::Context;
+use crate::metrics::Metrics;
+use crate::server::Components;
+use crate::shutdown::Shutdown;
+use crate::store::usage::RecalculateUsage;
+
+struct Activity {
+    user: Option<String>,
+    mailbox: Option<String>,
+    last_command: Instant,
+}
+
+struct Entry {
+    peer: Option<IpAddr>,
+    connected: SystemTime,
+    commands: AtomicU64,
+    activity: Mutex<Activity>,
+    terminate: Shutdown,
+}
+
+impl Entry {
+    fn activity(&self) -> MutexGuard<'_, Activity> {
+        match self.activity.lock() {
+            Ok(activity) => activity,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// What an open session is doing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    /// The UUID identifying the connection in the logs.
+    pub id: String,
+    pub user: Option<String>,
+    pub peer: Option<IpAddr>,
+    /// The selected mailbox, if any.
+    pub mailbox: Option<String>,
+    pub connected: SystemTime,
+    /// How long since the session ran a command, or since it connected if it has run none.
+    pub idle: Duration,
+    pub commands: u64,
+}
+
+/// A handle on the open sessions of a `Server`, see `Server::sessions`. Clones see the same
+/// sessions.
+#[derive(Clone, Default)]
+pub struct Sessions {
+    entries: Arc<Mutex<HashMap<String, Arc<Entry>>>>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn entries(&self) -> MutexGuard<'_, HashMap<String, Arc<Entry>>> {
+        match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Every open session, the longest connected first.
+    pub fn list(&self) -> Vec<Session> {
+        let mut sessions: Vec<Session> = self
+            .entries()
+            .iter()
+            .map(|(id, entry)| {
+                let activity = entry.activity();
+                Session {
+                    id: id.clone(),
+                    user: activity.user.clone(),
+                    peer: entry.peer,
+                    mailbox: activity.mailbox.clone(),
+                    connected: entry.connected,
+                    idle: activity.last_command.elapsed(),
+                    commands: entry.commands.load(Ordering::Relaxed),
+                }
+            })
+            .collect();
+        sessions.sort_by(|a, b| a.connected.cmp(&b.connected).then(a.id.cmp(&b.id)));
+        sessions
+    }
+
+    /// Sends the session `id` `* BYE` and closes it, once any command it is running has been
+    /// answered. Returns whether there was such a session.
+    pub fn terminate(&self, id: &str) -> bool {
+        match self.entries().get(id) {
+            Some(entry) => {
+                entry.terminate.trigger();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Terminates every session logged in as `user`, returning how many there were.
+    pub fn terminate_user(&self, user: &str) -> usize {
+        let mut terminated = 0;
+        for entry in self.entries().values() {
+            if entry.activity().user.as_deref() == Some(user) {
+                entry.terminate.trigger();
+                terminated += 1;
+            }
+        }
+        terminated
+    }
+
+    /// Lists the connection `id` until the registration is dropped.
+    pub(crate) fn register(&self, id: &str, peer: Option<IpAddr>) -> Registration {
+        let entry = Arc::new(Entry {
+            peer,
+            connected: SystemTime::now(),
+            commands: AtomicU64::new(0),
+            activity: Mutex::new(Activity {
+                user: None,
+                mailbox: None,
+                last_command: Instant::now(),
+            }),
+            terminate: Shutdown::new(),
+        });
+        self.entries().insert(id.to_string(), entry.clone());
+        Registration {
+            sessions: self.clone(),
+            id: id.to_string(),
+            entry,
+        }
+    }
+}
+
+/// The entry of a connection in `Sessions`, which the connection keeps up to date.
+pub(crate) struct Registration {
+    sessions: Sessions,
+    id: String,
+    entry: Arc<Entry>,
+}
+
+impl Registration {
+    /// Records that the session ran a command.
+    pub(crate) fn command(&self) {
+        self.entry.commands.fetch_add(1, Ordering::Relaxed);
+        self.entry.activity().last_command = Instant::now();
+    }
+
+    /// Records who the session is logged in as and what it has selected.
+    pub(crate) fn update(&self, context: &Context) {
+        let mut activity = self.entry.activity();
+        activity.user = context.user().map(|user| user.name());
+        activity.mailbox = context.folder();
+    }
+
+    /// Triggered when an administrator terminates the session.
+    pub(crate) fn terminated(&self) -> Shutdown {
+        self.entry.terminate.clone()
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.sessions.entries().remove(&self.id);
+    }
+}
+
+/// The answer to a line sent to the control socket.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn control(sessions: &Sessions, line: &str) -> String {
+    let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    match line.trim_end().split_once(' ') {
+        None if line.trim_end().eq_ignore_ascii_case("LIST") => sessions
+            .list()
+            .into_iter()
+            .map(|session| {
+                format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\n",
+                    session.id,
+                    or_dash(session.user),
+                    or_dash(session.peer.map(|peer| peer.to_string())),
+                    or_dash(session.mailbox),
+                    session.idle.as_secs(),
+                    session.commands
+                )
+            })
+            .collect(),
+        Some((command, id)) if command.eq_ignore_ascii_case("TERMINATE") => {
+            format!("{}\n", sessions.terminate(id) as usize)
+        }
+        Some((command, user)) if command.eq_ignore_ascii_case("TERMINATE-USER") => {
+            format!("{}\n", sessions.terminate_user(user))
+        }
+        _ => "ERROR unknown command\n".to_string(),
+    }
+}
+
+/// What the control socket of a server can reach.
+#[derive(Clone)]
+#[cfg_attr(not(unix), allow(dead_code))]
+pub(crate) struct Control {
+    pub(crate) sessions: Sessions,
+    pub(crate) usage: RecalculateUsage,
+    pub(crate) components: Components,
+    pub(crate) metrics: Arc<Metrics>,
+}
+
+#[cfg_attr(not(unix), allow(dead_code))]
+impl Control {
+    /// The answer to a line sent to the control socket, recalculating usage if it asks to.
+    async fn answer(&self, line: &str) -> String {
+        let line = line.trim_end();
+        let (command, user) = match line.split_once(' ') {
+            Some((command, user)) => (command, Some(user)),
+            None => (line, None),
+        };
+        if !command.eq_ignore_ascii_case("RECALCULATE-USAGE") {
+            return control(&self.sessions, line);
+        }
+        match self
+            .usage
+            .recalculate_in(&self.components, &self.metrics, user)
+            .await
+        {
+            Ok(recalculated) => recalculated
+                .into_iter()
+                .map(|usage| {
+                    format!(
+                        "{}\t{}\t{}\t{}\t{}\n",
+                        usage.user, usage.mailboxes, usage.bytes, usage.corrected, usage.drift
+                    )
+                })
+                .collect(),
+            Err(e) => format!("ERROR {}\n", e),
+        }
+    }
+}
+
+/// Answers the control socket until `shutdown` is triggered.
+#[cfg(unix)]
+pub(crate) async fn serve_control(
+    listener: async_std::os::unix::net::UnixListener,
+    control: Control,
+    shutdown: Shutdown,
+) {
+    use async_std::io::BufReader;
+    use futures::future::{self, Either};
+    use futures::{AsyncBufReadExt, AsyncWriteExt};
+
+    loop {
+        let accepted =
+            match future::select(Box::pin(listener.accept()), Box::pin(shutdown.wait())).await {
+                Either::Left((accepted, _)) => accepted,
+                Either::Right(..) => return,
+            };
+        let mut stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!("Could not accept a control connection: {}", e);
+                async_std::task::sleep(Duration::from_millis(500)).await;
+                continue;
+            }
+        };
+        let control = control.clone();
+        crate::runtime::spawn(async move {
+            let mut line = String::new();
+            let read = BufReader::new(stream.clone()).read_line(&mut line).await;
+            let answered = match read {
+                Ok(_) => {
+                    stream
+                        .write_all(control.answer(&line).await.as_bytes())
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = answered {
+                tracing::debug!("Could not answer a control request: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_std::path::PathBuf;
+
+    use super::{control, Control, Sessions};
+    use crate::auth::inmemory::{InMemoryAuthenticator, InMemoryUserStore};
+    use crate::auth::{Authenticate, User, UserStore};
+    use crate::connection::Context;
+    use crate::index::inmemory::InMemoryIndex;
+    use crate::index::Index;
+    use crate::metrics::Metrics;
+    use crate::server::Components;
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::usage::{RecalculateUsage, Usage};
+    use crate::store::{DataStore, Message};
+
+    #[test]
+    fn test_register_and_terminate() {
+        let sessions = Sessions::new();
+        let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let first = sessions.register("first", Some(peer));
+        let second = sessions.register("second", None);
+        first.update(&Context::of(
+            Some(User::new("me", "secret")),
+            Some(PathBuf::from("INBOX")),
+        ));
+        first.command();
+        first.command();
+
+        let listed = sessions.list();
+        assert_eq!(listed.len(), 2);
+        let me = listed.iter().find(|session| session.id == "first").unwrap();
+        assert_eq!(me.user.as_deref(), Some("me"));
+        assert_eq!(me.mailbox.as_deref(), Some("INBOX"));
+        assert_eq!((me.peer, me.commands), (Some(peer), 2));
+        assert!(control(&sessions, "LIST\r\n").contains("first\tme\t127.0.0.1\tINBOX\t0\t2\n"));
+
+        assert_eq!(sessions.terminate_user("me"), 1);
+        assert!(first.terminated().is_triggered());
+        assert!(!second.terminated().is_triggered());
+        assert_eq!(control(&sessions, "TERMINATE second\n"), "1\n");
+        assert!(second.terminated().is_triggered());
+        assert_eq!(control(&sessions, "TERMINATE other\n"), "0\n");
+
+        drop(first);
+        assert_eq!(sessions.list().len(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_recalculate_usage() {
+        let users: Arc<Box<dyn UserStore>> =
+            Arc::new(Box::new(InMemoryUserStore::new().with_user("me", "secret")));
+        let store = InMemoryStore::new();
+        store
+            .append("me", "INBOX", Message::new(b"Hello"))
+            .await
+            .unwrap();
+        let control = Control {
+            sessions: Sessions::new(),
+            usage: RecalculateUsage::new(Usage::new()).with_pause(Duration::ZERO),
+            components: Components {
+                index: Arc::new(Box::new(InMemoryIndex::new()) as Box<dyn Index>),
+                data_store: Arc::new(Box::new(store) as Box<dyn DataStore>),
+                user_store: users.clone(),
+                authenticator: Arc::new(
+                    Box::new(InMemoryAuthenticator::new(users)) as Box<dyn Authenticate>
+                ),
+            },
+            metrics: Arc::new(Metrics::new()),
+        };
+        assert_eq!(
+            control.answer("RECALCULATE-USAGE\r\n").await,
+            "me\t1\t5\t0\t0\n"
+        );
+        assert_eq!(
+            control.answer("recalculate-usage you\n").await,
+            "you\t0\t0\t0\t0\n"
+        );
+        assert_eq!(control.answer("TERMINATE other\n").await, "0\n");
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::channel::oneshot::{self, channel};
+use futures::future::{FutureExt, Shared};
+
+struct Signal {
+    triggered: AtomicBool,
+    sender: Mutex<Option<oneshot::Sender<()>>>,
+    receiver: Shared<oneshot::Receiver<()>>,
+}
+
+/// A handle for stopping a running `Server`, see `Server::shutdown`. Clones share the same
+/// signal, so any of them may trigger it and all of them see it.
+#[derive(Clone)]
+pub struct Shutdown {
+    signal: Arc<Signal>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (sender, receiver) = channel();
+        Self {
+            signal: Arc::new(Signal {
+                triggered: AtomicBool::new(false),
+                sender: Mutex::new(Some(sender)),
+                receiver: receiver.shared(),
+            }),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops accepting connections and tells every connection to say BYE and close.
+    pub fn trigger(&self) {
+        self.signal.triggered.store(true, Ordering::SeqCst);
+        if let Ok(mut sender) = self.signal.sender.lock() {
+            if let Some(sender) = sender.take() {
+                let _ = sender.send(());
+            }
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.signal.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the shutdown has been triggered.
+    pub async fn wait(&self) {
+        let _ = self.signal.receiver.clone().await;
+    }
+
+    /// Triggers the shutdown on SIGTERM or SIGINT. A second signal exits at once, for when
+    /// draining takes too long.
+    #[cfg(unix)]
+    pub fn trigger_on_signals(&self) -> std::io::Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        use signal_hook::iterator::Signals;
+
+        let mut signals = Signals::new([SIGTERM, SIGINT])?;
+        let shutdown = self.clone();
+        std::thread::spawn(move || {
+            for signal in signals.forever() {
+                if shutdown.is_triggered() {
+                    std::process::exit(128 + signal);
+                }
+                log::info!("Received signal {}, shutting down", signal);
+                shutdown.trigger();
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shutdown;
+
+    #[async_std::test]
+    async fn test_trigger() {
+        let shutdown = Shutdown::new();
+        let clone = shutdown.clone();
+        assert!(!clone.is_triggered());
+        let waiting = async_std::task::spawn(async move { clone.wait().await });
+        shutdown.trigger();
+        shutdown.trigger();
+        waiting.await;
+        assert!(shutdown.is_triggered());
+        // Waiting after the trigger returns at once.
+        shutdown.wait().await;
+    }
+}
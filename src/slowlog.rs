@@ -0,0 +1,326 @@
+//! Logging of commands which take too long or answer with too much, to find pathological
+//! clients and slow backends.
+//!
+//! A command is logged when it takes longer than the duration of the `SlowLog` from being
+//! dispatched until its tagged response has been written, or when its responses add up to
+//! more than the response size. The log line gives the user, the selected mailbox, the
+//! command with its credentials redacted, and where the time went:
+//!
+//! * parsing the command line,
+//! * calls to the index and to the data store made while the handler ran the command, which
+//!   handlers collect by running their work in `Timings::scope`,
+//! * writing to the client from the command's dispatch until its tagged response was
+//!   written, which includes writing the responses of commands running alongside it and
+//!   streaming message bodies from the store.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::channel::oneshot;
+use tracing::{debug_span, warn, Instrument};
+
+use crate::capture::redact;
+use crate::server::{Command, Response};
+
+/// The longest a command may take before it is logged, by default.
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(1);
+/// The most a command may answer with before it is logged, by default.
+pub const DEFAULT_RESPONSE_SIZE: u64 = 16 * 1024 * 1024;
+/// How much of a command line is logged.
+const SUMMARY_LENGTH: usize = 120;
+
+/// When a command is slow or large enough to be logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowLog {
+    duration: Duration,
+    response_size: u64,
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        Self {
+            duration: DEFAULT_DURATION,
+            response_size: DEFAULT_RESPONSE_SIZE,
+        }
+    }
+}
+
+impl SlowLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Logs commands which take longer than `duration`.
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+    /// Logs commands whose responses come to more than `bytes`, literals included.
+    pub fn with_response_size(mut self, bytes: u64) -> Self {
+        self.response_size = bytes;
+        self
+    }
+}
+
+/// A part of answering a command whose time is collected in `Timings`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Index,
+    Store,
+}
+
+thread_local! {
+    /// The timings of the command whose work is being polled on this thread.
+    static CURRENT: RefCell<Option<Timings>> = const { RefCell::new(None) };
+}
+
+/// The time a command has spent in each `Stage`. Clones add to the same totals.
+#[derive(Debug, Clone, Default)]
+pub struct Timings {
+    nanos: Arc<[AtomicU64; 2]>,
+}
+
+impl Timings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn spent(&self, stage: Stage) -> Duration {
+        Duration::from_nanos(self.nanos[stage as usize].load(Ordering::Relaxed))
+    }
+
+    /// Runs `future`, charging the index and store calls it makes to these timings. Calls
+    /// made from tasks it spawns are not charged unless they are scoped too.
+    pub fn scope<F: Future>(&self, future: F) -> Scoped<F> {
+        Scoped {
+            future: Box::pin(future),
+            timings: self.clone(),
+        }
+    }
+
+    fn charge(&self, stage: Stage, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        self.nanos[stage as usize].fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+/// A future run in the scope of some `Timings`, see `Timings::scope`.
+pub struct Scoped<F> {
+    future: Pin<Box<F>>,
+    timings: Timings,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let timings = self.timings.clone();
+        let previous = CURRENT.with(|current| current.replace(Some(timings)));
+        let poll = self.future.as_mut().poll(cx);
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+        poll
+    }
+}
+
+/// Runs `future`, the `operation` call to `stage`, in a span named after the stage, and
+/// charges the time it takes to the command in whose scope it runs, if any.
+pub(crate) async fn measure<F: Future>(
+    stage: Stage,
+    operation: &'static str,
+    future: F,
+) -> F::Output {
+    let span = match stage {
+        Stage::Index => debug_span!("index", operation),
+        Stage::Store => debug_span!("store", operation),
+    };
+    let future = future.instrument(span);
+    let timings = match CURRENT.with(|current| current.borrow().clone()) {
+        Some(timings) => timings,
+        None => return future.await,
+    };
+    let started = Instant::now();
+    let output = future.await;
+    timings.charge(stage, started.elapsed());
+    output
+}
+
+/// The time a connection has spent writing, and the commands waiting to hear when their
+/// tagged response has been written.
+#[derive(Default)]
+pub(crate) struct WriteClock {
+    nanos: AtomicU64,
+    waiting: Mutex<HashMap<String, oneshot::Sender<Duration>>>,
+}
+
+impl WriteClock {
+    fn spent(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+    }
+
+    /// The time spent writing so far, and a receiver of the time spent once the tagged
+    /// response with `tag` has been written.
+    fn expect(&self, tag: &str) -> (Duration, oneshot::Receiver<Duration>) {
+        let (sender, receiver) = oneshot::channel();
+        if let Ok(mut waiting) = self.waiting.lock() {
+            waiting.insert(tag.to_string(), sender);
+        }
+        (self.spent(), receiver)
+    }
+
+    /// Records that writing `tags`, those of the tagged responses among what was written,
+    /// took `elapsed`.
+    pub(crate) fn wrote(&self, tags: &[String], elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        self.nanos.fetch_add(nanos, Ordering::Relaxed);
+        if tags.is_empty() {
+            return;
+        }
+        let spent = self.spent();
+        if let Ok(mut waiting) = self.waiting.lock() {
+            for tag in tags {
+                if let Some(sender) = waiting.remove(tag) {
+                    let _ = sender.send(spent);
+                }
+            }
+        }
+    }
+}
+
+/// The tags of the tagged responses among `responses`.
+pub(crate) fn tags(responses: &[Response]) -> Vec<String> {
+    responses
+        .iter()
+        .map(Response::tag)
+        .filter(|tag| tag != "*" && tag != "+")
+        .collect()
+}
+
+/// What is measured of one command for the slow log.
+pub(crate) struct Measured {
+    log: SlowLog,
+    summary: String,
+    user: String,
+    mailbox: String,
+    started: Instant,
+    parse: Duration,
+    pub(crate) timings: Timings,
+    bytes: u64,
+    written_before: Duration,
+    written: oneshot::Receiver<Duration>,
+}
+
+impl Measured {
+    pub(crate) fn new(
+        log: SlowLog,
+        command: &Command,
+        context: &crate::connection::Context,
+        parse: Duration,
+        clock: &WriteClock,
+    ) -> Self {
+        let (written_before, written) = clock.expect(&command.tag());
+        Self {
+            log,
+            summary: summary(command),
+            user: context.user().map_or("-".to_string(), |user| user.name()),
+            mailbox: context.folder().unwrap_or_else(|| "-".to_string()),
+            started: Instant::now(),
+            parse,
+            timings: Timings::new(),
+            bytes: 0,
+            written_before,
+            written,
+        }
+    }
+
+    /// Counts `responses` towards the size of the answer.
+    pub(crate) fn observe(&mut self, responses: &[Response]) {
+        self.bytes += responses.iter().map(Response::size).sum::<u64>();
+    }
+
+    /// Waits for the tagged response to be written, then logs the command if it went over
+    /// either threshold.
+    pub(crate) async fn finish(self) {
+        let written = self.written.await.unwrap_or(self.written_before);
+        let elapsed = self.started.elapsed();
+        if elapsed <= self.log.duration && self.bytes <= self.log.response_size {
+            return;
+        }
+        warn!(
+            "Slow command `{}` by {} in {}: {} bytes answered in {:?}, of which {:?} parsing, \
+             {:?} in the index, {:?} in the store and {:?} writing",
+            self.summary,
+            self.user,
+            self.mailbox,
+            self.bytes,
+            elapsed,
+            self.parse,
+            self.timings.spent(Stage::Index),
+            self.timings.spent(Stage::Store),
+            written.saturating_sub(self.written_before)
+        );
+    }
+}
+
+/// The command line with credentials redacted, cut short if it is long.
+fn summary(command: &Command) -> String {
+    let mut line = format!("{} {}", command.tag(), command.command());
+    for position in 0..command.num_args() {
+        line.push(' ');
+        line.push_str(&command.arg(position));
+    }
+    let line = redact(&line);
+    match line.char_indices().nth(SUMMARY_LENGTH) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{measure, summary, Stage, Timings, WriteClock};
+    use crate::server::Command;
+
+    #[async_std::test]
+    async fn test_timings_are_charged_in_scope() {
+        let timings = Timings::new();
+        let slow = async {
+            async_std::task::sleep(Duration::from_millis(20)).await;
+        };
+        timings.scope(measure(Stage::Store, "fetch", slow)).await;
+        // Outside the scope nothing is charged.
+        measure(
+            Stage::Index,
+            "get",
+            async_std::task::sleep(Duration::from_millis(20)),
+        )
+        .await;
+        assert!(timings.spent(Stage::Store) >= Duration::from_millis(20));
+        assert_eq!(timings.spent(Stage::Index), Duration::ZERO);
+    }
+
+    #[async_std::test]
+    async fn test_write_clock() {
+        let clock = WriteClock::default();
+        clock.wrote(&[], Duration::from_millis(5));
+        let (before, written) = clock.expect("a1");
+        clock.wrote(&["a0".to_string()], Duration::from_millis(7));
+        clock.wrote(&["a1".to_string()], Duration::from_millis(11));
+        assert_eq!(before, Duration::from_millis(5));
+        assert_eq!(written.await.unwrap(), Duration::from_millis(23));
+    }
+
+    #[test]
+    fn test_summary() {
+        let login = Command::parse("a1 LOGIN me secret").unwrap();
+        assert_eq!(summary(&login), "a1 LOGIN [redacted]");
+        let long = Command::parse(&format!("a2 SEARCH TEXT {}", "x".repeat(200))).unwrap();
+        assert_eq!(summary(&long).len(), 123);
+    }
+}
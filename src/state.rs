@@ -0,0 +1,232 @@
+// From RFC 9051 (https://www.ietf.org/rfc/rfc9051.html#name-state-and-flow-diagram):
+//  A connection starts Not Authenticated, or Authenticated with PREAUTH. LOGIN and
+//  AUTHENTICATE move it to Authenticated, SELECT to Selected, and LOGOUT to Logout from
+//  any state. Each command is only valid in some of those states.
+
+use crate::connection::Context;
+use crate::server::{Command, Response, ResponseStatus};
+
+/// The state of an IMAP session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    NotAuthenticated,
+    Authenticated,
+    Selected,
+    Logout,
+}
+
+/// The states a command is valid in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Valid {
+    Always,
+    BeforeAuthentication,
+    /// Once authenticated, whether or not a mailbox is selected.
+    Authenticated,
+    Selected,
+}
+
+fn valid(command: &str) -> Valid {
+    match command {
+        "STARTTLS" | "AUTHENTICATE" | "LOGIN" => Valid::BeforeAuthentication,
+        "ENABLE" | "SELECT" | "EXAMINE" | "CREATE" | "DELETE" | "RENAME" | "SUBSCRIBE"
+        | "UNSUBSCRIBE" | "LIST" | "LSUB" | "NAMESPACE" | "STATUS" | "APPEND" | "IDLE" => {
+            Valid::Authenticated
+        }
+        "CLOSE" | "UNSELECT" | "EXPUNGE" | "SEARCH" | "FETCH" | "STORE" | "COPY" | "MOVE"
+        | "UID" => Valid::Selected,
+        // CAPABILITY, NOOP, LOGOUT, and commands from extensions this table does not know.
+        _ => Valid::Always,
+    }
+}
+
+impl State {
+    pub fn of(context: &Context) -> Self {
+        if context.is_logged_out() {
+            State::Logout
+        } else if !context.is_authenticated() {
+            State::NotAuthenticated
+        } else if context.is_selected() {
+            State::Selected
+        } else {
+            State::Authenticated
+        }
+    }
+
+    /// Whether `command`, by name, may be run in this state.
+    pub fn allows(&self, command: &str) -> bool {
+        match (self, valid(command)) {
+            (State::Logout, _) => false,
+            (_, Valid::Always) => true,
+            (State::NotAuthenticated, Valid::BeforeAuthentication) => true,
+            (State::Authenticated | State::Selected, Valid::Authenticated) => true,
+            (State::Selected, Valid::Selected) => true,
+            _ => false,
+        }
+    }
+
+    /// The response to `command` when this state does not allow it.
+    pub fn rejection(&self, command: &Command) -> Response {
+        let tag = command.tag();
+        let name = command.command();
+        match (self, valid(&name)) {
+            (State::NotAuthenticated, Valid::Authenticated | Valid::Selected) => Response::new(
+                &tag,
+                ResponseStatus::NO,
+                &format!(
+                    "cannot {} when un-authenticated. Please authenticate using LOGIN or AUTHENTICATE.",
+                    name
+                ),
+            ),
+            (State::Authenticated, Valid::Selected) => Response::new(
+                &tag,
+                ResponseStatus::NO,
+                &format!("cannot {} before SELECT. Please SELECT a folder.", name),
+            ),
+            (State::Logout, _) => Response::new(
+                &tag,
+                ResponseStatus::BAD,
+                &format!("cannot {} after LOGOUT.", name),
+            )
+            .with_code("CLIENTBUG"),
+            _ => Response::new(
+                &tag,
+                ResponseStatus::BAD,
+                &format!("cannot {} once authenticated.", name),
+            )
+            .with_code("CLIENTBUG"),
+        }
+    }
+
+    /// Whether `command` may run alongside other commands. Commands which change the state
+    /// of the session, or whose results depend on commands before them, such as EXPUNGE
+    /// renumbering messages, must run alone.
+    pub fn runs_concurrently(command: &Command) -> bool {
+        match command.command().as_str() {
+            "CAPABILITY" | "NOOP" | "CHECK" | "FETCH" | "SEARCH" | "STATUS" | "LIST" | "LSUB"
+            | "NAMESPACE" => true,
+            "UID" => matches!(command.arg(0).to_uppercase().as_str(), "FETCH" | "SEARCH"),
+            _ => false,
+        }
+    }
+
+    /// Checks `command` against the state of `context`, returning the response to reject it
+    /// with when it is not valid there.
+    pub fn check(command: &Command, context: &Context) -> Option<Response> {
+        let state = State::of(context);
+        match state.allows(&command.command()) {
+            true => None,
+            false => Some(state.rejection(command)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::State;
+    use crate::auth::User;
+    use crate::connection::Context;
+    use crate::server::{Command, ResponseStatus};
+
+    #[test]
+    fn test_transitions() {
+        let user = User::anonymous("public");
+        assert_eq!(State::of(&Context::default()), State::NotAuthenticated);
+        assert_eq!(
+            State::of(&Context::of(Some(user.clone()), None)),
+            State::Authenticated
+        );
+        assert_eq!(
+            State::of(&Context::of(Some(user), Some("INBOX".into()))),
+            State::Selected
+        );
+        assert_eq!(
+            State::of(&Context::default().with_logged_out()),
+            State::Logout
+        );
+    }
+
+    #[test]
+    fn test_allows() {
+        for (state, allowed, rejected) in [
+            (
+                State::NotAuthenticated,
+                vec!["LOGIN", "AUTHENTICATE", "STARTTLS", "CAPABILITY", "LOGOUT"],
+                vec!["SELECT", "FETCH", "SEARCH"],
+            ),
+            (
+                State::Authenticated,
+                vec!["SELECT", "EXAMINE", "NOOP", "LOGOUT"],
+                vec!["LOGIN", "AUTHENTICATE", "STARTTLS", "FETCH", "SEARCH"],
+            ),
+            (
+                State::Selected,
+                vec!["SELECT", "FETCH", "SEARCH", "UID", "LOGOUT"],
+                vec!["LOGIN", "AUTHENTICATE"],
+            ),
+            (State::Logout, vec![], vec!["NOOP", "LOGIN", "FETCH"]),
+        ] {
+            for command in allowed {
+                assert!(
+                    state.allows(command),
+                    "{:?} should allow {}",
+                    state,
+                    command
+                );
+            }
+            for command in rejected {
+                assert!(
+                    !state.allows(command),
+                    "{:?} should reject {}",
+                    state,
+                    command
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejection() {
+        let user = User::anonymous("public");
+        let fetch = Command::new("a1", "FETCH", vec!["1", "FLAGS"]);
+        let rejection = State::check(&fetch, &Context::default()).unwrap();
+        assert_eq!(rejection.status(), Some(ResponseStatus::NO));
+        assert_eq!(
+            rejection.message(),
+            "cannot FETCH when un-authenticated. Please authenticate using LOGIN or AUTHENTICATE."
+        );
+        let rejection = State::check(&fetch, &Context::of(Some(user.clone()), None)).unwrap();
+        assert_eq!(
+            rejection.message(),
+            "cannot FETCH before SELECT. Please SELECT a folder."
+        );
+
+        let login = Command::new("a2", "LOGIN", vec!["me", "password"]);
+        let rejection = State::check(&login, &Context::of(Some(user), None)).unwrap();
+        assert_eq!(rejection.status(), Some(ResponseStatus::BAD));
+        assert_eq!(
+            rejection.message(),
+            "[CLIENTBUG] cannot LOGIN once authenticated."
+        );
+        assert!(State::check(&login, &Context::default()).is_none());
+    }
+
+    #[test]
+    fn test_runs_concurrently() {
+        let command = |name, args| Command::new("a1", name, args);
+        assert!(State::runs_concurrently(&command(
+            "FETCH",
+            vec!["1", "FLAGS"]
+        )));
+        assert!(State::runs_concurrently(&command(
+            "UID",
+            vec!["fetch", "1", "FLAGS"]
+        )));
+        assert!(!State::runs_concurrently(&command(
+            "UID",
+            vec!["EXPUNGE", "1"]
+        )));
+        assert!(!State::runs_concurrently(&command("SELECT", vec!["INBOX"])));
+        assert!(!State::runs_concurrently(&command("LOGOUT", vec![])));
+        assert!(!State::runs_concurrently(&command("XUNKNOWN", vec![])));
+    }
+}
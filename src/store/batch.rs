@@ -0,0 +1,193 @@
+//! Changes to the messages of a user which are made all together or not at all, see
+//! `DataStore::apply`.
+
+use std::collections::BTreeMap;
+
+use log::warn;
+
+use super::{DataStore, Message, StoreError};
+
+/// Messages to append and to expunge in one go, such as the copies made by COPY, or a MOVE
+/// appending to its destination and expunging from its source.
+///
+/// The appends are made in the order they were added, then the expunges, whatever order the
+/// two were added in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Batch {
+    appends: Vec<(String, Message)>,
+    expunges: Vec<(String, Vec<u32>)>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Stores `message` at the end of `mailbox`, as `DataStore::append` does.
+    pub fn with_append(mut self, mailbox: &str, message: Message) -> Self {
+        self.appends.push((mailbox.to_string(), message));
+        self
+    }
+    /// Removes the messages `uids` from `mailbox`, as `DataStore::expunge` does.
+    pub fn with_expunge(mut self, mailbox: &str, uids: &[u32]) -> Self {
+        self.expunges.push((mailbox.to_string(), uids.to_vec()));
+        self
+    }
+
+    pub fn appends(&self) -> &[(String, Message)] {
+        &self.appends
+    }
+
+    /// Every mailbox the batch appends to or expunges from, sorted by name.
+    pub fn mailboxes(&self) -> Vec<String> {
+        let mut mailboxes: Vec<String> = self
+            .appends
+            .iter()
+            .map(|(mailbox, _)| mailbox.clone())
+            .chain(self.expunges.iter().map(|(mailbox, _)| mailbox.clone()))
+            .collect();
+        mailboxes.sort();
+        mailboxes.dedup();
+        mailboxes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.appends.is_empty() && self.expunges.is_empty()
+    }
+
+    /// The appends and the expunges of the batch.
+    pub fn into_parts(self) -> (Vec<(String, Message)>, Vec<(String, Vec<u32>)>) {
+        (self.appends, self.expunges)
+    }
+
+    /// The same batch with the messages to append replaced by what `map` makes of them, for
+    /// stores which change messages on their way to the store they wrap.
+    pub fn try_map<F>(self, mut map: F) -> Result<Self, StoreError>
+    where
+        F: FnMut(Message) -> Result<Message, StoreError>,
+    {
+        let appends = self
+            .appends
+            .into_iter()
+            .map(|(mailbox, message)| Ok((mailbox, map(message)?)))
+            .collect::<Result<Vec<_>, StoreError>>()?;
+        Ok(Self {
+            appends,
+            expunges: self.expunges,
+        })
+    }
+}
+
+/// Applies `batch` to `store` a call at a time, for stores which cannot make the changes in
+/// one transaction. The messages appended so far are noted as they are, and expunged again if
+/// a later call fails. Expunges cannot be undone, so they are only made once every append has
+/// been; if one of them fails, or the server stops part way, part of the batch stays applied.
+pub(crate) async fn apply_each<S: DataStore + ?Sized>(
+    store: &S,
+    user: &str,
+    batch: Batch,
+) -> Result<Vec<u32>, StoreError> {
+    let (appends, expunges) = batch.into_parts();
+    let mut appended: Vec<(String, u32)> = vec![];
+    for (mailbox, message) in appends {
+        match store.append(user, &mailbox, message).await {
+            Ok(uid) => appended.push((mailbox, uid)),
+            Err(e) => {
+                roll_back(store, user, appended).await;
+                return Err(e);
+            }
+        }
+    }
+    for (mailbox, uids) in expunges {
+        store.expunge(user, &mailbox, &uids).await?;
+    }
+    Ok(appended.into_iter().map(|(_, uid)| uid).collect())
+}
+
+/// Expunges the messages of a batch which had been appended before it failed.
+async fn roll_back<S: DataStore + ?Sized>(store: &S, user: &str, appended: Vec<(String, u32)>) {
+    let mut by_mailbox: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    for (mailbox, uid) in appended {
+        by_mailbox.entry(mailbox).or_default().push(uid);
+    }
+    for (mailbox, uids) in by_mailbox {
+        if let Err(e) = store.expunge(user, &mailbox, &uids).await {
+            warn!(
+                "Could not remove messages {:?} of a failed batch from {} of {}: {}",
+                uids, mailbox, user, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Batch;
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message, MessageMetadata, StoreError};
+
+    /// A store which cannot append to `Broken`.
+    struct Breaking {
+        inner: InMemoryStore,
+    }
+
+    #[async_trait::async_trait]
+    impl DataStore for Breaking {
+        async fn append(
+            &self,
+            user: &str,
+            mailbox: &str,
+            message: Message,
+        ) -> Result<u32, StoreError> {
+            if mailbox == "Broken" {
+                return Err(StoreError::Backend("broken".to_string()));
+            }
+            self.inner.append(user, mailbox, message).await
+        }
+        async fn fetch(&self, user: &str, mailbox: &str, uid: u32) -> Result<Vec<u8>, StoreError> {
+            self.inner.fetch(user, mailbox, uid).await
+        }
+        async fn list(
+            &self,
+            user: &str,
+            mailbox: &str,
+        ) -> Result<Vec<MessageMetadata>, StoreError> {
+            self.inner.list(user, mailbox).await
+        }
+        async fn expunge(
+            &self,
+            user: &str,
+            mailbox: &str,
+            uids: &[u32],
+        ) -> Result<Vec<u32>, StoreError> {
+            self.inner.expunge(user, mailbox, uids).await
+        }
+        async fn mailboxes(&self, user: &str) -> Result<Vec<String>, StoreError> {
+            self.inner.mailboxes(user).await
+        }
+    }
+
+    #[async_std::test]
+    async fn test_failed_batch_is_rolled_back() {
+        let store = Breaking {
+            inner: InMemoryStore::new(),
+        };
+        let source = store
+            .append("me", "INBOX", Message::new(b"first"))
+            .await
+            .unwrap();
+
+        let moving = Batch::new()
+            .with_expunge("INBOX", &[source])
+            .with_append("Archive", Message::new(b"first"))
+            .with_append("Broken", Message::new(b"first"));
+        assert!(store.apply("me", moving).await.is_err());
+        assert!(store.list("me", "Archive").await.unwrap().is_empty());
+        assert_eq!(store.list("me", "INBOX").await.unwrap().len(), 1);
+
+        let moving = Batch::new()
+            .with_append("Archive", Message::new(b"first"))
+            .with_expunge("INBOX", &[source]);
+        assert_eq!(store.apply("me", moving).await.unwrap(), vec![2]);
+        assert!(store.list("me", "INBOX").await.unwrap().is_empty());
+    }
+}
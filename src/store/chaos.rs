@@ -0,0 +1,146 @@
+use futures::AsyncReadExt;
+
+use crate::testing::{Fault, Faults};
+
+use super::{DataStore, Message, MessageBody, MessageMetadata, StoreError};
+
+fn injected(operation: &str) -> StoreError {
+    StoreError::Backend(format!("injected failure in {}", operation))
+}
+
+/// Makes the calls to a data store fail, stall or stop part way as `faults` says, to test how
+/// the server copes with a misbehaving backend.
+///
+/// A partial `append` stores the first half of the message before failing, and a partial
+/// `open` returns a body which ends before the size it announces. Other calls fail outright.
+pub struct ChaosStore {
+    inner: Box<dyn DataStore>,
+    faults: Faults,
+}
+
+impl ChaosStore {
+    pub fn new(inner: Box<dyn DataStore>, faults: Faults) -> Self {
+        Self { inner, faults }
+    }
+
+    async fn check(&self, operation: &str) -> Result<(), StoreError> {
+        match self.faults.inject(operation).await {
+            Some(..) => Err(injected(operation)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl DataStore for ChaosStore {
+    async fn append(&self, user: &str, mailbox: &str, message: Message) -> Result<u32, StoreError> {
+        match self.faults.inject("append").await {
+            Some(Fault::Error) => Err(injected("append")),
+            Some(Fault::Partial) => {
+                let half = Message {
+                    body: message.body[..message.body.len() / 2].to_vec(),
+                    ..message
+                };
+                self.inner.append(user, mailbox, half).await?;
+                Err(injected("append"))
+            }
+            None => self.inner.append(user, mailbox, message).await,
+        }
+    }
+    async fn fetch(&self, user: &str, mailbox: &str, uid: u32) -> Result<Vec<u8>, StoreError> {
+        self.check("fetch").await?;
+        self.inner.fetch(user, mailbox, uid).await
+    }
+    async fn list(&self, user: &str, mailbox: &str) -> Result<Vec<MessageMetadata>, StoreError> {
+        self.check("list").await?;
+        self.inner.list(user, mailbox).await
+    }
+    async fn expunge(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>, StoreError> {
+        self.check("expunge").await?;
+        self.inner.expunge(user, mailbox, uids).await
+    }
+    async fn mailboxes(&self, user: &str) -> Result<Vec<String>, StoreError> {
+        self.check("mailboxes").await?;
+        self.inner.mailboxes(user).await
+    }
+    async fn fetch_header(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<Vec<u8>, StoreError> {
+        self.check("fetch_header").await?;
+        self.inner.fetch_header(user, mailbox, uid).await
+    }
+    async fn fetch_range(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, StoreError> {
+        self.check("fetch_range").await?;
+        self.inner
+            .fetch_range(user, mailbox, uid, offset, length)
+            .await
+    }
+    async fn open(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<(u64, MessageBody), StoreError> {
+        match self.faults.inject("open").await {
+            Some(Fault::Error) => Err(injected("open")),
+            Some(Fault::Partial) => {
+                let (length, body) = self.inner.open(user, mailbox, uid).await?;
+                let cut: MessageBody = Box::pin(body.take(length / 2));
+                Ok((length, cut))
+            }
+            None => self.inner.open(user, mailbox, uid).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::AsyncReadExt;
+
+    use super::ChaosStore;
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message, StoreError};
+    use crate::testing::Faults;
+
+    #[async_std::test]
+    async fn test_faults_injected() {
+        let inner = InMemoryStore::new();
+        inner
+            .append("user", "INBOX", Message::new(b"Subject: hi\r\n\r\nbody"))
+            .await
+            .unwrap();
+        let faults = Faults::new().with_errors(1.0).only(&["fetch"]);
+        let store = ChaosStore::new(Box::new(inner), faults.clone());
+        assert!(matches!(
+            store.fetch("user", "INBOX", 1).await,
+            Err(StoreError::Backend(..))
+        ));
+        assert_eq!(store.list("user", "INBOX").await.unwrap().len(), 1);
+        assert_eq!(faults.injected(), 1);
+
+        let store = ChaosStore::new(Box::new(store), Faults::new().with_partial_writes(1.0));
+        assert!(store
+            .append("user", "INBOX", Message::new(b"0123456789"))
+            .await
+            .is_err());
+        let (length, mut body) = store.open("user", "INBOX", 1).await.unwrap();
+        let mut read = vec![];
+        body.read_to_end(&mut read).await.unwrap();
+        assert_eq!((length, read.len()), (19, 9));
+    }
+}
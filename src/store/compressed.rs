@@ -0,0 +1,230 @@
+use super::{Batch, DataStore, Message, MessageMetadata, StoreError};
+
+/// Marks a body written by `CompressedStore`. Bodies without it are returned unchanged, so
+/// compression can be enabled on a store which already holds messages.
+const MAGIC: &[u8; 4] = b"\0TMC";
+const HEADER_LENGTH: usize = MAGIC.len() + 1 + 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Identity,
+    Zstd,
+}
+
+impl Codec {
+    fn id(&self) -> u8 {
+        match self {
+            Codec::Identity => 0,
+            Codec::Zstd => 1,
+        }
+    }
+    fn from_id(id: u8) -> Result<Self, StoreError> {
+        match id {
+            0 => Ok(Codec::Identity),
+            1 => Ok(Codec::Zstd),
+            _ => Err(StoreError::Backend(format!(
+                "unknown compression codec {}",
+                id
+            ))),
+        }
+    }
+}
+
+struct Header {
+    codec: Codec,
+    size: u64,
+}
+
+impl Header {
+    fn parse(stored: &[u8]) -> Result<Option<Self>, StoreError> {
+        if stored.len() < HEADER_LENGTH || !stored.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        let size = stored[MAGIC.len() + 1..HEADER_LENGTH]
+            .try_into()
+            .map(u64::from_le_bytes)
+            .map_err(|_| StoreError::Backend("truncated compression header".to_string()))?;
+        Ok(Some(Header {
+            codec: Codec::from_id(stored[MAGIC.len()])?,
+            size,
+        }))
+    }
+    fn write(&self, payload: &[u8]) -> Vec<u8> {
+        let mut stored = Vec::with_capacity(HEADER_LENGTH + payload.len());
+        stored.extend_from_slice(MAGIC);
+        stored.push(self.codec.id());
+        stored.extend_from_slice(&self.size.to_le_bytes());
+        stored.extend_from_slice(payload);
+        stored
+    }
+}
+
+/// A `DataStore` wrapper which zstd-compresses message bodies before handing them to the
+/// wrapped store and decompresses them again on fetch.
+///
+/// Bodies smaller than the threshold, or which do not get smaller when compressed, are stored
+/// with the `Identity` codec. Every stored body starts with a header recording the codec and the
+/// original size, which `list` uses to report uncompressed sizes.
+pub struct CompressedStore<S: DataStore> {
+    inner: S,
+    threshold: usize,
+    level: i32,
+}
+
+impl<S: DataStore> CompressedStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            threshold: 1024,
+            level: 3,
+        }
+    }
+    /// Sets the minimum body size in bytes which will be compressed.
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    fn encode(&self, body: Vec<u8>) -> Result<Vec<u8>, StoreError> {
+        let size = body.len() as u64;
+        if body.len() >= self.threshold {
+            let compressed = zstd::bulk::compress(&body, self.level)
+                .map_err(|e| StoreError::Backend(e.to_string()))?;
+            if compressed.len() < body.len() {
+                return Ok(Header {
+                    codec: Codec::Zstd,
+                    size,
+                }
+                .write(&compressed));
+            }
+        }
+        Ok(Header {
+            codec: Codec::Identity,
+            size,
+        }
+        .write(&body))
+    }
+}
+
+fn decode(stored: Vec<u8>) -> Result<Vec<u8>, StoreError> {
+    match Header::parse(&stored)? {
+        None => Ok(stored),
+        Some(Header {
+            codec: Codec::Identity,
+            ..
+        }) => Ok(stored[HEADER_LENGTH..].to_vec()),
+        Some(Header {
+            codec: Codec::Zstd,
+            size,
+        }) => zstd::bulk::decompress(&stored[HEADER_LENGTH..], size as usize)
+            .map_err(|e| StoreError::Backend(e.to_string())),
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: DataStore> DataStore for CompressedStore<S> {
+    async fn append(&self, user: &str, mailbox: &str, message: Message) -> Result<u32, StoreError> {
+        let body = self.encode(message.body)?;
+        self.inner
+            .append(user, mailbox, Message { body, ..message })
+            .await
+    }
+
+    async fn fetch(&self, user: &str, mailbox: &str, uid: u32) -> Result<Vec<u8>, StoreError> {
+        decode(self.inner.fetch(user, mailbox, uid).await?)
+    }
+
+    async fn list(&self, user: &str, mailbox: &str) -> Result<Vec<MessageMetadata>, StoreError> {
+        let mut messages = self.inner.list(user, mailbox).await?;
+        for message in messages.iter_mut() {
+            let stored = self.inner.fetch(user, mailbox, message.uid).await?;
+            if let Some(header) = Header::parse(&stored)? {
+                message.size = header.size;
+            }
+        }
+        Ok(messages)
+    }
+
+    async fn expunge(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>, StoreError> {
+        self.inner.expunge(user, mailbox, uids).await
+    }
+
+    async fn mailboxes(&self, user: &str) -> Result<Vec<String>, StoreError> {
+        self.inner.mailboxes(user).await
+    }
+
+    async fn apply(&self, user: &str, batch: Batch) -> Result<Vec<u32>, StoreError> {
+        let batch = batch.try_map(|message| {
+            let body = self.encode(message.body)?;
+            Ok(Message { body, ..message })
+        })?;
+        self.inner.apply(user, batch).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Codec, CompressedStore, Header, HEADER_LENGTH};
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message};
+
+    #[async_std::test]
+    async fn test_large_bodies_are_compressed() {
+        let inner = Arc::new(InMemoryStore::new());
+        let store = CompressedStore::new(inner.clone()).with_threshold(64);
+        let body = "Lorem ipsum dolor sit amet. ".repeat(100).into_bytes();
+        let uid = store
+            .append("me", "INBOX", Message::new(&body))
+            .await
+            .unwrap();
+
+        let stored = inner.fetch("me", "INBOX", uid).await.unwrap();
+        assert_eq!(Header::parse(&stored).unwrap().unwrap().codec, Codec::Zstd);
+        assert!(stored.len() < body.len());
+        assert_eq!(store.fetch("me", "INBOX", uid).await.unwrap(), body);
+        assert_eq!(
+            store.list("me", "INBOX").await.unwrap()[0].size,
+            body.len() as u64
+        );
+    }
+
+    #[async_std::test]
+    async fn test_small_and_existing_bodies_are_passed_through() {
+        let inner = Arc::new(InMemoryStore::new());
+        inner
+            .append("me", "INBOX", Message::new(b"legacy"))
+            .await
+            .unwrap();
+        let store = CompressedStore::new(inner.clone());
+        let uid = store
+            .append("me", "INBOX", Message::new(b"tiny"))
+            .await
+            .unwrap();
+
+        let stored = inner.fetch("me", "INBOX", uid).await.unwrap();
+        assert_eq!(
+            Header::parse(&stored).unwrap().unwrap().codec,
+            Codec::Identity
+        );
+        assert_eq!(stored.len(), HEADER_LENGTH + 4);
+        assert_eq!(
+            store.fetch("me", "INBOX", 1).await.unwrap(),
+            b"legacy".to_vec()
+        );
+        assert_eq!(
+            store.fetch("me", "INBOX", uid).await.unwrap(),
+            b"tiny".to_vec()
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashSet;
+
+use async_lock::Mutex;
+
+use super::gc::BlobStore;
+use super::object::Bucket;
+use super::{DataStore, Message, MessageMetadata, StoreError};
+
+const POINTER_PREFIX: &str = "blake3:";
+
+/// A `DataStore` wrapper which stores every distinct message body once.
+///
+/// Bodies are hashed with BLAKE3 and written to `blobs` under `blobs/<hash>`, with a reference
+/// count kept next to them under `refs/<hash>`. The wrapped store only receives a small pointer
+/// message (`blake3:<hash> <size>`) so UIDs, flags and internal dates are still owned by it. The
+/// blob is deleted once the last message referencing it has been expunged; any which a failure
+/// leaves behind are deleted by `gc::BlobCollector`.
+pub struct DedupStore<S: DataStore, B: Bucket> {
+    inner: S,
+    blobs: B,
+    /// Guards the reference counts, recording the hashes appended while garbage is collected.
+    references: Mutex<Option<HashSet<String>>>,
+}
+
+struct Pointer {
+    hash: String,
+    size: u64,
+}
+
+impl Pointer {
+    fn encode(&self) -> Vec<u8> {
+        format!("{}{} {}", POINTER_PREFIX, self.hash, self.size).into_bytes()
+    }
+    fn decode(data: &[u8]) -> Result<Self, StoreError> {
+        let malformed = || StoreError::Backend("malformed deduplication pointer".to_string());
+        let text = std::str::from_utf8(data).map_err(|_| malformed())?;
+        let (hash, size) = text
+            .strip_prefix(POINTER_PREFIX)
+            .and_then(|rest| rest.split_once(' '))
+            .ok_or_else(malformed)?;
+        Ok(Pointer {
+            hash: hash.to_string(),
+            size: size.trim().parse().map_err(|_| malformed())?,
+        })
+    }
+}
+
+fn blob_key(hash: &str) -> String {
+    format!("blobs/{}", hash)
+}
+
+fn reference_key(hash: &str) -> String {
+    format!("refs/{}", hash)
+}
+
+impl<S: DataStore, B: Bucket> DedupStore<S, B> {
+    pub fn new(inner: S, blobs: B) -> Self {
+        Self {
+            inner,
+            blobs,
+            references: Mutex::new(None),
+        }
+    }
+
+    async fn references(&self, hash: &str) -> Result<u64, StoreError> {
+        match self.blobs.get(&reference_key(hash)).await? {
+            Some(count) => String::from_utf8_lossy(&count)
+                .trim()
+                .parse()
+                .map_err(|_| StoreError::Backend(format!("corrupt reference count for {}", hash))),
+            None => Ok(0),
+        }
+    }
+
+    /// Adds `delta` to the reference count of a blob and returns the new count.
+    async fn adjust(&self, hash: &str, delta: i64) -> Result<u64, StoreError> {
+        let count = (self.references(hash).await? as i64 + delta).max(0) as u64;
+        match count {
+            0 => self.blobs.delete(&reference_key(hash)).await?,
+            _ => {
+                self.blobs
+                    .put(&reference_key(hash), count.to_string().into_bytes())
+                    .await?
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: DataStore, B: Bucket> DataStore for DedupStore<S, B> {
+    async fn append(&self, user: &str, mailbox: &str, message: Message) -> Result<u32, StoreError> {
+        let pointer = Pointer {
+            hash: blake3::hash(&message.body).to_hex().to_string(),
+            size: message.body.len() as u64,
+        };
+        let mut guard = self.references.lock().await;
+        if let Some(appended) = guard.as_mut() {
+            appended.insert(pointer.hash.clone());
+        }
+        if self.references(&pointer.hash).await? == 0 {
+            self.blobs
+                .put(&blob_key(&pointer.hash), message.body)
+                .await?;
+        }
+        self.adjust(&pointer.hash, 1).await?;
+        drop(guard);
+
+        let stored = Message {
+            flags: message.flags,
+            internal_date: message.internal_date,
+            body: pointer.encode(),
+        };
+        match self.inner.append(user, mailbox, stored).await {
+            Ok(uid) => Ok(uid),
+            Err(e) => {
+                let _guard = self.references.lock().await;
+                if self.adjust(&pointer.hash, -1).await? == 0 {
+                    self.blobs.delete(&blob_key(&pointer.hash)).await?;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn fetch(&self, user: &str, mailbox: &str, uid: u32) -> Result<Vec<u8>, StoreError> {
+        let pointer = Pointer::decode(&self.inner.fetch(user, mailbox, uid).await?)?;
+        self.blobs
+            .get(&blob_key(&pointer.hash))
+            .await?
+            .ok_or_else(|| {
+                StoreError::Backend(format!(
+                    "blob {} for message {} is missing",
+                    pointer.hash, uid
+                ))
+            })
+    }
+
+    async fn list(&self, user: &str, mailbox: &str) -> Result<Vec<MessageMetadata>, StoreError> {
+        let mut messages = self.inner.list(user, mailbox).await?;
+        for message in messages.iter_mut() {
+            let pointer = Pointer::decode(&self.inner.fetch(user, mailbox, message.uid).await?)?;
+            message.size = pointer.size;
+        }
+        Ok(messages)
+    }
+
+    async fn expunge(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>, StoreError> {
+        let mut pointers = vec![];
+        for uid in uids {
+            if let Ok(data) = self.inner.fetch(user, mailbox, *uid).await {
+                pointers.push((*uid, Pointer::decode(&data)?));
+            }
+        }
+        let expunged = self.inner.expunge(user, mailbox, uids).await?;
+        let _guard = self.references.lock().await;
+        for (uid, pointer) in pointers {
+            if expunged.contains(&uid) && self.adjust(&pointer.hash, -1).await? == 0 {
+                self.blobs.delete(&blob_key(&pointer.hash)).await?;
+            }
+        }
+        Ok(expunged)
+    }
+
+    async fn mailboxes(&self, user: &str) -> Result<Vec<String>, StoreError> {
+        self.inner.mailboxes(user).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: DataStore, B: Bucket> BlobStore for DedupStore<S, B> {
+    async fn begin_collection(&self) {
+        *self.references.lock().await = Some(HashSet::new());
+    }
+    async fn blobs(&self) -> Result<Vec<String>, StoreError> {
+        self.blobs.list("blobs/").await
+    }
+    async fn referenced_blobs(&self, users: &[String]) -> Result<HashSet<String>, StoreError> {
+        let mut referenced = HashSet::new();
+        for user in users {
+            for mailbox in self.inner.mailboxes(user).await? {
+                for message in self.inner.list(user, &mailbox).await? {
+                    let data = match self.inner.fetch(user, &mailbox, message.uid).await {
+                        Ok(data) => data,
+                        // Expunged since it was listed.
+                        Err(StoreError::MessageDoesNotExist(..)) => continue,
+                        Err(e) => return Err(e),
+                    };
+                    referenced.insert(blob_key(&Pointer::decode(&data)?.hash));
+                }
+            }
+        }
+        Ok(referenced)
+    }
+    async fn blob_size(&self, key: &str) -> Result<Option<u64>, StoreError> {
+        self.blobs.size(key).await
+    }
+    /// Deletes the reference counts of the orphans along with them, as they can only be left
+    /// by a failure.
+    async fn finish_collection(&self, orphans: &[String]) -> Result<Vec<String>, StoreError> {
+        let mut guard = self.references.lock().await;
+        let appended = guard.take().unwrap_or_default();
+        let mut deleted = vec![];
+        for key in orphans {
+            let hash = key.strip_prefix("blobs/").unwrap_or(key);
+            if appended.contains(hash) {
+                continue;
+            }
+            self.blobs.delete(&reference_key(hash)).await?;
+            self.blobs.delete(key).await?;
+            deleted.push(key.to_string());
+        }
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::DedupStore;
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::object::{Bucket, FileBucket, InMemoryBucket};
+    use crate::store::{DataStore, Message};
+
+    #[async_std::test]
+    async fn test_shared_bodies_are_reference_counted() {
+        let blobs = Arc::new(InMemoryBucket::new());
+        let store = DedupStore::new(InMemoryStore::new(), blobs.clone());
+        let attachment = vec![b'x'; 4096];
+        store
+            .append("me", "INBOX", Message::new(&attachment))
+            .await
+            .unwrap();
+        store
+            .append("you", "INBOX", Message::new(&attachment))
+            .await
+            .unwrap();
+        assert_eq!(blobs.list("blobs/").await.unwrap().len(), 1);
+        assert_eq!(store.list("you", "INBOX").await.unwrap()[0].size, 4096);
+
+        store.expunge("me", "INBOX", &[1]).await.unwrap();
+        assert_eq!(store.fetch("you", "INBOX", 1).await.unwrap(), attachment);
+        store.expunge("you", "INBOX", &[1]).await.unwrap();
+        assert!(blobs.list("").await.unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_blobs_on_disk() {
+        let root = std::env::temp_dir().join(format!("treasurmap-dedup-{}", std::process::id()));
+        let store = DedupStore::new(InMemoryStore::new(), FileBucket::new(&root));
+        let uid = store
+            .append("me", "Sent", Message::new(b"hello"))
+            .await
+            .unwrap();
+        assert_eq!(
+            store.fetch("me", "Sent", uid).await.unwrap(),
+            b"hello".to_vec()
+        );
+        assert_eq!(
+            FileBucket::new(&root).list("refs/").await.unwrap(),
+            vec![format!("refs/{}", blake3::hash(b"hello").to_hex())]
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
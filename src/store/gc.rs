@@ -0,0 +1,302 @@
+//! Garbage collection of blobs which no message refers to any more.
+//!
+//! Stores which share one blob between identical bodies cannot always delete it along with a
+//! message: `ObjectStore` never does, and `DedupStore` leaks blobs if it fails between writing a
+//! blob and counting the reference to it. `BlobCollector` marks every blob referenced by the
+//! messages of any user and sweeps the rest, but only once they have been found orphaned for a
+//! whole safety window, so a blob uploaded just before its message is recorded is never lost.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use async_lock::Mutex;
+use log::{debug, info};
+
+use super::StoreError;
+use crate::metrics::Metrics;
+use crate::scheduler::Job;
+use crate::server::Components;
+use crate::util::Result;
+
+/// A store keeping message bodies as blobs which messages refer to.
+#[async_trait::async_trait]
+pub trait BlobStore: Sync + Send {
+    /// Starts recording the blobs messages are appended with, until `finish_collection`.
+    async fn begin_collection(&self);
+    /// The keys of every blob held.
+    async fn blobs(&self) -> std::result::Result<Vec<String>, StoreError>;
+    /// The keys of the blobs referred to by messages, including at least those of `users`.
+    async fn referenced_blobs(
+        &self,
+        users: &[String],
+    ) -> std::result::Result<HashSet<String>, StoreError>;
+    async fn blob_size(&self, key: &str) -> std::result::Result<Option<u64>, StoreError>;
+    /// Deletes the blobs of `orphans` no message has been appended with since
+    /// `begin_collection`, and stops recording them. Returns the keys which were deleted.
+    async fn finish_collection(
+        &self,
+        orphans: &[String],
+    ) -> std::result::Result<Vec<String>, StoreError>;
+}
+
+#[async_trait::async_trait]
+impl<S: BlobStore + ?Sized> BlobStore for std::sync::Arc<S> {
+    async fn begin_collection(&self) {
+        (**self).begin_collection().await
+    }
+    async fn blobs(&self) -> std::result::Result<Vec<String>, StoreError> {
+        (**self).blobs().await
+    }
+    async fn referenced_blobs(
+        &self,
+        users: &[String],
+    ) -> std::result::Result<HashSet<String>, StoreError> {
+        (**self).referenced_blobs(users).await
+    }
+    async fn blob_size(&self, key: &str) -> std::result::Result<Option<u64>, StoreError> {
+        (**self).blob_size(key).await
+    }
+    async fn finish_collection(
+        &self,
+        orphans: &[String],
+    ) -> std::result::Result<Vec<String>, StoreError> {
+        (**self).finish_collection(orphans).await
+    }
+}
+
+/// What one run of a `BlobCollector` found.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Collection {
+    /// Blobs referenced by no message, whether or not they were old enough to delete.
+    pub orphans: usize,
+    pub deleted: usize,
+    /// The size of the blobs deleted, or which would have been in a dry run.
+    pub reclaimed_bytes: u64,
+}
+
+/// Deletes the blobs of a `BlobStore` which have been orphaned for longer than a safety
+/// window, a day by default. Run it as a job with `ServerBuilder::with_job`, giving it the
+/// same store as the server, such as an `Arc<ObjectStore<_>>`.
+///
+/// When orphans were first seen is only kept in memory, so a restart starts the window again.
+pub struct BlobCollector<S: BlobStore> {
+    store: S,
+    window: Duration,
+    dry_run: bool,
+    orphaned_since: Mutex<HashMap<String, Instant>>,
+}
+
+impl<S: BlobStore> BlobCollector<S> {
+    pub fn new(store: S) -> Self {
+        Self {
+            store,
+            window: Duration::from_secs(24 * 60 * 60),
+            dry_run: false,
+            orphaned_since: Mutex::new(HashMap::new()),
+        }
+    }
+    /// How long a blob must have been orphaned for before it is deleted.
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+    /// Only logs and counts the blobs which would be deleted.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Marks the blobs referenced by the messages of `users` and sweeps the orphans which are
+    /// old enough.
+    pub async fn collect(&self, users: &[String]) -> Result<Collection> {
+        self.store.begin_collection().await;
+        let (orphans, expired) = match self.find_orphans(users).await {
+            Ok(found) => found,
+            Err(e) => {
+                self.store.finish_collection(&[]).await?;
+                return Err(e);
+            }
+        };
+        let deleted = match self.dry_run {
+            true => {
+                self.store.finish_collection(&[]).await?;
+                expired.keys().cloned().collect()
+            }
+            false => {
+                let keys: Vec<String> = expired.keys().cloned().collect();
+                self.store.finish_collection(&keys).await?
+            }
+        };
+
+        let mut orphaned_since = self.orphaned_since.lock().await;
+        orphaned_since.retain(|key, _| orphans.contains(key));
+        let mut collection = Collection {
+            orphans: orphans.len(),
+            deleted: deleted.len(),
+            reclaimed_bytes: 0,
+        };
+        for key in deleted {
+            collection.reclaimed_bytes += expired.get(&key).copied().unwrap_or(0);
+            match self.dry_run {
+                true => info!("Would collect orphaned blob {}", key),
+                false => {
+                    debug!("Collected orphaned blob {}", key);
+                    orphaned_since.remove(&key);
+                }
+            }
+        }
+        Ok(collection)
+    }
+
+    /// Finds the orphans, and the sizes of those which have been orphaned for the whole
+    /// window.
+    async fn find_orphans(
+        &self,
+        users: &[String],
+    ) -> Result<(HashSet<String>, BTreeMap<String, u64>)> {
+        let referenced = self.store.referenced_blobs(users).await?;
+        let orphans: HashSet<String> = self
+            .store
+            .blobs()
+            .await?
+            .into_iter()
+            .filter(|key| !referenced.contains(key))
+            .collect();
+        let now = Instant::now();
+        let mut expired = BTreeMap::new();
+        for key in &orphans {
+            let since = *self
+                .orphaned_since
+                .lock()
+                .await
+                .entry(key.to_string())
+                .or_insert(now);
+            if now.duration_since(since) >= self.window {
+                let size = self.store.blob_size(key).await?.unwrap_or(0);
+                expired.insert(key.to_string(), size);
+            }
+        }
+        Ok((orphans, expired))
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: BlobStore> Job for BlobCollector<S> {
+    fn name(&self) -> &str {
+        "blob_gc"
+    }
+    async fn run(&self, components: &Components, metrics: &Metrics) -> Result<()> {
+        let users = components.user_store.list().await?;
+        let collection = self.collect(&users).await?;
+        metrics.blobs_collected(
+            collection.deleted as u64,
+            collection.reclaimed_bytes,
+            self.dry_run,
+        );
+        if collection.deleted > 0 {
+            info!(
+                "{} {} orphaned blobs, reclaiming {} bytes",
+                if self.dry_run {
+                    "Would delete"
+                } else {
+                    "Deleted"
+                },
+                collection.deleted,
+                collection.reclaimed_bytes
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{BlobCollector, BlobStore, Collection};
+    use crate::store::dedup::DedupStore;
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::object::{Bucket, InMemoryBucket, ObjectStore};
+    use crate::store::{DataStore, Message};
+
+    #[async_std::test]
+    async fn test_orphans_are_collected_after_the_window() {
+        let bucket = Arc::new(InMemoryBucket::new());
+        let store = Arc::new(ObjectStore::new(bucket.clone()));
+        store
+            .append("me", "INBOX", Message::new(b"kept"))
+            .await
+            .unwrap();
+        store
+            .append("me", "INBOX", Message::new(b"expunged"))
+            .await
+            .unwrap();
+        store.expunge("me", "INBOX", &[2]).await.unwrap();
+        let users = vec!["me".to_string()];
+
+        let waiting = BlobCollector::new(store.clone()).with_window(Duration::from_secs(3600));
+        let found = waiting.collect(&users).await.unwrap();
+        assert_eq!(
+            found,
+            Collection {
+                orphans: 1,
+                ..Collection::default()
+            }
+        );
+
+        let dry = BlobCollector::new(store.clone())
+            .with_window(Duration::ZERO)
+            .with_dry_run(true);
+        let reclaimable = dry.collect(&users).await.unwrap();
+        assert_eq!(reclaimable.reclaimed_bytes, 8);
+        assert_eq!(bucket.list("blobs/").await.unwrap().len(), 2);
+
+        let collector = BlobCollector::new(store.clone()).with_window(Duration::ZERO);
+        let collected = collector.collect(&users).await.unwrap();
+        assert_eq!(
+            collected,
+            Collection {
+                orphans: 1,
+                deleted: 1,
+                reclaimed_bytes: 8
+            }
+        );
+        assert_eq!(bucket.list("blobs/").await.unwrap().len(), 1);
+        assert_eq!(
+            store.fetch("me", "INBOX", 1).await.unwrap(),
+            b"kept".to_vec()
+        );
+    }
+
+    #[async_std::test]
+    async fn test_blobs_appended_during_a_collection_are_kept() {
+        let blobs = Arc::new(InMemoryBucket::new());
+        let store = DedupStore::new(InMemoryStore::new(), blobs.clone());
+        // A blob written before a failure left it without a reference.
+        let hash = blake3::hash(b"body").to_hex().to_string();
+        blobs
+            .put(&format!("blobs/{}", hash), b"body".to_vec())
+            .await
+            .unwrap();
+        blobs.put("blobs/stray", b"stray".to_vec()).await.unwrap();
+
+        store.begin_collection().await;
+        let orphans = vec![format!("blobs/{}", hash), "blobs/stray".to_string()];
+        assert!(store
+            .referenced_blobs(&["me".to_string()])
+            .await
+            .unwrap()
+            .is_empty());
+        store
+            .append("me", "INBOX", Message::new(b"body"))
+            .await
+            .unwrap();
+        let deleted = store.finish_collection(&orphans).await.unwrap();
+        assert_eq!(deleted, vec!["blobs/stray".to_string()]);
+        assert_eq!(
+            store.fetch("me", "INBOX", 1).await.unwrap(),
+            b"body".to_vec()
+        );
+    }
+}
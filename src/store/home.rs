@@ -0,0 +1,106 @@
+use std::sync::Arc;
+
+use super::name::resolve_mailbox;
+use super::{Batch, DataStore, Message, MessageBody, MessageMetadata, StoreError};
+use crate::index::Owner;
+
+/// The mailboxes of one user in a data store. Every call is made as that user, with the
+/// mailbox name checked by `resolve_mailbox` first, so handlers given a `Home` can only
+/// reach the mailboxes of the user who logged in.
+#[derive(Clone)]
+pub struct Home {
+    store: Arc<Box<dyn DataStore>>,
+    owner: Owner,
+}
+
+impl Home {
+    pub fn new(store: Arc<Box<dyn DataStore>>, owner: Owner) -> Self {
+        Self { store, owner }
+    }
+    pub fn owner(&self) -> &Owner {
+        &self.owner
+    }
+    pub async fn append(&self, mailbox: &str, message: Message) -> Result<u32, StoreError> {
+        let mailbox = resolve_mailbox(mailbox)?;
+        self.store
+            .append(self.owner.name(), &mailbox, message)
+            .await
+    }
+    pub async fn fetch(&self, mailbox: &str, uid: u32) -> Result<Vec<u8>, StoreError> {
+        let mailbox = resolve_mailbox(mailbox)?;
+        self.store.fetch(self.owner.name(), &mailbox, uid).await
+    }
+    pub async fn fetch_header(&self, mailbox: &str, uid: u32) -> Result<Vec<u8>, StoreError> {
+        let mailbox = resolve_mailbox(mailbox)?;
+        self.store
+            .fetch_header(self.owner.name(), &mailbox, uid)
+            .await
+    }
+    pub async fn fetch_range(
+        &self,
+        mailbox: &str,
+        uid: u32,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, StoreError> {
+        let mailbox = resolve_mailbox(mailbox)?;
+        self.store
+            .fetch_range(self.owner.name(), &mailbox, uid, offset, length)
+            .await
+    }
+    pub async fn open(&self, mailbox: &str, uid: u32) -> Result<(u64, MessageBody), StoreError> {
+        let mailbox = resolve_mailbox(mailbox)?;
+        self.store.open(self.owner.name(), &mailbox, uid).await
+    }
+    pub async fn list(&self, mailbox: &str) -> Result<Vec<MessageMetadata>, StoreError> {
+        let mailbox = resolve_mailbox(mailbox)?;
+        self.store.list(self.owner.name(), &mailbox).await
+    }
+    pub async fn expunge(&self, mailbox: &str, uids: &[u32]) -> Result<Vec<u32>, StoreError> {
+        let mailbox = resolve_mailbox(mailbox)?;
+        self.store.expunge(self.owner.name(), &mailbox, uids).await
+    }
+    pub async fn mailboxes(&self) -> Result<Vec<String>, StoreError> {
+        self.store.mailboxes(self.owner.name()).await
+    }
+    /// Makes every change of `batch` or none of them, see `DataStore::apply`.
+    pub async fn apply(&self, batch: Batch) -> Result<Vec<u32>, StoreError> {
+        let (appends, expunges) = batch.into_parts();
+        let mut resolved = Batch::new();
+        for (mailbox, message) in appends {
+            resolved = resolved.with_append(&resolve_mailbox(&mailbox)?, message);
+        }
+        for (mailbox, uids) in expunges {
+            resolved = resolved.with_expunge(&resolve_mailbox(&mailbox)?, &uids);
+        }
+        self.store.apply(self.owner.name(), resolved).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::Home;
+    use crate::index::Owner;
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message, StoreError};
+
+    #[async_std::test]
+    async fn test_home() {
+        let store: Arc<Box<dyn DataStore>> = Arc::new(Box::new(InMemoryStore::new()));
+        store
+            .append("other", "INBOX", Message::new(b"Theirs"))
+            .await
+            .unwrap();
+        let home = Home::new(store.clone(), Owner::new("me"));
+        let uid = home.append("inbox", Message::new(b"Mine")).await.unwrap();
+        assert_eq!(home.fetch("INBOX", uid).await.unwrap(), b"Mine");
+        assert_eq!(store.fetch("me", "INBOX", uid).await.unwrap(), b"Mine");
+        assert_eq!(home.mailboxes().await.unwrap(), vec!["INBOX"]);
+        assert!(matches!(
+            home.fetch("../other/INBOX", 1).await,
+            Err(StoreError::InvalidName(_))
+        ));
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, HashMap};
+
+use async_lock::RwLock;
+
+use super::{slice, Batch, DataStore, Message, MessageMetadata, StoreError};
+use crate::mime::split_header;
+
+#[derive(Default)]
+struct StoredMailbox {
+    uid_next: u32,
+    messages: BTreeMap<u32, Message>,
+}
+
+pub struct InMemoryStore {
+    mailboxes: RwLock<HashMap<(String, String), StoredMailbox>>,
+}
+
+impl Default for InMemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        Self {
+            mailboxes: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+fn key(user: &str, mailbox: &str) -> (String, String) {
+    (user.to_string(), mailbox.to_string())
+}
+
+fn append(
+    mailboxes: &mut HashMap<(String, String), StoredMailbox>,
+    key: (String, String),
+    message: Message,
+) -> u32 {
+    let stored = mailboxes.entry(key).or_insert_with(|| StoredMailbox {
+        uid_next: 1,
+        ..Default::default()
+    });
+    let uid = stored.uid_next;
+    stored.uid_next += 1;
+    stored.messages.insert(uid, message);
+    uid
+}
+
+#[async_trait::async_trait]
+impl DataStore for InMemoryStore {
+    async fn append(&self, user: &str, mailbox: &str, message: Message) -> Result<u32, StoreError> {
+        let mut write_lock = self.mailboxes.write().await;
+        Ok(append(&mut write_lock, key(user, mailbox), message))
+    }
+
+    async fn fetch(&self, user: &str, mailbox: &str, uid: u32) -> Result<Vec<u8>, StoreError> {
+        let read_lock = self.mailboxes.read().await;
+        let stored = read_lock
+            .get(&key(user, mailbox))
+            .ok_or_else(|| StoreError::MailboxDoesNotExist(mailbox.to_string()))?;
+        match stored.messages.get(&uid) {
+            Some(message) => Ok(message.body.clone()),
+            None => Err(StoreError::MessageDoesNotExist(mailbox.to_string(), uid)),
+        }
+    }
+
+    async fn fetch_header(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<Vec<u8>, StoreError> {
+        let read_lock = self.mailboxes.read().await;
+        let stored = read_lock
+            .get(&key(user, mailbox))
+            .ok_or_else(|| StoreError::MailboxDoesNotExist(mailbox.to_string()))?;
+        match stored.messages.get(&uid) {
+            Some(message) => Ok(split_header(&message.body).0.to_vec()),
+            None => Err(StoreError::MessageDoesNotExist(mailbox.to_string(), uid)),
+        }
+    }
+
+    async fn fetch_range(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, StoreError> {
+        let read_lock = self.mailboxes.read().await;
+        let stored = read_lock
+            .get(&key(user, mailbox))
+            .ok_or_else(|| StoreError::MailboxDoesNotExist(mailbox.to_string()))?;
+        match stored.messages.get(&uid) {
+            Some(message) => Ok(slice(&message.body, offset, length).to_vec()),
+            None => Err(StoreError::MessageDoesNotExist(mailbox.to_string(), uid)),
+        }
+    }
+
+    async fn list(&self, user: &str, mailbox: &str) -> Result<Vec<MessageMetadata>, StoreError> {
+        let read_lock = self.mailboxes.read().await;
+        let stored = read_lock
+            .get(&key(user, mailbox))
+            .ok_or_else(|| StoreError::MailboxDoesNotExist(mailbox.to_string()))?;
+        Ok(stored
+            .messages
+            .iter()
+            .map(|(uid, message)| MessageMetadata {
+                uid: *uid,
+                flags: message.flags.clone(),
+                internal_date: message.internal_date,
+                size: message.body.len() as u64,
+            })
+            .collect())
+    }
+
+    async fn expunge(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>, StoreError> {
+        let mut write_lock = self.mailboxes.write().await;
+        let stored = write_lock
+            .get_mut(&key(user, mailbox))
+            .ok_or_else(|| StoreError::MailboxDoesNotExist(mailbox.to_string()))?;
+        Ok(uids
+            .iter()
+            .filter(|uid| stored.messages.remove(uid).is_some())
+            .copied()
+            .collect())
+    }
+
+    async fn mailboxes(&self, user: &str) -> Result<Vec<String>, StoreError> {
+        let mut mailboxes: Vec<String> = self
+            .mailboxes
+            .read()
+            .await
+            .keys()
+            .filter(|(owner, _)| owner == user)
+            .map(|(_, mailbox)| mailbox.clone())
+            .collect();
+        mailboxes.sort();
+        Ok(mailboxes)
+    }
+
+    async fn apply(&self, user: &str, batch: Batch) -> Result<Vec<u32>, StoreError> {
+        let (appends, expunges) = batch.into_parts();
+        let mut write_lock = self.mailboxes.write().await;
+        // Holding the lock throughout, nothing is changed until nothing can fail.
+        let exists = |mailbox: &String| {
+            write_lock.contains_key(&key(user, mailbox))
+                || appends.iter().any(|(appended, _)| appended == mailbox)
+        };
+        if let Some((mailbox, _)) = expunges.iter().find(|(mailbox, _)| !exists(mailbox)) {
+            return Err(StoreError::MailboxDoesNotExist(mailbox.clone()));
+        }
+        let uids = appends
+            .into_iter()
+            .map(|(mailbox, message)| append(&mut write_lock, key(user, &mailbox), message))
+            .collect();
+        for (mailbox, expunged) in expunges {
+            if let Some(stored) = write_lock.get_mut(&key(user, &mailbox)) {
+                for uid in expunged {
+                    stored.messages.remove(&uid);
+                }
+            }
+        }
+        Ok(uids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InMemoryStore;
+    use crate::store::{Batch, DataStore, Message, StoreError};
+
+    #[async_std::test]
+    async fn test_can_append_and_fetch() {
+        let store = InMemoryStore::new();
+        let first = store
+            .append("me", "INBOX", Message::new(b"first"))
+            .await
+            .unwrap();
+        let second = store
+            .append("me", "INBOX", Message::new(b"second"))
+            .await
+            .unwrap();
+        assert_eq!((first, second), (1, 2));
+        assert_eq!(
+            store.fetch("me", "INBOX", 2).await.unwrap(),
+            b"second".to_vec()
+        );
+        store
+            .append("me", "INBOX", Message::new(b"Subject: third\r\n\r\nbody"))
+            .await
+            .unwrap();
+        assert_eq!(
+            store.fetch_header("me", "INBOX", 3).await.unwrap(),
+            b"Subject: third\r\n\r\n".to_vec()
+        );
+        assert_eq!(
+            store.fetch_range("me", "INBOX", 3, 4, 100).await.unwrap(),
+            b"ect: third\r\n\r\nbody".to_vec()
+        );
+        assert!(store
+            .fetch_range("me", "INBOX", 3, 100, 1)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            store.fetch("you", "INBOX", 1).await,
+            Err(StoreError::MailboxDoesNotExist(..))
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_expunge_does_not_reuse_uids() {
+        let store = InMemoryStore::new();
+        store
+            .append("me", "INBOX", Message::new(b"first"))
+            .await
+            .unwrap();
+        assert_eq!(
+            store.expunge("me", "INBOX", &[1, 7]).await.unwrap(),
+            vec![1]
+        );
+        assert_eq!(
+            store
+                .append("me", "INBOX", Message::new(b"second"))
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(store.list("me", "INBOX").await.unwrap().len(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_batch_is_all_or_nothing() {
+        let store = InMemoryStore::new();
+        let batch = Batch::new()
+            .with_append("Archive", Message::new(b"first"))
+            .with_expunge("Missing", &[1]);
+        assert!(matches!(
+            store.apply("me", batch).await,
+            Err(StoreError::MailboxDoesNotExist(..))
+        ));
+        assert!(store.mailboxes("me").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,220 @@
+pub mod batch;
+pub mod chaos;
+pub mod compressed;
+pub mod dedup;
+pub mod gc;
+pub mod home;
+pub mod inmemory;
+pub mod name;
+pub mod object;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod sqlite;
+pub mod timed;
+pub mod usage;
+
+use std::{error::Error, fmt::Display, pin::Pin, sync::Arc, time::SystemTime};
+
+use futures::AsyncRead;
+
+use crate::mime::split_header;
+
+pub use batch::Batch;
+
+/// The body of a message read as it is sent, see `DataStore::open`.
+pub type MessageBody = Pin<Box<dyn AsyncRead + Send>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub flags: Vec<String>,
+    pub internal_date: SystemTime,
+    pub body: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(body: &[u8]) -> Self {
+        Self {
+            flags: vec![],
+            internal_date: SystemTime::now(),
+            body: body.to_vec(),
+        }
+    }
+    pub fn with_flags(mut self, flags: Vec<&str>) -> Self {
+        self.flags = flags.iter().map(|flag| flag.to_string()).collect();
+        self
+    }
+    pub fn with_internal_date(mut self, internal_date: SystemTime) -> Self {
+        self.internal_date = internal_date;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageMetadata {
+    pub uid: u32,
+    pub flags: Vec<String>,
+    pub internal_date: SystemTime,
+    pub size: u64,
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    MailboxDoesNotExist(String),
+    MessageDoesNotExist(String, u32),
+    /// A mailbox name which could reach outside the home of the user. See `name::resolve_mailbox`.
+    InvalidName(String),
+    /// Storing the message would take the user past their quota.
+    OverQuota(String),
+    Backend(String),
+}
+impl Error for StoreError {}
+impl Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::MailboxDoesNotExist(name) => {
+                write!(f, "Mailbox {} does not exist in the data store", name)
+            }
+            StoreError::MessageDoesNotExist(name, uid) => {
+                write!(f, "Message {} does not exist in mailbox {}", uid, name)
+            }
+            StoreError::InvalidName(name) => {
+                write!(f, "Mailbox name {} is not allowed", name)
+            }
+            StoreError::OverQuota(user) => {
+                write!(f, "User {} is over their storage quota", user)
+            }
+            StoreError::Backend(reason) => {
+                write!(f, "Data store failure: {}", reason)
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+pub trait DataStore: Sync + Send {
+    /// Stores a message at the end of `mailbox`, creating the mailbox if this is its first
+    /// message, and returns the UID assigned to it.
+    async fn append(&self, user: &str, mailbox: &str, message: Message) -> Result<u32, StoreError>;
+    async fn fetch(&self, user: &str, mailbox: &str, uid: u32) -> Result<Vec<u8>, StoreError>;
+    async fn list(&self, user: &str, mailbox: &str) -> Result<Vec<MessageMetadata>, StoreError>;
+    /// Permanently removes the given messages and returns the UIDs which were actually removed.
+    async fn expunge(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>, StoreError>;
+    /// Returns the names of every mailbox holding messages for `user`, sorted by name.
+    async fn mailboxes(&self, user: &str) -> Result<Vec<String>, StoreError>;
+    /// Makes every change of `batch` or, if one of them fails, none of them, and returns the
+    /// UIDs of the appended messages in the order they were added. Stores which can should
+    /// make the changes in one transaction; by default they are made a call at a time and the
+    /// appends undone on failure, see `batch::apply_each`.
+    async fn apply(&self, user: &str, batch: Batch) -> Result<Vec<u32>, StoreError> {
+        batch::apply_each(self, user, batch).await
+    }
+    /// Returns the header section of a message, including the blank line which ends it. Stores
+    /// which can read part of a message should override this to avoid loading the whole body.
+    async fn fetch_header(&self, user: &str, mailbox: &str, uid: u32) -> Result<Vec<u8>, StoreError> {
+        let message = self.fetch(user, mailbox, uid).await?;
+        Ok(split_header(&message).0.to_vec())
+    }
+    /// Returns at most `length` bytes of a message starting at `offset`, or nothing if the
+    /// offset is past its end. Stores which can read part of a message should override this.
+    async fn fetch_range(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, StoreError> {
+        let message = self.fetch(user, mailbox, uid).await?;
+        Ok(slice(&message, offset, length).to_vec())
+    }
+    /// Returns the size of a message and a reader of its body, so it can be sent to a client
+    /// without being held in memory. Stores which can read a message as it is sent should
+    /// override this; by default the whole message is loaded first.
+    async fn open(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<(u64, MessageBody), StoreError> {
+        let message = self.fetch(user, mailbox, uid).await?;
+        Ok((
+            message.len() as u64,
+            Box::pin(futures::io::Cursor::new(message)),
+        ))
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: DataStore + ?Sized> DataStore for Arc<S> {
+    async fn append(&self, user: &str, mailbox: &str, message: Message) -> Result<u32, StoreError> {
+        (**self).append(user, mailbox, message).await
+    }
+    async fn fetch(&self, user: &str, mailbox: &str, uid: u32) -> Result<Vec<u8>, StoreError> {
+        (**self).fetch(user, mailbox, uid).await
+    }
+    async fn list(&self, user: &str, mailbox: &str) -> Result<Vec<MessageMetadata>, StoreError> {
+        (**self).list(user, mailbox).await
+    }
+    async fn expunge(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>, StoreError> {
+        (**self).expunge(user, mailbox, uids).await
+    }
+    async fn mailboxes(&self, user: &str) -> Result<Vec<String>, StoreError> {
+        (**self).mailboxes(user).await
+    }
+    async fn apply(&self, user: &str, batch: Batch) -> Result<Vec<u32>, StoreError> {
+        (**self).apply(user, batch).await
+    }
+    async fn fetch_header(&self, user: &str, mailbox: &str, uid: u32) -> Result<Vec<u8>, StoreError> {
+        (**self).fetch_header(user, mailbox, uid).await
+    }
+    async fn fetch_range(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, StoreError> {
+        (**self).fetch_range(user, mailbox, uid, offset, length).await
+    }
+    async fn open(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<(u64, MessageBody), StoreError> {
+        (**self).open(user, mailbox, uid).await
+    }
+}
+
+/// Returns the part of `data` a ranged read of `length` bytes at `offset` covers.
+pub(crate) fn slice(data: &[u8], offset: u64, length: u64) -> &[u8] {
+    let start = (offset.min(data.len() as u64)) as usize;
+    let end = (offset.saturating_add(length).min(data.len() as u64)) as usize;
+    &data[start..end]
+}
+
+/// Escapes a user or mailbox name so it can safely be used as a single file name or key segment.
+/// A leading `.` is escaped too so that names like `..` can never refer to a parent directory.
+pub(crate) fn escape(name: &str) -> String {
+    name.bytes()
+        .enumerate()
+        .map(|(position, byte)| match byte {
+            b'.' if position == 0 => "%2E".to_string(),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'@' | b'.' | b'-' | b'_' | b'+' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
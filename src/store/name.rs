@@ -0,0 +1,98 @@
+//! Mailbox names as the stores and the index keep them.
+//!
+//! The same mailbox can be named several ways: INBOX in any case (RFC 9051 section 5.1),
+//! with a trailing hierarchy delimiter as CREATE allows, or with its accented characters
+//! composed or decomposed. `normalize` turns each into one name, which every index backend
+//! keys mailboxes by, and `resolve_mailbox` also refuses the names a client may not use, for
+//! handlers to check what they are sent before using it.
+
+use unicode_normalization::UnicodeNormalization;
+
+use super::StoreError;
+
+/// The hierarchy delimiter of mailbox names, as LIST reports it.
+pub const DELIMITER: char = '/';
+
+/// `name` in its one form: in Unicode NFC, without a trailing delimiter, and with INBOX in
+/// upper case, as the top level of the names below it too.
+pub fn normalize(name: &str) -> String {
+    let name: String = name.nfc().collect();
+    let name = name.strip_suffix(DELIMITER).unwrap_or(&name);
+    let (top, rest) = match name.split_once(DELIMITER) {
+        Some((top, rest)) => (top, Some(rest)),
+        None => (name, None),
+    };
+    match (top.eq_ignore_ascii_case("INBOX"), rest) {
+        (true, Some(rest)) => format!("INBOX{}{}", DELIMITER, rest),
+        (true, None) => "INBOX".to_string(),
+        (false, _) => name.to_string(),
+    }
+}
+
+/// Checks a mailbox name a client sent and returns it normalized.
+///
+/// Names are relative to the home of the user, so anything which could climb out of it or
+/// name another namespace is refused: `.` and `..` levels, empty levels from a leading or
+/// doubled delimiter, a leading `~` or `#`, backslashes and control characters.
+pub fn resolve_mailbox(name: &str) -> Result<String, StoreError> {
+    let invalid = || StoreError::InvalidName(name.to_string());
+    let normalized = normalize(name);
+    if normalized.starts_with(['~', '#'])
+        || normalized.contains('\\')
+        || normalized.contains(char::is_control)
+    {
+        return Err(invalid());
+    }
+    if normalized
+        .split(DELIMITER)
+        .any(|level| matches!(level, "" | "." | ".."))
+    {
+        return Err(invalid());
+    }
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize, resolve_mailbox};
+    use crate::store::StoreError;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("inbox"), "INBOX");
+        assert_eq!(normalize("Inbox/Receipts"), "INBOX/Receipts");
+        assert_eq!(normalize("Inboxes"), "Inboxes");
+        assert_eq!(normalize("Work/"), "Work");
+        assert_eq!(normalize("Caf\u{65}\u{301}"), "Caf\u{e9}");
+        assert_eq!(normalize("Work/inbox"), "Work/inbox");
+    }
+
+    #[test]
+    fn test_resolve_mailbox() {
+        assert_eq!(resolve_mailbox("inbox").unwrap(), "INBOX");
+        assert_eq!(resolve_mailbox("Work/2024").unwrap(), "Work/2024");
+        assert_eq!(resolve_mailbox("Work/").unwrap(), "Work");
+        assert_eq!(resolve_mailbox(".hidden").unwrap(), ".hidden");
+        for name in [
+            "",
+            "/",
+            "..",
+            "Work/../../other",
+            "./INBOX",
+            "/etc/passwd",
+            "Work//",
+            "Work//2024",
+            "~other/INBOX",
+            "#shared/INBOX",
+            "..\\other",
+            "Work\0",
+            "Work\r\n",
+        ] {
+            assert!(
+                matches!(resolve_mailbox(name), Err(StoreError::InvalidName(_))),
+                "{:?} was accepted",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,843 @@
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use async_lock::{Mutex, RwLock};
+use async_std::io::prelude::{ReadExt, SeekExt};
+use async_std::io::SeekFrom;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::gc::BlobStore;
+use super::{escape, slice, DataStore, Message, MessageMetadata, StoreError};
+use crate::util::uuid;
+
+/// Minimal key/value interface over an object storage bucket.
+#[async_trait::async_trait]
+pub trait Bucket: Sync + Send {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), StoreError>;
+    /// Writes `data` under `key` unless an object is already there, returning whether it was
+    /// written. Servers sharing a bucket claim UIDs with it, so it must be atomic.
+    async fn put_if_absent(&self, key: &str, data: Vec<u8>) -> Result<bool, StoreError>;
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError>;
+    async fn exists(&self, key: &str) -> Result<bool, StoreError>;
+    async fn delete(&self, key: &str) -> Result<(), StoreError>;
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, StoreError>;
+    /// Returns at most `length` bytes of an object starting at `offset`.
+    async fn get_range(
+        &self,
+        key: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self
+            .get(key)
+            .await?
+            .map(|data| slice(&data, offset, length).to_vec()))
+    }
+    /// Returns the size of an object in bytes.
+    async fn size(&self, key: &str) -> Result<Option<u64>, StoreError> {
+        Ok(self.get(key).await?.map(|data| data.len() as u64))
+    }
+}
+
+pub struct InMemoryBucket {
+    objects: RwLock<BTreeMap<String, Vec<u8>>>,
+}
+
+impl Default for InMemoryBucket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryBucket {
+    pub fn new() -> Self {
+        Self {
+            objects: RwLock::new(BTreeMap::new()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Bucket for InMemoryBucket {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), StoreError> {
+        self.objects.write().await.insert(key.to_string(), data);
+        Ok(())
+    }
+    async fn put_if_absent(&self, key: &str, data: Vec<u8>) -> Result<bool, StoreError> {
+        match self.objects.write().await.entry(key.to_string()) {
+            btree_map::Entry::Vacant(vacant) => {
+                vacant.insert(data);
+                Ok(true)
+            }
+            btree_map::Entry::Occupied(..) => Ok(false),
+        }
+    }
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self.objects.read().await.get(key).cloned())
+    }
+    async fn get_range(
+        &self,
+        key: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self
+            .objects
+            .read()
+            .await
+            .get(key)
+            .map(|data| slice(data, offset, length).to_vec()))
+    }
+    async fn exists(&self, key: &str) -> Result<bool, StoreError> {
+        Ok(self.objects.read().await.contains_key(key))
+    }
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        self.objects.write().await.remove(key);
+        Ok(())
+    }
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, StoreError> {
+        Ok(self
+            .objects
+            .read()
+            .await
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+}
+
+/// A `Bucket` storing each object as a file below `root`, with `/` in keys mapping to
+/// subdirectories.
+pub struct FileBucket {
+    root: PathBuf,
+}
+
+impl FileBucket {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        key.split('/')
+            .filter(|segment| !segment.is_empty())
+            .fold(self.root.clone(), |path, segment| {
+                path.join(escape(segment))
+            })
+    }
+}
+
+fn backend<E: Display>(e: E) -> StoreError {
+    StoreError::Backend(e.to_string())
+}
+
+#[async_trait::async_trait]
+impl Bucket for FileBucket {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), StoreError> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            async_std::fs::create_dir_all(parent)
+                .await
+                .map_err(backend)?;
+        }
+        // Write to a temporary file first so readers never observe a partially written object.
+        let temporary = path.with_extension("tmp");
+        async_std::fs::write(&temporary, data)
+            .await
+            .map_err(backend)?;
+        async_std::fs::rename(&temporary, &path)
+            .await
+            .map_err(backend)
+    }
+    async fn put_if_absent(&self, key: &str, data: Vec<u8>) -> Result<bool, StoreError> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            async_std::fs::create_dir_all(parent)
+                .await
+                .map_err(backend)?;
+        }
+        // Linking fails where the object exists, while renaming would replace it. Each writer
+        // has its own temporary file, as several may race for the same key.
+        let temporary = path.with_extension(format!("{}.tmp", uuid()));
+        async_std::fs::write(&temporary, data)
+            .await
+            .map_err(backend)?;
+        let linked = async_std::fs::hard_link(&temporary, &path).await;
+        let _ = async_std::fs::remove_file(&temporary).await;
+        match linked {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(backend(e)),
+        }
+    }
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        match async_std::fs::read(self.path(key)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(backend(e)),
+        }
+    }
+    async fn get_range(
+        &self,
+        key: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        let mut file = match async_std::fs::File::open(self.path(key)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(backend(e)),
+        };
+        let mut data = vec![];
+        file.seek(SeekFrom::Start(offset)).await.map_err(backend)?;
+        file.take(length)
+            .read_to_end(&mut data)
+            .await
+            .map_err(backend)?;
+        Ok(Some(data))
+    }
+    async fn exists(&self, key: &str) -> Result<bool, StoreError> {
+        match async_std::fs::metadata(self.path(key)).await {
+            Ok(metadata) => Ok(metadata.is_file()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(backend(e)),
+        }
+    }
+    async fn size(&self, key: &str) -> Result<Option<u64>, StoreError> {
+        match async_std::fs::metadata(self.path(key)).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(backend(e)),
+        }
+    }
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        match async_std::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(backend(e)),
+            _ => Ok(()),
+        }
+    }
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, StoreError> {
+        // Only the directory the prefix ends in is walked, rather than the whole bucket.
+        let directory = prefix
+            .rsplit_once('/')
+            .map_or("", |(directory, _)| directory);
+        let key: String = directory
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| format!("{}/", segment))
+            .collect();
+        let mut keys = vec![];
+        let mut pending = vec![(async_std::path::PathBuf::from(self.path(directory)), key)];
+        while let Some((directory, key)) = pending.pop() {
+            let mut entries = match async_std::fs::read_dir(&directory).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(backend(e)),
+            };
+            while let Some(entry) = entries.next().await {
+                let entry = entry.map_err(backend)?;
+                let name = unescape(&entry.file_name().to_string_lossy());
+                let child = format!("{}{}", key, name);
+                if entry.path().is_dir().await {
+                    let child = format!("{}/", child);
+                    if child.starts_with(prefix) || prefix.starts_with(&child) {
+                        pending.push((entry.path(), child));
+                    }
+                } else if child.starts_with(prefix) && !child.ends_with(".tmp") {
+                    keys.push(child);
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+fn unescape(name: &str) -> String {
+    let mut bytes = vec![];
+    let mut input = name.bytes();
+    while let Some(byte) = input.next() {
+        if byte == b'%' {
+            let code: String = input.by_ref().take(2).map(|byte| byte as char).collect();
+            if let Ok(value) = u8::from_str_radix(&code, 16) {
+                bytes.push(value);
+                continue;
+            }
+        }
+        bytes.push(byte);
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    uid: u32,
+    flags: Vec<String>,
+    internal_date: u64,
+    size: u64,
+    blob: String,
+}
+
+impl From<&Record> for MessageMetadata {
+    fn from(record: &Record) -> Self {
+        MessageMetadata {
+            uid: record.uid,
+            flags: record.flags.clone(),
+            internal_date: UNIX_EPOCH + Duration::from_secs(record.internal_date),
+            size: record.size,
+        }
+    }
+}
+
+impl Record {
+    /// Reads a record, or `None` for the empty object an expunged message leaves behind.
+    fn read(data: &[u8]) -> Result<Option<Self>, StoreError> {
+        if data.is_empty() {
+            return Ok(None);
+        }
+        serde_json::from_slice(data)
+            .map(Some)
+            .map_err(|e| StoreError::Backend(e.to_string()))
+    }
+}
+
+#[derive(Default)]
+struct CatalogEntry {
+    uid_next: u32,
+    records: BTreeMap<u32, Record>,
+}
+
+/// A `DataStore` writing message bodies to an object storage `Bucket` under content-addressed
+/// keys (`<prefix>blobs/<sha256>`), so identical bodies are only uploaded once.
+///
+/// Per-message metadata is written alongside as small JSON records under
+/// `<prefix>meta/<user>/<mailbox>/` and cached in memory after the first access to a mailbox.
+/// Expunging a message only empties its record; blobs which are no longer referenced are left for
+/// garbage collection since other messages may share them. See `gc::BlobCollector`.
+///
+/// Several servers may share a bucket. Each UID is claimed by writing its record with
+/// `Bucket::put_if_absent`, and an expunged message leaves an empty record behind, so no UID
+/// is handed out twice. A server reads the messages others appended when asked for them by
+/// UID, or when listing a mailbox whose UIDNEXT has moved past what it cached.
+pub struct ObjectStore<B: Bucket> {
+    bucket: B,
+    prefix: String,
+    catalog: RwLock<HashMap<(String, String), CatalogEntry>>,
+    /// Serialises appends, recording the blobs they refer to while garbage is collected.
+    appending: Mutex<Option<HashSet<String>>>,
+}
+
+impl<B: Bucket> ObjectStore<B> {
+    pub fn new(bucket: B) -> Self {
+        Self {
+            bucket,
+            prefix: String::new(),
+            catalog: RwLock::new(HashMap::new()),
+            appending: Mutex::new(None),
+        }
+    }
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    fn blob_key(&self, body: &[u8]) -> String {
+        let digest = Sha256::digest(body);
+        let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{}blobs/{}", self.prefix, hex)
+    }
+    fn mailbox_prefix(&self, user: &str, mailbox: &str) -> String {
+        format!("{}meta/{}/{}/", self.prefix, escape(user), escape(mailbox))
+    }
+
+    /// The UIDNEXT the bucket records for the mailbox under `prefix`, or `None` if it has
+    /// never been written to. Servers sharing the bucket may have claimed UIDs past it.
+    async fn uid_next(&self, prefix: &str, mailbox: &str) -> Result<Option<u32>, StoreError> {
+        match self.bucket.get(&format!("{}uidnext", prefix)).await? {
+            Some(value) => String::from_utf8_lossy(&value)
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|_| StoreError::Backend(format!("corrupt UIDNEXT for {}", mailbox))),
+            None => Ok(None),
+        }
+    }
+
+    /// Loads the records for a mailbox from the bucket if they are not cached yet. Returns
+    /// `false` if the mailbox has never been written to.
+    async fn load(&self, user: &str, mailbox: &str) -> Result<bool, StoreError> {
+        let key = (user.to_string(), mailbox.to_string());
+        if self.catalog.read().await.contains_key(&key) {
+            return Ok(true);
+        }
+        let prefix = self.mailbox_prefix(user, mailbox);
+        let uid_next = match self.uid_next(&prefix, mailbox).await? {
+            Some(uid_next) => uid_next,
+            None => return Ok(false),
+        };
+        let mut records = BTreeMap::new();
+        for object in self.bucket.list(&format!("{}uid/", prefix)).await? {
+            let data = self.bucket.get(&object).await?;
+            if let Some(record) = data.as_deref().map(Record::read).transpose()?.flatten() {
+                records.insert(record.uid, record);
+            }
+        }
+        self.catalog
+            .write()
+            .await
+            .entry(key)
+            .or_insert(CatalogEntry { uid_next, records });
+        Ok(true)
+    }
+
+    async fn record(&self, user: &str, mailbox: &str, uid: u32) -> Result<Record, StoreError> {
+        if !self.load(user, mailbox).await? {
+            return Err(StoreError::MailboxDoesNotExist(mailbox.to_string()));
+        }
+        let key = (user.to_string(), mailbox.to_string());
+        let cached = self
+            .catalog
+            .read()
+            .await
+            .get(&key)
+            .and_then(|entry| entry.records.get(&uid))
+            .cloned();
+        if let Some(record) = cached {
+            return Ok(record);
+        }
+        // Another server sharing the bucket may have appended it since the mailbox was loaded.
+        let prefix = self.mailbox_prefix(user, mailbox);
+        let data = self.bucket.get(&format!("{}uid/{}", prefix, uid)).await?;
+        let record = match data.as_deref().map(Record::read).transpose()?.flatten() {
+            Some(record) => record,
+            None => return Err(StoreError::MessageDoesNotExist(mailbox.to_string(), uid)),
+        };
+        if let Some(entry) = self.catalog.write().await.get_mut(&key) {
+            entry.records.insert(uid, record.clone());
+        }
+        Ok(record)
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Bucket> DataStore for ObjectStore<B> {
+    async fn append(&self, user: &str, mailbox: &str, message: Message) -> Result<u32, StoreError> {
+        let mut appending = self.appending.lock().await;
+        self.load(user, mailbox).await?;
+        let blob = self.blob_key(&message.body);
+        if let Some(appended) = appending.as_mut() {
+            appended.insert(blob.clone());
+        }
+        let size = message.body.len() as u64;
+        if !self.bucket.exists(&blob).await? {
+            self.bucket.put(&blob, message.body).await?;
+        }
+
+        let key = (user.to_string(), mailbox.to_string());
+        let prefix = self.mailbox_prefix(user, mailbox);
+        // Servers sharing the bucket may have appended since, so the UID is claimed by writing
+        // the record only where there is none yet, from the highest UIDNEXT known.
+        let cached = self
+            .catalog
+            .read()
+            .await
+            .get(&key)
+            .map_or(1, |entry| entry.uid_next);
+        let recorded = self.uid_next(&prefix, mailbox).await?.unwrap_or(1);
+        let mut record = Record {
+            uid: cached.max(recorded),
+            flags: message.flags,
+            internal_date: message
+                .internal_date
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0),
+            size,
+            blob,
+        };
+        loop {
+            let data =
+                serde_json::to_vec(&record).map_err(|e| StoreError::Backend(e.to_string()))?;
+            let claim = format!("{}uid/{}", prefix, record.uid);
+            if self.bucket.put_if_absent(&claim, data).await? {
+                break;
+            }
+            record.uid += 1;
+        }
+        let uid = record.uid;
+        self.bucket
+            .put(
+                &format!("{}uidnext", prefix),
+                (uid + 1).to_string().into_bytes(),
+            )
+            .await?;
+
+        let mut catalog = self.catalog.write().await;
+        let entry = catalog.entry(key).or_default();
+        entry.uid_next = entry.uid_next.max(uid + 1);
+        entry.records.insert(uid, record);
+        Ok(uid)
+    }
+
+    async fn fetch(&self, user: &str, mailbox: &str, uid: u32) -> Result<Vec<u8>, StoreError> {
+        let record = self.record(user, mailbox, uid).await?;
+        self.bucket.get(&record.blob).await?.ok_or_else(|| {
+            StoreError::Backend(format!(
+                "blob {} for message {} is missing",
+                record.blob, uid
+            ))
+        })
+    }
+
+    async fn fetch_range(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, StoreError> {
+        let record = self.record(user, mailbox, uid).await?;
+        self.bucket
+            .get_range(&record.blob, offset, length)
+            .await?
+            .ok_or_else(|| {
+                StoreError::Backend(format!(
+                    "blob {} for message {} is missing",
+                    record.blob, uid
+                ))
+            })
+    }
+
+    async fn list(&self, user: &str, mailbox: &str) -> Result<Vec<MessageMetadata>, StoreError> {
+        if !self.load(user, mailbox).await? {
+            return Err(StoreError::MailboxDoesNotExist(mailbox.to_string()));
+        }
+        // Reloaded once other servers sharing the bucket have appended to it.
+        let key = (user.to_string(), mailbox.to_string());
+        let cached = self
+            .catalog
+            .read()
+            .await
+            .get(&key)
+            .map(|entry| entry.uid_next);
+        let prefix = self.mailbox_prefix(user, mailbox);
+        if self.uid_next(&prefix, mailbox).await? > cached {
+            self.catalog.write().await.remove(&key);
+            self.load(user, mailbox).await?;
+        }
+        let catalog = self.catalog.read().await;
+        Ok(catalog
+            .get(&(user.to_string(), mailbox.to_string()))
+            .map(|entry| entry.records.values().map(MessageMetadata::from).collect())
+            .unwrap_or_default())
+    }
+
+    async fn expunge(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>, StoreError> {
+        if !self.load(user, mailbox).await? {
+            return Err(StoreError::MailboxDoesNotExist(mailbox.to_string()));
+        }
+        let prefix = self.mailbox_prefix(user, mailbox);
+        let mut catalog = self.catalog.write().await;
+        let entry = catalog
+            .get_mut(&(user.to_string(), mailbox.to_string()))
+            .ok_or_else(|| StoreError::MailboxDoesNotExist(mailbox.to_string()))?;
+        let mut expunged = vec![];
+        for uid in uids {
+            let record = format!("{}uid/{}", prefix, uid);
+            // Another server sharing the bucket may have appended it since it was loaded.
+            let exists = match entry.records.contains_key(uid) {
+                true => true,
+                false => self
+                    .bucket
+                    .get(&record)
+                    .await?
+                    .is_some_and(|data| !data.is_empty()),
+            };
+            if exists {
+                // An empty record is left in its place, so that the UID is never claimed again.
+                self.bucket.put(&record, vec![]).await?;
+                entry.records.remove(uid);
+                expunged.push(*uid);
+            }
+        }
+        Ok(expunged)
+    }
+
+    async fn mailboxes(&self, user: &str) -> Result<Vec<String>, StoreError> {
+        let prefix = format!("{}meta/{}/", self.prefix, escape(user));
+        let mut mailboxes: Vec<String> = self
+            .bucket
+            .list(&prefix)
+            .await?
+            .iter()
+            .filter_map(|key| key[prefix.len()..].strip_suffix("/uidnext"))
+            .map(unescape)
+            .collect();
+        mailboxes.sort();
+        Ok(mailboxes)
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Bucket> BlobStore for ObjectStore<B> {
+    async fn begin_collection(&self) {
+        *self.appending.lock().await = Some(HashSet::new());
+    }
+    async fn blobs(&self) -> Result<Vec<String>, StoreError> {
+        self.bucket.list(&format!("{}blobs/", self.prefix)).await
+    }
+    /// Reads the record of every message in the bucket, whoever it belongs to.
+    async fn referenced_blobs(&self, _: &[String]) -> Result<HashSet<String>, StoreError> {
+        let mut referenced = HashSet::new();
+        let prefix = format!("{}meta/", self.prefix);
+        for key in self.bucket.list(&prefix).await? {
+            // Records are under <user>/<mailbox>/uid/<uid>, and escaped names hold no `/`.
+            let segments: Vec<&str> = key[prefix.len()..].split('/').collect();
+            if segments.len() != 4 || segments[2] != "uid" {
+                continue;
+            }
+            let data = self.bucket.get(&key).await?;
+            if let Some(record) = data.as_deref().map(Record::read).transpose()?.flatten() {
+                referenced.insert(record.blob);
+            }
+        }
+        Ok(referenced)
+    }
+    async fn blob_size(&self, key: &str) -> Result<Option<u64>, StoreError> {
+        self.bucket.size(key).await
+    }
+    async fn finish_collection(&self, orphans: &[String]) -> Result<Vec<String>, StoreError> {
+        let mut appending = self.appending.lock().await;
+        let appended = appending.take().unwrap_or_default();
+        let mut deleted = vec![];
+        for key in orphans.iter().filter(|key| !appended.contains(*key)) {
+            self.bucket.delete(key).await?;
+            deleted.push(key.to_string());
+        }
+        Ok(deleted)
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Bucket> Bucket for std::sync::Arc<B> {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), StoreError> {
+        (**self).put(key, data).await
+    }
+    async fn put_if_absent(&self, key: &str, data: Vec<u8>) -> Result<bool, StoreError> {
+        (**self).put_if_absent(key, data).await
+    }
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        (**self).get(key).await
+    }
+    async fn exists(&self, key: &str) -> Result<bool, StoreError> {
+        (**self).exists(key).await
+    }
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        (**self).delete(key).await
+    }
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, StoreError> {
+        (**self).list(prefix).await
+    }
+    async fn get_range(
+        &self,
+        key: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        (**self).get_range(key, offset, length).await
+    }
+    async fn size(&self, key: &str) -> Result<Option<u64>, StoreError> {
+        (**self).size(key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Bucket, FileBucket, InMemoryBucket, ObjectStore};
+    use crate::store::{DataStore, Message};
+
+    #[async_std::test]
+    async fn test_identical_bodies_are_stored_once() {
+        let bucket = Arc::new(InMemoryBucket::new());
+        let store = ObjectStore::new(bucket.clone()).with_prefix("mail/");
+        store
+            .append("me", "INBOX", Message::new(b"body"))
+            .await
+            .unwrap();
+        store
+            .append("me", "Sent", Message::new(b"body"))
+            .await
+            .unwrap();
+
+        assert_eq!(bucket.list("mail/blobs/").await.unwrap().len(), 1);
+        assert_eq!(
+            store.fetch("me", "Sent", 1).await.unwrap(),
+            b"body".to_vec()
+        );
+        assert_eq!(
+            store.fetch_range("me", "Sent", 1, 2, 10).await.unwrap(),
+            b"dy".to_vec()
+        );
+    }
+
+    #[async_std::test]
+    async fn test_metadata_is_reloaded_from_the_bucket() {
+        let bucket = Arc::new(InMemoryBucket::new());
+        let store = ObjectStore::new(bucket.clone());
+        store
+            .append(
+                "me",
+                "INBOX",
+                Message::new(b"first").with_flags(vec!["\\Seen"]),
+            )
+            .await
+            .unwrap();
+        store
+            .append("me", "INBOX", Message::new(b"second"))
+            .await
+            .unwrap();
+        store.expunge("me", "INBOX", &[2]).await.unwrap();
+
+        let reopened = ObjectStore::new(bucket);
+        let listed = reopened.list("me", "INBOX").await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].flags, vec!["\\Seen".to_string()]);
+        assert_eq!(listed[0].size, 5);
+        assert_eq!(
+            reopened
+                .append("me", "INBOX", Message::new(b"third"))
+                .await
+                .unwrap(),
+            3
+        );
+        store
+            .append("me", "Lists/rust", Message::new(b"fourth"))
+            .await
+            .unwrap();
+        assert_eq!(
+            reopened.mailboxes("me").await.unwrap(),
+            vec!["INBOX".to_string(), "Lists/rust".to_string()]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_servers_sharing_a_bucket() {
+        let bucket = Arc::new(InMemoryBucket::new());
+        let first = ObjectStore::new(bucket.clone());
+        let second = ObjectStore::new(bucket.clone());
+        first
+            .append("me", "INBOX", Message::new(b"one"))
+            .await
+            .unwrap();
+        second.list("me", "INBOX").await.unwrap();
+        first
+            .append("me", "INBOX", Message::new(b"two"))
+            .await
+            .unwrap();
+
+        // As if a slower server had written back the UIDNEXT it knew, the second server has
+        // only seen the first message, yet claims the next free UID.
+        let uidnext = "meta/me/INBOX/uidnext";
+        bucket.put(uidnext, b"2".to_vec()).await.unwrap();
+        let uid = second
+            .append("me", "INBOX", Message::new(b"three"))
+            .await
+            .unwrap();
+        assert_eq!(uid, 3);
+        assert_eq!(
+            second.fetch("me", "INBOX", 2).await.unwrap(),
+            b"two".to_vec()
+        );
+        assert_eq!(
+            first.fetch("me", "INBOX", 3).await.unwrap(),
+            b"three".to_vec()
+        );
+        assert_eq!(first.list("me", "INBOX").await.unwrap().len(), 3);
+
+        // An expunged UID stays claimed.
+        assert_eq!(second.expunge("me", "INBOX", &[3]).await.unwrap(), vec![3]);
+        bucket.put(uidnext, b"3".to_vec()).await.unwrap();
+        let third = ObjectStore::new(bucket);
+        assert_eq!(third.list("me", "INBOX").await.unwrap().len(), 2);
+        let uid = third
+            .append("me", "INBOX", Message::new(b"four"))
+            .await
+            .unwrap();
+        assert_eq!(uid, 4);
+    }
+
+    #[async_std::test]
+    async fn test_file_bucket_lists_below_prefix() {
+        let root = std::env::temp_dir().join(format!("treasurmap-prefix-{}", std::process::id()));
+        let bucket = FileBucket::new(&root);
+        for key in ["a/b/one", "a/c/two", "ab/three", "d/four"] {
+            bucket.put(key, b"data".to_vec()).await.unwrap();
+        }
+        assert_eq!(
+            bucket.list("a/").await.unwrap(),
+            vec!["a/b/one".to_string(), "a/c/two".to_string()]
+        );
+        assert_eq!(
+            bucket.list("a/b").await.unwrap(),
+            vec!["a/b/one".to_string()]
+        );
+        assert_eq!(
+            bucket.list("a").await.unwrap(),
+            vec!["a/b/one", "a/c/two", "ab/three"]
+        );
+        assert!(bucket.list("e/").await.unwrap().is_empty());
+        assert!(bucket.exists("d/four").await.unwrap());
+        assert!(!bucket.exists("d").await.unwrap());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_file_bucket_keys_stay_below_root() {
+        let root = std::env::temp_dir().join(format!("treasurmap-bucket-{}", std::process::id()));
+        let bucket = FileBucket::new(root.join("objects"));
+        bucket.put("../escaped", b"data".to_vec()).await.unwrap();
+        assert!(!root.join("escaped").exists());
+        assert_eq!(
+            bucket.list("").await.unwrap(),
+            vec!["../escaped".to_string()]
+        );
+        assert_eq!(
+            bucket.get_range("../escaped", 1, 2).await.unwrap(),
+            Some(b"at".to_vec())
+        );
+        assert_eq!(
+            bucket.get("../escaped").await.unwrap(),
+            Some(b"data".to_vec())
+        );
+        assert!(!bucket
+            .put_if_absent("../escaped", b"other".to_vec())
+            .await
+            .unwrap());
+        bucket.delete("../escaped").await.unwrap();
+        assert!(!bucket.exists("../escaped").await.unwrap());
+        assert!(bucket
+            .put_if_absent("../escaped", b"other".to_vec())
+            .await
+            .unwrap());
+        assert_eq!(
+            bucket.list("").await.unwrap(),
+            vec!["../escaped".to_string()]
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
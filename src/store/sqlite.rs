@@ -0,0 +1,627 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+
+use super::{escape, Batch, DataStore, Message, MessageBody, MessageMetadata, StoreError};
+use crate::mime::split_header;
+use crate::runtime::spawn_blocking;
+
+/// How much of a message `fetch_header` reads before falling back to loading all of it.
+const HEADER_PREFIX: usize = 64 * 1024;
+
+/// How long a write waits for another server to release a database before failing.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS mailboxes (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    uid_next INTEGER NOT NULL,
+    uid_validity INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS messages (
+    mailbox_id INTEGER NOT NULL REFERENCES mailboxes(id) ON DELETE CASCADE,
+    uid INTEGER NOT NULL,
+    flags TEXT NOT NULL,
+    internal_date INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    body BLOB,
+    PRIMARY KEY (mailbox_id, uid)
+);
+";
+
+/// A `DataStore` keeping each user's messages in their own SQLite database under `root`.
+///
+/// Bodies are stored inline in the database by default. With `with_inline_bodies(false)` only
+/// the metadata lives in SQLite and bodies are written to a `<user>.blobs` directory next to it.
+///
+/// Several servers may share `root`, as they share a `SqliteIndex`: UIDs are allocated in
+/// transactions which hold the database's write lock, so no two servers hand out the same one.
+pub struct SqliteStore {
+    root: PathBuf,
+    inline_bodies: bool,
+    connections: Mutex<HashMap<String, Arc<Mutex<Connection>>>>,
+}
+
+impl SqliteStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            inline_bodies: true,
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+    pub fn with_inline_bodies(mut self, inline_bodies: bool) -> Self {
+        self.inline_bodies = inline_bodies;
+        self
+    }
+
+    fn connection(&self, user: &str) -> Result<Arc<Mutex<Connection>>, StoreError> {
+        let mut connections = self.connections.lock().map_err(backend)?;
+        if let Some(connection) = connections.get(user) {
+            return Ok(connection.clone());
+        }
+        std::fs::create_dir_all(&self.root).map_err(backend)?;
+        let connection = Connection::open(self.root.join(format!("{}.sqlite", escape(user))))
+            .map_err(backend)?;
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .map_err(backend)?;
+        connection
+            .pragma_update(None, "foreign_keys", "ON")
+            .map_err(backend)?;
+        connection.busy_timeout(BUSY_TIMEOUT).map_err(backend)?;
+        connection.execute_batch(SCHEMA).map_err(backend)?;
+        let connection = Arc::new(Mutex::new(connection));
+        connections.insert(user.to_string(), connection.clone());
+        Ok(connection)
+    }
+
+    fn blob_directory(&self, user: &str) -> PathBuf {
+        self.root.join(format!("{}.blobs", escape(user)))
+    }
+
+    async fn run<T, F>(&self, user: &str, operation: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, StoreError> + Send + 'static,
+    {
+        let connection = self.connection(user)?;
+        spawn_blocking(move || {
+            let mut connection = connection.lock().map_err(backend)?;
+            operation(&mut connection)
+        })
+        .await
+    }
+}
+
+fn backend<E: Display>(e: E) -> StoreError {
+    StoreError::Backend(e.to_string())
+}
+
+fn blob_path(directory: &Path, mailbox_id: i64, uid: u32) -> PathBuf {
+    directory.join(mailbox_id.to_string()).join(uid.to_string())
+}
+
+fn to_seconds(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn from_seconds(seconds: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(seconds.max(0) as u64)
+}
+
+fn mailbox_id(connection: &Connection, mailbox: &str) -> Result<i64, StoreError> {
+    connection
+        .query_row(
+            "SELECT id FROM mailboxes WHERE name = ?1",
+            params![mailbox],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(backend)?
+        .ok_or_else(|| StoreError::MailboxDoesNotExist(mailbox.to_string()))
+}
+
+/// Reads part of a message, using `substr` for inline bodies and a seek for external ones.
+fn read_range(
+    connection: &mut Connection,
+    blobs: &Path,
+    mailbox: &str,
+    uid: u32,
+    offset: u64,
+    length: u64,
+) -> Result<Vec<u8>, StoreError> {
+    let id = mailbox_id(connection, mailbox)?;
+    let (range, external): (Option<Vec<u8>>, bool) = connection
+        .query_row(
+            "SELECT substr(body, ?3, ?4), body IS NULL FROM messages WHERE mailbox_id = ?1 AND uid = ?2",
+            params![
+                id,
+                uid,
+                offset.saturating_add(1).min(i64::MAX as u64) as i64,
+                length.min(i64::MAX as u64) as i64
+            ],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .map_err(backend)?
+        .ok_or_else(|| StoreError::MessageDoesNotExist(mailbox.to_string(), uid))?;
+    if !external {
+        return Ok(range.unwrap_or_default());
+    }
+    let mut range = vec![];
+    let mut file = std::fs::File::open(blob_path(blobs, id, uid)).map_err(backend)?;
+    file.seek(SeekFrom::Start(offset)).map_err(backend)?;
+    file.take(length)
+        .read_to_end(&mut range)
+        .map_err(backend)?;
+    Ok(range)
+}
+
+/// Stores a message at the end of `mailbox` as part of `transaction`, writing its body under
+/// `blobs` if it is kept outside the database and noting the file in `written`.
+fn insert(
+    transaction: &Connection,
+    blobs: Option<&Path>,
+    mailbox: &str,
+    message: &Message,
+    written: &mut Vec<PathBuf>,
+) -> Result<u32, StoreError> {
+    transaction
+        .execute(
+            "INSERT OR IGNORE INTO mailboxes (name, uid_next, uid_validity) VALUES (?1, 1, ?2)",
+            params![mailbox, to_seconds(SystemTime::now())],
+        )
+        .map_err(backend)?;
+    let (id, uid): (i64, u32) = transaction
+        .query_row(
+            "SELECT id, uid_next FROM mailboxes WHERE name = ?1",
+            params![mailbox],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .map_err(backend)?;
+    transaction
+        .execute(
+            "UPDATE mailboxes SET uid_next = uid_next + 1 WHERE id = ?1",
+            params![id],
+        )
+        .map_err(backend)?;
+    let body = match blobs {
+        Some(directory) => {
+            let path = blob_path(directory, id, uid);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).map_err(backend)?;
+            }
+            std::fs::write(&path, &message.body).map_err(backend)?;
+            written.push(path);
+            None
+        }
+        None => Some(&message.body),
+    };
+    transaction
+        .execute(
+            "INSERT INTO messages (mailbox_id, uid, flags, internal_date, size, body) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                id,
+                uid,
+                message.flags.join(" "),
+                to_seconds(message.internal_date),
+                message.body.len() as i64,
+                body
+            ],
+        )
+        .map_err(backend)?;
+    Ok(uid)
+}
+
+/// Removes messages from `mailbox` as part of `transaction`, returning the ID of the mailbox
+/// and the UIDs which were there. Their bodies are left for the caller to remove once the
+/// transaction is committed.
+fn delete(
+    transaction: &Connection,
+    mailbox: &str,
+    uids: &[u32],
+) -> Result<(i64, Vec<u32>), StoreError> {
+    let id = mailbox_id(transaction, mailbox)?;
+    let mut expunged = vec![];
+    for uid in uids {
+        let removed = transaction
+            .execute(
+                "DELETE FROM messages WHERE mailbox_id = ?1 AND uid = ?2",
+                params![id, uid],
+            )
+            .map_err(backend)?;
+        if removed > 0 {
+            expunged.push(*uid);
+        }
+    }
+    Ok((id, expunged))
+}
+
+/// Makes the changes of `batch` as part of `transaction`, returning the UIDs appended and the
+/// mailbox ID and UID of each message expunged.
+fn apply_batch(
+    transaction: &Connection,
+    blobs: Option<&Path>,
+    batch: Batch,
+    written: &mut Vec<PathBuf>,
+) -> Result<(Vec<u32>, Vec<(i64, u32)>), StoreError> {
+    let (appends, expunges) = batch.into_parts();
+    let mut uids = vec![];
+    for (mailbox, message) in &appends {
+        uids.push(insert(transaction, blobs, mailbox, message, written)?);
+    }
+    let mut removed = vec![];
+    for (mailbox, expunging) in &expunges {
+        let (id, expunged) = delete(transaction, mailbox, expunging)?;
+        removed.extend(expunged.into_iter().map(|uid| (id, uid)));
+    }
+    Ok((uids, removed))
+}
+
+/// Commits `transaction` if what was done in it succeeded. Otherwise, or if the commit fails,
+/// the transaction is rolled back and the bodies `written` for it are removed.
+fn commit<T>(
+    transaction: Transaction,
+    done: Result<T, StoreError>,
+    written: &[PathBuf],
+) -> Result<T, StoreError> {
+    let committed = done.and_then(|value| transaction.commit().map(|_| value).map_err(backend));
+    if committed.is_err() {
+        for path in written {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    committed
+}
+
+#[async_trait::async_trait]
+impl DataStore for SqliteStore {
+    async fn append(&self, user: &str, mailbox: &str, message: Message) -> Result<u32, StoreError> {
+        let mailbox = mailbox.to_string();
+        let blobs = (!self.inline_bodies).then(|| self.blob_directory(user));
+        self.run(user, move |connection| {
+            // Taking the write lock up front stops another server reading the same UIDNEXT.
+            let transaction = connection
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(backend)?;
+            let mut written = vec![];
+            let uid = insert(
+                &transaction,
+                blobs.as_deref(),
+                &mailbox,
+                &message,
+                &mut written,
+            );
+            commit(transaction, uid, &written)
+        })
+        .await
+    }
+
+    async fn fetch(&self, user: &str, mailbox: &str, uid: u32) -> Result<Vec<u8>, StoreError> {
+        let mailbox = mailbox.to_string();
+        let blobs = self.blob_directory(user);
+        self.run(user, move |connection| {
+            let id = mailbox_id(connection, &mailbox)?;
+            let body: Option<Vec<u8>> = connection
+                .query_row(
+                    "SELECT body FROM messages WHERE mailbox_id = ?1 AND uid = ?2",
+                    params![id, uid],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(backend)?
+                .ok_or_else(|| StoreError::MessageDoesNotExist(mailbox.clone(), uid))?;
+            match body {
+                Some(body) => Ok(body),
+                None => std::fs::read(blob_path(&blobs, id, uid)).map_err(backend),
+            }
+        })
+        .await
+    }
+
+    async fn fetch_header(&self, user: &str, mailbox: &str, uid: u32) -> Result<Vec<u8>, StoreError> {
+        let prefix = self
+            .fetch_range(user, mailbox, uid, 0, HEADER_PREFIX as u64)
+            .await?;
+        let header = split_header(&prefix).0;
+        // Unless the whole message fit in the prefix, the header only ended inside it if there
+        // is something after it.
+        if prefix.len() < HEADER_PREFIX || header.len() < prefix.len() {
+            return Ok(header.to_vec());
+        }
+        let message = self.fetch(user, mailbox, uid).await?;
+        Ok(split_header(&message).0.to_vec())
+    }
+
+    async fn fetch_range(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, StoreError> {
+        let mailbox = mailbox.to_string();
+        let blobs = self.blob_directory(user);
+        self.run(user, move |connection| {
+            read_range(connection, &blobs, &mailbox, uid, offset, length)
+        })
+        .await
+    }
+
+    async fn open(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<(u64, MessageBody), StoreError> {
+        let name = mailbox.to_string();
+        let blobs = self.blob_directory(user);
+        let external = self
+            .run(user, move |connection| {
+                let id = mailbox_id(connection, &name)?;
+                let (external, size): (bool, i64) = connection
+                    .query_row(
+                        "SELECT body IS NULL, size FROM messages WHERE mailbox_id = ?1 AND uid = ?2",
+                        params![id, uid],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .optional()
+                    .map_err(backend)?
+                    .ok_or_else(|| StoreError::MessageDoesNotExist(name.clone(), uid))?;
+                Ok(external.then(|| (blob_path(&blobs, id, uid), size as u64)))
+            })
+            .await?;
+        match external {
+            // A body kept in its own file is read from it as it is sent.
+            Some((path, size)) => {
+                let file = async_std::fs::File::open(&path).await.map_err(backend)?;
+                Ok((size, Box::pin(file)))
+            }
+            None => {
+                let message = self.fetch(user, mailbox, uid).await?;
+                Ok((
+                    message.len() as u64,
+                    Box::pin(futures::io::Cursor::new(message)),
+                ))
+            }
+        }
+    }
+
+    async fn list(&self, user: &str, mailbox: &str) -> Result<Vec<MessageMetadata>, StoreError> {
+        let mailbox = mailbox.to_string();
+        self.run(user, move |connection| {
+            let id = mailbox_id(connection, &mailbox)?;
+            let mut statement = connection
+                .prepare("SELECT uid, flags, internal_date, size FROM messages WHERE mailbox_id = ?1 ORDER BY uid")
+                .map_err(backend)?;
+            let messages = statement
+                .query_map(params![id], |row| {
+                    let flags: String = row.get(1)?;
+                    Ok(MessageMetadata {
+                        uid: row.get(0)?,
+                        flags: flags.split_whitespace().map(|flag| flag.to_string()).collect(),
+                        internal_date: from_seconds(row.get(2)?),
+                        size: row.get::<_, i64>(3)? as u64,
+                    })
+                })
+                .map_err(backend)?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(backend)?;
+            Ok(messages)
+        })
+        .await
+    }
+
+    async fn expunge(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>, StoreError> {
+        let mailbox = mailbox.to_string();
+        let uids = uids.to_vec();
+        let blobs = self.blob_directory(user);
+        self.run(user, move |connection| {
+            let transaction = connection.transaction().map_err(backend)?;
+            let (id, expunged) = delete(&transaction, &mailbox, &uids)?;
+            transaction.commit().map_err(backend)?;
+            for uid in &expunged {
+                let _ = std::fs::remove_file(blob_path(&blobs, id, *uid));
+            }
+            Ok(expunged)
+        })
+        .await
+    }
+
+    async fn mailboxes(&self, user: &str) -> Result<Vec<String>, StoreError> {
+        self.run(user, |connection| {
+            let mut statement = connection
+                .prepare("SELECT name FROM mailboxes ORDER BY name")
+                .map_err(backend)?;
+            let names = statement
+                .query_map([], |row| row.get(0))
+                .map_err(backend)?
+                .collect::<Result<Vec<String>, _>>()
+                .map_err(backend)?;
+            Ok(names)
+        })
+        .await
+    }
+
+    async fn apply(&self, user: &str, batch: Batch) -> Result<Vec<u32>, StoreError> {
+        let blobs = (!self.inline_bodies).then(|| self.blob_directory(user));
+        let directory = self.blob_directory(user);
+        self.run(user, move |connection| {
+            let transaction = connection
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(backend)?;
+            let mut written = vec![];
+            let applied = apply_batch(&transaction, blobs.as_deref(), batch, &mut written);
+            let (uids, removed) = commit(transaction, applied, &written)?;
+            for (id, uid) in removed {
+                let _ = std::fs::remove_file(blob_path(&directory, id, uid));
+            }
+            Ok(uids)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{blob_path, SqliteStore, HEADER_PREFIX};
+    use crate::store::escape;
+    use crate::store::{Batch, DataStore, Message, StoreError};
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("treasurmap-sqlite-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        root
+    }
+
+    #[async_std::test]
+    async fn test_messages_survive_reopening_the_store() {
+        let root = temp_root("reopen");
+        let date = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let store = SqliteStore::new(&root);
+        let uid = store
+            .append(
+                "me@email.com",
+                "INBOX",
+                Message::new(b"Subject: hi\r\n\r\nbody")
+                    .with_flags(vec!["\\Seen"])
+                    .with_internal_date(date),
+            )
+            .await
+            .unwrap();
+        drop(store);
+
+        let store = SqliteStore::new(&root);
+        assert_eq!(
+            store.fetch("me@email.com", "INBOX", uid).await.unwrap(),
+            b"Subject: hi\r\n\r\nbody".to_vec()
+        );
+        let listed = store.list("me@email.com", "INBOX").await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].flags, vec!["\\Seen".to_string()]);
+        assert_eq!(listed[0].internal_date, date);
+        assert_eq!(
+            store
+                .append("me@email.com", "INBOX", Message::new(b"next"))
+                .await
+                .unwrap(),
+            uid + 1
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_external_bodies_are_removed_on_expunge() {
+        let root = temp_root("external");
+        let store = SqliteStore::new(&root).with_inline_bodies(false);
+        let uid = store
+            .append("me", "Sent", Message::new(b"body"))
+            .await
+            .unwrap();
+        assert_eq!(
+            store.fetch("me", "Sent", uid).await.unwrap(),
+            b"body".to_vec()
+        );
+        assert_eq!(
+            store.expunge("me", "Sent", &[uid]).await.unwrap(),
+            vec![uid]
+        );
+        assert!(matches!(
+            store.fetch("me", "Sent", uid).await,
+            Err(StoreError::MessageDoesNotExist(..))
+        ));
+        assert!(matches!(
+            store.list("me", "Drafts").await,
+            Err(StoreError::MailboxDoesNotExist(..))
+        ));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_batch_is_one_transaction() {
+        let root = temp_root("batch");
+        let store = SqliteStore::new(&root).with_inline_bodies(false);
+        let uid = store
+            .append("me", "INBOX", Message::new(b"body"))
+            .await
+            .unwrap();
+        let failing = Batch::new()
+            .with_append("Archive", Message::new(b"body"))
+            .with_expunge("Missing", &[uid]);
+        assert!(store.apply("me", failing).await.is_err());
+        assert_eq!(store.mailboxes("me").await.unwrap(), vec!["INBOX"]);
+        // The body written for the append is removed with it.
+        assert!(!blob_path(&store.blob_directory("me"), 2, 1).exists());
+
+        let moving = Batch::new()
+            .with_append("Archive", Message::new(b"body"))
+            .with_expunge("INBOX", &[uid]);
+        let moved = store.apply("me", moving).await.unwrap();
+        assert_eq!(
+            store.fetch("me", "Archive", moved[0]).await.unwrap(),
+            b"body"
+        );
+        assert!(store.list("me", "INBOX").await.unwrap().is_empty());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_fetch_header_and_range() {
+        let root = temp_root("header");
+        let long = format!("X-Long: {}\r\n\r\nbody", "a".repeat(HEADER_PREFIX));
+        for inline in [true, false] {
+            let store = SqliteStore::new(&root).with_inline_bodies(inline);
+            let short = store
+                .append("me", "INBOX", Message::new(b"Subject: hi\r\n\r\nbody"))
+                .await
+                .unwrap();
+            let long = store
+                .append("me", "INBOX", Message::new(long.as_bytes()))
+                .await
+                .unwrap();
+            assert_eq!(
+                store.fetch_header("me", "INBOX", short).await.unwrap(),
+                b"Subject: hi\r\n\r\n".to_vec()
+            );
+            assert_eq!(
+                store.fetch_header("me", "INBOX", long).await.unwrap().len(),
+                HEADER_PREFIX + "X-Long: \r\n\r\n".len()
+            );
+            assert_eq!(
+                store.fetch_range("me", "INBOX", short, 9, 4).await.unwrap(),
+                b"hi\r\n".to_vec()
+            );
+            assert!(store
+                .fetch_range("me", "INBOX", short, 100, 4)
+                .await
+                .unwrap()
+                .is_empty());
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_user_file_names_are_escaped() {
+        assert_eq!(escape("me@email.com"), "me@email.com");
+        assert_eq!(escape("../etc/passwd"), "%2E.%2Fetc%2Fpasswd");
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use crate::slowlog::{measure, Stage};
+
+use super::{Batch, DataStore, Message, MessageBody, MessageMetadata, StoreError};
+
+/// A `DataStore` wrapper which charges the time spent in the wrapped store to the command
+/// being answered, for the slow log. Opening a message for streaming is charged, reading it
+/// as it is sent is part of writing the response.
+pub struct TimedStore {
+    inner: Arc<Box<dyn DataStore>>,
+}
+
+impl TimedStore {
+    pub fn new(inner: Arc<Box<dyn DataStore>>) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait::async_trait]
+impl DataStore for TimedStore {
+    async fn append(&self, user: &str, mailbox: &str, message: Message) -> Result<u32, StoreError> {
+        measure(
+            Stage::Store,
+            "append",
+            self.inner.append(user, mailbox, message),
+        )
+        .await
+    }
+    async fn fetch(&self, user: &str, mailbox: &str, uid: u32) -> Result<Vec<u8>, StoreError> {
+        measure(Stage::Store, "fetch", self.inner.fetch(user, mailbox, uid)).await
+    }
+    async fn list(&self, user: &str, mailbox: &str) -> Result<Vec<MessageMetadata>, StoreError> {
+        measure(Stage::Store, "list", self.inner.list(user, mailbox)).await
+    }
+    async fn expunge(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<Vec<u32>, StoreError> {
+        measure(
+            Stage::Store,
+            "expunge",
+            self.inner.expunge(user, mailbox, uids),
+        )
+        .await
+    }
+    async fn mailboxes(&self, user: &str) -> Result<Vec<String>, StoreError> {
+        measure(Stage::Store, "mailboxes", self.inner.mailboxes(user)).await
+    }
+    async fn apply(&self, user: &str, batch: Batch) -> Result<Vec<u32>, StoreError> {
+        measure(Stage::Store, "apply", self.inner.apply(user, batch)).await
+    }
+    async fn fetch_header(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<Vec<u8>, StoreError> {
+        measure(
+            Stage::Store,
+            "fetch_header",
+            self.inner.fetch_header(user, mailbox, uid),
+        )
+        .await
+    }
+    async fn fetch_range(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, StoreError> {
+        measure(
+            Stage::Store,
+            "fetch_range",
+            self.inner.fetch_range(user, mailbox, uid, offset, length),
+        )
+        .await
+    }
+    async fn open(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<(u64, MessageBody), StoreError> {
+        measure(Stage::Store, "open", self.inner.open(user, mailbox, uid)).await
+    }
+}
@@ -0,0 +1,289 @@
+//! The storage each user has used, as quotas are checked against it.
+//!
+//! `DomainStore` keeps the usage of each mailbox in a `Usage` ledger rather than listing
+//! every message of a user before each append, adding what it stores and forgetting a mailbox
+//! when messages are expunged from it. The ledger drifts whenever mail reaches the data store
+//! some other way, such as through another server sharing the backend or a store written to
+//! directly, or when a write fails part way. `RecalculateUsage` recomputes the usage of every
+//! mailbox from the data store and corrects the ledger, logging each mailbox it was wrong
+//! about and counting the corrections in `Metrics`. It runs on a schedule, see
+//! `ServerBuilder::with_usage_recalculation`, and when an administrator asks for it, see
+//! `Server::recalculate_usage`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use async_std::task::sleep;
+use log::{info, warn};
+
+use super::{DataStore, StoreError};
+use crate::metrics::Metrics;
+use crate::scheduler::Job;
+use crate::server::Components;
+use crate::util::Result;
+
+/// The usage of the mailboxes of each user, by user and then mailbox. Clones share the same
+/// ledger. Only the mailboxes whose usage has been worked out are recorded.
+#[derive(Debug, Clone, Default)]
+pub struct Usage {
+    users: Arc<Mutex<HashMap<String, HashMap<String, u64>>>>,
+}
+
+impl Usage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn users(&self) -> MutexGuard<'_, HashMap<String, HashMap<String, u64>>> {
+        match self.users.lock() {
+            Ok(users) => users,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// The bytes recorded for `mailbox` of `user`, if its usage is known.
+    pub fn mailbox(&self, user: &str, mailbox: &str) -> Option<u64> {
+        self.users().get(user)?.get(mailbox).copied()
+    }
+
+    /// The bytes recorded for every mailbox of `user` whose usage is known.
+    pub fn user(&self, user: &str) -> u64 {
+        self.users()
+            .get(user)
+            .map_or(0, |mailboxes| mailboxes.values().sum())
+    }
+
+    /// Records `bytes` as the usage of `mailbox` of `user`, returning what was recorded before.
+    pub fn set(&self, user: &str, mailbox: &str, bytes: u64) -> Option<u64> {
+        self.users()
+            .entry(user.to_string())
+            .or_default()
+            .insert(mailbox.to_string(), bytes)
+    }
+
+    /// Adds `bytes` to the usage of `mailbox` of `user`, if it is known.
+    pub fn add(&self, user: &str, mailbox: &str, bytes: u64) {
+        if let Some(usage) = self
+            .users()
+            .get_mut(user)
+            .and_then(|mailboxes| mailboxes.get_mut(mailbox))
+        {
+            *usage += bytes;
+        }
+    }
+
+    /// Forgets the usage of `mailbox` of `user`, so that it is worked out again when next
+    /// needed.
+    pub fn forget(&self, user: &str, mailbox: &str) -> Option<u64> {
+        let mut users = self.users();
+        let mailboxes = users.get_mut(user)?;
+        let forgotten = mailboxes.remove(mailbox);
+        if mailboxes.is_empty() {
+            users.remove(user);
+        }
+        forgotten
+    }
+
+    /// The mailboxes of `user` whose usage is known.
+    fn mailboxes(&self, user: &str) -> Vec<String> {
+        self.users()
+            .get(user)
+            .map_or(vec![], |mailboxes| mailboxes.keys().cloned().collect())
+    }
+}
+
+/// What recalculating the usage of one user found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserUsage {
+    pub user: String,
+    pub mailboxes: usize,
+    /// The size of every message of the user in the data store.
+    pub bytes: u64,
+    /// The mailboxes whose usage was recorded wrongly, and so was corrected.
+    pub corrected: usize,
+    /// How far the usage recorded was from the data store, in bytes, summed over the
+    /// mailboxes corrected.
+    pub drift: u64,
+}
+
+/// Recomputes the usage of every mailbox of every user from the data store and corrects the
+/// `Usage` ledger of the server. Runs never overlap, whether scheduled or asked for.
+///
+/// Every message of a user is listed to size their mailboxes, so the job waits between
+/// mailboxes to leave the backend to the sessions using it. See `with_pause`.
+#[derive(Clone)]
+pub struct RecalculateUsage {
+    usage: Usage,
+    pause: Duration,
+    running: Arc<async_lock::Mutex<()>>,
+}
+
+impl RecalculateUsage {
+    pub fn new(usage: Usage) -> Self {
+        Self {
+            usage,
+            pause: Duration::from_millis(50),
+            running: Arc::new(async_lock::Mutex::new(())),
+        }
+    }
+    /// How long to wait after sizing each mailbox, 50ms by default.
+    pub fn with_pause(mut self, pause: Duration) -> Self {
+        self.pause = pause;
+        self
+    }
+
+    /// Recomputes the usage of `users` from `store`, correcting the ledger and counting the
+    /// corrections in `metrics`. Waits for any run in progress to finish first.
+    pub async fn recalculate(
+        &self,
+        users: &[String],
+        store: &dyn DataStore,
+        metrics: &Metrics,
+    ) -> Result<Vec<UserUsage>> {
+        let _running = self.running.lock().await;
+        let mut recalculated = Vec::with_capacity(users.len());
+        for user in users {
+            recalculated.push(self.recalculate_user(user, store, metrics).await?);
+        }
+        Ok(recalculated)
+    }
+
+    /// Recalculates the usage of `user`, or of every user of the server of `components`.
+    pub(crate) async fn recalculate_in(
+        &self,
+        components: &Components,
+        metrics: &Metrics,
+        user: Option<&str>,
+    ) -> Result<Vec<UserUsage>> {
+        let users = match user {
+            Some(user) => vec![user.to_string()],
+            None => components.user_store.list().await?,
+        };
+        let store = components.data_store.as_ref().as_ref();
+        self.recalculate(&users, store, metrics).await
+    }
+
+    async fn recalculate_user(
+        &self,
+        user: &str,
+        store: &dyn DataStore,
+        metrics: &Metrics,
+    ) -> Result<UserUsage> {
+        let mailboxes = store.mailboxes(user).await?;
+        let mut found = UserUsage {
+            user: user.to_string(),
+            mailboxes: mailboxes.len(),
+            ..UserUsage::default()
+        };
+        for mailbox in &mailboxes {
+            // A mailbox deleted since the mailboxes were listed is not using anything.
+            let bytes: u64 = match store.list(user, mailbox).await {
+                Ok(messages) => messages.iter().map(|message| message.size).sum(),
+                Err(StoreError::MailboxDoesNotExist(..)) => 0,
+                Err(e) => return Err(Box::new(e)),
+            };
+            found.bytes += bytes;
+            let recorded = self.usage.set(user, mailbox, bytes);
+            if let Some(recorded) = recorded.filter(|recorded| *recorded != bytes) {
+                warn!(
+                    "Usage of {} of {} was recorded as {} bytes rather than {}",
+                    mailbox, user, recorded, bytes
+                );
+                found.corrected += 1;
+                found.drift += recorded.abs_diff(bytes);
+                metrics.usage_corrected(recorded.abs_diff(bytes));
+            }
+            sleep(self.pause).await;
+        }
+        // Mailboxes the data store no longer has are not using anything.
+        for mailbox in self.usage.mailboxes(user) {
+            if mailboxes.contains(&mailbox) {
+                continue;
+            }
+            if let Some(recorded) = self.usage.forget(user, &mailbox).filter(|bytes| *bytes > 0) {
+                warn!(
+                    "Usage of {} of {} was recorded as {} bytes but it has no messages",
+                    mailbox, user, recorded
+                );
+                found.corrected += 1;
+                found.drift += recorded;
+                metrics.usage_corrected(recorded);
+            }
+        }
+        Ok(found)
+    }
+}
+
+#[async_trait::async_trait]
+impl Job for RecalculateUsage {
+    fn name(&self) -> &str {
+        "recalculate_usage"
+    }
+    async fn run(&self, components: &Components, metrics: &Metrics) -> Result<()> {
+        let recalculated = self.recalculate_in(components, metrics, None).await?;
+        let corrected: Vec<&UserUsage> = recalculated
+            .iter()
+            .filter(|usage| usage.corrected > 0)
+            .collect();
+        if !corrected.is_empty() {
+            info!(
+                "Corrected the usage of {} users, which had drifted by {} bytes",
+                corrected.len(),
+                corrected.iter().map(|usage| usage.drift).sum::<u64>()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{RecalculateUsage, Usage};
+    use crate::metrics::Metrics;
+    use crate::store::inmemory::InMemoryStore;
+    use crate::store::{DataStore, Message};
+
+    #[async_std::test]
+    async fn test_recalculate_usage() {
+        let store = InMemoryStore::new();
+        store
+            .append("me", "INBOX", Message::new(b"Hello"))
+            .await
+            .unwrap();
+        store
+            .append("me", "Sent", Message::new(b"Hi"))
+            .await
+            .unwrap();
+        let usage = Usage::new();
+        usage.set("me", "INBOX", 5);
+        usage.set("me", "Sent", 10);
+        usage.set("me", "Trash", 3);
+        usage.add("you", "INBOX", 7);
+        assert_eq!(usage.mailbox("you", "INBOX"), None);
+
+        let metrics = Metrics::new();
+        let job = RecalculateUsage::new(usage.clone()).with_pause(Duration::ZERO);
+        let users = ["me".to_string(), "you".to_string()];
+        let recalculated = job.recalculate(&users, &store, &metrics).await.unwrap();
+
+        // Sent had drifted by eight bytes and Trash is gone, while INBOX was right.
+        assert_eq!((recalculated[0].mailboxes, recalculated[0].bytes), (2, 7));
+        assert_eq!((recalculated[0].corrected, recalculated[0].drift), (2, 11));
+        assert_eq!((recalculated[1].bytes, recalculated[1].corrected), (0, 0));
+        assert_eq!(usage.mailbox("me", "Sent"), Some(2));
+        assert_eq!(usage.mailbox("me", "Trash"), None);
+        assert_eq!(usage.user("me"), 7);
+        assert_eq!(
+            (metrics.usage_corrections(), metrics.usage_drift()),
+            (2, 11)
+        );
+
+        // Nothing has drifted since.
+        let again = job.recalculate(&users, &store, &metrics).await.unwrap();
+        assert_eq!(again[0].corrected, 0);
+        assert_eq!(metrics.usage_corrections(), 2);
+    }
+}
@@ -0,0 +1,170 @@
+//! Socket activation and readiness notification under systemd, as in `sd_listen_fds(3)` and
+//! `sd_notify(3)`, without linking libsystemd.
+//!
+//! With socket activation systemd binds the sockets, including privileged ports, and keeps
+//! them open across restarts of the server, so clients connecting during a restart wait to
+//! be accepted rather than being refused.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+
+use crate::listener::Listener;
+
+/// The first file descriptor systemd passes.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A socket passed by systemd.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InheritedSocket {
+    pub fd: RawFd,
+    /// The name from `FileDescriptorName=` of its socket unit, or `unknown`.
+    pub name: String,
+}
+
+/// The sockets passed to this process by systemd, if it was socket activated.
+pub fn listen_fds() -> Vec<InheritedSocket> {
+    let var = |name| std::env::var(name).ok();
+    parse(
+        var("LISTEN_PID").as_deref(),
+        var("LISTEN_FDS").as_deref(),
+        var("LISTEN_FDNAMES").as_deref(),
+        std::process::id(),
+    )
+}
+
+fn parse(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    own: u32,
+) -> Vec<InheritedSocket> {
+    // The variables are meant for the process systemd started, not for its children.
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(own) {
+        return vec![];
+    }
+    let count = fds.and_then(|fds| fds.parse::<RawFd>().ok()).unwrap_or(0);
+    let mut names = names.unwrap_or_default().split(':');
+    (0..count.max(0))
+        .map(|offset| InheritedSocket {
+            fd: LISTEN_FDS_START + offset,
+            name: match names.next() {
+                Some(name) if !name.is_empty() => name.to_string(),
+                _ => "unknown".to_string(),
+            },
+        })
+        .collect()
+}
+
+/// A listener on each socket passed by systemd, with the default settings.
+pub fn listeners() -> Vec<Listener> {
+    listen_fds()
+        .into_iter()
+        .map(|socket| Listener::inherited(socket.fd, &socket.name))
+        .collect()
+}
+
+/// Tells systemd about the state of the server, such as `READY=1` once it accepts
+/// connections or `STOPPING=1` when it shuts down. Returns whether systemd is listening,
+/// which it is for services of `Type=notify`.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match std::env::var("NOTIFY_SOCKET") {
+        Ok(socket) => notify_to(&socket, state).map(|()| true),
+        Err(..) => Ok(false),
+    }
+}
+
+fn notify_to(socket: &str, state: &str) -> io::Result<()> {
+    let datagram = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(state.as_bytes(), &address)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(..) => {
+            let message = "abstract notification sockets are only supported on Linux";
+            return Err(io::Error::new(io::ErrorKind::Unsupported, message));
+        }
+        None => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixDatagram;
+
+    use async_std::io::BufReader;
+    use async_std::net::TcpStream;
+    use futures::{AsyncBufReadExt, StreamExt};
+
+    use super::{notify_to, parse, InheritedSocket};
+    use crate::listener::Listener;
+    use crate::server::ServerBuilder;
+
+    #[test]
+    fn test_parse() {
+        let sockets = parse(Some("42"), Some("2"), Some("imap:imaps"), 42);
+        assert_eq!(
+            sockets,
+            vec![
+                InheritedSocket {
+                    fd: 3,
+                    name: "imap".to_string()
+                },
+                InheritedSocket {
+                    fd: 4,
+                    name: "imaps".to_string()
+                },
+            ]
+        );
+        assert_eq!(parse(Some("42"), Some("1"), None, 42)[0].name, "unknown");
+        // Sockets meant for another process are not taken.
+        assert!(parse(Some("41"), Some("2"), None, 42).is_empty());
+        assert!(parse(None, None, None, 42).is_empty());
+    }
+
+    #[test]
+    fn test_notify() {
+        let path =
+            std::env::temp_dir().join(format!("treasurmap-systemd-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        notify_to(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buffer = [0u8; 64];
+        let read = systemd.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..read], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_inherited_socket() {
+        // As systemd would, bind the socket before the server starts.
+        let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let fd = socket.into_raw_fd();
+        let server = ServerBuilder::new()
+            .with_listener(Listener::inherited(fd, "imap"))
+            .bind()
+            .await
+            .unwrap();
+        let shutdown = server.shutdown();
+        let running = async_std::task::spawn(server.listen());
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let greeting = lines.next().await.unwrap().unwrap();
+        assert_eq!(
+            greeting,
+            "* OK [CAPABILITY IMAP4rev2 ID] IMAP4rev2 server ready"
+        );
+        shutdown.trigger();
+        running.await.unwrap();
+    }
+}
@@ -0,0 +1,799 @@
+//! Export of traces and metrics to an OpenTelemetry collector over OTLP/HTTP with JSON
+//! bodies, for Jaeger, Tempo or a Prometheus-compatible backend behind the collector.
+//!
+//! Traces are made of the `tracing` spans of the server: a span per connection, a span per
+//! command within it, and `index` and `store` spans for the calls a command makes to the
+//! backends. Backend calls made outside a connection, such as by scheduled jobs, are not
+//! traced. Events logged at INFO or above within a span are attached to it, and an error
+//! marks it as failed. Exporting traces installs the exporter as the global `tracing`
+//! subscriber, which passes every event on to the `log` crate as `tracing` does when there
+//! is none, so logging is unchanged.
+//!
+//! Every metric of `Metrics` is exported as well, counters as cumulative sums. Both are sent
+//! every interval, and once more when the server has shut down. Only plain `http://`
+//! endpoints are supported, as for a `Webhook`.
+//!
+//! The exporter is set with `Configuration::with_telemetry`, or by a `telemetry` section
+//! whose secret references are resolved as those of plugin sections are:
+//!
+//! ```json
+//! {"endpoint": "http://collector:4318", "service": "imap-1", "interval": 10,
+//!  "headers": {"authorization": {"from_env": "OTLP_AUTHORIZATION"}}, "metrics": false}
+//! ```
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_std::future::timeout;
+use futures::future::{self, Either};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{self, Id, Record};
+use tracing::{warn, Event, Level, Metadata, Subscriber};
+
+use crate::metrics::{self, Family, Kind, Metrics, LATENCY_BUCKETS};
+use crate::notify::{Publish, Webhook};
+use crate::shutdown::Shutdown;
+use crate::util::Result;
+
+/// How often spans and metrics are sent, by default.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+/// The name of the configuration section read by `ServerBuilder::bind`.
+pub const SECTION: &str = "telemetry";
+/// How many finished spans are kept for the next export before more are dropped.
+const MAX_QUEUED: usize = 4096;
+/// How many events are attached to a span.
+const MAX_EVENTS: usize = 32;
+/// How long the collector has to accept an export.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where and what to export.
+#[derive(Debug, Clone)]
+pub struct Telemetry {
+    endpoint: String,
+    service: String,
+    interval: Duration,
+    headers: Vec<(String, String)>,
+    traces: bool,
+    metrics: bool,
+}
+
+#[derive(Deserialize)]
+struct Section {
+    endpoint: String,
+    service: Option<String>,
+    /// In seconds.
+    interval: Option<u64>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    traces: Option<bool>,
+    metrics: Option<bool>,
+}
+
+impl Telemetry {
+    /// Exports to the collector at `endpoint`, such as `http://localhost:4318`, posting to
+    /// `/v1/traces` and `/v1/metrics` under it.
+    pub fn new(endpoint: &str) -> Result<Self> {
+        let endpoint = endpoint.trim_end_matches('/');
+        Webhook::new(endpoint)?;
+        Ok(Self {
+            endpoint: endpoint.to_string(),
+            service: "treasurmap".to_string(),
+            interval: DEFAULT_INTERVAL,
+            headers: vec![],
+            traces: true,
+            metrics: true,
+        })
+    }
+    /// Reads the exporter from a `telemetry` section, see the module documentation.
+    pub fn from_section(section: &Value) -> Result<Self> {
+        let section: Section = serde_json::from_value(section.clone())?;
+        let mut telemetry = Self::new(&section.endpoint)?;
+        if let Some(service) = section.service {
+            telemetry = telemetry.with_service(&service);
+        }
+        if let Some(interval) = section.interval {
+            telemetry = telemetry.with_interval(Duration::from_secs(interval));
+        }
+        for (name, value) in &section.headers {
+            telemetry = telemetry.with_header(name, value);
+        }
+        Ok(telemetry
+            .with_traces(section.traces.unwrap_or(true))
+            .with_metrics(section.metrics.unwrap_or(true)))
+    }
+    /// Names the server in what is exported, `treasurmap` by default.
+    pub fn with_service(mut self, service: &str) -> Self {
+        self.service = service.to_string();
+        self
+    }
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+    /// Sends `name: value` with every export, such as credentials for the collector.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+    pub fn with_traces(mut self, traces: bool) -> Self {
+        self.traces = traces;
+        self
+    }
+    pub fn with_metrics(mut self, metrics: bool) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    fn webhook(&self, path: &str) -> Result<Webhook> {
+        let webhook = Webhook::new(&format!("{}{}", self.endpoint, path))?;
+        Ok(self.headers.iter().fold(webhook, |webhook, (name, value)| {
+            webhook.with_header(name, value)
+        }))
+    }
+
+    /// Starts collecting spans, if traces are exported, by installing the global `tracing`
+    /// subscriber. Traces are not exported if another one is installed already.
+    pub(crate) fn start(self) -> Result<Exporter> {
+        let (traces, metrics) = (self.webhook("/v1/traces")?, self.webhook("/v1/metrics")?);
+        let spans = match self.traces {
+            true => {
+                let spans = Arc::new(Spans::default());
+                let collector = Collector {
+                    spans: spans.clone(),
+                };
+                match tracing::subscriber::set_global_default(collector) {
+                    Ok(()) => Some(spans),
+                    Err(_) => {
+                        warn!("Not exporting traces: a tracing subscriber is installed already");
+                        None
+                    }
+                }
+            }
+            false => None,
+        };
+        Ok(Exporter {
+            telemetry: self,
+            spans,
+            traces,
+            metrics,
+            started: SystemTime::now(),
+        })
+    }
+}
+
+/// Sends what `Telemetry` collects, see `Telemetry::start`.
+pub(crate) struct Exporter {
+    telemetry: Telemetry,
+    spans: Option<Arc<Spans>>,
+    traces: Webhook,
+    metrics: Webhook,
+    started: SystemTime,
+}
+
+impl Exporter {
+    /// Exports every interval until `shutdown` is triggered.
+    pub(crate) async fn run(&mut self, metrics: &Metrics, shutdown: &Shutdown) {
+        loop {
+            let tick = async_std::task::sleep(self.telemetry.interval);
+            if let Either::Right(..) =
+                future::select(Box::pin(tick), Box::pin(shutdown.wait())).await
+            {
+                return;
+            }
+            self.export(metrics).await;
+        }
+    }
+
+    /// Sends the spans finished since the last export and the current value of `metrics`.
+    pub(crate) async fn export(&mut self, metrics: &Metrics) {
+        if let Some(spans) = &self.spans {
+            let (finished, dropped) = spans.take();
+            if dropped > 0 {
+                warn!("Dropped {} spans which were not exported in time", dropped);
+            }
+            if !finished.is_empty() {
+                let body = traces(&self.telemetry.service, &finished);
+                send(&mut self.traces, &body).await;
+            }
+        }
+        if self.telemetry.metrics {
+            let now = SystemTime::now();
+            let families = metrics.families();
+            let body = metrics_body(&self.telemetry.service, families, self.started, now);
+            send(&mut self.metrics, &body).await;
+        }
+    }
+}
+
+async fn send(webhook: &mut Webhook, body: &Value) {
+    match timeout(EXPORT_TIMEOUT, webhook.publish(body)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("Could not export telemetry: {}", e),
+        Err(..) => warn!("Timed out exporting telemetry"),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Attribute {
+    String(String),
+    Int(i64),
+    Bool(bool),
+    Double(f64),
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Attribute::String(value) => write!(f, "{}", value),
+            Attribute::Int(value) => write!(f, "{}", value),
+            Attribute::Bool(value) => write!(f, "{}", value),
+            Attribute::Double(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// The fields recorded on a span or an event.
+#[derive(Debug, Default)]
+struct Fields(Vec<(&'static str, Attribute)>);
+
+impl Fields {
+    fn set(&mut self, field: &Field, value: Attribute) {
+        match self.0.iter_mut().find(|(name, _)| *name == field.name()) {
+            Some((_, recorded)) => *recorded = value,
+            None => self.0.push((field.name(), value)),
+        }
+    }
+
+    fn take(&mut self, name: &str) -> Option<Attribute> {
+        let position = self.0.iter().position(|(field, _)| *field == name)?;
+        Some(self.0.remove(position).1)
+    }
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field, Attribute::String(format!("{:?}", value)));
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, Attribute::String(value.to_string()));
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, Attribute::Int(value));
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(
+            field,
+            Attribute::Int(i64::try_from(value).unwrap_or(i64::MAX)),
+        );
+    }
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, Attribute::Bool(value));
+    }
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field, Attribute::Double(value));
+    }
+}
+
+struct SpanEvent {
+    time: SystemTime,
+    message: String,
+    fields: Fields,
+}
+
+/// A span, with what is exported of it.
+struct Recorded {
+    /// Handles on the span, which is finished once the last is dropped.
+    references: usize,
+    metadata: &'static Metadata<'static>,
+    /// The trace the span is part of, if it is exported.
+    trace: Option<[u8; 16]>,
+    id: [u8; 8],
+    parent: Option<[u8; 8]>,
+    started: SystemTime,
+    ended: SystemTime,
+    fields: Fields,
+    events: Vec<SpanEvent>,
+    error: Option<String>,
+}
+
+thread_local! {
+    /// The spans entered on this thread, innermost last.
+    static ENTERED: RefCell<Vec<Id>> = const { RefCell::new(Vec::new()) };
+}
+
+fn current() -> Option<Id> {
+    ENTERED.with(|entered| entered.borrow().last().cloned())
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        for (byte, nano) in bytes.iter_mut().zip(nanos.to_le_bytes()) {
+            *byte = nano;
+        }
+    }
+    bytes
+}
+
+/// The open spans and those finished since the last export.
+#[derive(Default)]
+struct Spans {
+    next: AtomicU64,
+    open: Mutex<HashMap<u64, Recorded>>,
+    finished: Mutex<Vec<Recorded>>,
+    dropped: AtomicU64,
+}
+
+impl Spans {
+    fn open(&self) -> MutexGuard<'_, HashMap<u64, Recorded>> {
+        match self.open.lock() {
+            Ok(open) => open,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn finished(&self) -> MutexGuard<'_, Vec<Recorded>> {
+        match self.finished.lock() {
+            Ok(finished) => finished,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// The spans finished since the last call, and how many were dropped.
+    fn take(&self) -> (Vec<Recorded>, u64) {
+        let finished = std::mem::take(&mut *self.finished());
+        (finished, self.dropped.swap(0, Ordering::Relaxed))
+    }
+}
+
+/// The `tracing` subscriber recording spans for the exporter.
+struct Collector {
+    spans: Arc<Spans>,
+}
+
+/// Passes an event on to the `log` crate, as `tracing` does without a subscriber.
+fn log(metadata: &Metadata<'_>, message: &str, fields: &Fields) {
+    let level = match *metadata.level() {
+        Level::ERROR => log::Level::Error,
+        Level::WARN => log::Level::Warn,
+        Level::INFO => log::Level::Info,
+        Level::DEBUG => log::Level::Debug,
+        _ => log::Level::Trace,
+    };
+    if level > log::max_level() {
+        return;
+    }
+    let mut line = message.to_string();
+    for (name, value) in &fields.0 {
+        line.push_str(&format!(" {}={}", name, value));
+    }
+    log::logger().log(
+        &log::Record::builder()
+            .level(level)
+            .target(metadata.target())
+            .module_path(metadata.module_path())
+            .file(metadata.file())
+            .line(metadata.line())
+            .args(format_args!("{}", line))
+            .build(),
+    );
+}
+
+impl Subscriber for Collector {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_event() || *metadata.level() <= Level::DEBUG
+    }
+
+    fn new_span(&self, attributes: &span::Attributes<'_>) -> Id {
+        let id = self.spans.next.fetch_add(1, Ordering::Relaxed) + 1;
+        let parent = match attributes.parent() {
+            Some(parent) => Some(parent.clone()),
+            None if attributes.is_contextual() => current(),
+            None => None,
+        };
+        let mut fields = Fields::default();
+        attributes.record(&mut fields);
+        let mut open = self.spans.open();
+        let parent = parent
+            .and_then(|parent| open.get(&parent.into_u64()))
+            .and_then(|parent| parent.trace.map(|trace| (trace, parent.id)));
+        let metadata = attributes.metadata();
+        // Spans below INFO, those of backend calls, only make sense within a trace.
+        let trace = match parent {
+            Some((trace, _)) => Some(trace),
+            None if *metadata.level() <= Level::INFO => Some(random()),
+            None => None,
+        };
+        let now = SystemTime::now();
+        open.insert(
+            id,
+            Recorded {
+                references: 1,
+                metadata,
+                trace,
+                id: random(),
+                parent: parent.map(|(_, id)| id),
+                started: now,
+                ended: now,
+                fields,
+                events: vec![],
+                error: None,
+            },
+        );
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(recorded) = self.spans.open().get_mut(&span.into_u64()) {
+            values.record(&mut recorded.fields);
+        }
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let message = fields
+            .take("message")
+            .map_or_else(String::new, |message| message.to_string());
+        let metadata = event.metadata();
+        log(metadata, &message, &fields);
+        if *metadata.level() > Level::INFO {
+            return;
+        }
+        let span = match event.parent() {
+            Some(parent) => Some(parent.clone()),
+            None if event.is_contextual() => current(),
+            None => None,
+        };
+        let mut open = self.spans.open();
+        let recorded = match span.and_then(|span| open.get_mut(&span.into_u64())) {
+            Some(recorded) if recorded.trace.is_some() => recorded,
+            _ => return,
+        };
+        if *metadata.level() == Level::ERROR {
+            recorded.error = Some(message.clone());
+        }
+        if recorded.events.len() < MAX_EVENTS {
+            recorded.events.push(SpanEvent {
+                time: SystemTime::now(),
+                message,
+                fields,
+            });
+        }
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.clone()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(position) = entered.iter().rposition(|id| id == span) {
+                entered.remove(position);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(recorded) = self.spans.open().get_mut(&span.into_u64()) {
+            recorded.references += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut open = self.spans.open();
+        match open.get_mut(&span.into_u64()) {
+            Some(recorded) if recorded.references > 1 => {
+                recorded.references -= 1;
+                return false;
+            }
+            Some(..) => {}
+            None => return false,
+        }
+        let mut recorded = match open.remove(&span.into_u64()) {
+            Some(recorded) => recorded,
+            None => return false,
+        };
+        drop(open);
+        if recorded.trace.is_some() {
+            recorded.ended = SystemTime::now();
+            let mut finished = self.spans.finished();
+            if finished.len() < MAX_QUEUED {
+                finished.push(recorded);
+            } else {
+                self.spans.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        true
+    }
+
+    fn current_span(&self) -> span::Current {
+        let span = match current() {
+            Some(span) => span,
+            None => return span::Current::none(),
+        };
+        let metadata = self
+            .spans
+            .open()
+            .get(&span.into_u64())
+            .map(|recorded| recorded.metadata);
+        match metadata {
+            Some(metadata) => span::Current::new(span, metadata),
+            None => span::Current::none(),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Nanoseconds since the epoch, as a string as OTLP takes 64-bit integers.
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos())
+        .to_string()
+}
+
+fn attribute(key: &str, value: &Attribute) -> Value {
+    let value = match value {
+        Attribute::String(value) => json!({ "stringValue": value }),
+        Attribute::Int(value) => json!({ "intValue": value.to_string() }),
+        Attribute::Bool(value) => json!({ "boolValue": value }),
+        Attribute::Double(value) => json!({ "doubleValue": value }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+fn attributes(fields: &Fields) -> Vec<Value> {
+    fields
+        .0
+        .iter()
+        .map(|(name, value)| attribute(name, value))
+        .collect()
+}
+
+fn resource(service: &str) -> Value {
+    let service = Attribute::String(service.to_string());
+    json!({ "attributes": [attribute("service.name", &service)] })
+}
+
+fn scope() -> Value {
+    json!({ "name": "treasurmap", "version": env!("CARGO_PKG_VERSION") })
+}
+
+/// The body of an export of `spans` to `/v1/traces`.
+fn traces(service: &str, spans: &[Recorded]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .filter_map(|span| {
+            let events: Vec<Value> = span
+                .events
+                .iter()
+                .map(|event| {
+                    json!({
+                        "timeUnixNano": nanos(event.time),
+                        "name": event.message,
+                        "attributes": attributes(&event.fields),
+                    })
+                })
+                .collect();
+            let status = match &span.error {
+                Some(message) => json!({ "code": 2, "message": message }),
+                None => json!({}),
+            };
+            // Spans which start a trace are those of connections, served for a client.
+            let kind = match span.parent {
+                Some(..) => 1,
+                None => 2,
+            };
+            let mut exported = json!({
+                "traceId": hex(&span.trace?),
+                "spanId": hex(&span.id),
+                "name": span.metadata.name(),
+                "kind": kind,
+                "startTimeUnixNano": nanos(span.started),
+                "endTimeUnixNano": nanos(span.ended),
+                "attributes": attributes(&span.fields),
+                "events": events,
+                "status": status,
+            });
+            if let Some(parent) = &span.parent {
+                exported["parentSpanId"] = json!(hex(parent));
+            }
+            Some(exported)
+        })
+        .collect();
+    json!({
+        "resourceSpans": [{
+            "resource": resource(service),
+            "scopeSpans": [{ "scope": scope(), "spans": spans }],
+        }]
+    })
+}
+
+/// The body of an export of `families` to `/v1/metrics`, counted since `started`.
+fn metrics_body(
+    service: &str,
+    families: Vec<Family>,
+    started: SystemTime,
+    now: SystemTime,
+) -> Value {
+    let metrics: Vec<Value> = families
+        .into_iter()
+        .map(|family| {
+            let points: Vec<Value> = family
+                .points
+                .iter()
+                .map(|point| {
+                    let labels: Vec<Value> = point
+                        .labels
+                        .iter()
+                        .map(|(name, value)| attribute(name, &Attribute::String(value.clone())))
+                        .collect();
+                    let mut exported = json!({
+                        "attributes": labels,
+                        "startTimeUnixNano": nanos(started),
+                        "timeUnixNano": nanos(now),
+                    });
+                    match &point.value {
+                        metrics::Value::Int(value) => exported["asInt"] = json!(value.to_string()),
+                        metrics::Value::Float(value) => exported["asDouble"] = json!(value),
+                        metrics::Value::Histogram(histogram) => {
+                            let counted: u64 = histogram.buckets.iter().sum();
+                            let overflow = histogram.count.saturating_sub(counted);
+                            let buckets: Vec<String> = histogram
+                                .buckets
+                                .iter()
+                                .chain([overflow].iter())
+                                .map(u64::to_string)
+                                .collect();
+                            exported["count"] = json!(histogram.count.to_string());
+                            exported["sum"] = json!(histogram.sum);
+                            exported["bucketCounts"] = json!(buckets);
+                            exported["explicitBounds"] = json!(LATENCY_BUCKETS);
+                        }
+                    }
+                    exported
+                })
+                .collect();
+            let (kind, data) = match family.kind {
+                Kind::Gauge => ("gauge", json!({ "dataPoints": points })),
+                Kind::Counter => (
+                    "sum",
+                    json!({
+                        "dataPoints": points,
+                        "aggregationTemporality": 2,
+                        "isMonotonic": true,
+                    }),
+                ),
+                Kind::Histogram => (
+                    "histogram",
+                    json!({ "dataPoints": points, "aggregationTemporality": 2 }),
+                ),
+            };
+            let mut metric = json!({ "name": family.name, "description": family.help });
+            metric[kind] = data;
+            metric
+        })
+        .collect();
+    json!({
+        "resourceMetrics": [{
+            "resource": resource(service),
+            "scopeMetrics": [{ "scope": scope(), "metrics": metrics }],
+        }]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use serde_json::json;
+    use tracing::{debug_span, error, info_span};
+
+    use super::{metrics_body, traces, Collector, Spans, Telemetry};
+    use crate::metrics::Metrics;
+
+    #[test]
+    fn test_traces() {
+        let spans = Arc::new(Spans::default());
+        let collector = Collector {
+            spans: spans.clone(),
+        };
+        tracing::subscriber::with_default(collector, || {
+            info_span!("connection", id = "c1").in_scope(|| {
+                info_span!("command", tag = "a1").in_scope(|| {
+                    debug_span!("index", operation = "get_mailbox").in_scope(|| {});
+                    error!("Could not read the mailbox");
+                });
+            });
+            // Outside a connection a backend call is not traced.
+            debug_span!("store", operation = "list").in_scope(|| {});
+        });
+
+        let (finished, dropped) = spans.take();
+        assert_eq!(dropped, 0);
+        let names: Vec<&str> = finished.iter().map(|span| span.metadata.name()).collect();
+        assert_eq!(names, ["index", "command", "connection"]);
+        let body = traces("imap-1", &finished);
+        let service = &body["resourceSpans"][0]["resource"]["attributes"][0];
+        assert_eq!(service["value"]["stringValue"], "imap-1");
+        let exported = &body["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        let (index, command, connection) = (&exported[0], &exported[1], &exported[2]);
+        assert_eq!(index["traceId"], connection["traceId"]);
+        assert_eq!(index["parentSpanId"], command["spanId"]);
+        assert_eq!(command["parentSpanId"], connection["spanId"]);
+        assert!(connection.get("parentSpanId").is_none());
+        assert_eq!(
+            index["attributes"][0],
+            json!({ "key": "operation", "value": { "stringValue": "get_mailbox" } })
+        );
+        assert_eq!(command["events"][0]["name"], "Could not read the mailbox");
+        assert_eq!(command["status"]["code"], 2);
+        assert_eq!(connection["status"], json!({}));
+    }
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::new();
+        metrics.command("FETCH");
+        metrics.command_completed("FETCH", Duration::from_millis(20));
+        let now = UNIX_EPOCH + Duration::from_secs(1);
+        let body = metrics_body("imap-1", metrics.families(), UNIX_EPOCH, now);
+        let exported = body["resourceMetrics"][0]["scopeMetrics"][0]["metrics"]
+            .as_array()
+            .unwrap();
+        let find = |name: &str| {
+            exported
+                .iter()
+                .find(|metric| metric["name"] == name)
+                .unwrap()
+        };
+
+        let connections = &find("imap_connections")["gauge"]["dataPoints"][0];
+        assert_eq!(connections["asInt"], "0");
+        let commands = &find("imap_commands_total")["sum"];
+        assert_eq!(commands["isMonotonic"], true);
+        let fetches = &commands["dataPoints"][0];
+        assert_eq!(fetches["attributes"][0]["value"]["stringValue"], "FETCH");
+        assert_eq!(fetches["timeUnixNano"], "1000000000");
+        let latency = &find("imap_command_duration_seconds")["histogram"]["dataPoints"][0];
+        assert_eq!(latency["count"], "1");
+        assert_eq!(latency["bucketCounts"].as_array().unwrap().len(), 13);
+        assert_eq!(latency["bucketCounts"][3], "1");
+    }
+
+    #[test]
+    fn test_from_section() {
+        let section = json!({
+            "endpoint": "http://collector:4318/",
+            "interval": 30,
+            "headers": { "authorization": "Bearer token" },
+            "traces": false,
+        });
+        let telemetry = Telemetry::from_section(&section).unwrap();
+        assert_eq!(telemetry.endpoint, "http://collector:4318");
+        assert_eq!(telemetry.interval, Duration::from_secs(30));
+        assert_eq!(
+            telemetry.headers,
+            [("authorization".to_string(), "Bearer token".to_string())]
+        );
+        assert!(!telemetry.traces && telemetry.metrics);
+        assert!(Telemetry::new("https://collector:4318").is_err());
+    }
+}
@@ -0,0 +1,478 @@
+//! Drives a server from tests without a network or an external IMAP client.
+//!
+//! `duplex` makes a pair of connected in-memory streams, and `ImapTestClient` speaks enough
+//! IMAP over one of them to send tagged commands and collect what the server answers,
+//! literals included. `Faults` describes the failures `ChaosStore` and `ChaosIndex` inject
+//! into the backends of a server under test. Nothing here is meant for production use.
+//!
+//! ```no_run
+//! # async fn example(server: std::sync::Arc<imaprust::server::Server>) -> imaprust::util::Result<()> {
+//! use imaprust::testing::ImapTestClient;
+//!
+//! let mut client = ImapTestClient::connect(&server).await?;
+//! client.command("LOGIN me@example.com password").await?.ok()?;
+//! let select = client.command("SELECT INBOX").await?;
+//! assert!(select.untagged.iter().any(|response| response.text == "1 EXISTS"));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use async_std::task::sleep;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::runtime::spawn;
+use crate::scheduler::random;
+use crate::server::Server;
+use crate::util::Result;
+
+/// One direction of a duplex stream.
+#[derive(Default)]
+struct Pipe {
+    bytes: VecDeque<u8>,
+    closed: bool,
+    reader: Option<Waker>,
+}
+
+fn lock(pipe: &Mutex<Pipe>) -> std::sync::MutexGuard<'_, Pipe> {
+    match pipe.lock() {
+        Ok(pipe) => pipe,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// One end of a pair of streams made by `duplex`. What is written to one end is read from
+/// the other, and dropping or closing an end ends the stream read from the other.
+pub struct DuplexStream {
+    reading: Arc<Mutex<Pipe>>,
+    writing: Arc<Mutex<Pipe>>,
+}
+
+/// Two connected in-memory streams. Writes never wait, as nothing limits how much is
+/// buffered.
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    let (there, back) = (Arc::default(), Arc::default());
+    let ours = DuplexStream {
+        reading: Arc::clone(&back),
+        writing: Arc::clone(&there),
+    };
+    let theirs = DuplexStream {
+        reading: there,
+        writing: back,
+    };
+    (ours, theirs)
+}
+
+impl DuplexStream {
+    fn close(&self) {
+        let mut pipe = lock(&self.writing);
+        pipe.closed = true;
+        if let Some(reader) = pipe.reader.take() {
+            reader.wake();
+        }
+    }
+}
+
+impl AsyncRead for DuplexStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = lock(&self.reading);
+        if pipe.bytes.is_empty() && !pipe.closed && !buf.is_empty() {
+            pipe.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let read = buf.len().min(pipe.bytes.len());
+        for (byte, value) in buf.iter_mut().zip(pipe.bytes.drain(..read)) {
+            *byte = value;
+        }
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl AsyncWrite for DuplexStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = lock(&self.writing);
+        if pipe.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        // The other end cannot write to this one once it is gone either.
+        if Arc::strong_count(&self.writing) == 1 {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        pipe.bytes.extend(buf);
+        if let Some(reader) = pipe.reader.take() {
+            reader.wake();
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for DuplexStream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// What a backend call is made to do instead of succeeding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Fail without doing anything.
+    Error,
+    /// Do only part of the work, then fail. What that means depends on the call, see
+    /// `ChaosStore` and `ChaosIndex`.
+    Partial,
+}
+
+/// The failures injected into backend calls. Every call, or every call to the operations
+/// given to `only`, first waits for the latency and then fails with the given probabilities.
+/// Clones share the count of faults injected.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    errors: f64,
+    partial: f64,
+    latency: Duration,
+    operations: Option<Vec<String>>,
+    injected: Arc<AtomicUsize>,
+}
+
+impl Faults {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails calls with probability `rate`, from 0 for never to 1 for always.
+    pub fn with_errors(mut self, rate: f64) -> Self {
+        self.errors = rate;
+        self
+    }
+
+    /// Makes writes stop part way with probability `rate`. Calls which write nothing fail
+    /// instead.
+    pub fn with_partial_writes(mut self, rate: f64) -> Self {
+        self.partial = rate;
+        self
+    }
+
+    /// Delays every call by `latency`, whether it fails or not.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Only affects calls to the named operations, such as `fetch` or `list_messages`.
+    pub fn only(mut self, operations: &[&str]) -> Self {
+        self.operations = Some(operations.iter().map(|name| name.to_string()).collect());
+        self
+    }
+
+    /// How many calls have been made to fail.
+    pub fn injected(&self) -> usize {
+        self.injected.load(Ordering::Relaxed)
+    }
+
+    /// Waits out the latency of a call to `operation`, then decides whether it fails.
+    pub async fn inject(&self, operation: &str) -> Option<Fault> {
+        if let Some(operations) = &self.operations {
+            if !operations.iter().any(|name| name == operation) {
+                return None;
+            }
+        }
+        if !self.latency.is_zero() {
+            sleep(self.latency).await;
+        }
+        let roll = random();
+        let fault = if roll < self.partial {
+            Fault::Partial
+        } else if roll < self.partial + self.errors {
+            Fault::Error
+        } else {
+            return None;
+        };
+        self.injected.fetch_add(1, Ordering::Relaxed);
+        Some(fault)
+    }
+}
+
+/// An untagged response, with the literals it contained taken out of its text. Each literal
+/// is left as its `{N}` marker in `text`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Untagged {
+    pub text: String,
+    pub literals: Vec<Vec<u8>>,
+}
+
+/// What the server said in answer to a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub tag: String,
+    /// `OK`, `NO` or `BAD`.
+    pub status: String,
+    /// The rest of the tagged response, after the status.
+    pub text: String,
+    /// The untagged responses sent before the tagged one, in order.
+    pub untagged: Vec<Untagged>,
+}
+
+impl Reply {
+    pub fn is_ok(&self) -> bool {
+        self.status == "OK"
+    }
+
+    /// The reply itself if the command succeeded, or an error saying what the server did say.
+    pub fn ok(self) -> Result<Self> {
+        if self.is_ok() {
+            return Ok(self);
+        }
+        Err(Box::new(UnexpectedReply(format!(
+            "{} {} {}",
+            self.tag, self.status, self.text
+        ))))
+    }
+
+    /// The literals of every untagged response, in order.
+    pub fn literals(&self) -> impl Iterator<Item = &[u8]> {
+        self.untagged
+            .iter()
+            .flat_map(|response| response.literals.iter().map(Vec::as_slice))
+    }
+}
+
+/// The server answered other than a test expected.
+#[derive(Debug)]
+pub struct UnexpectedReply(pub String);
+
+impl Display for UnexpectedReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unexpected reply from the server: {}", self.0)
+    }
+}
+
+impl std::error::Error for UnexpectedReply {}
+
+/// A response the client received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Received {
+    Untagged(Untagged),
+    /// A continuation request, with its text.
+    Continuation(String),
+    Tagged(Reply),
+}
+
+/// A minimal IMAP client for tests. Commands are tagged `t1`, `t2` and so on in the order
+/// they are sent.
+pub struct ImapTestClient<S = DuplexStream> {
+    stream: S,
+    buffer: Vec<u8>,
+    greeting: String,
+    tags: u32,
+}
+
+impl ImapTestClient {
+    /// Serves a new connection from `server` over an in-memory stream and reads its greeting.
+    pub async fn connect(server: &Arc<Server>) -> Result<Self> {
+        let (ours, theirs) = duplex();
+        let server = server.clone();
+        spawn(async move { server.serve_stream(theirs).await });
+        Self::new(ours).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ImapTestClient<S> {
+    /// A client over a stream to a server which has yet to send its greeting.
+    pub async fn new(stream: S) -> Result<Self> {
+        let mut client = Self {
+            stream,
+            buffer: vec![],
+            greeting: String::new(),
+            tags: 0,
+        };
+        client.greeting = match client.read().await? {
+            Received::Untagged(greeting) => greeting.text,
+            other => return Err(Box::new(UnexpectedReply(format!("{:?}", other)))),
+        };
+        Ok(client)
+    }
+
+    /// The greeting without the leading `* `, e.g. `OK [CAPABILITY IMAP4rev2 ID] IMAP4rev2 server ready`.
+    pub fn greeting(&self) -> &str {
+        &self.greeting
+    }
+
+    /// Sends `command` with the next tag and collects the responses up to its tagged one.
+    /// Fails if the server asks for a continuation, see `send` and `continue_with`.
+    pub async fn command(&mut self, command: &str) -> Result<Reply> {
+        let tag = self.send(command).await?;
+        self.reply(&tag).await
+    }
+
+    /// Sends `command` with the next tag, returning the tag.
+    pub async fn send(&mut self, command: &str) -> Result<String> {
+        self.tags += 1;
+        let tag = format!("t{}", self.tags);
+        self.write_line(&format!("{} {}", tag, command)).await?;
+        Ok(tag)
+    }
+
+    /// Answers a continuation request with `line`.
+    pub async fn continue_with(&mut self, line: &str) -> Result<()> {
+        self.write_line(line).await
+    }
+
+    /// Sends `line` as it is, for tests of what the server makes of malformed input.
+    pub async fn write_line(&mut self, line: &str) -> Result<()> {
+        self.stream.write_all(line.as_bytes()).await?;
+        self.stream.write_all(b"\r\n").await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Collects responses up to the one tagged `tag`.
+    pub async fn reply(&mut self, tag: &str) -> Result<Reply> {
+        let mut untagged = vec![];
+        loop {
+            match self.read().await? {
+                Received::Untagged(response) => untagged.push(response),
+                Received::Tagged(reply) if reply.tag == tag => {
+                    return Ok(Reply { untagged, ..reply });
+                }
+                other => return Err(Box::new(UnexpectedReply(format!("{:?}", other)))),
+            }
+        }
+    }
+
+    /// Reads the next response, with any literals it contains.
+    pub async fn read(&mut self) -> Result<Received> {
+        let mut text = String::new();
+        let mut literals = vec![];
+        loop {
+            let line = self.read_line().await?;
+            let line = String::from_utf8_lossy(&line);
+            text.push_str(&line);
+            match literal_length(&line) {
+                Some(length) => literals.push(self.read_exact(length).await?),
+                None => break,
+            }
+        }
+        if let Some(text) = text.strip_prefix("* ") {
+            return Ok(Received::Untagged(Untagged {
+                text: text.to_string(),
+                literals,
+            }));
+        }
+        if let Some(text) = text.strip_prefix('+') {
+            return Ok(Received::Continuation(text.trim_start().to_string()));
+        }
+        let mut parts = text.splitn(3, ' ');
+        let tag = parts.next().unwrap_or_default().to_string();
+        let status = parts.next().unwrap_or_default().to_string();
+        let text = parts.next().unwrap_or_default().to_string();
+        Ok(Received::Tagged(Reply {
+            tag,
+            status,
+            text,
+            untagged: vec![],
+        }))
+    }
+
+    /// Reads up to the next CRLF, which is not returned.
+    async fn read_line(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|pair| pair == b"\r\n") {
+                let mut line: Vec<u8> = self.buffer.drain(..end + 2).collect();
+                line.truncate(end);
+                return Ok(line);
+            }
+            self.fill().await?;
+        }
+    }
+
+    async fn read_exact(&mut self, length: usize) -> Result<Vec<u8>> {
+        while self.buffer.len() < length {
+            self.fill().await?;
+        }
+        Ok(self.buffer.drain(..length).collect())
+    }
+
+    async fn fill(&mut self) -> Result<()> {
+        let mut chunk = [0; 4096];
+        let read = self.stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(Box::new(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+        self.buffer.extend_from_slice(&chunk[..read]);
+        Ok(())
+    }
+}
+
+/// The length of the literal a line ends by announcing, as in `* 1 FETCH (BODY[] {342}`.
+fn literal_length(line: &str) -> Option<usize> {
+    let length = line.strip_suffix('}')?.rsplit_once('{')?.1;
+    length.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{duplex, ImapTestClient, Received, Untagged};
+
+    #[async_std::test]
+    async fn test_literals_collected() {
+        let (ours, mut theirs) = duplex();
+        theirs
+            .write_all(
+                b"* OK ready\r\n* 1 FETCH (BODY[] {6}\r\nab\r\ncd FLAGS (\\Seen))\r\n+ go on\r\n",
+            )
+            .await
+            .unwrap();
+        let mut client = ImapTestClient::new(ours).await.unwrap();
+        assert_eq!(client.greeting(), "OK ready");
+        assert_eq!(
+            client.read().await.unwrap(),
+            Received::Untagged(Untagged {
+                text: "1 FETCH (BODY[] {6} FLAGS (\\Seen))".to_string(),
+                literals: vec![b"ab\r\ncd".to_vec()],
+            })
+        );
+        assert_eq!(
+            client.read().await.unwrap(),
+            Received::Continuation("go on".to_string())
+        );
+
+        let tag = client.send("NOOP").await.unwrap();
+        assert_eq!(tag, "t1");
+        theirs.write_all(b"t1 OK NOOP completed\r\n").await.unwrap();
+        let reply = client.reply(&tag).await.unwrap();
+        assert_eq!(
+            (reply.status.as_str(), reply.text.as_str()),
+            ("OK", "NOOP completed")
+        );
+
+        drop(client);
+        let mut sent = String::new();
+        theirs.read_to_string(&mut sent).await.unwrap();
+        assert_eq!(sent, "t1 NOOP\r\n");
+    }
+}
@@ -1,6 +1,93 @@
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
 use std::error::Error;
-use futures::channel::mpsc::{UnboundedSender, UnboundedReceiver};
 
 pub type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
 pub type Sender<T> = UnboundedSender<T>;
-pub type Receiver<T> = UnboundedReceiver<T>;
\ No newline at end of file
+pub type Receiver<T> = UnboundedReceiver<T>;
+
+/// A UTC calendar date and time, for formatting timestamps without a date library.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UtcTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl From<std::time::SystemTime> for UtcTime {
+    fn from(time: std::time::SystemTime) -> Self {
+        let seconds = time
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let (days, rest) = (seconds / 86400, seconds % 86400);
+        // Civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days as i64 + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        Self {
+            year,
+            month: month as u32,
+            day: day as u32,
+            hour: (rest / 3600) as u32,
+            minute: (rest % 3600 / 60) as u32,
+            second: (rest % 60) as u32,
+        }
+    }
+}
+
+impl From<UtcTime> for std::time::SystemTime {
+    /// Times before the Unix epoch are clamped to it.
+    fn from(time: UtcTime) -> Self {
+        // Days-from-civil, the inverse of the conversion above.
+        let month = time.month as i64;
+        let year = if month <= 2 { time.year - 1 } else { time.year };
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let doy = (153 * ((month + 9) % 12) + 2) / 5 + time.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        let seconds = days * 86400 + (time.hour * 3600 + time.minute * 60 + time.second) as i64;
+        std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds.max(0) as u64)
+    }
+}
+
+/// Formats `time` as an RFC 3339 UTC timestamp, such as `2024-05-01T12:00:00Z`.
+pub fn rfc3339(time: std::time::SystemTime) -> String {
+    let time = UtcTime::from(time);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        time.year, time.month, time.day, time.hour, time.minute, time.second
+    )
+}
+
+/// A random (version 4) UUID in its usual hyphenated form.
+pub fn uuid() -> String {
+    let mut bytes = [0u8; 16];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        // Only used to tell things apart in logs, so the clock will do without randomness.
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        bytes = nanos.to_le_bytes();
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
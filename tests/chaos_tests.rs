@@ -0,0 +1,137 @@
+//! Checks that failing backends are answered with NO rather than panics or hangs.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::future::timeout;
+
+use imaprust::auth::inmemory::InMemoryUserStore;
+use imaprust::index::{chaos::ChaosIndex, inmemory::InMemoryIndex, reindex::reindex, Owner};
+use imaprust::server::{Configuration, Server, ServerBuilder};
+use imaprust::store::{chaos::ChaosStore, inmemory::InMemoryStore, DataStore, Message};
+use imaprust::testing::{Faults, ImapTestClient, Reply};
+
+const USER: &str = "me@example.com";
+
+async fn server(store: Faults, index: Faults, body: &[u8]) -> Arc<Server> {
+    let data_store = InMemoryStore::new();
+    data_store
+        .append(USER, "INBOX", Message::new(body))
+        .await
+        .unwrap();
+    let inmemory = InMemoryIndex::new();
+    reindex(&data_store, &inmemory, &Owner::new(USER), |_| {})
+        .await
+        .unwrap();
+    let server = ServerBuilder::new()
+        .with_configuration(Configuration::default().with_listeners(vec![]))
+        .with_user_store(InMemoryUserStore::new().with_user(USER, "password"))
+        .with_data_store(ChaosStore::new(Box::new(data_store), store))
+        .with_index(ChaosIndex::new(Box::new(inmemory), index))
+        .bind()
+        .await
+        .unwrap();
+    Arc::new(server)
+}
+
+/// Fails the test rather than letting it hang.
+async fn within<T>(future: impl Future<Output = T>) -> T {
+    timeout(Duration::from_secs(10), future)
+        .await
+        .expect("the server did not answer")
+}
+
+async fn selected(server: &Arc<Server>) -> ImapTestClient {
+    let mut client = within(ImapTestClient::connect(server)).await.unwrap();
+    within(client.command("LOGIN me@example.com password"))
+        .await
+        .unwrap()
+        .ok()
+        .unwrap();
+    within(client.command("SELECT INBOX"))
+        .await
+        .unwrap()
+        .ok()
+        .unwrap();
+    client
+}
+
+fn assert_unavailable(reply: &Reply) {
+    assert_eq!(reply.status, "NO", "{:?}", reply);
+    assert!(reply.text.starts_with("[UNAVAILABLE] "), "{:?}", reply);
+}
+
+#[async_std::test]
+async fn test_index_errors_answered() {
+    let faults =
+        Faults::new()
+            .with_errors(1.0)
+            .only(&["list_messages", "query_messages", "set_flags"]);
+    let server = server(Faults::new(), faults.clone(), b"Subject: hi\r\n\r\nbody").await;
+    let mut client = selected(&server).await;
+    for command in [
+        "FETCH 1 (FLAGS)",
+        "SEARCH ALL",
+        "STORE 1 +FLAGS (\\Seen)",
+        "COPY 1 INBOX",
+    ] {
+        let reply = within(client.command(command)).await.unwrap();
+        assert_unavailable(&reply);
+    }
+    assert!(faults.injected() >= 4);
+
+    // The connection is still usable.
+    within(client.command("LOGOUT"))
+        .await
+        .unwrap()
+        .ok()
+        .unwrap();
+}
+
+#[async_std::test]
+async fn test_mailbox_errors_answered() {
+    let faults = Faults::new().with_errors(1.0).only(&["get_mailbox"]);
+    let server = server(Faults::new(), faults, b"Subject: hi\r\n\r\nbody").await;
+    let mut client = within(ImapTestClient::connect(&server)).await.unwrap();
+    within(client.command("LOGIN me@example.com password"))
+        .await
+        .unwrap()
+        .ok()
+        .unwrap();
+    let reply = within(client.command("SELECT INBOX")).await.unwrap();
+    assert_eq!(reply.status, "NO", "{:?}", reply);
+}
+
+#[async_std::test]
+async fn test_store_errors_answered() {
+    let faults = Faults::new()
+        .with_errors(1.0)
+        .with_latency(Duration::from_millis(10))
+        .only(&["fetch", "fetch_header"]);
+    let server = server(faults, Faults::new(), b"Subject: hi\r\n\r\nbody").await;
+    let mut client = selected(&server).await;
+    for command in ["FETCH 1 (BODY[TEXT])", "FETCH 1 (RFC822.HEADER)"] {
+        let reply = within(client.command(command)).await.unwrap();
+        assert_unavailable(&reply);
+    }
+    // Items which need no body are still answered.
+    within(client.command("FETCH 1 (UID FLAGS)"))
+        .await
+        .unwrap()
+        .ok()
+        .unwrap();
+}
+
+#[async_std::test]
+async fn test_short_body_closes_connection() {
+    // Large enough to be streamed from the store rather than read into memory first.
+    let mut body = b"Subject: large\r\n\r\n".to_vec();
+    body.resize(2 * 1024 * 1024, b'x');
+    let faults = Faults::new().with_partial_writes(1.0).only(&["open"]);
+    let server = server(faults, Faults::new(), &body).await;
+    let mut client = selected(&server).await;
+
+    // The literal was announced, so the only way out is to drop the client.
+    assert!(within(client.command("FETCH 1 (BODY[])")).await.is_err());
+}
@@ -0,0 +1,174 @@
+//! Runs the scripts in `tests/conformance` against an in-process server and reports which
+//! of them pass. See `tests/conformance/README.md` for how the scripts are written.
+//!
+//! Run with `cargo test --test conformance -- --nocapture` to see the report.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use imaprust::auth::inmemory::InMemoryUserStore;
+use imaprust::index::{inmemory::InMemoryIndex, reindex::reindex, Owner};
+use imaprust::server::{Configuration, Server, ServerBuilder};
+use imaprust::store::{inmemory::InMemoryStore, DataStore, Message};
+use imaprust::testing::{ImapTestClient, Received};
+
+const USER: &str = "me@example.com";
+
+/// A command and the responses expected to it.
+struct Exchange {
+    line: usize,
+    command: String,
+    expected: Vec<String>,
+}
+
+fn parse(script: &str) -> Result<Vec<Exchange>, String> {
+    let mut exchanges: Vec<Exchange> = vec![];
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(command) = line.strip_prefix("C: ") {
+            exchanges.push(Exchange {
+                line: number + 1,
+                command: command.to_string(),
+                expected: vec![],
+            });
+        } else if let Some(response) = line.strip_prefix("S: ") {
+            match exchanges.last_mut() {
+                Some(exchange) => exchange.expected.push(response.to_string()),
+                None => {
+                    return Err(format!(
+                        "line {}: a response before any command",
+                        number + 1
+                    ))
+                }
+            }
+        } else {
+            return Err(format!("line {}: neither C: nor S:", number + 1));
+        }
+    }
+    Ok(exchanges)
+}
+
+fn matches(expected: &str, response: &str) -> bool {
+    match expected.strip_suffix("...") {
+        Some(prefix) => response.starts_with(prefix),
+        None => response == expected,
+    }
+}
+
+async fn server() -> Arc<Server> {
+    let data_store = InMemoryStore::new();
+    for message in [
+        &b"Subject: First\r\n\r\nHello.\r\n"[..],
+        &b"Subject: Second\r\n\r\nGoodbye.\r\n"[..],
+    ] {
+        data_store
+            .append(USER, "INBOX", Message::new(message))
+            .await
+            .unwrap();
+    }
+    let index = InMemoryIndex::new();
+    reindex(&data_store, &index, &Owner::new(USER), |_| {})
+        .await
+        .unwrap();
+    let server = ServerBuilder::new()
+        .with_configuration(Configuration::default().with_listeners(vec![]))
+        .with_user_store(InMemoryUserStore::new().with_user(USER, "password"))
+        .with_data_store(data_store)
+        .with_index(index)
+        .bind()
+        .await
+        .unwrap();
+    Arc::new(server)
+}
+
+/// Runs the script at `path`, saying where it went wrong if it did.
+async fn run(path: &Path) -> Result<(), String> {
+    let script = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let exchanges = parse(&script)?;
+    let server = server().await;
+    let mut client = ImapTestClient::connect(&server)
+        .await
+        .map_err(|e| e.to_string())?;
+    for exchange in exchanges {
+        let failed = |message: String| format!("line {}: {}", exchange.line, message);
+        let tag = exchange
+            .command
+            .split(' ')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        client
+            .write_line(&exchange.command)
+            .await
+            .map_err(|e| failed(e.to_string()))?;
+        let mut received = vec![];
+        loop {
+            match client.read().await.map_err(|e| failed(e.to_string()))? {
+                Received::Untagged(response) => received.push(format!("* {}", response.text)),
+                Received::Continuation(text) => received.push(format!("+ {}", text)),
+                Received::Tagged(reply) => {
+                    let tagged = format!("{} {} {}", reply.tag, reply.status, reply.text);
+                    received.push(tagged.trim_end().to_string());
+                    if reply.tag == tag {
+                        break;
+                    }
+                }
+            }
+        }
+        let mut responses = received.iter();
+        for expected in &exchange.expected {
+            if !responses.any(|response| matches(expected, response)) {
+                return Err(failed(format!(
+                    "expected `{}` in\n    {}",
+                    expected,
+                    received.join("\n    ")
+                )));
+            }
+        }
+        let last = exchange.expected.last();
+        if !last.is_some_and(|last| matches(last, received.last().unwrap())) {
+            return Err(failed(format!(
+                "the tagged response `{}` was not checked",
+                received.last().unwrap()
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_conformance() {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/conformance");
+    let mut scripts: Vec<PathBuf> = fs::read_dir(&directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "imap")
+        })
+        .collect();
+    scripts.sort();
+    assert!(!scripts.is_empty(), "no scripts in {}", directory.display());
+
+    let mut failures = vec![];
+    for script in &scripts {
+        let name = script.file_stem().unwrap().to_string_lossy().to_string();
+        match run(script).await {
+            Ok(()) => println!("PASS {}", name),
+            Err(e) => {
+                println!("FAIL {}: {}", name, e);
+                failures.push(name);
+            }
+        }
+    }
+    println!(
+        "{} of {} scripts passed",
+        scripts.len() - failures.len(),
+        scripts.len()
+    );
+    assert!(failures.is_empty(), "failed: {}", failures.join(", "));
+}
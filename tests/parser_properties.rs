@@ -0,0 +1,193 @@
+//! Property tests for the parsers which untrusted input reaches: command lines, sequence sets,
+//! FETCH items, SEARCH criteria and MIME messages. None of them may panic on any input, and
+//! what they parse must survive being written out and parsed again.
+//!
+//! The `fuzz` directory has `cargo fuzz` targets for the same parsers, for longer runs.
+
+use imaprust::handlers::fetch::items::{self, Section};
+use imaprust::handlers::search::criteria;
+use imaprust::handlers::sequence::SequenceSet;
+use imaprust::mime::address::Address;
+use imaprust::mime::encoding::{
+    decode_base64, decode_quoted_printable, decode_words, encode_base64,
+};
+use imaprust::mime::Part;
+use imaprust::server::Command;
+use proptest::prelude::*;
+
+/// Walks every part of a parsed message, doing what FETCH and SEARCH would with it.
+fn exercise(part: &Part) {
+    for field in part.headers().fields() {
+        let value = field.value();
+        decode_words(&value);
+        Address::parse_list(&value);
+    }
+    part.disposition();
+    part.text();
+    part.lines();
+    part.children.iter().for_each(exercise);
+    if let Some(message) = &part.message {
+        exercise(message);
+    }
+}
+
+/// Messages made of pieces which mean something to the MIME parser, so that arbitrary input
+/// reaches the multipart and encoding code rather than only the header parser.
+fn message() -> impl Strategy<Value = Vec<u8>> {
+    let pieces = [
+        &b"Content-Type: multipart/mixed; boundary=\"b\"\r\n"[..],
+        b"Content-Type: multipart/digest; boundary=b\r\n",
+        b"Content-Type: message/rfc822\r\n",
+        b"Content-Type: text/plain; charset=iso-8859-2\r\n",
+        b"Content-Transfer-Encoding: base64\r\n",
+        b"Content-Transfer-Encoding: quoted-printable\r\n",
+        b"From: \"Jane\" <jane@example.com>, (comment) x@y\r\n",
+        b"Subject: =?utf-8?Q?caf=C3=A9?= =?utf-8?B?w6k=?=\r\n",
+        b" folded\r\n",
+        b"--b\r\n",
+        b"--b--\r\n",
+        b"\r\n",
+        b"\n",
+        b"=\r\n",
+    ];
+    let piece = prop_oneof![
+        prop::sample::select(pieces.map(<[u8]>::to_vec).to_vec()),
+        prop::collection::vec(any::<u8>(), 0..32),
+    ];
+    prop::collection::vec(piece, 0..48).prop_map(|pieces| pieces.concat())
+}
+
+/// FETCH items, with the pieces of sections mixed in.
+const ITEMS: &str = concat!(
+    r"(\(|\)| |BODY(\.PEEK)?\[|\]|[0-9.]{0,6}|HEADER(\.FIELDS(\.NOT)?)?|TEXT|MIME",
+    r"|<[0-9]{0,3}\.?[0-9]{0,3}>|[^ ]{0,3}){0,12}",
+);
+
+/// SEARCH criteria, with nesting, quoting and the keys which take arguments mixed in.
+const CRITERIA: &str = concat!(
+    r#"(\(|\)| |"|\\|NOT |OR |ALL|SEEN|UID |FROM |LARGER |ON "#,
+    r"|[0-9]{1,2}-[A-Za-z]{3}-[0-9]{4}|[0-9:*,]{1,6}|[^ ]{0,3}){0,24}",
+);
+
+fn sequence_set() -> impl Strategy<Value = String> {
+    let bound = prop_oneof![
+        (1..=u32::MAX).prop_map(|n| n.to_string()),
+        Just("*".to_string())
+    ];
+    let range = prop_oneof![
+        bound.clone(),
+        (bound.clone(), bound).prop_map(|(first, last)| format!("{}:{}", first, last)),
+    ];
+    prop::collection::vec(range, 1..8).prop_map(|ranges| ranges.join(","))
+}
+
+proptest! {
+    #[test]
+    fn command_parse_never_panics(line in any::<String>()) {
+        // Every line with a space has a tag and a command, and no other line does.
+        prop_assert_eq!(Command::parse(&line).is_ok(), line.contains(' '));
+    }
+
+    #[test]
+    fn command_parse_keeps_components(
+        tag in "[A-Za-z0-9]{1,8}",
+        command in "[A-Za-z]{1,12}",
+        args in prop::collection::vec("[A-Za-z0-9@.\\\\]{1,12}", 0..4),
+    ) {
+        let line = [vec![tag.clone(), command.clone()], args.clone()].concat().join(" ");
+        let parsed = Command::parse(&line).unwrap();
+        prop_assert_eq!(&parsed.tag(), &tag);
+        prop_assert_eq!(&parsed.command(), &command);
+        prop_assert_eq!(parsed.num_args(), args.len());
+        for (position, arg) in args.iter().enumerate() {
+            prop_assert_eq!(&parsed.arg(position), arg);
+        }
+        // Quoting the arguments does not change them.
+        let quoted: Vec<String> = args.iter().map(|arg| format!("\"{}\"", arg)).collect();
+        let line = [vec![tag, command], quoted].concat().join(" ");
+        prop_assert_eq!(Command::parse(&line).unwrap(), parsed);
+    }
+
+    #[test]
+    fn sequence_set_round_trips(set in sequence_set(), largest in 0u32..1000) {
+        let parsed = SequenceSet::parse(&set).unwrap();
+        prop_assert_eq!(SequenceSet::parse(&parsed.to_string()).unwrap(), parsed.clone());
+        let resolved = parsed.resolve(largest);
+        prop_assert!(resolved.windows(2).all(|pair| pair[0] < pair[1]));
+        prop_assert!(resolved.iter().all(|number| parsed.contains(*number, largest)));
+    }
+
+    #[test]
+    fn sequence_set_parse_never_panics(value in any::<String>()) {
+        if let Ok(parsed) = SequenceSet::parse(&value) {
+            prop_assert_eq!(SequenceSet::parse(&parsed.to_string()).unwrap(), parsed);
+        }
+    }
+
+    #[test]
+    fn fetch_items_parse_never_panics(items in ITEMS) {
+        let _ = items::parse(&items);
+    }
+
+    #[test]
+    fn section_round_trips(spec in any::<String>()) {
+        if let Ok(section) = Section::parse(&spec) {
+            prop_assert_eq!(Section::parse(&section.to_string()).unwrap(), section);
+        }
+    }
+
+    #[test]
+    fn search_parse_never_panics(arguments in CRITERIA) {
+        let _ = criteria::parse(&arguments);
+    }
+
+    #[test]
+    fn search_parse_never_panics_on_any_string(arguments in any::<String>()) {
+        let _ = criteria::parse(&arguments);
+    }
+
+    #[test]
+    fn mime_parse_never_panics(
+        raw in message(),
+        path in prop::collection::vec(0usize..4, 0..4),
+    ) {
+        let message = Part::parse(&raw);
+        prop_assert_eq!(message.raw(), &raw[..]);
+        exercise(&message);
+        message.part(&path);
+    }
+
+    #[test]
+    fn mime_parse_never_panics_on_any_bytes(
+        raw in prop::collection::vec(any::<u8>(), 0..2048),
+    ) {
+        exercise(&Part::parse(&raw));
+    }
+
+    #[test]
+    fn multipart_bodies_round_trip(
+        boundary in "[A-Za-z0-9'+_,./=?-]{1,40}",
+        bodies in prop::collection::vec("[a-z ]{0,40}(\r\n[a-z ]{0,40}){0,3}", 1..6),
+    ) {
+        let mut raw = format!(
+            "Content-Type: multipart/mixed; boundary=\"{}\"\r\n\r\npreamble\r\n",
+            boundary
+        );
+        for body in &bodies {
+            raw.push_str(&format!("--{}\r\n\r\n{}\r\n", boundary, body));
+        }
+        raw.push_str(&format!("--{}--\r\nepilogue\r\n", boundary));
+        let message = Part::parse(raw.as_bytes());
+        prop_assert_eq!(message.children.len(), bodies.len());
+        for (child, body) in message.children.iter().zip(&bodies) {
+            prop_assert_eq!(child.body(), body.as_bytes());
+        }
+    }
+
+    #[test]
+    fn encodings_round_trip(data in prop::collection::vec(any::<u8>(), 0..512)) {
+        prop_assert_eq!(decode_base64(encode_base64(&data).as_bytes()), data.clone());
+        // Quoted-printable decoding never makes the data longer.
+        prop_assert!(decode_quoted_printable(&data).len() <= data.len());
+    }
+}
@@ -1,56 +1,80 @@
-use imap;
-use imaprust::{server::ServerBuilder, auth::inmemory::InMemoryUserStore};
+use std::sync::Arc;
 
-use std::net::TcpStream;
+use imaprust::{server::{Configuration, ServerBuilder}, auth::inmemory::InMemoryUserStore};
+use imaprust::index::{inmemory::InMemoryIndex, reindex::reindex, Owner};
+use imaprust::store::{inmemory::InMemoryStore, DataStore, Message};
+use imaprust::testing::{ImapTestClient, Received};
 
-use async_std::task;
-
-#[test]
-fn test_can_connect() {
-    let (sender, receiver): (std::sync::mpsc::Sender<()>, std::sync::mpsc::Receiver<()>) = std::sync::mpsc::channel();
-
-    let server_handle = std::thread::spawn(move || {
-        task::block_on(async {
+#[async_std::test]
+async fn test_can_connect() {
     let user_store = InMemoryUserStore::new().with_user("me@example.com", "password");
-            
-            let builder = ServerBuilder::new().with_user_store(user_store);
-            let server = builder.bind().await.unwrap();
-            sender.send(()).unwrap();
-            server.listen().await
-        })
-    });
 
-    receiver.recv().unwrap();
+    let data_store = InMemoryStore::new();
+    data_store
+        .append("me@example.com", "INBOX", Message::new(b"Subject: test\r\n\r\nThis is a test email body."))
+        .await
+        .unwrap();
+    let index = InMemoryIndex::new();
+    reindex(&data_store, &index, &Owner::new("me@example.com"), |_| {}).await.unwrap();
 
-    let connection = TcpStream::connect("127.0.0.1:3143").unwrap();
-    let mut client = imap::Client::new(connection);
-    client.read_greeting().unwrap();
-    client.debug = true;
+    let server = ServerBuilder::new()
+        .with_configuration(Configuration::default().with_listeners(vec![]))
+        .with_user_store(user_store)
+        .with_data_store(data_store)
+        .with_index(index)
+        .bind()
+        .await
+        .unwrap();
+    let server = Arc::new(server);
+
+    let mut client = ImapTestClient::connect(&server).await.unwrap();
+    assert_eq!(client.greeting(), "OK [CAPABILITY IMAP4rev2 ID] IMAP4rev2 server ready");
 
     // the client we have here is unauthenticated.
     // to do anything useful with the e-mails, we need to log in
-    let mut imap_session = client
-        .login("me@example.com", "password")
-        .map_err(|e| e.0)
-        .unwrap();
+    client.command("LOGIN me@example.com password").await.unwrap().ok().unwrap();
 
     // we want to fetch the first email in the INBOX mailbox
-    imap_session.select("INBOX").unwrap();
-
-    // fetch message number 1 in this mailbox, along with its RFC822 field.
-    // RFC 822 dictates the format of the body of e-mails
-    let messages = imap_session.fetch("1", "BODY[TEXT]").unwrap();
-    let message = messages.iter().next().unwrap();
+    let select = client.command("SELECT INBOX").await.unwrap().ok().unwrap();
+    assert!(select.untagged.iter().any(|response| response.text == "1 EXISTS"));
 
-    // extract the message's body
-    let body = message.text().expect("message did not have a body!");
-    let body = std::str::from_utf8(body)
-        .expect("message was not valid utf-8")
-        .to_string();
+    // fetch message number 1 in this mailbox, along with its text.
+    let fetch = client.command("FETCH 1 BODY[TEXT]").await.unwrap().ok().unwrap();
+    let body = fetch.literals().next().expect("message did not have a body!");
+    let body = std::str::from_utf8(body).expect("message was not valid utf-8");
 
     assert_eq!(body, "This is a test email body.");
 
     // be nice to the server and log out
-    imap_session.logout().unwrap();
-    drop(server_handle)
+    let logout = client.command("LOGOUT").await.unwrap().ok().unwrap();
+    assert_eq!(logout.untagged[0].text, "BYE IMAP4rev2 server logging out");
+}
+
+#[async_std::test]
+async fn test_append_keeps_flags_and_date() {
+    let server = ServerBuilder::new()
+        .with_configuration(Configuration::default().with_listeners(vec![]))
+        .with_user_store(InMemoryUserStore::new().with_user("me@example.com", "password"))
+        .with_data_store(InMemoryStore::new())
+        .with_index(InMemoryIndex::new())
+        .bind()
+        .await
+        .unwrap();
+    let server = Arc::new(server);
+
+    let mut client = ImapTestClient::connect(&server).await.unwrap();
+    client.command("LOGIN me@example.com password").await.unwrap().ok().unwrap();
+
+    // the message is only sent once the server asks for it
+    let message = "Subject: Sent\r\n\r\nSee you soon.";
+    let append = format!("APPEND inbox (\\Seen) \"01-Feb-2024 10:00:00 +0100\" {{{}}}", message.len());
+    let tag = client.send(&append).await.unwrap();
+    assert_eq!(client.read().await.unwrap(), Received::Continuation("Ready for literal data".to_string()));
+    client.continue_with(message).await.unwrap();
+    let reply = client.reply(&tag).await.unwrap().ok().unwrap();
+    assert!(reply.text.starts_with("[APPENDUID "));
+
+    client.command("SELECT INBOX").await.unwrap().ok().unwrap();
+    let fetch = client.command("FETCH 1 (FLAGS INTERNALDATE)").await.unwrap().ok().unwrap();
+    assert_eq!(fetch.untagged[0].text, "1 FETCH (FLAGS (\\Seen) INTERNALDATE \" 1-Feb-2024 09:00:00 +0000\")");
 }